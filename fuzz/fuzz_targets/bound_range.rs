@@ -0,0 +1,7 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| tikv_client::fuzz::bound_range(data));
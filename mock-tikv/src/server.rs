@@ -2,8 +2,8 @@
 
 use crate::{spawn_unary_success, KvStore};
 use derive_new::new;
-use futures::{FutureExt, TryFutureExt};
-use grpcio::{Environment, Server, ServerBuilder};
+use futures::{FutureExt, StreamExt, TryFutureExt, TryStreamExt};
+use grpcio::{Environment, Server, ServerBuilder, WriteFlags};
 use std::sync::Arc;
 use tikv_client_proto::{kvrpcpb::*, tikvpb::*};
 
@@ -408,11 +408,28 @@ impl Tikv for MockTikv {
 
     fn batch_commands(
         &mut self,
-        _ctx: grpcio::RpcContext,
-        _stream: grpcio::RequestStream<tikv_client_proto::tikvpb::BatchCommandsRequest>,
-        _sink: grpcio::DuplexSink<tikv_client_proto::tikvpb::BatchCommandsResponse>,
-    ) {
-        todo!()
+        ctx: grpcio::RpcContext,
+        stream: grpcio::RequestStream<tikv_client_proto::tikvpb::BatchCommandsRequest>,
+        sink: grpcio::DuplexSink<tikv_client_proto::tikvpb::BatchCommandsResponse>,
+    ) {
+        let inner = self.inner.clone();
+        let responses = stream.map_ok(move |req| {
+            let responses = req
+                .requests
+                .into_iter()
+                .map(|request| batch_commands_response::Response {
+                    cmd: request.cmd.and_then(|cmd| batch_command(&inner, cmd)),
+                })
+                .collect();
+            let resp = BatchCommandsResponse {
+                responses,
+                request_ids: req.request_ids,
+                ..Default::default()
+            };
+            (resp, WriteFlags::default())
+        });
+        // the stream ends once the client closes it
+        ctx.spawn(responses.forward(sink).map(|_| ()));
     }
 
     fn kv_check_secondary_locks(
@@ -505,3 +522,47 @@ impl Tikv for MockTikv {
         todo!()
     }
 }
+
+/// Handles a raw command of a batch commands stream like the unary call, `None` for other
+/// commands.
+fn batch_command(
+    store: &KvStore,
+    cmd: batch_commands_request::request::Cmd,
+) -> Option<batch_commands_response::response::Cmd> {
+    use batch_commands_request::request::Cmd;
+    use batch_commands_response::response::Cmd as ResponseCmd;
+    match cmd {
+        Cmd::RawGet(req) => {
+            let mut resp = RawGetResponse::default();
+            match store.raw_get(req.get_key()) {
+                Some(v) => resp.set_value(v),
+                None => resp.set_not_found(true),
+            }
+            Some(ResponseCmd::RawGet(resp))
+        }
+        Cmd::RawBatchGet(mut req) => {
+            let mut resp = RawBatchGetResponse::default();
+            resp.set_pairs(store.raw_batch_get(req.take_keys()));
+            Some(ResponseCmd::RawBatchGet(resp))
+        }
+        Cmd::RawPut(req) => {
+            store.raw_put(req.get_key().to_vec(), req.get_value().to_vec());
+            Some(ResponseCmd::RawPut(RawPutResponse::default()))
+        }
+        Cmd::RawBatchPut(mut req) => {
+            store.raw_batch_put(req.take_pairs());
+            Some(ResponseCmd::RawBatchPut(RawBatchPutResponse::default()))
+        }
+        Cmd::RawDelete(req) => {
+            store.raw_delete(req.get_key());
+            Some(ResponseCmd::RawDelete(RawDeleteResponse::default()))
+        }
+        Cmd::RawBatchDelete(mut req) => {
+            store.raw_batch_delete(req.take_keys());
+            Some(ResponseCmd::RawBatchDelete(
+                RawBatchDeleteResponse::default(),
+            ))
+        }
+        _ => None,
+    }
+}
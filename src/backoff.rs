@@ -2,16 +2,46 @@
 
 // https://aws.amazon.com/blogs/architecture/exponential-backoff-and-jitter/
 
+use crate::{clock::ClockHandle, region::RegionId};
 use rand::{thread_rng, Rng};
-use std::time::Duration;
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
 pub const DEFAULT_REGION_BACKOFF: Backoff = Backoff::no_jitter_backoff(2, 500, 10);
 pub const OPTIMISTIC_BACKOFF: Backoff = Backoff::no_jitter_backoff(2, 500, 10);
 pub const PESSIMISTIC_BACKOFF: Backoff = Backoff::no_backoff();
 
+/// How long it takes for the backoff level of a region to decay by one, see [`RegionBackoffs`].
+const REGION_BACKOFF_DECAY: Duration = Duration::from_secs(1);
+/// The maximum backoff level of a region.
+const MAX_REGION_BACKOFF_LEVEL: u32 = 16;
+
+/// The default backoff between reconnects to PD, see
+/// [`Config::with_pd_retry_backoff`](crate::Config::with_pd_retry_backoff).
+pub fn default_pd_backoff() -> Backoff {
+    Backoff::full_jitter_backoff(100, 3000, 50)
+}
+
 /// When a request is retried, we can backoff for some time to avoid saturating the network.
 ///
-/// `Backoff` is an object which determines how long to wait for.
+/// `Backoff` is an object which determines how long to wait for. Retries are bounded by three
+/// independent limits:
+/// - the maximum number of attempts, see [`with_max_attempts`](Backoff::with_max_attempts),
+/// - the maximum duration of a single sleep, see [`with_max_delay_ms`](Backoff::with_max_delay_ms),
+/// - the maximum total duration of all sleeps, see
+///   [`with_max_total_delay_ms`](Backoff::with_max_total_delay_ms), unlimited by default.
+///
+/// E.g. `Backoff::no_jitter_backoff(2, 500, 100).with_max_total_delay_ms(10_000)` retries
+/// aggressively, never sleeps more than 500ms at once, and gives up after sleeping for 10s in
+/// total.
+///
+/// The sleeps grow exponentially with or without jitter, see the constructors, or are computed
+/// by a [`BackoffStrategy`], see [`custom`](Backoff::custom). Backoffs are set per request with
+/// [`RetryOptions`](crate::RetryOptions), separately for region errors and locked keys.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Backoff {
     kind: BackoffKind,
@@ -19,24 +49,32 @@ pub struct Backoff {
     max_attempts: u32,
     base_delay_ms: u64,
     current_delay_ms: u64,
+    /// The maximum duration of a single sleep.
     max_delay_ms: u64,
+    /// The duration of all sleeps so far.
+    total_delay_ms: u64,
+    /// The maximum duration of all sleeps.
+    max_total_delay_ms: u64,
 }
 
 impl Backoff {
-    // Returns the delay period for next retry. If the maximum retry count is hit returns None.
+    // Returns the delay period for next retry. If the maximum retry count or the maximum total
+    // delay is hit returns None.
     pub fn next_delay_duration(&mut self) -> Option<Duration> {
-        if self.current_attempts >= self.max_attempts {
+        if self.current_attempts >= self.max_attempts
+            || self.total_delay_ms >= self.max_total_delay_ms
+        {
             return None;
         }
         self.current_attempts += 1;
 
-        match self.kind {
-            BackoffKind::None => None,
+        let delay_ms = match self.kind {
+            BackoffKind::None => return None,
             BackoffKind::NoJitter => {
                 let delay_ms = self.max_delay_ms.min(self.current_delay_ms);
                 self.current_delay_ms <<= 1;
 
-                Some(Duration::from_millis(delay_ms))
+                delay_ms
             }
             BackoffKind::FullJitter => {
                 let delay_ms = self.max_delay_ms.min(self.current_delay_ms);
@@ -45,7 +83,7 @@ impl Backoff {
                 let delay_ms: u64 = rng.gen_range(0..delay_ms);
                 self.current_delay_ms <<= 1;
 
-                Some(Duration::from_millis(delay_ms))
+                delay_ms
             }
             BackoffKind::EqualJitter => {
                 let delay_ms = self.max_delay_ms.min(self.current_delay_ms);
@@ -55,7 +93,17 @@ impl Backoff {
                 let delay_ms: u64 = rng.gen_range(0..half_delay_ms) + half_delay_ms;
                 self.current_delay_ms <<= 1;
 
-                Some(Duration::from_millis(delay_ms))
+                delay_ms
+            }
+            BackoffKind::Custom(ref strategy) => {
+                let delay = strategy.delay(
+                    self.current_attempts,
+                    Duration::from_millis(self.current_delay_ms),
+                );
+                let delay_ms = self.max_delay_ms.min(delay.as_millis() as u64);
+                self.current_delay_ms = delay_ms;
+
+                delay_ms
             }
             BackoffKind::DecorrelatedJitter => {
                 let mut rng = thread_rng();
@@ -66,9 +114,50 @@ impl Backoff {
                 let delay_ms = delay_ms.min(self.max_delay_ms);
                 self.current_delay_ms = delay_ms;
 
-                Some(Duration::from_millis(delay_ms))
+                delay_ms
             }
+        };
+
+        // the last sleep is cut short to stay within the maximum total delay
+        let delay_ms = delay_ms.min(self.max_total_delay_ms - self.total_delay_ms);
+        self.total_delay_ms += delay_ms;
+        Some(Duration::from_millis(delay_ms))
+    }
+
+    /// Make the next sleep at least as long as after `level` sleeps, i.e. the base delay doubled
+    /// `level` times. The number of attempts left is unchanged.
+    pub(crate) fn raise_level(&mut self, level: u32) {
+        if self.kind == BackoffKind::None {
+            return;
         }
+        let delay_ms = self
+            .base_delay_ms
+            .saturating_mul(1 << level.min(MAX_REGION_BACKOFF_LEVEL))
+            .min(self.max_delay_ms);
+        self.current_delay_ms = self.current_delay_ms.max(delay_ms);
+    }
+
+    /// Set the maximum number of attempts, i.e. of sleeps before giving up.
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> Backoff {
+        self.max_attempts = max_attempts;
+        self
+    }
+
+    /// Set the maximum duration of a single sleep, which must not be less than the base delay.
+    pub fn with_max_delay_ms(mut self, max_delay_ms: u64) -> Backoff {
+        assert!(
+            max_delay_ms >= self.base_delay_ms,
+            "max_delay_ms must not be less than base_delay_ms"
+        );
+        self.max_delay_ms = max_delay_ms;
+        self
+    }
+
+    /// Set the maximum total duration of all sleeps. Once it is reached, no more attempts are
+    /// made, however many attempts are left.
+    pub fn with_max_total_delay_ms(mut self, max_total_delay_ms: u64) -> Backoff {
+        self.max_total_delay_ms = max_total_delay_ms;
+        self
     }
 
     /// True if we should not backoff at all (usually indicates that we should not retry a request).
@@ -90,6 +179,8 @@ impl Backoff {
             base_delay_ms: 0,
             current_delay_ms: 0,
             max_delay_ms: 0,
+            total_delay_ms: 0,
+            max_total_delay_ms: u64::MAX,
         }
     }
 
@@ -110,6 +201,8 @@ impl Backoff {
             base_delay_ms,
             current_delay_ms: base_delay_ms,
             max_delay_ms,
+            total_delay_ms: 0,
+            max_total_delay_ms: u64::MAX,
         }
     }
 
@@ -135,6 +228,8 @@ impl Backoff {
             base_delay_ms,
             current_delay_ms: base_delay_ms,
             max_delay_ms,
+            total_delay_ms: 0,
+            max_total_delay_ms: u64::MAX,
         }
     }
 
@@ -160,6 +255,8 @@ impl Backoff {
             base_delay_ms,
             current_delay_ms: base_delay_ms,
             max_delay_ms,
+            total_delay_ms: 0,
+            max_total_delay_ms: u64::MAX,
         }
     }
 
@@ -182,23 +279,148 @@ impl Backoff {
             base_delay_ms,
             current_delay_ms: base_delay_ms,
             max_delay_ms,
+            total_delay_ms: 0,
+            max_total_delay_ms: u64::MAX,
+        }
+    }
+
+    /// Sleeps as long as `strategy` computes, at most `max_attempts` times.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use tikv_client::{Backoff, BackoffStrategy};
+    /// # use std::time::Duration;
+    /// // sleeps 100ms, 200ms, 300ms...
+    /// #[derive(Debug)]
+    /// struct Linear;
+    ///
+    /// impl BackoffStrategy for Linear {
+    ///     fn delay(&self, attempt: u32, _previous_delay: Duration) -> Duration {
+    ///         Duration::from_millis(100) * attempt
+    ///     }
+    /// }
+    ///
+    /// let mut backoff = Backoff::custom(Linear, 3);
+    /// assert_eq!(backoff.next_delay_duration(), Some(Duration::from_millis(100)));
+    /// assert_eq!(backoff.next_delay_duration(), Some(Duration::from_millis(200)));
+    /// ```
+    pub fn custom(strategy: impl BackoffStrategy, max_attempts: u32) -> Backoff {
+        Backoff {
+            kind: BackoffKind::Custom(Arc::new(strategy)),
+            current_attempts: 0,
+            max_attempts,
+            base_delay_ms: 0,
+            current_delay_ms: 0,
+            max_delay_ms: u64::MAX,
+            total_delay_ms: 0,
+            max_total_delay_ms: u64::MAX,
+        }
+    }
+}
+
+/// Computes the sleeps of a [`Backoff`], see [`Backoff::custom`].
+///
+/// The limits of the backoff still apply, e.g. [`with_max_delay_ms`](Backoff::with_max_delay_ms)
+/// caps the delays computed by the strategy.
+pub trait BackoffStrategy: fmt::Debug + Send + Sync + 'static {
+    /// How long to sleep before the attempt `attempt`, starting at 1. `previous_delay` is the
+    /// previous sleep, zero before the first one.
+    fn delay(&self, attempt: u32, previous_delay: Duration) -> Duration;
+}
+
+/// The backoff levels of the regions, shared by the requests of a client.
+///
+/// Each request has its own [`Backoff`], but a region which is failing, e.g. during a rolling
+/// restart of TiKV, fails the requests following it as well. A request retrying a region starts
+/// backing off at the level of the region, raised by each failure, instead of at the base delay.
+/// Successes feed back into the level: each one halves it, and the level decays by one for each
+/// second without failures, so the requests following a recovery aren't slowed down.
+pub struct RegionBackoffs {
+    clock: ClockHandle,
+    levels: Mutex<HashMap<RegionId, RegionLevel>>,
+}
+
+struct RegionLevel {
+    level: u32,
+    updated: Instant,
+}
+
+impl RegionBackoffs {
+    pub fn new(clock: ClockHandle) -> RegionBackoffs {
+        RegionBackoffs {
+            clock,
+            levels: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Records a failed request to `region_id`. Returns the level the retry backs off at, i.e.
+    /// the level of the region before the failure.
+    pub fn on_failure(&self, region_id: RegionId) -> u32 {
+        let now = self.clock.now();
+        let mut levels = self.levels.lock().unwrap();
+        // the regions which recovered are forgotten
+        levels.retain(|_, level| level.decayed(now) > 0);
+        let level = levels.get(&region_id).map_or(0, |level| level.decayed(now));
+        levels.insert(
+            region_id,
+            RegionLevel {
+                level: (level + 1).min(MAX_REGION_BACKOFF_LEVEL),
+                updated: now,
+            },
+        );
+        level
+    }
+
+    /// Records a successful request to `region_id`.
+    pub fn on_success(&self, region_id: RegionId) {
+        let now = self.clock.now();
+        let mut levels = self.levels.lock().unwrap();
+        if let Some(level) = levels.get_mut(&region_id) {
+            level.level = level.decayed(now) / 2;
+            level.updated = now;
+            if level.level == 0 {
+                levels.remove(&region_id);
+            }
         }
     }
 }
 
+impl RegionLevel {
+    fn decayed(&self, now: Instant) -> u32 {
+        let elapsed = now.saturating_duration_since(self.updated);
+        let steps = elapsed.as_secs_f64() / REGION_BACKOFF_DECAY.as_secs_f64();
+        self.level.saturating_sub(steps as u32)
+    }
+}
+
 /// The pattern for computing backoff times.
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone)]
 enum BackoffKind {
     None,
     NoJitter,
     FullJitter,
     EqualJitter,
     DecorrelatedJitter,
+    Custom(Arc<dyn BackoffStrategy>),
+}
+
+// Not derived, custom strategies are only equal to themselves.
+impl PartialEq for BackoffKind {
+    fn eq(&self, other: &BackoffKind) -> bool {
+        match (self, other) {
+            (BackoffKind::Custom(a), BackoffKind::Custom(b)) => Arc::ptr_eq(a, b),
+            (a, b) => std::mem::discriminant(a) == std::mem::discriminant(b),
+        }
+    }
 }
 
+impl Eq for BackoffKind {}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::MockClock;
     use std::convert::TryInto;
 
     #[test]
@@ -223,6 +445,144 @@ mod test {
         assert_eq!(backoff.next_delay_duration(), None);
     }
 
+    #[test]
+    fn test_backoff_limits() {
+        // the total delay is cut short before the attempts are exhausted
+        let mut backoff = Backoff::no_jitter_backoff(2, 4, 10).with_max_total_delay_ms(11);
+        assert_eq!(
+            backoff.next_delay_duration(),
+            Some(Duration::from_millis(2))
+        );
+        assert_eq!(
+            backoff.next_delay_duration(),
+            Some(Duration::from_millis(4))
+        );
+        assert_eq!(
+            backoff.next_delay_duration(),
+            Some(Duration::from_millis(4))
+        );
+        assert_eq!(
+            backoff.next_delay_duration(),
+            Some(Duration::from_millis(1))
+        );
+        assert_eq!(backoff.next_delay_duration(), None);
+
+        let mut backoff = Backoff::no_jitter_backoff(2, 4, 10)
+            .with_max_delay_ms(3)
+            .with_max_attempts(2);
+        assert_eq!(
+            backoff.next_delay_duration(),
+            Some(Duration::from_millis(2))
+        );
+        assert_eq!(
+            backoff.next_delay_duration(),
+            Some(Duration::from_millis(3))
+        );
+        assert_eq!(backoff.next_delay_duration(), None);
+    }
+
+    #[test]
+    fn test_raise_level() {
+        let mut backoff = Backoff::no_jitter_backoff(2, 20, 3);
+        backoff.raise_level(2);
+        assert_eq!(
+            backoff.next_delay_duration(),
+            Some(Duration::from_millis(8))
+        );
+        // a lower level doesn't shorten the sleeps
+        backoff.raise_level(1);
+        assert_eq!(
+            backoff.next_delay_duration(),
+            Some(Duration::from_millis(16))
+        );
+        backoff.raise_level(10);
+        assert_eq!(
+            backoff.next_delay_duration(),
+            Some(Duration::from_millis(20))
+        );
+        assert_eq!(backoff.next_delay_duration(), None);
+
+        let mut backoff = Backoff::no_backoff();
+        backoff.raise_level(2);
+        assert_eq!(backoff.next_delay_duration(), None);
+    }
+
+    #[test]
+    fn test_region_backoffs() {
+        let clock = MockClock::new();
+        let backoffs = RegionBackoffs::new(ClockHandle::new(clock.clone()));
+        assert_eq!(backoffs.on_failure(1), 0);
+        assert_eq!(backoffs.on_failure(1), 1);
+        assert_eq!(backoffs.on_failure(1), 2);
+        // the levels of the regions are independent
+        assert_eq!(backoffs.on_failure(2), 0);
+
+        // a success halves the level
+        backoffs.on_success(1);
+        assert_eq!(backoffs.on_failure(1), 1);
+        backoffs.on_success(1);
+        backoffs.on_success(1);
+        assert_eq!(backoffs.on_failure(1), 0);
+
+        // the level decays without failures
+        for _ in 0..4 {
+            backoffs.on_failure(2);
+        }
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(backoffs.on_failure(2), 3);
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(backoffs.on_failure(2), 0);
+
+        for _ in 0..100 {
+            backoffs.on_failure(3);
+        }
+        assert_eq!(backoffs.on_failure(3), MAX_REGION_BACKOFF_LEVEL);
+    }
+
+    #[test]
+    fn test_custom_backoff() {
+        #[derive(Debug)]
+        struct Doubling;
+
+        impl BackoffStrategy for Doubling {
+            fn delay(&self, attempt: u32, previous_delay: Duration) -> Duration {
+                assert!(attempt > 0);
+                (previous_delay * 2).max(Duration::from_millis(3))
+            }
+        }
+
+        let mut backoff = Backoff::custom(Doubling, 4).with_max_delay_ms(10);
+        assert_eq!(
+            backoff.next_delay_duration(),
+            Some(Duration::from_millis(3))
+        );
+        assert_eq!(
+            backoff.next_delay_duration(),
+            Some(Duration::from_millis(6))
+        );
+        // the delays of the strategy are capped
+        assert_eq!(
+            backoff.next_delay_duration(),
+            Some(Duration::from_millis(10))
+        );
+        assert_eq!(
+            backoff.next_delay_duration(),
+            Some(Duration::from_millis(10))
+        );
+        assert_eq!(backoff.next_delay_duration(), None);
+
+        // a strategy is only equal to itself
+        let backoff = Backoff::custom(Doubling, 4);
+        assert_eq!(backoff, backoff.clone());
+        assert_ne!(backoff, Backoff::custom(Doubling, 4));
+    }
+
+    #[test]
+    #[should_panic(expected = "max_delay_ms must not be less than base_delay_ms")]
+    fn test_backoff_with_invalid_max_delay_ms() {
+        Backoff::full_jitter_backoff(2, 7, 3).with_max_delay_ms(1);
+    }
+
     #[test]
     fn test_full_jitter_backoff() {
         let mut backoff = Backoff::full_jitter_backoff(2, 7, 3);
@@ -0,0 +1,746 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! Change data capture: a stream of the changes of the rows in a range of transactional keys, see
+//! [`TransactionClient::subscribe_changes`](crate::TransactionClient::subscribe_changes).
+//!
+//! The feed subscribes to the `ChangeData` service of the leader of each region of the range, over
+//! one `EventFeed` stream per store, like TiCDC does. Each store first scans the changes of its
+//! region committed after the checkpoint, then sends the changes as they are committed, and
+//! regularly a resolved timestamp, before which all changes of the region have been sent. The
+//! feed holds the changes until the resolved timestamps of all regions have passed them, then
+//! delivers them in the order of their commit timestamps.
+//!
+//! When a region is split, merged, or moves to another leader, its store fails the subscription.
+//! The feed then subscribes to the regions now covering its keys again, from its resolved
+//! timestamp, and drops the changes it receives twice.
+
+use crate::{
+    backoff::{Backoff, DEFAULT_REGION_BACKOFF},
+    import::store_addresses,
+    pd::PdClient,
+    region::{RegionId, RegionVerId, StoreId},
+    timestamp::TimestampExt,
+    BoundRange, Error, Key, Result, Value,
+};
+use futures::{
+    channel::mpsc,
+    prelude::*,
+    stream::{self, BoxStream, SelectAll},
+};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    mem,
+    sync::Arc,
+};
+use tikv_client_proto::{
+    cdcpb::{
+        self, change_data_request,
+        event::{self, row::OpType, LogType},
+    },
+    kvrpcpb,
+};
+
+/// The TiCDC version announced to the stores, which send the resolved timestamps of their regions
+/// in batches to versions 4.0.8 or later.
+const TICDC_VERSION: &str = "5.0.0";
+
+/// An event of the feed of a store, with the id of the feed and the store, or `None` once the
+/// feed ended.
+type StoreEvent = (u64, StoreId, Option<Result<cdcpb::ChangeDataEvent>>);
+
+/// Options of a change feed, see
+/// [`TransactionClient::subscribe_changes`](crate::TransactionClient::subscribe_changes).
+#[derive(Clone, Debug, Default)]
+pub struct ChangeFeedOptions {
+    pub(crate) checkpoint_ts: Option<u64>,
+    pub(crate) old_value: bool,
+}
+
+impl ChangeFeedOptions {
+    pub fn new() -> ChangeFeedOptions {
+        ChangeFeedOptions::default()
+    }
+
+    /// Deliver the changes committed after `checkpoint_ts`, e.g. the last resolved timestamp
+    /// delivered by a previous feed, to resume it. By default, the changes committed after the
+    /// feed started.
+    ///
+    /// The stores scan the versions committed after the checkpoint, so it must not be older than
+    /// the GC safepoint.
+    pub fn checkpoint_ts(mut self, checkpoint_ts: u64) -> ChangeFeedOptions {
+        self.checkpoint_ts = Some(checkpoint_ts);
+        self
+    }
+
+    /// Deliver the value of each row before the change along with the change, which costs the
+    /// stores a read per change. Disabled by default.
+    pub fn old_value(mut self, old_value: bool) -> ChangeFeedOptions {
+        self.old_value = old_value;
+        self
+    }
+}
+
+/// An event of a change feed.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ChangeEvent {
+    /// A committed change of a row. The changes are delivered in the order of their commit
+    /// timestamps, and the changes committed by a transaction in the order of their keys.
+    Row(RowChange),
+    /// All changes committed at or before this timestamp have been delivered. A feed resumed from
+    /// it, see [`ChangeFeedOptions::checkpoint_ts`], delivers the changes committed after it.
+    Resolved(u64),
+}
+
+/// A committed change of a row, see [`ChangeEvent::Row`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RowChange {
+    pub key: Key,
+    pub op: RowOp,
+    /// The start timestamp of the transaction which made the change.
+    pub start_ts: u64,
+    pub commit_ts: u64,
+    /// The value of the row before the change, if [`old_value`](ChangeFeedOptions::old_value) is
+    /// enabled. `None` if the row didn't exist or its value was empty.
+    pub old_value: Option<Value>,
+}
+
+/// What a [`RowChange`] did to its row.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum RowOp {
+    Put(Value),
+    Delete,
+}
+
+/// Subscribes to the changes in `range`, a range of raw keys, see
+/// [`TransactionClient::subscribe_changes`](crate::TransactionClient::subscribe_changes).
+///
+/// The changes are delivered once all regions are subscribed; failing to subscribe fails the call.
+pub(crate) async fn change_feed<PdC: PdClient>(
+    pd_client: Arc<PdC>,
+    range: BoundRange,
+    cluster_id: u64,
+    options: ChangeFeedOptions,
+) -> Result<BoxStream<'static, Result<ChangeEvent>>> {
+    let checkpoint_ts = match options.checkpoint_ts {
+        Some(checkpoint_ts) => checkpoint_ts,
+        None => pd_client.clone().get_timestamp().await?.version(),
+    };
+    let (start, end) = range.into_keys();
+    let mut feed = ChangeFeed {
+        pd_client,
+        cluster_id,
+        old_value: options.old_value,
+        addresses: HashMap::new(),
+        stores: HashMap::new(),
+        events: SelectAll::new(),
+        subscriptions: HashMap::new(),
+        next_id: 0,
+        pending: BTreeMap::new(),
+        resolved_ts: checkpoint_ts,
+        ready: VecDeque::new(),
+    };
+    let end = end.unwrap_or(Key::EMPTY);
+    feed.subscribe(start, end, checkpoint_ts, DEFAULT_REGION_BACKOFF)
+        .await?;
+    Ok(stream::try_unfold(feed, |mut feed| async move {
+        let event = feed.next_event().await?;
+        Ok(Some((event, feed)))
+    })
+    .boxed())
+}
+
+/// The state of a change feed.
+struct ChangeFeed<PdC: PdClient> {
+    pd_client: Arc<PdC>,
+    cluster_id: u64,
+    old_value: bool,
+    /// The addresses of the TiKV stores, by id, fetched again when a leader is on an unknown
+    /// store.
+    addresses: HashMap<StoreId, String>,
+    /// The current event feed of each store the feed subscribed to.
+    stores: HashMap<StoreId, StoreFeed>,
+    /// The events of the feeds of the stores.
+    events: SelectAll<BoxStream<'static, StoreEvent>>,
+    /// The subscriptions, which cover the range of the feed, by request id.
+    subscriptions: HashMap<u64, Subscription>,
+    /// The next request or feed id.
+    next_id: u64,
+    /// The committed changes which are not resolved yet, by commit timestamp and key.
+    pending: BTreeMap<(u64, Key), RowChange>,
+    /// The last resolved timestamp delivered, or the checkpoint.
+    resolved_ts: u64,
+    /// The events to deliver.
+    ready: VecDeque<ChangeEvent>,
+}
+
+/// The `EventFeed` stream of a store.
+struct StoreFeed {
+    id: u64,
+    requests: mpsc::UnboundedSender<cdcpb::ChangeDataRequest>,
+}
+
+/// The subscription to the changes of the keys of the feed in a region.
+struct Subscription {
+    ver_id: RegionVerId,
+    store_id: StoreId,
+    /// The keys of the subscription in the raw format, `end` is empty if unbounded.
+    start: Key,
+    end: Key,
+    /// The changes committed at or before this timestamp have been received.
+    resolved_ts: u64,
+    /// Whether the store has sent the changes committed before it subscribed.
+    initialized: bool,
+    /// The prewrites waiting for their commit or rollback, by start timestamp and key.
+    prewrites: HashMap<(u64, Vec<u8>), event::Row>,
+    /// The commits which arrived before their prewrite while the store was scanning.
+    early_commits: Vec<event::Row>,
+    /// The delays before subscribing again after failures of the subscription, reset once it is
+    /// initialized.
+    backoff: Backoff,
+}
+
+impl<PdC: PdClient> ChangeFeed<PdC> {
+    async fn next_event(&mut self) -> Result<ChangeEvent> {
+        loop {
+            if let Some(event) = self.ready.pop_front() {
+                return Ok(event);
+            }
+            let (feed_id, store_id, event) = match self.events.next().await {
+                Some(event) => event,
+                None => return Err(feed_err("all event feeds ended".to_owned())),
+            };
+            // the events of a feed which failed and was replaced
+            if self.stores.get(&store_id).map(|feed| feed.id) != Some(feed_id) {
+                continue;
+            }
+            match event {
+                Some(Ok(event)) => self.on_event(store_id, event).await?,
+                Some(Err(e)) => self.on_store_failure(store_id, e.to_string()).await?,
+                None => {
+                    let cause = "the event feed was closed by the store".to_owned();
+                    self.on_store_failure(store_id, cause).await?
+                }
+            }
+            self.resolve();
+        }
+    }
+
+    /// Subscribes to the changes from `start` to `end` committed after `checkpoint_ts`, in each
+    /// region the keys are in.
+    async fn subscribe(
+        &mut self,
+        mut start: Key,
+        end: Key,
+        checkpoint_ts: u64,
+        backoff: Backoff,
+    ) -> Result<()> {
+        loop {
+            let region = self.pd_client.region_for_key(&start).await?;
+            let region_end = region.end_key();
+            let subscription_end = if !end.is_empty() && (region_end.is_empty() || end < region_end)
+            {
+                end.clone()
+            } else {
+                region_end
+            };
+            let store_id = region.get_store_id()?;
+            let request_id = self.next_id();
+            let mut request = cdcpb::ChangeDataRequest {
+                header: Some(cdcpb::Header {
+                    cluster_id: self.cluster_id,
+                    ticdc_version: TICDC_VERSION.to_owned(),
+                }),
+                region_id: region.id(),
+                region_epoch: Some(region.region.get_region_epoch().clone()),
+                checkpoint_ts,
+                start_key: encode_key(&start),
+                end_key: encode_key(&subscription_end),
+                request_id,
+                request: Some(change_data_request::Request::Register(
+                    change_data_request::Register::default(),
+                )),
+                ..Default::default()
+            };
+            if self.old_value {
+                request.set_extra_op(kvrpcpb::ExtraOp::ReadOldValue);
+            }
+            // a failure of the feed is received with its events
+            let _ = self.store_feed(store_id).await?.unbounded_send(request);
+            self.subscriptions.insert(
+                request_id,
+                Subscription {
+                    ver_id: region.ver_id(),
+                    store_id,
+                    start,
+                    end: subscription_end.clone(),
+                    resolved_ts: checkpoint_ts,
+                    initialized: false,
+                    prewrites: HashMap::new(),
+                    early_commits: Vec::new(),
+                    backoff: backoff.clone(),
+                },
+            );
+            if subscription_end == end {
+                return Ok(());
+            }
+            start = subscription_end;
+        }
+    }
+
+    /// The sender of the requests of the event feed of the store, opened if needed.
+    async fn store_feed(
+        &mut self,
+        store_id: StoreId,
+    ) -> Result<&mpsc::UnboundedSender<cdcpb::ChangeDataRequest>> {
+        if !self.stores.contains_key(&store_id) {
+            if !self.addresses.contains_key(&store_id) {
+                self.addresses = store_addresses(self.pd_client.clone()).await?;
+            }
+            let address = self
+                .addresses
+                .get(&store_id)
+                .ok_or_else(|| feed_err(format!("store {} is unknown", store_id)))?;
+            let client = self.pd_client.store_client(address).await?;
+            let (requests, receiver) = mpsc::unbounded();
+            let events = client.event_feed(receiver.boxed())?;
+            let feed_id = self.next_id();
+            self.events.push(
+                events
+                    .map(move |event| (feed_id, store_id, Some(event)))
+                    .chain(stream::once(future::ready((feed_id, store_id, None))))
+                    .boxed(),
+            );
+            self.stores.insert(
+                store_id,
+                StoreFeed {
+                    id: feed_id,
+                    requests,
+                },
+            );
+        }
+        Ok(&self.stores[&store_id].requests)
+    }
+
+    // the stores send the resolved timestamps of the regions in the region events to TiCDC
+    // versions before 4.0.8
+    #[allow(deprecated)]
+    async fn on_event(&mut self, store_id: StoreId, event: cdcpb::ChangeDataEvent) -> Result<()> {
+        for event in event.events {
+            let request_id = event.request_id;
+            // the events of a subscription which failed and was replaced
+            match self.subscriptions.get(&request_id) {
+                Some(subscription) if subscription.ver_id.id == event.region_id => {}
+                _ => continue,
+            }
+            match event.event {
+                Some(event::Event::Entries(entries)) => {
+                    for row in entries.entries {
+                        self.on_row(request_id, row)?;
+                    }
+                }
+                Some(event::Event::Error(error)) => self.on_error(request_id, error).await?,
+                Some(event::Event::ResolvedTs(ts)) => self.on_resolved_ts(request_id, ts),
+                // splits and merges are followed by errors of the subscriptions
+                Some(event::Event::Admin(_)) | Some(event::Event::LongTxn(_)) | None => {}
+            }
+        }
+        if let Some(resolved_ts) = event.resolved_ts {
+            let regions: HashSet<RegionId> = resolved_ts.regions.into_iter().collect();
+            let resolved: Vec<u64> = self
+                .subscriptions
+                .iter()
+                .filter(|(_, subscription)| {
+                    subscription.store_id == store_id && regions.contains(&subscription.ver_id.id)
+                })
+                .map(|(request_id, _)| *request_id)
+                .collect();
+            for request_id in resolved {
+                self.on_resolved_ts(request_id, resolved_ts.ts);
+            }
+        }
+        Ok(())
+    }
+
+    fn on_row(&mut self, request_id: u64, row: event::Row) -> Result<()> {
+        let subscription = self.subscriptions.get_mut(&request_id).unwrap();
+        if row.get_type() == LogType::Initialized {
+            subscription.initialized = true;
+            subscription.backoff = DEFAULT_REGION_BACKOFF;
+            let mut changes = Vec::new();
+            for commit in mem::take(&mut subscription.early_commits) {
+                match subscription
+                    .prewrites
+                    .remove(&(commit.start_ts, commit.key.clone()))
+                {
+                    Some(prewrite) => changes.push((prewrite, commit.commit_ts)),
+                    None => return Err(missing_prewrite(&commit)),
+                }
+            }
+            for (prewrite, commit_ts) in changes {
+                self.add_change(prewrite, commit_ts);
+            }
+            return Ok(());
+        }
+
+        // the stores may send the changes of the whole region
+        let key = Key::from(row.key.clone());
+        if key < subscription.start || (!subscription.end.is_empty() && key >= subscription.end) {
+            return Ok(());
+        }
+        match row.get_type() {
+            LogType::Committed => {
+                let commit_ts = row.commit_ts;
+                self.add_change(row, commit_ts);
+            }
+            LogType::Prewrite => {
+                subscription
+                    .prewrites
+                    .insert((row.start_ts, row.key.clone()), row);
+            }
+            LogType::Commit => {
+                match subscription
+                    .prewrites
+                    .remove(&(row.start_ts, row.key.clone()))
+                {
+                    Some(prewrite) => self.add_change(prewrite, row.commit_ts),
+                    None if !subscription.initialized => subscription.early_commits.push(row),
+                    None => return Err(missing_prewrite(&row)),
+                }
+            }
+            LogType::Rollback => {
+                subscription
+                    .prewrites
+                    .remove(&(row.start_ts, row.key.clone()));
+            }
+            LogType::Initialized | LogType::Unknown => {}
+        }
+        Ok(())
+    }
+
+    /// Holds the change of `row`, which carries the value, until it is resolved.
+    fn add_change(&mut self, row: event::Row, commit_ts: u64) {
+        // changes received again after a subscription failed
+        if commit_ts <= self.resolved_ts {
+            return;
+        }
+        let op = match row.get_op_type() {
+            OpType::Put => RowOp::Put(row.value),
+            OpType::Delete => RowOp::Delete,
+            OpType::Unknown => return,
+        };
+        let key = Key::from(row.key);
+        let old_value = if self.old_value && !row.old_value.is_empty() {
+            Some(row.old_value)
+        } else {
+            None
+        };
+        let change = RowChange {
+            key: key.clone(),
+            op,
+            start_ts: row.start_ts,
+            commit_ts,
+            old_value,
+        };
+        self.pending.insert((commit_ts, key), change);
+    }
+
+    fn on_resolved_ts(&mut self, request_id: u64, ts: u64) {
+        let subscription = self.subscriptions.get_mut(&request_id).unwrap();
+        // a resolved timestamp doesn't cover the changes the store is still scanning
+        if subscription.initialized {
+            subscription.resolved_ts = subscription.resolved_ts.max(ts);
+        }
+    }
+
+    /// Subscribes again to the keys of a subscription which the store failed.
+    async fn on_error(&mut self, request_id: u64, error: cdcpb::Error) -> Result<()> {
+        if let Some(mismatch) = &error.cluster_id_mismatch {
+            return Err(feed_err(format!(
+                "the feed is for cluster {}, the stores are in cluster {}",
+                mismatch.request, mismatch.current
+            )));
+        }
+        if let Some(compatibility) = &error.compatibility {
+            return Err(feed_err(format!(
+                "the stores require TiCDC {}",
+                compatibility.required_version
+            )));
+        }
+        // the region was split or merged, has another leader, or is subscribed twice
+        let subscription = self.subscriptions.remove(&request_id).unwrap();
+        self.resubscribe(subscription, format!("{:?}", error)).await
+    }
+
+    /// Subscribes again to the keys of the subscriptions to the store, whose feed failed.
+    async fn on_store_failure(&mut self, store_id: StoreId, cause: String) -> Result<()> {
+        self.stores.remove(&store_id);
+        let failed: Vec<u64> = self
+            .subscriptions
+            .iter()
+            .filter(|(_, subscription)| subscription.store_id == store_id)
+            .map(|(request_id, _)| *request_id)
+            .collect();
+        for request_id in failed {
+            let subscription = self.subscriptions.remove(&request_id).unwrap();
+            self.resubscribe(subscription, cause.clone()).await?;
+        }
+        Ok(())
+    }
+
+    async fn resubscribe(&mut self, subscription: Subscription, cause: String) -> Result<()> {
+        let Subscription {
+            ver_id,
+            start,
+            end,
+            resolved_ts,
+            mut backoff,
+            ..
+        } = subscription;
+        self.pd_client.invalidate_region_cache(ver_id.clone()).await;
+        match backoff.next_delay_duration() {
+            Some(delay) => self.pd_client.clock().sleep(delay).await,
+            None => {
+                return Err(feed_err(format!(
+                    "the subscription to region {} kept failing: {}",
+                    ver_id.id, cause
+                )))
+            }
+        }
+        self.subscribe(start, end, resolved_ts, backoff).await
+    }
+
+    /// Delivers the changes committed at or before the resolved timestamps of all subscriptions,
+    /// then that timestamp, once it advances.
+    fn resolve(&mut self) {
+        let resolved_ts = match self
+            .subscriptions
+            .values()
+            .map(|subscription| subscription.resolved_ts)
+            .min()
+        {
+            Some(resolved_ts) if resolved_ts > self.resolved_ts => resolved_ts,
+            _ => return,
+        };
+        let unresolved = self.pending.split_off(&(resolved_ts + 1, Key::EMPTY));
+        let resolved = mem::replace(&mut self.pending, unresolved);
+        self.ready
+            .extend(resolved.into_values().map(ChangeEvent::Row));
+        self.ready.push_back(ChangeEvent::Resolved(resolved_ts));
+        self.resolved_ts = resolved_ts;
+    }
+
+    fn next_id(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id
+    }
+}
+
+/// The key in the encoding of the keys of the stores, empty if it is empty, i.e. unbounded.
+fn encode_key(key: &Key) -> Vec<u8> {
+    if key.is_empty() {
+        Vec::new()
+    } else {
+        key.to_encoded().into()
+    }
+}
+
+fn missing_prewrite(commit: &event::Row) -> Error {
+    feed_err(format!(
+        "the commit of key {:?} at {} has no prewrite",
+        Key::from(commit.key.clone()),
+        commit.start_ts
+    ))
+}
+
+fn feed_err(message: String) -> Error {
+    Error::ChangeFeedFailed { message }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mock::{MockKvClient, MockPdClient};
+    use futures::executor::block_on;
+    use std::sync::Mutex;
+    use tikv_client_proto::errorpb;
+
+    fn row(log_type: LogType, key: Vec<u8>, start_ts: u64, commit_ts: u64) -> event::Row {
+        let mut row = event::Row {
+            start_ts,
+            commit_ts,
+            key,
+            ..Default::default()
+        };
+        row.set_type(log_type);
+        row
+    }
+
+    fn put(log_type: LogType, key: Vec<u8>, start_ts: u64, commit_ts: u64) -> event::Row {
+        let mut row = row(log_type, key.clone(), start_ts, commit_ts);
+        row.set_op_type(OpType::Put);
+        row.value = key;
+        row
+    }
+
+    fn region_event(request: &cdcpb::ChangeDataRequest, event: event::Event) -> cdcpb::Event {
+        cdcpb::Event {
+            region_id: request.region_id,
+            request_id: request.request_id,
+            event: Some(event),
+            ..Default::default()
+        }
+    }
+
+    fn entries(request: &cdcpb::ChangeDataRequest, rows: Vec<event::Row>) -> cdcpb::Event {
+        region_event(
+            request,
+            event::Event::Entries(event::Entries { entries: rows }),
+        )
+    }
+
+    fn store_resolved_ts(region_id: RegionId, ts: u64) -> cdcpb::ChangeDataEvent {
+        cdcpb::ChangeDataEvent {
+            events: Vec::new(),
+            resolved_ts: Some(cdcpb::ResolvedTs {
+                regions: vec![region_id],
+                ts,
+            }),
+        }
+    }
+
+    /// The events the stores send for the subscription `request`.
+    #[allow(deprecated)]
+    fn respond(request: &cdcpb::ChangeDataRequest, attempt: usize) -> Vec<cdcpb::ChangeDataEvent> {
+        let events = |events| cdcpb::ChangeDataEvent {
+            events,
+            resolved_ts: None,
+        };
+        match (request.region_id, attempt) {
+            (1, _) => {
+                let mut prewrite = put(LogType::Prewrite, vec![2], 6, 0);
+                prewrite.old_value = vec![9];
+                vec![
+                    events(vec![entries(
+                        request,
+                        vec![
+                            put(LogType::Committed, vec![1], 4, 5),
+                            prewrite,
+                            row(LogType::Initialized, vec![], 0, 0),
+                        ],
+                    )]),
+                    events(vec![
+                        entries(request, vec![row(LogType::Commit, vec![2], 6, 7)]),
+                        // not resolved yet
+                        entries(request, vec![put(LogType::Prewrite, vec![3], 11, 0)]),
+                        region_event(request, event::Event::ResolvedTs(10)),
+                    ]),
+                ]
+            }
+            // the region was split after its initial scan
+            (2, 0) => vec![events(vec![
+                entries(
+                    request,
+                    vec![
+                        put(LogType::Committed, vec![20], 4, 8),
+                        row(LogType::Initialized, vec![], 0, 0),
+                    ],
+                ),
+                region_event(
+                    request,
+                    event::Event::Error(cdcpb::Error {
+                        epoch_not_match: Some(errorpb::EpochNotMatch::default()),
+                        ..Default::default()
+                    }),
+                ),
+            ])],
+            (2, _) => {
+                let mut delete = row(LogType::Prewrite, vec![21], 3, 0);
+                delete.set_op_type(OpType::Delete);
+                vec![
+                    events(vec![entries(
+                        request,
+                        vec![
+                            put(LogType::Committed, vec![20], 4, 8),
+                            // the commit is scanned before the lock of its prewrite
+                            row(LogType::Commit, vec![21], 3, 9),
+                            delete,
+                            row(LogType::Initialized, vec![], 0, 0),
+                        ],
+                    )]),
+                    store_resolved_ts(2, 10),
+                ]
+            }
+            _ => vec![
+                events(vec![entries(
+                    request,
+                    vec![
+                        // out of the range of the feed
+                        put(LogType::Committed, vec![255, 1], 4, 6),
+                        row(LogType::Initialized, vec![], 0, 0),
+                    ],
+                )]),
+                store_resolved_ts(3, 10),
+            ],
+        }
+    }
+
+    #[test]
+    fn test_change_feed() {
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let feed_requests = requests.clone();
+        let client = MockKvClient::default().with_event_feed_hook(move |feed| {
+            let requests = feed_requests.clone();
+            feed.flat_map(move |request: cdcpb::ChangeDataRequest| {
+                let mut requests = requests.lock().unwrap();
+                let attempt = requests
+                    .iter()
+                    .filter(|r: &&cdcpb::ChangeDataRequest| r.region_id == request.region_id)
+                    .count();
+                requests.push(request.clone());
+                stream::iter(respond(&request, attempt).into_iter().map(Ok))
+            })
+            .boxed()
+        });
+        let pd_client = Arc::new(MockPdClient::new(client));
+
+        let options = ChangeFeedOptions::new().checkpoint_ts(1).old_value(true);
+        let range = BoundRange::from(vec![1]..vec![255]);
+        let changes = block_on(change_feed(pd_client, range, 7, options)).unwrap();
+        let events: Vec<ChangeEvent> = block_on(changes.take(5).try_collect()).unwrap();
+
+        let change = |key: Vec<u8>, op, start_ts, commit_ts, old_value| {
+            ChangeEvent::Row(RowChange {
+                key: key.into(),
+                op,
+                start_ts,
+                commit_ts,
+                old_value,
+            })
+        };
+        assert_eq!(
+            events,
+            vec![
+                change(vec![1], RowOp::Put(vec![1]), 4, 5, None),
+                change(vec![2], RowOp::Put(vec![2]), 6, 7, Some(vec![9])),
+                change(vec![20], RowOp::Put(vec![20]), 4, 8, None),
+                change(vec![21], RowOp::Delete, 3, 9, None),
+                ChangeEvent::Resolved(10),
+            ]
+        );
+
+        let encoded = |key: Vec<u8>| Vec::from(Key::from(key).to_encoded());
+        let requests = requests.lock().unwrap();
+        let region1 = requests.iter().find(|r| r.region_id == 1).unwrap();
+        assert_eq!(region1.start_key, encoded(vec![1]));
+        assert_eq!(region1.end_key, encoded(vec![10]));
+        assert_eq!(region1.checkpoint_ts, 1);
+        assert_eq!(region1.get_header().cluster_id, 7);
+        assert_eq!(region1.get_extra_op(), kvrpcpb::ExtraOp::ReadOldValue);
+        let region3 = requests.iter().find(|r| r.region_id == 3).unwrap();
+        assert_eq!(region3.end_key, encoded(vec![255]));
+        // the failed subscription resumed from its resolved timestamp
+        let region2: Vec<_> = requests.iter().filter(|r| r.region_id == 2).collect();
+        assert_eq!(region2.len(), 2);
+        assert_eq!(region2[1].checkpoint_ts, 1);
+    }
+}
@@ -0,0 +1,205 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! The time as seen by the client, so that tests can control it.
+//!
+//! The client reads the time and sleeps through a [`Clock`], set by
+//! [`Config::with_clock`](crate::Config::with_clock): for the backoffs between retries of requests
+//! to TiKV, the heartbeats of transactions and the TTLs of their locks. With a [`MockClock`], a
+//! test can simulate hours of backoffs and heartbeats without waiting.
+
+use futures::{
+    channel::oneshot,
+    future::{self, BoxFuture},
+    FutureExt,
+};
+use std::{
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// A source of the current time, which can sleep.
+pub trait Clock: Send + Sync + 'static {
+    fn now(&self) -> Instant;
+
+    /// Completes once `duration` has passed according to the clock.
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()>;
+}
+
+/// The time of the system.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        futures_timer::Delay::new(duration).boxed()
+    }
+}
+
+/// A clock whose time only passes when the test advances it.
+///
+/// Clones share the time, so a test keeps a clone to advance the clock it passed to the client.
+///
+/// # Examples
+/// ```rust
+/// # use tikv_client::{ClockHandle, Config, MockClock};
+/// # use std::time::Duration;
+/// let clock = MockClock::new();
+/// let config = Config::default().with_clock(ClockHandle::new(clock.clone()));
+/// // ... start a transaction, which sends heartbeats every 10 seconds
+/// clock.advance(Duration::from_secs(60));
+/// ```
+#[derive(Clone, Default)]
+pub struct MockClock {
+    state: Arc<Mutex<MockClockState>>,
+}
+
+#[derive(Default)]
+struct MockClockState {
+    /// The time passed since the clock was created.
+    elapsed: Duration,
+    /// If true, sleeping advances the clock instead of waiting for the test to advance it.
+    auto_advance: bool,
+    /// The sleepers waiting for the clock to pass their deadline.
+    sleepers: Vec<(Duration, oneshot::Sender<()>)>,
+}
+
+impl MockClock {
+    pub fn new() -> MockClock {
+        MockClock::default()
+    }
+
+    /// A clock whose time passes whenever the client sleeps, so backoffs complete immediately
+    /// without the test advancing the clock.
+    pub fn auto_advancing() -> MockClock {
+        let clock = MockClock::new();
+        clock.state.lock().unwrap().auto_advance = true;
+        clock
+    }
+
+    /// Advance the time by `duration`, completing the sleeps which end until then.
+    pub fn advance(&self, duration: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.elapsed += duration;
+        let elapsed = state.elapsed;
+        let (done, waiting) = state
+            .sleepers
+            .drain(..)
+            .partition(|(deadline, _)| *deadline <= elapsed);
+        state.sleepers = waiting;
+        for (_, sleeper) in done {
+            let _ = sleeper.send(());
+        }
+    }
+
+    /// The time passed since the clock was created.
+    pub fn elapsed(&self) -> Duration {
+        self.state.lock().unwrap().elapsed
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        start_instant() + self.elapsed()
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        let mut state = self.state.lock().unwrap();
+        if state.auto_advance {
+            drop(state);
+            self.advance(duration);
+            return future::ready(()).boxed();
+        }
+        if duration.is_zero() {
+            return future::ready(()).boxed();
+        }
+        let (tx, rx) = oneshot::channel();
+        let deadline = state.elapsed + duration;
+        state.sleepers.push((deadline, tx));
+        rx.map(|_| ()).boxed()
+    }
+}
+
+/// The instant mock clocks start at. All mock clocks share it, so their instants are comparable.
+fn start_instant() -> Instant {
+    lazy_static::lazy_static! {
+        static ref START: Instant = Instant::now();
+    }
+    *START
+}
+
+/// The [`Clock`] of a client, the [`SystemClock`] by default.
+#[derive(Clone)]
+pub struct ClockHandle(Arc<dyn Clock>);
+
+impl ClockHandle {
+    pub fn new(clock: impl Clock) -> ClockHandle {
+        ClockHandle(Arc::new(clock))
+    }
+
+    pub fn now(&self) -> Instant {
+        self.0.now()
+    }
+
+    /// The time passed since `instant`, zero if `instant` is later than now.
+    pub fn elapsed_since(&self, instant: Instant) -> Duration {
+        self.now().saturating_duration_since(instant)
+    }
+
+    pub fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        self.0.sleep(duration)
+    }
+}
+
+impl Default for ClockHandle {
+    fn default() -> ClockHandle {
+        ClockHandle::new(SystemClock)
+    }
+}
+
+/// Handles are equal if they are clones of each other.
+impl PartialEq for ClockHandle {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::as_ptr(&self.0) as *const () == Arc::as_ptr(&other.0) as *const ()
+    }
+}
+
+impl fmt::Debug for ClockHandle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("ClockHandle")
+            .field(&(Arc::as_ptr(&self.0) as *const ()))
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{executor::block_on, poll};
+
+    #[test]
+    fn test_mock_clock() {
+        let clock = MockClock::new();
+        let start = clock.now();
+        let mut sleep = clock.sleep(Duration::from_secs(10));
+        block_on(async {
+            assert!(poll!(&mut sleep).is_pending());
+            clock.advance(Duration::from_secs(5));
+            assert!(poll!(&mut sleep).is_pending());
+            clock.advance(Duration::from_secs(5));
+            assert!(poll!(&mut sleep).is_ready());
+        });
+        assert_eq!(clock.now() - start, Duration::from_secs(10));
+
+        let clock = MockClock::auto_advancing();
+        let handle = ClockHandle::new(clock.clone());
+        let start = handle.now();
+        block_on(handle.sleep(Duration::from_secs(3600)));
+        assert_eq!(handle.elapsed_since(start), Duration::from_secs(3600));
+        assert_eq!(clock.elapsed(), Duration::from_secs(3600));
+    }
+}
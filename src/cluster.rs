@@ -0,0 +1,585 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+use crate::{
+    pd::{PdClient, PdRpcClient},
+    ApiVersion, Config, Key, PdMember, RegionId, RegionWithLeader, Result, StoreId,
+};
+use futures::future;
+use semver::Version;
+use slog::{Drain, Logger};
+use std::{collections::BTreeMap, sync::Arc};
+use tikv_client_proto::{metapb, pdpb};
+
+/// A connection to a TiKV cluster which can be shared by several clients.
+///
+/// Every client created with [`RawClient::new`](crate::RawClient::new) or
+/// [`TransactionClient::new`](crate::TransactionClient::new) connects to PD and to TiKV stores on
+/// its own, and has its own region cache. Clients created from the same `Cluster` with
+/// [`RawClient::new_with_cluster`](crate::RawClient::new_with_cluster) or
+/// [`TransactionClient::new_with_cluster`](crate::TransactionClient::new_with_cluster) instead
+/// share the connection to PD, the region cache and the connections to TiKV stores, so regions are
+/// only fetched from PD once for all of them.
+///
+/// A `Cluster` is cheap to clone and clones refer to the same connection.
+///
+/// # Examples
+///
+/// ```rust,no_run
+/// # use tikv_client::{Cluster, Config, RawClient, TransactionClient};
+/// # use futures::prelude::*;
+/// # futures::executor::block_on(async {
+/// let cluster = Cluster::connect(vec!["192.168.0.100"], Config::default(), None)
+///     .await
+///     .unwrap();
+/// let raw = RawClient::new_with_cluster(&cluster);
+/// let txn = TransactionClient::new_with_cluster(&cluster);
+/// # });
+/// ```
+#[derive(Clone)]
+pub struct Cluster {
+    pd: Arc<PdRpcClient>,
+    info: Arc<ClusterInfo>,
+    logger: Logger,
+}
+
+impl Cluster {
+    /// Connect to the TiKV cluster.
+    ///
+    /// Because TiKV is managed by a [PD](https://github.com/pingcap/pd/) cluster, the endpoints for
+    /// PD must be provided, not the TiKV nodes. It's important to include more than one PD endpoint
+    /// (include all endpoints, if possible), this helps avoid having a single point of failure.
+    pub async fn connect<S: Into<String>>(
+        pd_endpoints: Vec<S>,
+        config: Config,
+        optional_logger: Option<Logger>,
+    ) -> Result<Cluster> {
+        let logger = optional_logger.unwrap_or_else(|| {
+            let plain = slog_term::PlainSyncDecorator::new(std::io::stdout());
+            Logger::root(
+                slog_term::FullFormat::new(plain)
+                    .build()
+                    .filter_level(slog::Level::Info)
+                    .fuse(),
+                o!(),
+            )
+        });
+        debug!(logger, "connecting to cluster");
+        let pd_endpoints: Vec<String> = pd_endpoints.into_iter().map(Into::into).collect();
+        let pd =
+            Arc::new(PdRpcClient::connect(&pd_endpoints, config, false, logger.clone()).await?);
+        let info = Arc::new(ClusterInfo::fetch(pd.clone(), &logger).await);
+        Ok(Cluster { pd, info, logger })
+    }
+
+    /// The versions and features of the cluster, as fetched when connecting.
+    pub fn info(&self) -> &ClusterInfo {
+        &self.info
+    }
+
+    /// A client for PD and TiKV which shares all connections and caches with this cluster.
+    pub(crate) fn pd_client(&self, enable_codec: bool) -> Arc<PdRpcClient> {
+        Arc::new(self.pd.with_codec(enable_codec))
+    }
+
+    pub(crate) fn logger(&self) -> &Logger {
+        &self.logger
+    }
+
+    pub(crate) fn shared_info(&self) -> Arc<ClusterInfo> {
+        self.info.clone()
+    }
+
+    /// The id of the cluster, which PD assigns when the cluster is created.
+    pub async fn cluster_id(&self) -> u64 {
+        self.pd.cluster_id().await
+    }
+
+    /// List the members of the PD cluster.
+    pub async fn members(&self) -> Result<Vec<PdMember>> {
+        self.pd.clone().members().await
+    }
+
+    /// List all stores of the cluster, including TiFlash stores and removed stores, fetched from
+    /// PD.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # use tikv_client::{Cluster, Config, StoreState};
+    /// # use futures::prelude::*;
+    /// # futures::executor::block_on(async {
+    /// # let cluster = Cluster::connect(vec!["192.168.0.100"], Config::default(), None)
+    /// #     .await
+    /// #     .unwrap();
+    /// for store in cluster.stores().await.unwrap() {
+    ///     if store.state != StoreState::Up {
+    ///         println!("store {} at {} is {:?}", store.id, store.address, store.state);
+    ///     }
+    /// }
+    /// # });
+    /// ```
+    pub async fn stores(&self) -> Result<Vec<StoreInfo>> {
+        let stores = self.pd.clone().stores().await?;
+        Ok(stores.into_iter().map(StoreInfo::from).collect())
+    }
+
+    /// The disk usage and the number of regions of the stores of the cluster which have not been
+    /// removed, as last reported to PD, see [`StoreStats`].
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # use tikv_client::{Cluster, Config};
+    /// # use futures::prelude::*;
+    /// # futures::executor::block_on(async {
+    /// # let cluster = Cluster::connect(vec!["192.168.0.100"], Config::default(), None)
+    /// #     .await
+    /// #     .unwrap();
+    /// for stats in cluster.store_stats().await.unwrap() {
+    ///     if stats.available_ratio() < 0.1 {
+    ///         // throttle the writes to the store
+    ///     }
+    /// }
+    /// # });
+    /// ```
+    pub async fn store_stats(&self) -> Result<Vec<StoreStats>> {
+        fetch_store_stats(self.pd.clone()).await
+    }
+
+    /// The region containing the raw key `key`, from the region cache or fetched from PD.
+    ///
+    /// Transactional keys are stored encoded, use
+    /// [`TransactionClient::region_for_key`](crate::TransactionClient::region_for_key) for them.
+    pub async fn region_for_key(&self, key: impl Into<Key>) -> Result<RegionInfo> {
+        let region = self.pd.region_for_key(&key.into()).await?;
+        Ok(RegionInfo::from(region))
+    }
+
+    /// The region `id`, from the region cache or fetched from PD. Its keys are raw keys.
+    pub async fn region_for_id(&self, id: RegionId) -> Result<RegionInfo> {
+        let region = self.pd.region_for_id(id).await?;
+        Ok(RegionInfo::from(region))
+    }
+
+    /// The current GC safepoint of the cluster.
+    pub async fn gc_safepoint(&self) -> Result<u64> {
+        self.pd.clone().get_gc_safepoint().await
+    }
+}
+
+/// A store of a cluster, see [`Cluster::stores`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct StoreInfo {
+    pub id: StoreId,
+    /// The address clients connect to.
+    pub address: String,
+    /// The labels of the store, e.g. its zone, and `engine = "tiflash"` for TiFlash stores.
+    pub labels: BTreeMap<String, String>,
+    pub state: StoreState,
+    /// The version of TiKV or TiFlash the store runs.
+    pub version: String,
+}
+
+impl StoreInfo {
+    /// Whether the store is a TiFlash store.
+    pub fn is_tiflash(&self) -> bool {
+        self.labels.get("engine").map(String::as_str) == Some("tiflash")
+    }
+}
+
+impl From<metapb::Store> for StoreInfo {
+    fn from(mut store: metapb::Store) -> StoreInfo {
+        StoreInfo {
+            id: store.get_id(),
+            address: store.take_address(),
+            labels: store
+                .take_labels()
+                .into_iter()
+                .map(|label| (label.key, label.value))
+                .collect(),
+            state: store.get_state().into(),
+            version: store.take_version(),
+        }
+    }
+}
+
+/// The disk usage and the number of regions of a store, as last reported to PD, see
+/// [`Cluster::store_stats`].
+///
+/// Stores report their statistics to PD every few seconds, so they may be slightly behind. PD only
+/// serves the number of leaders of a store over its HTTP API, which the client doesn't use.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct StoreStats {
+    pub store_id: StoreId,
+    /// The size of the disk of the store, in bytes.
+    pub capacity: u64,
+    /// The free space on the disk of the store, in bytes. TiKV stops accepting writes when it is
+    /// almost full.
+    pub available: u64,
+    /// The size of the data of the store, in bytes.
+    pub used_size: u64,
+    /// The number of regions with a peer in the store.
+    pub region_count: u32,
+    /// Whether the store reported being too busy to handle more requests.
+    pub is_busy: bool,
+}
+
+impl StoreStats {
+    /// The free fraction of the disk of the store, between 0 and 1, or 0 if the store reported no
+    /// capacity.
+    pub fn available_ratio(&self) -> f64 {
+        if self.capacity == 0 {
+            0.0
+        } else {
+            self.available as f64 / self.capacity as f64
+        }
+    }
+}
+
+impl From<pdpb::StoreStats> for StoreStats {
+    fn from(stats: pdpb::StoreStats) -> StoreStats {
+        StoreStats {
+            store_id: stats.get_store_id(),
+            capacity: stats.get_capacity(),
+            available: stats.get_available(),
+            used_size: stats.get_used_size(),
+            region_count: stats.get_region_count(),
+            is_busy: stats.get_is_busy(),
+        }
+    }
+}
+
+/// The statistics of the stores which have not been removed, fetched concurrently.
+pub(crate) async fn fetch_store_stats(pd: Arc<impl PdClient>) -> Result<Vec<StoreStats>> {
+    let stores = pd.clone().stores().await?;
+    let stats = stores
+        .iter()
+        .filter(|store| store.get_state() != metapb::StoreState::Tombstone)
+        .map(|store| pd.clone().store_stats(store.get_id()));
+    Ok(future::try_join_all(stats)
+        .await?
+        .into_iter()
+        .map(StoreStats::from)
+        .collect())
+}
+
+/// The state of a store in PD.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum StoreState {
+    /// The store serves requests.
+    Up,
+    /// The store is being removed, its regions are moved to other stores.
+    Offline,
+    /// The store has been removed.
+    Tombstone,
+}
+
+impl From<metapb::StoreState> for StoreState {
+    fn from(state: metapb::StoreState) -> StoreState {
+        match state {
+            metapb::StoreState::Up => StoreState::Up,
+            metapb::StoreState::Offline => StoreState::Offline,
+            metapb::StoreState::Tombstone => StoreState::Tombstone,
+        }
+    }
+}
+
+/// A region of a cluster and its peers, see [`Cluster::region_for_key`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RegionInfo {
+    pub id: RegionId,
+    pub start_key: Key,
+    /// The exclusive end of the keys of the region, empty for the last region.
+    pub end_key: Key,
+    /// The version of the configuration of the peers, incremented by each change of the peers.
+    pub conf_ver: u64,
+    /// The version of the range, incremented by each split or merge.
+    pub version: u64,
+    pub peers: Vec<PeerInfo>,
+    /// The leader, `None` if the region has no leader, e.g. during an election.
+    pub leader: Option<PeerInfo>,
+}
+
+impl From<RegionWithLeader> for RegionInfo {
+    fn from(region: RegionWithLeader) -> RegionInfo {
+        let RegionWithLeader {
+            mut region, leader, ..
+        } = region;
+        let epoch = region.take_region_epoch();
+        RegionInfo {
+            id: region.get_id(),
+            start_key: region.take_start_key().into(),
+            end_key: region.take_end_key().into(),
+            conf_ver: epoch.get_conf_ver(),
+            version: epoch.get_version(),
+            peers: region
+                .take_peers()
+                .into_iter()
+                .map(PeerInfo::from)
+                .collect(),
+            leader: leader.map(PeerInfo::from),
+        }
+    }
+}
+
+/// A replica of a region, see [`RegionInfo`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PeerInfo {
+    pub id: u64,
+    pub store_id: StoreId,
+    /// Whether the peer is a learner, which replicates the region without voting.
+    pub is_learner: bool,
+    /// Whether the peer is a witness, which votes without storing the data of the region.
+    pub is_witness: bool,
+}
+
+impl From<metapb::Peer> for PeerInfo {
+    fn from(peer: metapb::Peer) -> PeerInfo {
+        PeerInfo {
+            id: peer.get_id(),
+            store_id: peer.get_store_id(),
+            is_learner: peer.get_role() == metapb::PeerRole::Learner,
+            is_witness: peer.get_is_witness(),
+        }
+    }
+}
+
+/// The versions of the servers of a TiKV cluster and the features they support.
+///
+/// Clients fetch the information from PD when they connect, see
+/// [`TransactionClient::cluster_info`](crate::TransactionClient::cluster_info) and
+/// [`RawClient::cluster_info`](crate::RawClient::cluster_info). Features are supported if all TiKV
+/// stores of the cluster support them. If the versions could not be fetched, all features are
+/// assumed to be supported, and using an unsupported feature fails at request time.
+///
+/// Clients don't use features the cluster doesn't support: transactions fall back to two-phase
+/// commit if async commit or 1PC is not supported.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ClusterInfo {
+    /// The version of the PD leader, `None` if unknown.
+    pub pd_version: Option<Version>,
+    /// The versions of the TiKV stores which are not removed, by store id. TiFlash stores are not
+    /// included.
+    pub tikv_versions: BTreeMap<StoreId, Version>,
+    /// What raw requests are supported, `None` if unknown. Only raw clients created with
+    /// [`RawClient::new`](crate::RawClient::new) or
+    /// [`RawClient::new_with_config`](crate::RawClient::new_with_config) detect it.
+    pub api_version: Option<ApiVersion>,
+}
+
+impl ClusterInfo {
+    /// Fetch the versions of the cluster. Failures are logged and leave the versions unknown, so
+    /// that connecting doesn't fail because of them.
+    pub(crate) async fn fetch(pd: Arc<impl PdClient>, logger: &Logger) -> ClusterInfo {
+        let pd_version = match pd.clone().pd_version().await {
+            Ok(version) => parse_version(&version),
+            Err(e) => {
+                warn!(logger, "failed to fetch the version of PD: {}", e);
+                None
+            }
+        };
+        let stores = match pd.stores().await {
+            Ok(stores) => stores,
+            Err(e) => {
+                warn!(logger, "failed to fetch the stores of the cluster: {}", e);
+                Vec::new()
+            }
+        };
+        let info = ClusterInfo::new(pd_version, &stores);
+        debug!(logger, "fetched cluster info"; "info" => ?info);
+        info
+    }
+
+    fn new(pd_version: Option<Version>, stores: &[metapb::Store]) -> ClusterInfo {
+        let tikv_versions = stores
+            .iter()
+            .filter(|store| is_tikv_store(store))
+            .filter_map(|store| Some((store.get_id(), parse_version(store.get_version())?)))
+            .collect();
+        ClusterInfo {
+            pd_version,
+            tikv_versions,
+            api_version: None,
+        }
+    }
+
+    /// The oldest version of the TiKV stores, `None` if unknown.
+    pub fn min_tikv_version(&self) -> Option<&Version> {
+        self.tikv_versions.values().min()
+    }
+
+    /// Whether transactions can use async commit (TiKV 5.0 or later).
+    pub fn supports_async_commit(&self) -> bool {
+        self.tikv_at_least(5, 0)
+    }
+
+    /// Whether transactions can be committed in one phase (TiKV 5.0 or later).
+    pub fn supports_one_pc(&self) -> bool {
+        self.tikv_at_least(5, 0)
+    }
+
+    /// Whether followers can serve raw reads as replica reads (TiKV 5.0 or later).
+    pub fn supports_raw_replica_read(&self) -> bool {
+        self.tikv_at_least(5, 0)
+    }
+
+    /// Whether regions report buckets (TiKV 6.1 or later).
+    pub fn supports_buckets(&self) -> bool {
+        self.tikv_at_least(6, 1)
+    }
+
+    /// Whether requests can be assigned to resource groups (TiKV 7.0 or later).
+    pub fn supports_resource_control(&self) -> bool {
+        self.tikv_at_least(7, 0)
+    }
+
+    fn tikv_at_least(&self, major: u64, minor: u64) -> bool {
+        // pre-releases of a version are considered to have the features of the version
+        self.min_tikv_version()
+            .is_none_or(|v| (v.major, v.minor) >= (major, minor))
+    }
+}
+
+/// Whether `store` is a TiKV store which has not been removed, i.e. neither a TiFlash store nor a
+/// tombstone.
+pub(crate) fn is_tikv_store(store: &metapb::Store) -> bool {
+    store.get_state() != metapb::StoreState::Tombstone
+        && !store
+            .get_labels()
+            .iter()
+            .any(|label| label.get_key() == "engine" && label.get_value() == "tiflash")
+}
+
+/// Parses versions like "5.0.1" or "v6.1.0-alpha", `None` if the version is malformed.
+fn parse_version(version: &str) -> Option<Version> {
+    Version::parse(version.trim_start_matches('v')).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockPdClient;
+
+    fn store(id: StoreId, version: &str) -> metapb::Store {
+        metapb::Store {
+            id,
+            version: version.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_store_and_region_info() {
+        let mut tiflash = store(4, "v5.0.0");
+        tiflash.address = "tiflash4".to_owned();
+        tiflash.set_state(metapb::StoreState::Offline);
+        tiflash.mut_labels().push(metapb::StoreLabel {
+            key: "engine".to_owned(),
+            value: "tiflash".to_owned(),
+        });
+        let info = StoreInfo::from(tiflash);
+        assert_eq!(info.address, "tiflash4");
+        assert_eq!(info.state, StoreState::Offline);
+        assert_eq!(info.version, "v5.0.0");
+        assert!(info.is_tiflash());
+        assert!(!StoreInfo::from(store(1, "v5.0.0")).is_tiflash());
+
+        let peer = |id: u64, role: metapb::PeerRole| {
+            let mut peer = metapb::Peer {
+                id,
+                store_id: id + 10,
+                ..Default::default()
+            };
+            peer.set_role(role);
+            peer
+        };
+        let mut region = metapb::Region {
+            id: 7,
+            start_key: vec![1],
+            end_key: vec![9],
+            peers: vec![
+                peer(1, metapb::PeerRole::Voter),
+                peer(2, metapb::PeerRole::Learner),
+            ],
+            ..Default::default()
+        };
+        region.mut_region_epoch().set_conf_ver(2);
+        region.mut_region_epoch().set_version(3);
+        let info = RegionInfo::from(RegionWithLeader {
+            region,
+            leader: Some(peer(1, metapb::PeerRole::Voter)),
+            ..Default::default()
+        });
+        assert_eq!((info.id, info.conf_ver, info.version), (7, 2, 3));
+        assert_eq!(
+            (info.start_key, info.end_key),
+            (vec![1].into(), vec![9].into())
+        );
+        assert_eq!(info.peers.len(), 2);
+        assert!(info.peers[1].is_learner);
+        assert_eq!(info.leader.unwrap().store_id, 11);
+    }
+
+    #[test]
+    fn test_cluster_info() {
+        let unknown = ClusterInfo::default();
+        assert!(unknown.supports_async_commit());
+        assert!(unknown.supports_resource_control());
+
+        let mut tiflash = store(4, "4.0.0");
+        tiflash.mut_labels().push(metapb::StoreLabel {
+            key: "engine".to_owned(),
+            value: "tiflash".to_owned(),
+        });
+        let mut removed = store(5, "3.0.0");
+        removed.set_state(metapb::StoreState::Tombstone);
+        let stores = vec![
+            store(1, "v6.1.0-alpha"),
+            store(2, "5.4.2"),
+            store(3, "nightly"),
+            tiflash,
+            removed,
+        ];
+        let info = ClusterInfo::new(parse_version("v6.5.0"), &stores);
+        assert_eq!(info.pd_version, Some(Version::new(6, 5, 0)));
+        assert_eq!(info.tikv_versions.len(), 2);
+        assert_eq!(info.min_tikv_version(), Some(&Version::new(5, 4, 2)));
+        assert!(info.supports_async_commit());
+        assert!(info.supports_one_pc());
+        assert!(!info.supports_buckets());
+        assert!(!info.supports_resource_control());
+
+        let info = ClusterInfo::new(None, &[store(1, "4.0.10")]);
+        assert!(!info.supports_async_commit());
+        assert!(!info.supports_raw_replica_read());
+    }
+
+    #[tokio::test]
+    async fn test_store_stats() {
+        // the removed store 44 is left out
+        let pd = Arc::new(MockPdClient::default());
+        let stats = fetch_store_stats(pd).await.unwrap();
+        let ids: Vec<StoreId> = stats.iter().map(|stats| stats.store_id).collect();
+        assert_eq!(ids, vec![41, 42, 43]);
+        assert_eq!(
+            stats[0],
+            StoreStats {
+                store_id: 41,
+                capacity: 1 << 30,
+                available: 3 << 28,
+                used_size: 1 << 28,
+                region_count: 1,
+                is_busy: false,
+            }
+        );
+        assert_eq!(stats[0].available_ratio(), 0.75);
+
+        let unknown = StoreStats::from(pdpb::StoreStats {
+            store_id: 1,
+            is_busy: true,
+            ..Default::default()
+        });
+        assert!(unknown.is_busy);
+        assert_eq!(unknown.available_ratio(), 0.0);
+    }
+}
@@ -1,7 +1,16 @@
 // Copyright 2019 TiKV Project Authors. Licensed under Apache-2.0.
 
+use crate::{
+    backoff::default_pd_backoff,
+    clock::ClockHandle,
+    interceptor::{Interceptor, InterceptorHandle},
+    key_validator::{KeyValidator, KeyValidatorHandle},
+    region_cache::{RegionCacheBackend, RegionCacheBackendHandle},
+    Backoff, Error, Key, Result,
+};
 use serde_derive::{Deserialize, Serialize};
-use std::{path::PathBuf, time::Duration};
+use std::{collections::HashMap, path::PathBuf, time::Duration};
+use tikv_client_common::security::ChannelOptions;
 
 /// The configuration for either a [`RawClient`](crate::RawClient) or a
 /// [`TransactionClient`](crate::TransactionClient).
@@ -15,10 +24,185 @@ pub struct Config {
     pub ca_path: Option<PathBuf>,
     pub cert_path: Option<PathBuf>,
     pub key_path: Option<PathBuf>,
+    /// The name the certificates of PD and TiKV are verified against instead of the hosts of
+    /// their addresses, see [`with_tls_server_name`](Config::with_tls_server_name).
+    pub tls_server_name: Option<String>,
     pub timeout: Duration,
+    pub store_concurrency: usize,
+    pub pd_endpoint_priorities: HashMap<String, u32>,
+    /// The zone of the client, used to find replicas close to it.
+    pub zone: Option<String>,
+    /// How stale reads choose the replica to read from.
+    pub replica_selection: ReplicaSelection,
+    /// The backoff between reconnects to PD when a request to PD fails.
+    #[serde(skip)]
+    pub pd_retry_backoff: Backoff,
+    /// The maximum sizes of the keys and values written by the client.
+    pub size_limits: SizeLimits,
+    /// Where regions are cached, the built-in in-memory cache of the client is used if `None`.
+    #[serde(skip)]
+    pub region_cache_backend: Option<RegionCacheBackendHandle>,
+    /// The time as seen by the client, see [`with_clock`](Config::with_clock).
+    #[serde(skip)]
+    pub clock: ClockHandle,
+    /// The interceptors of the requests sent to TiKV, in the order in which they run, see
+    /// [`with_interceptor`](Config::with_interceptor).
+    #[serde(skip)]
+    pub interceptors: Vec<InterceptorHandle>,
+    /// The checks of the keys written by the clients, in the order in which they run, see
+    /// [`with_key_validator`](Config::with_key_validator).
+    #[serde(skip)]
+    pub key_validators: Vec<KeyValidatorHandle>,
+    /// The name of the keyspace the keys of the clients are in, see
+    /// [`with_keyspace`](Config::with_keyspace).
+    pub keyspace: Option<String>,
+    /// The maximum number of requests in a message of the `BatchCommands` stream of a store,
+    /// requests are unary calls if `None`, see
+    /// [`with_batch_commands`](Config::with_batch_commands).
+    pub batch_commands: Option<usize>,
+    /// Timestamps fetched from PD ahead of time, every timestamp is fetched when it is needed if
+    /// `None`, see [`with_timestamp_prefetch`](Config::with_timestamp_prefetch).
+    pub timestamp_prefetch: Option<TimestampPrefetch>,
+    /// How the most used regions are refreshed in the background, regions are only refreshed
+    /// once a request to them fails if `None`, see
+    /// [`with_region_refresh`](Config::with_region_refresh).
+    pub region_refresh: Option<RegionRefresh>,
+    /// The number of gRPC connections to each TiKV store, see
+    /// [`with_connections_per_store`](Config::with_connections_per_store).
+    pub connections_per_store: usize,
+    /// How the gRPC connections to PD and TiKV are checked to be alive, see
+    /// [`with_keepalive`](Config::with_keepalive).
+    pub keepalive: Keepalive,
+    /// The maximum size of a gRPC message sent to or received from PD and TiKV, the default of
+    /// gRPC if `None`, see [`with_max_message_size`](Config::with_max_message_size).
+    pub max_message_size: Option<usize>,
+    /// How long a gRPC connection without requests is kept open, forever if `None`, see
+    /// [`with_idle_timeout`](Config::with_idle_timeout).
+    pub idle_timeout: Option<Duration>,
+}
+
+/// How often the gRPC connections are pinged and how long the pings wait for their
+/// acknowledgement, see [`Config::with_keepalive`].
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct Keepalive {
+    /// The time between two pings.
+    pub interval: Duration,
+    /// How long a ping waits for its acknowledgement before the connection is closed.
+    pub timeout: Duration,
+}
+
+impl Default for Keepalive {
+    fn default() -> Keepalive {
+        Keepalive {
+            interval: Duration::from_secs(10),
+            timeout: Duration::from_secs(3),
+        }
+    }
+}
+
+/// How many timestamps are fetched from PD at once and for how long they are handed out, see
+/// [`Config::with_timestamp_prefetch`].
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct TimestampPrefetch {
+    /// The number of timestamps fetched at once.
+    pub count: u32,
+    /// How long after they were fetched the timestamps are handed out.
+    pub max_age: Duration,
+}
+
+/// How often and how many of the most used regions are refreshed in the background, see
+/// [`Config::with_region_refresh`].
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct RegionRefresh {
+    /// The time between two refreshes.
+    pub interval: Duration,
+    /// The maximum number of regions loaded from PD by a refresh.
+    pub max_regions: u32,
+}
+
+/// How stale reads, which can be served by any replica, choose the replica to read from, see
+/// [`Config::with_replica_selection`].
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ReplicaSelection {
+    /// A replica on a store in the [zone](Config::with_zone) of the client, or the leader if
+    /// there is none.
+    Zone,
+    /// The replica on the healthy store with the lowest average latency. The latency of the
+    /// other stores is sampled again every 10 seconds, so that a store which recovered from being
+    /// slow or unreachable is used again. Falls back to [`Zone`](ReplicaSelection::Zone) until
+    /// the latency of a store is known.
+    Fastest,
+}
+
+/// The maximum sizes of the keys and values written by a client, see
+/// [`Config::with_size_limits`].
+///
+/// Writes are checked before they are sent to TiKV, so that a write which TiKV would reject fails
+/// at once with [`KeyTooLarge`](Error::KeyTooLarge) or [`ValueTooLarge`](Error::ValueTooLarge),
+/// rather than when a transaction commits.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, Eq, PartialEq)]
+#[serde(default)]
+#[serde(rename_all = "kebab-case")]
+pub struct SizeLimits {
+    /// The maximum size of a key in bytes, 8 KiB by default like the `max-key-size` of TiKV.
+    pub max_key_size: usize,
+    /// The maximum size of a value in bytes, 6 MiB by default like the `txn-entry-size-limit` of
+    /// TiDB, which keeps a write well below the `raft-entry-max-size` of TiKV.
+    pub max_value_size: usize,
+}
+
+impl Default for SizeLimits {
+    fn default() -> SizeLimits {
+        SizeLimits {
+            max_key_size: 8 * 1024,
+            max_value_size: 6 * 1024 * 1024,
+        }
+    }
+}
+
+impl SizeLimits {
+    /// Checks the sizes of a write of `key`, with `value` unless it is a deletion.
+    pub(crate) fn check(&self, key: &Key, value: Option<&[u8]>) -> Result<()> {
+        if key.len() > self.max_key_size {
+            return Err(Error::KeyTooLarge {
+                key: key.clone().into(),
+                size: key.len(),
+                limit: self.max_key_size,
+            });
+        }
+        match value {
+            Some(value) if value.len() > self.max_value_size => Err(Error::ValueTooLarge {
+                key: key.clone().into(),
+                size: value.len(),
+                limit: self.max_value_size,
+            }),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// A preset of the configuration for a kind of workload, see [`Config::profile`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Profile {
+    /// Interactive requests, e.g. of an online service, which should rather fail fast than wait:
+    /// a timeout of 500 milliseconds, short backoffs when PD is unreachable, and reads avoiding
+    /// slow replicas.
+    LowLatency,
+    /// Many concurrent requests, which should keep the cluster busy: 256 requests in flight to
+    /// each store, and the default timeout and backoffs.
+    Throughput,
+    /// Long-running jobs, e.g. imports or exports, which should not fail on a slow request or a
+    /// PD failover, nor crowd out other clients: a timeout of 20 seconds, backoffs of up to 10
+    /// seconds when PD is unreachable, and 16 requests in flight to each store.
+    BatchJob,
 }
 
 const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(2);
+const DEFAULT_STORE_CONCURRENCY: usize = 64;
 
 impl Default for Config {
     fn default() -> Self {
@@ -26,12 +210,71 @@ impl Default for Config {
             ca_path: None,
             cert_path: None,
             key_path: None,
+            tls_server_name: None,
             timeout: DEFAULT_REQUEST_TIMEOUT,
+            store_concurrency: DEFAULT_STORE_CONCURRENCY,
+            pd_endpoint_priorities: HashMap::new(),
+            zone: None,
+            replica_selection: ReplicaSelection::Zone,
+            pd_retry_backoff: default_pd_backoff(),
+            size_limits: SizeLimits::default(),
+            region_cache_backend: None,
+            clock: ClockHandle::default(),
+            interceptors: Vec::new(),
+            key_validators: Vec::new(),
+            keyspace: None,
+            batch_commands: None,
+            timestamp_prefetch: None,
+            region_refresh: None,
+            connections_per_store: 1,
+            keepalive: Keepalive::default(),
+            max_message_size: None,
+            idle_timeout: None,
         }
     }
 }
 
 impl Config {
+    /// Apply the preset of `profile`, which sets the timeout, the concurrency per store, the
+    /// backoff between reconnects to PD and the replica selection to values which suit the kind
+    /// of workload.
+    ///
+    /// Options set after the profile override it.
+    ///
+    /// # Examples
+    /// ```rust
+    /// # use tikv_client::{Config, Profile};
+    /// # use std::time::Duration;
+    /// let config = Config::default()
+    ///     .profile(Profile::BatchJob)
+    ///     .with_timeout(Duration::from_secs(60));
+    /// ```
+    pub fn profile(self, profile: Profile) -> Self {
+        match profile {
+            Profile::LowLatency => Config {
+                timeout: Duration::from_millis(500),
+                store_concurrency: DEFAULT_STORE_CONCURRENCY,
+                pd_retry_backoff: Backoff::full_jitter_backoff(20, 500, 10),
+                replica_selection: ReplicaSelection::Fastest,
+                ..self
+            },
+            Profile::Throughput => Config {
+                timeout: DEFAULT_REQUEST_TIMEOUT,
+                store_concurrency: 256,
+                pd_retry_backoff: default_pd_backoff(),
+                replica_selection: ReplicaSelection::Zone,
+                ..self
+            },
+            Profile::BatchJob => Config {
+                timeout: Duration::from_secs(20),
+                store_concurrency: 16,
+                pd_retry_backoff: Backoff::full_jitter_backoff(500, 10000, 50),
+                replica_selection: ReplicaSelection::Zone,
+                ..self
+            },
+        }
+    }
+
     /// Set the certificate authority, certificate, and key locations for clients.
     ///
     /// By default, this client will use an insecure connection over instead of one protected by
@@ -60,6 +303,26 @@ impl Config {
         self
     }
 
+    /// Verify the certificates of PD and TiKV against the subject alternative name `name`,
+    /// rather than the hosts of their addresses, when connecting with TLS, see
+    /// [`with_security`](Config::with_security).
+    ///
+    /// The addresses of the stores are reported by PD, often as IP addresses, while the
+    /// certificates of a cluster may be issued for DNS names only. Without TLS, the name is
+    /// ignored.
+    ///
+    /// # Examples
+    /// ```rust
+    /// # use tikv_client::Config;
+    /// let config = Config::default()
+    ///     .with_security("root.ca", "internal.cert", "internal.key")
+    ///     .with_tls_server_name("tikv.internal");
+    /// ```
+    pub fn with_tls_server_name(mut self, name: impl Into<String>) -> Self {
+        self.tls_server_name = Some(name.into());
+        self
+    }
+
     /// Set the timeout for clients.
     ///
     /// The timeout is used for all requests when using or connecting to a TiKV cluster (including
@@ -78,4 +341,449 @@ impl Config {
         self.timeout = timeout;
         self
     }
+
+    /// Set the maximum number of in-flight requests to a single TiKV store.
+    ///
+    /// Requests beyond this limit wait in a per-store queue and are sent in the order they were
+    /// issued, without priorities: a point get issued after a large scan waits for the requests
+    /// of the scan which were queued before it. Multi-region requests send at most 16 requests at
+    /// once, so a limit above that keeps a single scan from taking all the slots of a store.
+    ///
+    /// The default is 64. A limit of 0 is raised to 1.
+    ///
+    /// # Examples
+    /// ```rust
+    /// # use tikv_client::Config;
+    /// let config = Config::default().with_store_concurrency(128);
+    /// ```
+    pub fn with_store_concurrency(mut self, store_concurrency: usize) -> Self {
+        self.store_concurrency = store_concurrency;
+        self
+    }
+
+    /// Set the priority of a PD endpoint.
+    ///
+    /// When connecting or reconnecting to PD, healthy endpoints with a higher priority are
+    /// preferred over ones with a lower priority. Endpoints with no explicit priority have priority
+    /// 0. This is useful when some PD nodes are, e.g., in another availability zone and should only
+    /// be used as a fallback.
+    ///
+    /// # Examples
+    /// ```rust
+    /// # use tikv_client::Config;
+    /// let config = Config::default()
+    ///     .with_pd_endpoint_priority("192.168.0.100:2379", 10)
+    ///     .with_pd_endpoint_priority("10.0.0.100:2379", 1);
+    /// ```
+    pub fn with_pd_endpoint_priority(mut self, endpoint: impl Into<String>, priority: u32) -> Self {
+        self.pd_endpoint_priorities
+            .insert(endpoint.into(), priority);
+        self
+    }
+
+    /// Set the zone the client runs in.
+    ///
+    /// Stale reads, see
+    /// [`TransactionClient::stale_snapshot`](crate::TransactionClient::stale_snapshot), prefer
+    /// replicas on TiKV stores whose `zone` label is `zone`.
+    ///
+    /// # Examples
+    /// ```rust
+    /// # use tikv_client::Config;
+    /// let config = Config::default().with_zone("us-west-1a");
+    /// ```
+    pub fn with_zone(mut self, zone: impl Into<String>) -> Self {
+        self.zone = Some(zone.into());
+        self
+    }
+
+    /// Set how stale reads choose the replica to read from.
+    ///
+    /// By default, stale reads prefer replicas in the zone of the client, see
+    /// [`with_zone`](Config::with_zone). With [`ReplicaSelection::Fastest`], they avoid slow
+    /// stores by tracking the latency of the requests to each store.
+    ///
+    /// # Examples
+    /// ```rust
+    /// # use tikv_client::{Config, ReplicaSelection};
+    /// let config = Config::default().with_replica_selection(ReplicaSelection::Fastest);
+    /// ```
+    pub fn with_replica_selection(mut self, replica_selection: ReplicaSelection) -> Self {
+        self.replica_selection = replica_selection;
+        self
+    }
+
+    /// Set the backoff between reconnects to PD.
+    ///
+    /// When a request to PD, e.g., to look up a region, fails, the client waits according to the
+    /// backoff, reconnects to PD and retries the request. Once the backoff is exhausted, the error
+    /// is returned. Jitter spreads out the reconnects of many clients, so that they do not
+    /// overload PD after it restarts.
+    ///
+    /// The default is a full jitter backoff starting at 100 milliseconds and growing to at most 3
+    /// seconds.
+    ///
+    /// # Examples
+    /// ```rust
+    /// # use tikv_client::{Backoff, Config};
+    /// let config =
+    ///     Config::default().with_pd_retry_backoff(Backoff::full_jitter_backoff(50, 1000, 10));
+    /// ```
+    pub fn with_pd_retry_backoff(mut self, backoff: Backoff) -> Self {
+        self.pd_retry_backoff = backoff;
+        self
+    }
+
+    /// Set the maximum sizes of the keys and values written by clients.
+    ///
+    /// A write which exceeds them fails before it is sent to TiKV. The limits of a transaction or
+    /// of the requests of a raw client can be set with
+    /// [`TransactionOptions::size_limits`](crate::TransactionOptions::size_limits) and
+    /// [`RawOptions::size_limits`](crate::RawOptions::size_limits).
+    ///
+    /// # Examples
+    /// ```rust
+    /// # use tikv_client::{Config, SizeLimits};
+    /// let config = Config::default().with_size_limits(SizeLimits {
+    ///     max_value_size: 1024 * 1024,
+    ///     ..Default::default()
+    /// });
+    /// ```
+    pub fn with_size_limits(mut self, size_limits: SizeLimits) -> Self {
+        self.size_limits = size_limits;
+        self
+    }
+
+    /// Set the backend which stores the region cache of clients.
+    ///
+    /// By default, each client caches regions in its own memory. A custom
+    /// [`RegionCacheBackend`] can, e.g., share cached regions between processes or evict them
+    /// with a custom policy. Clients created with the same `Config` share the backend.
+    ///
+    /// # Examples
+    /// ```rust
+    /// # use tikv_client::{Config, InMemoryRegionCache};
+    /// let config = Config::default().with_region_cache_backend(InMemoryRegionCache::default());
+    /// ```
+    pub fn with_region_cache_backend(mut self, backend: impl RegionCacheBackend) -> Self {
+        self.region_cache_backend = Some(RegionCacheBackendHandle::new(backend));
+        self
+    }
+
+    /// Set the clock the client reads the time from and sleeps with, for the backoffs between
+    /// retries of requests to TiKV, the heartbeats of transactions and the TTLs of their locks.
+    ///
+    /// Meant for tests, which can simulate long timelines with a [`MockClock`](crate::MockClock).
+    /// Timeouts of requests and requests to PD use the time of the system regardless.
+    ///
+    /// # Examples
+    /// ```rust
+    /// # use tikv_client::{ClockHandle, Config, MockClock};
+    /// let config = Config::default().with_clock(ClockHandle::new(MockClock::auto_advancing()));
+    /// ```
+    pub fn with_clock(mut self, clock: ClockHandle) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Add an interceptor which sees every request sent to TiKV and its response, see
+    /// [`Interceptor`].
+    ///
+    /// Interceptors run in the order in which they are added. Clients created with the same
+    /// `Config` share the interceptors.
+    ///
+    /// # Examples
+    /// ```rust
+    /// # use tikv_client::{Config, Interceptor, Next, Result, RpcRequest};
+    /// # use std::any::Any;
+    /// struct Audit;
+    ///
+    /// #[async_trait::async_trait]
+    /// impl Interceptor for Audit {
+    ///     async fn intercept(
+    ///         &self,
+    ///         request: &dyn RpcRequest,
+    ///         next: Next<'_>,
+    ///     ) -> Result<Box<dyn Any>> {
+    ///         println!("sending {} to {}", request.label(), next.address());
+    ///         next.run(request).await
+    ///     }
+    /// }
+    ///
+    /// let config = Config::default().with_interceptor(Audit);
+    /// ```
+    pub fn with_interceptor(mut self, interceptor: impl Interceptor) -> Self {
+        self.interceptors.push(InterceptorHandle::new(interceptor));
+        self
+    }
+
+    /// Add a validator which checks every key written by the clients before any request is
+    /// sent, see [`KeyValidator`].
+    ///
+    /// Validators run in the order in which they are added, a key is rejected by the first
+    /// validator which rejects it. Clients created with the same `Config` share the validators.
+    ///
+    /// # Examples
+    /// ```rust
+    /// # use tikv_client::{Config, Key};
+    /// let config = Config::default().with_key_validator(|key: &Key| {
+    ///     let key: &[u8] = key.into();
+    ///     match key.first() {
+    ///         Some(b'_') => Err("keys starting with '_' are reserved".to_owned()),
+    ///         _ => Ok(()),
+    ///     }
+    /// });
+    /// ```
+    pub fn with_key_validator(mut self, validator: impl KeyValidator) -> Self {
+        self.key_validators.push(KeyValidatorHandle::new(validator));
+        self
+    }
+
+    /// Put the keys of the clients in the keyspace `name` of a cluster running API V2.
+    ///
+    /// The id of the keyspace is loaded from PD when connecting, which fails if the keyspace
+    /// doesn't exist or is not enabled. Keys are prefixed with the keyspace before they are sent
+    /// to TiKV and the prefix is removed from keys returned by TiKV, so the keyspace is
+    /// transparent to the user, and requests use API V2. Raw clients are in the keyspace like
+    /// with [`RawClient::with_keyspace`](crate::RawClient::with_keyspace), transactional clients
+    /// like in a [namespace](crate::TransactionClient::namespace) of the keyspace.
+    /// [`TransactionClient::gc`](crate::TransactionClient::gc) still covers the whole cluster.
+    ///
+    /// # Examples
+    /// ```rust
+    /// # use tikv_client::Config;
+    /// let config = Config::default().with_keyspace("orders");
+    /// ```
+    pub fn with_keyspace(mut self, name: impl Into<String>) -> Self {
+        self.keyspace = Some(name.into());
+        self
+    }
+
+    /// Send the requests to each TiKV store over a single `BatchCommands` stream, instead of a
+    /// unary call per request.
+    ///
+    /// The requests to a store which are issued while a message is being sent to it are sent
+    /// together in the next message, at most `max_batch_size` requests per message. A large
+    /// transaction, whose prewrite and commit send a request per region, then needs a few messages
+    /// over one stream per store instead of a call per region. The requests still count against
+    /// the [concurrency of the store](Config::with_store_concurrency), which can be raised as the
+    /// requests no longer need a stream each.
+    ///
+    /// Requests which TiKV doesn't accept in the stream, e.g. raw compare-and-swaps, are still
+    /// unary calls.
+    ///
+    /// # Examples
+    /// ```rust
+    /// # use tikv_client::Config;
+    /// let config = Config::default()
+    ///     .with_batch_commands(128)
+    ///     .with_store_concurrency(1024);
+    /// ```
+    pub fn with_batch_commands(mut self, max_batch_size: usize) -> Self {
+        self.batch_commands = Some(max_batch_size);
+        self
+    }
+
+    /// Fetch `count` timestamps from PD at once, and hand them out for at most `max_age`.
+    ///
+    /// Timestamps are always fetched over a single stream to PD, with the calls which are waiting
+    /// for a timestamp at the same time sharing a request. Prefetching goes further: once a batch
+    /// is fetched, the next timestamps are handed out without a round trip to PD, so that starting
+    /// a transaction or calling
+    /// [`current_timestamp`](crate::TransactionClient::current_timestamp) costs microseconds under
+    /// load.
+    ///
+    /// **Warning:** a prefetched timestamp may be older than the commits which completed before it
+    /// was handed out, by up to `max_age`. A transaction may then not see writes committed
+    /// shortly before it began, and a read at [`current_timestamp`] isn't guaranteed to see all
+    /// completed writes. Only enable it if the clients can tolerate reads this stale.
+    ///
+    /// [`current_timestamp`]: crate::TransactionClient::current_timestamp
+    ///
+    /// # Examples
+    /// ```rust
+    /// # use tikv_client::Config;
+    /// # use std::time::Duration;
+    /// let config = Config::default().with_timestamp_prefetch(64, Duration::from_millis(1));
+    /// ```
+    pub fn with_timestamp_prefetch(mut self, count: u32, max_age: Duration) -> Self {
+        self.timestamp_prefetch = Some(TimestampPrefetch { count, max_age });
+        self
+    }
+
+    /// Refresh the cached regions which are used the most in the background, every `interval`
+    /// and at most `max_regions` of them at a time.
+    ///
+    /// By default, a cached region is only loaded from PD again once a request to it fails
+    /// because its leader moved or it was split or merged, so that the request pays for an extra
+    /// round trip to TiKV and PD. Refreshing the hot regions ahead of time spares most requests
+    /// this round trip while the cluster is rebalancing, at the cost of up to `max_regions`
+    /// requests to PD per `interval`.
+    ///
+    /// # Examples
+    /// ```rust
+    /// # use tikv_client::Config;
+    /// # use std::time::Duration;
+    /// let config = Config::default().with_region_refresh(Duration::from_secs(10), 64);
+    /// ```
+    pub fn with_region_refresh(mut self, interval: Duration, max_regions: u32) -> Self {
+        self.region_refresh = Some(RegionRefresh {
+            interval,
+            max_regions,
+        });
+        self
+    }
+
+    /// Open `connections` gRPC connections to each TiKV store, and send the requests to a store
+    /// over its connections in turn.
+    ///
+    /// A single HTTP/2 connection per store saturates under many concurrent requests: its
+    /// streams share one TCP connection, its flow control window and one completion queue of
+    /// gRPC. If [`connection_stats`](crate::RawClient::connection_stats) shows requests waiting
+    /// while the latency of the store stays low, more connections help. The
+    /// [concurrency of a store](Config::with_store_concurrency) is shared by its connections.
+    ///
+    /// The default is a single connection, a value of 0 is treated as 1.
+    ///
+    /// # Examples
+    /// ```rust
+    /// # use tikv_client::Config;
+    /// let config = Config::default()
+    ///     .with_connections_per_store(4)
+    ///     .with_store_concurrency(256);
+    /// ```
+    pub fn with_connections_per_store(mut self, connections: usize) -> Self {
+        self.connections_per_store = connections;
+        self
+    }
+
+    /// Ping the gRPC connections to PD and TiKV every `interval`, and close a connection whose
+    /// ping isn't acknowledged within `timeout`, so that a connection broken without being closed,
+    /// e.g. by a firewall dropping idle connections, is detected and opened again.
+    ///
+    /// The default is a ping every 10 seconds with a timeout of 3 seconds.
+    ///
+    /// # Examples
+    /// ```rust
+    /// # use tikv_client::Config;
+    /// # use std::time::Duration;
+    /// let config =
+    ///     Config::default().with_keepalive(Duration::from_secs(30), Duration::from_secs(5));
+    /// ```
+    pub fn with_keepalive(mut self, interval: Duration, timeout: Duration) -> Self {
+        self.keepalive = Keepalive { interval, timeout };
+        self
+    }
+
+    /// Allow the gRPC messages sent to and received from PD and TiKV to be up to `size` bytes,
+    /// e.g. for scans or batch gets of large values whose responses exceed the default limit of
+    /// gRPC on received messages, 4 MiB.
+    ///
+    /// # Examples
+    /// ```rust
+    /// # use tikv_client::Config;
+    /// let config = Config::default().with_max_message_size(64 * 1024 * 1024);
+    /// ```
+    pub fn with_max_message_size(mut self, size: usize) -> Self {
+        self.max_message_size = Some(size);
+        self
+    }
+
+    /// Close the gRPC connections to PD and TiKV which had no request for `timeout`. A closed
+    /// connection is opened again by the next request over it.
+    ///
+    /// By default, connections are kept open as long as the client exists, e.g. to the stores
+    /// which the client no longer sends requests to after their regions moved, or to stores which
+    /// were removed from the cluster.
+    ///
+    /// # Examples
+    /// ```rust
+    /// # use tikv_client::Config;
+    /// # use std::time::Duration;
+    /// let config = Config::default().with_idle_timeout(Duration::from_secs(600));
+    /// ```
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// The options of the gRPC channels to PD and TiKV.
+    pub(crate) fn channel_options(&self) -> ChannelOptions {
+        ChannelOptions {
+            keepalive_time: self.keepalive.interval,
+            keepalive_timeout: self.keepalive.timeout,
+            max_message_size: self.max_message_size,
+            idle_timeout: self.idle_timeout,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_profile() {
+        let config = Config::default().with_zone("us-west-1a");
+        let low_latency = config.clone().profile(Profile::LowLatency);
+        assert_eq!(low_latency.timeout, Duration::from_millis(500));
+        assert_eq!(low_latency.replica_selection, ReplicaSelection::Fastest);
+        // other options are kept
+        assert_eq!(low_latency.zone.as_deref(), Some("us-west-1a"));
+
+        // the last profile wins
+        let throughput = low_latency.profile(Profile::Throughput);
+        assert_eq!(throughput.store_concurrency, 256);
+        assert_eq!(throughput.timeout, config.timeout);
+        assert_eq!(throughput.replica_selection, ReplicaSelection::Zone);
+
+        let batch_job = config.profile(Profile::BatchJob).with_store_concurrency(8);
+        assert_eq!(batch_job.timeout, Duration::from_secs(20));
+        assert_eq!(batch_job.store_concurrency, 8);
+    }
+
+    #[test]
+    fn test_channel_options() {
+        assert_eq!(
+            Config::default().channel_options(),
+            ChannelOptions::default()
+        );
+        let config = Config::default()
+            .with_keepalive(Duration::from_secs(30), Duration::from_secs(5))
+            .with_max_message_size(1 << 26)
+            .with_idle_timeout(Duration::from_secs(600));
+        assert_eq!(
+            config.channel_options(),
+            ChannelOptions {
+                keepalive_time: Duration::from_secs(30),
+                keepalive_timeout: Duration::from_secs(5),
+                max_message_size: Some(1 << 26),
+                idle_timeout: Some(Duration::from_secs(600)),
+            }
+        );
+    }
+
+    #[test]
+    fn test_size_limits() {
+        let limits = SizeLimits {
+            max_key_size: 2,
+            max_value_size: 3,
+        };
+        assert!(limits
+            .check(&vec![1, 2].into(), Some(&[1, 2, 3][..]))
+            .is_ok());
+        assert!(limits.check(&vec![1, 2].into(), None).is_ok());
+        assert!(matches!(
+            limits.check(&vec![1, 2, 3].into(), None),
+            Err(Error::KeyTooLarge {
+                size: 3,
+                limit: 2,
+                ..
+            })
+        ));
+        assert!(matches!(
+            limits.check(&vec![1].into(), Some(&[0; 4][..])),
+            Err(Error::ValueTooLarge { key, size: 4, limit: 3 }) if key == vec![1]
+        ));
+    }
 }
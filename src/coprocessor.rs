@@ -0,0 +1,358 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! Requests to the coprocessor of TiKV, which evaluates computations pushed down to the regions,
+//! e.g. the DAG requests of TiDB, see
+//! [`TransactionClient::coprocessor`](crate::TransactionClient::coprocessor).
+
+use crate::{
+    backoff::{DEFAULT_REGION_BACKOFF, OPTIMISTIC_BACKOFF},
+    pd::PdClient,
+    request::{
+        plan::MULTI_REGION_CONCURRENCY, DefaultProcessor, KvRequest, Plan, PlanBuilder, Process,
+        ResponseWithShard, Shardable,
+    },
+    stats::MetricsLabels,
+    store::{store_stream_for_ranges, RegionStore},
+    timestamp::TimestampExt,
+    transaction::HasLocks,
+    BoundRange, Error, Key, LockPolicy, RawChecksum, RegionId, Result, Timestamp,
+};
+use futures::{prelude::*, stream::BoxStream};
+use prost::Message;
+use std::{iter, ops::Range, sync::Arc};
+use tikv_client_proto::{coprocessor, kvrpcpb};
+
+/// How TiKV interprets the payload of a [`CoprocessorRequest`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CoprocessorRequestType {
+    /// A serialized `tipb::DagRequest`, a tree of executors, e.g. a table scan followed by a
+    /// selection and an aggregation.
+    Dag,
+    /// A serialized `tipb::AnalyzeReq`, which collects the statistics of the ranges.
+    Analyze,
+    /// A serialized `tipb::ChecksumRequest`, which computes the checksum of the ranges.
+    Checksum,
+}
+
+impl CoprocessorRequestType {
+    /// The code of the type in requests to TiKV.
+    fn code(self) -> i64 {
+        match self {
+            CoprocessorRequestType::Dag => 103,
+            CoprocessorRequestType::Analyze => 104,
+            CoprocessorRequestType::Checksum => 105,
+        }
+    }
+}
+
+/// A computation evaluated by the coprocessor of TiKV on the keys in some ranges.
+///
+/// The client doesn't interpret the payload, it is built with the `tipb` protocol of TiDB. The
+/// request is split by region, each region evaluates it on its part of the ranges.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CoprocessorRequest {
+    tp: CoprocessorRequestType,
+    data: Vec<u8>,
+    ranges: Vec<kvrpcpb::KeyRange>,
+}
+
+impl CoprocessorRequest {
+    /// A request of type `tp` with the serialized payload `data`, evaluated on the keys in
+    /// `ranges`, which must not overlap.
+    pub fn new(
+        tp: CoprocessorRequestType,
+        data: impl Into<Vec<u8>>,
+        ranges: impl IntoIterator<Item = impl Into<BoundRange>>,
+    ) -> CoprocessorRequest {
+        let mut ranges: Vec<kvrpcpb::KeyRange> = ranges
+            .into_iter()
+            .map(|range| BoundRange::into(range.into()))
+            .collect();
+        ranges.sort_by(|a, b| a.start_key.cmp(&b.start_key));
+        CoprocessorRequest {
+            tp,
+            data: data.into(),
+            ranges,
+        }
+    }
+}
+
+/// The result of a [`CoprocessorRequest`] in one region.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CoprocessorResponse {
+    pub region_id: RegionId,
+    /// The parts of the ranges of the request in the region.
+    pub ranges: Vec<Range<Key>>,
+    /// The serialized result, e.g. a `tipb::SelectResponse` for a DAG request.
+    pub data: Vec<u8>,
+}
+
+/// The `tipb::ChecksumRequest` of a checksum of the pairs in the ranges, scanned as a table and
+/// computed with CRC-64 like [`RawChecksum`].
+#[derive(Clone, PartialEq, Message)]
+struct ChecksumRequest {
+    #[prost(uint64, tag = "1")]
+    start_ts_deprecated: u64,
+    /// `tipb::ChecksumScanOn::Table`.
+    #[prost(int32, tag = "2")]
+    scan_on: i32,
+    /// `tipb::ChecksumAlgorithm::Crc64Xor`.
+    #[prost(int32, tag = "3")]
+    algorithm: i32,
+}
+
+/// The `tipb::ChecksumResponse` of a region.
+#[derive(Clone, PartialEq, Message)]
+struct ChecksumResponse {
+    #[prost(uint64, tag = "1")]
+    checksum: u64,
+    #[prost(uint64, tag = "2")]
+    total_kvs: u64,
+    #[prost(uint64, tag = "3")]
+    total_bytes: u64,
+}
+
+/// A request computing the checksum of the pairs in `range`.
+pub(crate) fn new_checksum_request(range: BoundRange) -> CoprocessorRequest {
+    let mut data = Vec::new();
+    ChecksumRequest::default()
+        .encode(&mut data)
+        .expect("a Vec grows as needed");
+    CoprocessorRequest::new(CoprocessorRequestType::Checksum, data, iter::once(range))
+}
+
+/// The checksum of a region in the response to a request of [`new_checksum_request`].
+pub(crate) fn decode_checksum(response: CoprocessorResponse) -> Result<RawChecksum> {
+    let resp = ChecksumResponse::decode(response.data.as_slice()).map_err(|e| {
+        Error::StringError(format!(
+            "invalid checksum response of region {}: {}",
+            response.region_id, e
+        ))
+    })?;
+    Ok(RawChecksum {
+        checksum: resp.checksum,
+        total_kvs: resp.total_kvs,
+        total_bytes: resp.total_bytes,
+    })
+}
+
+/// Sends `request` to the regions of its ranges, reading at `timestamp`, and streams the
+/// responses in the order of the regions.
+pub(crate) fn coprocessor_stream<PdC: PdClient>(
+    pd_client: Arc<PdC>,
+    request: CoprocessorRequest,
+    timestamp: Timestamp,
+    labels: MetricsLabels,
+) -> BoxStream<'static, Result<CoprocessorResponse>> {
+    let mut inner = coprocessor::Request {
+        tp: request.tp.code(),
+        data: request.data,
+        start_ts: timestamp.version(),
+        ..Default::default()
+    };
+    pd_client
+        .clone()
+        .group_ranges_by_region(request.ranges)
+        .map_ok(move |(_, ranges)| {
+            inner.set_ranges(ranges.into_iter().map(into_coprocessor_range).collect());
+            let plan = PlanBuilder::new(pd_client.clone(), inner.clone())
+                .labels(labels.clone())
+                .resolve_lock_for_read(
+                    timestamp.clone(),
+                    LockPolicy::ResolveAndWait,
+                    OPTIMISTIC_BACKOFF,
+                )
+                .preserve_shard()
+                .retry_multi_region(DEFAULT_REGION_BACKOFF)
+                .post_process_default()
+                .plan();
+            async move { plan.execute().await }
+        })
+        .try_buffered(MULTI_REGION_CONCURRENCY)
+        .map_ok(|responses| stream::iter(responses.into_iter().map(Ok)))
+        .try_flatten()
+        .boxed()
+}
+
+fn into_coprocessor_range(range: kvrpcpb::KeyRange) -> coprocessor::KeyRange {
+    coprocessor::KeyRange {
+        start: range.start_key,
+        end: range.end_key,
+    }
+}
+
+impl KvRequest for coprocessor::Request {
+    type Response = coprocessor::Response;
+}
+
+/// The region of a shard and the parts of the ranges of the request in it.
+type CoprocessorShard = (RegionId, Vec<kvrpcpb::KeyRange>);
+
+impl Shardable for coprocessor::Request {
+    type Shard = CoprocessorShard;
+
+    fn shards(
+        &self,
+        pd_client: &Arc<impl PdClient>,
+    ) -> BoxStream<'static, Result<(Self::Shard, RegionStore)>> {
+        let ranges = self
+            .ranges
+            .iter()
+            .map(|range| kvrpcpb::KeyRange {
+                start_key: range.start.clone(),
+                end_key: range.end.clone(),
+            })
+            .collect();
+        store_stream_for_ranges(ranges, pd_client.clone())
+            .map_ok(|(ranges, store)| ((store.region_with_leader.id(), ranges), store))
+            .boxed()
+    }
+
+    fn apply_shard(&mut self, shard: Self::Shard, _: &RegionStore) -> Result<()> {
+        self.set_ranges(shard.1.into_iter().map(into_coprocessor_range).collect());
+        Ok(())
+    }
+}
+
+impl HasLocks for coprocessor::Response {
+    fn take_locks(&mut self) -> Vec<kvrpcpb::LockInfo> {
+        self.locked.take().into_iter().collect()
+    }
+}
+
+impl Process<Vec<Result<ResponseWithShard<coprocessor::Response, CoprocessorShard>>>>
+    for DefaultProcessor
+{
+    type Out = Vec<CoprocessorResponse>;
+
+    fn process(
+        &self,
+        input: Result<Vec<Result<ResponseWithShard<coprocessor::Response, CoprocessorShard>>>>,
+    ) -> Result<Self::Out> {
+        input?
+            .into_iter()
+            .map(|shard_resp| {
+                shard_resp.map(|ResponseWithShard(mut resp, (region_id, ranges))| {
+                    CoprocessorResponse {
+                        region_id,
+                        ranges: ranges
+                            .into_iter()
+                            .map(|range| range.start_key.into()..range.end_key.into())
+                            .collect(),
+                        data: resp.take_data(),
+                    }
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{MockKvClient, MockPdClient};
+    use std::any::Any;
+
+    #[tokio::test]
+    async fn test_coprocessor_stream() {
+        let pd_client = Arc::new(MockPdClient::new(MockKvClient::with_dispatch_hook(
+            |req: &dyn Any| {
+                let req = req.downcast_ref::<coprocessor::Request>().unwrap();
+                assert_eq!(req.tp, 103);
+                assert_eq!(req.start_ts, 7);
+                let resp = coprocessor::Response {
+                    data: [req.data.clone(), vec![req.ranges.len() as u8]].concat(),
+                    ..Default::default()
+                };
+                Ok(Box::new(resp) as Box<dyn Any>)
+            },
+        )));
+        let request = CoprocessorRequest::new(
+            CoprocessorRequestType::Dag,
+            b"dag".to_vec(),
+            vec![vec![20]..vec![30], vec![5]..vec![15]],
+        );
+        let responses: Vec<CoprocessorResponse> = coprocessor_stream(
+            pd_client,
+            request,
+            Timestamp::from_version(7),
+            MetricsLabels::default(),
+        )
+        .try_collect()
+        .await
+        .unwrap();
+        assert_eq!(
+            responses,
+            vec![
+                CoprocessorResponse {
+                    region_id: 1,
+                    ranges: vec![Key::from(vec![5])..Key::from(vec![10])],
+                    data: b"dag\x01".to_vec(),
+                },
+                CoprocessorResponse {
+                    region_id: 2,
+                    ranges: vec![
+                        Key::from(vec![10])..Key::from(vec![15]),
+                        Key::from(vec![20])..Key::from(vec![30]),
+                    ],
+                    data: b"dag\x02".to_vec(),
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_checksum() {
+        let pd_client = Arc::new(MockPdClient::new(MockKvClient::with_dispatch_hook(
+            |req: &dyn Any| {
+                let req = req.downcast_ref::<coprocessor::Request>().unwrap();
+                assert_eq!(req.tp, 105);
+                let checksum = ChecksumResponse {
+                    checksum: req.ranges[0].start[0] as u64,
+                    total_kvs: 1,
+                    total_bytes: 2,
+                };
+                let mut data = Vec::new();
+                checksum.encode(&mut data).unwrap();
+                let resp = coprocessor::Response {
+                    data,
+                    ..Default::default()
+                };
+                Ok(Box::new(resp) as Box<dyn Any>)
+            },
+        )));
+        let request = new_checksum_request((vec![5]..vec![15]).into());
+        let checksums: Vec<RawChecksum> = coprocessor_stream(
+            pd_client,
+            request,
+            Timestamp::from_version(7),
+            MetricsLabels::default(),
+        )
+        .and_then(|response| future::ready(decode_checksum(response)))
+        .try_collect()
+        .await
+        .unwrap();
+        assert_eq!(
+            checksums,
+            vec![
+                RawChecksum {
+                    checksum: 5,
+                    total_kvs: 1,
+                    total_bytes: 2,
+                },
+                RawChecksum {
+                    checksum: 10,
+                    total_kvs: 1,
+                    total_bytes: 2,
+                },
+            ]
+        );
+
+        let response = CoprocessorResponse {
+            region_id: 1,
+            ranges: Vec::new(),
+            data: vec![0xff],
+        };
+        assert!(decode_checksum(response).is_err());
+    }
+}
@@ -0,0 +1,184 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! Fuzz targets over untrusted keys and ranges, behind the `fuzzing` feature.
+//!
+//! Each target builds keys and ranges from arbitrary bytes, runs them through the conversions
+//! the client applies to the keys of its users and to the region boundaries returned by PD, and
+//! checks their invariants. A target panics only if an invariant is broken, whatever its input.
+//!
+//! The `fuzz` directory of the repository holds a
+//! [cargo fuzz](https://github.com/rust-fuzz/cargo-fuzz) target for each of them, run with
+//! libFuzzer by, e.g., `cargo +nightly fuzz run key_codec`.
+//!
+//! The unit tests of the crate also run them on inputs generated by proptest.
+
+use crate::{kv::codec, namespace::Namespace, store::range_intersection, BoundRange, Error, Key};
+use std::ops::Bound;
+use tikv_client_proto::kvrpcpb;
+
+/// Keys are at most this long, so that they span a few groups of the memcomparable format.
+const MAX_KEY_LEN: usize = 32;
+
+/// Encodes and decodes keys in the memcomparable format, and decodes arbitrary bytes as region
+/// boundaries.
+pub fn key_codec(data: &[u8]) {
+    let mut input = Input(data);
+    let (a, b) = (input.key(), input.key());
+
+    for desc in [false, true] {
+        let encoded = codec::encode_bytes(&a, desc);
+        assert_eq!(encoded.len() % 9, 0);
+        let mut decoded = encoded.clone();
+        codec::decode_bytes_in_place(&mut decoded, desc).unwrap();
+        assert_eq!(decoded, a);
+        // the bytes following an encoded key are ignored
+        let mut decoded = encoded;
+        decoded.extend_from_slice(&b);
+        codec::decode_bytes_in_place(&mut decoded, desc).unwrap();
+        assert_eq!(decoded, a);
+    }
+
+    // the encoding preserves the order of keys
+    let (a, b) = (Key::from(a), Key::from(b));
+    assert_eq!(a.to_encoded().cmp(&b.to_encoded()), a.cmp(&b));
+
+    // arbitrary bytes are either decoded or left unchanged
+    let mut decoded = data.to_vec();
+    match codec::decode_bytes_in_place(&mut decoded, false) {
+        Ok(()) if data.is_empty() => assert!(decoded.is_empty()),
+        Ok(()) => assert!(data.starts_with(&codec::encode_bytes(&decoded, false))),
+        Err(Error::InvalidEncodedKey { key, .. }) => {
+            assert_eq!(key, data);
+            assert_eq!(decoded, data);
+        }
+        Err(e) => panic!("unexpected error {:?}", e),
+    }
+}
+
+/// Converts ranges between their bounds, their keys and the ranges of requests.
+pub fn bound_range(data: &[u8]) {
+    let mut input = Input(data);
+    let kinds = input.byte();
+    let (start, end) = (input.key(), input.key());
+    let range = BoundRange::from((bound(kinds, start), bound(kinds >> 2, end)));
+
+    let (start, end) = range.clone().into_keys();
+    match range.clone().try_into_keys() {
+        Ok(keys) => assert_eq!(keys, (start.clone(), end.clone())),
+        Err(Error::InvalidRange { start: s, end: e }) => {
+            assert_eq!(Key::from(s), start);
+            assert!(!e.is_empty() && Some(Key::from(e)) == end && start > end.clone().unwrap());
+        }
+        Err(e) => panic!("unexpected error {:?}", e),
+    }
+
+    // the range of a request is a range with the same keys
+    let key_range = kvrpcpb::KeyRange::from(range.clone());
+    assert_eq!(
+        BoundRange::from(key_range).into_keys(),
+        (start.clone(), Some(end.clone().unwrap_or_default()))
+    );
+
+    // a range encoded in a namespace stays in the namespace
+    let name = String::from_utf8_lossy(&input.key()).into_owned();
+    let namespace = Namespace::new(None, &name);
+    let prefix = format!("{}/", name).into_bytes();
+    let (encoded_start, encoded_end) = namespace.encode_range(range).into_keys();
+    assert!(Vec::from(encoded_start.clone()).starts_with(&prefix));
+    assert_eq!(namespace.decode_key(encoded_start).unwrap(), start);
+    let namespace_end = BoundRange::prefix(prefix).into_keys().1;
+    match (encoded_end, namespace_end) {
+        (Some(encoded_end), Some(namespace_end)) => assert!(encoded_end <= namespace_end),
+        (encoded_end, namespace_end) => assert_eq!(encoded_end, namespace_end),
+    }
+}
+
+/// Intersects the ranges of requests with the ranges of regions, an empty end being unbounded.
+pub fn region_range(data: &[u8]) {
+    let mut input = Input(data);
+    let region: (Key, Key) = (input.key().into(), input.key().into());
+    let range: (Key, Key) = (input.key().into(), input.key().into());
+    let (start, end) = range_intersection(region.clone(), range.clone());
+
+    assert_eq!(start, region.0.clone().max(range.0));
+    let ends = [region.1, range.1];
+    if end.is_empty() {
+        assert!(ends.iter().all(Key::is_empty));
+    } else {
+        assert!(ends.contains(&end));
+        assert!(ends.iter().all(|e| e.is_empty() || end <= *e));
+    }
+}
+
+/// The bound of `kind`, given by its last two bits, of `key`.
+fn bound(kind: u8, key: Vec<u8>) -> Bound<Vec<u8>> {
+    match kind & 0b11 {
+        0 | 1 => Bound::Included(key),
+        2 => Bound::Excluded(key),
+        _ => Bound::Unbounded,
+    }
+}
+
+/// Splits the input of a target into bytes and keys, which are empty once it is exhausted.
+struct Input<'a>(&'a [u8]);
+
+impl<'a> Input<'a> {
+    fn byte(&mut self) -> u8 {
+        match self.0.split_first() {
+            Some((byte, rest)) => {
+                self.0 = rest;
+                *byte
+            }
+            None => 0,
+        }
+    }
+
+    /// A key whose length is given by the next byte.
+    fn key(&mut self) -> Vec<u8> {
+        let len = (self.byte() as usize % (MAX_KEY_LEN + 1)).min(self.0.len());
+        let (key, rest) = self.0.split_at(len);
+        self.0 = rest;
+        key.to_vec()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use proptest::{collection::vec, prelude::*};
+
+    proptest! {
+        #[test]
+        fn fuzz_key_codec(data in vec(any::<u8>(), 0..128)) {
+            key_codec(&data);
+        }
+
+        #[test]
+        fn fuzz_bound_range(data in vec(any::<u8>(), 0..128)) {
+            bound_range(&data);
+        }
+
+        #[test]
+        fn fuzz_region_range(data in vec(any::<u8>(), 0..128)) {
+            region_range(&data);
+        }
+    }
+
+    #[test]
+    fn test_targets() {
+        // encoded keys, keys ending with zeros, inverted ranges and exhausted inputs
+        let inputs: Vec<Vec<u8>> = vec![
+            vec![],
+            vec![9, 1, 2, 3, 0, 0, 0, 0, 0, 250],
+            vec![0, 2, 5, 0, 1, 4],
+            vec![1, 2, b'z', 0, 1, b'a', 3, b'n', b's', b'!'],
+            vec![2, 8, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 9],
+            vec![3, 1, 0, 1, 0, 0xff],
+        ];
+        for input in inputs {
+            key_codec(&input);
+            bound_range(&input);
+            region_range(&input);
+        }
+    }
+}
@@ -0,0 +1,520 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! Bulk loading of data through the `ImportSST` service of the TiKV stores, see
+//! [`TransactionClient::import`](crate::TransactionClient::import).
+
+use crate::{
+    backoff::{Backoff, DEFAULT_REGION_BACKOFF},
+    cluster::is_tikv_store,
+    pd::PdClient,
+    region::{RegionWithLeader, StoreId},
+    ColumnFamily, Error, Key, KvPair, Result,
+};
+use futures::{future, prelude::*};
+use std::{any::Any, collections::HashMap, sync::Arc, time::Duration};
+use tikv_client_proto::{
+    errorpb,
+    import_sstpb::{self, upload_request, write_request},
+};
+use tikv_client_store::ImportRequest;
+
+/// The number of pairs in each message of the stream writing the pairs of a region to a store, by
+/// default.
+pub const IMPORT_BATCH_SIZE: usize = 4096;
+/// The number of bytes of keys and values imported at once by default, about the size of a
+/// region.
+pub const IMPORT_REGION_SIZE: u64 = 96 * 1024 * 1024;
+/// The size of the messages uploading a pre-built SST file.
+const UPLOAD_CHUNK_SIZE: usize = 1024 * 1024;
+const DEFAULT_IMPORT_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Options of an [`import`](crate::TransactionClient::import) or an
+/// [`ingest_sst_files`](crate::TransactionClient::ingest_sst_files).
+#[derive(Clone, Debug)]
+pub struct ImportOptions {
+    pub(crate) batch_size: usize,
+    pub(crate) region_size: u64,
+    pub(crate) split_regions: bool,
+    pub(crate) timeout: Duration,
+}
+
+impl Default for ImportOptions {
+    fn default() -> ImportOptions {
+        ImportOptions {
+            batch_size: IMPORT_BATCH_SIZE,
+            region_size: IMPORT_REGION_SIZE,
+            split_regions: true,
+            timeout: DEFAULT_IMPORT_TIMEOUT,
+        }
+    }
+}
+
+impl ImportOptions {
+    pub fn new() -> ImportOptions {
+        ImportOptions::default()
+    }
+
+    /// Write at most `batch_size` pairs in each message sent to a store, [`IMPORT_BATCH_SIZE`]
+    /// by default.
+    pub fn batch_size(mut self, batch_size: usize) -> ImportOptions {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// Import the pairs in chunks of `region_size` bytes of keys and values,
+    /// [`IMPORT_REGION_SIZE`] by default. The pairs of a chunk are held in memory until they are
+    /// ingested.
+    pub fn region_size(mut self, region_size: u64) -> ImportOptions {
+        self.region_size = region_size.max(1);
+        self
+    }
+
+    /// Whether to split the regions at the start of each chunk of pairs or SST file, and to
+    /// scatter the new regions across the stores before ingesting into them, so that the import
+    /// is spread over the cluster instead of filling up a single region. Enabled by default.
+    pub fn split_regions(mut self, split_regions: bool) -> ImportOptions {
+        self.split_regions = split_regions;
+        self
+    }
+
+    /// Fail each call to a store which takes longer than `timeout`, 5 minutes by default. The
+    /// calls carry the data of a whole region, so they take much longer than other requests.
+    pub fn timeout(mut self, timeout: Duration) -> ImportOptions {
+        self.timeout = timeout;
+        self
+    }
+}
+
+/// An SST file built outside of the client, to be ingested with
+/// [`ingest_sst_files`](crate::TransactionClient::ingest_sst_files).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SstFile {
+    /// The column family the file is ingested into.
+    pub cf: ColumnFamily,
+    /// The first key in the file.
+    pub first_key: Key,
+    /// The last key in the file.
+    pub last_key: Key,
+    /// The CRC32 checksum of `data`, which the stores verify.
+    pub crc32: u32,
+    /// The content of the file.
+    pub data: Vec<u8>,
+}
+
+/// What an [`import`](crate::TransactionClient::import) or an
+/// [`ingest_sst_files`](crate::TransactionClient::ingest_sst_files) loaded.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ImportSummary {
+    /// The number of pairs imported, 0 for SST files.
+    pub pairs: u64,
+    /// The number of bytes of keys and values imported, or of the SST files ingested.
+    pub bytes: u64,
+    /// The number of ingestions, each into one region.
+    pub ingests: usize,
+}
+
+/// Imports `pairs`, which must be sorted by key without duplicates, committed at `commit_ts`.
+pub(crate) async fn import_pairs<PdC: PdClient>(
+    pd_client: Arc<PdC>,
+    pairs: impl Stream<Item = Result<KvPair>>,
+    commit_ts: u64,
+    options: &ImportOptions,
+) -> Result<ImportSummary> {
+    let addresses = store_addresses(pd_client.clone()).await?;
+    let mut summary = ImportSummary::default();
+    let mut chunk: Vec<KvPair> = Vec::new();
+    let mut chunk_size = 0;
+    let mut last_key: Option<Key> = None;
+    futures::pin_mut!(pairs);
+    while let Some(pair) = pairs.try_next().await? {
+        if last_key.as_ref().is_some_and(|last| pair.key() <= last) {
+            return Err(Error::ImportFailed {
+                message: format!("key {:?} is not greater than the key before it", pair.key()),
+            });
+        }
+        last_key = Some(pair.key().clone());
+        chunk_size += (pair.key().len() + pair.value().len()) as u64;
+        chunk.push(pair);
+        if chunk_size >= options.region_size {
+            let chunk = std::mem::take(&mut chunk);
+            summary.ingests +=
+                import_chunk(&pd_client, &chunk, commit_ts, &addresses, options).await?;
+            summary.pairs += chunk.len() as u64;
+            summary.bytes += chunk_size;
+            chunk_size = 0;
+        }
+    }
+    if !chunk.is_empty() {
+        summary.ingests += import_chunk(&pd_client, &chunk, commit_ts, &addresses, options).await?;
+        summary.pairs += chunk.len() as u64;
+        summary.bytes += chunk_size;
+    }
+    Ok(summary)
+}
+
+/// Ingests `files`, each of which must fit in a region, one after the other.
+pub(crate) async fn ingest_files<PdC: PdClient>(
+    pd_client: Arc<PdC>,
+    files: Vec<SstFile>,
+    options: &ImportOptions,
+) -> Result<ImportSummary> {
+    let addresses = store_addresses(pd_client.clone()).await?;
+    let mut summary = ImportSummary::default();
+    for file in files {
+        if options.split_regions {
+            split_region_at(&pd_client, file.first_key.clone()).await?;
+        }
+        let mut backoff = DEFAULT_REGION_BACKOFF;
+        loop {
+            let region = pd_client.region_for_key(&file.first_key).await?;
+            if !region.contains(&file.last_key) {
+                return Err(Error::ImportFailed {
+                    message: format!(
+                        "SST file from {:?} to {:?} spans several regions",
+                        file.first_key, file.last_key
+                    ),
+                });
+            }
+            match ingest_file(&pd_client, &region, &file, &addresses, options).await {
+                Ok(()) => break,
+                Err(Error::RegionError(e)) => {
+                    backoff_region(&pd_client, &region, &mut backoff, e).await?
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        summary.bytes += file.data.len() as u64;
+        summary.ingests += 1;
+    }
+    Ok(summary)
+}
+
+/// Imports `pairs` region by region, returning the number of regions ingested into.
+async fn import_chunk<PdC: PdClient>(
+    pd_client: &Arc<PdC>,
+    pairs: &[KvPair],
+    commit_ts: u64,
+    addresses: &HashMap<StoreId, String>,
+    options: &ImportOptions,
+) -> Result<usize> {
+    if options.split_regions {
+        split_region_at(pd_client, pairs[0].key().clone()).await?;
+    }
+    let mut ingests = 0;
+    let mut backoff = DEFAULT_REGION_BACKOFF;
+    let mut rest = pairs;
+    while let Some(first) = rest.first() {
+        let region = pd_client.region_for_key(first.key()).await?;
+        let count = rest
+            .iter()
+            .take_while(|pair| region.contains(pair.key()))
+            .count();
+        let (batch, remaining) = rest.split_at(count);
+        match write_region(pd_client, &region, batch, commit_ts, addresses, options).await {
+            Ok(()) => {
+                ingests += 1;
+                rest = remaining;
+            }
+            Err(Error::RegionError(e)) => {
+                backoff_region(pd_client, &region, &mut backoff, e).await?
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(ingests)
+}
+
+/// Has each store holding a replica of `region` write `pairs` into SST files, then has the leader
+/// ingest its files, which applies them to all replicas through Raft.
+async fn write_region<PdC: PdClient>(
+    pd_client: &Arc<PdC>,
+    region: &RegionWithLeader,
+    pairs: &[KvPair],
+    commit_ts: u64,
+    addresses: &HashMap<StoreId, String>,
+    options: &ImportOptions,
+) -> Result<()> {
+    let meta = sst_meta(region, pairs[0].key(), pairs[pairs.len() - 1].key());
+    let mut requests = vec![import_sstpb::WriteRequest {
+        chunk: Some(write_request::Chunk::Meta(meta)),
+    }];
+    requests.extend(pairs.chunks(options.batch_size).map(|batch| {
+        let pairs = batch
+            .iter()
+            .map(|pair| import_sstpb::Pair {
+                key: pair.key().clone().into(),
+                value: pair.value().clone(),
+                ..Default::default()
+            })
+            .collect();
+        import_sstpb::WriteRequest {
+            chunk: Some(write_request::Chunk::Batch(import_sstpb::WriteBatch {
+                commit_ts,
+                pairs,
+            })),
+        }
+    }));
+
+    let stores = replica_addresses(region, addresses)?;
+    let requests = &requests;
+    let writes = stores.iter().map(|address| async move {
+        let resp: import_sstpb::WriteResponse =
+            dispatch(pd_client, address, requests, options.timeout).await?;
+        match resp.error {
+            Some(error) => Err(Error::ImportFailed {
+                message: error.message,
+            }),
+            None => Ok(resp.metas),
+        }
+    });
+    // the leader comes first, its files are ingested
+    let metas = future::try_join_all(writes).await?.swap_remove(0);
+    let request = import_sstpb::MultiIngestRequest {
+        context: Some(region.context()?),
+        ssts: metas,
+    };
+    let resp: import_sstpb::IngestResponse =
+        dispatch(pd_client, &stores[0], &request, options.timeout).await?;
+    ingest_result(resp)
+}
+
+/// Uploads `file` to each store holding a replica of `region`, then has the leader ingest it.
+async fn ingest_file<PdC: PdClient>(
+    pd_client: &Arc<PdC>,
+    region: &RegionWithLeader,
+    file: &SstFile,
+    addresses: &HashMap<StoreId, String>,
+    options: &ImportOptions,
+) -> Result<()> {
+    let mut meta = sst_meta(region, &file.first_key, &file.last_key);
+    meta.cf_name = file.cf.to_string();
+    meta.crc32 = file.crc32;
+    meta.length = file.data.len() as u64;
+    let mut requests = vec![import_sstpb::UploadRequest {
+        chunk: Some(upload_request::Chunk::Meta(meta.clone())),
+    }];
+    requests.extend(
+        file.data
+            .chunks(UPLOAD_CHUNK_SIZE)
+            .map(|data| import_sstpb::UploadRequest {
+                chunk: Some(upload_request::Chunk::Data(data.to_vec())),
+            }),
+    );
+
+    let stores = replica_addresses(region, addresses)?;
+    let uploads = stores.iter().map(|address| {
+        dispatch::<_, import_sstpb::UploadResponse>(pd_client, address, &requests, options.timeout)
+    });
+    future::try_join_all(uploads).await?;
+    let request = import_sstpb::IngestRequest {
+        context: Some(region.context()?),
+        sst: Some(meta),
+    };
+    let resp: import_sstpb::IngestResponse =
+        dispatch(pd_client, &stores[0], &request, options.timeout).await?;
+    ingest_result(resp)
+}
+
+/// The metadata of an SST file of `region` with keys from `first_key` to `last_key`.
+///
+/// The stores check the range of the file against the range of the region, in the encoding of
+/// transactional keys.
+fn sst_meta(region: &RegionWithLeader, first_key: &Key, last_key: &Key) -> import_sstpb::SstMeta {
+    import_sstpb::SstMeta {
+        uuid: rand::random::<[u8; 16]>().to_vec(),
+        range: Some(import_sstpb::Range {
+            start: first_key.to_encoded().into(),
+            end: last_key.to_encoded().into(),
+        }),
+        region_id: region.id(),
+        region_epoch: Some(region.region.get_region_epoch().clone()),
+        ..Default::default()
+    }
+}
+
+/// The addresses of the TiKV stores, by id.
+pub(crate) async fn store_addresses(
+    pd_client: Arc<impl PdClient>,
+) -> Result<HashMap<StoreId, String>> {
+    Ok(pd_client
+        .stores()
+        .await?
+        .into_iter()
+        .filter(is_tikv_store)
+        .map(|store| (store.id, store.address))
+        .collect())
+}
+
+/// The addresses of the TiKV stores holding a replica of `region`, the leader first. Witnesses
+/// hold no data, they are left out.
+fn replica_addresses(
+    region: &RegionWithLeader,
+    addresses: &HashMap<StoreId, String>,
+) -> Result<Vec<String>> {
+    let leader = region.get_store_id()?;
+    let leader_address = addresses.get(&leader).ok_or_else(|| Error::ImportFailed {
+        message: format!(
+            "the leader store {} of region {} is unknown",
+            leader,
+            region.id()
+        ),
+    })?;
+    let mut stores = vec![leader_address.clone()];
+    stores.extend(
+        region
+            .region
+            .get_peers()
+            .iter()
+            .filter(|peer| peer.store_id != leader && !peer.get_is_witness())
+            .filter_map(|peer| addresses.get(&peer.store_id).cloned()),
+    );
+    Ok(stores)
+}
+
+async fn dispatch<PdC: PdClient, Resp: Any>(
+    pd_client: &Arc<PdC>,
+    address: &str,
+    request: &dyn ImportRequest,
+    timeout: Duration,
+) -> Result<Resp> {
+    let kv_client = pd_client.store_client(address).await?;
+    let resp = kv_client.dispatch_import(request, timeout).await?;
+    Ok(*resp
+        .downcast()
+        .expect("Downcast failed: request and response type mismatch"))
+}
+
+fn ingest_result(resp: import_sstpb::IngestResponse) -> Result<()> {
+    match resp.error {
+        Some(error) => Err(Error::RegionError(error)),
+        None => Ok(()),
+    }
+}
+
+/// Splits the region containing `key` at `key` and scatters the new regions, unless `key` is the
+/// start of its region already.
+async fn split_region_at<PdC: PdClient>(pd_client: &Arc<PdC>, key: Key) -> Result<()> {
+    let region = pd_client.region_for_key(&key).await?;
+    if region.start_key() == key {
+        return Ok(());
+    }
+    pd_client
+        .clone()
+        .split_and_scatter_regions(vec![key])
+        .await?;
+    pd_client.invalidate_region_cache(region.ver_id()).await;
+    Ok(())
+}
+
+/// Forgets the cached `region` after the region error `error` and waits before the next attempt,
+/// or fails with the error once the attempts are exhausted.
+async fn backoff_region<PdC: PdClient>(
+    pd_client: &Arc<PdC>,
+    region: &RegionWithLeader,
+    backoff: &mut Backoff,
+    error: errorpb::Error,
+) -> Result<()> {
+    pd_client.invalidate_region_cache(region.ver_id()).await;
+    match backoff.next_delay_duration() {
+        Some(delay) => {
+            pd_client.clock().sleep(delay).await;
+            Ok(())
+        }
+        None => Err(Error::RegionError(error)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{MockKvClient, MockPdClient};
+    use futures::stream;
+    use std::sync::Mutex;
+
+    #[tokio::test]
+    async fn test_import_pairs() {
+        // the first ingestion into region 2 fails with a region error and is retried
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let ingested = Arc::new(Mutex::new(Vec::new()));
+        let (written_cloned, ingested_cloned) = (written.clone(), ingested.clone());
+        let pd_client = Arc::new(MockPdClient::new(MockKvClient::with_dispatch_hook(
+            move |req: &dyn Any| {
+                if let Some(req) = req.downcast_ref::<Vec<import_sstpb::WriteRequest>>() {
+                    let meta = match &req[0].chunk {
+                        Some(write_request::Chunk::Meta(meta)) => meta.clone(),
+                        _ => unreachable!(),
+                    };
+                    let keys: Vec<Vec<u8>> = req[1..]
+                        .iter()
+                        .flat_map(|message| match &message.chunk {
+                            Some(write_request::Chunk::Batch(batch)) => {
+                                assert_eq!(batch.commit_ts, 7);
+                                batch.pairs.clone()
+                            }
+                            _ => unreachable!(),
+                        })
+                        .map(|pair| pair.key)
+                        .collect();
+                    written_cloned.lock().unwrap().push((meta.region_id, keys));
+                    let resp = import_sstpb::WriteResponse {
+                        metas: vec![meta],
+                        ..Default::default()
+                    };
+                    Ok(Box::new(resp) as Box<dyn Any>)
+                } else if let Some(req) = req.downcast_ref::<import_sstpb::MultiIngestRequest>() {
+                    let region_id = req.context.as_ref().unwrap().region_id;
+                    assert_eq!(req.ssts.len(), 1);
+                    assert_eq!(req.ssts[0].region_id, region_id);
+                    let mut ingested = ingested_cloned.lock().unwrap();
+                    ingested.push(region_id);
+                    let mut resp = import_sstpb::IngestResponse::default();
+                    if region_id == 2 && !ingested[..ingested.len() - 1].contains(&2) {
+                        resp.error = Some(errorpb::Error {
+                            epoch_not_match: Some(Default::default()),
+                            ..Default::default()
+                        });
+                    }
+                    Ok(Box::new(resp) as Box<dyn Any>)
+                } else {
+                    unreachable!()
+                }
+            },
+        )));
+        let keys = [vec![5], vec![6], vec![20], vec![30], vec![251]];
+        let pairs = keys
+            .iter()
+            .map(|key| Ok(KvPair::new(key.clone(), b"v".to_vec())));
+        let options = ImportOptions::new().batch_size(1);
+        let summary = import_pairs(pd_client.clone(), stream::iter(pairs), 7, &options)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            summary,
+            ImportSummary {
+                pairs: 5,
+                bytes: 10,
+                ingests: 3,
+            }
+        );
+        assert_eq!(
+            *pd_client.split_keys.lock().unwrap(),
+            vec![Key::from(vec![5])]
+        );
+        {
+            let written = written.lock().unwrap();
+            assert_eq!(written.len(), 4);
+            assert_eq!(written[0], (1, keys[..2].to_vec()));
+            assert_eq!(written[3], (3, keys[4..].to_vec()));
+        }
+        assert_eq!(*ingested.lock().unwrap(), vec![1, 2, 2, 3]);
+
+        // the pairs must be sorted
+        let pairs = keys
+            .iter()
+            .rev()
+            .map(|key| Ok(KvPair::new(key.clone(), b"v".to_vec())));
+        let result = import_pairs(pd_client, stream::iter(pairs), 7, &options).await;
+        assert!(matches!(result, Err(Error::ImportFailed { .. })));
+    }
+}
@@ -0,0 +1,247 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! Middleware on the requests sent to TiKV, see [`Interceptor`].
+
+use crate::Result;
+use async_trait::async_trait;
+use futures::stream::BoxStream;
+use std::{any::Any, fmt, sync::Arc, time::Duration};
+use tikv_client_proto::cdcpb::{ChangeDataEvent, ChangeDataRequest};
+use tikv_client_store::{ImportRequest, KvClient, Request};
+
+/// A middleware which sees every request sent to a TiKV store and its response, registered with
+/// [`Config::with_interceptor`](crate::Config::with_interceptor).
+///
+/// An interceptor passes the request on with [`Next::run`], and may inspect or replace the request
+/// before and the response after, e.g. to inject fields in the context of requests, audit them, or
+/// rate limit them. It may also fail a request without passing it on, e.g. to inject faults in
+/// tests. Requests are `kvrpcpb` messages and responses are boxed `kvrpcpb` messages, which are
+/// downcast to their concrete types, see [`Request::as_any`].
+///
+/// Interceptors run in the order in which they were registered, after the request waited in the
+/// queue of its store, and before it is sent. Retries of a request run them again.
+///
+/// # Examples
+/// ```rust
+/// # use tikv_client::{Interceptor, Next, Result, RpcRequest};
+/// # use std::any::Any;
+/// use tikv_client_proto::kvrpcpb;
+///
+/// /// Sets the priority of each get request to high.
+/// struct HighPriorityGets;
+///
+/// #[async_trait::async_trait]
+/// impl Interceptor for HighPriorityGets {
+///     async fn intercept(
+///         &self,
+///         request: &dyn RpcRequest,
+///         next: Next<'_>,
+///     ) -> Result<Box<dyn Any>> {
+///         match request.as_any().downcast_ref::<kvrpcpb::GetRequest>() {
+///             Some(get) => {
+///                 let mut get = get.clone();
+///                 get.mut_context().set_priority(kvrpcpb::CommandPri::High);
+///                 next.run(&get).await
+///             }
+///             None => next.run(request).await,
+///         }
+///     }
+/// }
+/// ```
+#[async_trait]
+pub trait Interceptor: Send + Sync + 'static {
+    async fn intercept(&self, request: &dyn Request, next: Next<'_>) -> Result<Box<dyn Any>>;
+}
+
+/// The rest of the interceptors of a request and the store it is sent to, see [`Interceptor`].
+pub struct Next<'a> {
+    address: &'a str,
+    interceptors: &'a [Arc<dyn Interceptor>],
+    client: &'a (dyn KvClient + Send + Sync),
+    timeout: Option<Duration>,
+}
+
+impl<'a> Next<'a> {
+    /// The address of the store the request is sent to.
+    pub fn address(&self) -> &str {
+        self.address
+    }
+
+    /// Pass `request` to the next interceptor, or send it to the store if this is the last one.
+    pub async fn run(self, request: &dyn Request) -> Result<Box<dyn Any>> {
+        match self.interceptors.split_first() {
+            Some((interceptor, interceptors)) => {
+                let next = Next {
+                    interceptors,
+                    ..self
+                };
+                interceptor.intercept(request, next).await
+            }
+            None => match self.timeout {
+                Some(timeout) => self.client.dispatch_with_timeout(request, timeout).await,
+                None => self.client.dispatch(request).await,
+            },
+        }
+    }
+}
+
+/// A handle to an [`Interceptor`], as stored in a [`Config`](crate::Config).
+///
+/// Two handles are equal if they refer to the same interceptor.
+#[derive(Clone)]
+pub struct InterceptorHandle(Arc<dyn Interceptor>);
+
+impl InterceptorHandle {
+    pub fn new(interceptor: impl Interceptor) -> InterceptorHandle {
+        InterceptorHandle(Arc::new(interceptor))
+    }
+
+    pub(crate) fn into_inner(self) -> Arc<dyn Interceptor> {
+        self.0
+    }
+}
+
+impl PartialEq for InterceptorHandle {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::as_ptr(&self.0) as *const () == Arc::as_ptr(&other.0) as *const ()
+    }
+}
+
+impl fmt::Debug for InterceptorHandle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("InterceptorHandle")
+            .field(&(Arc::as_ptr(&self.0) as *const ()))
+            .finish()
+    }
+}
+
+/// A [`KvClient`] which runs the requests it dispatches through interceptors.
+#[derive(Clone)]
+pub(crate) struct InterceptedKvClient<C> {
+    inner: C,
+    address: String,
+    interceptors: Arc<[Arc<dyn Interceptor>]>,
+}
+
+impl<C: KvClient + Send + Sync> InterceptedKvClient<C> {
+    pub fn new(
+        inner: C,
+        address: String,
+        interceptors: Arc<[Arc<dyn Interceptor>]>,
+    ) -> InterceptedKvClient<C> {
+        InterceptedKvClient {
+            inner,
+            address,
+            interceptors,
+        }
+    }
+
+    fn next(&self, timeout: Option<Duration>) -> Next<'_> {
+        Next {
+            address: &self.address,
+            interceptors: &self.interceptors,
+            client: &self.inner,
+            timeout,
+        }
+    }
+}
+
+#[async_trait]
+impl<C: KvClient + Send + Sync> KvClient for InterceptedKvClient<C> {
+    async fn dispatch(&self, req: &dyn Request) -> Result<Box<dyn Any>> {
+        self.next(None).run(req).await
+    }
+
+    async fn dispatch_with_timeout(
+        &self,
+        req: &dyn Request,
+        timeout: Duration,
+    ) -> Result<Box<dyn Any>> {
+        self.next(Some(timeout)).run(req).await
+    }
+
+    /// Interceptors only see the requests to the `Tikv` service.
+    async fn dispatch_import(
+        &self,
+        req: &dyn ImportRequest,
+        timeout: Duration,
+    ) -> Result<Box<dyn Any>> {
+        self.inner.dispatch_import(req, timeout).await
+    }
+
+    fn event_feed(
+        &self,
+        requests: BoxStream<'static, ChangeDataRequest>,
+    ) -> Result<BoxStream<'static, Result<ChangeDataEvent>>> {
+        self.inner.event_feed(requests)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{mock::MockKvClient, Error};
+    use std::sync::Mutex;
+    use tikv_client_proto::kvrpcpb;
+
+    /// Records the labels of the requests it sees.
+    struct Audit(Arc<Mutex<Vec<&'static str>>>);
+
+    #[async_trait]
+    impl Interceptor for Audit {
+        async fn intercept(&self, request: &dyn Request, next: Next<'_>) -> Result<Box<dyn Any>> {
+            self.0.lock().unwrap().push(request.label());
+            next.run(request).await
+        }
+    }
+
+    /// Fails the delete requests, and sets the priority of the others to high.
+    struct FailDeletes;
+
+    #[async_trait]
+    impl Interceptor for FailDeletes {
+        async fn intercept(&self, request: &dyn Request, next: Next<'_>) -> Result<Box<dyn Any>> {
+            if request.label() == "raw_delete" {
+                let address = next.address();
+                return Err(Error::StringError(format!("injected at {}", address)));
+            }
+            let mut get = request
+                .as_any()
+                .downcast_ref::<kvrpcpb::RawGetRequest>()
+                .unwrap()
+                .clone();
+            get.mut_context().set_priority(kvrpcpb::CommandPri::High);
+            next.run(&get).await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_interceptors() {
+        let kv_client = MockKvClient::with_dispatch_hook(|req: &dyn Any| {
+            let req = req.downcast_ref::<kvrpcpb::RawGetRequest>().unwrap();
+            assert_eq!(req.get_context().get_priority(), kvrpcpb::CommandPri::High);
+            Ok(Box::new(kvrpcpb::RawGetResponse {
+                value: req.key.clone(),
+                ..Default::default()
+            }) as Box<dyn Any>)
+        });
+        let audit = Arc::new(Mutex::new(Vec::new()));
+        let interceptors: Vec<Arc<dyn Interceptor>> =
+            vec![Arc::new(Audit(audit.clone())), Arc::new(FailDeletes)];
+        let client = InterceptedKvClient::new(kv_client, "tikv-1".to_owned(), interceptors.into());
+
+        let get = kvrpcpb::RawGetRequest {
+            key: b"k".to_vec(),
+            ..Default::default()
+        };
+        let resp = client.dispatch(&get).await.unwrap();
+        let resp = resp.downcast::<kvrpcpb::RawGetResponse>().unwrap();
+        assert_eq!(resp.value, b"k");
+
+        // the delete is not sent to the store
+        let delete = kvrpcpb::RawDeleteRequest::default();
+        let err = client.dispatch(&delete).await.unwrap_err();
+        assert_eq!(err.to_string(), "injected at tikv-1");
+        assert_eq!(*audit.lock().unwrap(), vec!["raw_get", "raw_delete"]);
+    }
+}
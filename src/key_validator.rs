@@ -0,0 +1,169 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! Checks on the keys written by the clients, see [`KeyValidator`].
+
+use crate::{Error, Key, Result};
+use std::{fmt, sync::Arc};
+
+/// A check of every key written by the clients of a [`Config`](crate::Config), registered with
+/// [`Config::with_key_validator`](crate::Config::with_key_validator), e.g. to enforce the prefix
+/// of a tenant or to keep services out of a reserved range of keys.
+///
+/// The key is checked as it is given to the client, before it is prefixed by the namespace of the
+/// client, and before any request is sent. A rejected write fails with
+/// [`KeyRejected`](crate::Error::KeyRejected), and nothing is written: a batch is rejected as a
+/// whole if one of its keys is. Range deletions are only checked by
+/// [`validate_range`](KeyValidator::validate_range). Keys which are only read or locked are not
+/// checked.
+///
+/// Closures taking a key and returning why it is rejected are validators.
+///
+/// # Examples
+/// ```rust
+/// # use tikv_client::{Config, Key, KeyValidator};
+/// let config = Config::default().with_key_validator(|key: &Key| {
+///     let key: &[u8] = key.into();
+///     if key.starts_with(b"tenant-42/") {
+///         Ok(())
+///     } else {
+///         Err("keys must start with the prefix of the tenant".to_owned())
+///     }
+/// });
+/// ```
+pub trait KeyValidator: Send + Sync + 'static {
+    /// Check that `key` may be written, returning why it is rejected otherwise.
+    fn validate_key(&self, key: &Key) -> std::result::Result<(), String>;
+
+    /// Check that the keys from `start` to `end`, excluded, may be deleted by a range deletion,
+    /// unbounded if `end` is `None`, returning why they are rejected otherwise.
+    ///
+    /// Ranges are accepted by default.
+    fn validate_range(&self, _start: &Key, _end: Option<&Key>) -> std::result::Result<(), String> {
+        Ok(())
+    }
+}
+
+impl<F> KeyValidator for F
+where
+    F: Fn(&Key) -> std::result::Result<(), String> + Send + Sync + 'static,
+{
+    fn validate_key(&self, key: &Key) -> std::result::Result<(), String> {
+        self(key)
+    }
+}
+
+/// A handle to a [`KeyValidator`], as stored in a [`Config`](crate::Config).
+///
+/// Two handles are equal if they refer to the same validator.
+#[derive(Clone)]
+pub struct KeyValidatorHandle(Arc<dyn KeyValidator>);
+
+impl KeyValidatorHandle {
+    pub fn new(validator: impl KeyValidator) -> KeyValidatorHandle {
+        KeyValidatorHandle(Arc::new(validator))
+    }
+
+    pub(crate) fn into_inner(self) -> Arc<dyn KeyValidator> {
+        self.0
+    }
+}
+
+impl PartialEq for KeyValidatorHandle {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::as_ptr(&self.0) as *const () == Arc::as_ptr(&other.0) as *const ()
+    }
+}
+
+impl fmt::Debug for KeyValidatorHandle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("KeyValidatorHandle")
+            .field(&(Arc::as_ptr(&self.0) as *const ()))
+            .finish()
+    }
+}
+
+/// Check `key` against all `validators`, the first rejection wins.
+pub(crate) fn validate_key(validators: &[Arc<dyn KeyValidator>], key: &Key) -> Result<()> {
+    for validator in validators {
+        validator
+            .validate_key(key)
+            .map_err(|reason| Error::KeyRejected {
+                key: key.clone().into(),
+                reason,
+            })?;
+    }
+    Ok(())
+}
+
+/// Check the range deleted from `start` to `end` against all `validators`, the first rejection
+/// wins.
+pub(crate) fn validate_range(
+    validators: &[Arc<dyn KeyValidator>],
+    start: &Key,
+    end: Option<&Key>,
+) -> Result<()> {
+    for validator in validators {
+        validator
+            .validate_range(start, end)
+            .map_err(|reason| Error::KeyRejected {
+                key: start.clone().into(),
+                reason,
+            })?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_key() {
+        // the keys from [0xff] on are reserved
+        struct NoReservedRange;
+
+        impl KeyValidator for NoReservedRange {
+            fn validate_key(&self, key: &Key) -> std::result::Result<(), String> {
+                let end = Key::from(vec![0xff]);
+                self.validate_range(key, Some(&end))
+            }
+
+            fn validate_range(
+                &self,
+                start: &Key,
+                end: Option<&Key>,
+            ) -> std::result::Result<(), String> {
+                let reserved = Key::from(vec![0xff]);
+                if *start >= reserved || end.is_none_or(|end| *end > reserved) {
+                    Err("reserved".to_owned())
+                } else {
+                    Ok(())
+                }
+            }
+        }
+
+        let validators: Vec<Arc<dyn KeyValidator>> = vec![
+            Arc::new(NoReservedRange),
+            Arc::new(|key: &Key| {
+                if key.is_empty() {
+                    Err("empty".to_owned())
+                } else {
+                    Ok(())
+                }
+            }),
+        ];
+        assert!(validate_key(&validators, &vec![1].into()).is_ok());
+        assert!(matches!(
+            validate_key(&validators, &vec![0xff, 1].into()),
+            Err(Error::KeyRejected { key, reason }) if key == vec![0xff, 1] && reason == "reserved"
+        ));
+        assert!(matches!(
+            validate_key(&validators, &Key::EMPTY),
+            Err(Error::KeyRejected { reason, .. }) if reason == "empty"
+        ));
+
+        let (start, end) = (Key::from(vec![1]), Key::from(vec![2]));
+        assert!(validate_range(&validators, &start, Some(&end)).is_ok());
+        assert!(validate_range(&validators, &start, None).is_err());
+    }
+}
@@ -0,0 +1,200 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+use crate::{BoundRange, Error, Key, KvPair, Result};
+use tikv_client_common::internal_err;
+use tikv_client_proto::keyspacepb;
+
+/// The largest keyspace id, ids are encoded in three bytes.
+pub const MAX_KEYSPACE_ID: u32 = 0xFF_FFFF;
+
+/// Whether the keys of a keyspace are written by raw requests or by transactions, which TiKV
+/// keeps apart under API V2 by the first byte of the keys.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum KeyMode {
+    Raw,
+    Txn,
+}
+
+impl KeyMode {
+    fn first_byte(self) -> u8 {
+        match self {
+            KeyMode::Raw => b'r',
+            KeyMode::Txn => b'x',
+        }
+    }
+}
+
+/// A keyspace of a cluster running API V2.
+///
+/// All raw keys of the keyspace start with the prefix `r{keyspace id}`, and all transactional
+/// keys with the prefix `x{keyspace id}`, where the id is encoded in three bytes in big endian.
+/// Keys given by the user are prefixed before being sent to TiKV, and the prefix is removed from
+/// keys returned by TiKV.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) struct Keyspace {
+    id: u32,
+    mode: KeyMode,
+}
+
+impl Keyspace {
+    /// The raw keys of the keyspace `id`.
+    pub fn new(id: u32) -> Result<Keyspace> {
+        if id > MAX_KEYSPACE_ID {
+            return Err(Error::StringError(format!(
+                "keyspace id {} exceeds the maximum {}",
+                id, MAX_KEYSPACE_ID
+            )));
+        }
+        Ok(Keyspace {
+            id,
+            mode: KeyMode::Raw,
+        })
+    }
+
+    /// The raw keys of the keyspace of `meta`, as loaded from PD, which must be enabled.
+    pub fn from_meta(meta: &keyspacepb::KeyspaceMeta) -> Result<Keyspace> {
+        if meta.get_state() != keyspacepb::KeyspaceState::Enabled {
+            return Err(Error::StringError(format!(
+                "keyspace {} is {:?}",
+                meta.get_name(),
+                meta.get_state()
+            )));
+        }
+        Keyspace::new(meta.get_id())
+    }
+
+    /// The transactional keys of the keyspace.
+    pub fn txn(self) -> Keyspace {
+        Keyspace {
+            mode: KeyMode::Txn,
+            ..self
+        }
+    }
+
+    pub fn prefix(self) -> Vec<u8> {
+        let [_, a, b, c] = self.id.to_be_bytes();
+        vec![self.mode.first_byte(), a, b, c]
+    }
+
+    /// The exclusive end of the keys of the keyspace, i.e., the prefix of the next keyspace.
+    fn end(self) -> Vec<u8> {
+        if self.id == MAX_KEYSPACE_ID {
+            vec![self.mode.first_byte() + 1]
+        } else {
+            Keyspace {
+                id: self.id + 1,
+                ..self
+            }
+            .prefix()
+        }
+    }
+
+    pub fn encode_key(self, key: Key) -> Key {
+        let mut encoded = self.prefix();
+        encoded.extend_from_slice(&Vec::from(key));
+        encoded.into()
+    }
+
+    /// Encodes the keys of `range`. An unbounded range is bounded by the keys of the keyspace.
+    pub fn encode_range(self, range: BoundRange) -> BoundRange {
+        let (start, end) = range.into_keys();
+        let end = match end.filter(|end| !end.is_empty()) {
+            Some(end) => self.encode_key(end),
+            None => self.end().into(),
+        };
+        (self.encode_key(start), end).into()
+    }
+
+    pub fn decode_key(self, key: Key) -> Result<Key> {
+        let mut key = Vec::from(key);
+        let prefix = self.prefix();
+        if !key.starts_with(&prefix) {
+            return Err(internal_err!(
+                "key {:?} is not in keyspace {}",
+                Key::from(key),
+                self.id
+            ));
+        }
+        key.drain(..prefix.len());
+        Ok(key.into())
+    }
+
+    pub fn decode_pairs(self, pairs: Vec<KvPair>) -> Result<Vec<KvPair>> {
+        pairs
+            .into_iter()
+            .map(|KvPair(key, value)| Ok(KvPair(self.decode_key(key)?, value)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keyspace_encoding() {
+        let keyspace = Keyspace::new(0x010203).unwrap();
+        let key = keyspace.encode_key(b"key".to_vec().into());
+        assert_eq!(key, Key::from(b"r\x01\x02\x03key".to_vec()));
+        assert_eq!(
+            keyspace.decode_key(key).unwrap(),
+            Key::from(b"key".to_vec())
+        );
+        assert!(keyspace
+            .decode_key(b"r\x01\x02\x04key".to_vec().into())
+            .is_err());
+
+        let range = keyspace.encode_range((b"a".to_vec()..b"b".to_vec()).into());
+        assert_eq!(
+            range.into_keys(),
+            (
+                Key::from(b"r\x01\x02\x03a".to_vec()),
+                Some(Key::from(b"r\x01\x02\x03b".to_vec()))
+            )
+        );
+        let range = keyspace.encode_range((..).into());
+        assert_eq!(
+            range.into_keys(),
+            (
+                Key::from(b"r\x01\x02\x03".to_vec()),
+                Some(Key::from(b"r\x01\x02\x04".to_vec()))
+            )
+        );
+
+        let last = Keyspace::new(MAX_KEYSPACE_ID).unwrap();
+        assert_eq!(
+            last.encode_range((..).into()).into_keys().1,
+            Some(Key::from(b"s".to_vec()))
+        );
+        assert!(Keyspace::new(MAX_KEYSPACE_ID + 1).is_err());
+
+        let txn = keyspace.txn();
+        assert_eq!(
+            txn.encode_key(b"key".to_vec().into()),
+            Key::from(b"x\x01\x02\x03key".to_vec())
+        );
+        assert!(txn.decode_key(b"r\x01\x02\x03key".to_vec().into()).is_err());
+        assert_eq!(
+            last.txn().encode_range((..).into()).into_keys().1,
+            Some(Key::from(b"y".to_vec()))
+        );
+    }
+
+    #[test]
+    fn test_keyspace_from_meta() {
+        let mut meta = keyspacepb::KeyspaceMeta {
+            id: 7,
+            name: "orders".to_owned(),
+            ..Default::default()
+        };
+        assert_eq!(
+            Keyspace::from_meta(&meta).unwrap(),
+            Keyspace::new(7).unwrap()
+        );
+        meta.set_state(keyspacepb::KeyspaceState::Archived);
+        assert_eq!(
+            Keyspace::from_meta(&meta).unwrap_err().to_string(),
+            "keyspace orders is Archived"
+        );
+    }
+}
@@ -13,6 +13,7 @@ use proptest_derive::Arbitrary;
 use tikv_client_proto::kvrpcpb;
 
 use super::Key;
+use crate::{Error, Result};
 
 /// A struct for expressing ranges. This type is semi-opaque and is not really meant for users to
 /// deal with directly. Most functions which operate on ranges will accept any types which
@@ -83,6 +84,50 @@ impl BoundRange {
         }
     }
 
+    /// Create a new BoundRange of all keys starting with `prefix`.
+    ///
+    /// # Examples
+    /// ```rust
+    /// use tikv_client::{BoundRange, Key};
+    /// assert_eq!(
+    ///     BoundRange::prefix(vec![1, 2]).into_keys(),
+    ///     (Key::from(vec![1, 2]), Some(Key::from(vec![1, 3]))),
+    /// );
+    /// // Trailing 0xff bytes can't be incremented
+    /// assert_eq!(
+    ///     BoundRange::prefix(vec![1, 0xff]).into_keys(),
+    ///     (Key::from(vec![1, 0xff]), Some(Key::from(vec![2]))),
+    /// );
+    /// assert_eq!(
+    ///     BoundRange::prefix(vec![0xff]).into_keys(),
+    ///     (Key::from(vec![0xff]), None),
+    /// );
+    /// ```
+    pub fn prefix(prefix: impl Into<Key>) -> BoundRange {
+        let start: Key = prefix.into();
+        let mut end: Vec<u8> = start.clone().into();
+        while let Some(0xff) = end.last() {
+            end.pop();
+        }
+        match end.last_mut() {
+            Some(last) => {
+                *last += 1;
+                BoundRange::new(Bound::Included(start), Bound::Excluded(end.into()))
+            }
+            None => BoundRange::range_from(start),
+        }
+    }
+
+    /// The range of the keys starting with `prefix` like [`prefix`](BoundRange::prefix), but
+    /// [`EmptyPrefix`](crate::Error::EmptyPrefix) if `prefix` is empty, whose range is all keys.
+    pub(crate) fn non_empty_prefix(prefix: impl Into<Key>) -> Result<BoundRange> {
+        let prefix = prefix.into();
+        if prefix.is_empty() {
+            return Err(Error::EmptyPrefix);
+        }
+        Ok(BoundRange::prefix(prefix))
+    }
+
     /// Ranges used in scanning TiKV have a particularity to them.
     ///
     /// The **start** of a scan is inclusive, unless appended with an '\0', then it is exclusive.
@@ -148,6 +193,34 @@ impl BoundRange {
         };
         (start, end)
     }
+
+    /// Like [`into_keys`](BoundRange::into_keys), but fails with
+    /// [`Error::InvalidRange`](crate::Error::InvalidRange) if the start of the range is after its
+    /// end.
+    ///
+    /// # Examples
+    /// ```rust
+    /// use tikv_client::{BoundRange, Key};
+    /// let range = BoundRange::from(("a".to_owned(), "z".to_owned()));
+    /// assert_eq!(
+    ///     range.try_into_keys().unwrap(),
+    ///     (Key::from("a".to_owned()), Some(Key::from("z".to_owned()))),
+    /// );
+    /// let range = BoundRange::from(("z".to_owned(), "a".to_owned()));
+    /// assert!(range.try_into_keys().is_err());
+    /// // An empty end is unbounded
+    /// let range = BoundRange::from(("z".to_owned(), "".to_owned()));
+    /// assert_eq!(range.try_into_keys().unwrap(), (Key::from("z".to_owned()), Some(Key::EMPTY)));
+    /// ```
+    pub fn try_into_keys(self) -> Result<(Key, Option<Key>)> {
+        match self.into_keys() {
+            (start, Some(end)) if !end.is_empty() && start > end => Err(Error::InvalidRange {
+                start: start.into(),
+                end: end.into(),
+            }),
+            keys => Ok(keys),
+        }
+    }
 }
 
 impl RangeBounds<Key> for BoundRange {
@@ -1,7 +1,4 @@
-use std::{io::Write, ptr};
-use tikv_client_common::internal_err;
-
-use crate::Result;
+use crate::{Error, Result};
 
 const ENC_GROUP_SIZE: usize = 8;
 const ENC_MARKER: u8 = 0xff;
@@ -15,120 +12,92 @@ pub fn max_encoded_bytes_size(n: usize) -> usize {
     (n / ENC_GROUP_SIZE + 1) * (ENC_GROUP_SIZE + 1)
 }
 
-pub trait BytesEncoder: Write {
-    /// Refer: <https://github.com/facebook/mysql-5.6/wiki/MyRocks-record-format#memcomparable-format>
-    ///
-    /// Duplicate from components/tikv_util/src/codec/bytes.rs.
-    fn encode_bytes(&mut self, key: &[u8], desc: bool) -> Result<()> {
-        let len = key.len();
-        let mut index = 0;
-        let mut buf = [0; ENC_GROUP_SIZE];
-        while index <= len {
-            let remain = len - index;
-            let mut pad: usize = 0;
-            if remain > ENC_GROUP_SIZE {
-                self.write_all(adjust_bytes_order(
-                    &key[index..index + ENC_GROUP_SIZE],
-                    desc,
-                    &mut buf,
-                ))?;
-            } else {
-                pad = ENC_GROUP_SIZE - remain;
-                self.write_all(adjust_bytes_order(&key[index..], desc, &mut buf))?;
-                if desc {
-                    self.write_all(&ENC_DESC_PADDING[..pad])?;
-                } else {
-                    self.write_all(&ENC_ASC_PADDING[..pad])?;
-                }
-            }
-            self.write_all(adjust_bytes_order(
-                &[ENC_MARKER - (pad as u8)],
-                desc,
-                &mut buf,
-            ))?;
-            index += ENC_GROUP_SIZE;
-        }
-        Ok(())
+/// Encodes `key` in the memcomparable format, the bytes of each group of `ENC_GROUP_SIZE` bytes
+/// followed by a marker, the last group padded with zeros.
+///
+/// Refer: <https://github.com/facebook/mysql-5.6/wiki/MyRocks-record-format#memcomparable-format>
+pub fn encode_bytes(key: &[u8], desc: bool) -> Vec<u8> {
+    let mut encoded = Vec::with_capacity(max_encoded_bytes_size(key.len()));
+    for group in 0..=key.len() / ENC_GROUP_SIZE {
+        let start = group * ENC_GROUP_SIZE;
+        let bytes = &key[start..key.len().min(start + ENC_GROUP_SIZE)];
+        let pad = ENC_GROUP_SIZE - bytes.len();
+        encoded.extend_from_slice(bytes);
+        encoded.extend_from_slice(&ENC_ASC_PADDING[..pad]);
+        encoded.push(ENC_MARKER - pad as u8);
     }
-}
-
-impl<T: Write> BytesEncoder for T {}
-
-fn adjust_bytes_order<'a>(bs: &'a [u8], desc: bool, buf: &'a mut [u8]) -> &'a [u8] {
     if desc {
-        let mut buf_idx = 0;
-        for &b in bs {
-            buf[buf_idx] = !b;
-            buf_idx += 1;
+        for b in &mut encoded {
+            *b = !*b;
         }
-        &buf[..buf_idx]
-    } else {
-        bs
     }
+    encoded
 }
 
 /// Decodes bytes which are encoded by `encode_bytes` before just in place without malloc.
 ///
+/// `data` is left unchanged if it is not a valid encoded key. Bytes following the last group
+/// are ignored.
+///
 /// Duplicate from components/tikv_util/src/codec/bytes.rs.
 pub fn decode_bytes_in_place(data: &mut Vec<u8>, desc: bool) -> Result<()> {
     if data.is_empty() {
         return Ok(());
     }
-    let mut write_offset = 0;
-    let mut read_offset = 0;
-    loop {
-        let marker_offset = read_offset + ENC_GROUP_SIZE;
-        if marker_offset >= data.len() {
-            return Err(internal_err!("unexpected EOF, original key = {:?}", data));
-        };
-
-        unsafe {
-            // it is semantically equivalent to C's memmove()
-            // and the src and dest may overlap
-            // if src == dest do nothing
-            ptr::copy(
-                data.as_ptr().add(read_offset),
-                data.as_mut_ptr().add(write_offset),
-                ENC_GROUP_SIZE,
-            );
-        }
-        write_offset += ENC_GROUP_SIZE;
+    let (groups, len) = decoded_len(data, desc)?;
+    for group in 0..groups {
         // everytime make ENC_GROUP_SIZE + 1 elements as a decode unit
-        read_offset += ENC_GROUP_SIZE + 1;
+        let read_offset = group * (ENC_GROUP_SIZE + 1);
+        data.copy_within(
+            read_offset..read_offset + ENC_GROUP_SIZE,
+            group * ENC_GROUP_SIZE,
+        );
+    }
+    data.truncate(len);
+    if desc {
+        for k in data {
+            *k = !*k;
+        }
+    }
+    Ok(())
+}
 
+/// Checks that `data` starts with a valid encoded key, returning its number of groups and the
+/// length of the decoded key.
+fn decoded_len(data: &[u8], desc: bool) -> Result<(usize, usize)> {
+    let invalid = |message: &str| Error::InvalidEncodedKey {
+        key: data.to_vec(),
+        message: message.to_owned(),
+    };
+    for (group, unit) in data.chunks(ENC_GROUP_SIZE + 1).enumerate() {
+        if unit.len() <= ENC_GROUP_SIZE {
+            break;
+        }
         // the last byte in decode unit is for marker which indicates pad size
-        let marker = data[marker_offset];
+        let marker = unit[ENC_GROUP_SIZE];
         let pad_size = if desc {
             marker as usize
         } else {
             (ENC_MARKER - marker) as usize
         };
-
-        if pad_size > 0 {
-            if pad_size > ENC_GROUP_SIZE {
-                return Err(internal_err!("invalid key padding"));
-            }
-
-            // check the padding pattern whether validate or not
-            let padding_slice = if desc {
-                &ENC_DESC_PADDING[..pad_size]
-            } else {
-                &ENC_ASC_PADDING[..pad_size]
-            };
-            if &data[write_offset - pad_size..write_offset] != padding_slice {
-                return Err(internal_err!("invalid key padding"));
-            }
-            unsafe {
-                data.set_len(write_offset - pad_size);
-            }
-            if desc {
-                for k in data {
-                    *k = !*k;
-                }
-            }
-            return Ok(());
+        if pad_size == 0 {
+            continue;
+        }
+        if pad_size > ENC_GROUP_SIZE {
+            return Err(invalid("invalid key padding"));
+        }
+        // check the padding pattern whether validate or not
+        let padding_slice = if desc {
+            &ENC_DESC_PADDING[..pad_size]
+        } else {
+            &ENC_ASC_PADDING[..pad_size]
+        };
+        if &unit[ENC_GROUP_SIZE - pad_size..ENC_GROUP_SIZE] != padding_slice {
+            return Err(invalid("invalid key padding"));
         }
+        return Ok((group + 1, (group + 1) * ENC_GROUP_SIZE - pad_size));
     }
+    Err(invalid("unexpected EOF"))
 }
 
 #[cfg(test)]
@@ -144,10 +113,7 @@ pub mod test {
     }
 
     fn encode_order_bytes(bs: &[u8], desc: bool) -> Vec<u8> {
-        let cap = max_encoded_bytes_size(bs.len());
-        let mut encoded = Vec::with_capacity(cap);
-        encoded.encode_bytes(bs, desc).unwrap();
-        encoded
+        super::encode_bytes(bs, desc)
     }
 
     #[test]
@@ -213,4 +179,27 @@ pub mod test {
             assert_eq!(source, desc);
         }
     }
+
+    #[test]
+    fn test_dec_invalid_bytes() {
+        let invalid = vec![
+            // no marker
+            vec![1, 2, 3],
+            vec![1, 2, 3, 0, 0, 0, 0, 0],
+            // no last group
+            vec![1, 2, 3, 4, 5, 6, 7, 8, 255],
+            // padding larger than a group
+            vec![0, 0, 0, 0, 0, 0, 0, 0, 246],
+            // padding which is not zeros
+            vec![1, 2, 3, 0, 0, 0, 0, 1, 250],
+        ];
+        for key in invalid {
+            let mut data = key.clone();
+            match decode_bytes_in_place(&mut data, false) {
+                Err(Error::InvalidEncodedKey { key: k, .. }) => assert_eq!(k, key),
+                res => panic!("unexpected result {:?}", res),
+            }
+            assert_eq!(data, key);
+        }
+    }
 }
@@ -1,7 +1,7 @@
 // Copyright 2019 TiKV Project Authors. Licensed under Apache-2.0.
 
 use super::HexRepr;
-use crate::kv::codec::{self, BytesEncoder};
+use crate::kv::codec;
 #[allow(unused_imports)]
 #[cfg(test)]
 use proptest::{arbitrary::any_with, collection::size_range};
@@ -100,7 +100,7 @@ impl Key {
     #[inline]
     pub(super) fn into_lower_bound(mut self) -> Bound<Key> {
         if self.zero_terminated() {
-            self.0.pop().unwrap();
+            self.0.pop();
             Bound::Excluded(self)
         } else {
             Bound::Included(self)
@@ -111,7 +111,7 @@ impl Key {
     #[inline]
     pub(super) fn into_upper_bound(mut self) -> Bound<Key> {
         if self.zero_terminated() {
-            self.0.pop().unwrap();
+            self.0.pop();
             Bound::Included(self)
         } else {
             Bound::Excluded(self)
@@ -121,10 +121,7 @@ impl Key {
     /// Return the MVCC-encoded representation of the key.
     #[inline]
     pub fn to_encoded(&self) -> Key {
-        let len = codec::max_encoded_bytes_size(self.0.len());
-        let mut encoded = Vec::with_capacity(len);
-        encoded.encode_bytes(&self.0, false).unwrap();
-        Key(encoded)
+        Key(codec::encode_bytes(&self.0, false))
     }
 
     pub fn len(&self) -> usize {
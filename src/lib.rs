@@ -97,21 +97,41 @@ pub mod request;
 pub mod transaction;
 
 mod backoff;
+mod cdc;
+pub mod clock;
+mod cluster;
 mod compat;
 mod config;
+pub mod coprocessor;
+#[cfg(any(test, feature = "fuzzing"))]
+pub mod fuzz;
+pub mod import;
+mod interceptor;
+mod key_validator;
+mod keyspace;
 mod kv;
+#[cfg(any(test, feature = "mock"))]
+pub mod mock;
+mod namespace;
 mod pd;
+mod pressure;
 #[doc(hidden)]
 pub mod raw;
+pub mod recipes;
 mod region;
 mod region_cache;
+mod split;
 mod stats;
 mod store;
+#[cfg(feature = "test-cluster")]
+pub mod test_cluster;
 mod timestamp;
+mod trace;
+#[cfg(any(test, feature = "typed"))]
+pub mod typed;
 mod util;
+pub mod value_codec;
 
-#[cfg(test)]
-mod mock;
 #[cfg(test)]
 mod proptests;
 
@@ -120,21 +140,74 @@ extern crate slog;
 extern crate slog_term;
 
 #[doc(inline)]
-pub use crate::backoff::Backoff;
+pub use crate::backoff::{Backoff, BackoffStrategy};
+#[doc(inline)]
+pub use crate::cdc::{ChangeEvent, ChangeFeedOptions, RowChange, RowOp};
+#[doc(inline)]
+pub use crate::clock::{ClockHandle, MockClock};
+#[doc(inline)]
+pub use crate::cluster::{
+    Cluster, ClusterInfo, PeerInfo, RegionInfo, StoreInfo, StoreState, StoreStats,
+};
+#[doc(inline)]
+pub use crate::coprocessor::{CoprocessorRequest, CoprocessorRequestType, CoprocessorResponse};
+#[doc(inline)]
+pub use crate::import::{
+    ImportOptions, ImportSummary, SstFile, IMPORT_BATCH_SIZE, IMPORT_REGION_SIZE,
+};
+#[doc(inline)]
+pub use crate::interceptor::{Interceptor, InterceptorHandle, Next};
+#[doc(inline)]
+pub use crate::key_validator::{KeyValidator, KeyValidatorHandle};
 #[doc(inline)]
 pub use crate::kv::{BoundRange, IntoOwnedRange, Key, KvPair, Value};
 #[doc(inline)]
-pub use crate::raw::{lowering as raw_lowering, Client as RawClient, ColumnFamily};
+pub use crate::pd::PdMember;
+#[doc(inline)]
+pub use crate::pressure::ClusterPressure;
 #[doc(inline)]
-pub use crate::request::RetryOptions;
+pub use crate::raw::{
+    lowering as raw_lowering, ApiVersion, Client as RawClient, ColumnFamily, CommandPriority,
+    RawChecksum, RawMutation, RawOptions, ScanToken, ScannedPair, SelfCheckReport, SelfCheckStep,
+    WriteGroup,
+};
+#[doc(inline)]
+pub use crate::region::{RegionId, RegionVerId, RegionWithLeader, StoreId};
+#[doc(inline)]
+pub use crate::region_cache::{InMemoryRegionCache, RegionCacheBackend, RegionCacheBackendHandle};
+#[doc(inline)]
+pub use crate::request::{
+    ContextHook, DeleteSummary, OnRetriesExhausted, Progress, ProgressCallback, RetryOptions,
+    ScanItem, ScanOrder, ScanPrefetch,
+};
+#[doc(inline)]
+#[cfg(feature = "prometheus")]
+pub use crate::stats::PrometheusSink;
+#[doc(inline)]
+pub use crate::stats::{set_metrics_sink, Metric, MetricsLabels, MetricsSink};
+#[doc(inline)]
+pub use crate::store::ConnectionStats;
 #[doc(inline)]
 pub use crate::timestamp::{Timestamp, TimestampExt};
 #[doc(inline)]
+pub use crate::trace::{RetryStats, RpcEvent, RpcTrace};
+#[doc(inline)]
 pub use crate::transaction::{
-    lowering as transaction_lowering, CheckLevel, Client as TransactionClient, Snapshot,
-    Transaction, TransactionOptions,
+    lowering as transaction_lowering, CheckLevel, Client as TransactionClient, CoalescingRules,
+    ExportSink, FilterPushdown, KeyDiff, LockPolicy, Mutation, MvccInfo, MvccKind, MvccLock,
+    MvccValue, MvccWrite, PessimisticLockOptions, PurgeOptions, PurgeProgress, ReadCache,
+    ReadOptions, ReplicaRead, ResolveLocksSummary, Savepoint, ScanCache, ScanFilter, Snapshot,
+    Transaction, TransactionOptions, COMMIT_CHUNK_SIZE, EXPORT_BATCH_SIZE, PURGE_BATCH_SIZE,
+    SCAN_STREAM_BATCH_SIZE,
 };
 #[doc(inline)]
-pub use config::Config;
+pub use config::{
+    Config, Keepalive, Profile, RegionRefresh, ReplicaSelection, SizeLimits, TimestampPrefetch,
+};
+#[doc(inline)]
+pub use tikv_client_common::{
+    security::SecurityManager, Assertion, Error, FailedRequest, LockInfo, Result, WaitForEntry,
+    WriteOrigin,
+};
 #[doc(inline)]
-pub use tikv_client_common::{security::SecurityManager, Error, Result};
+pub use tikv_client_store::Request as RpcRequest;
@@ -0,0 +1,361 @@
+// Copyright 2019 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! Various mock versions of the various clients and other objects.
+//!
+//! The goal is to be able to test functionality independently of the rest of
+//! the system, in particular without requiring a TiKV or PD server, or RPC layer.
+
+use crate::{
+    clock::ClockHandle,
+    key_validator::KeyValidator,
+    pd::{PdClient, PdMember, PdRpcClient, RetryClient},
+    region::{RegionId, RegionVerId, RegionWithLeader, StoreId},
+    store::{QueuedKvClient, RegionStore, StoreQueue},
+    Config, Error, Key, Result, Timestamp,
+};
+use async_trait::async_trait;
+use derive_new::new;
+use futures::stream::BoxStream;
+use slog::{Drain, Logger};
+use std::{
+    any::Any,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tikv_client_proto::{cdcpb, metapb, pdpb};
+use tikv_client_store::{ImportRequest, KvClient, KvConnect, Request};
+
+/// Create a `PdRpcClient` with it's internals replaced with mocks so that the
+/// client can be tested without doing any RPC calls.
+pub async fn pd_rpc_client() -> PdRpcClient<MockKvConnect, MockCluster> {
+    pd_rpc_client_with_config(Config::default()).await
+}
+
+/// Like [`pd_rpc_client`], with `config`.
+pub async fn pd_rpc_client_with_config(config: Config) -> PdRpcClient<MockKvConnect, MockCluster> {
+    let plain = slog_term::PlainSyncDecorator::new(std::io::stdout());
+    let logger = Logger::root(
+        slog_term::FullFormat::new(plain)
+            .build()
+            .filter_level(slog::Level::Info)
+            .fuse(),
+        o!(),
+    );
+    PdRpcClient::new(
+        config.clone(),
+        |_, _| MockKvConnect,
+        |e, sm| {
+            futures::future::ok(RetryClient::new_with_cluster(
+                e,
+                sm,
+                config.timeout,
+                MockCluster,
+            ))
+        },
+        false,
+        logger,
+    )
+    .await
+    .unwrap()
+}
+
+#[allow(clippy::type_complexity)]
+#[derive(new, Default, Clone)]
+pub struct MockKvClient {
+    pub addr: String,
+    dispatch: Option<Arc<dyn Fn(&dyn Any) -> Result<Box<dyn Any>> + Send + Sync + 'static>>,
+    #[new(default)]
+    event_feed: Option<Arc<EventFeedHook>>,
+}
+
+/// Answers the requests of an event feed with a stream of events.
+type EventFeedHook = dyn Fn(
+        BoxStream<'static, cdcpb::ChangeDataRequest>,
+    ) -> BoxStream<'static, Result<cdcpb::ChangeDataEvent>>
+    + Send
+    + Sync
+    + 'static;
+
+impl MockKvClient {
+    pub fn with_dispatch_hook<F>(dispatch: F) -> MockKvClient
+    where
+        F: Fn(&dyn Any) -> Result<Box<dyn Any>> + Send + Sync + 'static,
+    {
+        MockKvClient {
+            addr: String::new(),
+            dispatch: Some(Arc::new(dispatch)),
+            event_feed: None,
+        }
+    }
+
+    /// Answers the requests of each event feed with the stream of events returned by `feed`.
+    pub fn with_event_feed_hook<F>(mut self, feed: F) -> MockKvClient
+    where
+        F: Fn(
+                BoxStream<'static, cdcpb::ChangeDataRequest>,
+            ) -> BoxStream<'static, Result<cdcpb::ChangeDataEvent>>
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.event_feed = Some(Arc::new(feed));
+        self
+    }
+}
+
+pub struct MockKvConnect;
+
+pub struct MockCluster;
+
+#[derive(new)]
+pub struct MockPdClient {
+    client: MockKvClient,
+    /// Returned by `get_gc_safepoint`.
+    #[new(default)]
+    pub safepoint: AtomicU64,
+    /// Returned by `clock`.
+    #[new(default)]
+    pub clock: ClockHandle,
+    /// The keys at which `split_and_scatter_regions` was asked to split, the regions are not
+    /// actually split.
+    #[new(default)]
+    pub split_keys: Mutex<Vec<Key>>,
+    /// The regions `scatter_regions` was asked to scatter.
+    #[new(default)]
+    pub scattered: Mutex<Vec<RegionId>>,
+    /// The regions whose cache entries `invalidate_region_cache` was asked to drop, the regions
+    /// are not actually cached.
+    #[new(default)]
+    pub invalidated: Mutex<Vec<RegionVerId>>,
+    /// Returned by `key_validators`.
+    #[new(default)]
+    pub key_validators: Vec<Arc<dyn KeyValidator>>,
+    /// If set, the requests to all stores go through this queue.
+    #[new(default)]
+    pub store_queue: Option<StoreQueue>,
+}
+
+#[async_trait]
+impl KvClient for MockKvClient {
+    async fn dispatch(&self, req: &dyn Request) -> Result<Box<dyn Any>> {
+        match &self.dispatch {
+            Some(f) => f(req.as_any()),
+            None => panic!("no dispatch hook set"),
+        }
+    }
+
+    async fn dispatch_import(
+        &self,
+        req: &dyn ImportRequest,
+        _timeout: Duration,
+    ) -> Result<Box<dyn Any>> {
+        match &self.dispatch {
+            Some(f) => f(req.as_any()),
+            None => panic!("no dispatch hook set"),
+        }
+    }
+
+    fn event_feed(
+        &self,
+        requests: BoxStream<'static, cdcpb::ChangeDataRequest>,
+    ) -> Result<BoxStream<'static, Result<cdcpb::ChangeDataEvent>>> {
+        match &self.event_feed {
+            Some(f) => Ok(f(requests)),
+            None => panic!("no event feed hook set"),
+        }
+    }
+}
+
+impl KvConnect for MockKvConnect {
+    type KvClient = MockKvClient;
+
+    fn connect(&self, address: &str) -> Result<Self::KvClient> {
+        Ok(MockKvClient {
+            addr: address.to_owned(),
+            dispatch: None,
+            event_feed: None,
+        })
+    }
+}
+
+impl MockPdClient {
+    pub fn default() -> MockPdClient {
+        MockPdClient {
+            client: MockKvClient::default(),
+            safepoint: AtomicU64::new(0),
+            clock: ClockHandle::default(),
+            split_keys: Mutex::new(Vec::new()),
+            scattered: Mutex::new(Vec::new()),
+            invalidated: Mutex::new(Vec::new()),
+            key_validators: Vec::new(),
+            store_queue: None,
+        }
+    }
+
+    pub fn region1() -> RegionWithLeader {
+        let mut region = RegionWithLeader::default();
+        region.region.id = 1;
+        region.region.set_start_key(vec![]);
+        region.region.set_end_key(vec![10]);
+
+        let leader = metapb::Peer {
+            store_id: 41,
+            ..Default::default()
+        };
+        region.leader = Some(leader);
+
+        region
+    }
+
+    pub fn region2() -> RegionWithLeader {
+        let mut region = RegionWithLeader::default();
+        region.region.id = 2;
+        region.region.set_start_key(vec![10]);
+        region.region.set_end_key(vec![250, 250]);
+
+        let leader = metapb::Peer {
+            store_id: 42,
+            ..Default::default()
+        };
+        region.leader = Some(leader);
+
+        region
+    }
+
+    pub fn region3() -> RegionWithLeader {
+        let mut region = RegionWithLeader::default();
+        region.region.id = 3;
+        region.region.set_start_key(vec![250, 250]);
+        region.region.set_end_key(vec![]);
+
+        let leader = metapb::Peer {
+            store_id: 43,
+            ..Default::default()
+        };
+        region.leader = Some(leader);
+
+        region
+    }
+}
+
+#[async_trait]
+impl PdClient for MockPdClient {
+    type KvClient = MockKvClient;
+
+    async fn map_region_to_store(self: Arc<Self>, region: RegionWithLeader) -> Result<RegionStore> {
+        Ok(match &self.store_queue {
+            Some(queue) => RegionStore::new(
+                region,
+                Arc::new(QueuedKvClient::new(self.client.clone(), queue.clone())),
+            ),
+            None => RegionStore::new(region, Arc::new(self.client.clone())),
+        })
+    }
+
+    async fn region_for_key(&self, key: &Key) -> Result<RegionWithLeader> {
+        let bytes: &[_] = key.into();
+        let region = if bytes.is_empty() || bytes < &[10][..] {
+            Self::region1()
+        } else if bytes >= &[10][..] && bytes < &[250, 250][..] {
+            Self::region2()
+        } else {
+            Self::region3()
+        };
+
+        Ok(region)
+    }
+
+    async fn region_for_id(&self, id: RegionId) -> Result<RegionWithLeader> {
+        match id {
+            1 => Ok(Self::region1()),
+            2 => Ok(Self::region2()),
+            3 => Ok(Self::region3()),
+            _ => Err(Error::RegionNotFoundInResponse { region_id: id }),
+        }
+    }
+
+    async fn get_timestamp(self: Arc<Self>) -> Result<Timestamp> {
+        Ok(Timestamp::default())
+    }
+
+    fn clock(&self) -> ClockHandle {
+        self.clock.clone()
+    }
+
+    fn key_validators(&self) -> &[Arc<dyn KeyValidator>] {
+        &self.key_validators
+    }
+
+    async fn store_client(&self, _address: &str) -> Result<Arc<dyn KvClient + Send + Sync>> {
+        Ok(Arc::new(self.client.clone()))
+    }
+
+    async fn update_safepoint(self: Arc<Self>, safepoint: u64) -> Result<bool> {
+        Ok(self.safepoint.fetch_max(safepoint, Ordering::SeqCst) <= safepoint)
+    }
+
+    async fn get_gc_safepoint(self: Arc<Self>) -> Result<u64> {
+        Ok(self.safepoint.load(Ordering::SeqCst))
+    }
+
+    async fn members(self: Arc<Self>) -> Result<Vec<PdMember>> {
+        unimplemented!()
+    }
+
+    async fn pd_version(self: Arc<Self>) -> Result<String> {
+        unimplemented!()
+    }
+
+    async fn stores(self: Arc<Self>) -> Result<Vec<metapb::Store>> {
+        // the leaders of the regions, and a removed store
+        let mut stores: Vec<_> = (41..=44)
+            .map(|id| metapb::Store {
+                id,
+                address: format!("store{}", id),
+                ..Default::default()
+            })
+            .collect();
+        stores[3].set_state(metapb::StoreState::Tombstone);
+        Ok(stores)
+    }
+
+    async fn store_stats(self: Arc<Self>, id: StoreId) -> Result<pdpb::StoreStats> {
+        // each store leads a region, whose data takes a quarter of its disk
+        Ok(pdpb::StoreStats {
+            store_id: id,
+            capacity: 1 << 30,
+            available: 3 << 28,
+            used_size: 1 << 28,
+            region_count: 1,
+            ..Default::default()
+        })
+    }
+
+    async fn split_and_scatter_regions(
+        self: Arc<Self>,
+        split_keys: Vec<Key>,
+    ) -> Result<Vec<RegionId>> {
+        self.split_keys.lock().unwrap().extend(split_keys);
+        Ok(Vec::new())
+    }
+
+    async fn scatter_regions(self: Arc<Self>, region_ids: Vec<RegionId>) -> Result<()> {
+        self.scattered.lock().unwrap().extend(region_ids);
+        Ok(())
+    }
+
+    async fn update_leader(
+        &self,
+        _ver_id: crate::region::RegionVerId,
+        _leader: metapb::Peer,
+    ) -> Result<()> {
+        todo!()
+    }
+
+    async fn invalidate_region_cache(&self, ver_id: RegionVerId) {
+        self.invalidated.lock().unwrap().push(ver_id);
+    }
+}
@@ -0,0 +1,1489 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! An in-memory cluster of a single region on a single store, see [`MockTikv`].
+
+use crate::{
+    pd::{PdClient, PdMember},
+    region::{RegionId, RegionVerId, RegionWithLeader, StoreId},
+    store::RegionStore,
+    Error, Key, RawClient, Result, Timestamp, TimestampExt, Transaction, TransactionOptions,
+};
+use async_trait::async_trait;
+use futures::stream::BoxStream;
+use slog::Logger;
+use std::{
+    any::Any,
+    collections::{btree_map, BTreeMap, HashMap},
+    iter,
+    ops::Bound,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tikv_client_proto::{cdcpb, kvrpcpb, metapb, pdpb};
+use tikv_client_store::{ImportRequest, KvClient, Request};
+
+/// The id of the only region of the cluster, of its only store and of its only peer.
+const ID: u64 = 1;
+
+/// A raw client of a [`MockTikv`].
+pub type MockRawClient = RawClient<MockTikvPd>;
+
+/// A transaction of a [`MockTikv`].
+pub type MockTransaction = Transaction<MockTikvPd>;
+
+/// An in-memory cluster, whose clients are connected to the same data.
+///
+/// Clones of a cluster share its data, a new cluster is empty.
+///
+/// # Examples
+///
+/// ```rust
+/// # use tikv_client::mock::MockTikv;
+/// # #[tokio::main]
+/// # async fn main() -> tikv_client::Result<()> {
+/// let cluster = MockTikv::new();
+/// let client = cluster.transaction_client();
+///
+/// let mut first = client.begin_optimistic().await?;
+/// let mut second = client.begin_optimistic().await?;
+/// first.put("key".to_owned(), "first".to_owned()).await?;
+/// second.put("key".to_owned(), "second".to_owned()).await?;
+/// first.commit().await?;
+/// // the key was written since the second transaction started
+/// assert!(second.commit().await.is_err());
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Default)]
+pub struct MockTikv {
+    pd: Arc<MockTikvPd>,
+}
+
+impl MockTikv {
+    /// An empty cluster.
+    pub fn new() -> MockTikv {
+        MockTikv::default()
+    }
+
+    /// A raw client of the cluster.
+    ///
+    /// Raw data is separate from the data of transactions, and TTLs are ignored.
+    pub fn raw_client(&self) -> MockRawClient {
+        RawClient::new_with_rpc(self.pd.clone(), logger())
+    }
+
+    /// A transactional client of the cluster.
+    pub fn transaction_client(&self) -> MockTransactionClient {
+        MockTransactionClient {
+            pd: self.pd.clone(),
+            logger: logger(),
+        }
+    }
+}
+
+/// Begins transactions on a [`MockTikv`], like a
+/// [`TransactionClient`](crate::TransactionClient) does on a cluster.
+#[derive(Clone)]
+pub struct MockTransactionClient {
+    pd: Arc<MockTikvPd>,
+    logger: Logger,
+}
+
+impl MockTransactionClient {
+    /// Creates a new optimistic transaction, see
+    /// [`TransactionClient::begin_optimistic`](crate::TransactionClient::begin_optimistic).
+    pub async fn begin_optimistic(&self) -> Result<MockTransaction> {
+        self.begin_with_options(TransactionOptions::new_optimistic())
+            .await
+    }
+
+    /// Creates a new pessimistic transaction, see
+    /// [`TransactionClient::begin_pessimistic`](crate::TransactionClient::begin_pessimistic).
+    pub async fn begin_pessimistic(&self) -> Result<MockTransaction> {
+        self.begin_with_options(TransactionOptions::new_pessimistic())
+            .await
+    }
+
+    /// Creates a new customized transaction, see
+    /// [`TransactionClient::begin_with_options`](crate::TransactionClient::begin_with_options).
+    pub async fn begin_with_options(&self, options: TransactionOptions) -> Result<MockTransaction> {
+        let timestamp = self.current_timestamp().await?;
+        Ok(Transaction::new(
+            timestamp,
+            self.pd.clone(),
+            options,
+            self.logger.new(o!("child" => 1)),
+        ))
+    }
+
+    /// Retrieves the current timestamp of the cluster.
+    pub async fn current_timestamp(&self) -> Result<Timestamp> {
+        self.pd.clone().get_timestamp().await
+    }
+}
+
+/// The PD of a [`MockTikv`], placing all keys in a single region led by a single store.
+///
+/// Timestamps follow the wall clock, like the timestamps of PD, so that the locks of transactions
+/// expire after their TTL.
+#[derive(Default)]
+pub struct MockTikvPd {
+    store: MockTikvStore,
+    /// The last timestamp returned.
+    tso: Mutex<u64>,
+    safepoint: AtomicU64,
+}
+
+#[async_trait]
+impl PdClient for MockTikvPd {
+    type KvClient = MockTikvStore;
+
+    async fn map_region_to_store(self: Arc<Self>, region: RegionWithLeader) -> Result<RegionStore> {
+        Ok(RegionStore::new(region, Arc::new(self.store.clone())))
+    }
+
+    async fn region_for_key(&self, _key: &Key) -> Result<RegionWithLeader> {
+        Ok(region())
+    }
+
+    async fn region_for_id(&self, id: RegionId) -> Result<RegionWithLeader> {
+        match id {
+            ID => Ok(region()),
+            _ => Err(Error::RegionNotFoundInResponse { region_id: id }),
+        }
+    }
+
+    async fn get_timestamp(self: Arc<Self>) -> Result<Timestamp> {
+        let physical = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_millis() as i64);
+        let now = Timestamp {
+            physical,
+            ..Default::default()
+        };
+        let mut last = self.tso.lock().unwrap();
+        *last = (*last + 1).max(now.version());
+        Ok(Timestamp::from_version(*last))
+    }
+
+    async fn store_client(&self, _address: &str) -> Result<Arc<dyn KvClient + Send + Sync>> {
+        Ok(Arc::new(self.store.clone()))
+    }
+
+    async fn update_safepoint(self: Arc<Self>, safepoint: u64) -> Result<bool> {
+        Ok(self.safepoint.fetch_max(safepoint, Ordering::SeqCst) <= safepoint)
+    }
+
+    async fn get_gc_safepoint(self: Arc<Self>) -> Result<u64> {
+        Ok(self.safepoint.load(Ordering::SeqCst))
+    }
+
+    async fn members(self: Arc<Self>) -> Result<Vec<PdMember>> {
+        Err(Error::Unimplemented)
+    }
+
+    async fn pd_version(self: Arc<Self>) -> Result<String> {
+        Err(Error::Unimplemented)
+    }
+
+    async fn stores(self: Arc<Self>) -> Result<Vec<metapb::Store>> {
+        Ok(vec![metapb::Store {
+            id: ID,
+            address: "mock-tikv".to_owned(),
+            ..Default::default()
+        }])
+    }
+
+    async fn store_stats(self: Arc<Self>, _id: StoreId) -> Result<pdpb::StoreStats> {
+        Err(Error::Unimplemented)
+    }
+
+    async fn split_and_scatter_regions(
+        self: Arc<Self>,
+        _split_keys: Vec<Key>,
+    ) -> Result<Vec<RegionId>> {
+        Err(Error::Unimplemented)
+    }
+
+    async fn scatter_regions(self: Arc<Self>, _region_ids: Vec<RegionId>) -> Result<()> {
+        Err(Error::Unimplemented)
+    }
+
+    async fn update_leader(&self, _ver_id: RegionVerId, _leader: metapb::Peer) -> Result<()> {
+        Ok(())
+    }
+
+    async fn invalidate_region_cache(&self, _ver_id: RegionVerId) {}
+}
+
+/// The only store of a [`MockTikv`], serving the requests of its clients from memory.
+///
+/// Checksums, coprocessor requests, the TTLs of raw keys, imports and change feeds are not
+/// supported and fail with [`Unimplemented`](crate::Error::Unimplemented), as do the secondary
+/// locks of async commit transactions, which the store never writes.
+#[derive(Clone, Default)]
+pub struct MockTikvStore {
+    data: Arc<Mutex<Data>>,
+}
+
+#[async_trait]
+impl KvClient for MockTikvStore {
+    async fn dispatch(&self, req: &dyn Request) -> Result<Box<dyn Any>> {
+        self.data.lock().unwrap().dispatch(req.as_any())
+    }
+
+    async fn dispatch_import(
+        &self,
+        _req: &dyn ImportRequest,
+        _timeout: Duration,
+    ) -> Result<Box<dyn Any>> {
+        Err(Error::Unimplemented)
+    }
+
+    fn event_feed(
+        &self,
+        _requests: BoxStream<'static, cdcpb::ChangeDataRequest>,
+    ) -> Result<BoxStream<'static, Result<cdcpb::ChangeDataEvent>>> {
+        Err(Error::Unimplemented)
+    }
+}
+
+fn logger() -> Logger {
+    Logger::root(slog::Discard, o!())
+}
+
+/// The only region of the cluster, covering all keys.
+fn region() -> RegionWithLeader {
+    let mut region = RegionWithLeader::default();
+    region.region.id = ID;
+    let leader = metapb::Peer {
+        id: ID,
+        store_id: ID,
+        ..Default::default()
+    };
+    region.region.peers = vec![leader.clone()];
+    region.leader = Some(leader);
+    region
+}
+
+type KeyResult<T> = std::result::Result<T, kvrpcpb::KeyError>;
+
+/// Answers the requests whose type is listed by the method of `Data` handling it.
+macro_rules! dispatch {
+    ($data:expr, $req:expr, { $($request:ident => $handler:ident,)* }) => {
+        $(
+            if let Some(req) = $req.downcast_ref::<kvrpcpb::$request>() {
+                return Ok(Box::new($data.$handler(req)) as Box<dyn Any>);
+            }
+        )*
+    };
+}
+
+/// The data of a store.
+#[derive(Default)]
+struct Data {
+    /// The versions of the keys of transactions.
+    mvcc: BTreeMap<Vec<u8>, Versions>,
+    /// The pairs of raw clients by column family.
+    raw: HashMap<String, BTreeMap<Vec<u8>, Vec<u8>>>,
+}
+
+/// The lock and the writes of a key.
+#[derive(Default)]
+struct Versions {
+    lock: Option<Lock>,
+    /// The writes by commit timestamp. A rollback is written at the start timestamp of its
+    /// transaction, so that the transaction can't write the key after it was rolled back.
+    writes: BTreeMap<u64, Write>,
+}
+
+struct Lock {
+    op: kvrpcpb::Op,
+    primary: Vec<u8>,
+    start_ts: u64,
+    ttl: u64,
+    for_update_ts: u64,
+    min_commit_ts: u64,
+    txn_size: u64,
+    /// The value written once a `Put` is committed.
+    value: Vec<u8>,
+}
+
+struct Write {
+    start_ts: u64,
+    kind: WriteKind,
+}
+
+enum WriteKind {
+    Put(Vec<u8>),
+    Delete,
+    Lock,
+    Rollback,
+}
+
+impl Data {
+    fn dispatch(&mut self, req: &dyn Any) -> Result<Box<dyn Any>> {
+        dispatch!(self, req, {
+            GetRequest => get,
+            BatchGetRequest => batch_get,
+            ScanRequest => scan,
+            PessimisticLockRequest => pessimistic_lock,
+            PessimisticRollbackRequest => pessimistic_rollback,
+            PrewriteRequest => prewrite,
+            CommitRequest => commit,
+            BatchRollbackRequest => batch_rollback,
+            CleanupRequest => cleanup,
+            TxnHeartBeatRequest => txn_heart_beat,
+            CheckTxnStatusRequest => check_txn_status,
+            ResolveLockRequest => resolve_lock,
+            ScanLockRequest => scan_lock,
+            DeleteRangeRequest => delete_range,
+            MvccGetByKeyRequest => mvcc_get_by_key,
+            MvccGetByStartTsRequest => mvcc_get_by_start_ts,
+            RawGetRequest => raw_get,
+            RawBatchGetRequest => raw_batch_get,
+            RawPutRequest => raw_put,
+            RawBatchPutRequest => raw_batch_put,
+            RawDeleteRequest => raw_delete,
+            RawBatchDeleteRequest => raw_batch_delete,
+            RawScanRequest => raw_scan,
+            RawBatchScanRequest => raw_batch_scan,
+            RawDeleteRangeRequest => raw_delete_range,
+            RawCasRequest => raw_cas,
+        });
+        Err(Error::Unimplemented)
+    }
+
+    /// The value of `key` as of `ts`, failing if a transaction which may commit before `ts`
+    /// locked it, unless the lock is one of the `resolved_locks` of the request.
+    fn read(&self, key: &[u8], ts: u64, context: &kvrpcpb::Context) -> KeyResult<Option<Vec<u8>>> {
+        let versions = match self.mvcc.get(key) {
+            Some(versions) => versions,
+            None => return Ok(None),
+        };
+        if let Some(lock) = &versions.lock {
+            let blocks = lock.start_ts <= ts
+                && lock.min_commit_ts <= ts
+                && !matches!(lock.op, kvrpcpb::Op::Lock | kvrpcpb::Op::PessimisticLock)
+                && !context.resolved_locks.contains(&lock.start_ts);
+            if blocks {
+                return Err(locked(key, lock));
+            }
+        }
+        Ok(versions.value(ts).cloned())
+    }
+
+    fn get(&self, req: &kvrpcpb::GetRequest) -> kvrpcpb::GetResponse {
+        let mut resp = kvrpcpb::GetResponse::default();
+        match self.read(&req.key, req.version, req.get_context()) {
+            Ok(Some(value)) => resp.value = value,
+            Ok(None) => resp.not_found = true,
+            Err(error) => resp.error = Some(error),
+        }
+        resp
+    }
+
+    fn batch_get(&self, req: &kvrpcpb::BatchGetRequest) -> kvrpcpb::BatchGetResponse {
+        let pairs = req
+            .keys
+            .iter()
+            .filter_map(|key| self.read_pair(key, req.version, req.get_context(), false))
+            .collect();
+        kvrpcpb::BatchGetResponse {
+            pairs,
+            ..Default::default()
+        }
+    }
+
+    fn scan(&self, req: &kvrpcpb::ScanRequest) -> kvrpcpb::ScanResponse {
+        // a reverse scan scans from `start_key`, excluded, down to `end_key`
+        let (start, end) = if req.reverse {
+            (&req.end_key, &req.start_key)
+        } else {
+            (&req.start_key, &req.end_key)
+        };
+        let mut keys: Vec<&Vec<u8>> = range(&self.mvcc, start, end).map(|(key, _)| key).collect();
+        if req.reverse {
+            keys.reverse();
+        }
+        let pairs = keys
+            .into_iter()
+            .filter_map(|key| self.read_pair(key, req.version, req.get_context(), req.key_only))
+            .take(req.limit as usize)
+            .collect();
+        kvrpcpb::ScanResponse {
+            pairs,
+            ..Default::default()
+        }
+    }
+
+    /// The pair of `key` read at `ts`, `None` if it doesn't exist.
+    fn read_pair(
+        &self,
+        key: &[u8],
+        ts: u64,
+        context: &kvrpcpb::Context,
+        key_only: bool,
+    ) -> Option<kvrpcpb::KvPair> {
+        match self.read(key, ts, context) {
+            Ok(value) => value.map(|value| pair(key, value, key_only)),
+            Err(error) => Some(kvrpcpb::KvPair {
+                error: Some(error),
+                key: key.to_vec(),
+                ..Default::default()
+            }),
+        }
+    }
+
+    fn pessimistic_lock(
+        &mut self,
+        req: &kvrpcpb::PessimisticLockRequest,
+    ) -> kvrpcpb::PessimisticLockResponse {
+        let mut resp = kvrpcpb::PessimisticLockResponse::default();
+        // the keys are locked all or nothing
+        let mut values = Vec::new();
+        for mutation in &req.mutations {
+            match self.check_pessimistic_lock(req, mutation) {
+                Ok(value) => values.push(value),
+                Err(error) => resp.errors.push(error),
+            }
+        }
+        if !resp.errors.is_empty() {
+            return resp;
+        }
+
+        for mutation in &req.mutations {
+            let versions = self.mvcc.entry(mutation.key.clone()).or_default();
+            match &mut versions.lock {
+                // locked again by the transaction
+                Some(lock) => lock.for_update_ts = lock.for_update_ts.max(req.for_update_ts),
+                None => {
+                    versions.lock = Some(Lock {
+                        op: kvrpcpb::Op::PessimisticLock,
+                        primary: req.primary_lock.clone(),
+                        start_ts: req.start_version,
+                        ttl: req.lock_ttl,
+                        for_update_ts: req.for_update_ts,
+                        min_commit_ts: req.min_commit_ts,
+                        txn_size: 0,
+                        value: Vec::new(),
+                    })
+                }
+            }
+        }
+        if req.return_values || req.check_existence {
+            resp.not_founds = values.iter().map(Option::is_none).collect();
+            if req.return_values {
+                resp.values = values.into_iter().map(Option::unwrap_or_default).collect();
+            }
+        }
+        resp
+    }
+
+    /// Checks that the transaction of `req` can lock the key of `mutation`, returning its latest
+    /// value.
+    fn check_pessimistic_lock(
+        &self,
+        req: &kvrpcpb::PessimisticLockRequest,
+        mutation: &kvrpcpb::Mutation,
+    ) -> KeyResult<Option<Vec<u8>>> {
+        let key = &mutation.key;
+        let versions = match self.mvcc.get(key) {
+            Some(versions) => versions,
+            None => return Ok(None),
+        };
+        let value = versions.value(u64::MAX).cloned();
+        match &versions.lock {
+            Some(lock) if lock.start_ts != req.start_version => return Err(locked(key, lock)),
+            Some(_) => return Ok(value),
+            None => {}
+        }
+        if versions.is_rolled_back(req.start_version) {
+            return Err(abort(format!(
+                "PessimisticLockRolledBack {{ start_ts: {}, key: {:?} }}",
+                req.start_version, key
+            )));
+        }
+        if let Some((commit_ts, write)) = versions.latest_commit() {
+            if commit_ts > req.for_update_ts {
+                return Err(conflict(
+                    key,
+                    req.start_version,
+                    &req.primary_lock,
+                    write.start_ts,
+                    commit_ts,
+                ));
+            }
+        }
+        if value.is_some() && mutation.get_assertion() == kvrpcpb::Assertion::NotExist {
+            return Err(already_exist(key));
+        }
+        Ok(value)
+    }
+
+    fn pessimistic_rollback(
+        &mut self,
+        req: &kvrpcpb::PessimisticRollbackRequest,
+    ) -> kvrpcpb::PessimisticRollbackResponse {
+        for key in &req.keys {
+            if let Some(versions) = self.mvcc.get_mut(key) {
+                let is_own = versions.lock.as_ref().is_some_and(|lock| {
+                    lock.is_pessimistic()
+                        && lock.start_ts == req.start_version
+                        && lock.for_update_ts <= req.for_update_ts
+                });
+                if is_own {
+                    versions.lock = None;
+                }
+            }
+        }
+        kvrpcpb::PessimisticRollbackResponse::default()
+    }
+
+    fn prewrite(&mut self, req: &kvrpcpb::PrewriteRequest) -> kvrpcpb::PrewriteResponse {
+        let mut resp = kvrpcpb::PrewriteResponse::default();
+        // the keys are prewritten all or nothing
+        for (i, mutation) in req.mutations.iter().enumerate() {
+            let is_pessimistic_lock = req.is_pessimistic_lock.get(i).copied().unwrap_or(false);
+            if let Err(error) = self.check_prewrite(req, mutation, is_pessimistic_lock) {
+                resp.errors.push(error);
+            }
+        }
+        if !resp.errors.is_empty() {
+            return resp;
+        }
+
+        for mutation in &req.mutations {
+            let op = match mutation.get_op() {
+                kvrpcpb::Op::CheckNotExists => continue,
+                kvrpcpb::Op::Insert => kvrpcpb::Op::Put,
+                op => op,
+            };
+            let versions = self.mvcc.entry(mutation.key.clone()).or_default();
+            // a pushed `min_commit_ts` is kept when the key is prewritten again
+            let min_commit_ts = versions
+                .lock
+                .as_ref()
+                .map_or(0, |lock| lock.min_commit_ts)
+                .max(req.min_commit_ts);
+            versions.lock = Some(Lock {
+                op,
+                primary: req.primary_lock.clone(),
+                start_ts: req.start_version,
+                ttl: req.lock_ttl,
+                for_update_ts: req.for_update_ts,
+                min_commit_ts,
+                txn_size: req.txn_size,
+                value: mutation.value.clone(),
+            });
+        }
+        // a zero `min_commit_ts` and `one_pc_commit_ts` let transactions fall back to two-phase
+        // commit
+        resp
+    }
+
+    /// Checks that the transaction of `req` can prewrite `mutation`.
+    fn check_prewrite(
+        &self,
+        req: &kvrpcpb::PrewriteRequest,
+        mutation: &kvrpcpb::Mutation,
+        is_pessimistic_lock: bool,
+    ) -> KeyResult<()> {
+        let key = &mutation.key;
+        let empty = Versions::default();
+        let versions = self.mvcc.get(key).unwrap_or(&empty);
+        let has_pessimistic_lock = match &versions.lock {
+            Some(lock) if lock.start_ts != req.start_version => return Err(locked(key, lock)),
+            // prewritten again
+            Some(lock) if !lock.is_pessimistic() => return Ok(()),
+            lock => lock.is_some(),
+        };
+        if is_pessimistic_lock && !has_pessimistic_lock {
+            return Err(abort(format!(
+                "PessimisticLockNotFound {{ start_ts: {}, key: {:?} }}",
+                req.start_version, key
+            )));
+        }
+        // the keys locked by the transaction can't have been written since they were locked
+        if !has_pessimistic_lock {
+            if versions.is_rolled_back(req.start_version) {
+                return Err(conflict(
+                    key,
+                    req.start_version,
+                    &req.primary_lock,
+                    req.start_version,
+                    req.start_version,
+                ));
+            }
+            if let Some((commit_ts, write)) = versions.latest_commit() {
+                if commit_ts > req.start_version {
+                    return Err(conflict(
+                        key,
+                        req.start_version,
+                        &req.primary_lock,
+                        write.start_ts,
+                        commit_ts,
+                    ));
+                }
+            }
+        }
+
+        let exists = versions.value(u64::MAX).is_some();
+        if exists
+            && matches!(
+                mutation.get_op(),
+                kvrpcpb::Op::Insert | kvrpcpb::Op::CheckNotExists
+            )
+        {
+            return Err(already_exist(key));
+        }
+        let failed = match mutation.get_assertion() {
+            kvrpcpb::Assertion::None => false,
+            kvrpcpb::Assertion::Exist => !exists,
+            kvrpcpb::Assertion::NotExist => exists,
+        };
+        if failed && req.get_assertion_level() != kvrpcpb::AssertionLevel::Off {
+            return Err(kvrpcpb::KeyError {
+                assertion_failed: Some(kvrpcpb::AssertionFailed {
+                    start_ts: req.start_version,
+                    key: key.clone(),
+                    assertion: mutation.assertion,
+                    ..Default::default()
+                }),
+                ..Default::default()
+            });
+        }
+        Ok(())
+    }
+
+    fn commit(&mut self, req: &kvrpcpb::CommitRequest) -> kvrpcpb::CommitResponse {
+        let mut resp = kvrpcpb::CommitResponse::default();
+        for key in &req.keys {
+            if let Err(error) = self.check_commit(key, req.start_version, req.commit_version) {
+                resp.error = Some(error);
+                return resp;
+            }
+        }
+        for key in &req.keys {
+            self.commit_key(key, req.start_version, req.commit_version);
+        }
+        resp.commit_version = req.commit_version;
+        resp
+    }
+
+    /// Checks that the transaction started at `start_ts` can commit `key` at `commit_ts`.
+    fn check_commit(&self, key: &[u8], start_ts: u64, commit_ts: u64) -> KeyResult<()> {
+        let versions = self.mvcc.get(key);
+        match versions.and_then(|versions| versions.lock.as_ref()) {
+            Some(lock) if lock.start_ts == start_ts && lock.is_pessimistic() => {
+                Err(abort(format!(
+                    "PessimisticLockNotPrewritten {{ start_ts: {}, key: {:?} }}",
+                    start_ts, key
+                )))
+            }
+            Some(lock) if lock.start_ts == start_ts && commit_ts < lock.min_commit_ts => {
+                Err(kvrpcpb::KeyError {
+                    commit_ts_expired: Some(kvrpcpb::CommitTsExpired {
+                        start_ts,
+                        attempted_commit_ts: commit_ts,
+                        key: key.to_vec(),
+                        min_commit_ts: lock.min_commit_ts,
+                    }),
+                    ..Default::default()
+                })
+            }
+            Some(lock) if lock.start_ts == start_ts => Ok(()),
+            // committed before, e.g. by a request which is retried
+            _ if self.commit_ts(key, start_ts).is_some() => Ok(()),
+            _ => Err(kvrpcpb::KeyError {
+                retryable: format!(
+                    "TxnLockNotFound {{ start_ts: {}, commit_ts: {}, key: {:?} }}",
+                    start_ts, commit_ts, key
+                ),
+                ..Default::default()
+            }),
+        }
+    }
+
+    /// Commits the lock of the transaction started at `start_ts` on `key`, if any.
+    fn commit_key(&mut self, key: &[u8], start_ts: u64, commit_ts: u64) {
+        let versions = match self.mvcc.get_mut(key) {
+            Some(versions) => versions,
+            None => return,
+        };
+        match versions.lock.take() {
+            Some(lock) if lock.start_ts == start_ts => {
+                let kind = match lock.op {
+                    kvrpcpb::Op::Put => WriteKind::Put(lock.value),
+                    kvrpcpb::Op::Del => WriteKind::Delete,
+                    kvrpcpb::Op::Lock => WriteKind::Lock,
+                    // the key was locked but not written
+                    _ => return,
+                };
+                versions.writes.insert(commit_ts, Write { start_ts, kind });
+            }
+            lock => versions.lock = lock,
+        }
+    }
+
+    /// Rolls back the transaction started at `start_ts` on `key`, releasing its lock.
+    fn rollback_key(&mut self, key: &[u8], start_ts: u64) {
+        let versions = self.mvcc.entry(key.to_vec()).or_default();
+        if versions
+            .lock
+            .as_ref()
+            .is_some_and(|lock| lock.start_ts == start_ts)
+        {
+            versions.lock = None;
+        }
+        versions.writes.entry(start_ts).or_insert(Write {
+            start_ts,
+            kind: WriteKind::Rollback,
+        });
+    }
+
+    /// The commit timestamp of the transaction started at `start_ts` on `key`, if it committed.
+    fn commit_ts(&self, key: &[u8], start_ts: u64) -> Option<u64> {
+        match self.mvcc.get(key)?.write_of(start_ts)? {
+            (
+                _,
+                Write {
+                    kind: WriteKind::Rollback,
+                    ..
+                },
+            ) => None,
+            (commit_ts, _) => Some(commit_ts),
+        }
+    }
+
+    fn batch_rollback(
+        &mut self,
+        req: &kvrpcpb::BatchRollbackRequest,
+    ) -> kvrpcpb::BatchRollbackResponse {
+        let mut resp = kvrpcpb::BatchRollbackResponse::default();
+        for key in &req.keys {
+            if let Some(commit_ts) = self.commit_ts(key, req.start_version) {
+                resp.error = Some(abort(format!("Committed {{ commit_ts: {} }}", commit_ts)));
+                return resp;
+            }
+        }
+        for key in &req.keys {
+            self.rollback_key(key, req.start_version);
+        }
+        resp
+    }
+
+    fn cleanup(&mut self, req: &kvrpcpb::CleanupRequest) -> kvrpcpb::CleanupResponse {
+        let mut resp = kvrpcpb::CleanupResponse::default();
+        if let Some(commit_ts) = self.commit_ts(&req.key, req.start_version) {
+            resp.error = Some(abort(format!("Committed {{ commit_ts: {} }}", commit_ts)));
+            resp.commit_version = commit_ts;
+            return resp;
+        }
+        let lock = self
+            .mvcc
+            .get(&req.key)
+            .and_then(|versions| versions.lock.as_ref());
+        if let Some(lock) = lock.filter(|lock| lock.start_ts == req.start_version) {
+            // a zero `current_ts` rolls the transaction back whether its lock expired or not
+            if req.current_ts != 0 && !lock.is_expired(req.current_ts) {
+                resp.error = Some(locked(&req.key, lock));
+                return resp;
+            }
+        }
+        self.rollback_key(&req.key, req.start_version);
+        resp
+    }
+
+    fn txn_heart_beat(
+        &mut self,
+        req: &kvrpcpb::TxnHeartBeatRequest,
+    ) -> kvrpcpb::TxnHeartBeatResponse {
+        let mut resp = kvrpcpb::TxnHeartBeatResponse::default();
+        let lock = self
+            .mvcc
+            .get_mut(&req.primary_lock)
+            .and_then(|versions| versions.lock.as_mut());
+        match lock {
+            Some(lock) if lock.start_ts == req.start_version => {
+                lock.ttl = lock.ttl.max(req.advise_lock_ttl);
+                resp.lock_ttl = lock.ttl;
+            }
+            _ => resp.error = Some(txn_not_found(req.start_version, &req.primary_lock)),
+        }
+        resp
+    }
+
+    fn check_txn_status(
+        &mut self,
+        req: &kvrpcpb::CheckTxnStatusRequest,
+    ) -> kvrpcpb::CheckTxnStatusResponse {
+        let mut resp = kvrpcpb::CheckTxnStatusResponse::default();
+        let key = &req.primary_key;
+        let lock = self
+            .mvcc
+            .get_mut(key)
+            .and_then(|versions| versions.lock.as_mut())
+            .filter(|lock| lock.start_ts == req.lock_ts);
+        if let Some(lock) = lock {
+            if !lock.is_expired(req.current_ts) {
+                // the transaction commits after the caller, which can read the previous values
+                // of its keys
+                if req.caller_start_ts > 0 && lock.min_commit_ts <= req.caller_start_ts {
+                    lock.min_commit_ts = req.caller_start_ts.saturating_add(1);
+                }
+                resp.lock_ttl = lock.ttl;
+                resp.lock_info = Some(lock.info(key));
+                return resp;
+            }
+            if lock.is_pessimistic() && req.resolving_pessimistic_lock {
+                // only the pessimistic lock is released, the transaction fails when it prewrites
+                if let Some(versions) = self.mvcc.get_mut(key) {
+                    versions.lock = None;
+                }
+            } else {
+                self.rollback_key(key, req.lock_ts);
+            }
+            return resp;
+        }
+
+        match self
+            .mvcc
+            .get(key)
+            .and_then(|versions| versions.write_of(req.lock_ts))
+        {
+            Some((
+                _,
+                Write {
+                    kind: WriteKind::Rollback,
+                    ..
+                },
+            )) => {}
+            Some((commit_ts, _)) => resp.commit_version = commit_ts,
+            None if req.rollback_if_not_exist => self.rollback_key(key, req.lock_ts),
+            None => resp.error = Some(txn_not_found(req.lock_ts, key)),
+        }
+        resp
+    }
+
+    fn resolve_lock(&mut self, req: &kvrpcpb::ResolveLockRequest) -> kvrpcpb::ResolveLockResponse {
+        // the commit version of each transaction, 0 if it is rolled back
+        let txns: HashMap<u64, u64> = if req.txn_infos.is_empty() {
+            iter::once((req.start_version, req.commit_version)).collect()
+        } else {
+            req.txn_infos
+                .iter()
+                .map(|info| (info.txn, info.status))
+                .collect()
+        };
+        let keys: Vec<Vec<u8>> = if req.keys.is_empty() {
+            self.mvcc
+                .iter()
+                .filter(|(_, versions)| {
+                    let lock = versions.lock.as_ref();
+                    lock.is_some_and(|lock| txns.contains_key(&lock.start_ts))
+                })
+                .map(|(key, _)| key.clone())
+                .collect()
+        } else {
+            req.keys.clone()
+        };
+        for key in keys {
+            let lock = self
+                .mvcc
+                .get(&key)
+                .and_then(|versions| versions.lock.as_ref());
+            let start_ts = match lock {
+                Some(lock) => lock.start_ts,
+                None => continue,
+            };
+            match txns.get(&start_ts) {
+                Some(0) => self.rollback_key(&key, start_ts),
+                Some(&commit_ts) => self.commit_key(&key, start_ts, commit_ts),
+                None => {}
+            }
+        }
+        kvrpcpb::ResolveLockResponse::default()
+    }
+
+    fn scan_lock(&self, req: &kvrpcpb::ScanLockRequest) -> kvrpcpb::ScanLockResponse {
+        let locks =
+            range(&self.mvcc, &req.start_key, &req.end_key).filter_map(|(key, versions)| {
+                let lock = versions.lock.as_ref()?;
+                (lock.start_ts <= req.max_version).then(|| lock.info(key))
+            });
+        let locks = match req.limit {
+            0 => locks.collect(),
+            limit => locks.take(limit as usize).collect(),
+        };
+        kvrpcpb::ScanLockResponse {
+            locks,
+            ..Default::default()
+        }
+    }
+
+    fn delete_range(&mut self, req: &kvrpcpb::DeleteRangeRequest) -> kvrpcpb::DeleteRangeResponse {
+        if !req.notify_only {
+            let keys: Vec<Vec<u8>> = range(&self.mvcc, &req.start_key, &req.end_key)
+                .map(|(key, _)| key.clone())
+                .collect();
+            for key in keys {
+                self.mvcc.remove(&key);
+            }
+        }
+        kvrpcpb::DeleteRangeResponse::default()
+    }
+
+    fn mvcc_get_by_key(&self, req: &kvrpcpb::MvccGetByKeyRequest) -> kvrpcpb::MvccGetByKeyResponse {
+        kvrpcpb::MvccGetByKeyResponse {
+            info: self.mvcc.get(&req.key).map(Versions::mvcc_info),
+            ..Default::default()
+        }
+    }
+
+    fn mvcc_get_by_start_ts(
+        &self,
+        req: &kvrpcpb::MvccGetByStartTsRequest,
+    ) -> kvrpcpb::MvccGetByStartTsResponse {
+        let found = self.mvcc.iter().find(|(_, versions)| {
+            versions
+                .lock
+                .as_ref()
+                .is_some_and(|lock| lock.start_ts == req.start_ts)
+                || versions
+                    .writes
+                    .values()
+                    .any(|write| write.start_ts == req.start_ts)
+        });
+        match found {
+            Some((key, versions)) => kvrpcpb::MvccGetByStartTsResponse {
+                key: key.clone(),
+                info: Some(versions.mvcc_info()),
+                ..Default::default()
+            },
+            None => kvrpcpb::MvccGetByStartTsResponse::default(),
+        }
+    }
+
+    /// The raw pairs of the column family `cf`.
+    fn raw_cf(&mut self, cf: &str) -> &mut BTreeMap<Vec<u8>, Vec<u8>> {
+        let cf = if cf.is_empty() { "default" } else { cf };
+        self.raw.entry(cf.to_owned()).or_default()
+    }
+
+    fn raw_get(&mut self, req: &kvrpcpb::RawGetRequest) -> kvrpcpb::RawGetResponse {
+        let mut resp = kvrpcpb::RawGetResponse::default();
+        match self.raw_cf(&req.cf).get(&req.key) {
+            Some(value) => resp.value = value.clone(),
+            None => resp.not_found = true,
+        }
+        resp
+    }
+
+    fn raw_batch_get(&mut self, req: &kvrpcpb::RawBatchGetRequest) -> kvrpcpb::RawBatchGetResponse {
+        let data = self.raw_cf(&req.cf);
+        let pairs = req
+            .keys
+            .iter()
+            .filter_map(|key| Some(pair(key, data.get(key)?.clone(), false)))
+            .collect();
+        kvrpcpb::RawBatchGetResponse {
+            pairs,
+            ..Default::default()
+        }
+    }
+
+    fn raw_put(&mut self, req: &kvrpcpb::RawPutRequest) -> kvrpcpb::RawPutResponse {
+        self.raw_cf(&req.cf)
+            .insert(req.key.clone(), req.value.clone());
+        kvrpcpb::RawPutResponse::default()
+    }
+
+    fn raw_batch_put(&mut self, req: &kvrpcpb::RawBatchPutRequest) -> kvrpcpb::RawBatchPutResponse {
+        let data = self.raw_cf(&req.cf);
+        for pair in &req.pairs {
+            data.insert(pair.key.clone(), pair.value.clone());
+        }
+        kvrpcpb::RawBatchPutResponse::default()
+    }
+
+    fn raw_delete(&mut self, req: &kvrpcpb::RawDeleteRequest) -> kvrpcpb::RawDeleteResponse {
+        self.raw_cf(&req.cf).remove(&req.key);
+        kvrpcpb::RawDeleteResponse::default()
+    }
+
+    fn raw_batch_delete(
+        &mut self,
+        req: &kvrpcpb::RawBatchDeleteRequest,
+    ) -> kvrpcpb::RawBatchDeleteResponse {
+        let data = self.raw_cf(&req.cf);
+        for key in &req.keys {
+            data.remove(key);
+        }
+        kvrpcpb::RawBatchDeleteResponse::default()
+    }
+
+    fn raw_scan(&mut self, req: &kvrpcpb::RawScanRequest) -> kvrpcpb::RawScanResponse {
+        let (start, end) = if req.reverse {
+            (&req.end_key, &req.start_key)
+        } else {
+            (&req.start_key, &req.end_key)
+        };
+        let kvs = scan_pairs(
+            self.raw_cf(&req.cf),
+            start,
+            end,
+            req.limit,
+            req.reverse,
+            req.key_only,
+        );
+        kvrpcpb::RawScanResponse {
+            kvs,
+            ..Default::default()
+        }
+    }
+
+    fn raw_batch_scan(
+        &mut self,
+        req: &kvrpcpb::RawBatchScanRequest,
+    ) -> kvrpcpb::RawBatchScanResponse {
+        let data = &*self.raw_cf(&req.cf);
+        let kvs = req
+            .ranges
+            .iter()
+            .flat_map(|key_range| {
+                let (start, end) = if req.reverse {
+                    (&key_range.end_key, &key_range.start_key)
+                } else {
+                    (&key_range.start_key, &key_range.end_key)
+                };
+                scan_pairs(data, start, end, req.each_limit, req.reverse, req.key_only)
+            })
+            .collect();
+        kvrpcpb::RawBatchScanResponse {
+            kvs,
+            ..Default::default()
+        }
+    }
+
+    fn raw_delete_range(
+        &mut self,
+        req: &kvrpcpb::RawDeleteRangeRequest,
+    ) -> kvrpcpb::RawDeleteRangeResponse {
+        let data = self.raw_cf(&req.cf);
+        let keys: Vec<Vec<u8>> = range(data, &req.start_key, &req.end_key)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in keys {
+            data.remove(&key);
+        }
+        kvrpcpb::RawDeleteRangeResponse::default()
+    }
+
+    fn raw_cas(&mut self, req: &kvrpcpb::RawCasRequest) -> kvrpcpb::RawCasResponse {
+        let data = self.raw_cf(&req.cf);
+        let previous = data.get(&req.key).cloned();
+        let succeed = match &previous {
+            Some(value) => !req.previous_not_exist && *value == req.previous_value,
+            None => req.previous_not_exist,
+        };
+        if succeed {
+            data.insert(req.key.clone(), req.value.clone());
+        }
+        kvrpcpb::RawCasResponse {
+            succeed,
+            previous_not_exist: previous.is_none(),
+            previous_value: previous.unwrap_or_default(),
+            ..Default::default()
+        }
+    }
+}
+
+impl Versions {
+    /// The value as of `ts`.
+    fn value(&self, ts: u64) -> Option<&Vec<u8>> {
+        self.writes
+            .range(..=ts)
+            .rev()
+            .find_map(|(_, write)| match &write.kind {
+                WriteKind::Put(value) => Some(Some(value)),
+                WriteKind::Delete => Some(None),
+                WriteKind::Lock | WriteKind::Rollback => None,
+            })
+            .flatten()
+    }
+
+    /// The latest write which is not a rollback, with its commit timestamp.
+    fn latest_commit(&self) -> Option<(u64, &Write)> {
+        self.writes
+            .iter()
+            .rev()
+            .find(|(_, write)| !matches!(write.kind, WriteKind::Rollback))
+            .map(|(commit_ts, write)| (*commit_ts, write))
+    }
+
+    /// The write of the transaction started at `start_ts`, with its commit timestamp.
+    fn write_of(&self, start_ts: u64) -> Option<(u64, &Write)> {
+        self.writes
+            .range(start_ts..)
+            .find(|(_, write)| write.start_ts == start_ts)
+            .map(|(commit_ts, write)| (*commit_ts, write))
+    }
+
+    /// The history of the key, the values are all short values since there is no default column
+    /// family.
+    fn mvcc_info(&self) -> kvrpcpb::MvccInfo {
+        let lock = self.lock.as_ref().map(|lock| {
+            let mut info = kvrpcpb::MvccLock {
+                start_ts: lock.start_ts,
+                primary: lock.primary.clone(),
+                short_value: lock.value.clone(),
+                ttl: lock.ttl,
+                for_update_ts: lock.for_update_ts,
+                txn_size: lock.txn_size,
+                ..Default::default()
+            };
+            info.set_type(lock.op);
+            info
+        });
+        let writes = self
+            .writes
+            .iter()
+            .rev()
+            .map(|(commit_ts, write)| {
+                let (op, short_value) = match &write.kind {
+                    WriteKind::Put(value) => (kvrpcpb::Op::Put, value.clone()),
+                    WriteKind::Delete => (kvrpcpb::Op::Del, Vec::new()),
+                    WriteKind::Lock => (kvrpcpb::Op::Lock, Vec::new()),
+                    WriteKind::Rollback => (kvrpcpb::Op::Rollback, Vec::new()),
+                };
+                let mut info = kvrpcpb::MvccWrite {
+                    start_ts: write.start_ts,
+                    commit_ts: *commit_ts,
+                    short_value,
+                    ..Default::default()
+                };
+                info.set_type(op);
+                info
+            })
+            .collect();
+        kvrpcpb::MvccInfo {
+            lock,
+            writes,
+            ..Default::default()
+        }
+    }
+
+    fn is_rolled_back(&self, start_ts: u64) -> bool {
+        matches!(
+            self.writes.get(&start_ts),
+            Some(Write {
+                kind: WriteKind::Rollback,
+                ..
+            })
+        )
+    }
+}
+
+impl Lock {
+    fn info(&self, key: &[u8]) -> kvrpcpb::LockInfo {
+        let mut info = kvrpcpb::LockInfo {
+            primary_lock: self.primary.clone(),
+            lock_version: self.start_ts,
+            key: key.to_vec(),
+            lock_ttl: self.ttl,
+            txn_size: self.txn_size,
+            lock_for_update_ts: self.for_update_ts,
+            min_commit_ts: self.min_commit_ts,
+            ..Default::default()
+        };
+        info.set_lock_type(self.op);
+        info
+    }
+
+    fn is_pessimistic(&self) -> bool {
+        self.op == kvrpcpb::Op::PessimisticLock
+    }
+
+    /// Whether the TTL of the lock elapsed at `ts`.
+    fn is_expired(&self, ts: u64) -> bool {
+        let elapsed_ms =
+            Timestamp::from_version(ts).physical - Timestamp::from_version(self.start_ts).physical;
+        elapsed_ms >= self.ttl as i64
+    }
+}
+
+/// The entries of `map` from `start` to `end`, excluded, an empty `end` being unbounded.
+fn range<'a, V>(
+    map: &'a BTreeMap<Vec<u8>, V>,
+    start: &[u8],
+    end: &[u8],
+) -> btree_map::Range<'a, Vec<u8>, V> {
+    let end = if end.is_empty() {
+        Bound::Unbounded
+    } else {
+        // an inverted range is empty
+        Bound::Excluded(end.max(start))
+    };
+    map.range::<[u8], _>((Bound::Included(start), end))
+}
+
+/// The first `limit` raw pairs from `start` to `end`, excluded, in reverse order if `reverse`.
+fn scan_pairs(
+    data: &BTreeMap<Vec<u8>, Vec<u8>>,
+    start: &[u8],
+    end: &[u8],
+    limit: u32,
+    reverse: bool,
+    key_only: bool,
+) -> Vec<kvrpcpb::KvPair> {
+    let pairs = range(data, start, end).map(|(key, value)| pair(key, value.clone(), key_only));
+    if reverse {
+        pairs.rev().take(limit as usize).collect()
+    } else {
+        pairs.take(limit as usize).collect()
+    }
+}
+
+fn pair(key: &[u8], value: Vec<u8>, key_only: bool) -> kvrpcpb::KvPair {
+    kvrpcpb::KvPair {
+        key: key.to_vec(),
+        value: if key_only { Vec::new() } else { value },
+        ..Default::default()
+    }
+}
+
+fn locked(key: &[u8], lock: &Lock) -> kvrpcpb::KeyError {
+    kvrpcpb::KeyError {
+        locked: Some(lock.info(key)),
+        ..Default::default()
+    }
+}
+
+fn abort(reason: String) -> kvrpcpb::KeyError {
+    kvrpcpb::KeyError {
+        abort: reason,
+        ..Default::default()
+    }
+}
+
+fn conflict(
+    key: &[u8],
+    start_ts: u64,
+    primary: &[u8],
+    conflict_ts: u64,
+    conflict_commit_ts: u64,
+) -> kvrpcpb::KeyError {
+    kvrpcpb::KeyError {
+        conflict: Some(kvrpcpb::WriteConflict {
+            start_ts,
+            conflict_ts,
+            key: key.to_vec(),
+            primary: primary.to_vec(),
+            conflict_commit_ts,
+        }),
+        ..Default::default()
+    }
+}
+
+fn already_exist(key: &[u8]) -> kvrpcpb::KeyError {
+    kvrpcpb::KeyError {
+        already_exist: Some(kvrpcpb::AlreadyExist { key: key.to_vec() }),
+        ..Default::default()
+    }
+}
+
+fn txn_not_found(start_ts: u64, primary_key: &[u8]) -> kvrpcpb::KeyError {
+    kvrpcpb::KeyError {
+        txn_not_found: Some(kvrpcpb::TxnNotFound {
+            start_ts,
+            primary_key: primary_key.to_vec(),
+        }),
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MvccKind, PessimisticLockOptions};
+
+    #[tokio::test]
+    async fn test_raw() {
+        let cluster = MockTikv::new();
+        let client = cluster.raw_client();
+        client.put(vec![1], vec![10]).await.unwrap();
+        client
+            .batch_put(vec![(vec![2], vec![20]), (vec![3], vec![30])])
+            .await
+            .unwrap();
+        assert_eq!(client.get(vec![1]).await.unwrap(), Some(vec![10]));
+        assert_eq!(
+            cluster.raw_client().get(vec![2]).await.unwrap(),
+            Some(vec![20])
+        );
+
+        let pairs = client.scan(vec![2].., 10).await.unwrap();
+        let keys: Vec<Key> = pairs.into_iter().map(|pair| pair.into_key()).collect();
+        assert_eq!(keys, vec![Key::from(vec![2]), Key::from(vec![3])]);
+
+        client.delete_range(vec![1]..vec![3]).await.unwrap();
+        assert_eq!(client.get(vec![1]).await.unwrap(), None);
+        assert_eq!(client.get(vec![3]).await.unwrap(), Some(vec![30]));
+        // raw data is separate from the data of transactions
+        let mut txn = cluster
+            .transaction_client()
+            .begin_optimistic()
+            .await
+            .unwrap();
+        assert_eq!(txn.get(vec![3]).await.unwrap(), None);
+        txn.rollback().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_optimistic_conflict() {
+        let client = MockTikv::new().transaction_client();
+        let mut first = client.begin_optimistic().await.unwrap();
+        let mut second = client.begin_optimistic().await.unwrap();
+        first.put(vec![1], vec![10]).await.unwrap();
+        second.put(vec![1], vec![20]).await.unwrap();
+        assert_eq!(second.get(vec![2]).await.unwrap(), None);
+        first.commit().await.unwrap();
+        assert!(second.commit().await.is_err());
+
+        // later transactions read the committed value, earlier snapshots don't
+        let mut txn = client.begin_optimistic().await.unwrap();
+        assert_eq!(txn.get(vec![1]).await.unwrap(), Some(vec![10]));
+        txn.delete(vec![1]).await.unwrap();
+        txn.commit().await.unwrap();
+        let mut txn = client.begin_optimistic().await.unwrap();
+        assert_eq!(txn.get(vec![1]).await.unwrap(), None);
+        txn.rollback().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_pessimistic_lock() {
+        let client = MockTikv::new().transaction_client();
+        let mut first = client.begin_pessimistic().await.unwrap();
+        let mut second = client.begin_pessimistic().await.unwrap();
+        assert_eq!(first.get_for_update(vec![1]).await.unwrap(), None);
+
+        // the key stays locked until the first transaction commits
+        let no_wait = PessimisticLockOptions::new().wait_timeout(Duration::ZERO);
+        assert!(second
+            .lock_keys_with_options(vec![vec![1]], no_wait.clone())
+            .await
+            .is_err());
+        first.put(vec![1], vec![10]).await.unwrap();
+        first.commit().await.unwrap();
+
+        // the second transaction locks the key at the latest version
+        second
+            .lock_keys_with_options(vec![vec![1]], no_wait)
+            .await
+            .unwrap();
+        assert_eq!(
+            second.get_for_update(vec![1]).await.unwrap(),
+            Some(vec![10])
+        );
+        second.put(vec![1], vec![20]).await.unwrap();
+        second.commit().await.unwrap();
+
+        let mut txn = client.begin_optimistic().await.unwrap();
+        assert_eq!(txn.get(vec![1]).await.unwrap(), Some(vec![20]));
+        txn.rollback().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_get_all_versions() {
+        let client = MockTikv::new().transaction_client();
+        let mut txn = client.begin_optimistic().await.unwrap();
+        txn.put(vec![1], vec![10]).await.unwrap();
+        txn.commit().await.unwrap();
+        let mut txn = client.begin_pessimistic().await.unwrap();
+        txn.delete(vec![1]).await.unwrap();
+        txn.commit().await.unwrap();
+
+        let mut locking = client.begin_pessimistic().await.unwrap();
+        locking.lock_keys(vec![vec![1]]).await.unwrap();
+        let mut txn = client.begin_optimistic().await.unwrap();
+        let history = txn.get_all_versions(vec![1]).await.unwrap();
+        txn.rollback().await.unwrap();
+        let kinds: Vec<MvccKind> = history.writes.iter().map(|write| write.kind).collect();
+        assert_eq!(kinds, vec![MvccKind::Delete, MvccKind::Put]);
+        assert_eq!(
+            history.value_at(history.writes[1].commit_ts),
+            Some(vec![10])
+        );
+        assert_eq!(history.value_at(u64::MAX), None);
+        let lock = history.lock.unwrap();
+        assert_eq!(lock.kind, MvccKind::PessimisticLock);
+        assert_eq!(lock.start_ts, locking.start_timestamp().version());
+        locking.rollback().await.unwrap();
+    }
+
+    #[test]
+    fn test_check_txn_status() {
+        let mut data = Data::default();
+        let now = Timestamp {
+            physical: 1000,
+            ..Default::default()
+        }
+        .version();
+        let prewrite = kvrpcpb::PrewriteRequest {
+            mutations: vec![kvrpcpb::Mutation {
+                key: vec![1],
+                value: vec![10],
+                ..Default::default()
+            }],
+            primary_lock: vec![1],
+            start_version: now,
+            lock_ttl: 100,
+            ..Default::default()
+        };
+        assert!(data.prewrite(&prewrite).errors.is_empty());
+
+        // a live lock is pushed past the reader
+        let mut check = kvrpcpb::CheckTxnStatusRequest {
+            primary_key: vec![1],
+            lock_ts: now,
+            caller_start_ts: now + 1,
+            current_ts: now + 1,
+            ..Default::default()
+        };
+        let resp = data.check_txn_status(&check);
+        assert_eq!(resp.lock_ttl, 100);
+        assert_eq!(resp.get_lock_info().min_commit_ts, now + 2);
+        let get = kvrpcpb::GetRequest {
+            key: vec![1],
+            version: now + 1,
+            ..Default::default()
+        };
+        assert!(data.get(&get).not_found);
+
+        // an expired lock is rolled back
+        check.current_ts = Timestamp {
+            physical: 1100,
+            ..Default::default()
+        }
+        .version();
+        let resp = data.check_txn_status(&check);
+        assert_eq!((resp.lock_ttl, resp.commit_version), (0, 0));
+        assert!(resp.lock_info.is_none());
+        let commit = kvrpcpb::CommitRequest {
+            keys: vec![vec![1]],
+            start_version: now,
+            commit_version: now + 3,
+            ..Default::default()
+        };
+        assert!(data
+            .commit(&commit)
+            .get_error()
+            .retryable
+            .starts_with("TxnLockNotFound"));
+        assert!(!data.prewrite(&prewrite).errors.is_empty());
+    }
+}
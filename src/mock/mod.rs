@@ -0,0 +1,23 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! An in-memory cluster for the unit tests of applications, behind the `mock` feature.
+//!
+//! [`MockTikv`] serves the requests of raw clients and transactions from memory, without PD, TiKV
+//! or any RPC, so that the transactional logic of an application can be tested without starting
+//! a cluster. Its raw client is a [`RawClient`](crate::RawClient) and its transactions are
+//! [`Transaction`](crate::Transaction)s, only connected to the in-memory cluster instead of PD.
+//!
+//! The data of transactions is versioned and locked like in TiKV: transactions read a snapshot,
+//! optimistic transactions fail to commit on write conflicts, pessimistic locks block other
+//! transactions and the locks of crashed transactions are resolved once they expire. Async commit
+//! and one-phase commit are not supported, transactions using them fall back to two-phase commit.
+
+#[cfg(test)]
+mod hooks;
+mod memory;
+
+#[cfg(test)]
+pub use self::hooks::*;
+pub use self::memory::{
+    MockRawClient, MockTikv, MockTikvPd, MockTikvStore, MockTransaction, MockTransactionClient,
+};
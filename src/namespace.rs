@@ -0,0 +1,128 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+use crate::{keyspace::Keyspace, BoundRange, Key, KvPair, Result};
+use tikv_client_common::internal_err;
+
+/// Ends the name of a namespace in the prefix of its keys.
+const SEPARATOR: u8 = b'/';
+
+/// A namespace of keys, see [`RawClient::namespace`](crate::RawClient::namespace) and
+/// [`TransactionClient::namespace`](crate::TransactionClient::namespace).
+///
+/// All keys of the namespace `name` start with the prefix `name/`, and the prefix of a nested
+/// namespace is appended to the prefix of its parent. Keys given by the user are prefixed before
+/// being sent to TiKV, and the prefix is removed from keys returned by TiKV.
+///
+/// The transactional keys of a [`Keyspace`] form the root namespace of a transactional client
+/// whose keys are in the keyspace, see [`Config::with_keyspace`](crate::Config::with_keyspace).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) struct Namespace {
+    prefix: Vec<u8>,
+}
+
+impl Namespace {
+    /// The namespace `name` nested in `parent`, if any.
+    pub fn new(parent: Option<&Namespace>, name: &str) -> Namespace {
+        let mut prefix = parent.map_or_else(Vec::new, |parent| parent.prefix.clone());
+        prefix.extend_from_slice(name.as_bytes());
+        prefix.push(SEPARATOR);
+        Namespace { prefix }
+    }
+
+    /// The namespace of the transactional keys of `keyspace`.
+    pub fn keyspace(keyspace: Keyspace) -> Namespace {
+        Namespace {
+            prefix: keyspace.txn().prefix(),
+        }
+    }
+
+    pub fn encode_key(&self, key: Key) -> Key {
+        let mut encoded = self.prefix.clone();
+        encoded.extend_from_slice(&Vec::from(key));
+        encoded.into()
+    }
+
+    /// Encodes the keys of `range`. An unbounded range is bounded by the keys of the namespace,
+    /// so that scans and deletions never reach the keys of other namespaces.
+    pub fn encode_range(&self, range: BoundRange) -> BoundRange {
+        let (start, end) = range.into_keys();
+        let end = match end.filter(|end| !end.is_empty()) {
+            Some(end) => Some(self.encode_key(end)),
+            // the end of the keys starting with the prefix, unbounded if the prefix is only 0xFF
+            // bytes which can't be incremented
+            None => BoundRange::prefix(self.prefix.clone()).into_keys().1,
+        };
+        (self.encode_key(start), end).into()
+    }
+
+    pub fn decode_key(&self, key: Key) -> Result<Key> {
+        let mut key = Vec::from(key);
+        if !key.starts_with(&self.prefix) {
+            return Err(internal_err!(
+                "key {:?} is not in namespace {:?}",
+                Key::from(key),
+                String::from_utf8_lossy(&self.prefix)
+            ));
+        }
+        key.drain(..self.prefix.len());
+        Ok(key.into())
+    }
+
+    pub fn decode_pairs(&self, pairs: Vec<KvPair>) -> Result<Vec<KvPair>> {
+        pairs
+            .into_iter()
+            .map(|KvPair(key, value)| Ok(KvPair(self.decode_key(key)?, value)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_namespace_encoding() {
+        let orders = Namespace::new(None, "orders");
+        let key = orders.encode_key(b"1".to_vec().into());
+        assert_eq!(key, Key::from(b"orders/1".to_vec()));
+        assert_eq!(orders.decode_key(key).unwrap(), Key::from(b"1".to_vec()));
+        // a namespace whose name starts with the name of another is not in it
+        assert!(orders
+            .decode_key(b"orders_archive/1".to_vec().into())
+            .is_err());
+
+        let range = orders.encode_range((b"a".to_vec()..=b"b".to_vec()).into());
+        assert_eq!(
+            range.into_keys(),
+            (
+                Key::from(b"orders/a".to_vec()),
+                Some(Key::from(b"orders/b\x00".to_vec()))
+            )
+        );
+        let range = orders.encode_range((..).into());
+        assert_eq!(
+            range.into_keys(),
+            (
+                Key::from(b"orders/".to_vec()),
+                Some(Key::from(b"orders0".to_vec()))
+            )
+        );
+
+        let lines = Namespace::new(Some(&orders), "lines");
+        assert_eq!(
+            lines.encode_key(b"1".to_vec().into()),
+            Key::from(b"orders/lines/1".to_vec())
+        );
+
+        let keyspace = Namespace::keyspace(Keyspace::new(0x0102FF).unwrap());
+        let orders = Namespace::new(Some(&keyspace), "orders");
+        assert_eq!(
+            orders.encode_key(b"1".to_vec().into()),
+            Key::from(b"x\x01\x02\xFForders/1".to_vec())
+        );
+        assert_eq!(
+            keyspace.encode_range((..).into()).into_keys().1,
+            Some(Key::from(b"x\x01\x03".to_vec()))
+        );
+    }
+}
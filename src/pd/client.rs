@@ -1,26 +1,45 @@
 // Copyright 2018 TiKV Project Authors. Licensed under Apache-2.0.
 
 use crate::{
+    backoff::RegionBackoffs,
+    clock::ClockHandle,
     compat::stream_fn,
+    config::{ReplicaSelection, SizeLimits},
+    interceptor::{InterceptedKvClient, Interceptor},
+    key_validator::KeyValidator,
+    keyspace::Keyspace,
     kv::codec,
-    pd::{retry::RetryClientTrait, RetryClient},
-    region::{RegionId, RegionVerId, RegionWithLeader},
-    region_cache::RegionCache,
-    store::RegionStore,
-    BoundRange, Config, Key, Result, SecurityManager, Timestamp,
+    pd::{retry::RetryClientTrait, timestamp::TimestampPrefetcher, PdMember, RetryClient},
+    pressure::PressureTracker,
+    region::{RegionId, RegionVerId, RegionWithLeader, StoreId},
+    region_cache::{spawn_region_refresh, RegionCache},
+    stats::enter_tso_queue,
+    store::{ConnectionStats, QueuedKvClient, RegionStore, StoreQueue},
+    transaction::ReplicaRead,
+    BoundRange, Config, Error, Key, Result, SecurityManager, Timestamp,
 };
 use async_trait::async_trait;
 use futures::{prelude::*, stream::BoxStream};
 use grpcio::{EnvBuilder, Environment};
 use slog::Logger;
-use std::{collections::HashMap, sync::Arc, thread};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
 use tikv_client_pd::Cluster;
-use tikv_client_proto::{kvrpcpb, metapb};
+use tikv_client_proto::{kvrpcpb, metapb, pdpb};
 use tikv_client_store::{KvClient, KvConnect, TikvConnect};
 use tokio::sync::RwLock;
 
 const CQ_COUNT: usize = 1;
 const CLIENT_PREFIX: &str = "tikv-client";
+/// How often the latency of a store which is not the fastest is sampled again by stale reads.
+const REPLICA_SAMPLING_INTERVAL: Duration = Duration::from_secs(10);
 
 /// The PdClient handles all the encoding stuff.
 ///
@@ -52,10 +71,100 @@ pub trait PdClient: Send + Sync + 'static {
     /// In transactional API, the returned region is decoded (keys in raw format)
     async fn region_for_id(&self, id: RegionId) -> Result<RegionWithLeader>;
 
+    /// Like [`region_for_id`](PdClient::region_for_id), but the region is read from PD, and the
+    /// region cache (if any) is updated with it.
+    async fn refresh_region_for_id(&self, id: RegionId) -> Result<RegionWithLeader> {
+        self.region_for_id(id).await
+    }
+
     async fn get_timestamp(self: Arc<Self>) -> Result<Timestamp>;
 
+    /// The clock to read the time from and sleep with, see [`Config::with_clock`].
+    fn clock(&self) -> ClockHandle {
+        ClockHandle::default()
+    }
+
+    /// Tracks how often TiKV replies `ServerIsBusy`, see
+    /// [`ClusterPressure`](crate::ClusterPressure).
+    fn pressure(&self) -> Option<&PressureTracker> {
+        None
+    }
+
+    /// The backoff levels of the regions, raised by the retries of requests and lowered by their
+    /// successes, see [`RegionBackoffs`].
+    fn region_backoffs(&self) -> Option<&RegionBackoffs> {
+        None
+    }
+
+    /// The maximum sizes of the keys and values written, see [`Config::with_size_limits`].
+    fn size_limits(&self) -> SizeLimits {
+        SizeLimits::default()
+    }
+
+    /// The checks of the keys written, see [`Config::with_key_validator`].
+    fn key_validators(&self) -> &[Arc<dyn KeyValidator>] {
+        &[]
+    }
+
+    /// The statistics of the connections to the stores, see [`ConnectionStats`].
+    async fn connection_stats(&self) -> Vec<ConnectionStats> {
+        Vec::new()
+    }
+
+    /// A client of the store at `address`, for store commands which don't target a region, e.g.
+    /// `UnsafeDestroyRange`.
+    async fn store_client(&self, address: &str) -> Result<Arc<dyn KvClient + Send + Sync>>;
+
     async fn update_safepoint(self: Arc<Self>, safepoint: u64) -> Result<bool>;
 
+    /// The current GC safepoint of the cluster.
+    async fn get_gc_safepoint(self: Arc<Self>) -> Result<u64>;
+
+    /// The members of the PD cluster, including which one is the leader and whether each is healthy.
+    async fn members(self: Arc<Self>) -> Result<Vec<PdMember>>;
+
+    /// The version of the leader of the PD cluster.
+    async fn pd_version(self: Arc<Self>) -> Result<String>;
+
+    /// All stores of the cluster, including TiFlash stores and stores which have been removed.
+    async fn stores(self: Arc<Self>) -> Result<Vec<metapb::Store>>;
+
+    /// The statistics of the store `id`, e.g. its disk usage, as last reported to PD.
+    async fn store_stats(self: Arc<Self>, id: StoreId) -> Result<pdpb::StoreStats>;
+
+    /// Split the regions at `split_keys` and scatter the new regions across the stores, returning
+    /// the ids of the new regions. The keys are in raw format, like the other keys of this trait.
+    async fn split_and_scatter_regions(
+        self: Arc<Self>,
+        split_keys: Vec<Key>,
+    ) -> Result<Vec<RegionId>>;
+
+    /// Scatter the regions `region_ids` across the stores, e.g. after splitting them. The peers
+    /// are moved in the background.
+    async fn scatter_regions(self: Arc<Self>, region_ids: Vec<RegionId>) -> Result<()>;
+
+    /// Returns a Stream of the GC safepoint of the cluster.
+    ///
+    /// The current safepoint is yielded first, then PD is polled every `interval` and each change
+    /// of the safepoint is yielded. The stream ends after the first error.
+    fn watch_gc_safepoint(self: Arc<Self>, interval: Duration) -> BoxStream<'static, Result<u64>> {
+        stream_fn(None, move |last: Option<u64>| {
+            let this = self.clone();
+            async move {
+                loop {
+                    if last.is_some() {
+                        this.clock().sleep(interval).await;
+                    }
+                    let safepoint = this.clone().get_gc_safepoint().await?;
+                    if last != Some(safepoint) {
+                        return Ok(Some((Some(safepoint), safepoint)));
+                    }
+                }
+            }
+        })
+        .boxed()
+    }
+
     /// In transactional API, `key` is in raw format
     async fn store_for_key(self: Arc<Self>, key: &Key) -> Result<RegionStore> {
         let region = self.region_for_key(key).await?;
@@ -83,11 +192,8 @@ pub trait PdClient: Send + Sync + 'static {
                     let region = this.region_for_key(key.as_ref()).await?;
                     let id = region.id();
                     let mut grouped = vec![key.into()];
-                    while let Some(key) = keys.peek() {
-                        if !region.contains(key.as_ref()) {
-                            break;
-                        }
-                        grouped.push(keys.next().unwrap().into());
+                    while let Some(key) = keys.next_if(|key| region.contains(key.as_ref())) {
+                        grouped.push(key.into());
                     }
                     Ok(Some((keys, (id, grouped))))
                 } else {
@@ -103,7 +209,10 @@ pub trait PdClient: Send + Sync + 'static {
         self: Arc<Self>,
         range: BoundRange,
     ) -> BoxStream<'static, Result<RegionStore>> {
-        let (start_key, end_key) = range.into_keys();
+        let (start_key, end_key) = match range.try_into_keys() {
+            Ok(keys) => keys,
+            Err(e) => return stream::once(future::err(e)).boxed(),
+        };
         stream_fn(Some(start_key), move |start_key| {
             let end_key = end_key.clone();
             let this = self.clone();
@@ -134,6 +243,16 @@ pub trait PdClient: Send + Sync + 'static {
         self: Arc<Self>,
         mut ranges: Vec<kvrpcpb::KeyRange>,
     ) -> BoxStream<'static, Result<(RegionId, Vec<kvrpcpb::KeyRange>)>> {
+        if let Some(range) = ranges
+            .iter()
+            .find(|range| !range.end_key.is_empty() && range.start_key > range.end_key)
+        {
+            let err = Error::InvalidRange {
+                start: range.start_key.clone(),
+                end: range.end_key.clone(),
+            };
+            return stream::once(future::err(err)).boxed();
+        }
         ranges.reverse();
         stream_fn(Some(ranges), move |ranges| {
             let this = self.clone();
@@ -167,7 +286,10 @@ pub trait PdClient: Send + Sync + 'static {
                     while let Some(range) = ranges.pop() {
                         let start_key: Key = range.start_key.clone().into();
                         let end_key: Key = range.end_key.clone().into();
-                        if start_key < region_start {
+                        // the range is grouped with the next ranges in the region of its start
+                        if start_key < region_start
+                            || (!region_end.is_empty() && start_key >= region_end)
+                        {
                             ranges.push(range);
                             break;
                         }
@@ -204,16 +326,61 @@ pub trait PdClient: Send + Sync + 'static {
     async fn update_leader(&self, ver_id: RegionVerId, leader: metapb::Peer) -> Result<()>;
 
     async fn invalidate_region_cache(&self, ver_id: RegionVerId);
+
+    /// Replace the cached region with the given version by `regions`, which TiKV reported to
+    /// have replaced it, instead of loading them from PD.
+    async fn replace_region_cache(&self, ver_id: RegionVerId, _regions: Vec<RegionWithLeader>) {
+        self.invalidate_region_cache(ver_id).await
+    }
+
+    /// Forget the cached address of the store with the given id.
+    async fn invalidate_store_cache(&self, _store_id: StoreId) {}
+
+    /// Set whether the cached region with the given version is being merged, see
+    /// [`RegionWithLeader::merging`].
+    async fn set_region_merging(&self, ver_id: RegionVerId, merging: bool) {
+        if merging {
+            self.invalidate_region_cache(ver_id).await
+        }
+    }
 }
 
 /// This client converts requests for the logical TiKV cluster into requests
 /// for a single TiKV store using PD and internal logic.
 pub struct PdRpcClient<KvC: KvConnect + Send + Sync + 'static = TikvConnect, Cl = Cluster> {
     pd: Arc<RetryClient<Cl>>,
-    kv_connect: KvC,
-    kv_client_cache: Arc<RwLock<HashMap<String, KvC::KvClient>>>,
+    kv_connect: Arc<KvC>,
+    // The connections to each store, see `Config::with_connections_per_store`.
+    kv_client_cache: Arc<RwLock<HashMap<String, Vec<KvC::KvClient>>>>,
+    connections_per_store: usize,
+    // Picks the connection of the next request to a store.
+    kv_client_turn: Arc<AtomicUsize>,
+    store_queues: Arc<RwLock<HashMap<String, StoreQueue>>>,
+    store_concurrency: usize,
+    // The interceptors of the requests sent to TiKV.
+    interceptors: Arc<[Arc<dyn Interceptor>]>,
+    // The checks of the keys written by the clients.
+    key_validators: Arc<[Arc<dyn KeyValidator>]>,
     enable_codec: bool,
-    region_cache: RegionCache<RetryClient<Cl>>,
+    // If true, requests are stale reads served by replicas in `zone` if possible.
+    stale_read: bool,
+    // If true, requests are replica reads served by replicas in `zone` if possible.
+    replica_read: bool,
+    // The replicas which serve replica reads, any replica if `None`.
+    replica_read_kind: Option<ReplicaRead>,
+    zone: Option<String>,
+    replica_selection: ReplicaSelection,
+    clock: ClockHandle,
+    pressure: Arc<PressureTracker>,
+    region_backoffs: Arc<RegionBackoffs>,
+    size_limits: SizeLimits,
+    // The API version of requests sent to TiKV.
+    api_version: kvrpcpb::ApiVersion,
+    // The keyspace of the clients, loaded from PD when connecting, see `Config::with_keyspace`.
+    keyspace: Option<Keyspace>,
+    region_cache: Arc<RegionCache<RetryClient<Cl>>>,
+    // Hands out prefetched timestamps, see `Config::with_timestamp_prefetch`.
+    timestamp_prefetcher: Option<Arc<TimestampPrefetcher>>,
     logger: Logger,
 }
 
@@ -222,10 +389,20 @@ impl<KvC: KvConnect + Send + Sync + 'static> PdClient for PdRpcClient<KvC> {
     type KvClient = KvC::KvClient;
 
     async fn map_region_to_store(self: Arc<Self>, region: RegionWithLeader) -> Result<RegionStore> {
-        let store_id = region.get_store_id()?;
-        let store = self.region_cache.get_store_by_id(store_id).await?;
-        let kv_client = self.kv_client(store.get_address()).await?;
-        Ok(RegionStore::new(region, Arc::new(kv_client)))
+        let mut region_store = if self.stale_read || self.replica_read {
+            let mut region_store = self.map_region_to_replica(region).await?;
+            region_store.replica_read = self.replica_read;
+            region_store
+        } else {
+            let store_id = region.get_store_id()?;
+            let store = self.region_cache.get_store_by_id(store_id).await?;
+            let kv_client = self.queued_kv_client(store.get_address()).await?;
+            let mut region_store = RegionStore::new(region, Arc::new(kv_client));
+            region_store.address = Some(store.get_address().to_owned());
+            region_store
+        };
+        region_store.api_version = self.api_version;
+        Ok(region_store)
     }
 
     async fn region_for_key(&self, key: &Key) -> Result<RegionWithLeader> {
@@ -245,14 +422,99 @@ impl<KvC: KvConnect + Send + Sync + 'static> PdClient for PdRpcClient<KvC> {
         Self::decode_region(region, self.enable_codec)
     }
 
+    async fn refresh_region_for_id(&self, id: RegionId) -> Result<RegionWithLeader> {
+        let region = self.region_cache.read_through_region_by_id(id).await?;
+        Self::decode_region(region, self.enable_codec)
+    }
+
     async fn get_timestamp(self: Arc<Self>) -> Result<Timestamp> {
-        self.pd.clone().get_timestamp().await
+        let _queued = enter_tso_queue();
+        match &self.timestamp_prefetcher {
+            Some(prefetcher) => {
+                let fetch = |count| self.pd.clone().get_timestamps(count);
+                prefetcher.get_timestamp(&self.clock, fetch).await
+            }
+            None => self.pd.clone().get_timestamp().await,
+        }
+    }
+
+    fn clock(&self) -> ClockHandle {
+        self.clock.clone()
+    }
+
+    fn pressure(&self) -> Option<&PressureTracker> {
+        Some(&self.pressure)
+    }
+
+    fn region_backoffs(&self) -> Option<&RegionBackoffs> {
+        Some(&self.region_backoffs)
+    }
+
+    fn size_limits(&self) -> SizeLimits {
+        self.size_limits
+    }
+
+    fn key_validators(&self) -> &[Arc<dyn KeyValidator>] {
+        &self.key_validators
+    }
+
+    async fn connection_stats(&self) -> Vec<ConnectionStats> {
+        let mut stats: Vec<ConnectionStats> = self
+            .store_queues
+            .read()
+            .await
+            .iter()
+            .map(|(address, queue)| queue.stats(address.clone()))
+            .collect();
+        stats.sort_by(|a, b| a.address.cmp(&b.address));
+        stats
+    }
+
+    async fn store_client(&self, address: &str) -> Result<Arc<dyn KvClient + Send + Sync>> {
+        Ok(Arc::new(self.queued_kv_client(address).await?))
     }
 
     async fn update_safepoint(self: Arc<Self>, safepoint: u64) -> Result<bool> {
         self.pd.clone().update_safepoint(safepoint).await
     }
 
+    async fn get_gc_safepoint(self: Arc<Self>) -> Result<u64> {
+        self.pd.clone().get_safepoint().await
+    }
+
+    async fn members(self: Arc<Self>) -> Result<Vec<PdMember>> {
+        self.pd.clone().get_members().await
+    }
+
+    async fn pd_version(self: Arc<Self>) -> Result<String> {
+        self.pd.clone().get_leader_version().await
+    }
+
+    async fn stores(self: Arc<Self>) -> Result<Vec<metapb::Store>> {
+        self.pd.clone().get_all_stores().await
+    }
+
+    async fn store_stats(self: Arc<Self>, id: StoreId) -> Result<pdpb::StoreStats> {
+        self.pd.clone().get_store_stats(id).await
+    }
+
+    async fn split_and_scatter_regions(
+        self: Arc<Self>,
+        split_keys: Vec<Key>,
+    ) -> Result<Vec<RegionId>> {
+        let enable_codec = self.enable_codec;
+        let split_keys = split_keys
+            .into_iter()
+            .map(|key| if enable_codec { key.to_encoded() } else { key })
+            .map(Into::into)
+            .collect();
+        self.pd.clone().split_and_scatter_regions(split_keys).await
+    }
+
+    async fn scatter_regions(self: Arc<Self>, region_ids: Vec<RegionId>) -> Result<()> {
+        self.pd.clone().scatter_regions(region_ids).await
+    }
+
     async fn update_leader(&self, ver_id: RegionVerId, leader: metapb::Peer) -> Result<()> {
         self.region_cache.update_leader(ver_id, leader).await
     }
@@ -260,6 +522,18 @@ impl<KvC: KvConnect + Send + Sync + 'static> PdClient for PdRpcClient<KvC> {
     async fn invalidate_region_cache(&self, ver_id: RegionVerId) {
         self.region_cache.invalidate_region_cache(ver_id).await
     }
+
+    async fn replace_region_cache(&self, ver_id: RegionVerId, regions: Vec<RegionWithLeader>) {
+        self.region_cache.replace_region(ver_id, regions).await
+    }
+
+    async fn invalidate_store_cache(&self, store_id: StoreId) {
+        self.region_cache.invalidate_store_cache(store_id).await
+    }
+
+    async fn set_region_merging(&self, ver_id: RegionVerId, merging: bool) {
+        self.region_cache.set_merging(ver_id, merging).await
+    }
 }
 
 impl PdRpcClient<TikvConnect, Cluster> {
@@ -269,16 +543,43 @@ impl PdRpcClient<TikvConnect, Cluster> {
         enable_codec: bool,
         logger: Logger,
     ) -> Result<PdRpcClient> {
-        PdRpcClient::new(
+        let mut client = PdRpcClient::new(
             config.clone(),
-            |env, security_mgr| TikvConnect::new(env, security_mgr, config.timeout),
             |env, security_mgr| {
-                RetryClient::connect(env, pd_endpoints, security_mgr, config.timeout)
+                let connect = TikvConnect::new(env, security_mgr, config.timeout);
+                match config.batch_commands {
+                    Some(max_batch_size) => connect.with_batch_commands(max_batch_size),
+                    None => connect,
+                }
+            },
+            |env, security_mgr| {
+                RetryClient::connect(
+                    env,
+                    pd_endpoints,
+                    config.pd_endpoint_priorities.clone(),
+                    security_mgr,
+                    config.timeout,
+                    config.pd_retry_backoff.clone(),
+                    config.clock.clone(),
+                )
             },
             enable_codec,
             logger,
         )
-        .await
+        .await?;
+        if let Some(name) = &config.keyspace {
+            let meta = client.pd.clone().load_keyspace(name).await?;
+            client.keyspace = Some(Keyspace::from_meta(&meta)?);
+        }
+        if let Some(refresh) = config.region_refresh {
+            spawn_region_refresh(Arc::downgrade(&client.region_cache), refresh, config.clock);
+        }
+        Ok(client)
+    }
+
+    /// The id of the cluster.
+    pub async fn cluster_id(&self) -> u64 {
+        self.pd.cluster_id().await
     }
 }
 
@@ -291,6 +592,21 @@ fn thread_name(prefix: &str) -> String {
         .unwrap_or_else(|| prefix.to_owned())
 }
 
+/// The TLS configuration of `config`, without TLS if any of its files isn't set.
+fn security_manager(config: &Config) -> Result<SecurityManager> {
+    let mut security_mgr = if let (Some(ca_path), Some(cert_path), Some(key_path)) =
+        (&config.ca_path, &config.cert_path, &config.key_path)
+    {
+        SecurityManager::load(ca_path, cert_path, key_path)?
+    } else {
+        SecurityManager::default()
+    };
+    if let Some(name) = &config.tls_server_name {
+        security_mgr = security_mgr.with_server_name(name.clone());
+    }
+    Ok(security_mgr.with_channel_options(config.channel_options()))
+}
+
 impl<KvC: KvConnect + Send + Sync + 'static, Cl> PdRpcClient<KvC, Cl> {
     pub async fn new<PdFut, MakeKvC, MakePd>(
         config: Config,
@@ -310,43 +626,311 @@ impl<KvC: KvConnect + Send + Sync + 'static, Cl> PdRpcClient<KvC, Cl> {
                 .name_prefix(thread_name(CLIENT_PREFIX))
                 .build(),
         );
-        let security_mgr = Arc::new(
-            if let (Some(ca_path), Some(cert_path), Some(key_path)) =
-                (&config.ca_path, &config.cert_path, &config.key_path)
-            {
-                SecurityManager::load(ca_path, cert_path, key_path)?
-            } else {
-                SecurityManager::default()
-            },
-        );
+        let security_mgr = Arc::new(security_manager(&config)?);
 
         let pd = Arc::new(pd(env.clone(), security_mgr.clone()).await?);
+        let region_cache = match config.region_cache_backend {
+            Some(backend) => RegionCache::with_backend(pd.clone(), backend.into_inner()),
+            None => RegionCache::new(pd.clone()),
+        };
+        let region_cache = match config.region_refresh {
+            Some(_) => region_cache.with_hit_tracking(),
+            None => region_cache,
+        };
         let kv_client_cache = Default::default();
         Ok(PdRpcClient {
             pd: pd.clone(),
             kv_client_cache,
-            kv_connect: kv_connect(env, security_mgr),
+            connections_per_store: config.connections_per_store.max(1),
+            kv_client_turn: Default::default(),
+            store_queues: Default::default(),
+            store_concurrency: config.store_concurrency,
+            interceptors: config
+                .interceptors
+                .into_iter()
+                .map(|interceptor| interceptor.into_inner())
+                .collect(),
+            key_validators: config
+                .key_validators
+                .into_iter()
+                .map(|validator| validator.into_inner())
+                .collect(),
+            kv_connect: Arc::new(kv_connect(env, security_mgr)),
             enable_codec,
-            region_cache: RegionCache::new(pd),
+            stale_read: false,
+            replica_read: false,
+            replica_read_kind: None,
+            zone: config.zone,
+            replica_selection: config.replica_selection,
+            pressure: Arc::new(PressureTracker::new(config.clock.clone())),
+            region_backoffs: Arc::new(RegionBackoffs::new(config.clock.clone())),
+            clock: config.clock,
+            size_limits: config.size_limits,
+            api_version: kvrpcpb::ApiVersion::V1,
+            keyspace: None,
+            region_cache: Arc::new(region_cache),
+            timestamp_prefetcher: config
+                .timestamp_prefetch
+                .map(|prefetch| Arc::new(TimestampPrefetcher::new(prefetch))),
             logger,
         })
     }
 
+    /// A client which shares the PD connection, the region cache and the connections to TiKV
+    /// stores with `self`, and encodes keys if `enable_codec` is true.
+    pub(crate) fn with_codec(&self, enable_codec: bool) -> PdRpcClient<KvC, Cl> {
+        PdRpcClient {
+            pd: self.pd.clone(),
+            kv_connect: self.kv_connect.clone(),
+            kv_client_cache: self.kv_client_cache.clone(),
+            connections_per_store: self.connections_per_store,
+            kv_client_turn: self.kv_client_turn.clone(),
+            store_queues: self.store_queues.clone(),
+            store_concurrency: self.store_concurrency,
+            interceptors: self.interceptors.clone(),
+            key_validators: self.key_validators.clone(),
+            enable_codec,
+            stale_read: self.stale_read,
+            replica_read: self.replica_read,
+            replica_read_kind: self.replica_read_kind,
+            zone: self.zone.clone(),
+            replica_selection: self.replica_selection,
+            clock: self.clock.clone(),
+            pressure: self.pressure.clone(),
+            region_backoffs: self.region_backoffs.clone(),
+            size_limits: self.size_limits,
+            api_version: self.api_version,
+            keyspace: self.keyspace,
+            region_cache: self.region_cache.clone(),
+            timestamp_prefetcher: self.timestamp_prefetcher.clone(),
+            logger: self.logger.clone(),
+        }
+    }
+
+    /// A client like [`with_codec`](PdRpcClient::with_codec), whose requests are stale reads.
+    pub(crate) fn with_stale_read(&self) -> PdRpcClient<KvC, Cl> {
+        PdRpcClient {
+            stale_read: true,
+            ..self.with_codec(self.enable_codec)
+        }
+    }
+
+    /// A client like [`with_codec`](PdRpcClient::with_codec), whose requests are replica reads.
+    pub(crate) fn with_replica_read(&self) -> PdRpcClient<KvC, Cl> {
+        PdRpcClient {
+            replica_read: true,
+            ..self.with_codec(self.enable_codec)
+        }
+    }
+
+    /// A client like [`with_replica_read`](PdRpcClient::with_replica_read), whose requests are
+    /// served by the replicas of `kind`.
+    pub(crate) fn with_replica_read_kind(&self, kind: ReplicaRead) -> PdRpcClient<KvC, Cl> {
+        PdRpcClient {
+            replica_read_kind: Some(kind),
+            ..self.with_replica_read()
+        }
+    }
+
+    /// A client like [`with_codec`](PdRpcClient::with_codec) using `api_version`.
+    ///
+    /// Under API V2, keys in PD are encoded for raw requests as well, so the client encodes keys
+    /// only for API V2, which transactional clients always do.
+    pub(crate) fn with_api_version(
+        &self,
+        api_version: kvrpcpb::ApiVersion,
+    ) -> PdRpcClient<KvC, Cl> {
+        PdRpcClient {
+            api_version,
+            ..self.with_codec(api_version == kvrpcpb::ApiVersion::V2)
+        }
+    }
+
+    /// The keyspace of the clients, see [`Config::with_keyspace`].
+    pub(crate) fn keyspace(&self) -> Option<Keyspace> {
+        self.keyspace
+    }
+
+    /// Maps the region to a replica as chosen by the replica selection, see [`ReplicaSelection`].
+    async fn map_region_to_replica(&self, region: RegionWithLeader) -> Result<RegionStore>
+    where
+        RetryClient<Cl>: RetryClientTrait,
+    {
+        let leader_healthy = match (&region.leader, self.replica_read_kind) {
+            (Some(leader), Some(ReplicaRead::PreferLeader)) => {
+                let store = self.region_cache.get_store_by_id(leader.store_id).await?;
+                self.store_queue(store.get_address())
+                    .await
+                    .latency()
+                    .healthy
+            }
+            _ => true,
+        };
+        let candidates = replica_candidates(&region, self.replica_read_kind, leader_healthy);
+        let mut replica = match self.replica_selection {
+            ReplicaSelection::Fastest => self.fastest_replica(&candidates).await?,
+            ReplicaSelection::Zone => None,
+        };
+        // without a replica of known latency, prefer the zone of the client
+        if let Some(zone) = self.zone.as_ref().filter(|_| replica.is_none()) {
+            for peer in &candidates {
+                let store = self
+                    .region_cache
+                    .get_store_by_id(peer.get_store_id())
+                    .await?;
+                let queue = self.store_queue(store.get_address()).await;
+                if !queue.latency().healthy {
+                    continue;
+                }
+                let is_local = store
+                    .get_labels()
+                    .iter()
+                    .any(|label| label.get_key() == "zone" && label.get_value() == zone);
+                if is_local {
+                    replica = Some((peer.clone(), store));
+                    break;
+                }
+            }
+        }
+        let (peer, store) = match replica {
+            Some(replica) => replica,
+            None => {
+                let leader = region.leader.clone().ok_or(Error::LeaderNotFound {
+                    region_id: region.id(),
+                })?;
+                // the leader, unless the reads are restricted to other replicas
+                let peer = match candidates.first() {
+                    Some(first) if candidates.iter().all(|peer| peer.id != leader.id) => {
+                        first.clone()
+                    }
+                    _ => leader,
+                };
+                let store = self.region_cache.get_store_by_id(peer.store_id).await?;
+                (peer, store)
+            }
+        };
+        let kv_client = self.queued_kv_client(store.get_address()).await?;
+        let mut region_store = RegionStore::new(region, Arc::new(kv_client));
+        region_store.replica = Some(peer);
+        region_store.address = Some(store.get_address().to_owned());
+        Ok(region_store)
+    }
+
+    /// The replica of `peers` on the healthy store with the lowest average latency, or on a healthy
+    /// store whose latency is to be sampled again. `None` if the latency of no store is known.
+    async fn fastest_replica(
+        &self,
+        peers: &[metapb::Peer],
+    ) -> Result<Option<(metapb::Peer, metapb::Store)>>
+    where
+        RetryClient<Cl>: RetryClientTrait,
+    {
+        let mut fastest: Option<(Duration, metapb::Peer, metapb::Store)> = None;
+        for peer in peers {
+            let store = self
+                .region_cache
+                .get_store_by_id(peer.get_store_id())
+                .await?;
+            let queue = self.store_queue(store.get_address()).await;
+            // a store on probation is neither sampled nor chosen
+            let latency = queue.latency();
+            if !latency.healthy {
+                continue;
+            }
+            if queue.should_sample(REPLICA_SAMPLING_INTERVAL) {
+                return Ok(Some((peer.clone(), store)));
+            }
+            if let Some(average) = latency.average {
+                if fastest
+                    .as_ref()
+                    .is_none_or(|(fastest, ..)| average < *fastest)
+                {
+                    fastest = Some((average, peer.clone(), store));
+                }
+            }
+        }
+        Ok(fastest.map(|(_, peer, store)| (peer, store)))
+    }
+
     async fn kv_client(&self, address: &str) -> Result<KvC::KvClient> {
-        if let Some(client) = self.kv_client_cache.read().await.get(address) {
-            return Ok(client.clone());
+        let turn = self.kv_client_turn.fetch_add(1, Ordering::Relaxed);
+        if let Some(clients) = self.kv_client_cache.read().await.get(address) {
+            return Ok(clients[turn % clients.len()].clone());
         };
         info!(self.logger, "connect to tikv endpoint: {:?}", address);
-        match self.kv_connect.connect(address) {
-            Ok(client) => {
-                self.kv_client_cache
-                    .write()
-                    .await
-                    .insert(address.to_owned(), client.clone());
-                Ok(client)
-            }
-            Err(e) => Err(e),
+        let clients = (0..self.connections_per_store)
+            .map(|_| self.kv_connect.connect(address))
+            .collect::<Result<Vec<_>>>()?;
+        let client = clients[turn % clients.len()].clone();
+        self.kv_client_cache
+            .write()
+            .await
+            .insert(address.to_owned(), clients);
+        Ok(client)
+    }
+
+    /// The client of the store at `address`, which dispatches requests through the queue of the
+    /// store and then the interceptors.
+    async fn queued_kv_client(
+        &self,
+        address: &str,
+    ) -> Result<QueuedKvClient<InterceptedKvClient<KvC::KvClient>>> {
+        let kv_client = self.kv_client(address).await?;
+        let kv_client =
+            InterceptedKvClient::new(kv_client, address.to_owned(), self.interceptors.clone());
+        let queue = self.store_queue(address).await;
+        Ok(QueuedKvClient::new(kv_client, queue))
+    }
+
+    async fn store_queue(&self, address: &str) -> StoreQueue {
+        if let Some(queue) = self.store_queues.read().await.get(address) {
+            return queue.clone();
         }
+        self.store_queues
+            .write()
+            .await
+            .entry(address.to_owned())
+            .or_insert_with(|| StoreQueue::new(self.store_concurrency))
+            .clone()
+    }
+}
+
+/// The peers of `region` which may serve its replica reads of `kind`, all peers if `None`. The
+/// leader serves the reads if none of the peers qualifies. Witnesses don't have the data of the
+/// region, so they never serve reads.
+fn replica_candidates(
+    region: &RegionWithLeader,
+    kind: Option<ReplicaRead>,
+    leader_healthy: bool,
+) -> Vec<metapb::Peer> {
+    let peers = || {
+        region
+            .region
+            .get_peers()
+            .iter()
+            .filter(|peer| !peer.get_is_witness())
+    };
+    let leader_id = region.leader.as_ref().map(|leader| leader.id);
+    let followers = || {
+        peers()
+            .filter(|peer| {
+                Some(peer.id) != leader_id && peer.get_role() != metapb::PeerRole::Learner
+            })
+            .cloned()
+            .collect::<Vec<_>>()
+    };
+    match kind {
+        None => peers().cloned().collect(),
+        Some(ReplicaRead::Leader) => region.leader.iter().cloned().collect(),
+        Some(ReplicaRead::Follower) => followers(),
+        Some(ReplicaRead::Learner) => peers()
+            .filter(|peer| peer.get_role() == metapb::PeerRole::Learner)
+            .cloned()
+            .collect(),
+        Some(ReplicaRead::PreferLeader) if leader_healthy => {
+            region.leader.iter().cloned().collect()
+        }
+        Some(ReplicaRead::PreferLeader) => followers(),
     }
 }
 
@@ -356,6 +940,7 @@ pub mod test {
     use crate::mock::*;
 
     use futures::{executor, executor::block_on};
+    use std::sync::atomic::Ordering;
 
     #[tokio::test]
     async fn test_kv_client_caching() {
@@ -371,6 +956,69 @@ pub mod test {
         assert_eq!(kv2.addr, kv3.addr);
     }
 
+    #[test]
+    fn test_security_manager() {
+        let security_mgr = security_manager(&Config::default()).unwrap();
+        assert_eq!(security_mgr.server_name(), None);
+
+        let config = Config::default().with_tls_server_name("tikv.internal");
+        let security_mgr = security_manager(&config).unwrap();
+        assert_eq!(security_mgr.server_name(), Some("tikv.internal"));
+
+        let config = Config::default()
+            .with_security("/nonexistent/ca", "/nonexistent/cert", "/nonexistent/key")
+            .with_tls_server_name("tikv.internal");
+        assert!(security_manager(&config).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_connections_per_store() {
+        let config = Config::default().with_connections_per_store(3);
+        let client = pd_rpc_client_with_config(config).await;
+        for _ in 0..5 {
+            client.kv_client("foo").await.unwrap();
+        }
+        assert_eq!(client.kv_client_cache.read().await["foo"].len(), 3);
+        assert_eq!(client.kv_client_turn.load(Ordering::Relaxed), 5);
+
+        let client =
+            pd_rpc_client_with_config(Config::default().with_connections_per_store(0)).await;
+        client.kv_client("foo").await.unwrap();
+        assert_eq!(client.kv_client_cache.read().await["foo"].len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_with_codec() {
+        let client = pd_rpc_client().await;
+        let shared = client.with_codec(true);
+        assert!(!client.enable_codec);
+        assert!(shared.enable_codec);
+        assert!(Arc::ptr_eq(&client.region_cache, &shared.region_cache));
+
+        client.kv_client("foo").await.unwrap();
+        assert!(shared.kv_client_cache.read().await.contains_key("foo"));
+
+        let stale = shared.with_stale_read();
+        assert!(stale.stale_read);
+        assert!(stale.enable_codec);
+        assert!(!shared.stale_read);
+
+        let replica = shared.with_replica_read();
+        assert!(replica.replica_read);
+        assert!(!replica.stale_read);
+        assert!(!shared.replica_read);
+
+        let follower = shared.with_replica_read_kind(ReplicaRead::Follower);
+        assert!(follower.replica_read);
+        assert_eq!(follower.replica_read_kind, Some(ReplicaRead::Follower));
+        assert_eq!(replica.replica_read_kind, None);
+
+        let v2 = client.with_api_version(kvrpcpb::ApiVersion::V2);
+        assert_eq!(v2.api_version, kvrpcpb::ApiVersion::V2);
+        assert!(v2.enable_codec);
+        assert_eq!(client.api_version, kvrpcpb::ApiVersion::V1);
+    }
+
     #[test]
     fn test_group_keys_by_region() {
         let client = MockPdClient::default();
@@ -406,6 +1054,17 @@ pub mod test {
         assert!(stream.next().is_none());
     }
 
+    #[test]
+    fn test_watch_gc_safepoint() {
+        let client = Arc::new(MockPdClient::default());
+        client.safepoint.store(5, Ordering::SeqCst);
+        let stream = client.clone().watch_gc_safepoint(Duration::from_millis(1));
+        let mut stream = executor::block_on_stream(stream);
+        assert_eq!(stream.next().unwrap().unwrap(), 5);
+        client.safepoint.store(7, Ordering::SeqCst);
+        assert_eq!(stream.next().unwrap().unwrap(), 7);
+    }
+
     #[test]
     fn test_stores_for_range() {
         let client = Arc::new(MockPdClient::default());
@@ -492,4 +1151,100 @@ pub mod test {
         );
         assert!(stream.next().is_none());
     }
+
+    #[test]
+    fn test_group_ranges_outside_region() {
+        let client = Arc::new(MockPdClient::default());
+        let range = |start_key: Vec<u8>, end_key: Vec<u8>| kvrpcpb::KeyRange { start_key, end_key };
+        // the second range is after the region of the first one
+        let ranges = vec![range(vec![1], vec![5]), range(vec![12], vec![14])];
+        let grouped: Vec<_> =
+            executor::block_on_stream(client.clone().group_ranges_by_region(ranges))
+                .collect::<Result<_>>()
+                .unwrap();
+        assert_eq!(
+            grouped,
+            vec![
+                (1, vec![range(vec![1], vec![5])]),
+                (2, vec![range(vec![12], vec![14])])
+            ]
+        );
+
+        let ranges = vec![range(vec![1], vec![5]), range(vec![12], vec![3])];
+        let mut stream = executor::block_on_stream(client.clone().group_ranges_by_region(ranges));
+        assert!(matches!(
+            stream.next().unwrap(),
+            Err(Error::InvalidRange { .. })
+        ));
+
+        let range = (vec![12], vec![3]).into();
+        let mut stream = executor::block_on_stream(client.stores_for_range(range));
+        assert!(matches!(
+            stream.next().unwrap(),
+            Err(Error::InvalidRange { .. })
+        ));
+        assert!(stream.next().is_none());
+    }
+
+    #[test]
+    fn test_replica_candidates() {
+        let peer = |id: u64, role: metapb::PeerRole| {
+            let mut peer = metapb::Peer {
+                id,
+                store_id: id + 40,
+                ..Default::default()
+            };
+            peer.set_role(role);
+            peer
+        };
+        let leader = peer(1, metapb::PeerRole::Voter);
+        let follower = peer(2, metapb::PeerRole::Voter);
+        let learner = peer(3, metapb::PeerRole::Learner);
+        let mut region = MockPdClient::region1();
+        region.region.peers = vec![leader.clone(), follower.clone(), learner.clone()];
+        region.leader = Some(leader.clone());
+
+        let candidates = |kind, leader_healthy| replica_candidates(&region, kind, leader_healthy);
+        assert_eq!(candidates(None, true).len(), 3);
+        assert_eq!(
+            candidates(Some(ReplicaRead::Leader), true),
+            vec![leader.clone()]
+        );
+        assert_eq!(
+            candidates(Some(ReplicaRead::Follower), true),
+            vec![follower.clone()]
+        );
+        assert_eq!(candidates(Some(ReplicaRead::Learner), true), vec![learner]);
+        assert_eq!(
+            candidates(Some(ReplicaRead::PreferLeader), true),
+            vec![leader]
+        );
+        // followers serve the reads while the store of the leader is unreachable
+        assert_eq!(
+            candidates(Some(ReplicaRead::PreferLeader), false),
+            vec![follower]
+        );
+
+        // witnesses never serve reads, whatever their role
+        let mut witness = peer(4, metapb::PeerRole::Voter);
+        witness.is_witness = true;
+        let mut learner_witness = peer(5, metapb::PeerRole::Learner);
+        learner_witness.is_witness = true;
+        region.region.peers.extend([witness, learner_witness]);
+        let candidate_ids = |kind| {
+            let candidates = replica_candidates(&region, kind, false);
+            candidates
+                .into_iter()
+                .map(|peer| peer.id)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(candidate_ids(None), vec![1, 2, 3]);
+        assert_eq!(candidate_ids(Some(ReplicaRead::Follower)), vec![2]);
+        assert_eq!(candidate_ids(Some(ReplicaRead::Learner)), vec![3]);
+        assert_eq!(candidate_ids(Some(ReplicaRead::PreferLeader)), vec![2]);
+
+        // without learners, the leader serves learner reads
+        region.region.peers.truncate(2);
+        assert!(replica_candidates(&region, Some(ReplicaRead::Learner), true).is_empty());
+    }
 }
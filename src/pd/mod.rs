@@ -1,5 +1,19 @@
 mod client;
 mod retry;
+mod timestamp;
 
 pub use client::{PdClient, PdRpcClient};
 pub use retry::{RetryClient, RetryClientTrait};
+
+/// A member of the PD cluster.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PdMember {
+    pub name: String,
+    pub member_id: u64,
+    /// The URLs clients use to connect to the member.
+    pub client_urls: Vec<String>,
+    /// Whether the member is the leader of the PD cluster.
+    pub is_leader: bool,
+    /// Whether the member responded to the client when the members were listed.
+    pub is_healthy: bool,
+}
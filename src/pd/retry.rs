@@ -3,21 +3,25 @@
 //! A utility module for managing and retrying PD requests.
 
 use crate::{
+    backoff::Backoff,
+    clock::ClockHandle,
+    pd::PdMember,
     region::{RegionId, RegionWithLeader, StoreId},
     stats::pd_stats,
     Error, Result, SecurityManager,
 };
 use async_trait::async_trait;
-use futures_timer::Delay;
+use futures::future::join_all;
 use grpcio::Environment;
 use std::{
+    collections::HashMap,
     fmt,
     sync::Arc,
     time::{Duration, Instant},
 };
 use tikv_client_pd::{Cluster, Connection};
 use tikv_client_proto::{
-    metapb,
+    keyspacepb, metapb,
     pdpb::{self, Timestamp},
 };
 use tokio::sync::RwLock;
@@ -43,6 +47,8 @@ pub trait RetryClientTrait {
     async fn get_timestamp(self: Arc<Self>) -> Result<Timestamp>;
 
     async fn update_safepoint(self: Arc<Self>, safepoint: u64) -> Result<bool>;
+
+    async fn get_safepoint(self: Arc<Self>) -> Result<u64>;
 }
 /// Client for communication with a PD cluster. Has the facility to reconnect to the cluster.
 pub struct RetryClient<Cl = Cluster> {
@@ -50,6 +56,10 @@ pub struct RetryClient<Cl = Cluster> {
     cluster: RwLock<(Cl, Instant)>,
     connection: Connection,
     timeout: Duration,
+    // The backoff between reconnects, cloned for each request.
+    backoff: Backoff,
+    // The clock to sleep with between reconnects.
+    clock: ClockHandle,
 }
 
 #[cfg(test)]
@@ -65,6 +75,8 @@ impl<Cl> RetryClient<Cl> {
             cluster: RwLock::new((cluster, Instant::now())),
             connection,
             timeout,
+            backoff: crate::backoff::default_pd_backoff(),
+            clock: ClockHandle::default(),
         }
     }
 }
@@ -72,6 +84,7 @@ impl<Cl> RetryClient<Cl> {
 macro_rules! retry {
     ($self: ident, $tag: literal, |$cluster: ident| $call: expr) => {{
         let stats = pd_stats($tag);
+        let mut backoff = $self.backoff.clone();
         let mut last_err = Ok(());
         for _ in 0..LEADER_CHANGE_RETRY {
             // use the block here to drop the guard of the read lock,
@@ -82,19 +95,30 @@ macro_rules! retry {
                 res
             };
 
-            match stats.done(res) {
+            let err = match stats.done(res) {
                 Ok(r) => return Ok(r),
-                Err(e) => last_err = Err(e),
-            }
+                Err(e) => e,
+            };
 
             let mut reconnect_count = MAX_REQUEST_COUNT;
-            while let Err(e) = $self.reconnect(RECONNECT_INTERVAL_SEC).await {
-                reconnect_count -= 1;
-                if reconnect_count == 0 {
-                    return Err(e);
+            loop {
+                // Wait for a random time before reconnecting, so that a large number of clients
+                // do not all reconnect at the same time, e.g., after PD restarted.
+                match backoff.next_delay_duration() {
+                    Some(delay) => $self.clock.sleep(delay).await,
+                    None => return Err(err),
+                }
+                match $self.reconnect(RECONNECT_INTERVAL_SEC).await {
+                    Ok(()) => break,
+                    Err(e) => {
+                        reconnect_count -= 1;
+                        if reconnect_count == 0 {
+                            return Err(e);
+                        }
+                    }
                 }
-                Delay::new(Duration::from_secs(RECONNECT_INTERVAL_SEC)).await;
             }
+            last_err = Err(err);
         }
 
         last_err?;
@@ -106,10 +130,14 @@ impl RetryClient<Cluster> {
     pub async fn connect(
         env: Arc<Environment>,
         endpoints: &[String],
+        endpoint_priorities: HashMap<String, u32>,
         security_mgr: Arc<SecurityManager>,
         timeout: Duration,
+        backoff: Backoff,
+        clock: ClockHandle,
     ) -> Result<RetryClient> {
-        let connection = Connection::new(env, security_mgr);
+        let connection =
+            Connection::new(env, security_mgr).with_endpoint_priorities(endpoint_priorities);
         let cluster = RwLock::new((
             connection.connect_cluster(endpoints, timeout).await?,
             Instant::now(),
@@ -118,8 +146,94 @@ impl RetryClient<Cluster> {
             cluster,
             connection,
             timeout,
+            backoff,
+            clock,
+        })
+    }
+
+    /// List the members of the PD cluster, checking whether each of them is reachable.
+    pub async fn get_members(self: Arc<Self>) -> Result<Vec<PdMember>> {
+        let resp = self.clone().get_members_response().await?;
+        let this = &self;
+        let health = join_all(resp.get_members().iter().map(|m| async move {
+            for url in m.get_client_urls() {
+                if this.connection.is_reachable(url, this.timeout).await {
+                    return true;
+                }
+            }
+            false
+        }))
+        .await;
+        Ok(members_from_response(resp, health))
+    }
+
+    /// The statistics of the store `id`, as last reported to PD by the store.
+    pub async fn get_store_stats(self: Arc<Self>, id: StoreId) -> Result<pdpb::StoreStats> {
+        retry!(self, "get_store", |cluster| async {
+            cluster
+                .get_store(id, self.timeout)
+                .await
+                .map(|mut resp| resp.take_stats())
+        })
+    }
+
+    /// The binary version of the leader of the PD cluster.
+    pub async fn get_leader_version(self: Arc<Self>) -> Result<String> {
+        let mut resp = self.get_members_response().await?;
+        Ok(resp.mut_leader().take_binary_version())
+    }
+
+    /// Get `count` consecutive timestamps, returning the last of them.
+    pub async fn get_timestamps(self: Arc<Self>, count: u32) -> Result<Timestamp> {
+        retry!(self, "get_timestamp", |cluster| {
+            cluster.get_timestamps(count)
+        })
+    }
+
+    /// The metadata of the keyspace `name`.
+    pub async fn load_keyspace(self: Arc<Self>, name: &str) -> Result<keyspacepb::KeyspaceMeta> {
+        retry!(self, "load_keyspace", |cluster| async {
+            cluster
+                .load_keyspace(name, self.timeout)
+                .await
+                .map(|mut resp| resp.take_keyspace())
+        })
+    }
+
+    /// The id of the cluster.
+    pub async fn cluster_id(&self) -> u64 {
+        self.cluster.read().await.0.id()
+    }
+
+    /// Split the regions at `split_keys` and scatter the new regions across the stores, returning
+    /// the ids of the new regions.
+    pub async fn split_and_scatter_regions(
+        self: Arc<Self>,
+        split_keys: Vec<Vec<u8>>,
+    ) -> Result<Vec<RegionId>> {
+        retry!(self, "split_and_scatter_regions", |cluster| async {
+            cluster
+                .split_and_scatter_regions(split_keys.clone(), self.timeout)
+                .await
+                .map(|resp| resp.regions_id)
         })
     }
+
+    /// Scatter the regions `region_ids` across the stores. PD schedules the moves of their peers,
+    /// which are done in the background.
+    pub async fn scatter_regions(self: Arc<Self>, region_ids: Vec<RegionId>) -> Result<()> {
+        retry!(self, "scatter_regions", |cluster| async {
+            cluster
+                .scatter_regions(region_ids.clone(), self.timeout)
+                .await
+                .map(|_| ())
+        })
+    }
+
+    async fn get_members_response(self: Arc<Self>) -> Result<pdpb::GetMembersResponse> {
+        retry!(self, "get_members", |cluster| cluster
+            .get_members(self.timeout))
+    }
 }
 
 #[async_trait]
@@ -160,7 +274,6 @@ impl RetryClientTrait for RetryClient<Cluster> {
         })
     }
 
-    #[allow(dead_code)]
     async fn get_all_stores(self: Arc<Self>) -> Result<Vec<metapb::Store>> {
         retry!(self, "get_all_stores", |cluster| async {
             cluster
@@ -182,6 +295,15 @@ impl RetryClientTrait for RetryClient<Cluster> {
                 .map(|resp| resp.get_new_safe_point() == safepoint)
         })
     }
+
+    async fn get_safepoint(self: Arc<Self>) -> Result<u64> {
+        retry!(self, "get_gc_safepoint", |cluster| async {
+            cluster
+                .get_safepoint(self.timeout)
+                .await
+                .map(|resp| resp.get_safe_point())
+        })
+    }
 }
 
 impl fmt::Debug for RetryClient {
@@ -192,12 +314,35 @@ impl fmt::Debug for RetryClient {
     }
 }
 
+// `health` tells whether each member of `resp` is reachable.
+fn members_from_response(mut resp: pdpb::GetMembersResponse, health: Vec<bool>) -> Vec<PdMember> {
+    let leader_id = resp.get_leader().get_member_id();
+    resp.take_members()
+        .into_iter()
+        .zip(health)
+        .map(|(mut m, is_healthy)| PdMember {
+            name: m.take_name(),
+            member_id: m.get_member_id(),
+            client_urls: m.take_client_urls().into_iter().collect(),
+            is_leader: m.get_member_id() == leader_id,
+            is_healthy,
+        })
+        .collect()
+}
+
 fn region_from_response(
     resp: pdpb::GetRegionResponse,
     err: impl FnOnce() -> Error,
 ) -> Result<RegionWithLeader> {
     let region = resp.region.ok_or_else(err)?;
-    Ok(RegionWithLeader::new(region, resp.leader))
+    let mut region = RegionWithLeader::new(region, resp.leader);
+    region.down_peers = resp
+        .down_peers
+        .into_iter()
+        .filter_map(|stats| stats.peer)
+        .collect();
+    region.pending_peers = resp.pending_peers;
+    Ok(region)
 }
 
 // A node-like thing that can be connected to.
@@ -229,6 +374,7 @@ impl Reconnect for RetryClient<Cluster> {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::clock::MockClock;
     use futures::{executor, future::ready};
     use std::sync::{
         atomic::{AtomicUsize, Ordering},
@@ -241,6 +387,8 @@ mod test {
         struct MockClient {
             reconnect_count: AtomicUsize,
             cluster: RwLock<((), Instant)>,
+            backoff: Backoff,
+            clock: ClockHandle,
         }
 
         #[async_trait]
@@ -267,6 +415,8 @@ mod test {
             let client = Arc::new(MockClient {
                 reconnect_count: AtomicUsize::new(0),
                 cluster: RwLock::new(((), Instant::now())),
+                backoff: Backoff::no_jitter_backoff(0, 0, 100),
+                clock: ClockHandle::default(),
             });
 
             assert!(retry_err(client.clone()).await.is_err());
@@ -294,6 +444,8 @@ mod test {
     fn test_retry() {
         struct MockClient {
             cluster: RwLock<(AtomicUsize, Instant)>,
+            backoff: Backoff,
+            clock: ClockHandle,
         }
 
         #[async_trait]
@@ -340,6 +492,8 @@ mod test {
         executor::block_on(async {
             let client = Arc::new(MockClient {
                 cluster: RwLock::new((AtomicUsize::new(0), Instant::now())),
+                backoff: Backoff::no_jitter_backoff(0, 0, 100),
+                clock: ClockHandle::default(),
             });
             let max_retries = Arc::new(AtomicUsize::new(1000));
 
@@ -351,6 +505,8 @@ mod test {
 
             let client = Arc::new(MockClient {
                 cluster: RwLock::new((AtomicUsize::new(0), Instant::now())),
+                backoff: Backoff::no_jitter_backoff(0, 0, 100),
+                clock: ClockHandle::default(),
             });
             let max_retries = Arc::new(AtomicUsize::new(2));
 
@@ -358,4 +514,131 @@ mod test {
             assert_eq!(client.cluster.read().await.0.load(Ordering::SeqCst), 2);
         })
     }
+
+    #[test]
+    fn test_retry_backoff() {
+        struct MockClient {
+            cluster: RwLock<(AtomicUsize, Instant)>,
+            backoff: Backoff,
+            clock: ClockHandle,
+        }
+
+        #[async_trait]
+        impl Reconnect for MockClient {
+            type Cl = ();
+
+            async fn reconnect(&self, _: u64) -> Result<()> {
+                Ok(())
+            }
+        }
+
+        async fn retry_err(client: Arc<MockClient>) -> Result<()> {
+            retry!(client, "test", |c| {
+                c.fetch_add(1, Ordering::SeqCst);
+                ready(Err(internal_err!("whoops")))
+            })
+        }
+
+        executor::block_on(async {
+            // the request is not retried once the backoff is exhausted
+            let client = Arc::new(MockClient {
+                cluster: RwLock::new((AtomicUsize::new(0), Instant::now())),
+                backoff: Backoff::no_jitter_backoff(0, 0, 2),
+                clock: ClockHandle::default(),
+            });
+            assert!(retry_err(client.clone()).await.is_err());
+            assert_eq!(client.cluster.read().await.0.load(Ordering::SeqCst), 3);
+
+            let client = Arc::new(MockClient {
+                cluster: RwLock::new((AtomicUsize::new(0), Instant::now())),
+                backoff: Backoff::no_backoff(),
+                clock: ClockHandle::default(),
+            });
+            assert!(retry_err(client.clone()).await.is_err());
+            assert_eq!(client.cluster.read().await.0.load(Ordering::SeqCst), 1);
+
+            // the backoff sleeps with the clock of the client
+            let clock = MockClock::auto_advancing();
+            let client = Arc::new(MockClient {
+                cluster: RwLock::new((AtomicUsize::new(0), Instant::now())),
+                backoff: Backoff::no_jitter_backoff(60_000, 60_000, 2),
+                clock: ClockHandle::new(clock.clone()),
+            });
+            assert!(retry_err(client.clone()).await.is_err());
+            assert_eq!(client.cluster.read().await.0.load(Ordering::SeqCst), 3);
+            assert_eq!(clock.elapsed(), Duration::from_secs(120));
+        })
+    }
+
+    #[test]
+    fn test_region_from_response() {
+        let down = metapb::Peer {
+            id: 2,
+            store_id: 2,
+            ..Default::default()
+        };
+        let pending = metapb::Peer {
+            id: 3,
+            store_id: 3,
+            ..Default::default()
+        };
+        let resp = pdpb::GetRegionResponse {
+            region: Some(metapb::Region {
+                id: 1,
+                ..Default::default()
+            }),
+            down_peers: vec![pdpb::PeerStats {
+                peer: Some(down.clone()),
+                down_seconds: 60,
+            }],
+            pending_peers: vec![pending.clone()],
+            ..Default::default()
+        };
+        let region = region_from_response(resp, || unreachable!()).unwrap();
+        assert_eq!(region.down_peers, vec![down]);
+        assert_eq!(region.pending_peers, vec![pending]);
+        assert!(region.has_unavailable_peers());
+    }
+
+    #[test]
+    fn test_members_from_response() {
+        let member = |id: u64| pdpb::Member {
+            name: format!("pd{}", id),
+            member_id: id,
+            client_urls: vec![format!("http://pd{}:2379", id)],
+            ..Default::default()
+        };
+        let resp = pdpb::GetMembersResponse {
+            members: vec![member(1), member(2), member(3)],
+            leader: Some(member(2)),
+            ..Default::default()
+        };
+        let members = members_from_response(resp, vec![true, true, false]);
+        assert_eq!(
+            members,
+            vec![
+                PdMember {
+                    name: "pd1".to_owned(),
+                    member_id: 1,
+                    client_urls: vec!["http://pd1:2379".to_owned()],
+                    is_leader: false,
+                    is_healthy: true,
+                },
+                PdMember {
+                    name: "pd2".to_owned(),
+                    member_id: 2,
+                    client_urls: vec!["http://pd2:2379".to_owned()],
+                    is_leader: true,
+                    is_healthy: true,
+                },
+                PdMember {
+                    name: "pd3".to_owned(),
+                    member_id: 3,
+                    client_urls: vec!["http://pd3:2379".to_owned()],
+                    is_leader: false,
+                    is_healthy: false,
+                },
+            ]
+        );
+    }
 }
@@ -0,0 +1,128 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+use crate::{clock::ClockHandle, config::TimestampPrefetch, Result, Timestamp};
+use std::{future::Future, sync::Mutex, time::Instant};
+
+/// Hands out the timestamps of a batch fetched from PD at once, until they run out or the batch
+/// is older than the maximum age of the prefetch, see
+/// [`Config::with_timestamp_prefetch`](crate::Config::with_timestamp_prefetch).
+pub(crate) struct TimestampPrefetcher {
+    prefetch: TimestampPrefetch,
+    batch: Mutex<Option<Batch>>,
+}
+
+/// The timestamps of a batch which were not handed out yet.
+struct Batch {
+    next: Timestamp,
+    last_logical: i64,
+    requested_at: Instant,
+}
+
+impl TimestampPrefetcher {
+    pub(crate) fn new(prefetch: TimestampPrefetch) -> TimestampPrefetcher {
+        TimestampPrefetcher {
+            prefetch,
+            batch: Mutex::new(None),
+        }
+    }
+
+    /// The next timestamp of the current batch, or the first timestamp of a new batch. `fetch`
+    /// gets the given number of consecutive timestamps from PD and returns the last of them.
+    pub(crate) async fn get_timestamp<Fut>(
+        &self,
+        clock: &ClockHandle,
+        fetch: impl FnOnce(u32) -> Fut,
+    ) -> Result<Timestamp>
+    where
+        Fut: Future<Output = Result<Timestamp>>,
+    {
+        if let Some(timestamp) = self.take(clock) {
+            return Ok(timestamp);
+        }
+
+        // the age of the batch counts from the request, the timestamps are at least as old
+        let requested_at = clock.now();
+        let count = self.prefetch.count.max(1);
+        let last = fetch(count).await?;
+        let first = Timestamp {
+            logical: last.logical - (count as i64 - 1),
+            ..last.clone()
+        };
+        if count > 1 {
+            let next = Timestamp {
+                logical: first.logical + 1,
+                ..last.clone()
+            };
+            // concurrent calls may have fetched batches too, only the latest one is kept
+            *self.batch.lock().unwrap() = Some(Batch {
+                next,
+                last_logical: last.logical,
+                requested_at,
+            });
+        }
+        Ok(first)
+    }
+
+    fn take(&self, clock: &ClockHandle) -> Option<Timestamp> {
+        let mut guard = self.batch.lock().unwrap();
+        let batch = guard.as_mut()?;
+        if clock.elapsed_since(batch.requested_at) > self.prefetch.max_age {
+            *guard = None;
+            return None;
+        }
+        let timestamp = batch.next.clone();
+        if timestamp.logical >= batch.last_logical {
+            *guard = None;
+        } else {
+            batch.next.logical += 1;
+        }
+        Some(timestamp)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use std::{
+        sync::atomic::{AtomicU32, Ordering},
+        time::Duration,
+    };
+
+    #[tokio::test]
+    async fn test_timestamp_prefetch() -> Result<()> {
+        let clock = MockClock::new();
+        let handle = ClockHandle::new(clock.clone());
+        let prefetcher = TimestampPrefetcher::new(TimestampPrefetch {
+            count: 3,
+            max_age: Duration::from_millis(10),
+        });
+        // each batch is one physical millisecond later, with logical times up to 10
+        let fetches = AtomicU32::new(0);
+        let fetch = |count| {
+            let physical = fetches.fetch_add(1, Ordering::SeqCst) as i64;
+            async move {
+                assert_eq!(count, 3);
+                Ok(Timestamp {
+                    physical,
+                    logical: 10,
+                    ..Default::default()
+                })
+            }
+        };
+
+        let mut timestamps = Vec::new();
+        for _ in 0..4 {
+            let timestamp = prefetcher.get_timestamp(&handle, fetch).await?;
+            timestamps.push((timestamp.physical, timestamp.logical));
+        }
+        assert_eq!(timestamps, vec![(0, 8), (0, 9), (0, 10), (1, 8)]);
+
+        // the rest of the batch is too old
+        clock.advance(Duration::from_millis(11));
+        let timestamp = prefetcher.get_timestamp(&handle, fetch).await?;
+        assert_eq!((timestamp.physical, timestamp.logical), (2, 8));
+        assert_eq!(fetches.load(Ordering::SeqCst), 3);
+        Ok(())
+    }
+}
@@ -0,0 +1,166 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+use crate::{clock::ClockHandle, region::StoreId};
+use std::{
+    collections::{BTreeSet, VecDeque},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tikv_client_proto::errorpb;
+
+/// The window over which the pressure of the cluster is measured.
+const PRESSURE_WINDOW: Duration = Duration::from_secs(10);
+/// The number of buckets the window is divided into. The oldest bucket is dropped at once, so the
+/// window slides in steps of `PRESSURE_WINDOW / PRESSURE_BUCKETS`.
+const PRESSURE_BUCKETS: u64 = 10;
+
+/// How loaded the TiKV cluster appears to a client, from the responses to its requests in the
+/// last 10 seconds.
+///
+/// TiKV replies `ServerIsBusy` when its queues are full, e.g. when the scheduler has too many
+/// pending writes. The client retries these requests after a backoff, so they only fail once the
+/// backoff is exhausted. A rising [`busy_ratio`](ClusterPressure::busy_ratio) lets an application
+/// shed its own load before that happens.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ClusterPressure {
+    /// The number of responses received from TiKV.
+    pub responses: u64,
+    /// The number of responses which were `ServerIsBusy` errors.
+    pub busy_responses: u64,
+    /// The longest backoff suggested by the busy stores.
+    pub suggested_backoff: Duration,
+    /// The stores which replied `ServerIsBusy`, ordered by id.
+    pub busy_stores: Vec<StoreId>,
+}
+
+impl ClusterPressure {
+    /// The fraction of the responses which were `ServerIsBusy` errors, between 0 and 1.
+    pub fn busy_ratio(&self) -> f64 {
+        if self.responses == 0 {
+            0.0
+        } else {
+            self.busy_responses as f64 / self.responses as f64
+        }
+    }
+}
+
+/// Counts the responses of the stores in the buckets of a sliding window, see
+/// [`ClusterPressure`].
+pub struct PressureTracker {
+    clock: ClockHandle,
+    start: Instant,
+    buckets: Mutex<VecDeque<Bucket>>,
+}
+
+#[derive(Default)]
+struct Bucket {
+    index: u64,
+    responses: u64,
+    busy_responses: u64,
+    suggested_backoff: Duration,
+    busy_stores: BTreeSet<StoreId>,
+}
+
+impl PressureTracker {
+    pub fn new(clock: ClockHandle) -> PressureTracker {
+        PressureTracker {
+            start: clock.now(),
+            clock,
+            buckets: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Records a response of `store`, which was `busy` if set.
+    pub fn observe(&self, store: Option<StoreId>, busy: Option<&errorpb::ServerIsBusy>) {
+        let index = self.bucket_index();
+        let mut buckets = self.buckets.lock().unwrap();
+        Self::expire(&mut buckets, index);
+        if buckets.back().is_none_or(|bucket| bucket.index != index) {
+            buckets.push_back(Bucket {
+                index,
+                ..Default::default()
+            });
+        }
+        let bucket = buckets.back_mut().unwrap();
+        bucket.responses += 1;
+        if let Some(busy) = busy {
+            bucket.busy_responses += 1;
+            bucket.suggested_backoff = bucket
+                .suggested_backoff
+                .max(Duration::from_millis(busy.backoff_ms));
+            bucket.busy_stores.extend(store);
+        }
+    }
+
+    pub fn pressure(&self) -> ClusterPressure {
+        let index = self.bucket_index();
+        let mut buckets = self.buckets.lock().unwrap();
+        Self::expire(&mut buckets, index);
+        let mut busy_stores = BTreeSet::new();
+        let mut pressure = ClusterPressure::default();
+        for bucket in buckets.iter() {
+            pressure.responses += bucket.responses;
+            pressure.busy_responses += bucket.busy_responses;
+            pressure.suggested_backoff = pressure.suggested_backoff.max(bucket.suggested_backoff);
+            busy_stores.extend(bucket.busy_stores.iter().copied());
+        }
+        pressure.busy_stores = busy_stores.into_iter().collect();
+        pressure
+    }
+
+    fn bucket_index(&self) -> u64 {
+        let bucket_len = PRESSURE_WINDOW.as_millis() as u64 / PRESSURE_BUCKETS;
+        self.clock.elapsed_since(self.start).as_millis() as u64 / bucket_len
+    }
+
+    /// Drops the buckets which are out of the window ending in bucket `index`.
+    fn expire(buckets: &mut VecDeque<Bucket>, index: u64) {
+        while buckets
+            .front()
+            .is_some_and(|bucket| bucket.index + PRESSURE_BUCKETS <= index)
+        {
+            buckets.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MockClock;
+
+    #[test]
+    fn test_pressure_tracker() {
+        let clock = MockClock::new();
+        let tracker = PressureTracker::new(ClockHandle::new(clock.clone()));
+        assert_eq!(tracker.pressure(), ClusterPressure::default());
+        assert_eq!(tracker.pressure().busy_ratio(), 0.0);
+
+        let mut busy = errorpb::ServerIsBusy {
+            backoff_ms: 100,
+            ..Default::default()
+        };
+        tracker.observe(Some(1), None);
+        tracker.observe(Some(2), Some(&busy));
+        clock.advance(Duration::from_secs(5));
+        busy.backoff_ms = 50;
+        tracker.observe(Some(3), Some(&busy));
+        tracker.observe(Some(1), None);
+        let pressure = tracker.pressure();
+        assert_eq!(pressure.responses, 4);
+        assert_eq!(pressure.busy_responses, 2);
+        assert_eq!(pressure.busy_ratio(), 0.5);
+        assert_eq!(pressure.suggested_backoff, Duration::from_millis(100));
+        assert_eq!(pressure.busy_stores, vec![2, 3]);
+
+        // the first responses leave the window
+        clock.advance(Duration::from_secs(6));
+        let pressure = tracker.pressure();
+        assert_eq!(pressure.responses, 2);
+        assert_eq!(pressure.busy_stores, vec![3]);
+        assert_eq!(pressure.suggested_backoff, Duration::from_millis(50));
+
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(tracker.pressure(), ClusterPressure::default());
+    }
+}
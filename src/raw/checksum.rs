@@ -0,0 +1,93 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+use crate::KvPair;
+use std::ops::BitXor;
+use tikv_client_proto::kvrpcpb;
+
+/// The checksum of the key-value pairs in some ranges, see
+/// [`RawClient::checksum`](crate::RawClient::checksum) and
+/// [`TransactionClient::checksum`](crate::TransactionClient::checksum).
+///
+/// It is computed the way TiKV computes it, as the XOR of the CRC-64 (ECMA-182) of each key
+/// followed by its value, so the checksum of pairs read by the client can be compared with the
+/// checksum computed by TiKV.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct RawChecksum {
+    pub checksum: u64,
+    pub total_kvs: u64,
+    pub total_bytes: u64,
+}
+
+impl RawChecksum {
+    /// The checksum of `pairs`, which must have distinct keys.
+    pub fn of_pairs<'a>(pairs: impl IntoIterator<Item = &'a KvPair>) -> RawChecksum {
+        pairs
+            .into_iter()
+            .map(|pair| RawChecksum::of_pair(pair.key().into(), pair.value()))
+            .fold(RawChecksum::default(), BitXor::bitxor)
+    }
+
+    fn of_pair(key: &[u8], value: &[u8]) -> RawChecksum {
+        let mut digest = crc64fast::Digest::new();
+        digest.write(key);
+        digest.write(value);
+        RawChecksum {
+            checksum: digest.sum64(),
+            total_kvs: 1,
+            total_bytes: (key.len() + value.len()) as u64,
+        }
+    }
+}
+
+/// Combines the checksums of disjoint sets of pairs.
+impl BitXor for RawChecksum {
+    type Output = RawChecksum;
+
+    fn bitxor(self, other: RawChecksum) -> RawChecksum {
+        RawChecksum {
+            checksum: self.checksum ^ other.checksum,
+            total_kvs: self.total_kvs + other.total_kvs,
+            total_bytes: self.total_bytes + other.total_bytes,
+        }
+    }
+}
+
+impl From<kvrpcpb::RawChecksumResponse> for RawChecksum {
+    fn from(resp: kvrpcpb::RawChecksumResponse) -> RawChecksum {
+        RawChecksum {
+            checksum: resp.checksum,
+            total_kvs: resp.total_kvs,
+            total_bytes: resp.total_bytes,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_raw_checksum() {
+        let pairs = vec![
+            KvPair::new(b"k1".to_vec(), b"v1".to_vec()),
+            KvPair::new(b"k2".to_vec(), b"value2".to_vec()),
+        ];
+        let checksum = RawChecksum::of_pairs(&pairs);
+        assert_eq!(checksum.total_kvs, 2);
+        assert_eq!(checksum.total_bytes, 12);
+        // independent of the order of the pairs, and of how they are split
+        let reversed: Vec<KvPair> = pairs.iter().rev().cloned().collect();
+        assert_eq!(RawChecksum::of_pairs(&reversed), checksum);
+        assert_eq!(
+            RawChecksum::of_pairs(&pairs[..1]) ^ RawChecksum::of_pairs(&pairs[1..]),
+            checksum
+        );
+        // a changed value changes the checksum
+        let changed = vec![
+            KvPair::new(b"k1".to_vec(), b"v1".to_vec()),
+            KvPair::new(b"k2".to_vec(), b"value3".to_vec()),
+        ];
+        assert_ne!(RawChecksum::of_pairs(&changed).checksum, checksum.checksum);
+        assert_eq!(RawChecksum::of_pairs(&[]), RawChecksum::default());
+    }
+}
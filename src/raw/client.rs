@@ -1,22 +1,50 @@
 // Copyright 2019 TiKV Project Authors. Licensed under Apache-2.0.
 
 use core::ops::Range;
-use std::{str::FromStr, sync::Arc, u32};
+use std::{collections::HashMap, iter, str::FromStr, sync::Arc, time::Duration, u32};
 
+use futures::{prelude::*, stream::BoxStream};
+use rand::Rng;
 use slog::{Drain, Logger};
 use tikv_client_common::Error;
-use tikv_client_proto::metapb;
+use tikv_client_proto::{kvrpcpb, metapb};
+use tikv_client_store::{HasKeyErrors, HasRegionErrors};
 
 use crate::{
-    backoff::DEFAULT_REGION_BACKOFF,
+    cluster::fetch_store_stats,
     config::Config,
+    key_validator,
+    keyspace::Keyspace,
+    namespace::Namespace,
     pd::{PdClient, PdRpcClient},
-    raw::lowering::*,
-    request::{Collect, CollectSingle, Plan},
-    BoundRange, ColumnFamily, Key, KvPair, Result, Value,
+    pressure::PressureTracker,
+    raw::{
+        lowering::*,
+        self_check::{SelfCheckStep, SELF_CHECK_PREFIX, SELF_CHECK_SUFFIXES},
+        ApiVersion, RawChecksum, RawMutation, RawOptions, ScanToken, ScannedPair, SelfCheckReport,
+        WriteGroup,
+    },
+    region::{RegionId, RegionWithLeader},
+    request::{
+        pair_size, plan::MULTI_REGION_CONCURRENCY, scan_items, scan_stream_with_regions, Collect,
+        CollectError, CollectSingle, DeleteSummary, Dispatch, KvRequest, NoTarget, Plan,
+        PlanBuilder, ResponseWithShard, RetryOptions, ScanItem, ScanPrefetch,
+    },
+    split::new_split_region_request,
+    stats::observe_result_bytes,
+    store::RegionStore,
+    transaction::{CheckLevel, Transaction, TransactionOptions},
+    value_codec::ValueCodec,
+    BoundRange, Cluster, ClusterInfo, ClusterPressure, ColumnFamily, ConnectionStats, Key, KvPair,
+    PdMember, Result, StoreStats, Value,
 };
 
 const MAX_RAW_KV_SCAN_LIMIT: u32 = 10240;
+/// The key read by [`detect_api_version`](Client::detect_api_version).
+const PROBE_KEY: &[u8] = b"tikv_client_probe";
+
+/// The batches of a streaming scan, with the ids of their regions.
+type RegionBatches = BoxStream<'static, Result<(RegionId, Vec<KvPair>)>>;
 
 /// The TiKV raw `Client` is used to interact with TiKV using raw requests.
 ///
@@ -25,15 +53,56 @@ const MAX_RAW_KV_SCAN_LIMIT: u32 = 10240;
 ///
 /// The returned results of raw request methods are [`Future`](std::future::Future)s that must be
 /// awaited to execute.
-#[derive(Clone)]
+///
+/// Requests are retried if TiKV can't be reached, except for
+/// [`compare_and_swap`](Client::compare_and_swap), [`put_if_absent`](Client::put_if_absent) and
+/// [`coprocessor`](Client::coprocessor), which may have taken effect before the connection was
+/// lost. A retried write writes the same values again, which has the same effect as one write
+/// unless another client wrote the keys in between: the last write wins, so a retry may overwrite
+/// the newer values of the other client.
 pub struct Client<PdC: PdClient = PdRpcClient> {
     rpc: Arc<PdC>,
-    cf: Option<ColumnFamily>,
-    /// Whether to use the [`atomic mode`](Client::with_atomic_for_cas).
-    atomic: bool,
+    /// The options of requests, see [`with_options`](Client::with_options).
+    options: RawOptions,
+    /// Applied to values written and read, see [`with_value_codec`](Client::with_value_codec).
+    value_codec: Option<Arc<dyn ValueCodec>>,
+    /// If set, keys are in the keyspace and requests use API V2, see
+    /// [`with_keyspace`](Client::with_keyspace).
+    keyspace: Option<Keyspace>,
+    /// If set, keys are in the namespace, see [`namespace`](Client::namespace).
+    namespace: Option<Namespace>,
+    /// The versions and features of the cluster, fetched when connecting.
+    cluster_info: Arc<ClusterInfo>,
+    /// Limits the size of the results of scans and batch gets, see
+    /// [`with_max_result_bytes`](Client::with_max_result_bytes).
+    max_result_bytes: Option<usize>,
+    /// If set, reads are replica reads sent through it, see
+    /// [`with_replica_read`](Client::with_replica_read).
+    replica_rpc: Option<Arc<PdC>>,
+    /// If set, reads fail over to the cluster of this client, see
+    /// [`with_standby`](Client::with_standby).
+    standby: Option<Arc<Client<PdC>>>,
     logger: Logger,
 }
 
+// Not derived, since that would require `PdC: Clone`.
+impl<PdC: PdClient> Clone for Client<PdC> {
+    fn clone(&self) -> Self {
+        Client {
+            rpc: self.rpc.clone(),
+            options: self.options.clone(),
+            value_codec: self.value_codec.clone(),
+            keyspace: self.keyspace,
+            namespace: self.namespace.clone(),
+            cluster_info: self.cluster_info.clone(),
+            max_result_bytes: self.max_result_bytes,
+            replica_rpc: self.replica_rpc.clone(),
+            standby: self.standby.clone(),
+            logger: self.logger.clone(),
+        }
+    }
+}
+
 impl Client<PdRpcClient> {
     /// Create a raw [`Client`] and connect to the TiKV cluster.
     ///
@@ -98,524 +167,3717 @@ impl Client<PdRpcClient> {
         let pd_endpoints: Vec<String> = pd_endpoints.into_iter().map(Into::into).collect();
         let rpc =
             Arc::new(PdRpcClient::connect(&pd_endpoints, config, false, logger.clone()).await?);
-        Ok(Client {
+        let mut cluster_info = ClusterInfo::fetch(rpc.clone(), &logger).await;
+        let mut client = Client {
             rpc,
-            cf: None,
-            atomic: false,
+            options: RawOptions::default(),
+            value_codec: None,
+            keyspace: None,
+            namespace: None,
+            cluster_info: Arc::new(cluster_info.clone()),
+            max_result_bytes: None,
+            replica_rpc: None,
+            standby: None,
             logger,
-        })
+        };
+        match client.detect_api_version().await {
+            Ok(api_version) => {
+                cluster_info.api_version = Some(api_version);
+                client.cluster_info = Arc::new(cluster_info);
+            }
+            Err(e) => warn!(client.logger, "failed to detect the API version: {}", e),
+        }
+        match client.rpc.keyspace() {
+            Some(keyspace) => client.in_keyspace(keyspace),
+            None => Ok(client),
+        }
     }
 
-    /// Create a new client which is a clone of `self`, but which uses an explicit column family for
-    /// all requests.
-    ///
-    /// This function returns a new `Client`; requests created with the new client will use the
-    /// supplied column family. The original `Client` can still be used (without the new
-    /// column family).
-    ///
-    /// By default, raw clients use the `Default` column family.
+    /// Create a raw [`Client`] which shares the connections and the region cache of `cluster`
+    /// with all other clients created from it.
     ///
     /// # Examples
     ///
     /// ```rust,no_run
-    /// # use tikv_client::{Config, RawClient, ColumnFamily};
+    /// # use tikv_client::{Cluster, Config, RawClient};
     /// # use futures::prelude::*;
-    /// # use std::convert::TryInto;
     /// # futures::executor::block_on(async {
-    /// let client = RawClient::new(vec!["192.168.0.100"], None)
+    /// let cluster = Cluster::connect(vec!["192.168.0.100"], Config::default(), None)
     ///     .await
-    ///     .unwrap()
-    ///     .with_cf(ColumnFamily::Write);
-    /// // Fetch a value at "foo" from the Write CF.
-    /// let get_request = client.get("foo".to_owned());
+    ///     .unwrap();
+    /// let client = RawClient::new_with_cluster(&cluster);
     /// # });
     /// ```
-    pub fn with_cf(&self, cf: ColumnFamily) -> Self {
-        Client {
-            rpc: self.rpc.clone(),
-            cf: Some(cf),
-            atomic: self.atomic,
-            logger: self.logger.clone(),
+    pub fn new_with_cluster(cluster: &Cluster) -> Self {
+        let client = Client {
+            rpc: cluster.pd_client(false),
+            options: RawOptions::default(),
+            value_codec: None,
+            keyspace: None,
+            namespace: None,
+            cluster_info: cluster.shared_info(),
+            max_result_bytes: None,
+            replica_rpc: None,
+            standby: None,
+            logger: cluster.logger().clone(),
+        };
+        match client.rpc.keyspace() {
+            Some(keyspace) => client.with_api(Some(keyspace)),
+            None => client,
         }
     }
 
-    /// Set to use the atomic mode.
+    /// Create a new client which is a clone of `self`, but which transforms values with `codec`.
     ///
-    /// The only reason of using atomic mode is the
-    /// [`compare_and_swap`](Client::compare_and_swap) operation. To guarantee
-    /// the atomicity of CAS, write operations like [`put`](Client::put) or
-    /// [`delete`](Client::delete) in atomic mode are more expensive. Some
-    /// operations are not supported in the mode.
-    pub fn with_atomic_for_cas(&self) -> Self {
+    /// Values are encoded before being written and decoded after being read, e.g., to compress
+    /// large values with [`Compression`](crate::value_codec::Compression). Keys are not affected.
+    /// Several codecs are combined with [`ValueCodec::then`].
+    /// All values accessed through the new client must have been written with the same codec.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # use tikv_client::{value_codec::Compression, RawClient};
+    /// # use futures::prelude::*;
+    /// # futures::executor::block_on(async {
+    /// let client = RawClient::new(vec!["192.168.0.100"], None)
+    ///     .await
+    ///     .unwrap()
+    ///     .with_value_codec(Compression::uncompressed());
+    /// # });
+    /// ```
+    pub fn with_value_codec(&self, codec: impl ValueCodec) -> Self {
         Client {
             rpc: self.rpc.clone(),
-            cf: self.cf.clone(),
-            atomic: true,
+            options: self.options.clone(),
+            value_codec: Some(Arc::new(codec)),
+            keyspace: self.keyspace,
+            namespace: self.namespace.clone(),
+            cluster_info: self.cluster_info.clone(),
+            max_result_bytes: self.max_result_bytes,
+            replica_rpc: self.replica_rpc.clone(),
+            standby: self.standby.clone(),
             logger: self.logger.clone(),
         }
     }
-}
 
-impl<PdC: PdClient> Client<PdC> {
-    /// Create a new 'get' request.
+    /// Create a new client which is a clone of `self`, but whose keys are in the keyspace
+    /// `keyspace_id` of a cluster running API V2.
     ///
-    /// Once resolved this request will result in the fetching of the value associated with the
-    /// given key.
+    /// Keys are prefixed with the keyspace before they are sent to TiKV and the prefix is removed
+    /// from keys returned by TiKV, so the keyspace is transparent to the user. Requests of the new
+    /// client use API V2, which supports TTLs but only the `Default` column family. The
+    /// [`coprocessor`](Client::coprocessor) is not supported in keyspaces.
     ///
-    /// Retuning `Ok(None)` indicates the key does not exist in TiKV.
+    /// Returns an error if `keyspace_id` does not fit in three bytes, or if the
+    /// [cluster](Client::cluster_info) is known not to run API V2. To put the keys of clients in a
+    /// keyspace by name, see [`Config::with_keyspace`](crate::Config::with_keyspace).
     ///
     /// # Examples
+    ///
     /// ```rust,no_run
-    /// # use tikv_client::{Value, Config, RawClient};
+    /// # use tikv_client::RawClient;
     /// # use futures::prelude::*;
     /// # futures::executor::block_on(async {
-    /// # let client = RawClient::new(vec!["192.168.0.100"], None).await.unwrap();
-    /// let key = "TiKV".to_owned();
-    /// let req = client.get(key);
-    /// let result: Option<Value> = req.await.unwrap();
+    /// let client = RawClient::new(vec!["192.168.0.100"], None)
+    ///     .await
+    ///     .unwrap()
+    ///     .with_keyspace(1)
+    ///     .unwrap();
+    /// client.put_with_ttl("key".to_owned(), "value".to_owned(), 60).await.unwrap();
     /// # });
     /// ```
-    pub async fn get(&self, key: impl Into<Key>) -> Result<Option<Value>> {
-        debug!(self.logger, "invoking raw get request");
-        let request = new_raw_get_request(key.into(), self.cf.clone());
-        let plan = crate::request::PlanBuilder::new(self.rpc.clone(), request)
-            .retry_multi_region(DEFAULT_REGION_BACKOFF)
-            .merge(CollectSingle)
-            .post_process_default()
-            .plan();
-        plan.execute().await
+    pub fn with_keyspace(&self, keyspace_id: u32) -> Result<Self> {
+        self.in_keyspace(Keyspace::new(keyspace_id)?)
     }
 
-    /// Create a new 'batch get' request.
+    /// A clone of `self` in `keyspace`, see [`with_keyspace`](Client::with_keyspace).
+    fn in_keyspace(&self, keyspace: Keyspace) -> Result<Self> {
+        match self.cluster_info.api_version {
+            Some(api_version) if !api_version.supports_keyspaces() => {
+                Err(Error::ApiVersionNotMatched {
+                    message: format!("keyspaces are not supported by {:?}", api_version),
+                })
+            }
+            _ => Ok(self.with_api(Some(keyspace))),
+        }
+    }
+
+    /// Create a new client which is a clone of `self`, but whose reads are replica reads.
     ///
-    /// Once resolved this request will result in the fetching of the values associated with the
-    /// given keys.
+    /// Gets, batch gets, scans and TTL lookups are served by followers or learners instead of the
+    /// leader, chosen like the replicas of stale reads: see
+    /// [`Config::with_zone`](crate::Config::with_zone) and
+    /// [`Config::with_replica_selection`](crate::Config::with_replica_selection). A replica serves
+    /// a read once it has caught up with the leader, so replica reads see the latest writes, at
+    /// the cost of a round trip between the replica and the leader. Writes are still sent to the
+    /// leader.
     ///
-    /// Non-existent entries will not appear in the result. The order of the keys is not retained in the result.
+    /// Replica reads spread read-heavy workloads over all replicas, and keep reads within the
+    /// zone of the client. If the [cluster](Client::cluster_info) is known not to support them,
+    /// reads are served by the leader as before.
     ///
     /// # Examples
+    ///
     /// ```rust,no_run
-    /// # use tikv_client::{KvPair, Config, RawClient};
+    /// # use tikv_client::{Config, RawClient};
     /// # use futures::prelude::*;
     /// # futures::executor::block_on(async {
-    /// # let client = RawClient::new(vec!["192.168.0.100"], None).await.unwrap();
-    /// let keys = vec!["TiKV".to_owned(), "TiDB".to_owned()];
-    /// let req = client.batch_get(keys);
-    /// let result: Vec<KvPair> = req.await.unwrap();
+    /// let config = Config::default().with_zone("us-east-1a");
+    /// let client = RawClient::new_with_config(vec!["192.168.0.100"], config, None)
+    ///     .await
+    ///     .unwrap()
+    ///     .with_replica_read();
+    /// let value = client.get("TiKV".to_owned()).await.unwrap();
     /// # });
     /// ```
-    pub async fn batch_get(
-        &self,
-        keys: impl IntoIterator<Item = impl Into<Key>>,
-    ) -> Result<Vec<KvPair>> {
-        debug!(self.logger, "invoking raw batch_get request");
-        let request = new_raw_batch_get_request(keys.into_iter().map(Into::into), self.cf.clone());
-        let plan = crate::request::PlanBuilder::new(self.rpc.clone(), request)
-            .retry_multi_region(DEFAULT_REGION_BACKOFF)
-            .merge(Collect)
-            .plan();
-        plan.execute()
-            .await
-            .map(|r| r.into_iter().map(Into::into).collect())
+    pub fn with_replica_read(&self) -> Self {
+        if !self.cluster_info.supports_raw_replica_read() {
+            info!(
+                self.logger,
+                "replica reads are not supported by the cluster, reading from leaders"
+            );
+            return self.clone();
+        }
+        Client {
+            replica_rpc: Some(Arc::new(self.rpc.with_replica_read())),
+            ..self.clone()
+        }
     }
 
-    /// Create a new 'put' request.
+    /// The versions and features of the cluster, fetched when the client connected.
     ///
-    /// Once resolved this request will result in the setting of the value associated with the given key.
+    /// Clients created with [`new`](Client::new) or [`new_with_config`](Client::new_with_config)
+    /// also detect the [API version](Client::detect_api_version) of the cluster.
     ///
     /// # Examples
+    ///
     /// ```rust,no_run
-    /// # use tikv_client::{Key, Value, Config, RawClient};
+    /// # use tikv_client::RawClient;
     /// # use futures::prelude::*;
     /// # futures::executor::block_on(async {
-    /// # let client = RawClient::new(vec!["192.168.0.100"], None).await.unwrap();
-    /// let key = "TiKV".to_owned();
-    /// let val = "TiKV".to_owned();
-    /// let req = client.put(key, val);
-    /// let result: () = req.await.unwrap();
+    /// let client = RawClient::new(vec!["192.168.0.100"], None).await.unwrap();
+    /// let supports_ttl = client
+    ///     .cluster_info()
+    ///     .api_version
+    ///     .is_some_and(|v| v.supports_ttl());
     /// # });
     /// ```
-    pub async fn put(&self, key: impl Into<Key>, value: impl Into<Value>) -> Result<()> {
-        debug!(self.logger, "invoking raw put request");
-        let request = new_raw_put_request(key.into(), value.into(), self.cf.clone(), self.atomic);
-        let plan = crate::request::PlanBuilder::new(self.rpc.clone(), request)
-            .retry_multi_region(DEFAULT_REGION_BACKOFF)
-            .merge(CollectSingle)
-            .extract_error()
-            .plan();
-        plan.execute().await?;
-        Ok(())
+    pub fn cluster_info(&self) -> &ClusterInfo {
+        &self.cluster_info
     }
 
-    /// Create a new 'batch put' request.
+    /// A clone of `self` using API V2 in `keyspace` if it is set, and API V1 otherwise.
+    fn with_api(&self, keyspace: Option<Keyspace>) -> Self {
+        let api_version = if keyspace.is_some() {
+            kvrpcpb::ApiVersion::V2
+        } else {
+            kvrpcpb::ApiVersion::V1
+        };
+        Client {
+            rpc: Arc::new(self.rpc.with_api_version(api_version)),
+            options: self.options.clone(),
+            value_codec: self.value_codec.clone(),
+            keyspace,
+            namespace: self.namespace.clone(),
+            cluster_info: self.cluster_info.clone(),
+            max_result_bytes: self.max_result_bytes,
+            replica_rpc: self
+                .replica_rpc
+                .as_ref()
+                .map(|rpc| Arc::new(rpc.with_api_version(api_version))),
+            standby: self.standby.clone(),
+            logger: self.logger.clone(),
+        }
+    }
+
+    /// Detect what the cluster supports for raw requests.
     ///
-    /// Once resolved this request will result in the setting of the values associated with the given keys.
+    /// TiKV does not report its API version, so the client sends probing requests: a request in
+    /// a keyspace, which only succeeds under API V2, and then a TTL lookup, which only succeeds if
+    /// TTL is enabled.
     ///
     /// # Examples
+    ///
     /// ```rust,no_run
-    /// # use tikv_client::{Result, KvPair, Key, Value, Config, RawClient, IntoOwnedRange};
+    /// # use tikv_client::{ApiVersion, RawClient};
     /// # use futures::prelude::*;
     /// # futures::executor::block_on(async {
-    /// # let client = RawClient::new(vec!["192.168.0.100"], None).await.unwrap();
-    /// let kvpair1 = ("PD".to_owned(), "Go".to_owned());
-    /// let kvpair2 = ("TiKV".to_owned(), "Rust".to_owned());
-    /// let iterable = vec![kvpair1, kvpair2];
-    /// let req = client.batch_put(iterable);
-    /// let result: () = req.await.unwrap();
+    /// let client = RawClient::new(vec!["192.168.0.100"], None).await.unwrap();
+    /// let client = match client.detect_api_version().await.unwrap() {
+    ///     ApiVersion::V2 => client.with_keyspace(1).unwrap(),
+    ///     _ => client,
+    /// };
     /// # });
     /// ```
-    pub async fn batch_put(
-        &self,
-        pairs: impl IntoIterator<Item = impl Into<KvPair>>,
-    ) -> Result<()> {
-        debug!(self.logger, "invoking raw batch_put request");
-        let request = new_raw_batch_put_request(
-            pairs.into_iter().map(Into::into),
-            self.cf.clone(),
-            self.atomic,
-        );
-        let plan = crate::request::PlanBuilder::new(self.rpc.clone(), request)
-            .retry_multi_region(DEFAULT_REGION_BACKOFF)
-            .extract_error()
-            .plan();
-        plan.execute().await?;
-        Ok(())
+    pub async fn detect_api_version(&self) -> Result<ApiVersion> {
+        debug!(self.logger, "detecting API version");
+        let v2 = Client {
+            options: RawOptions::default(),
+            ..self.with_api(Some(Keyspace::new(0)?))
+        };
+        match v2.get_key_ttl_secs(PROBE_KEY.to_vec()).await {
+            Ok(_) => return Ok(ApiVersion::V2),
+            Err(e) if has_error(&e, &|e| matches!(e, Error::ApiVersionNotMatched { .. })) => {}
+            Err(e) => return Err(e),
+        }
+        let v1 = Client {
+            options: RawOptions::default(),
+            ..self.with_api(None)
+        };
+        match v1.get_key_ttl_secs(PROBE_KEY.to_vec()).await {
+            Ok(_) => Ok(ApiVersion::V1Ttl),
+            Err(e) if has_error(&e, &|e| matches!(e, Error::TtlNotEnabled { .. })) => {
+                Ok(ApiVersion::V1)
+            }
+            Err(e) => Err(e),
+        }
     }
+}
 
-    /// Create a new 'delete' request.
-    ///
-    /// Once resolved this request will result in the deletion of the given key.
-    ///
-    /// It does not return an error if the key does not exist in TiKV.
+impl<PdC: PdClient> Client<PdC> {
+    /// A client sending its requests through `rpc`, with the default options.
+    #[cfg(any(test, feature = "mock"))]
+    pub(crate) fn new_with_rpc(rpc: Arc<PdC>, logger: Logger) -> Client<PdC> {
+        Client {
+            rpc,
+            options: RawOptions::default(),
+            value_codec: None,
+            keyspace: None,
+            namespace: None,
+            cluster_info: Arc::new(ClusterInfo::default()),
+            max_result_bytes: None,
+            replica_rpc: None,
+            standby: None,
+            logger,
+        }
+    }
+
+    /// How loaded the cluster appears from the responses to the requests of the client and its
+    /// clones, see [`ClusterPressure`].
     ///
     /// # Examples
+    ///
     /// ```rust,no_run
-    /// # use tikv_client::{Key, Config, RawClient};
-    /// # use futures::prelude::*;
+    /// # use tikv_client::RawClient;
     /// # futures::executor::block_on(async {
-    /// # let client = RawClient::new(vec!["192.168.0.100"], None).await.unwrap();
-    /// let key = "TiKV".to_owned();
-    /// let req = client.delete(key);
-    /// let result: () = req.await.unwrap();
+    /// let client = RawClient::new(vec!["192.168.0.100"], None).await.unwrap();
+    /// if client.cluster_pressure().busy_ratio() > 0.1 {
+    ///     // shed load
+    /// }
     /// # });
     /// ```
-    pub async fn delete(&self, key: impl Into<Key>) -> Result<()> {
-        debug!(self.logger, "invoking raw delete request");
-        let request = new_raw_delete_request(key.into(), self.cf.clone(), self.atomic);
-        let plan = crate::request::PlanBuilder::new(self.rpc.clone(), request)
-            .retry_multi_region(DEFAULT_REGION_BACKOFF)
-            .merge(CollectSingle)
-            .extract_error()
-            .plan();
-        plan.execute().await?;
-        Ok(())
+    pub fn cluster_pressure(&self) -> ClusterPressure {
+        self.rpc
+            .pressure()
+            .map(PressureTracker::pressure)
+            .unwrap_or_default()
     }
 
-    /// Create a new 'batch delete' request.
-    ///
-    /// Once resolved this request will result in the deletion of the given keys.
-    ///
-    /// It does not return an error if some of the keys do not exist and will delete the others.
+    /// The statistics of the connections of the client and its clones to the stores, ordered by
+    /// address, see [`ConnectionStats`].
     ///
     /// # Examples
+    ///
     /// ```rust,no_run
-    /// # use tikv_client::{Config, RawClient};
-    /// # use futures::prelude::*;
+    /// # use tikv_client::RawClient;
     /// # futures::executor::block_on(async {
-    /// # let client = RawClient::new(vec!["192.168.0.100"], None).await.unwrap();
-    /// let keys = vec!["TiKV".to_owned(), "TiDB".to_owned()];
-    /// let req = client.batch_delete(keys);
-    /// let result: () = req.await.unwrap();
+    /// let client = RawClient::new(vec!["192.168.0.100"], None).await.unwrap();
+    /// for stats in client.connection_stats().await {
+    ///     println!("{}: {:?} waiting per request", stats.address, stats.average_queue_wait());
+    /// }
     /// # });
     /// ```
-    pub async fn batch_delete(&self, keys: impl IntoIterator<Item = impl Into<Key>>) -> Result<()> {
-        debug!(self.logger, "invoking raw batch_delete request");
-        self.assert_non_atomic()?;
-        let request =
-            new_raw_batch_delete_request(keys.into_iter().map(Into::into), self.cf.clone());
-        let plan = crate::request::PlanBuilder::new(self.rpc.clone(), request)
-            .retry_multi_region(DEFAULT_REGION_BACKOFF)
-            .extract_error()
-            .plan();
-        plan.execute().await?;
-        Ok(())
+    pub async fn connection_stats(&self) -> Vec<ConnectionStats> {
+        self.rpc.connection_stats().await
     }
 
-    /// Create a new 'delete range' request.
+    /// Check that the client can write, read and delete keys, e.g. to attach the report to an
+    /// issue.
     ///
-    /// Once resolved this request will result in the deletion of all keys lying in the given range.
+    /// Sentinel keys starting with `tikv_client_self_check/`, spread over several regions if the
+    /// namespace is split, are located, written, read back, deleted and read again, and the
+    /// latency and the error of each step are reported. A failed step doesn't stop the check, so
+    /// that the keys are deleted in any case.
     ///
     /// # Examples
+    ///
     /// ```rust,no_run
-    /// # use tikv_client::{Key, Config, RawClient, IntoOwnedRange};
-    /// # use futures::prelude::*;
+    /// # use tikv_client::RawClient;
     /// # futures::executor::block_on(async {
-    /// # let client = RawClient::new(vec!["192.168.0.100"], None).await.unwrap();
-    /// let inclusive_range = "TiKV"..="TiDB";
-    /// let req = client.delete_range(inclusive_range.into_owned());
-    /// let result: () = req.await.unwrap();
+    /// let client = RawClient::new(vec!["192.168.0.100"], None).await.unwrap();
+    /// let report = client.self_check().await;
+    /// if !report.is_ok() {
+    ///     eprintln!("{}", report);
+    /// }
     /// # });
     /// ```
-    pub async fn delete_range(&self, range: impl Into<BoundRange>) -> Result<()> {
-        debug!(self.logger, "invoking raw delete_range request");
-        self.assert_non_atomic()?;
-        let request = new_raw_delete_range_request(range.into(), self.cf.clone());
-        let plan = crate::request::PlanBuilder::new(self.rpc.clone(), request)
-            .retry_multi_region(DEFAULT_REGION_BACKOFF)
-            .extract_error()
-            .plan();
-        plan.execute().await?;
-        Ok(())
+    pub async fn self_check(&self) -> SelfCheckReport {
+        debug!(self.logger, "invoking raw self_check");
+        let value = rand::thread_rng().gen::<u64>().to_be_bytes().to_vec();
+        let mut report = SelfCheckReport::default();
+        for suffix in SELF_CHECK_SUFFIXES.iter().copied() {
+            let key = Key::from([SELF_CHECK_PREFIX, &[suffix]].concat());
+            let encoded_key = self.encode_key(key.clone());
+            let locate = self
+                .rpc
+                .region_for_key(&encoded_key)
+                .map_ok(|region| region.id());
+            let (mut step, region_id) = SelfCheckStep::run("locate", &key, None, locate).await;
+            step.region_id = region_id;
+            report.steps.push(step);
+
+            let put = self.put(key.clone(), value.clone());
+            let get = self.get(key.clone()).map(|result| match result? {
+                Some(read) if read == value => Ok(()),
+                Some(_) => Err(Error::StringError("read a different value".to_owned())),
+                None => Err(Error::StringError("the key was not found".to_owned())),
+            });
+            let delete = self.delete(key.clone());
+            let get_deleted = self.get(key.clone()).map(|result| match result? {
+                Some(_) => Err(Error::StringError("the deleted key was found".to_owned())),
+                None => Ok(()),
+            });
+            report.steps.extend(vec![
+                SelfCheckStep::run("put", &key, region_id, put).await.0,
+                SelfCheckStep::run("get", &key, region_id, get).await.0,
+                SelfCheckStep::run("delete", &key, region_id, delete)
+                    .await
+                    .0,
+                SelfCheckStep::run("get_deleted", &key, region_id, get_deleted)
+                    .await
+                    .0,
+            ]);
+        }
+        report
     }
 
-    /// Create a new 'scan' request.
-    ///
-    /// Once resolved this request will result in a `Vec` of key-value pairs that lies in the specified range.
-    ///
-    /// If the number of eligible key-value pairs are greater than `limit`,
-    /// only the first `limit` pairs are returned, ordered by the key.
+    /// Create a new client which is a clone of `self`, but which limits the size of the results
+    /// of scans and batch gets to `max_bytes`.
     ///
+    /// The size of a result is the total size of its keys and values as received from TiKV. Scans
+    /// and batch gets stop fetching regions as soon as the limit is exceeded, and a batch get
+    /// fetches one region after the other instead of all of them concurrently. A request whose result
+    /// exceeds the limit fails with [`ResultTooLarge`](Error::ResultTooLarge), except for
+    /// [`scan_from`](Client::scan_from), which returns the pairs that fit. This protects a
+    /// service from accidentally loading huge results into memory.
     ///
     /// # Examples
+    ///
     /// ```rust,no_run
-    /// # use tikv_client::{KvPair, Config, RawClient, IntoOwnedRange};
+    /// # use tikv_client::{Error, RawClient};
     /// # use futures::prelude::*;
     /// # futures::executor::block_on(async {
-    /// # let client = RawClient::new(vec!["192.168.0.100"], None).await.unwrap();
-    /// let inclusive_range = "TiKV"..="TiDB";
-    /// let req = client.scan(inclusive_range.into_owned(), 2);
-    /// let result: Vec<KvPair> = req.await.unwrap();
+    /// let client = RawClient::new(vec!["192.168.0.100"], None)
+    ///     .await
+    ///     .unwrap()
+    ///     .with_max_result_bytes(64 * 1024 * 1024);
+    /// match client.scan("TiDB".to_owned().."TiKV".to_owned(), 10240).await {
+    ///     Ok(pairs) => { /* Process the pairs... */ }
+    ///     Err(Error::ResultTooLarge { resume_key, .. }) => {
+    ///         // Split the scan at `resume_key`...
+    ///     }
+    ///     Err(e) => panic!("{}", e),
+    /// }
     /// # });
     /// ```
-    pub async fn scan(&self, range: impl Into<BoundRange>, limit: u32) -> Result<Vec<KvPair>> {
-        debug!(self.logger, "invoking raw scan request");
-        self.scan_inner(range.into(), limit, false).await
+    pub fn with_max_result_bytes(&self, max_bytes: usize) -> Self {
+        Client {
+            max_result_bytes: Some(max_bytes),
+            ..self.clone()
+        }
     }
 
-    /// Create a new 'scan' request that only returns the keys.
-    ///
-    /// Once resolved this request will result in a `Vec` of keys that lies in the specified range.
+    /// Create a new client which is a clone of `self`, but which uses an explicit column family for
+    /// all requests.
     ///
-    /// If the number of eligible keys are greater than `limit`,
-    /// only the first `limit` pairs are returned, ordered by the key.
+    /// This function returns a new `Client`; requests created with the new client will use the
+    /// supplied column family. The original `Client` can still be used (without the new
+    /// column family).
     ///
+    /// By default, raw clients use the `Default` column family.
     ///
     /// # Examples
+    ///
     /// ```rust,no_run
-    /// # use tikv_client::{Key, Config, RawClient, IntoOwnedRange};
+    /// # use tikv_client::{Config, RawClient, ColumnFamily};
     /// # use futures::prelude::*;
+    /// # use std::convert::TryInto;
     /// # futures::executor::block_on(async {
-    /// # let client = RawClient::new(vec!["192.168.0.100"], None).await.unwrap();
-    /// let inclusive_range = "TiKV"..="TiDB";
-    /// let req = client.scan_keys(inclusive_range.into_owned(), 2);
-    /// let result: Vec<Key> = req.await.unwrap();
+    /// let client = RawClient::new(vec!["192.168.0.100"], None)
+    ///     .await
+    ///     .unwrap()
+    ///     .with_cf(ColumnFamily::Write);
+    /// // Fetch a value at "foo" from the Write CF.
+    /// let get_request = client.get("foo".to_owned());
     /// # });
     /// ```
-    pub async fn scan_keys(&self, range: impl Into<BoundRange>, limit: u32) -> Result<Vec<Key>> {
-        debug!(self.logger, "invoking raw scan_keys request");
-        Ok(self
-            .scan_inner(range, limit, true)
-            .await?
-            .into_iter()
-            .map(KvPair::into_key)
-            .collect())
+    pub fn with_cf(&self, cf: ColumnFamily) -> Self {
+        self.with_options(self.options.clone().cf(cf))
     }
 
-    /// Create a new 'batch scan' request.
+    /// Set to use the atomic mode.
     ///
-    /// Once resolved this request will result in a set of scanners over the given keys.
+    /// The only reason of using atomic mode is the
+    /// [`compare_and_swap`](Client::compare_and_swap) operation. To guarantee
+    /// the atomicity of CAS, write operations like [`put`](Client::put) or
+    /// [`delete`](Client::delete) in atomic mode are more expensive. Some
+    /// operations are not supported in the mode.
+    pub fn with_atomic_for_cas(&self) -> Self {
+        self.with_options(self.options.clone().atomic(true))
+    }
+
+    /// Create a new client which is a clone of `self`, but which retries requests with
+    /// `retry_options`. Raw requests don't lock keys, so only the
+    /// [`region_backoff`](RetryOptions::region_backoff) applies, e.g. to the retries while the
+    /// regions are not ready or their leaders move during a rolling restart.
     ///
-    /// **Warning**: This method is experimental. The `each_limit` parameter does not work as expected.
-    /// It does not limit the number of results returned of each range,
-    /// instead it limits the number of results in each region of each range.
-    /// As a result, you may get **more than** `each_limit` key-value pairs for each range.
-    /// But you should not miss any entries.
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # use tikv_client::{Backoff, RawClient, RetryOptions};
+    /// # use futures::prelude::*;
+    /// # futures::executor::block_on(async {
+    /// # let client = RawClient::new(vec!["192.168.0.100"], None).await.unwrap();
+    /// let backoff = Backoff::decorrelated_jitter_backoff(10, 2000, 20);
+    /// let client = client.with_retry_options(RetryOptions::new(backoff, Backoff::no_backoff()));
+    /// # });
+    /// ```
+    pub fn with_retry_options(&self, retry_options: RetryOptions) -> Self {
+        self.with_options(self.options.clone().retry_options(retry_options))
+    }
+
+    /// Create a new client which is a clone of `self`, but whose requests fail with
+    /// [`DeadlineExceeded`](Error::DeadlineExceeded) if they don't complete within `timeout`,
+    /// including their retries, see [`RawOptions::deadline`].
     ///
     /// # Examples
     /// ```rust,no_run
-    /// # use tikv_client::{Key, Config, RawClient, IntoOwnedRange};
+    /// # use tikv_client::RawClient;
     /// # use futures::prelude::*;
+    /// # use std::time::Duration;
     /// # futures::executor::block_on(async {
     /// # let client = RawClient::new(vec!["192.168.0.100"], None).await.unwrap();
-    /// let inclusive_range1 = "TiDB"..="TiKV";
-    /// let inclusive_range2 = "TiKV"..="TiSpark";
-    /// let iterable = vec![inclusive_range1.into_owned(), inclusive_range2.into_owned()];
-    /// let req = client.batch_scan(iterable, 2);
-    /// let result = req.await;
+    /// let client = client.with_deadline(Duration::from_secs(1));
+    /// let value = client.get("key".to_owned()).await;
     /// # });
     /// ```
-    pub async fn batch_scan(
-        &self,
-        ranges: impl IntoIterator<Item = impl Into<BoundRange>>,
-        each_limit: u32,
-    ) -> Result<Vec<KvPair>> {
-        debug!(self.logger, "invoking raw batch_scan request");
-        self.batch_scan_inner(ranges, each_limit, false).await
+    pub fn with_deadline(&self, timeout: Duration) -> Self {
+        self.with_options(self.options.clone().deadline(timeout))
     }
 
-    /// Create a new 'batch scan' request that only returns the keys.
+    /// Create a new client which is a clone of `self`, but which sends requests with `options`.
     ///
-    /// Once resolved this request will result in a set of scanners over the given keys.
+    /// Creating a client is cheap, so options can be set for a single call, like
+    /// [`Transaction::set_read_options`](crate::Transaction::set_read_options) does for
+    /// transactional reads. The options replace the column family and atomic mode set with
+    /// [`with_cf`](Client::with_cf) and [`with_atomic_for_cas`](Client::with_atomic_for_cas).
     ///
-    /// **Warning**: This method is experimental.
-    /// The `each_limit` parameter does not limit the number of results returned of each range,
-    /// instead it limits the number of results in each region of each range.
-    /// As a result, you may get **more than** `each_limit` key-value pairs for each range,
-    /// but you should not miss any entries.
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # use tikv_client::{CommandPriority, RawClient, RawOptions};
+    /// # use futures::prelude::*;
+    /// # futures::executor::block_on(async {
+    /// # let client = RawClient::new(vec!["192.168.0.100"], None).await.unwrap();
+    /// let options = RawOptions::new().priority(CommandPriority::High);
+    /// let value = client.with_options(options).get("TiKV".to_owned()).await.unwrap();
+    /// # });
+    /// ```
+    pub fn with_options(&self, options: RawOptions) -> Self {
+        Client {
+            options,
+            ..self.clone()
+        }
+    }
+
+    /// Create a new client which is a clone of `self`, but whose keys are in the namespace
+    /// `name`, e.g. to share a cluster between components without their keys colliding.
+    ///
+    /// Keys are prefixed with `name/` before they are sent to TiKV and the prefix is removed from
+    /// keys returned by TiKV. Ranges are restricted to the namespace, so scans and range deletions
+    /// of the new client, e.g. `delete_range(..)`, never reach the keys of other namespaces.
+    /// Namespaces nest: the namespace of a namespaced client is created in its namespace. The
+    /// [`coprocessor`](Client::coprocessor) is not supported in namespaces.
     ///
     /// # Examples
+    ///
     /// ```rust,no_run
-    /// # use tikv_client::{Key, Config, RawClient, IntoOwnedRange};
+    /// # use tikv_client::RawClient;
+    /// # use futures::prelude::*;
+    /// # futures::executor::block_on(async {
+    /// let client = RawClient::new(vec!["192.168.0.100"], None).await.unwrap();
+    /// let orders = client.namespace("orders");
+    /// // writes the key "orders/42"
+    /// orders.put("42".to_owned(), "pending".to_owned()).await.unwrap();
+    /// // only deletes the keys of the namespace
+    /// orders.delete_range(..).await.unwrap();
+    /// # });
+    /// ```
+    pub fn namespace(&self, name: &str) -> Self {
+        Client {
+            namespace: Some(Namespace::new(self.namespace.as_ref(), name)),
+            ..self.clone()
+        }
+    }
+
+    /// **Experimental.** Create a new client which is a clone of `self`, but whose reads fail
+    /// over to the cluster of `standby` when the cluster of `self` can't be reached, e.g. for
+    /// active/passive disaster recovery where a standby cluster replicates the primary cluster.
+    ///
+    /// Writes are only sent to the primary cluster, and fail if it can't be reached. The reads of
+    /// [`get`](Client::get), [`batch_get`](Client::batch_get),
+    /// [`batch_get_values`](Client::batch_get_values), [`scan`](Client::scan),
+    /// [`scan_keys`](Client::scan_keys) and [`scan_from`](Client::scan_from) which fail because
+    /// PD or TiKV can't be reached or time out are sent again to the standby cluster. Every read
+    /// is sent to the primary cluster first, so reads return to it once it recovers.
+    ///
+    /// Only the cluster of `standby` is used, i.e. its connections and keyspace: the options,
+    /// namespace and value codec of `self` apply to the reads from the standby cluster too.
+    ///
+    /// **Warning:** the standby cluster is usually replicated asynchronously. A read which fails
+    /// over may then return stale values, miss writes acknowledged by the primary cluster,
+    /// including the writes of this client, and see older values than a previous read. Reads
+    /// which fail over because the primary cluster timed out only do so after the timeout.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # use tikv_client::RawClient;
+    /// # use futures::prelude::*;
+    /// # futures::executor::block_on(async {
+    /// let primary = RawClient::new(vec!["192.168.0.100"], None).await.unwrap();
+    /// let standby = RawClient::new(vec!["192.168.1.100"], None).await.unwrap();
+    /// let client = primary.with_standby(&standby);
+    /// let value = client.get("TiKV".to_owned()).await.unwrap();
+    /// # });
+    /// ```
+    pub fn with_standby(&self, standby: &Client<PdC>) -> Self {
+        Client {
+            standby: Some(Arc::new(standby.clone())),
+            ..self.clone()
+        }
+    }
+
+    /// Create a new 'get' request.
+    ///
+    /// Once resolved this request will result in the fetching of the value associated with the
+    /// given key.
+    ///
+    /// Retuning `Ok(None)` indicates the key does not exist in TiKV.
+    ///
+    /// # Examples
+    /// ```rust,no_run
+    /// # use tikv_client::{Value, Config, RawClient};
     /// # use futures::prelude::*;
     /// # futures::executor::block_on(async {
     /// # let client = RawClient::new(vec!["192.168.0.100"], None).await.unwrap();
-    /// let inclusive_range1 = "TiDB"..="TiKV";
-    /// let inclusive_range2 = "TiKV"..="TiSpark";
-    /// let iterable = vec![inclusive_range1.into_owned(), inclusive_range2.into_owned()];
-    /// let req = client.batch_scan(iterable, 2);
-    /// let result = req.await;
+    /// let key = "TiKV".to_owned();
+    /// let req = client.get(key);
+    /// let result: Option<Value> = req.await.unwrap();
     /// # });
     /// ```
-    pub async fn batch_scan_keys(
+    pub async fn get(&self, key: impl Into<Key>) -> Result<Option<Value>> {
+        debug!(self.logger, "invoking raw get request");
+        let key = key.into();
+        match self.get_inner(key.clone()).await {
+            Err(e) => match self.standby_after(&e) {
+                Some(standby) => standby.get_inner(key).await,
+                None => Err(e),
+            },
+            result => result,
+        }
+    }
+
+    async fn get_inner(&self, user_key: Key) -> Result<Option<Value>> {
+        let key = self.encode_key(user_key.clone());
+        let request = new_raw_get_request(key.clone(), self.options.cf.clone());
+        let plan = self
+            .read_plan_builder(request)
+            .retry_multi_region(self.options.region_backoff())
+            .merge(CollectSingle)
+            .post_process_default()
+            .plan();
+        let mut value = plan.execute().await?;
+        if self.sample_checksum() {
+            let ranges = vec![key_range(key.clone())];
+            let pairs = value
+                .map(|value| KvPair::new(key.clone(), value))
+                .into_iter()
+                .collect();
+            let reread = plan.execute().map_ok(|value| {
+                let pair = value.map(|value| KvPair::new(key, value));
+                pair.into_iter().collect::<Vec<_>>()
+            });
+            let pairs = self.verify_checksum(ranges, pairs, reread).await?;
+            value = pairs.into_iter().next().map(KvPair::into_value);
+        }
+        value
+            .map(|value| self.decode_value(&user_key, value))
+            .transpose()
+    }
+
+    /// Create a new 'batch get' request.
+    ///
+    /// Once resolved this request will result in the fetching of the values associated with the
+    /// given keys.
+    ///
+    /// The pairs are in the order of `keys`, with a pair for each key which exists, even if the
+    /// key is given more than once. Use [`batch_get_values`](Client::batch_get_values) to also
+    /// get the keys which do not exist.
+    ///
+    /// The keys of each region are requested at once, or in chunks as set by
+    /// [`RawOptions::batch_get_chunk_size`], and up to 16 requests are sent concurrently, or as
+    /// set by [`RawOptions::batch_get_concurrency`].
+    ///
+    /// # Examples
+    /// ```rust,no_run
+    /// # use tikv_client::{KvPair, Config, RawClient};
+    /// # use futures::prelude::*;
+    /// # futures::executor::block_on(async {
+    /// # let client = RawClient::new(vec!["192.168.0.100"], None).await.unwrap();
+    /// let keys = vec!["TiKV".to_owned(), "TiDB".to_owned()];
+    /// let req = client.batch_get(keys);
+    /// let result: Vec<KvPair> = req.await.unwrap();
+    /// # });
+    /// ```
+    pub async fn batch_get(
         &self,
-        ranges: impl IntoIterator<Item = impl Into<BoundRange>>,
-        each_limit: u32,
-    ) -> Result<Vec<Key>> {
-        debug!(self.logger, "invoking raw batch_scan_keys request");
-        Ok(self
-            .batch_scan_inner(ranges, each_limit, true)
-            .await?
+        keys: impl IntoIterator<Item = impl Into<Key>>,
+    ) -> Result<Vec<KvPair>> {
+        debug!(self.logger, "invoking raw batch_get request");
+        let keys: Vec<Key> = keys.into_iter().map(Into::into).collect();
+        let values = self.batch_get_inner(keys.clone()).await?;
+        Ok(keys
             .into_iter()
-            .map(KvPair::into_key)
+            .zip(values)
+            .filter_map(|(key, value)| Some(KvPair(key, value?)))
             .collect())
     }
 
-    /// Create a new *atomic* 'compare and set' request.
-    ///
-    /// Once resolved this request will result in an atomic `compare and set'
-    /// operation for the given key.
-    ///
-    /// If the value retrived is equal to `current_value`, `new_value` is
-    /// written.
-    ///
-    /// # Return Value
-    ///
-    /// A tuple is returned if successful: the previous value and whether the
-    /// value is swapped
-    pub async fn compare_and_swap(
-        &self,
-        key: impl Into<Key>,
-        previous_value: impl Into<Option<Value>>,
-        new_value: impl Into<Value>,
-    ) -> Result<(Option<Value>, bool)> {
-        debug!(self.logger, "invoking raw compare_and_swap request");
-        self.assert_atomic()?;
-        let req = new_cas_request(
-            key.into(),
-            new_value.into(),
-            previous_value.into(),
-            self.cf.clone(),
+    /// Create a new 'batch get' request which results in the value of each of `keys`, in the
+    /// same order, and `None` for the keys which do not exist.
+    ///
+    /// See [`batch_get`](Client::batch_get) for how the keys are requested.
+    ///
+    /// # Examples
+    /// ```rust,no_run
+    /// # use tikv_client::{Value, Config, RawClient};
+    /// # use futures::prelude::*;
+    /// # futures::executor::block_on(async {
+    /// # let client = RawClient::new(vec!["192.168.0.100"], None).await.unwrap();
+    /// let keys = vec!["TiKV".to_owned(), "TiDB".to_owned()];
+    /// let values: Vec<Option<Value>> = client.batch_get_values(keys).await.unwrap();
+    /// # });
+    /// ```
+    pub async fn batch_get_values(
+        &self,
+        keys: impl IntoIterator<Item = impl Into<Key>>,
+    ) -> Result<Vec<Option<Value>>> {
+        debug!(self.logger, "invoking raw batch_get_values request");
+        self.batch_get_inner(keys.into_iter().map(Into::into).collect())
+            .await
+    }
+
+    /// Get the values of `keys` from each of the column families `cfs`, e.g. to inspect the MVCC
+    /// state of transactional keys in the `Default`, `Lock` and `Write` column families.
+    ///
+    /// The column families are read concurrently, each like by
+    /// [`batch_get_values`](Client::batch_get_values). Returns the values of each column family in
+    /// the order of `cfs`, and the values of each in the order of `keys`, `None` for the keys
+    /// which do not exist in it. The values are decoded by the value codec of the client, if any,
+    /// so the `Lock` and `Write` column families should be read by a client without one.
+    ///
+    /// # Examples
+    /// ```rust,no_run
+    /// # use tikv_client::{ColumnFamily, RawClient};
+    /// # futures::executor::block_on(async {
+    /// # let client = RawClient::new(vec!["192.168.0.100"], None).await.unwrap();
+    /// let cfs = vec![ColumnFamily::Default, ColumnFamily::Lock, ColumnFamily::Write];
+    /// let values = client.batch_get_in_cfs(vec!["TiKV".to_owned()], cfs).await.unwrap();
+    /// for (cf, values) in values {
+    ///     println!("{}: {:?}", cf, values[0]);
+    /// }
+    /// # });
+    /// ```
+    pub async fn batch_get_in_cfs(
+        &self,
+        keys: impl IntoIterator<Item = impl Into<Key>>,
+        cfs: impl IntoIterator<Item = ColumnFamily>,
+    ) -> Result<Vec<(ColumnFamily, Vec<Option<Value>>)>> {
+        debug!(self.logger, "invoking raw batch_get_in_cfs request");
+        let keys: Vec<Key> = keys.into_iter().map(Into::into).collect();
+        future::try_join_all(cfs.into_iter().map(|cf| {
+            let client = self.with_options(self.options.clone().cf(cf.clone()));
+            let keys = keys.clone();
+            async move { Ok((cf, client.batch_get_inner(keys).await?)) }
+        }))
+        .await
+    }
+
+    /// Create a new 'put' request.
+    ///
+    /// Once resolved this request will result in the setting of the value associated with the given key.
+    ///
+    /// # Examples
+    /// ```rust,no_run
+    /// # use tikv_client::{Key, Value, Config, RawClient};
+    /// # use futures::prelude::*;
+    /// # futures::executor::block_on(async {
+    /// # let client = RawClient::new(vec!["192.168.0.100"], None).await.unwrap();
+    /// let key = "TiKV".to_owned();
+    /// let val = "TiKV".to_owned();
+    /// let req = client.put(key, val);
+    /// let result: () = req.await.unwrap();
+    /// # });
+    /// ```
+    pub async fn put(&self, key: impl Into<Key>, value: impl Into<Value>) -> Result<()> {
+        self.put_with_ttl(key, value, 0).await
+    }
+
+    /// Create a new 'put' request with a TTL.
+    ///
+    /// Once resolved this request will result in the setting of the value associated with the
+    /// given key, which expires after `ttl_secs` seconds. A TTL of 0 means the value never expires.
+    /// Returns [`TtlNotEnabled`](Error::TtlNotEnabled) if TTL is not enabled in the cluster.
+    ///
+    /// # Examples
+    /// ```rust,no_run
+    /// # use tikv_client::{Key, Value, Config, RawClient};
+    /// # use futures::prelude::*;
+    /// # futures::executor::block_on(async {
+    /// # let client = RawClient::new(vec!["192.168.0.100"], None).await.unwrap();
+    /// let key = "TiKV".to_owned();
+    /// let val = "TiKV".to_owned();
+    /// let req = client.put_with_ttl(key, val, 60);
+    /// let result: () = req.await.unwrap();
+    /// # });
+    /// ```
+    pub async fn put_with_ttl(
+        &self,
+        key: impl Into<Key>,
+        value: impl Into<Value>,
+        ttl_secs: u64,
+    ) -> Result<()> {
+        debug!(self.logger, "invoking raw put request");
+        let key = key.into();
+        let value = self.encode_value(&key, value.into())?;
+        self.check_write(&key, Some(&value))?;
+        let key = self.encode_key(key);
+        let mut request =
+            new_raw_put_request(key, value, self.options.cf.clone(), self.options.atomic);
+        request.ttl = ttl_secs;
+        let plan = self
+            .plan_builder(request)
+            .retry_multi_region(self.options.region_backoff())
+            .merge(CollectSingle)
+            .extract_error()
+            .plan();
+        plan.execute().await?;
+        Ok(())
+    }
+
+    /// Create a new 'batch put' request.
+    ///
+    /// Once resolved this request will result in the setting of the values associated with the given keys.
+    ///
+    /// # Examples
+    /// ```rust,no_run
+    /// # use tikv_client::{Result, KvPair, Key, Value, Config, RawClient, IntoOwnedRange};
+    /// # use futures::prelude::*;
+    /// # futures::executor::block_on(async {
+    /// # let client = RawClient::new(vec!["192.168.0.100"], None).await.unwrap();
+    /// let kvpair1 = ("PD".to_owned(), "Go".to_owned());
+    /// let kvpair2 = ("TiKV".to_owned(), "Rust".to_owned());
+    /// let iterable = vec![kvpair1, kvpair2];
+    /// let req = client.batch_put(iterable);
+    /// let result: () = req.await.unwrap();
+    /// # });
+    /// ```
+    pub async fn batch_put(
+        &self,
+        pairs: impl IntoIterator<Item = impl Into<KvPair>>,
+    ) -> Result<()> {
+        self.batch_put_with_ttl(pairs, 0).await
+    }
+
+    /// Create a new 'batch put' request with a TTL.
+    ///
+    /// Once resolved this request will result in the setting of the values associated with the
+    /// given keys, which expire after `ttl_secs` seconds. A TTL of 0 means the values never
+    /// expire. Returns [`TtlNotEnabled`](Error::TtlNotEnabled) if TTL is not enabled in the
+    /// cluster.
+    ///
+    /// # Examples
+    /// ```rust,no_run
+    /// # use tikv_client::{Result, KvPair, Key, Value, Config, RawClient, IntoOwnedRange};
+    /// # use futures::prelude::*;
+    /// # futures::executor::block_on(async {
+    /// # let client = RawClient::new(vec!["192.168.0.100"], None).await.unwrap();
+    /// let kvpair1 = ("PD".to_owned(), "Go".to_owned());
+    /// let kvpair2 = ("TiKV".to_owned(), "Rust".to_owned());
+    /// let iterable = vec![kvpair1, kvpair2];
+    /// let req = client.batch_put_with_ttl(iterable, 60);
+    /// let result: () = req.await.unwrap();
+    /// # });
+    /// ```
+    pub async fn batch_put_with_ttl(
+        &self,
+        pairs: impl IntoIterator<Item = impl Into<KvPair>>,
+        ttl_secs: u64,
+    ) -> Result<()> {
+        debug!(self.logger, "invoking raw batch_put request");
+        let pairs = self.encode_pairs(pairs)?;
+        let mut request = new_raw_batch_put_request(
+            pairs.into_iter(),
+            self.options.cf.clone(),
+            self.options.atomic,
+        );
+        if ttl_secs > 0 {
+            // a TTL per pair, which is sharded along with the pairs
+            request.ttls = vec![ttl_secs; request.pairs.len()];
+        }
+        let plan = self
+            .plan_builder(request)
+            .retry_multi_region(self.options.region_backoff())
+            .extract_error()
+            .plan();
+        plan.execute().await?;
+        Ok(())
+    }
+
+    /// Create a new 'batch put' request which reports which keys were written together.
+    ///
+    /// The pairs are written with a single request per region, which is applied atomically: all
+    /// of its keys are written, or none of them. A [`batch_put`](Client::batch_put) spanning
+    /// several regions which fails may thus have written the keys of some regions and not those
+    /// of others. This returns a [`WriteGroup`] for each set of keys written atomically, in the
+    /// order of their keys, with its own result, rather than a single error.
+    ///
+    /// It only fails if the regions of the keys can't be found, before anything is written.
+    ///
+    /// # Examples
+    /// ```rust,no_run
+    /// # use tikv_client::{Config, RawClient};
+    /// # use futures::prelude::*;
+    /// # futures::executor::block_on(async {
+    /// # let client = RawClient::new(vec!["192.168.0.100"], None).await.unwrap();
+    /// let kvpair1 = ("PD".to_owned(), "Go".to_owned());
+    /// let kvpair2 = ("TiKV".to_owned(), "Rust".to_owned());
+    /// for group in client.batch_put_grouped(vec![kvpair1, kvpair2]).await.unwrap() {
+    ///     if let Err(e) = group.result {
+    ///         println!("{:?} were not written: {}", group.keys, e);
+    ///     }
+    /// }
+    /// # });
+    /// ```
+    pub async fn batch_put_grouped(
+        &self,
+        pairs: impl IntoIterator<Item = impl Into<KvPair>>,
+    ) -> Result<Vec<WriteGroup>> {
+        debug!(self.logger, "invoking raw batch_put_grouped request");
+        let mut pairs = self.encode_pairs(pairs)?;
+        pairs.sort_by(|a, b| a.key().cmp(b.key()));
+        let groups: Vec<(RegionId, Vec<KvPair>)> = self
+            .rpc
+            .clone()
+            .group_keys_by_region(pairs.into_iter())
+            .try_collect()
+            .await?;
+
+        let groups = stream::iter(groups).map(|(_, pairs)| async move {
+            let keys: Vec<Key> = pairs.iter().map(|pair| pair.key().clone()).collect();
+            let request = new_raw_batch_put_request(
+                pairs.into_iter(),
+                self.options.cf.clone(),
+                self.options.atomic,
+            );
+            let plan = self
+                .plan_builder(request)
+                .preserve_shard()
+                .retry_multi_region(self.options.region_backoff())
+                .merge(CollectError)
+                .plan();
+            match plan.execute().await {
+                // the region may have split while the request was retried, so that its keys were
+                // written by several requests
+                Ok(responses) => responses
+                    .into_iter()
+                    .map(|ResponseWithShard(_, (pairs, _))| {
+                        let keys = pairs.into_iter().map(|pair| pair.key.into());
+                        self.write_group(keys, Ok(()))
+                    })
+                    .collect::<Result<Vec<_>>>(),
+                Err(e) => Ok(vec![self.write_group(keys.into_iter(), Err(e))?]),
+            }
+        });
+        let groups: Vec<Vec<WriteGroup>> = groups
+            .buffered(MULTI_REGION_CONCURRENCY)
+            .try_collect()
+            .await?;
+        Ok(groups.into_iter().flatten().collect())
+    }
+
+    /// Create a new 'delete' request.
+    ///
+    /// Once resolved this request will result in the deletion of the given key.
+    ///
+    /// It does not return an error if the key does not exist in TiKV.
+    ///
+    /// # Examples
+    /// ```rust,no_run
+    /// # use tikv_client::{Key, Config, RawClient};
+    /// # use futures::prelude::*;
+    /// # futures::executor::block_on(async {
+    /// # let client = RawClient::new(vec!["192.168.0.100"], None).await.unwrap();
+    /// let key = "TiKV".to_owned();
+    /// let req = client.delete(key);
+    /// let result: () = req.await.unwrap();
+    /// # });
+    /// ```
+    pub async fn delete(&self, key: impl Into<Key>) -> Result<()> {
+        debug!(self.logger, "invoking raw delete request");
+        let key = key.into();
+        self.check_write(&key, None)?;
+        let key = self.encode_key(key);
+        let request = new_raw_delete_request(key, self.options.cf.clone(), self.options.atomic);
+        let plan = self
+            .plan_builder(request)
+            .retry_multi_region(self.options.region_backoff())
+            .merge(CollectSingle)
+            .extract_error()
+            .plan();
+        plan.execute().await?;
+        Ok(())
+    }
+
+    /// Create a new 'batch delete' request.
+    ///
+    /// Once resolved this request will result in the deletion of the given keys.
+    ///
+    /// It does not return an error if some of the keys do not exist and will delete the others.
+    ///
+    /// # Examples
+    /// ```rust,no_run
+    /// # use tikv_client::{Config, RawClient};
+    /// # use futures::prelude::*;
+    /// # futures::executor::block_on(async {
+    /// # let client = RawClient::new(vec!["192.168.0.100"], None).await.unwrap();
+    /// let keys = vec!["TiKV".to_owned(), "TiDB".to_owned()];
+    /// let req = client.batch_delete(keys);
+    /// let result: () = req.await.unwrap();
+    /// # });
+    /// ```
+    pub async fn batch_delete(&self, keys: impl IntoIterator<Item = impl Into<Key>>) -> Result<()> {
+        debug!(self.logger, "invoking raw batch_delete request");
+        let keys = keys
+            .into_iter()
+            .map(|key| {
+                let key = key.into();
+                self.check_write(&key, None)?;
+                Ok(self.encode_key(key))
+            })
+            .collect::<Result<Vec<Key>>>()?;
+        let mut request = new_raw_batch_delete_request(keys.into_iter(), self.options.cf.clone());
+        request.set_for_cas(self.options.atomic);
+        let plan = self
+            .plan_builder(request)
+            .retry_multi_region(self.options.region_backoff())
+            .extract_error()
+            .plan();
+        plan.execute().await?;
+        Ok(())
+    }
+
+    /// Apply `mutations` atomically: either all of them are applied, or none of them.
+    ///
+    /// TiKV applies a raw request atomically within a region, but has no atomic writes of raw
+    /// keys across regions, nor of puts and deletes together. If the mutations are all puts or all
+    /// deletes, of keys in a single region, they are sent in a single raw request, which is never
+    /// split: if the region changed, e.g. it split, TiKV rejects the request and it is sent again,
+    /// as long as the keys are still in a single region.
+    ///
+    /// Otherwise, i.e. if the keys span several regions or the mutations mix puts and deletes,
+    /// they are applied by a short optimistic transaction instead, as by a
+    /// [`TransactionClient`](crate::TransactionClient) on the same cluster. Note that TiKV stores
+    /// transactional keys apart from raw keys, and ignores the column family of the options: the
+    /// keys written by the transaction are read by a `TransactionClient` or a
+    /// [`Snapshot`](crate::Snapshot), not by `get` or `scan`, so a batch should consistently take
+    /// one path or the other. The transaction fails with a
+    /// [`WriteConflict`](Error::WriteConflict) if it conflicts with another transaction, and is
+    /// not retried.
+    ///
+    /// # Examples
+    /// ```rust,no_run
+    /// # use tikv_client::{RawClient, RawMutation};
+    /// # use futures::prelude::*;
+    /// # futures::executor::block_on(async {
+    /// # let client = RawClient::new(vec!["192.168.0.100"], None).await.unwrap();
+    /// let mutations = vec![
+    ///     RawMutation::Put(b"account/1".to_vec().into(), b"90".to_vec()),
+    ///     RawMutation::Put(b"account/2".to_vec().into(), b"110".to_vec()),
+    /// ];
+    /// client.atomic_batch(mutations).await.unwrap();
+    /// # });
+    /// ```
+    pub async fn atomic_batch(
+        &self,
+        mutations: impl IntoIterator<Item = RawMutation>,
+    ) -> Result<()> {
+        debug!(self.logger, "invoking raw atomic_batch request");
+        let mutations: Vec<RawMutation> = mutations.into_iter().collect();
+        let mut pairs = Vec::new();
+        let mut deleted = Vec::new();
+        for mutation in &mutations {
+            match mutation {
+                RawMutation::Put(key, value) => pairs.push(KvPair(key.clone(), value.clone())),
+                RawMutation::Delete(key) => {
+                    self.check_write(key, None)?;
+                    deleted.push(self.encode_key(key.clone()));
+                }
+            }
+        }
+        if !pairs.is_empty() && !deleted.is_empty() {
+            return self.atomic_batch_in_transaction(mutations).await;
+        }
+        let pairs = self.encode_pairs(pairs)?;
+        let keys: Vec<&Key> = pairs.iter().map(KvPair::key).chain(&deleted).collect();
+        let first = match keys.iter().min() {
+            Some(first) => (*first).clone(),
+            None => return Ok(()),
+        };
+
+        let mut backoff = self.options.region_backoff();
+        loop {
+            let store = self.rpc.clone().store_for_key(&first).await?;
+            let region = store.region_with_leader.clone();
+            if keys.iter().any(|key| !region.contains(key)) {
+                return self.atomic_batch_in_transaction(mutations).await;
+            }
+            let result = if deleted.is_empty() {
+                let request = new_raw_batch_put_request(
+                    pairs.iter().cloned(),
+                    self.options.cf.clone(),
+                    self.options.atomic,
+                );
+                self.execute_in_region(request, store).await
+            } else {
+                let mut request =
+                    new_raw_batch_delete_request(deleted.iter().cloned(), self.options.cf.clone());
+                request.set_for_cas(self.options.atomic);
+                self.execute_in_region(request, store).await
+            };
+            match result {
+                Err(Error::ExtractedErrors(errors))
+                    if matches!(errors.as_slice(), [Error::RegionError(_)]) =>
+                {
+                    match backoff.next_delay_duration() {
+                        Some(delay) => {
+                            self.rpc.invalidate_region_cache(region.ver_id()).await;
+                            self.rpc.clock().sleep(delay).await;
+                        }
+                        None => return Err(Error::ExtractedErrors(errors)),
+                    }
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Applies `mutations` in an optimistic transaction, see [`atomic_batch`](Client::atomic_batch).
+    async fn atomic_batch_in_transaction(&self, mutations: Vec<RawMutation>) -> Result<()> {
+        debug!(self.logger, "applying raw atomic_batch in a transaction");
+        let timestamp = self.rpc.clone().get_timestamp().await?;
+        let options = TransactionOptions::new_optimistic().drop_check(CheckLevel::None);
+        let logger = self.logger.new(o!("child" => 1));
+        let mut txn = Transaction::new(timestamp, self.rpc.clone(), options, logger);
+        for mutation in mutations {
+            match mutation {
+                RawMutation::Put(key, value) => {
+                    let value = self.encode_value(&key, value)?;
+                    self.check_write(&key, Some(&value))?;
+                    txn.put(self.encode_txn_key(key), value).await?;
+                }
+                RawMutation::Delete(key) => {
+                    self.check_write(&key, None)?;
+                    txn.delete(self.encode_txn_key(key)).await?;
+                }
+            }
+        }
+        match txn.commit().await {
+            Ok(_) => Ok(()),
+            // the transaction may have been committed, it must not be rolled back
+            Err(e @ Error::UndeterminedError(_)) => Err(e),
+            Err(e) => {
+                if let Err(rollback_err) = txn.rollback().await {
+                    debug!(
+                        self.logger,
+                        "failed to roll back atomic_batch: {}", rollback_err
+                    );
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Create a new 'delete range' request.
+    ///
+    /// Once resolved this request will result in the deletion of all keys lying in the given range.
+    ///
+    /// # Examples
+    /// ```rust,no_run
+    /// # use tikv_client::{Key, Config, RawClient, IntoOwnedRange};
+    /// # use futures::prelude::*;
+    /// # futures::executor::block_on(async {
+    /// # let client = RawClient::new(vec!["192.168.0.100"], None).await.unwrap();
+    /// let inclusive_range = "TiKV"..="TiDB";
+    /// let req = client.delete_range(inclusive_range.into_owned());
+    /// let result: () = req.await.unwrap();
+    /// # });
+    /// ```
+    pub async fn delete_range(&self, range: impl Into<BoundRange>) -> Result<()> {
+        debug!(self.logger, "invoking raw delete_range request");
+        self.assert_non_atomic()?;
+        let range = range.into();
+        self.check_range(&range)?;
+        let range = self.encode_range(range);
+        let request = new_raw_delete_range_request(range, self.options.cf.clone());
+        let plan = self
+            .plan_builder(request)
+            .retry_multi_region(self.options.region_backoff())
+            .progress(self.options.progress.clone())
+            .extract_error()
+            .plan();
+        plan.execute().await?;
+        Ok(())
+    }
+
+    /// Delete all keys starting with `prefix`.
+    ///
+    /// Outside of the [atomic mode](Client::with_atomic_for_cas), the keys are deleted with a
+    /// single range deletion like [`delete_range`](Client::delete_range), which does not report
+    /// how many keys it deleted. The atomic mode does not support range deletions, so the keys
+    /// are scanned and deleted in batches instead.
+    ///
+    /// Returns [`EmptyPrefix`](Error::EmptyPrefix) if `prefix` is empty, rather than deleting all
+    /// keys.
+    ///
+    /// # Examples
+    /// ```rust,no_run
+    /// # use tikv_client::{DeleteSummary, RawClient};
+    /// # use futures::prelude::*;
+    /// # futures::executor::block_on(async {
+    /// # let client = RawClient::new(vec!["192.168.0.100"], None).await.unwrap();
+    /// let summary: DeleteSummary = client.delete_prefix("user/42/".to_owned()).await.unwrap();
+    /// println!("deleted keys in {} regions", summary.regions);
+    /// # });
+    /// ```
+    pub async fn delete_prefix(&self, prefix: impl Into<Key>) -> Result<DeleteSummary> {
+        debug!(self.logger, "invoking raw delete_prefix request");
+        let range = BoundRange::non_empty_prefix(prefix)?;
+        self.check_range(&range)?;
+        if !self.options.atomic {
+            let request =
+                new_raw_delete_range_request(self.encode_range(range), self.options.cf.clone());
+            let plan = self
+                .plan_builder(request)
+                .retry_multi_region(self.options.region_backoff())
+                .progress(self.options.progress.clone())
+                .extract_error()
+                .plan();
+            // one response per region
+            let regions = plan.execute().await?.len();
+            return Ok(DeleteSummary {
+                keys: None,
+                regions,
+            });
+        }
+
+        let regions = self
+            .rpc
+            .clone()
+            .stores_for_range(self.encode_range(range.clone()))
+            .try_fold(0, |regions, _| future::ready(Ok(regions + 1)))
+            .await?;
+        let mut keys = 0;
+        loop {
+            // deleted keys are not scanned again
+            let batch = self.scan_keys(range.clone(), MAX_RAW_KV_SCAN_LIMIT).await?;
+            let count = batch.len() as u32;
+            self.batch_delete(batch).await?;
+            keys += count as u64;
+            if count < MAX_RAW_KV_SCAN_LIMIT {
+                return Ok(DeleteSummary {
+                    keys: Some(keys),
+                    regions,
+                });
+            }
+        }
+    }
+
+    /// Split the regions at `split_keys` and scatter the resulting regions across the stores,
+    /// returning their ids, e.g. to pre-split a hot range before writing to it.
+    ///
+    /// Keys which already start a region are skipped. PD moves the peers of the scattered
+    /// regions in the background, so they may still be moving when this returns.
+    ///
+    /// # Examples
+    /// ```rust,no_run
+    /// # use tikv_client::RawClient;
+    /// # use futures::prelude::*;
+    /// # futures::executor::block_on(async {
+    /// # let client = RawClient::new(vec!["192.168.0.100"], None).await.unwrap();
+    /// let split_keys = (1..16u8).map(|i| vec![i << 4]);
+    /// let regions = client.split_region(split_keys).await.unwrap();
+    /// # });
+    /// ```
+    pub async fn split_region(
+        &self,
+        split_keys: impl IntoIterator<Item = impl Into<Key>>,
+    ) -> Result<Vec<RegionId>> {
+        debug!(self.logger, "invoking raw split_region request");
+        let split_keys = split_keys
+            .into_iter()
+            .map(|key| self.encode_key(key.into()));
+        let request = new_split_region_request(split_keys, true);
+        let plan = self
+            .plan_builder(request)
+            .retry_multi_region(self.options.region_backoff())
+            .merge(Collect)
+            .plan();
+        let region_ids = plan.execute().await?;
+        self.rpc.clone().scatter_regions(region_ids.clone()).await?;
+        Ok(region_ids)
+    }
+
+    /// Create a new 'scan' request.
+    ///
+    /// Once resolved this request will result in a `Vec` of key-value pairs that lies in the specified range.
+    ///
+    /// If the number of eligible key-value pairs are greater than `limit`,
+    /// only the first `limit` pairs are returned, ordered by the key.
+    ///
+    /// The regions of the range are scanned one at a time, or up to
+    /// [`RawOptions::scan_concurrency`] of them concurrently, e.g. to export a large range.
+    ///
+    /// # Examples
+    /// ```rust,no_run
+    /// # use tikv_client::{KvPair, Config, RawClient, IntoOwnedRange};
+    /// # use futures::prelude::*;
+    /// # futures::executor::block_on(async {
+    /// # let client = RawClient::new(vec!["192.168.0.100"], None).await.unwrap();
+    /// let inclusive_range = "TiKV"..="TiDB";
+    /// let req = client.scan(inclusive_range.into_owned(), 2);
+    /// let result: Vec<KvPair> = req.await.unwrap();
+    /// # });
+    /// ```
+    pub async fn scan(&self, range: impl Into<BoundRange>, limit: u32) -> Result<Vec<KvPair>> {
+        debug!(self.logger, "invoking raw scan request");
+        let threshold = self.options.value_size_threshold;
+        match self
+            .scan_inner(range.into(), limit, self.options.key_only, threshold)
+            .await?
+        {
+            (pairs, None) => Ok(pairs
+                .into_iter()
+                .filter_map(ScannedPair::into_pair)
+                .collect()),
+            (_, Some(resume_key)) => self.result_too_large(resume_key),
+        }
+    }
+
+    /// Scan `range` like [`scan`](Client::scan), but return only the keys and the sizes of the
+    /// values larger than `threshold` bytes, e.g. to find oversized entries.
+    ///
+    /// TiKV has no way to leave values out of a scan by their size, so the oversized values are
+    /// still sent to the client, but they are dropped as soon as they are received, without being
+    /// decoded by the [value codec](Client::with_value_codec). The sizes are those of the values
+    /// as stored in TiKV. Oversized pairs count against `limit` like other pairs.
+    ///
+    /// # Examples
+    /// ```rust,no_run
+    /// # use tikv_client::{RawClient, ScannedPair};
+    /// # use futures::prelude::*;
+    /// # futures::executor::block_on(async {
+    /// # let client = RawClient::new(vec!["192.168.0.100"], None).await.unwrap();
+    /// let scanned = client
+    ///     .scan_with_value_threshold("TiDB".to_owned().."TiKV".to_owned(), 100, 1024 * 1024)
+    ///     .await
+    ///     .unwrap();
+    /// for pair in scanned {
+    ///     if let ScannedPair::Oversized { key, value_size } = pair {
+    ///         println!("{:?} has a value of {} bytes", key, value_size);
+    ///     }
+    /// }
+    /// # });
+    /// ```
+    pub async fn scan_with_value_threshold(
+        &self,
+        range: impl Into<BoundRange>,
+        limit: u32,
+        threshold: usize,
+    ) -> Result<Vec<ScannedPair>> {
+        debug!(
+            self.logger,
+            "invoking raw scan_with_value_threshold request"
+        );
+        match self
+            .scan_inner(range, limit, false, Some(threshold))
+            .await?
+        {
+            (pairs, None) => Ok(pairs),
+            (_, Some(resume_key)) => self.result_too_large(resume_key),
+        }
+    }
+
+    /// Continue the scan at `token`, returning at most `limit` pairs.
+    ///
+    /// Also returns the token to continue the scan after the returned pairs, or `None` if the
+    /// scan has reached the end of its range. If the client has a
+    /// [byte limit](Client::with_max_result_bytes), fewer pairs are returned once the limit is
+    /// reached. Only if not even the first pair fits, the scan fails with
+    /// [`ResultTooLarge`](Error::ResultTooLarge).
+    ///
+    /// # Examples
+    /// ```rust,no_run
+    /// # use tikv_client::{KvPair, Config, RawClient, ScanToken};
+    /// # use futures::prelude::*;
+    /// # futures::executor::block_on(async {
+    /// # let client = RawClient::new(vec!["192.168.0.100"], None).await.unwrap();
+    /// let mut token = Some(ScanToken::new("TiDB".to_owned().."TiKV".to_owned()));
+    /// while let Some(current) = token {
+    ///     let (pairs, next): (Vec<KvPair>, _) = client.scan_from(current, 100).await.unwrap();
+    ///     // Export the pairs and checkpoint `next`...
+    ///     token = next;
+    /// }
+    /// # });
+    /// ```
+    pub async fn scan_from(
+        &self,
+        token: ScanToken,
+        limit: u32,
+    ) -> Result<(Vec<KvPair>, Option<ScanToken>)> {
+        debug!(self.logger, "invoking raw scan_from request");
+        let threshold = self.options.value_size_threshold;
+        let (pairs, resume_key) = self
+            .scan_inner(token.range(), limit, self.options.key_only, threshold)
+            .await?;
+        let next = match resume_key {
+            Some(resume_key) if pairs.is_empty() => return self.result_too_large(resume_key),
+            Some(resume_key) => Some(token.at(&resume_key)),
+            // a scan returns fewer pairs than the limit only if the range is exhausted
+            None if (pairs.len() as u32) < limit => None,
+            None => pairs.last().map(|pair| token.after(pair.key())),
+        };
+        let pairs = pairs
+            .into_iter()
+            .filter_map(ScannedPair::into_pair)
+            .collect();
+        Ok((pairs, next))
+    }
+
+    /// Create a new 'scan' request which streams the key-value pairs in `range`, ordered by the
+    /// key unless `prefetch` scans regions concurrently in
+    /// [`ScanOrder::Unordered`](crate::ScanOrder::Unordered).
+    ///
+    /// Pairs are fetched in batches of at most `batch_size` pairs, by default from one region at a
+    /// time. The next batch is fetched while the current one is consumed, so that a fast consumer
+    /// doesn't wait for each request, and `prefetch` bounds how many batches and bytes are
+    /// buffered, so that a slow consumer doesn't cause unbounded memory growth. Fetching stops
+    /// when the stream is dropped.
+    ///
+    /// The stream ends after the first error.
+    ///
+    /// # Examples
+    /// ```rust,no_run
+    /// # use tikv_client::{KvPair, Config, RawClient, ScanPrefetch};
+    /// # use futures::prelude::*;
+    /// # futures::executor::block_on(async {
+    /// # let client = RawClient::new(vec!["192.168.0.100"], None).await.unwrap();
+    /// let mut stream = client
+    ///     .scan_stream("TiDB".to_owned().."TiKV".to_owned(), 256, ScanPrefetch::default())
+    ///     .unwrap();
+    /// while let Some(pair) = stream.next().await {
+    ///     let pair: KvPair = pair.unwrap();
+    ///     // Process the pair...
+    /// }
+    /// # });
+    /// ```
+    pub fn scan_stream(
+        &self,
+        range: impl Into<BoundRange>,
+        batch_size: u32,
+        prefetch: ScanPrefetch,
+    ) -> Result<BoxStream<'static, Result<KvPair>>> {
+        debug!(self.logger, "invoking raw scan_stream request");
+        Ok(self
+            .scan_batches(range.into(), batch_size, prefetch)?
+            .map_ok(|(_, pairs)| stream::iter(pairs.into_iter().map(Ok)))
+            .try_flatten()
+            .boxed())
+    }
+
+    /// Like [`scan_stream`](Client::scan_stream), but each pair comes with the id of the region
+    /// it was scanned from, and a [`ScanItem::Region`] marker is yielded before the pairs of each
+    /// region, e.g. to aggregate the pairs per region or to write a file per region without
+    /// looking up the regions of the keys.
+    ///
+    /// The regions are those known by the region cache when the pairs were requested, so a
+    /// region split during the scan may not be reflected.
+    ///
+    /// # Examples
+    /// ```rust,no_run
+    /// # use tikv_client::{RawClient, ScanItem, ScanPrefetch};
+    /// # use futures::prelude::*;
+    /// # futures::executor::block_on(async {
+    /// # let client = RawClient::new(vec!["192.168.0.100"], None).await.unwrap();
+    /// let mut stream = client
+    ///     .scan_stream_with_regions(.., 256, ScanPrefetch::default())
+    ///     .unwrap();
+    /// while let Some(item) = stream.next().await {
+    ///     match item.unwrap() {
+    ///         ScanItem::Region(region_id) => println!("region {}", region_id),
+    ///         ScanItem::Pair(_, pair) => println!("{:?}", pair.key()),
+    ///     }
+    /// }
+    /// # });
+    /// ```
+    pub fn scan_stream_with_regions(
+        &self,
+        range: impl Into<BoundRange>,
+        batch_size: u32,
+        prefetch: ScanPrefetch,
+    ) -> Result<BoxStream<'static, Result<ScanItem>>> {
+        debug!(self.logger, "invoking raw scan_stream_with_regions request");
+        let batches = self.scan_batches(range.into(), batch_size, prefetch)?;
+        Ok(scan_items(batches).boxed())
+    }
+
+    /// The decoded batches of a streaming scan of `range`, with the ids of their regions.
+    fn scan_batches(
+        &self,
+        range: BoundRange,
+        batch_size: u32,
+        prefetch: ScanPrefetch,
+    ) -> Result<RegionBatches> {
+        if batch_size > MAX_RAW_KV_SCAN_LIMIT {
+            return Err(Error::MaxScanLimitExceeded {
+                limit: batch_size,
+                max_limit: MAX_RAW_KV_SCAN_LIMIT,
+            });
+        }
+
+        let key_only = self.options.key_only;
+        let client = self.clone();
+        let batches = scan_stream_with_regions(
+            self.read_rpc(),
+            self.encode_range(range),
+            batch_size,
+            prefetch,
+            move |range, limit| {
+                let cf = client.options.cf.clone();
+                let request = new_raw_scan_request(range, limit, key_only, cf);
+                let plan = client
+                    .read_plan_builder(request)
+                    .retry_multi_region(client.options.region_backoff())
+                    .merge(Collect)
+                    .plan();
+                async move { plan.execute().await }
+            },
+        );
+        let client = self.clone();
+        Ok(batches
+            .and_then(move |(region_id, pairs)| {
+                let pairs = client.decode_keys(pairs);
+                let pairs = if key_only {
+                    pairs
+                } else {
+                    pairs.and_then(|pairs| client.decode_pairs(pairs))
+                };
+                future::ready(pairs.map(|pairs| (region_id, pairs)))
+            })
+            .boxed())
+    }
+
+    /// Create a new 'scan' request that only returns the keys.
+    ///
+    /// Once resolved this request will result in a `Vec` of keys that lies in the specified range.
+    ///
+    /// If the number of eligible keys are greater than `limit`,
+    /// only the first `limit` pairs are returned, ordered by the key.
+    ///
+    ///
+    /// # Examples
+    /// ```rust,no_run
+    /// # use tikv_client::{Key, Config, RawClient, IntoOwnedRange};
+    /// # use futures::prelude::*;
+    /// # futures::executor::block_on(async {
+    /// # let client = RawClient::new(vec!["192.168.0.100"], None).await.unwrap();
+    /// let inclusive_range = "TiKV"..="TiDB";
+    /// let req = client.scan_keys(inclusive_range.into_owned(), 2);
+    /// let result: Vec<Key> = req.await.unwrap();
+    /// # });
+    /// ```
+    pub async fn scan_keys(&self, range: impl Into<BoundRange>, limit: u32) -> Result<Vec<Key>> {
+        debug!(self.logger, "invoking raw scan_keys request");
+        match self.scan_inner(range, limit, true, None).await? {
+            (pairs, None) => Ok(pairs.iter().map(|pair| pair.key().clone()).collect()),
+            (_, Some(resume_key)) => self.result_too_large(resume_key),
+        }
+    }
+
+    /// Create a new 'batch scan' request.
+    ///
+    /// Once resolved this request will result in a set of scanners over the given keys.
+    ///
+    /// **Warning**: This method is experimental. The `each_limit` parameter does not work as expected.
+    /// It does not limit the number of results returned of each range,
+    /// instead it limits the number of results in each region of each range.
+    /// As a result, you may get **more than** `each_limit` key-value pairs for each range.
+    /// But you should not miss any entries.
+    ///
+    /// The pairs are returned range by range, in the order of `ranges`. Overlapping parts of the
+    /// ranges are scanned once, and their pairs are returned for each range containing them.
+    ///
+    /// # Examples
+    /// ```rust,no_run
+    /// # use tikv_client::{Key, Config, RawClient, IntoOwnedRange};
+    /// # use futures::prelude::*;
+    /// # futures::executor::block_on(async {
+    /// # let client = RawClient::new(vec!["192.168.0.100"], None).await.unwrap();
+    /// let inclusive_range1 = "TiDB"..="TiKV";
+    /// let inclusive_range2 = "TiKV"..="TiSpark";
+    /// let iterable = vec![inclusive_range1.into_owned(), inclusive_range2.into_owned()];
+    /// let req = client.batch_scan(iterable, 2);
+    /// let result = req.await;
+    /// # });
+    /// ```
+    pub async fn batch_scan(
+        &self,
+        ranges: impl IntoIterator<Item = impl Into<BoundRange>>,
+        each_limit: u32,
+    ) -> Result<Vec<KvPair>> {
+        debug!(self.logger, "invoking raw batch_scan request");
+        self.batch_scan_inner(ranges, each_limit, self.options.key_only)
+            .await
+    }
+
+    /// Create a new 'batch scan' request that only returns the keys.
+    ///
+    /// Once resolved this request will result in a set of scanners over the given keys.
+    ///
+    /// **Warning**: This method is experimental.
+    /// The `each_limit` parameter does not limit the number of results returned of each range,
+    /// instead it limits the number of results in each region of each range.
+    /// As a result, you may get **more than** `each_limit` key-value pairs for each range,
+    /// but you should not miss any entries.
+    ///
+    /// The keys are returned range by range, see [`batch_scan`](Client::batch_scan).
+    ///
+    /// # Examples
+    /// ```rust,no_run
+    /// # use tikv_client::{Key, Config, RawClient, IntoOwnedRange};
+    /// # use futures::prelude::*;
+    /// # futures::executor::block_on(async {
+    /// # let client = RawClient::new(vec!["192.168.0.100"], None).await.unwrap();
+    /// let inclusive_range1 = "TiDB"..="TiKV";
+    /// let inclusive_range2 = "TiKV"..="TiSpark";
+    /// let iterable = vec![inclusive_range1.into_owned(), inclusive_range2.into_owned()];
+    /// let req = client.batch_scan(iterable, 2);
+    /// let result = req.await;
+    /// # });
+    /// ```
+    pub async fn batch_scan_keys(
+        &self,
+        ranges: impl IntoIterator<Item = impl Into<BoundRange>>,
+        each_limit: u32,
+    ) -> Result<Vec<Key>> {
+        debug!(self.logger, "invoking raw batch_scan_keys request");
+        Ok(self
+            .batch_scan_inner(ranges, each_limit, true)
+            .await?
+            .into_iter()
+            .map(KvPair::into_key)
+            .collect())
+    }
+
+    /// Create a new 'get key ttl' request.
+    ///
+    /// Once resolved this request will result in the fetching of the remaining TTL in seconds of
+    /// the given key. Returns `None` if the key does not exist, and `Some(0)` if it never expires.
+    /// Returns [`TtlNotEnabled`](Error::TtlNotEnabled) if TTL is not enabled in the cluster.
+    ///
+    /// # Examples
+    /// ```rust,no_run
+    /// # use tikv_client::{Config, RawClient};
+    /// # use futures::prelude::*;
+    /// # futures::executor::block_on(async {
+    /// # let client = RawClient::new(vec!["192.168.0.100"], None).await.unwrap();
+    /// let key = "TiKV".to_owned();
+    /// let req = client.get_key_ttl_secs(key);
+    /// let result: Option<u64> = req.await.unwrap();
+    /// # });
+    /// ```
+    pub async fn get_key_ttl_secs(&self, key: impl Into<Key>) -> Result<Option<u64>> {
+        debug!(self.logger, "invoking raw get_key_ttl request");
+        let key = self.encode_key(key.into());
+        let request = new_raw_get_key_ttl_request(key, self.options.cf.clone());
+        let plan = self
+            .read_plan_builder(request)
+            .retry_multi_region(self.options.region_backoff())
+            .merge(CollectSingle)
+            .extract_error()
+            .post_process_default()
+            .plan();
+        plan.execute().await
+    }
+
+    /// Like [`get_key_ttl_secs`](Client::get_key_ttl_secs), but the TTL is returned as a
+    /// `Duration`, `Duration::ZERO` if the key never expires.
+    ///
+    /// # Examples
+    /// ```rust,no_run
+    /// # use tikv_client::{Config, RawClient};
+    /// # use futures::prelude::*;
+    /// # use std::time::Duration;
+    /// # futures::executor::block_on(async {
+    /// # let client = RawClient::new(vec!["192.168.0.100"], None).await.unwrap();
+    /// client.put_with_ttl("TiKV".to_owned(), "Rust".to_owned(), 60).await.unwrap();
+    /// let ttl: Option<Duration> = client.get_key_ttl("TiKV".to_owned()).await.unwrap();
+    /// # });
+    /// ```
+    pub async fn get_key_ttl(&self, key: impl Into<Key>) -> Result<Option<Duration>> {
+        let ttl_secs = self.get_key_ttl_secs(key).await?;
+        Ok(ttl_secs.map(Duration::from_secs))
+    }
+
+    /// Create a new *atomic* 'compare and set' request.
+    ///
+    /// Once resolved this request will result in an atomic `compare and set'
+    /// operation for the given key.
+    ///
+    /// If the value retrived is equal to `current_value`, `new_value` is
+    /// written.
+    ///
+    /// # Return Value
+    ///
+    /// A tuple is returned if successful: the previous value and whether the
+    /// value is swapped
+    ///
+    /// The request is not retried if TiKV can't be reached, since a swap which succeeded fails
+    /// the second time. Read the key to check whether the swap took effect.
+    pub async fn compare_and_swap(
+        &self,
+        key: impl Into<Key>,
+        previous_value: impl Into<Option<Value>>,
+        new_value: impl Into<Value>,
+    ) -> Result<(Option<Value>, bool)> {
+        debug!(self.logger, "invoking raw compare_and_swap request");
+        self.assert_atomic()?;
+        let key = key.into();
+        let previous_value = previous_value
+            .into()
+            .map(|value| self.encode_value(&key, value))
+            .transpose()?;
+        let new_value = self.encode_value(&key, new_value.into())?;
+        self.check_write(&key, Some(&new_value))?;
+        let req = new_cas_request(
+            self.encode_key(key.clone()),
+            new_value,
+            previous_value,
+            self.options.cf.clone(),
+        );
+        let plan = self
+            .plan_builder(req)
+            .retry_multi_region(self.options.region_backoff())
+            .merge(CollectSingle)
+            .post_process_default()
+            .plan();
+        let (previous_value, swapped) = plan.execute().await?;
+        let previous_value = previous_value
+            .map(|value| self.decode_value(&key, value))
+            .transpose()?;
+        Ok((previous_value, swapped))
+    }
+
+    /// Create a new *atomic* 'put if absent' request.
+    ///
+    /// Once resolved this request will write `value` for the given key only if the key does not
+    /// exist yet. It is built on [`compare_and_swap`](Client::compare_and_swap), so the client
+    /// must be created with [`with_atomic_for_cas`](Client::with_atomic_for_cas).
+    ///
+    /// # Return Value
+    ///
+    /// `None` if the value is written, otherwise the value which already exists.
+    ///
+    /// # Examples
+    /// ```rust,no_run
+    /// # use tikv_client::{Value, Config, RawClient};
+    /// # use futures::prelude::*;
+    /// # futures::executor::block_on(async {
+    /// # let client = RawClient::new(vec!["192.168.0.100"], None).await.unwrap().with_atomic_for_cas();
+    /// let existing: Option<Value> = client.put_if_absent("TiKV".to_owned(), "Rust".to_owned()).await.unwrap();
+    /// # });
+    /// ```
+    pub async fn put_if_absent(
+        &self,
+        key: impl Into<Key>,
+        value: impl Into<Value>,
+    ) -> Result<Option<Value>> {
+        debug!(self.logger, "invoking raw put_if_absent request");
+        let (previous_value, swapped) = self.compare_and_swap(key, None::<Value>, value).await?;
+        if swapped {
+            Ok(None)
+        } else {
+            Ok(previous_value)
+        }
+    }
+
+    pub async fn coprocessor(
+        &self,
+        copr_name: impl Into<String>,
+        copr_version_req: impl Into<String>,
+        ranges: impl IntoIterator<Item = impl Into<BoundRange>>,
+        request_builder: impl Fn(metapb::Region, Vec<Range<Key>>) -> Vec<u8> + Send + Sync + 'static,
+    ) -> Result<Vec<(Vec<u8>, Vec<Range<Key>>)>> {
+        if self.keyspace.is_some() || self.namespace.is_some() {
+            return Err(Error::Unimplemented);
+        }
+        let copr_version_req = copr_version_req.into();
+        semver::VersionReq::from_str(&copr_version_req)?;
+        let req = new_raw_coprocessor_request(
+            copr_name.into(),
+            copr_version_req,
+            ranges.into_iter().map(Into::into),
+            request_builder,
+        );
+        let plan = self
+            .plan_builder(req)
+            .preserve_shard()
+            .retry_multi_region(self.options.region_backoff())
+            .post_process_default()
+            .plan();
+        plan.execute().await
+    }
+
+    /// List the members of the PD cluster.
+    ///
+    /// The result tells which member is the leader and whether each member could be reached.
+    ///
+    /// # Examples
+    /// ```rust,no_run
+    /// # use tikv_client::{Config, RawClient};
+    /// # use futures::prelude::*;
+    /// # futures::executor::block_on(async {
+    /// # let client = RawClient::new(vec!["192.168.0.100"], None).await.unwrap();
+    /// let members = client.pd_members().await.unwrap();
+    /// let leader = members.iter().find(|m| m.is_leader);
+    /// # });
+    /// ```
+    pub async fn pd_members(&self) -> Result<Vec<PdMember>> {
+        self.rpc.clone().members().await
+    }
+
+    /// The disk usage and the number of regions of the stores of the cluster, as last reported to
+    /// PD, see [`StoreStats`].
+    ///
+    /// # Examples
+    /// ```rust,no_run
+    /// # use tikv_client::{Config, RawClient};
+    /// # use futures::prelude::*;
+    /// # futures::executor::block_on(async {
+    /// # let client = RawClient::new(vec!["192.168.0.100"], None).await.unwrap();
+    /// let stats = client.store_stats().await.unwrap();
+    /// let almost_full = stats.iter().any(|store| store.available_ratio() < 0.1);
+    /// # });
+    /// ```
+    pub async fn store_stats(&self) -> Result<Vec<StoreStats>> {
+        fetch_store_stats(self.rpc.clone()).await
+    }
+
+    /// Compute the checksum of the pairs in `range` in TiKV.
+    ///
+    /// The checksum of pairs read by the client can be computed with [`RawChecksum::of_pairs`],
+    /// to verify them, see also [`RawOptions::verify_checksums`]. Only the default column family
+    /// is covered.
+    ///
+    /// # Examples
+    /// ```rust,no_run
+    /// # use tikv_client::{Config, RawClient, RawChecksum};
+    /// # use futures::prelude::*;
+    /// # futures::executor::block_on(async {
+    /// # let client = RawClient::new(vec!["192.168.0.100"], None).await.unwrap();
+    /// let pairs = client.scan("TiDB".to_owned().."TiKV".to_owned(), 100).await.unwrap();
+    /// let checksum = client.checksum("TiDB".to_owned().."TiKV".to_owned()).await.unwrap();
+    /// if pairs.len() < 100 {
+    ///     assert_eq!(RawChecksum::of_pairs(&pairs), checksum);
+    /// }
+    /// # });
+    /// ```
+    pub async fn checksum(&self, range: impl Into<BoundRange>) -> Result<RawChecksum> {
+        debug!(self.logger, "invoking raw checksum request");
+        self.checksum_ranges(iter::once(self.encode_range(range.into())))
+            .await
+    }
+
+    /// Look up the region with `id` in PD, e.g. to find the keys of a region referenced by the
+    /// logs of TiKV. The cached entry of the region is refreshed with the result.
+    ///
+    /// The keys of the region are as stored in TiKV, i.e. including the keyspace prefix, if any.
+    ///
+    /// # Examples
+    /// ```rust,no_run
+    /// # use tikv_client::{Config, RawClient};
+    /// # use futures::prelude::*;
+    /// # futures::executor::block_on(async {
+    /// # let client = RawClient::new(vec!["192.168.0.100"], None).await.unwrap();
+    /// let region = client.region_by_id(42).await.unwrap();
+    /// let (start_key, end_key) = region.range();
+    /// # });
+    /// ```
+    pub async fn region_by_id(&self, id: RegionId) -> Result<RegionWithLeader> {
+        self.rpc.refresh_region_for_id(id).await
+    }
+
+    /// The region which contains `key`, as cached by the client, or loaded from PD if it isn't
+    /// cached. Besides its peers, the region holds the states the client learned about it, e.g.
+    /// whether some of its peers are down or whether it is being merged, so that requests can be
+    /// routed around it, see [`RegionWithLeader`].
+    ///
+    /// The keys of the region are as stored in TiKV, i.e. including the keyspace prefix, if any.
+    ///
+    /// # Examples
+    /// ```rust,no_run
+    /// # use tikv_client::{Config, RawClient};
+    /// # use futures::prelude::*;
+    /// # futures::executor::block_on(async {
+    /// # let client = RawClient::new(vec!["192.168.0.100"], None).await.unwrap();
+    /// let region = client.region_for_key("TiKV".to_owned()).await.unwrap();
+    /// if region.merging || region.has_unavailable_peers() {
+    ///     // defer the writes to the region
+    /// }
+    /// # });
+    /// ```
+    pub async fn region_for_key(&self, key: impl Into<Key>) -> Result<RegionWithLeader> {
+        self.rpc.region_for_key(&self.encode_key(key.into())).await
+    }
+
+    /// Sample at most `n` keys splitting `range` into parts which hold about the same amount of
+    /// data, e.g. to choose the shard boundaries of a downstream system or to build a histogram of
+    /// the keys.
+    ///
+    /// The keys are sampled from the boundaries of the regions in `range`, which TiKV splits once
+    /// they grow past a size limit, so regions hold about the same amount of data. If `range`
+    /// spans more than `n + 1` regions, evenly spaced boundaries are returned, otherwise all of
+    /// them, i.e. fewer than `n` keys and none if `range` is within a single region. The keys are
+    /// sorted and within `range`, excluding its start. Only the regions are looked up, no data is
+    /// read.
+    ///
+    /// # Examples
+    /// ```rust,no_run
+    /// # use tikv_client::{Config, RawClient};
+    /// # use futures::prelude::*;
+    /// # futures::executor::block_on(async {
+    /// # let client = RawClient::new(vec!["192.168.0.100"], None).await.unwrap();
+    /// // split the keys of a table into 8 shards
+    /// let boundaries = client.sample_keys("t1_".to_owned().."t2_".to_owned(), 7).await.unwrap();
+    /// # });
+    /// ```
+    pub async fn sample_keys(&self, range: impl Into<BoundRange>, n: usize) -> Result<Vec<Key>> {
+        debug!(self.logger, "invoking raw sample_keys request");
+        let (mut key, end) = self.encode_range(range.into()).into_keys();
+        let end = end.filter(|end| !end.is_empty());
+        let mut boundaries = Vec::new();
+        loop {
+            let region_end = self.rpc.region_for_key(&key).await?.end_key();
+            if region_end.is_empty() || matches!(&end, Some(end) if region_end >= *end) {
+                break;
+            }
+            boundaries.push(region_end.clone());
+            key = region_end;
+        }
+        // the boundaries split the range into `boundaries.len() + 1` regions, which are grouped
+        // into `n + 1` parts of about the same number of regions
+        let regions = boundaries.len() + 1;
+        if regions > n + 1 {
+            boundaries = (1..=n)
+                .map(|i| boundaries[i * regions / (n + 1) - 1].clone())
+                .collect();
+        }
+        boundaries
+            .into_iter()
+            .map(|key| self.decode_key(key))
+            .collect()
+    }
+
+    /// The value of each of `keys`, in the same order.
+    async fn batch_get_inner(&self, keys: Vec<Key>) -> Result<Vec<Option<Value>>> {
+        match self.batch_get_on_cluster(keys.clone()).await {
+            Err(e) => match self.standby_after(&e) {
+                Some(standby) => standby.batch_get_on_cluster(keys).await,
+                None => Err(e),
+            },
+            result => result,
+        }
+    }
+
+    async fn batch_get_on_cluster(&self, keys: Vec<Key>) -> Result<Vec<Option<Value>>> {
+        let encoded_keys: Vec<Key> = keys
+            .iter()
+            .map(|key| self.encode_key(key.clone()))
+            .collect();
+        let mut unique_keys = encoded_keys.clone();
+        unique_keys.sort();
+        unique_keys.dedup();
+        let (pairs, resume_key) = match self.max_result_bytes {
+            Some(max_bytes) => {
+                self.batch_get_with_byte_limit(unique_keys, max_bytes)
+                    .await?
+            }
+            None => (self.batch_get_sorted(unique_keys).await?, None),
+        };
+        observe_result_bytes("raw_batch_get", pairs.iter().map(pair_size).sum());
+        if let Some(resume_key) = resume_key {
+            return self.result_too_large(self.decode_key(resume_key)?);
+        }
+        let values: HashMap<Key, Value> = pairs
+            .into_iter()
+            .map(|KvPair(key, value)| (key, value))
+            .collect();
+        keys.iter()
+            .zip(&encoded_keys)
+            .map(|(key, encoded_key)| {
+                values
+                    .get(encoded_key)
+                    .map(|value| self.decode_value(key, value.clone()))
+                    .transpose()
+            })
+            .collect()
+    }
+
+    /// The pairs of `keys`, which are encoded, sorted and without duplicates, in no particular
+    /// order.
+    async fn batch_get_sorted(&self, keys: Vec<Key>) -> Result<Vec<KvPair>> {
+        let request = new_raw_batch_get_request(keys.iter().cloned(), self.options.cf.clone());
+        let plan = self
+            .read_plan_builder(request)
+            .retry_multi_region(self.options.region_backoff())
+            .chunk_size(self.options.batch_get_chunk_size)
+            .concurrency(self.options.batch_get_concurrency)
+            .merge(Collect)
+            .plan();
+        let pairs = plan.execute().await?;
+        if !self.sample_checksum() {
+            return Ok(pairs);
+        }
+        let ranges = keys.into_iter().map(key_range).collect();
+        self.verify_checksum(ranges, pairs, plan.execute()).await
+    }
+
+    /// Like [`batch_get_sorted`](Client::batch_get_sorted), but reads the keys region by region
+    /// in the order of the keys, and stops at the first pair whose key and value exceed
+    /// `max_bytes`, so that no more than the keys of one region are read beyond the limit.
+    ///
+    /// Returns the pairs which fit, ordered by key, and the key of the first pair which did not
+    /// fit, if any.
+    async fn batch_get_with_byte_limit(
+        &self,
+        keys: Vec<Key>,
+        max_bytes: usize,
+    ) -> Result<(Vec<KvPair>, Option<Key>)> {
+        let rpc = self.read_rpc();
+        let mut result = Vec::new();
+        let mut bytes = 0;
+        let mut keys = &keys[..];
+        while let Some(first) = keys.first() {
+            let region = rpc.region_for_key(first).await?;
+            let end_key = region.end_key();
+            let count = keys.partition_point(|key| end_key.is_empty() || *key < end_key);
+            let (region_keys, rest) = keys.split_at(count);
+            keys = rest;
+
+            let mut pairs = self.batch_get_sorted(region_keys.to_vec()).await?;
+            pairs.sort_by(|a, b| a.key().cmp(b.key()));
+            for (i, pair) in pairs.iter().enumerate() {
+                bytes += pair_size(pair);
+                if bytes > max_bytes {
+                    let resume_key = pair.key().clone();
+                    pairs.truncate(i);
+                    result.append(&mut pairs);
+                    return Ok((result, Some(resume_key)));
+                }
+            }
+            result.append(&mut pairs);
+        }
+        Ok((result, None))
+    }
+
+    async fn scan_inner(
+        &self,
+        range: impl Into<BoundRange>,
+        limit: u32,
+        key_only: bool,
+        threshold: Option<usize>,
+    ) -> Result<(Vec<ScannedPair>, Option<Key>)> {
+        let range = range.into();
+        match self
+            .scan_on_cluster(range.clone(), limit, key_only, threshold)
+            .await
+        {
+            Err(e) => match self.standby_after(&e) {
+                Some(standby) => {
+                    standby
+                        .scan_on_cluster(range, limit, key_only, threshold)
+                        .await
+                }
+                None => Err(e),
+            },
+            result => result,
+        }
+    }
+
+    async fn scan_on_cluster(
+        &self,
+        range: BoundRange,
+        limit: u32,
+        key_only: bool,
+        threshold: Option<usize>,
+    ) -> Result<(Vec<ScannedPair>, Option<Key>)> {
+        if limit > MAX_RAW_KV_SCAN_LIMIT {
+            return Err(Error::MaxScanLimitExceeded {
+                limit,
+                max_limit: MAX_RAW_KV_SCAN_LIMIT,
+            });
+        }
+
+        let range = self.encode_range(range);
+        let verify = !key_only && limit > 0 && self.sample_checksum();
+        let scanned_range = range.clone();
+        let scan_region = |range, limit| {
+            let request = new_raw_scan_request(range, limit, key_only, self.options.cf.clone());
+            let plan = self
+                .read_plan_builder(request)
+                .retry_multi_region(self.options.region_backoff())
+                .merge(Collect)
+                .plan();
+            async move { plan.execute().await }
+        };
+        let concurrency = self.options.scan_concurrency.unwrap_or(1);
+        // the resume key is the first key which exceeded the byte limit, if any
+        let (pairs, resume_key) = match self.max_result_bytes {
+            Some(max_bytes) => {
+                crate::request::scan_with_byte_limit_concurrently(
+                    self.read_rpc(),
+                    range,
+                    limit,
+                    max_bytes,
+                    concurrency,
+                    scan_region,
+                )
+                .await?
+            }
+            None => {
+                let pairs = crate::request::scan_with_limit_concurrently(
+                    self.read_rpc(),
+                    range,
+                    limit,
+                    concurrency,
+                    scan_region,
+                )
+                .await?;
+                (pairs, None)
+            }
+        };
+        observe_result_bytes("raw_scan", pairs.iter().map(pair_size).sum());
+        if verify {
+            // the pairs read are all pairs from the start of the range up to where the scan stopped
+            let (start, end) = scanned_range.into_keys();
+            let end = match &resume_key {
+                Some(resume_key) => Some(resume_key.clone()),
+                None if pairs.len() == limit as usize => {
+                    pairs.last().map(|pair| successor(pair.key()))
+                }
+                None => end,
+            };
+            // unlike point reads, the scan is not read again on a mismatch
+            let scanned = vec![(start, end).into()];
+            self.check_checksum(scanned, &pairs).await?;
+        }
+        let resume_key = resume_key.map(|key| self.decode_key(key)).transpose()?;
+        let pairs = self.decode_keys(pairs)?;
+        let pairs = pairs
+            .into_iter()
+            .map(|KvPair(key, value)| match threshold {
+                _ if key_only => Ok(ScannedPair::Pair(KvPair(key, value))),
+                // oversized values are dropped before they are decoded
+                Some(threshold) if value.len() > threshold => Ok(ScannedPair::Oversized {
+                    key,
+                    value_size: value.len(),
+                }),
+                _ => {
+                    let value = self.decode_value(&key, value)?;
+                    Ok(ScannedPair::Pair(KvPair(key, value)))
+                }
+            })
+            .collect::<Result<_>>()?;
+        Ok((pairs, resume_key))
+    }
+
+    async fn batch_scan_inner(
+        &self,
+        ranges: impl IntoIterator<Item = impl Into<BoundRange>>,
+        each_limit: u32,
+        key_only: bool,
+    ) -> Result<Vec<KvPair>> {
+        if each_limit > MAX_RAW_KV_SCAN_LIMIT {
+            return Err(Error::MaxScanLimitExceeded {
+                limit: each_limit,
+                max_limit: MAX_RAW_KV_SCAN_LIMIT,
+            });
+        }
+
+        let ranges: Vec<(Key, Option<Key>)> = ranges
+            .into_iter()
+            .map(|range| {
+                let (start, end) = self.encode_range(range.into()).into_keys();
+                // an empty end key means the range is unbounded
+                (start, end.filter(|end| !end.is_empty()))
+            })
+            .collect();
+        // overlapping parts of the ranges are scanned once
+        let request = new_raw_batch_scan_request(
+            disjoint_segments(&ranges).into_iter().map(Into::into),
+            each_limit,
+            key_only,
+            self.options.cf.clone(),
+        );
+        let plan = self
+            .read_plan_builder(request)
+            .retry_multi_region(self.options.region_backoff())
+            .progress(self.options.progress.clone())
+            .concurrency(self.options.scan_concurrency)
+            .merge(Collect)
+            .plan();
+        let mut scanned = plan.execute().await?;
+        scanned.sort_by(|a, b| a.key().cmp(b.key()));
+        let pairs: Vec<KvPair> = ranges
+            .iter()
+            .flat_map(|(start, end)| {
+                let from = scanned.partition_point(|pair| pair.key() < start);
+                let to = match end {
+                    Some(end) => scanned.partition_point(|pair| pair.key() < end),
+                    None => scanned.len(),
+                };
+                scanned[from..to.max(from)].iter().cloned()
+            })
+            .collect();
+        observe_result_bytes("raw_batch_scan", pairs.iter().map(pair_size).sum());
+        let pairs = self.decode_keys(pairs)?;
+        if key_only {
+            Ok(pairs)
+        } else {
+            self.decode_pairs(pairs)
+        }
+    }
+
+    /// A plan builder for `request` with the priority, timeout and context hook of the options.
+    fn plan_builder<Req: KvRequest>(
+        &self,
+        request: Req,
+    ) -> PlanBuilder<PdC, Dispatch<Req>, NoTarget> {
+        self.plan_builder_with(self.rpc.clone(), request)
+            .on_retries_exhausted(self.options.on_write_exhausted())
+    }
+
+    /// Sends `request` to the region of `store` only, so that TiKV applies it atomically, or
+    /// rejects it whole if the region changed.
+    async fn execute_in_region<Req>(&self, request: Req, store: RegionStore) -> Result<()>
+    where
+        Req: KvRequest,
+        Req::Response: HasKeyErrors + HasRegionErrors,
+    {
+        let plan = self
+            .plan_builder(request)
+            .single_region_with_store(store)
+            .await?
+            .extract_error()
+            .plan();
+        plan.execute().await?;
+        Ok(())
+    }
+
+    /// Like [`plan_builder`](Client::plan_builder), for a read which may be served by a replica.
+    fn read_plan_builder<Req: KvRequest>(
+        &self,
+        request: Req,
+    ) -> PlanBuilder<PdC, Dispatch<Req>, NoTarget> {
+        self.plan_builder_with(self.read_rpc(), request)
+            .on_retries_exhausted(self.options.on_read_exhausted())
+    }
+
+    fn plan_builder_with<Req: KvRequest>(
+        &self,
+        rpc: Arc<PdC>,
+        request: Req,
+    ) -> PlanBuilder<PdC, Dispatch<Req>, NoTarget> {
+        PlanBuilder::new(rpc, request)
+            .priority(self.options.priority.into())
+            .resource_group_tag(self.options.resource_group_tag.clone())
+            .timeout(self.options.timeout)
+            .deadline(self.options.deadline)
+            .context_hook(self.options.context_hook.clone())
+    }
+
+    /// The client reading from the standby cluster, if a read from the cluster of `self` failed
+    /// with `e` and should fail over, see [`with_standby`](Client::with_standby).
+    fn standby_after(&self, e: &Error) -> Option<Client<PdC>> {
+        let standby = self.standby.as_ref().filter(|_| is_unreachable(e))?;
+        info!(self.logger, "reading from the standby cluster"; "error" => %e);
+        Some(Client {
+            rpc: standby.rpc.clone(),
+            keyspace: standby.keyspace,
+            cluster_info: standby.cluster_info.clone(),
+            replica_rpc: standby.replica_rpc.clone(),
+            standby: None,
+            ..self.clone()
+        })
+    }
+
+    /// The client for reads, which sends replica reads if they are enabled.
+    fn read_rpc(&self) -> Arc<PdC> {
+        self.replica_rpc.as_ref().unwrap_or(&self.rpc).clone()
+    }
+
+    // Keys are prefixed with the namespace first, then with the keyspace.
+    fn encode_key(&self, key: Key) -> Key {
+        let key = match &self.namespace {
+            Some(namespace) => namespace.encode_key(key),
+            None => key,
+        };
+        match self.keyspace {
+            Some(keyspace) => keyspace.encode_key(key),
+            None => key,
+        }
+    }
+
+    /// `key` encoded as a transactional key, written by the transaction of
+    /// [`atomic_batch`](Client::atomic_batch).
+    fn encode_txn_key(&self, key: Key) -> Key {
+        let key = match &self.namespace {
+            Some(namespace) => namespace.encode_key(key),
+            None => key,
+        };
+        match self.keyspace {
+            Some(keyspace) => keyspace.txn().encode_key(key),
+            None => key,
+        }
+    }
+
+    fn encode_range(&self, range: BoundRange) -> BoundRange {
+        let range = match &self.namespace {
+            Some(namespace) => namespace.encode_range(range),
+            None => range,
+        };
+        match self.keyspace {
+            Some(keyspace) => keyspace.encode_range(range),
+            None => range,
+        }
+    }
+
+    fn encode_pairs(
+        &self,
+        pairs: impl IntoIterator<Item = impl Into<KvPair>>,
+    ) -> Result<Vec<KvPair>> {
+        pairs
+            .into_iter()
+            .map(|pair| {
+                let KvPair(key, value) = pair.into();
+                let value = self.encode_value(&key, value)?;
+                self.check_write(&key, Some(&value))?;
+                Ok(KvPair(self.encode_key(key), value))
+            })
+            .collect()
+    }
+
+    fn write_group(
+        &self,
+        keys: impl Iterator<Item = Key>,
+        result: Result<()>,
+    ) -> Result<WriteGroup> {
+        let keys = keys
+            .map(|key| self.decode_key(key))
+            .collect::<Result<_>>()?;
+        Ok(WriteGroup { keys, result })
+    }
+
+    fn decode_keys(&self, pairs: Vec<KvPair>) -> Result<Vec<KvPair>> {
+        let pairs = match self.keyspace {
+            Some(keyspace) => keyspace.decode_pairs(pairs)?,
+            None => pairs,
+        };
+        match &self.namespace {
+            Some(namespace) => namespace.decode_pairs(pairs),
+            None => Ok(pairs),
+        }
+    }
+
+    fn decode_key(&self, key: Key) -> Result<Key> {
+        let key = match self.keyspace {
+            Some(keyspace) => keyspace.decode_key(key)?,
+            None => key,
+        };
+        match &self.namespace {
+            Some(namespace) => namespace.decode_key(key),
+            None => Ok(key),
+        }
+    }
+
+    /// The error of a result which exceeded the byte limit at `resume_key`.
+    fn result_too_large<T>(&self, resume_key: Key) -> Result<T> {
+        Err(Error::ResultTooLarge {
+            max_bytes: self.max_result_bytes.unwrap_or_default(),
+            resume_key: resume_key.into(),
+        })
+    }
+
+    /// Whether to verify the checksum of a read, sampled as set by
+    /// [`RawOptions::verify_checksums`]. Reads whose checksum can't be computed are not verified.
+    fn sample_checksum(&self) -> bool {
+        if self.options.checksum_sampling == 0 {
+            return false;
+        }
+        let other_cf = matches!(&self.options.cf, Some(cf) if *cf != ColumnFamily::Default);
+        let ttl = self
+            .cluster_info
+            .api_version
+            .is_some_and(ApiVersion::supports_ttl);
+        if other_cf || ttl {
+            return false;
+        }
+        rand::thread_rng().gen_ratio(1, self.options.checksum_sampling)
+    }
+
+    /// Verify that the (encoded) `pairs` are all the pairs in `ranges` stored in TiKV, and return
+    /// them. A write between the read and the checksum also causes a mismatch, so on a mismatch
+    /// the pairs are read once more by `reread`, which is only polled then, and those are verified
+    /// and returned instead.
+    async fn verify_checksum(
+        &self,
+        ranges: Vec<BoundRange>,
+        pairs: Vec<KvPair>,
+        reread: impl Future<Output = Result<Vec<KvPair>>>,
+    ) -> Result<Vec<KvPair>> {
+        match self.check_checksum(ranges.clone(), &pairs).await {
+            Err(Error::ChecksumMismatch { .. }) => {
+                let pairs = reread.await?;
+                self.check_checksum(ranges, &pairs).await?;
+                Ok(pairs)
+            }
+            result => result.map(|()| pairs),
+        }
+    }
+
+    /// Check that the (encoded) `pairs` are all the pairs in `ranges` stored in TiKV.
+    async fn check_checksum(&self, ranges: Vec<BoundRange>, pairs: &[KvPair]) -> Result<()> {
+        let stored = self.checksum_ranges(ranges.into_iter()).await?;
+        let read = RawChecksum::of_pairs(pairs);
+        if read != stored {
+            return Err(Error::ChecksumMismatch {
+                read: read.checksum,
+                stored: stored.checksum,
+            });
+        }
+        Ok(())
+    }
+
+    async fn checksum_ranges(
+        &self,
+        ranges: impl Iterator<Item = BoundRange>,
+    ) -> Result<RawChecksum> {
+        let request = new_raw_checksum_request(ranges);
+        let plan = self
+            .plan_builder(request)
+            .retry_multi_region(self.options.region_backoff())
+            .merge(Collect)
+            .plan();
+        plan.execute().await
+    }
+
+    fn encode_value(&self, key: &Key, value: Value) -> Result<Value> {
+        match &self.value_codec {
+            Some(codec) => codec.encode(key, value),
+            None => Ok(value),
+        }
+    }
+
+    fn decode_value(&self, key: &Key, value: Value) -> Result<Value> {
+        match &self.value_codec {
+            Some(codec) => codec.decode(key, value),
+            None => Ok(value),
+        }
+    }
+
+    fn decode_pairs(&self, pairs: impl IntoIterator<Item = KvPair>) -> Result<Vec<KvPair>> {
+        pairs
+            .into_iter()
+            .map(|KvPair(key, value)| {
+                let value = self.decode_value(&key, value)?;
+                Ok(KvPair(key, value))
+            })
+            .collect()
+    }
+
+    /// Checks a write of `key`, with the encoded `value` unless it is a deletion, against the key
+    /// validators and the size limits.
+    fn check_write(&self, key: &Key, value: Option<&Value>) -> Result<()> {
+        key_validator::validate_key(self.rpc.key_validators(), key)?;
+        self.options
+            .size_limits
+            .unwrap_or_else(|| self.rpc.size_limits())
+            .check(key, value.map(Vec::as_slice))
+    }
+
+    /// Checks a range deletion of `range` against the key validators.
+    fn check_range(&self, range: &BoundRange) -> Result<()> {
+        let (start, end) = range.clone().into_keys();
+        key_validator::validate_range(self.rpc.key_validators(), &start, end.as_ref())
+    }
+
+    fn assert_non_atomic(&self) -> Result<()> {
+        (!self.options.atomic)
+            .then(|| ())
+            .ok_or(Error::UnsupportedMode)
+    }
+
+    fn assert_atomic(&self) -> Result<()> {
+        self.options
+            .atomic
+            .then(|| ())
+            .ok_or(Error::UnsupportedMode)
+    }
+}
+
+/// Whether `e`, one of the errors it collects, or the error of the request it fails, matches
+/// `pred`.
+fn has_error(e: &Error, pred: &dyn Fn(&Error) -> bool) -> bool {
+    match e {
+        Error::ExtractedErrors(errors) | Error::MultipleKeyErrors(errors) => {
+            errors.iter().any(|e| has_error(e, pred))
+        }
+        Error::RequestFailed { source, .. } => has_error(source, pred),
+        e => pred(e),
+    }
+}
+
+/// Whether a read failed because PD or TiKV could not be reached or timed out, so that it fails
+/// over to the standby cluster, see [`Client::with_standby`].
+fn is_unreachable(e: &Error) -> bool {
+    has_error(e, &|e| match e {
+        Error::Grpc(grpcio::Error::RpcFailure(status)) => {
+            status.code() == grpcio::RpcStatusCode::UNAVAILABLE
+                || status.code() == grpcio::RpcStatusCode::DEADLINE_EXCEEDED
+        }
+        Error::Grpc(grpcio::Error::RemoteStopped) | Error::RequestTimeout { .. } => true,
+        Error::RetriesExhausted { source, .. } => is_unreachable(source),
+        _ => false,
+    })
+}
+
+/// The smallest key after `key`.
+fn successor(key: &Key) -> Key {
+    let mut next = Vec::from(key.clone());
+    next.push(0);
+    next.into()
+}
+
+/// The range containing only `key`.
+fn key_range(key: Key) -> BoundRange {
+    let end = successor(&key);
+    (key, end).into()
+}
+
+/// Splits `ranges` at the bounds of all of them into disjoint segments in ascending order, each
+/// covered by one of the ranges or more. The end of a range is exclusive, `None` if unbounded.
+///
+/// The segments within a range contain at least the first pairs of the range, so scanning the
+/// segments with a limit returns the pairs the range would return with that limit, or more.
+fn disjoint_segments(ranges: &[(Key, Option<Key>)]) -> Vec<(Key, Option<Key>)> {
+    let ranges = ranges
+        .iter()
+        .filter(|(start, end)| end.as_ref().is_none_or(|end| start < end));
+    let mut starts: Vec<&Key> = ranges.clone().map(|(start, _)| start).collect();
+    let mut ends: Vec<&Key> = ranges.filter_map(|(_, end)| end.as_ref()).collect();
+    starts.sort();
+    ends.sort();
+    let mut bounds: Vec<&Key> = starts.iter().chain(&ends).copied().collect();
+    bounds.sort();
+    bounds.dedup();
+
+    let mut segments = Vec::new();
+    let (mut started, mut ended) = (0, 0);
+    for (i, bound) in bounds.iter().enumerate() {
+        // the segment from `bound` to the next bound is covered if more ranges started than ended
+        while started < starts.len() && starts[started] <= *bound {
+            started += 1;
+        }
+        while ended < ends.len() && ends[ended] <= *bound {
+            ended += 1;
+        }
+        if started > ended {
+            let end = bounds.get(i + 1).map(|end| (*end).clone());
+            segments.push(((*bound).clone(), end));
+        }
+    }
+    segments
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        clock::{ClockHandle, MockClock},
+        mock::{MockKvClient, MockPdClient},
+        raw::CommandPriority,
+        store::StoreQueue,
+        value_codec::Compression,
+        Backoff, BackoffStrategy, ContextHook, OnRetriesExhausted, ProgressCallback, Result,
+        SizeLimits,
+    };
+    use std::{
+        any::Any,
+        sync::{
+            atomic::{AtomicBool, AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+        time::Duration,
+    };
+    use tikv_client_proto::{errorpb, kvrpcpb};
+
+    fn mock_client<PdC: PdClient>(rpc: Arc<PdC>, options: RawOptions) -> Client<PdC> {
+        Client {
+            options,
+            ..Client::new_with_rpc(rpc, Logger::root(slog::Discard, o!()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_raw_scan_shares_store_queue() -> Result<()> {
+        let dispatched = Arc::new(Mutex::new(Vec::new()));
+        let hook_dispatched = dispatched.clone();
+        let mut pd_client =
+            MockPdClient::new(MockKvClient::with_dispatch_hook(move |req: &dyn Any| {
+                if req.is::<kvrpcpb::RawBatchScanRequest>() {
+                    hook_dispatched.lock().unwrap().push("scan");
+                    let resp = kvrpcpb::RawBatchScanResponse::default();
+                    Ok(Box::new(resp) as Box<dyn Any>)
+                } else if req.is::<kvrpcpb::RawGetRequest>() {
+                    hook_dispatched.lock().unwrap().push("get");
+                    let resp = kvrpcpb::RawGetResponse {
+                        not_found: true,
+                        ..Default::default()
+                    };
+                    Ok(Box::new(resp) as Box<dyn Any>)
+                } else {
+                    unreachable!()
+                }
+            }));
+        let queue = StoreQueue::new(1);
+        pd_client.store_queue = Some(queue.clone());
+        let client = mock_client(Arc::new(pd_client), RawOptions::new());
+
+        // while the only slot of the store is taken, a scan over the three regions fills the
+        // queue, then a get is queued behind it
+        let slots = queue.hold(1);
+        let mut scan = Box::pin(client.batch_scan(vec![vec![1]..], 10));
+        let mut get = Box::pin(client.get(vec![1]));
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while queue.stats(String::new()).queued < 3 {
+                assert!(futures::poll!(&mut scan).is_pending());
+                tokio::task::yield_now().await;
+            }
+            while queue.stats(String::new()).queued < 4 {
+                assert!(futures::poll!(&mut get).is_pending());
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .expect("the requests are not queued");
+        drop(slots);
+
+        // the get takes the second slot, before the scan drains
+        let (scanned, value) = future::join(scan, get).await;
+        assert!(scanned?.is_empty());
+        assert_eq!(value?, None);
+        assert_eq!(*dispatched.lock().unwrap(), ["scan", "get", "scan", "scan"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_raw_put_if_absent() -> Result<()> {
+        let pd_client = Arc::new(MockPdClient::new(MockKvClient::with_dispatch_hook(
+            move |req: &dyn Any| {
+                if let Some(req) = req.downcast_ref::<kvrpcpb::RawCasRequest>() {
+                    assert!(req.previous_not_exist);
+                    // keys starting with 0 don't exist
+                    let resp = if req.key[0] == 0 {
+                        kvrpcpb::RawCasResponse {
+                            succeed: true,
+                            previous_not_exist: true,
+                            ..Default::default()
+                        }
+                    } else {
+                        kvrpcpb::RawCasResponse {
+                            succeed: false,
+                            previous_value: vec![42],
+                            ..Default::default()
+                        }
+                    };
+                    Ok(Box::new(resp) as Box<dyn Any>)
+                } else {
+                    unreachable!()
+                }
+            },
+        )));
+        let client = mock_client(
+            pd_client,
+            RawOptions::new().cf(ColumnFamily::Default).atomic(true),
+        );
+        assert_eq!(client.put_if_absent(vec![0], vec![1]).await?, None);
+        assert_eq!(
+            client.put_if_absent(vec![1], vec![1]).await?,
+            Some(vec![42])
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_raw_batch_put_grouped() -> Result<()> {
+        let pd_client = Arc::new(MockPdClient::new(MockKvClient::with_dispatch_hook(
+            move |req: &dyn Any| {
+                if let Some(req) = req.downcast_ref::<kvrpcpb::RawBatchPutRequest>() {
+                    assert!(req.for_cas);
+                    // the write to region2 fails
+                    let key = &req.pairs[0].key;
+                    let resp = if key >= &vec![10] && key < &vec![250, 250] {
+                        kvrpcpb::RawBatchPutResponse {
+                            error: "write failed".to_owned(),
+                            ..Default::default()
+                        }
+                    } else {
+                        kvrpcpb::RawBatchPutResponse::default()
+                    };
+                    Ok(Box::new(resp) as Box<dyn Any>)
+                } else {
+                    unreachable!()
+                }
+            },
+        )));
+        let client = mock_client(
+            pd_client,
+            RawOptions::new().cf(ColumnFamily::Default).atomic(true),
+        );
+        let pairs = vec![
+            (vec![12], vec![0]),
+            (vec![1], vec![0]),
+            (vec![251], vec![0]),
+            (vec![11], vec![0]),
+            (vec![2], vec![0]),
+        ];
+        let groups = client.batch_put_grouped(pairs).await?;
+        let keys: Vec<Vec<Key>> = groups.iter().map(|group| group.keys.clone()).collect();
+        assert_eq!(
+            keys,
+            vec![
+                vec![vec![1].into(), vec![2].into()],
+                vec![vec![11].into(), vec![12].into()],
+                vec![vec![251].into()],
+            ]
+        );
+        assert!(groups[0].result.is_ok());
+        assert!(groups[1].result.is_err());
+        assert!(groups[2].result.is_ok());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_raw_atomic_batch() -> Result<()> {
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let attempts_cloned = attempts.clone();
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let written_cloned = written.clone();
+        let mut pd_client =
+            MockPdClient::new(MockKvClient::with_dispatch_hook(move |req: &dyn Any| {
+                if let Some(req) = req.downcast_ref::<kvrpcpb::PrewriteRequest>() {
+                    let mut written = written_cloned.lock().unwrap();
+                    for mutation in &req.mutations {
+                        written.push((mutation.op, mutation.key.clone()));
+                    }
+                    let resp = kvrpcpb::PrewriteResponse::default();
+                    return Ok(Box::new(resp) as Box<dyn Any>);
+                }
+                if req.downcast_ref::<kvrpcpb::CommitRequest>().is_some() {
+                    let resp = kvrpcpb::CommitResponse::default();
+                    return Ok(Box::new(resp) as Box<dyn Any>);
+                }
+                let req = req
+                    .downcast_ref::<kvrpcpb::RawBatchPutRequest>()
+                    .expect("only puts are sent");
+                // all mutations are sent in a single request, which is rejected the first time
+                assert_eq!(req.pairs.len(), 2);
+                let resp = if attempts_cloned.fetch_add(1, Ordering::SeqCst) == 0 {
+                    kvrpcpb::RawBatchPutResponse {
+                        region_error: Some(errorpb::Error {
+                            epoch_not_match: Some(Default::default()),
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    }
+                } else {
+                    kvrpcpb::RawBatchPutResponse::default()
+                };
+                Ok(Box::new(resp) as Box<dyn Any>)
+            }));
+        pd_client.clock = ClockHandle::new(MockClock::auto_advancing());
+        let client = mock_client(Arc::new(pd_client), RawOptions::default());
+
+        let put = |key: u8| RawMutation::Put(vec![key].into(), vec![0]);
+        client.atomic_batch(vec![put(12), put(11)]).await?;
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+        client.atomic_batch(vec![]).await?;
+
+        // keys in different regions are written by a transaction
+        client.atomic_batch(vec![put(1), put(11)]).await?;
+        let mut prewritten = written.lock().unwrap().split_off(0);
+        prewritten.sort();
+        let op_put = kvrpcpb::Op::Put as i32;
+        assert_eq!(prewritten, vec![(op_put, vec![1]), (op_put, vec![11])]);
+
+        // as are puts and deletes together, even in a single region
+        let mutations = vec![put(11), RawMutation::Delete(vec![12].into())];
+        client.atomic_batch(mutations).await?;
+        let op_del = kvrpcpb::Op::Del as i32;
+        assert_eq!(
+            written.lock().unwrap().split_off(0),
+            vec![(op_put, vec![11]), (op_del, vec![12])]
+        );
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_raw_standby() -> Result<()> {
+        // the primary cluster can't be reached
+        let mut primary = MockPdClient::new(MockKvClient::with_dispatch_hook(|_: &dyn Any| {
+            let status = grpcio::RpcStatus::new(grpcio::RpcStatusCode::UNAVAILABLE);
+            Err(Error::Grpc(grpcio::Error::RpcFailure(status)))
+        }));
+        primary.clock = ClockHandle::new(MockClock::auto_advancing());
+        let standby = MockPdClient::new(MockKvClient::with_dispatch_hook(|req: &dyn Any| {
+            if req.is::<kvrpcpb::RawGetRequest>() {
+                let resp = kvrpcpb::RawGetResponse {
+                    value: b"standby".to_vec(),
+                    ..Default::default()
+                };
+                Ok(Box::new(resp) as Box<dyn Any>)
+            } else if req.is::<kvrpcpb::RawScanRequest>() {
+                Ok(Box::new(kvrpcpb::RawScanResponse::default()) as Box<dyn Any>)
+            } else {
+                panic!("writes must not fail over")
+            }
+        }));
+        let client = |rpc: MockPdClient| mock_client(Arc::new(rpc), RawOptions::default());
+        let client = client(primary).with_standby(&client(standby));
+
+        assert_eq!(client.get(vec![1]).await?, Some(b"standby".to_vec()));
+        assert!(client.scan(vec![1]..vec![2], 10).await?.is_empty());
+        assert!(client.put(vec![1], vec![2]).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_raw_value_codec() -> Result<()> {
+        let pd_client = Arc::new(MockPdClient::new(MockKvClient::with_dispatch_hook(
+            move |req: &dyn Any| {
+                if let Some(req) = req.downcast_ref::<kvrpcpb::RawPutRequest>() {
+                    assert_eq!(req.value, b"\x00value".to_vec());
+                    Ok(Box::new(kvrpcpb::RawPutResponse::default()) as Box<dyn Any>)
+                } else if req.downcast_ref::<kvrpcpb::RawGetRequest>().is_some() {
+                    let resp = kvrpcpb::RawGetResponse {
+                        value: b"\x00value".to_vec(),
+                        ..Default::default()
+                    };
+                    Ok(Box::new(resp) as Box<dyn Any>)
+                } else {
+                    unreachable!()
+                }
+            },
+        )));
+        let client = Client {
+            value_codec: Some(Arc::new(Compression::uncompressed())),
+            ..mock_client(pd_client, RawOptions::new().cf(ColumnFamily::Default))
+        };
+        client.put(vec![1], b"value".to_vec()).await?;
+        assert_eq!(client.get(vec![1]).await?, Some(b"value".to_vec()));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_raw_keyspace() -> Result<()> {
+        let pd_client = Arc::new(MockPdClient::new(MockKvClient::with_dispatch_hook(
+            move |req: &dyn Any| {
+                if let Some(req) = req.downcast_ref::<kvrpcpb::RawPutRequest>() {
+                    assert_eq!(req.key, b"r\x00\x00\x01key".to_vec());
+                    assert_eq!(req.ttl, 60);
+                    Ok(Box::new(kvrpcpb::RawPutResponse::default()) as Box<dyn Any>)
+                } else if let Some(req) = req.downcast_ref::<kvrpcpb::RawScanRequest>() {
+                    assert_eq!(req.start_key, b"r\x00\x00\x01".to_vec());
+                    assert_eq!(req.end_key, b"r\x00\x00\x02".to_vec());
+                    let resp = kvrpcpb::RawScanResponse {
+                        kvs: vec![kvrpcpb::KvPair {
+                            key: b"r\x00\x00\x01key".to_vec(),
+                            value: vec![0],
+                            ..Default::default()
+                        }],
+                        ..Default::default()
+                    };
+                    Ok(Box::new(resp) as Box<dyn Any>)
+                } else if req.downcast_ref::<kvrpcpb::RawGetKeyTtlRequest>().is_some() {
+                    let resp = kvrpcpb::RawGetKeyTtlResponse {
+                        region_error: Some(errorpb::Error {
+                            ttl_not_enabled: Some(Default::default()),
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    };
+                    Ok(Box::new(resp) as Box<dyn Any>)
+                } else {
+                    unreachable!()
+                }
+            },
+        )));
+        let client = Client {
+            keyspace: Some(Keyspace::new(1)?),
+            ..mock_client(pd_client, RawOptions::default())
+        };
+        client.put_with_ttl(b"key".to_vec(), vec![0], 60).await?;
+        assert_eq!(
+            client.scan(.., 10).await?,
+            vec![KvPair::new(b"key".to_vec(), vec![0])]
+        );
+        let e = client.get_key_ttl_secs(b"key".to_vec()).await.unwrap_err();
+        assert!(has_error(&e, &|e| matches!(e, Error::TtlNotEnabled { .. })));
+        assert!(matches!(
+            client
+                .coprocessor("example", "0.1.0", vec![..], |_, _| vec![])
+                .await,
+            Err(Error::Unimplemented)
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_raw_namespace() -> Result<()> {
+        let pd_client = Arc::new(MockPdClient::new(MockKvClient::with_dispatch_hook(
+            move |req: &dyn Any| {
+                if let Some(req) = req.downcast_ref::<kvrpcpb::RawPutRequest>() {
+                    assert_eq!(req.key, b"orders/lines/key".to_vec());
+                    Ok(Box::new(kvrpcpb::RawPutResponse::default()) as Box<dyn Any>)
+                } else if let Some(req) = req.downcast_ref::<kvrpcpb::RawScanRequest>() {
+                    assert_eq!(req.start_key, b"orders/".to_vec());
+                    assert_eq!(req.end_key, b"orders0".to_vec());
+                    let resp = kvrpcpb::RawScanResponse {
+                        kvs: vec![kvrpcpb::KvPair {
+                            key: b"orders/key".to_vec(),
+                            value: vec![0],
+                            ..Default::default()
+                        }],
+                        ..Default::default()
+                    };
+                    Ok(Box::new(resp) as Box<dyn Any>)
+                } else if let Some(req) = req.downcast_ref::<kvrpcpb::RawDeleteRangeRequest>() {
+                    assert_eq!(req.start_key, b"orders/".to_vec());
+                    assert_eq!(req.end_key, b"orders0".to_vec());
+                    Ok(Box::new(kvrpcpb::RawDeleteRangeResponse::default()) as Box<dyn Any>)
+                } else {
+                    unreachable!()
+                }
+            },
+        )));
+        let client = mock_client(pd_client, RawOptions::default());
+        let orders = client.namespace("orders");
+        assert_eq!(
+            orders.scan(.., 10).await?,
+            vec![KvPair::new(b"key".to_vec(), vec![0])]
+        );
+        orders.delete_range(..).await?;
+        orders
+            .namespace("lines")
+            .put(b"key".to_vec(), vec![0])
+            .await?;
+        assert!(matches!(
+            orders
+                .coprocessor("example", "0.1.0", vec![..], |_, _| vec![])
+                .await,
+            Err(Error::Unimplemented)
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_raw_scan_from() -> Result<()> {
+        let pd_client = Arc::new(MockPdClient::new(MockKvClient::with_dispatch_hook(
+            move |req: &dyn Any| {
+                if let Some(req) = req.downcast_ref::<kvrpcpb::RawScanRequest>() {
+                    let kvs = [1u8, 2, 3]
+                        .iter()
+                        .map(|k| vec![*k])
+                        .filter(|k| *k >= req.start_key && *k < req.end_key)
+                        .take(req.limit as usize)
+                        .map(|k| kvrpcpb::KvPair {
+                            key: k,
+                            value: vec![0],
+                            ..Default::default()
+                        })
+                        .collect();
+                    let resp = kvrpcpb::RawScanResponse {
+                        kvs,
+                        ..Default::default()
+                    };
+                    Ok(Box::new(resp) as Box<dyn Any>)
+                } else {
+                    unreachable!()
+                }
+            },
+        )));
+        let client = mock_client(pd_client, RawOptions::new().cf(ColumnFamily::Default));
+        let (pairs, token) = client
+            .scan_from(ScanToken::new(vec![1]..vec![5]), 2)
+            .await?;
+        assert_eq!(pairs.len(), 2);
+        let token = token.unwrap();
+        assert_eq!(token, ScanToken::new(vec![2, 0]..vec![5]));
+
+        let (pairs, token) = client.scan_from(token, 2).await?;
+        assert_eq!(pairs, vec![KvPair::new(vec![3], vec![0])]);
+        assert_eq!(token, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_split_region() -> Result<()> {
+        let pd_client = Arc::new(MockPdClient::new(MockKvClient::with_dispatch_hook(
+            move |req: &dyn Any| {
+                if let Some(req) = req.downcast_ref::<kvrpcpb::SplitRegionRequest>() {
+                    assert!(req.is_raw_kv);
+                    let expected: Vec<Vec<u8>> = match req.get_context().region_id {
+                        1 => vec![vec![1], vec![5]],
+                        2 => vec![vec![20]],
+                        _ => unreachable!(),
+                    };
+                    assert_eq!(req.split_keys, expected);
+                    // one new region starts at each split key
+                    let regions = req
+                        .split_keys
+                        .iter()
+                        .rev()
+                        .map(|key| metapb::Region {
+                            id: 100 + key[0] as u64,
+                            start_key: key.clone(),
+                            ..Default::default()
+                        })
+                        .collect();
+                    let resp = kvrpcpb::SplitRegionResponse {
+                        regions,
+                        ..Default::default()
+                    };
+                    Ok(Box::new(resp) as Box<dyn Any>)
+                } else {
+                    unreachable!()
+                }
+            },
+        )));
+        let client = mock_client(pd_client.clone(), RawOptions::new());
+        // [10] starts region 2 already
+        let split_keys = vec![vec![20], vec![5], vec![10], vec![1], vec![5]];
+        let region_ids = client.split_region(split_keys).await?;
+        assert_eq!(region_ids, vec![101, 105, 120]);
+        assert_eq!(*pd_client.scattered.lock().unwrap(), region_ids);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_scan_with_value_threshold() -> Result<()> {
+        let pd_client = Arc::new(MockPdClient::new(MockKvClient::with_dispatch_hook(
+            move |req: &dyn Any| {
+                if let Some(req) = req.downcast_ref::<kvrpcpb::RawScanRequest>() {
+                    // the value of key 2 is oversized
+                    let kvs = vec![(1, 1), (2, 10), (3, 2)]
+                        .into_iter()
+                        .take(req.limit as usize)
+                        .map(|(key, size)| kvrpcpb::KvPair {
+                            key: vec![key],
+                            value: vec![0; size],
+                            ..Default::default()
+                        })
+                        .collect();
+                    let resp = kvrpcpb::RawScanResponse {
+                        kvs,
+                        ..Default::default()
+                    };
+                    Ok(Box::new(resp) as Box<dyn Any>)
+                } else {
+                    unreachable!()
+                }
+            },
+        )));
+        let client = mock_client(pd_client, RawOptions::new());
+        let scanned = client
+            .scan_with_value_threshold(vec![1]..vec![5], 10, 4)
+            .await?;
+        assert_eq!(
+            scanned,
+            vec![
+                ScannedPair::Pair(KvPair::new(vec![1], vec![0])),
+                ScannedPair::Oversized {
+                    key: vec![2].into(),
+                    value_size: 10,
+                },
+                ScannedPair::Pair(KvPair::new(vec![3], vec![0; 2])),
+            ]
+        );
+
+        let client = client.with_options(RawOptions::new().skip_values_larger_than(4));
+        let pairs = client.scan(vec![1]..vec![5], 10).await?;
+        assert_eq!(pairs.len(), 2);
+        // the skipped pair counts against the limit, the scan continues after it
+        let (pairs, token) = client
+            .scan_from(ScanToken::new(vec![1]..vec![5]), 2)
+            .await?;
+        assert_eq!(pairs, vec![KvPair::new(vec![1], vec![0])]);
+        assert_eq!(token, Some(ScanToken::new(vec![2, 0]..vec![5])));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_raw_options() -> Result<()> {
+        let pd_client = Arc::new(MockPdClient::new(MockKvClient::with_dispatch_hook(
+            move |req: &dyn Any| {
+                if let Some(req) = req.downcast_ref::<kvrpcpb::RawScanRequest>() {
+                    let context = req.get_context();
+                    assert_eq!(context.get_priority(), kvrpcpb::CommandPri::Low);
+                    assert_eq!(context.get_region_id(), 1);
+                    assert_eq!(context.resource_group_tag, b"tag");
+                    assert_eq!(req.cf, "write");
+                    let value = if req.key_only { vec![] } else { vec![0] };
+                    let resp = kvrpcpb::RawScanResponse {
+                        kvs: vec![kvrpcpb::KvPair {
+                            key: vec![1],
+                            value,
+                            ..Default::default()
+                        }],
+                        ..Default::default()
+                    };
+                    Ok(Box::new(resp) as Box<dyn Any>)
+                } else {
+                    unreachable!()
+                }
+            },
+        )));
+        let client = mock_client(pd_client, RawOptions::default());
+        let options = RawOptions::new()
+            .priority(CommandPriority::Low)
+            .cf(ColumnFamily::Write)
+            .context_hook(ContextHook::new(|context| {
+                context.resource_group_tag = b"tag".to_vec()
+            }));
+        let pairs = client
+            .with_options(options.clone())
+            .scan(vec![1]..vec![2], 10)
+            .await?;
+        assert_eq!(pairs, vec![KvPair::new(vec![1], vec![0])]);
+        let pairs = client
+            .with_options(options.key_only(true))
+            .scan(vec![1]..vec![2], 10)
+            .await?;
+        assert_eq!(pairs, vec![KvPair::new(vec![1], vec![])]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_raw_retry_options() -> Result<()> {
+        #[derive(Debug)]
+        struct NoDelay;
+
+        impl BackoffStrategy for NoDelay {
+            fn delay(&self, _attempt: u32, _previous_delay: Duration) -> Duration {
+                Duration::from_millis(0)
+            }
+        }
+
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let attempts_cloned = attempts.clone();
+        let pd_client = Arc::new(MockPdClient::new(MockKvClient::with_dispatch_hook(
+            move |req: &dyn Any| {
+                assert!(req.is::<kvrpcpb::RawGetRequest>());
+                attempts_cloned.fetch_add(1, Ordering::SeqCst);
+                let resp = kvrpcpb::RawGetResponse {
+                    region_error: Some(errorpb::Error {
+                        data_is_not_ready: Some(errorpb::DataIsNotReady {
+                            region_id: 1,
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                };
+                Ok(Box::new(resp) as Box<dyn Any>)
+            },
+        )));
+        let client = mock_client(pd_client, RawOptions::default());
+        let retry_options = RetryOptions::new(Backoff::custom(NoDelay, 2), Backoff::no_backoff());
+        let err = client
+            .with_retry_options(retry_options)
+            .get(vec![1])
+            .await
+            .unwrap_err();
+        match err {
+            Error::RequestFailed { source, request } => {
+                assert!(matches!(
+                    *source,
+                    Error::DataIsNotReady { region_id: 1, .. }
+                ));
+                assert_eq!((request.request_type, request.region_id), ("raw_get", 1));
+                assert!(request.region_error.unwrap().has_data_is_not_ready());
+            }
+            other => panic!("unexpected error: {:?}", other),
+        }
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+
+        client
+            .with_retry_options(RetryOptions::none())
+            .get(vec![1])
+            .await
+            .unwrap_err();
+        assert_eq!(attempts.load(Ordering::SeqCst), 4);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_raw_retries_exhausted() -> Result<()> {
+        #[derive(Debug)]
+        struct NoDelay;
+
+        impl BackoffStrategy for NoDelay {
+            fn delay(&self, _attempt: u32, _previous_delay: Duration) -> Duration {
+                Duration::from_millis(0)
+            }
+        }
+
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let attempts_cloned = attempts.clone();
+        let pd_client = Arc::new(MockPdClient::new(MockKvClient::with_dispatch_hook(
+            move |req: &dyn Any| {
+                attempts_cloned.fetch_add(1, Ordering::SeqCst);
+                let region_error = Some(errorpb::Error {
+                    region_not_found: Some(errorpb::RegionNotFound::default()),
+                    ..Default::default()
+                });
+                if req.is::<kvrpcpb::RawGetRequest>() {
+                    let resp = kvrpcpb::RawGetResponse {
+                        region_error,
+                        ..Default::default()
+                    };
+                    Ok(Box::new(resp) as Box<dyn Any>)
+                } else if req.is::<kvrpcpb::RawPutRequest>() {
+                    let resp = kvrpcpb::RawPutResponse {
+                        region_error,
+                        ..Default::default()
+                    };
+                    Ok(Box::new(resp) as Box<dyn Any>)
+                } else {
+                    unreachable!()
+                }
+            },
+        )));
+        let client = mock_client(pd_client, RawOptions::default());
+        // reads fail with their history, writes are retried once more by the callback
+        let retry_options = RetryOptions::new(Backoff::custom(NoDelay, 2), Backoff::no_backoff())
+            .on_read_exhausted(OnRetriesExhausted::History)
+            .on_write_exhausted(OnRetriesExhausted::callback(|_, attempts| {
+                (attempts < 4).then(|| Backoff::custom(NoDelay, 1))
+            }));
+        let client = client.with_retry_options(retry_options);
+
+        match client.get(vec![1]).await {
+            Err(Error::RetriesExhausted {
+                source,
+                attempts: 3,
+                history,
+            }) => {
+                assert!(matches!(*source, Error::RequestFailed { .. }));
+                assert!(source.region_error().is_some());
+                assert_eq!(history.len(), 3);
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+
+        let err = client.put(vec![1], vec![1]).await.unwrap_err();
+        assert!(matches!(err.failed_request(), Some(request) if request.request_type == "raw_put"));
+        assert!(err.region_error().is_some());
+        assert_eq!(attempts.load(Ordering::SeqCst), 7);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_raw_sample_keys() -> Result<()> {
+        let pd_client = Arc::new(MockPdClient::new(MockKvClient::with_dispatch_hook(
+            |_: &dyn Any| panic!("no request is sent to TiKV"),
+        )));
+        let client = mock_client(pd_client, RawOptions::default());
+        let boundaries = vec![Key::from(vec![10]), Key::from(vec![250, 250])];
+        assert_eq!(client.sample_keys(.., 5).await?, boundaries);
+        assert_eq!(client.sample_keys(.., 2).await?, boundaries);
+        // the 3 regions are split in 2 parts
+        assert_eq!(client.sample_keys(.., 1).await?, boundaries[..1]);
+        assert_eq!(client.sample_keys(.., 0).await?, vec![]);
+        // the range ends at a boundary, or within a region
+        let sample = client.sample_keys(..vec![250, 250], 5).await?;
+        assert_eq!(sample, boundaries[..1]);
+        assert_eq!(client.sample_keys(vec![10]..vec![20], 5).await?, vec![]);
+        assert_eq!(client.sample_keys(vec![5].., 5).await?, boundaries);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_raw_deadline() -> Result<()> {
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let attempts_cloned = attempts.clone();
+        // the region is never available
+        let pd_client = Arc::new(MockPdClient::new(MockKvClient::with_dispatch_hook(
+            move |req: &dyn Any| {
+                assert!(req.is::<kvrpcpb::RawGetRequest>());
+                attempts_cloned.fetch_add(1, Ordering::SeqCst);
+                let resp = kvrpcpb::RawGetResponse {
+                    region_error: Some(errorpb::Error {
+                        region_not_found: Some(errorpb::RegionNotFound { region_id: 1 }),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                };
+                Ok(Box::new(resp) as Box<dyn Any>)
+            },
+        )));
+        let client = mock_client(pd_client, RawOptions::default());
+        let backoff = Backoff::no_jitter_backoff(20, 20, 1000);
+        let err = client
+            .with_retry_options(RetryOptions::new(backoff, Backoff::no_backoff()))
+            .with_deadline(Duration::from_millis(100))
+            .get(vec![1])
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            Error::DeadlineExceeded { timeout } if timeout == Duration::from_millis(100)
+        ));
+        let sent = attempts.load(Ordering::SeqCst);
+        assert!(sent > 1, "{} attempts", sent);
+
+        // the retries stop with the operation
+        futures_timer::Delay::new(Duration::from_millis(100)).await;
+        assert_eq!(attempts.load(Ordering::SeqCst), sent);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_raw_scan_stream_with_regions() -> Result<()> {
+        let pd_client = Arc::new(MockPdClient::new(MockKvClient::with_dispatch_hook(
+            move |req: &dyn Any| {
+                let req: &kvrpcpb::RawScanRequest = req.downcast_ref().unwrap();
+                let kvs = [1u8, 2, 11]
+                    .iter()
+                    .filter(|key| {
+                        vec![**key] >= req.start_key
+                            && (req.end_key.is_empty() || vec![**key] < req.end_key)
+                    })
+                    .map(|key| kvrpcpb::KvPair {
+                        key: vec![*key],
+                        value: vec![*key],
+                        ..Default::default()
+                    })
+                    .collect();
+                let resp = kvrpcpb::RawScanResponse {
+                    kvs,
+                    ..Default::default()
+                };
+                Ok(Box::new(resp) as Box<dyn Any>)
+            },
+        )));
+        let client = mock_client(pd_client, RawOptions::default());
+        let items: Vec<ScanItem> = client
+            .scan_stream_with_regions(.., 10, ScanPrefetch::default())?
+            .try_collect()
+            .await?;
+        let pair = |key: u8| KvPair::new(vec![key], vec![key]);
+        assert_eq!(
+            items,
+            vec![
+                ScanItem::Region(1),
+                ScanItem::Pair(1, pair(1)),
+                ScanItem::Pair(1, pair(2)),
+                ScanItem::Region(2),
+                ScanItem::Pair(2, pair(11)),
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_raw_progress() -> Result<()> {
+        let pd_client = Arc::new(MockPdClient::new(MockKvClient::with_dispatch_hook(
+            move |req: &dyn Any| {
+                if req
+                    .downcast_ref::<kvrpcpb::RawDeleteRangeRequest>()
+                    .is_some()
+                {
+                    let resp = kvrpcpb::RawDeleteRangeResponse::default();
+                    Ok(Box::new(resp) as Box<dyn Any>)
+                } else {
+                    unreachable!()
+                }
+            },
+        )));
+        let reported = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let callback = {
+            let reported = reported.clone();
+            ProgressCallback::new(move |progress| reported.lock().unwrap().push(progress.clone()))
+        };
+        let client = mock_client(pd_client, RawOptions::new().progress(callback));
+        client.delete_range(vec![5]..vec![251]).await?;
+
+        let mut reported = reported.lock().unwrap().clone();
+        assert_eq!(reported.len(), 3);
+        assert!(reported.iter().all(|progress| progress.regions_total == 3));
+        reported.sort_by_key(|progress| progress.regions_completed);
+        assert_eq!(
+            reported
+                .iter()
+                .map(|progress| progress.regions_completed)
+                .collect::<Vec<_>>(),
+            vec![1, 2, 3]
+        );
+        let mut keys: Vec<Key> = reported.into_iter().map(|p| p.current_key).collect();
+        keys.sort();
+        assert_eq!(
+            keys,
+            vec![Key::EMPTY, vec![10].into(), vec![250, 250].into()]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_raw_delete_prefix() -> Result<()> {
+        let pd_client = Arc::new(MockPdClient::new(MockKvClient::with_dispatch_hook(
+            move |req: &dyn Any| {
+                if let Some(req) = req.downcast_ref::<kvrpcpb::RawDeleteRangeRequest>() {
+                    if req.start_key == vec![10, 1] {
+                        assert_eq!(req.end_key, vec![10, 2]);
+                    }
+                    let resp = kvrpcpb::RawDeleteRangeResponse::default();
+                    Ok(Box::new(resp) as Box<dyn Any>)
+                } else {
+                    unreachable!()
+                }
+            },
+        )));
+        let client = mock_client(pd_client, RawOptions::default());
+        let summary = client.delete_prefix(vec![10, 1]).await?;
+        assert_eq!(
+            summary,
+            DeleteSummary {
+                keys: None,
+                regions: 1
+            }
+        );
+        assert!(matches!(
+            client.delete_prefix(vec![]).await,
+            Err(Error::EmptyPrefix)
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_raw_atomic_delete_prefix() -> Result<()> {
+        let keys = Arc::new(std::sync::Mutex::new(vec![
+            vec![10, 1, 1],
+            vec![10, 1, 2],
+            vec![10, 2],
+        ]));
+        let keys_cloned = keys.clone();
+        let pd_client = Arc::new(MockPdClient::new(MockKvClient::with_dispatch_hook(
+            move |req: &dyn Any| {
+                let mut keys = keys_cloned.lock().unwrap();
+                if let Some(req) = req.downcast_ref::<kvrpcpb::RawScanRequest>() {
+                    let kvs = keys
+                        .iter()
+                        .filter(|k| {
+                            **k >= req.start_key && (req.end_key.is_empty() || **k < req.end_key)
+                        })
+                        .map(|k| kvrpcpb::KvPair {
+                            key: k.clone(),
+                            ..Default::default()
+                        })
+                        .collect();
+                    let resp = kvrpcpb::RawScanResponse {
+                        kvs,
+                        ..Default::default()
+                    };
+                    Ok(Box::new(resp) as Box<dyn Any>)
+                } else if let Some(req) = req.downcast_ref::<kvrpcpb::RawBatchDeleteRequest>() {
+                    // deletes are ordered with the compare-and-swaps of the atomic mode
+                    assert!(req.for_cas);
+                    keys.retain(|k| !req.keys.contains(k));
+                    let resp = kvrpcpb::RawBatchDeleteResponse::default();
+                    Ok(Box::new(resp) as Box<dyn Any>)
+                } else {
+                    unreachable!()
+                }
+            },
+        )));
+        let client = mock_client(pd_client, RawOptions::default()).with_atomic_for_cas();
+        let summary = client.delete_prefix(vec![10, 1]).await?;
+        assert_eq!(
+            summary,
+            DeleteSummary {
+                keys: Some(2),
+                regions: 1
+            }
+        );
+        assert_eq!(*keys.lock().unwrap(), vec![vec![10, 2]]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_raw_get_key_ttl() -> Result<()> {
+        let pd_client = Arc::new(MockPdClient::new(MockKvClient::with_dispatch_hook(
+            move |req: &dyn Any| {
+                if let Some(req) = req.downcast_ref::<kvrpcpb::RawGetKeyTtlRequest>() {
+                    // keys starting with 0 don't exist
+                    let resp = kvrpcpb::RawGetKeyTtlResponse {
+                        ttl: 30,
+                        not_found: req.key[0] == 0,
+                        ..Default::default()
+                    };
+                    Ok(Box::new(resp) as Box<dyn Any>)
+                } else {
+                    unreachable!()
+                }
+            },
+        )));
+        let client = mock_client(pd_client, RawOptions::default());
+        assert_eq!(
+            client.get_key_ttl(vec![1]).await?,
+            Some(Duration::from_secs(30))
         );
-        let plan = crate::request::PlanBuilder::new(self.rpc.clone(), req)
-            .retry_multi_region(DEFAULT_REGION_BACKOFF)
-            .merge(CollectSingle)
-            .post_process_default()
-            .plan();
-        plan.execute().await
+        assert_eq!(client.get_key_ttl(vec![0]).await?, None);
+        Ok(())
     }
 
-    pub async fn coprocessor(
-        &self,
-        copr_name: impl Into<String>,
-        copr_version_req: impl Into<String>,
-        ranges: impl IntoIterator<Item = impl Into<BoundRange>>,
-        request_builder: impl Fn(metapb::Region, Vec<Range<Key>>) -> Vec<u8> + Send + Sync + 'static,
-    ) -> Result<Vec<(Vec<u8>, Vec<Range<Key>>)>> {
-        let copr_version_req = copr_version_req.into();
-        semver::VersionReq::from_str(&copr_version_req)?;
-        let req = new_raw_coprocessor_request(
-            copr_name.into(),
-            copr_version_req,
-            ranges.into_iter().map(Into::into),
-            request_builder,
+    #[tokio::test]
+    async fn test_raw_self_check() {
+        let pairs = Arc::new(std::sync::Mutex::new(HashMap::new()));
+        let pairs_cloned = pairs.clone();
+        let pd_client = Arc::new(MockPdClient::new(MockKvClient::with_dispatch_hook(
+            move |req: &dyn Any| {
+                let mut pairs = pairs_cloned.lock().unwrap();
+                if let Some(req) = req.downcast_ref::<kvrpcpb::RawPutRequest>() {
+                    pairs.insert(req.key.clone(), req.value.clone());
+                    Ok(Box::new(kvrpcpb::RawPutResponse::default()) as Box<dyn Any>)
+                } else if let Some(req) = req.downcast_ref::<kvrpcpb::RawGetRequest>() {
+                    let resp = match pairs.get(&req.key) {
+                        // the sentinel key ending with 0x40 reads a different value
+                        Some(value) if req.key.ends_with(&[0x40]) => kvrpcpb::RawGetResponse {
+                            value: [value.clone(), vec![0]].concat(),
+                            ..Default::default()
+                        },
+                        Some(value) => kvrpcpb::RawGetResponse {
+                            value: value.clone(),
+                            ..Default::default()
+                        },
+                        None => kvrpcpb::RawGetResponse {
+                            not_found: true,
+                            ..Default::default()
+                        },
+                    };
+                    Ok(Box::new(resp) as Box<dyn Any>)
+                } else if let Some(req) = req.downcast_ref::<kvrpcpb::RawDeleteRequest>() {
+                    pairs.remove(&req.key);
+                    Ok(Box::new(kvrpcpb::RawDeleteResponse::default()) as Box<dyn Any>)
+                } else {
+                    unreachable!()
+                }
+            },
+        )));
+        let client = mock_client(pd_client, RawOptions::default());
+        let report = client.self_check().await;
+        assert_eq!(report.steps.len(), 20);
+        assert_eq!(report.regions(), vec![2]);
+        let failures: Vec<&SelfCheckStep> = report.failures().collect();
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].name, "get");
+        assert_eq!(
+            failures[0].key,
+            Key::from(b"tikv_client_self_check/\x40".to_vec())
         );
-        let plan = crate::request::PlanBuilder::new(self.rpc.clone(), req)
-            .preserve_shard()
-            .retry_multi_region(DEFAULT_REGION_BACKOFF)
-            .post_process_default()
-            .plan();
-        plan.execute().await
+        assert!(!report.is_ok());
+        // the sentinel keys are deleted
+        assert!(pairs.lock().unwrap().is_empty());
+        assert_eq!(report.to_string().lines().count(), 20);
     }
 
-    async fn scan_inner(
-        &self,
-        range: impl Into<BoundRange>,
-        limit: u32,
-        key_only: bool,
-    ) -> Result<Vec<KvPair>> {
-        if limit > MAX_RAW_KV_SCAN_LIMIT {
-            return Err(Error::MaxScanLimitExceeded {
-                limit,
-                max_limit: MAX_RAW_KV_SCAN_LIMIT,
-            });
-        }
-
-        let request = new_raw_scan_request(range.into(), limit, key_only, self.cf.clone());
-        let plan = crate::request::PlanBuilder::new(self.rpc.clone(), request)
-            .retry_multi_region(DEFAULT_REGION_BACKOFF)
-            .merge(Collect)
-            .plan();
-        let res = plan.execute().await;
-        res.map(|mut s| {
-            s.truncate(limit as usize);
-            s
-        })
+    #[tokio::test]
+    async fn test_raw_size_limits() -> Result<()> {
+        let pd_client = Arc::new(MockPdClient::new(MockKvClient::with_dispatch_hook(
+            move |req: &dyn Any| {
+                if req.downcast_ref::<kvrpcpb::RawPutRequest>().is_some() {
+                    Ok(Box::new(kvrpcpb::RawPutResponse::default()) as Box<dyn Any>)
+                } else {
+                    unreachable!()
+                }
+            },
+        )));
+        let client = mock_client(pd_client, RawOptions::default()).with_options(
+            RawOptions::new().size_limits(SizeLimits {
+                max_key_size: 2,
+                max_value_size: 2,
+            }),
+        );
+        client.put(vec![1, 2], vec![1, 2]).await?;
+        // oversized writes fail before they are sent
+        assert!(matches!(
+            client.put(vec![1, 2, 3], vec![]).await,
+            Err(Error::KeyTooLarge { key, size: 3, limit: 2 }) if key == vec![1, 2, 3]
+        ));
+        assert!(matches!(
+            client.batch_put(vec![(vec![1], vec![1]), (vec![2], vec![0; 3])]).await,
+            Err(Error::ValueTooLarge { key, size: 3, limit: 2 }) if key == vec![2]
+        ));
+        assert!(matches!(
+            client.batch_delete(vec![vec![0u8; 3]]).await,
+            Err(Error::KeyTooLarge { .. })
+        ));
+        Ok(())
     }
 
-    async fn batch_scan_inner(
-        &self,
-        ranges: impl IntoIterator<Item = impl Into<BoundRange>>,
-        each_limit: u32,
-        key_only: bool,
-    ) -> Result<Vec<KvPair>> {
-        if each_limit > MAX_RAW_KV_SCAN_LIMIT {
-            return Err(Error::MaxScanLimitExceeded {
-                limit: each_limit,
-                max_limit: MAX_RAW_KV_SCAN_LIMIT,
-            });
-        }
+    #[tokio::test]
+    async fn test_raw_max_result_bytes() -> Result<()> {
+        // every pair is 11 bytes
+        let pair = |k: u8| kvrpcpb::KvPair {
+            key: vec![k],
+            value: vec![0; 10],
+            ..Default::default()
+        };
+        let batch_gets = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let batch_gets_cloned = batch_gets.clone();
+        let pd_client = Arc::new(MockPdClient::new(MockKvClient::with_dispatch_hook(
+            move |req: &dyn Any| {
+                if let Some(req) = req.downcast_ref::<kvrpcpb::RawScanRequest>() {
+                    let kvs = [1u8, 2, 3]
+                        .iter()
+                        .filter(|k| vec![**k] >= req.start_key && vec![**k] < req.end_key)
+                        .take(req.limit as usize)
+                        .map(|k| pair(*k))
+                        .collect();
+                    let resp = kvrpcpb::RawScanResponse {
+                        kvs,
+                        ..Default::default()
+                    };
+                    Ok(Box::new(resp) as Box<dyn Any>)
+                } else if let Some(req) = req.downcast_ref::<kvrpcpb::RawBatchGetRequest>() {
+                    batch_gets_cloned.lock().unwrap().push(req.keys.clone());
+                    let resp = kvrpcpb::RawBatchGetResponse {
+                        pairs: req.keys.iter().map(|k| pair(k[0])).collect(),
+                        ..Default::default()
+                    };
+                    Ok(Box::new(resp) as Box<dyn Any>)
+                } else {
+                    unreachable!()
+                }
+            },
+        )));
+        let client = mock_client(pd_client, RawOptions::new().cf(ColumnFamily::Default));
+        assert_eq!(client.scan(vec![1]..vec![5], 10).await?.len(), 3);
 
-        let request = new_raw_batch_scan_request(
-            ranges.into_iter().map(Into::into),
-            each_limit,
-            key_only,
-            self.cf.clone(),
+        let client = client.with_max_result_bytes(25);
+        match client.scan(vec![1]..vec![5], 10).await {
+            Err(Error::ResultTooLarge {
+                max_bytes: 25,
+                resume_key,
+            }) => assert_eq!(resume_key, vec![3]),
+            r => panic!("unexpected result: {:?}", r),
+        }
+        match client.batch_get(vec![vec![3], vec![2], vec![1]]).await {
+            Err(Error::ResultTooLarge { resume_key, .. }) => assert_eq!(resume_key, vec![3]),
+            r => panic!("unexpected result: {:?}", r),
+        }
+        // the regions are read one after the other, up to the one exceeding the limit
+        batch_gets.lock().unwrap().clear();
+        match client
+            .batch_get(vec![vec![251], vec![11], vec![2], vec![1], vec![12]])
+            .await
+        {
+            Err(Error::ResultTooLarge { resume_key, .. }) => assert_eq!(resume_key, vec![11]),
+            r => panic!("unexpected result: {:?}", r),
+        }
+        assert_eq!(
+            *batch_gets.lock().unwrap(),
+            vec![vec![vec![1], vec![2]], vec![vec![11], vec![12]]]
         );
-        let plan = crate::request::PlanBuilder::new(self.rpc.clone(), request)
-            .retry_multi_region(DEFAULT_REGION_BACKOFF)
-            .merge(Collect)
-            .plan();
-        plan.execute().await
-    }
 
-    fn assert_non_atomic(&self) -> Result<()> {
-        (!self.atomic).then(|| ()).ok_or(Error::UnsupportedMode)
+        let (pairs, token) = client
+            .scan_from(ScanToken::new(vec![1]..vec![5]), 10)
+            .await?;
+        assert_eq!(pairs.len(), 2);
+        assert_eq!(token, Some(ScanToken::new(vec![3]..vec![5])));
+        let client = client.with_max_result_bytes(5);
+        assert!(client
+            .scan_from(ScanToken::new(vec![1]..vec![5]), 10)
+            .await
+            .is_err());
+        Ok(())
     }
 
-    fn assert_atomic(&self) -> Result<()> {
-        self.atomic.then(|| ()).ok_or(Error::UnsupportedMode)
+    #[tokio::test]
+    async fn test_raw_timeout() -> Result<()> {
+        let pd_client = Arc::new(MockPdClient::new(MockKvClient::with_dispatch_hook(
+            move |req: &dyn Any| {
+                let req = req.downcast_ref::<kvrpcpb::RawGetRequest>().unwrap();
+                // TiKV stops processing the request once the timeout passes
+                assert_eq!(req.get_context().max_execution_duration_ms, 100);
+                if req.key == vec![1] {
+                    let status = grpcio::RpcStatus::new(grpcio::RpcStatusCode::DEADLINE_EXCEEDED);
+                    Err(Error::Grpc(grpcio::Error::RpcFailure(status)))
+                } else {
+                    let resp = kvrpcpb::RawGetResponse {
+                        value: vec![2],
+                        ..Default::default()
+                    };
+                    Ok(Box::new(resp) as Box<dyn Any>)
+                }
+            },
+        )));
+        let client = mock_client(
+            pd_client,
+            RawOptions::new().timeout(Duration::from_millis(100)),
+        );
+        assert_eq!(client.get(vec![2]).await?, Some(vec![2]));
+        let err = client.get(vec![1]).await.unwrap_err();
+        assert!(has_error(&err, &|e| matches!(
+            e,
+            Error::RequestTimeout { timeout } if *timeout == Duration::from_millis(100)
+        )));
+        Ok(())
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::{
-        mock::{MockKvClient, MockPdClient},
-        Result,
-    };
-    use std::{any::Any, sync::Arc};
-    use tikv_client_proto::kvrpcpb;
 
     #[tokio::test]
     async fn test_raw_coprocessor() -> Result<()> {
@@ -643,10 +3905,8 @@ mod tests {
             },
         )));
         let client = Client {
-            rpc: pd_client,
-            cf: Some(ColumnFamily::Default),
-            atomic: false,
             logger,
+            ..mock_client(pd_client, RawOptions::new().cf(ColumnFamily::Default))
         };
         let resps = client
             .coprocessor(
@@ -682,4 +3942,355 @@ mod tests {
         );
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_raw_verify_checksums() -> Result<()> {
+        fn stored_pairs(start: &[u8], end: &[u8]) -> Vec<KvPair> {
+            (1..5u8)
+                .map(|i| KvPair::new(vec![i], vec![i]))
+                .filter(|pair| {
+                    let key: &[u8] = pair.key().into();
+                    key >= start && (end.is_empty() || key < end)
+                })
+                .collect()
+        }
+
+        let written = AtomicBool::new(false);
+        let pd_client = Arc::new(MockPdClient::new(MockKvClient::with_dispatch_hook(
+            move |req: &dyn Any| {
+                if let Some(req) = req.downcast_ref::<kvrpcpb::RawGetRequest>() {
+                    // the value of key 3 is corrupted, and key 4 is written after it is first read
+                    let value = match req.key[0] {
+                        3 => vec![9],
+                        4 if !written.swap(true, Ordering::SeqCst) => vec![],
+                        i if i < 5 => vec![i],
+                        _ => vec![],
+                    };
+                    let resp = kvrpcpb::RawGetResponse {
+                        not_found: value.is_empty(),
+                        value,
+                        ..Default::default()
+                    };
+                    Ok(Box::new(resp) as Box<dyn Any>)
+                } else if let Some(req) = req.downcast_ref::<kvrpcpb::RawScanRequest>() {
+                    let kvs = stored_pairs(&req.start_key, &req.end_key)
+                        .into_iter()
+                        .take(req.limit as usize)
+                        .map(Into::into)
+                        .collect();
+                    let resp = kvrpcpb::RawScanResponse {
+                        kvs,
+                        ..Default::default()
+                    };
+                    Ok(Box::new(resp) as Box<dyn Any>)
+                } else if let Some(req) = req.downcast_ref::<kvrpcpb::RawChecksumRequest>() {
+                    let checksum = req
+                        .ranges
+                        .iter()
+                        .map(|range| stored_pairs(&range.start_key, &range.end_key))
+                        .fold(RawChecksum::default(), |acc, pairs| {
+                            acc ^ RawChecksum::of_pairs(&pairs)
+                        });
+                    let resp = kvrpcpb::RawChecksumResponse {
+                        checksum: checksum.checksum,
+                        total_kvs: checksum.total_kvs,
+                        total_bytes: checksum.total_bytes,
+                        ..Default::default()
+                    };
+                    Ok(Box::new(resp) as Box<dyn Any>)
+                } else {
+                    unreachable!()
+                }
+            },
+        )));
+        let client = mock_client(pd_client, RawOptions::new().verify_checksums(1));
+        assert_eq!(client.get(vec![1]).await?, Some(vec![1]));
+        assert_eq!(client.get(vec![5]).await?, None);
+        assert!(matches!(
+            client.get(vec![3]).await,
+            Err(Error::ChecksumMismatch { .. })
+        ));
+        // the write between the read and the checksum is read again
+        assert_eq!(client.get(vec![4]).await?, Some(vec![4]));
+        // only the scanned part of the range is verified
+        assert_eq!(
+            client.scan(vec![1]..vec![5], 2).await?,
+            vec![KvPair::new(vec![1], vec![1]), KvPair::new(vec![2], vec![2])]
+        );
+        assert_eq!(client.scan(vec![2]..vec![6], 10).await?.len(), 3);
+
+        let options = RawOptions::new().verify_checksums(1).cf(ColumnFamily::Lock);
+        let client = client.with_options(options);
+        // the reads of other column families are not verified
+        assert_eq!(client.get(vec![3]).await?, Some(vec![9]));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_raw_region_by_id() -> Result<()> {
+        let client = mock_client(Arc::new(MockPdClient::default()), RawOptions::default());
+        let region = client.region_by_id(2).await?;
+        assert_eq!(region.range(), (vec![10].into(), vec![250, 250].into()));
+        assert!(client.region_by_id(4).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_raw_batch_get_order() -> Result<()> {
+        let request_sizes = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let request_sizes_cloned = request_sizes.clone();
+        let pd_client = Arc::new(MockPdClient::new(MockKvClient::with_dispatch_hook(
+            move |req: &dyn Any| {
+                let req = req.downcast_ref::<kvrpcpb::RawBatchGetRequest>().unwrap();
+                request_sizes_cloned.lock().unwrap().push(req.keys.len());
+                // keys with an odd first byte exist, the pairs are returned in reverse order
+                let pairs = req
+                    .keys
+                    .iter()
+                    .rev()
+                    .filter(|key| key[0] % 2 == 1)
+                    .map(|key| kvrpcpb::KvPair {
+                        key: key.clone(),
+                        value: key.clone(),
+                        ..Default::default()
+                    })
+                    .collect();
+                let resp = kvrpcpb::RawBatchGetResponse {
+                    pairs,
+                    ..Default::default()
+                };
+                Ok(Box::new(resp) as Box<dyn Any>)
+            },
+        )));
+        let client = mock_client(
+            pd_client,
+            RawOptions::new()
+                .batch_get_chunk_size(2)
+                .batch_get_concurrency(1),
+        );
+        let keys = vec![
+            vec![11],
+            vec![3],
+            vec![5],
+            vec![2],
+            vec![3],
+            vec![12],
+            vec![13],
+        ];
+
+        let pairs = client.batch_get(keys.clone()).await?;
+        let found: Vec<Key> = pairs.into_iter().map(KvPair::into_key).collect();
+        let expected: Vec<Key> = vec![vec![11], vec![3], vec![5], vec![3], vec![13]]
+            .into_iter()
+            .map(Into::into)
+            .collect();
+        assert_eq!(found, expected);
+
+        let values = client.batch_get_values(keys.clone()).await?;
+        let expected: Vec<Option<Value>> = keys
+            .iter()
+            .map(|key| Some(key.clone()).filter(|key| key[0] % 2 == 1))
+            .collect();
+        assert_eq!(values, expected);
+
+        // the distinct keys of each region are requested in chunks of two
+        let mut request_sizes = request_sizes.lock().unwrap().clone();
+        request_sizes.sort_unstable();
+        assert_eq!(request_sizes, vec![1, 1, 1, 1, 2, 2, 2, 2]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_disjoint_segments() {
+        let key = |k: u8| Key::from(vec![k]);
+        let ranges = vec![
+            (key(3), Some(key(8))),
+            (key(1), Some(key(5))),
+            (key(3), Some(key(8))),
+            (key(9), Some(key(9))),
+            (key(20), None),
+            (key(30), Some(key(40))),
+        ];
+        assert_eq!(
+            disjoint_segments(&ranges),
+            vec![
+                (key(1), Some(key(3))),
+                (key(3), Some(key(5))),
+                (key(5), Some(key(8))),
+                (key(20), Some(key(30))),
+                (key(30), Some(key(40))),
+                (key(40), None),
+            ]
+        );
+        assert!(disjoint_segments(&[]).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_raw_batch_scan_overlapping_ranges() -> Result<()> {
+        let scanned = Arc::new(std::sync::Mutex::new(0));
+        let scanned_cloned = scanned.clone();
+        let pd_client = Arc::new(MockPdClient::new(MockKvClient::with_dispatch_hook(
+            move |req: &dyn Any| {
+                let req = req.downcast_ref::<kvrpcpb::RawBatchScanRequest>().unwrap();
+                let kvs: Vec<kvrpcpb::KvPair> = req
+                    .ranges
+                    .iter()
+                    .flat_map(|range| {
+                        (1..=9u8)
+                            .map(|k| vec![k])
+                            .filter(move |k| {
+                                *k >= range.start_key
+                                    && (range.end_key.is_empty() || *k < range.end_key)
+                            })
+                            .take(req.each_limit as usize)
+                    })
+                    .map(|k| kvrpcpb::KvPair {
+                        key: k.clone(),
+                        value: k,
+                        ..Default::default()
+                    })
+                    .collect();
+                *scanned_cloned.lock().unwrap() += kvs.len();
+                let resp = kvrpcpb::RawBatchScanResponse {
+                    kvs,
+                    ..Default::default()
+                };
+                Ok(Box::new(resp) as Box<dyn Any>)
+            },
+        )));
+        let client = mock_client(pd_client, RawOptions::default());
+        let ranges = vec![vec![3]..vec![8], vec![1]..vec![5], vec![3]..vec![8]];
+        let keys = client.batch_scan_keys(ranges, 10).await?;
+        let expected: Vec<Key> = [3, 4, 5, 6, 7, 1, 2, 3, 4, 3, 4, 5, 6, 7]
+            .iter()
+            .map(|k| Key::from(vec![*k]))
+            .collect();
+        assert_eq!(keys, expected);
+        // each key is scanned once
+        assert_eq!(*scanned.lock().unwrap(), 7);
+
+        // a limit applies to each segment of a range, so no pairs of a range are missed
+        let keys = client
+            .batch_scan_keys(vec![vec![1]..vec![5], vec![3]..vec![5]], 1)
+            .await?;
+        let expected: Vec<Key> = [1, 3, 3].iter().map(|k| Key::from(vec![*k])).collect();
+        assert_eq!(keys, expected);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_raw_replica_read() -> Result<()> {
+        let leader = Arc::new(MockPdClient::new(MockKvClient::with_dispatch_hook(
+            |req: &dyn Any| {
+                if req.is::<kvrpcpb::RawPutRequest>() {
+                    Ok(Box::new(kvrpcpb::RawPutResponse::default()) as Box<dyn Any>)
+                } else {
+                    panic!("reads must be sent to replicas")
+                }
+            },
+        )));
+        let replica = Arc::new(MockPdClient::new(MockKvClient::with_dispatch_hook(
+            |req: &dyn Any| {
+                let resp = if req.is::<kvrpcpb::RawGetRequest>() {
+                    Box::new(kvrpcpb::RawGetResponse {
+                        value: b"replica".to_vec(),
+                        ..Default::default()
+                    }) as Box<dyn Any>
+                } else if req.is::<kvrpcpb::RawScanRequest>() {
+                    Box::new(kvrpcpb::RawScanResponse::default()) as Box<dyn Any>
+                } else {
+                    panic!("writes must be sent to leaders")
+                };
+                Ok(resp)
+            },
+        )));
+        let client = Client {
+            replica_rpc: Some(replica),
+            ..mock_client(leader, RawOptions::default())
+        };
+        assert_eq!(client.get(vec![1]).await?, Some(b"replica".to_vec()));
+        assert!(client.scan(vec![1]..vec![2], 10).await?.is_empty());
+        client.put(vec![1], vec![2]).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_raw_batch_get_in_cfs() -> Result<()> {
+        let pd_client = Arc::new(MockPdClient::new(MockKvClient::with_dispatch_hook(
+            move |req: &dyn Any| {
+                let req = req.downcast_ref::<kvrpcpb::RawBatchGetRequest>().unwrap();
+                // the key exists in the lock and write column families, with the name as value
+                let pairs = match req.cf.as_str() {
+                    "lock" | "write" => req
+                        .keys
+                        .iter()
+                        .map(|key| kvrpcpb::KvPair {
+                            key: key.clone(),
+                            value: req.cf.as_bytes().to_vec(),
+                            ..Default::default()
+                        })
+                        .collect(),
+                    _ => Vec::new(),
+                };
+                let resp = kvrpcpb::RawBatchGetResponse {
+                    pairs,
+                    ..Default::default()
+                };
+                Ok(Box::new(resp) as Box<dyn Any>)
+            },
+        )));
+        let client = mock_client(pd_client, RawOptions::new());
+        let cfs = vec![
+            ColumnFamily::Default,
+            ColumnFamily::Lock,
+            ColumnFamily::Write,
+        ];
+        let values = client.batch_get_in_cfs(vec![vec![1]], cfs).await?;
+        assert_eq!(
+            values,
+            vec![
+                (ColumnFamily::Default, vec![None]),
+                (ColumnFamily::Lock, vec![Some(b"lock".to_vec())]),
+                (ColumnFamily::Write, vec![Some(b"write".to_vec())]),
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_raw_key_validators() -> Result<()> {
+        let puts = Arc::new(AtomicUsize::new(0));
+        let puts_cloned = puts.clone();
+        let mut pd_client =
+            MockPdClient::new(MockKvClient::with_dispatch_hook(move |req: &dyn Any| {
+                let req = req.downcast_ref::<kvrpcpb::RawPutRequest>().unwrap();
+                assert_eq!(req.key, b"ns/\x01".to_vec());
+                puts_cloned.fetch_add(1, Ordering::SeqCst);
+                Ok(Box::new(kvrpcpb::RawPutResponse::default()) as Box<dyn Any>)
+            }));
+        // the validator checks the keys before they are prefixed by the namespace
+        pd_client.key_validators = vec![Arc::new(|key: &Key| {
+            let key: &[u8] = key.into();
+            if key.starts_with(&[0xff]) {
+                Err("reserved".to_owned())
+            } else {
+                Ok(())
+            }
+        })];
+        let client = Client {
+            namespace: Some(Namespace::new(None, "ns")),
+            ..mock_client(Arc::new(pd_client), RawOptions::new())
+        };
+        client.put(vec![1], vec![1]).await?;
+        assert!(matches!(
+            client.put(vec![0xff], vec![1]).await,
+            Err(Error::KeyRejected { key, reason }) if key == vec![0xff] && reason == "reserved"
+        ));
+        assert!(matches!(
+            client.batch_delete(vec![vec![1], vec![0xff, 1]]).await,
+            Err(Error::KeyRejected { .. })
+        ));
+        assert_eq!(puts.load(Ordering::SeqCst), 1);
+        Ok(())
+    }
 }
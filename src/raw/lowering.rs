@@ -95,6 +95,19 @@ pub fn new_cas_request(
     requests::new_cas_request(key.into(), value, previous_value, cf)
 }
 
+pub fn new_raw_get_key_ttl_request(
+    key: Key,
+    cf: Option<ColumnFamily>,
+) -> kvrpcpb::RawGetKeyTtlRequest {
+    requests::new_raw_get_key_ttl_request(key.into(), cf)
+}
+
+pub fn new_raw_checksum_request(
+    ranges: impl Iterator<Item = BoundRange>,
+) -> kvrpcpb::RawChecksumRequest {
+    requests::new_raw_checksum_request(ranges.map(Into::into).collect())
+}
+
 pub fn new_raw_coprocessor_request(
     copr_name: String,
     copr_version_req: String,
@@ -9,13 +9,24 @@
 //!
 //! **Warning:** It is not advisable to use both raw and transactional functionality in the same keyspace.
 
-pub use self::client::Client;
-use crate::Error;
-use std::{convert::TryFrom, fmt};
+pub use self::{
+    checksum::RawChecksum,
+    client::Client,
+    self_check::{SelfCheckReport, SelfCheckStep},
+};
+use crate::{
+    backoff::DEFAULT_REGION_BACKOFF, config::SizeLimits, Backoff, BoundRange, ContextHook, Error,
+    Key, KvPair, OnRetriesExhausted, ProgressCallback, RetryOptions, Value,
+};
+use serde_derive::{Deserialize, Serialize};
+use std::{convert::TryFrom, fmt, time::Duration};
+use tikv_client_proto::kvrpcpb;
 
+mod checksum;
 mod client;
 pub mod lowering;
 mod requests;
+mod self_check;
 
 /// A [`ColumnFamily`](ColumnFamily) is an optional parameter for [`raw::Client`](Client) requests.
 ///
@@ -81,6 +92,378 @@ impl fmt::Display for ColumnFamily {
     }
 }
 
+/// What a TiKV cluster supports for raw requests, see
+/// [`detect_api_version`](Client::detect_api_version).
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ApiVersion {
+    /// Raw requests without TTL, i.e., TiKV runs with `api-version = 1` and TTL is not enabled.
+    V1,
+    /// Raw requests with TTL, i.e., TiKV runs with `api-version = 1` and `enable-ttl = true`.
+    V1Ttl,
+    /// Raw requests in keyspaces with TTL, i.e., TiKV runs with `api-version = 2`. Requests must
+    /// use a keyspace, see [`with_keyspace`](Client::with_keyspace).
+    V2,
+}
+
+impl ApiVersion {
+    /// Whether raw requests can set a TTL.
+    pub fn supports_ttl(self) -> bool {
+        self != ApiVersion::V1
+    }
+
+    /// Whether raw requests can use keyspaces.
+    pub fn supports_keyspaces(self) -> bool {
+        self == ApiVersion::V2
+    }
+}
+
+/// Options for the requests of a raw [`Client`], the raw counterpart of
+/// [`ReadOptions`](crate::ReadOptions).
+///
+/// The options of a call are set with [`Client::with_options`], which cheaply creates a client
+/// for the call.
+///
+/// # Examples
+///
+/// ```rust,no_run
+/// # use tikv_client::{CommandPriority, RawClient, RawOptions};
+/// # use futures::prelude::*;
+/// # use std::time::Duration;
+/// # futures::executor::block_on(async {
+/// # let client = RawClient::new(vec!["192.168.0.100"], None).await.unwrap();
+/// let options = RawOptions::new()
+///     .priority(CommandPriority::Low)
+///     .timeout(Duration::from_secs(10))
+///     .key_only(true);
+/// let pairs = client
+///     .with_options(options)
+///     .scan("TiDB".to_owned().."TiKV".to_owned(), 100)
+///     .await
+///     .unwrap();
+/// # });
+/// ```
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct RawOptions {
+    priority: CommandPriority,
+    resource_group_tag: Vec<u8>,
+    timeout: Option<Duration>,
+    deadline: Option<Duration>,
+    cf: Option<ColumnFamily>,
+    key_only: bool,
+    atomic: bool,
+    progress: Option<ProgressCallback>,
+    context_hook: Option<ContextHook>,
+    checksum_sampling: u32,
+    batch_get_chunk_size: Option<usize>,
+    batch_get_concurrency: Option<usize>,
+    scan_concurrency: Option<usize>,
+    size_limits: Option<SizeLimits>,
+    retry_options: Option<RetryOptions>,
+    value_size_threshold: Option<usize>,
+}
+
+impl RawOptions {
+    pub fn new() -> RawOptions {
+        RawOptions::default()
+    }
+
+    /// Set the priority of the requests in TiKV, e.g. `Low` for background jobs, so that the
+    /// scheduler of TiKV serves them after the requests of higher priority.
+    pub fn priority(mut self, priority: CommandPriority) -> RawOptions {
+        self.priority = priority;
+        self
+    }
+
+    /// Tag the requests with `tag`, which TiKV attributes their resource usage to, e.g. the name
+    /// of the job sending them. The requests are not tagged by default.
+    pub fn resource_group_tag(mut self, tag: impl Into<Vec<u8>>) -> RawOptions {
+        self.resource_group_tag = tag.into();
+        self
+    }
+
+    /// Set the timeout of each request sent to TiKV. A request which takes longer fails with
+    /// [`RequestTimeout`](Error::RequestTimeout), wrapped in a
+    /// [`RequestFailed`](Error::RequestFailed) error, and is cancelled so that TiKV stops
+    /// processing it. The timeout of the [`Config`](crate::Config) still applies, so it should be
+    /// shorter than that.
+    pub fn timeout(mut self, timeout: Duration) -> RawOptions {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Fail each request sent to TiKV which doesn't complete within `timeout`, including its
+    /// retries, with [`DeadlineExceeded`](Error::DeadlineExceeded) instead of retrying further,
+    /// e.g. while a region is unavailable. The time left also bounds the
+    /// [`timeout`](RawOptions::timeout) of each attempt, and the attempts in flight are cancelled
+    /// at the deadline.
+    pub fn deadline(mut self, timeout: Duration) -> RawOptions {
+        self.deadline = Some(timeout);
+        self
+    }
+
+    /// Set the column family of the requests, see [`Client::with_cf`].
+    pub fn cf(mut self, cf: ColumnFamily) -> RawOptions {
+        self.cf = Some(cf);
+        self
+    }
+
+    /// Set whether scans only return the keys, with empty values.
+    ///
+    /// TiKV leaves the values out of the responses of key-only scans. Gets and batch gets have no
+    /// such option: they always return the values, whether or not this is set.
+    pub fn key_only(mut self, key_only: bool) -> RawOptions {
+        self.key_only = key_only;
+        self
+    }
+
+    /// Set whether to use the atomic mode, see [`Client::with_atomic_for_cas`].
+    pub fn atomic(mut self, atomic: bool) -> RawOptions {
+        self.atomic = atomic;
+        self
+    }
+
+    /// Report the progress of operations over ranges of keys which span many regions, i.e.
+    /// [`delete_range`](Client::delete_range), [`batch_scan`](Client::batch_scan) and
+    /// [`delete_prefix`](Client::delete_prefix) outside of the atomic mode, to `callback`.
+    pub fn progress(mut self, callback: ProgressCallback) -> RawOptions {
+        self.progress = Some(callback);
+        self
+    }
+
+    /// Verify the pairs returned by one in `n` gets, batch gets and scans, or by none if `n` is 0.
+    ///
+    /// TiKV does not return checksums with the pairs, so a sampled read is verified by comparing
+    /// the [checksum](Client::checksum) of the pairs read with the checksum TiKV computes for the
+    /// keys read. A mismatch fails the read with [`ChecksumMismatch`](Error::ChecksumMismatch).
+    /// Because the checksum is computed by a second request, a concurrent write to the keys read
+    /// between both requests also causes a mismatch. Gets and batch gets are therefore read and
+    /// verified once more before they fail. Scans are not read again, a scan which fails may be
+    /// retried.
+    ///
+    /// Scans of keys only are not verified, nor are reads of column families other than the
+    /// default one, or of clusters which store a TTL with the values, whose checksum TiKV can't
+    /// compare with the pairs read.
+    pub fn verify_checksums(mut self, n: u32) -> RawOptions {
+        self.checksum_sampling = n;
+        self
+    }
+
+    /// Amend the context of each request sent to TiKV with `hook`, see [`ContextHook`].
+    pub fn context_hook(mut self, hook: ContextHook) -> RawOptions {
+        self.context_hook = Some(hook);
+        self
+    }
+
+    /// Split the keys of a [`batch_get`](Client::batch_get) in each region into requests of at
+    /// most `chunk_size` keys. By default, the keys of a region are requested at once.
+    pub fn batch_get_chunk_size(mut self, chunk_size: usize) -> RawOptions {
+        self.batch_get_chunk_size = Some(chunk_size);
+        self
+    }
+
+    /// Limit the requests of a [`batch_get`](Client::batch_get) in flight to `concurrency`,
+    /// instead of the default of 16.
+    pub fn batch_get_concurrency(mut self, concurrency: usize) -> RawOptions {
+        self.batch_get_concurrency = Some(concurrency);
+        self
+    }
+
+    /// Scan up to `concurrency` regions concurrently. A [`scan`](Client::scan) of several regions
+    /// scans them one at a time by default, and a [`batch_scan`](Client::batch_scan) up to 16.
+    ///
+    /// The regions of a scan are scanned ahead of the pairs it collected, so up to
+    /// `concurrency - 1` regions after the last one needed for its limit may be scanned for
+    /// nothing.
+    pub fn scan_concurrency(mut self, concurrency: usize) -> RawOptions {
+        self.scan_concurrency = Some(concurrency);
+        self
+    }
+
+    /// Check the keys and values written against `size_limits` instead of the limits of the
+    /// client, see [`Config::with_size_limits`](crate::Config::with_size_limits).
+    pub fn size_limits(mut self, size_limits: SizeLimits) -> RawOptions {
+        self.size_limits = Some(size_limits);
+        self
+    }
+
+    /// Set how the requests are retried, see [`Client::with_retry_options`].
+    pub fn retry_options(mut self, retry_options: RetryOptions) -> RawOptions {
+        self.retry_options = Some(retry_options);
+        self
+    }
+
+    /// Leave the pairs whose values are larger than `threshold` bytes out of the results of
+    /// [`scan`](Client::scan) and [`scan_from`](Client::scan_from), e.g. to read around a few
+    /// oversized entries. Use [`scan_with_value_threshold`](Client::scan_with_value_threshold) to
+    /// find their keys and sizes.
+    pub fn skip_values_larger_than(mut self, threshold: usize) -> RawOptions {
+        self.value_size_threshold = Some(threshold);
+        self
+    }
+
+    /// The backoff of the retries after region errors.
+    fn region_backoff(&self) -> Backoff {
+        match &self.retry_options {
+            Some(options) => options.region_backoff.clone(),
+            None => DEFAULT_REGION_BACKOFF,
+        }
+    }
+
+    /// What reads do once their retries are exhausted.
+    fn on_read_exhausted(&self) -> OnRetriesExhausted {
+        self.retry_options
+            .as_ref()
+            .map_or(OnRetriesExhausted::LastError, |options| {
+                options.on_read_exhausted.clone()
+            })
+    }
+
+    /// What writes do once their retries are exhausted.
+    fn on_write_exhausted(&self) -> OnRetriesExhausted {
+        self.retry_options
+            .as_ref()
+            .map_or(OnRetriesExhausted::LastError, |options| {
+                options.on_write_exhausted.clone()
+            })
+    }
+}
+
+/// A pair read by [`Client::scan_with_value_threshold`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ScannedPair {
+    /// A pair whose value is at most as large as the threshold.
+    Pair(KvPair),
+    /// The key of a pair whose value is larger than the threshold, and the size of the value as
+    /// stored in TiKV, i.e. encoded by the [value codec](Client::with_value_codec) if any. The
+    /// value is not returned.
+    Oversized { key: Key, value_size: usize },
+}
+
+impl ScannedPair {
+    pub fn key(&self) -> &Key {
+        match self {
+            ScannedPair::Pair(pair) => pair.key(),
+            ScannedPair::Oversized { key, .. } => key,
+        }
+    }
+
+    /// The pair, unless its value is oversized.
+    pub fn into_pair(self) -> Option<KvPair> {
+        match self {
+            ScannedPair::Pair(pair) => Some(pair),
+            ScannedPair::Oversized { .. } => None,
+        }
+    }
+}
+
+/// The priority of requests in TiKV, see [`RawOptions::priority`] and
+/// [`TransactionOptions::priority`](crate::TransactionOptions::priority).
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum CommandPriority {
+    #[default]
+    Normal,
+    Low,
+    High,
+}
+
+impl From<CommandPriority> for kvrpcpb::CommandPri {
+    fn from(priority: CommandPriority) -> kvrpcpb::CommandPri {
+        match priority {
+            CommandPriority::Normal => kvrpcpb::CommandPri::Normal,
+            CommandPriority::Low => kvrpcpb::CommandPri::Low,
+            CommandPriority::High => kvrpcpb::CommandPri::High,
+        }
+    }
+}
+
+impl From<kvrpcpb::CommandPri> for CommandPriority {
+    fn from(priority: kvrpcpb::CommandPri) -> CommandPriority {
+        match priority {
+            kvrpcpb::CommandPri::Normal => CommandPriority::Normal,
+            kvrpcpb::CommandPri::Low => CommandPriority::Low,
+            kvrpcpb::CommandPri::High => CommandPriority::High,
+        }
+    }
+}
+
+/// The position of a scan, used to continue it with [`scan_from`](Client::scan_from).
+///
+/// A token can be serialized, e.g. to checkpoint an export and continue it after a restart. Scans
+/// of disjoint ranges are independent, so a range can be split into several tokens which are
+/// scanned in parallel.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ScanToken {
+    start_key: Vec<u8>,
+    /// `None` if the range is unbounded.
+    end_key: Option<Vec<u8>>,
+}
+
+impl ScanToken {
+    /// A token at the start of `range`.
+    pub fn new(range: impl Into<BoundRange>) -> ScanToken {
+        let (start_key, end_key) = range.into().into_keys();
+        ScanToken {
+            start_key: start_key.into(),
+            end_key: end_key.filter(|k| !k.is_empty()).map(Into::into),
+        }
+    }
+
+    fn range(&self) -> BoundRange {
+        (self.start_key.clone(), self.end_key.clone()).into()
+    }
+
+    /// The token for the rest of the range from `key` on.
+    fn at(&self, key: &Key) -> ScanToken {
+        ScanToken {
+            start_key: key.clone().into(),
+            end_key: self.end_key.clone(),
+        }
+    }
+
+    /// The token for the rest of the range after `key`.
+    fn after(&self, key: &Key) -> ScanToken {
+        let mut start_key: Vec<u8> = key.clone().into();
+        start_key.push(0);
+        ScanToken {
+            start_key,
+            end_key: self.end_key.clone(),
+        }
+    }
+}
+
+/// Keys written atomically by a [`batch_put_grouped`](Client::batch_put_grouped): either all of
+/// them were written, or none of them.
+#[derive(Debug)]
+pub struct WriteGroup {
+    /// The keys of the group, in order.
+    pub keys: Vec<Key>,
+    /// Whether the keys were written.
+    ///
+    /// A group fails as a whole, but if its region split while it was retried, the keys of the
+    /// new regions were written by separate requests, and some of them may have been written
+    /// despite the error. A group which failed because TiKV couldn't be reached may or may not
+    /// have been written.
+    pub result: crate::Result<()>,
+}
+
+/// A mutation of a raw key, written by [`atomic_batch`](Client::atomic_batch).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum RawMutation {
+    /// The key is written with the value.
+    Put(Key, Value),
+    /// The key is deleted.
+    Delete(Key),
+}
+
+impl RawMutation {
+    /// The key of the mutation.
+    pub fn key(&self) -> &Key {
+        match self {
+            RawMutation::Put(key, _) | RawMutation::Delete(key) => key,
+        }
+    }
+}
+
 trait RawRpcRequest: Default {
     fn set_cf(&mut self, cf: String);
 
@@ -90,3 +473,21 @@ trait RawRpcRequest: Default {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_command_priority_conversion() {
+        for (priority, proto) in [
+            (CommandPriority::Normal, kvrpcpb::CommandPri::Normal),
+            (CommandPriority::Low, kvrpcpb::CommandPri::Low),
+            (CommandPriority::High, kvrpcpb::CommandPri::High),
+        ] {
+            assert_eq!(kvrpcpb::CommandPri::from(priority), proto);
+            assert_eq!(CommandPriority::from(proto), priority);
+        }
+        assert_eq!(CommandPriority::default(), CommandPriority::Normal);
+    }
+}
@@ -1,11 +1,14 @@
 // Copyright 2019 TiKV Project Authors. Licensed under Apache-2.0.
 
-use std::{any::Any, ops::Range, sync::Arc};
+use std::{any::Any, iter, ops::Range, sync::Arc};
 
 use async_trait::async_trait;
-use futures::stream::BoxStream;
+use futures::{stream::BoxStream, StreamExt, TryStreamExt};
 use grpcio::CallOption;
-use tikv_client_proto::{kvrpcpb, metapb, tikvpb::TikvClient};
+use tikv_client_proto::{
+    kvrpcpb, metapb,
+    tikvpb::{batch_commands_request::request::Cmd, TikvClient},
+};
 use tikv_client_store::Request;
 
 use super::RawRpcRequest;
@@ -13,13 +16,13 @@ use crate::{
     collect_first,
     pd::PdClient,
     request::{
-        plan::ResponseWithShard, Collect, CollectSingle, DefaultProcessor, KvRequest, Merge,
-        Process, Shardable, SingleKey,
+        plan::ResponseWithShard, Collect, CollectSingle, DefaultProcessor, Idempotence, KvRequest,
+        Merge, Process, Shardable, SingleKey,
     },
     store::{store_stream_for_keys, store_stream_for_ranges, RegionStore},
     transaction::HasLocks,
     util::iter::FlatMapOkIterExt,
-    ColumnFamily, Key, KvPair, Result, Value,
+    ColumnFamily, Key, KvPair, RawChecksum, Result, Value,
 };
 
 pub fn new_raw_get_request(key: Vec<u8>, cf: Option<ColumnFamily>) -> kvrpcpb::RawGetRequest {
@@ -128,24 +131,49 @@ impl KvRequest for kvrpcpb::RawBatchPutRequest {
     type Response = kvrpcpb::RawBatchPutResponse;
 }
 
+/// A pair of a batch put along with its TTL, so that they are sharded together.
+struct PairWithTtl(kvrpcpb::KvPair, u64);
+
+impl AsRef<Key> for PairWithTtl {
+    fn as_ref(&self) -> &Key {
+        self.0.key.as_ref()
+    }
+}
+
 impl Shardable for kvrpcpb::RawBatchPutRequest {
-    type Shard = Vec<kvrpcpb::KvPair>;
+    /// The pairs of a region and their TTLs, empty if the request has none.
+    type Shard = (Vec<kvrpcpb::KvPair>, Vec<u64>);
 
     fn shards(
         &self,
         pd_client: &Arc<impl PdClient>,
     ) -> BoxStream<'static, Result<(Self::Shard, RegionStore)>> {
-        let mut pairs = self.pairs.clone();
-        pairs.sort_by(|a, b| a.key.cmp(&b.key));
-        store_stream_for_keys(
-            pairs.into_iter().map(Into::<KvPair>::into),
-            pd_client.clone(),
-        )
+        let has_ttls = !self.ttls.is_empty();
+        // a single TTL applies to all pairs
+        let ttls = match self.ttls.as_slice() {
+            [ttl] => vec![*ttl; self.pairs.len()],
+            ttls => ttls.to_vec(),
+        };
+        let mut pairs: Vec<_> = self
+            .pairs
+            .iter()
+            .cloned()
+            .zip(ttls.into_iter().chain(iter::repeat(0)))
+            .map(|(pair, ttl)| PairWithTtl(pair, ttl))
+            .collect();
+        pairs.sort_by(|a, b| a.0.key.cmp(&b.0.key));
+        store_stream_for_keys(pairs.into_iter(), pd_client.clone())
+            .map_ok(move |(pairs, store): (Vec<PairWithTtl>, _)| {
+                let (pairs, ttls): (Vec<_>, Vec<_>) =
+                    pairs.into_iter().map(|pair| (pair.0, pair.1)).unzip();
+                ((pairs, if has_ttls { ttls } else { Vec::new() }), store)
+            })
+            .boxed()
     }
 
-    fn apply_shard(&mut self, shard: Self::Shard, store: &RegionStore) -> Result<()> {
-        self.set_context(store.region_with_leader.context()?);
-        self.set_pairs(shard);
+    fn apply_shard(&mut self, (pairs, ttls): Self::Shard, _store: &RegionStore) -> Result<()> {
+        self.set_pairs(pairs);
+        self.set_ttls(ttls);
         Ok(())
     }
 }
@@ -274,8 +302,7 @@ impl Shardable for kvrpcpb::RawBatchScanRequest {
         store_stream_for_ranges(self.ranges.clone(), pd_client.clone())
     }
 
-    fn apply_shard(&mut self, shard: Self::Shard, store: &RegionStore) -> Result<()> {
-        self.set_context(store.region_with_leader.context()?);
+    fn apply_shard(&mut self, shard: Self::Shard, _store: &RegionStore) -> Result<()> {
         self.set_ranges(shard);
         Ok(())
     }
@@ -311,6 +338,8 @@ pub fn new_cas_request(
 
 impl KvRequest for kvrpcpb::RawCasRequest {
     type Response = kvrpcpb::RawCasResponse;
+    // a swap which succeeded fails the second time
+    const IDEMPOTENCE: Idempotence = Idempotence::RequiresStatusCheck;
 }
 
 shardable_key!(kvrpcpb::RawCasRequest);
@@ -334,6 +363,42 @@ impl Process<kvrpcpb::RawCasResponse> for DefaultProcessor {
     }
 }
 
+pub fn new_raw_get_key_ttl_request(
+    key: Vec<u8>,
+    cf: Option<ColumnFamily>,
+) -> kvrpcpb::RawGetKeyTtlRequest {
+    let mut req = kvrpcpb::RawGetKeyTtlRequest::default();
+    req.set_key(key);
+    req.maybe_set_cf(cf);
+
+    req
+}
+
+impl KvRequest for kvrpcpb::RawGetKeyTtlRequest {
+    type Response = kvrpcpb::RawGetKeyTtlResponse;
+}
+
+shardable_key!(kvrpcpb::RawGetKeyTtlRequest);
+collect_first!(kvrpcpb::RawGetKeyTtlResponse);
+impl SingleKey for kvrpcpb::RawGetKeyTtlRequest {
+    fn key(&self) -> &Vec<u8> {
+        &self.key
+    }
+}
+
+impl Process<kvrpcpb::RawGetKeyTtlResponse> for DefaultProcessor {
+    type Out = Option<u64>;
+
+    fn process(&self, input: Result<kvrpcpb::RawGetKeyTtlResponse>) -> Result<Self::Out> {
+        let input = input?;
+        Ok(if input.not_found {
+            None
+        } else {
+            Some(input.ttl)
+        })
+    }
+}
+
 type RawCoprocessorRequestDataBuilder =
     Arc<dyn Fn(metapb::Region, Vec<kvrpcpb::KeyRange>) -> Vec<u8> + Send + Sync>;
 
@@ -376,10 +441,20 @@ impl Request for RawCoprocessorRequest {
     fn set_context(&mut self, context: kvrpcpb::Context) {
         self.inner.set_context(context);
     }
+
+    fn add_resolved_locks(&mut self, start_versions: &[u64]) {
+        self.inner.add_resolved_locks(start_versions);
+    }
+
+    fn to_batch_command(&self) -> Option<Cmd> {
+        self.inner.to_batch_command()
+    }
 }
 
 impl KvRequest for RawCoprocessorRequest {
     type Response = kvrpcpb::RawCoprocessorResponse;
+    // the coprocessor may write
+    const IDEMPOTENCE: Idempotence = Idempotence::RequiresStatusCheck;
 }
 
 impl Shardable for RawCoprocessorRequest {
@@ -393,7 +468,6 @@ impl Shardable for RawCoprocessorRequest {
     }
 
     fn apply_shard(&mut self, shard: Self::Shard, store: &RegionStore) -> Result<()> {
-        self.inner.set_context(store.region_with_leader.context()?);
         self.inner.set_ranges(shard.clone());
         self.inner.set_data((self.data_builder)(
             store.region_with_leader.region.clone(),
@@ -433,6 +507,45 @@ impl
     }
 }
 
+pub fn new_raw_checksum_request(ranges: Vec<kvrpcpb::KeyRange>) -> kvrpcpb::RawChecksumRequest {
+    let mut req = kvrpcpb::RawChecksumRequest::default();
+    req.set_algorithm(kvrpcpb::ChecksumAlgorithm::Crc64Xor);
+    req.set_ranges(ranges);
+    req
+}
+
+impl KvRequest for kvrpcpb::RawChecksumRequest {
+    type Response = kvrpcpb::RawChecksumResponse;
+}
+
+impl Shardable for kvrpcpb::RawChecksumRequest {
+    type Shard = Vec<kvrpcpb::KeyRange>;
+
+    fn shards(
+        &self,
+        pd_client: &Arc<impl PdClient>,
+    ) -> BoxStream<'static, Result<(Self::Shard, RegionStore)>> {
+        store_stream_for_ranges(self.ranges.clone(), pd_client.clone())
+    }
+
+    fn apply_shard(&mut self, shard: Self::Shard, _store: &RegionStore) -> Result<()> {
+        self.set_ranges(shard);
+        Ok(())
+    }
+}
+
+impl Merge<kvrpcpb::RawChecksumResponse> for Collect {
+    type Out = RawChecksum;
+
+    fn merge(&self, input: Vec<Result<kvrpcpb::RawChecksumResponse>>) -> Result<Self::Out> {
+        input
+            .into_iter()
+            .try_fold(RawChecksum::default(), |acc, resp| {
+                Ok(acc ^ RawChecksum::from(resp?))
+            })
+    }
+}
+
 macro_rules! impl_raw_rpc_request {
     ($name: ident) => {
         impl RawRpcRequest for kvrpcpb::$name {
@@ -453,6 +566,7 @@ impl_raw_rpc_request!(RawScanRequest);
 impl_raw_rpc_request!(RawBatchScanRequest);
 impl_raw_rpc_request!(RawDeleteRangeRequest);
 impl_raw_rpc_request!(RawCasRequest);
+impl_raw_rpc_request!(RawGetKeyTtlRequest);
 
 impl HasLocks for kvrpcpb::RawGetResponse {}
 impl HasLocks for kvrpcpb::RawBatchGetResponse {}
@@ -464,7 +578,9 @@ impl HasLocks for kvrpcpb::RawScanResponse {}
 impl HasLocks for kvrpcpb::RawBatchScanResponse {}
 impl HasLocks for kvrpcpb::RawDeleteRangeResponse {}
 impl HasLocks for kvrpcpb::RawCasResponse {}
+impl HasLocks for kvrpcpb::RawGetKeyTtlResponse {}
 impl HasLocks for kvrpcpb::RawCoprocessorResponse {}
+impl HasLocks for kvrpcpb::RawChecksumResponse {}
 
 #[cfg(test)]
 mod test {
@@ -520,4 +636,35 @@ mod test {
         assert_eq!(scan.len(), 10);
         // FIXME test the keys returned.
     }
+
+    #[tokio::test]
+    async fn test_raw_batch_put_ttls() {
+        let client = Arc::new(MockPdClient::new(MockKvClient::with_dispatch_hook(
+            |req: &dyn Any| {
+                let req: &kvrpcpb::RawBatchPutRequest = req.downcast_ref().unwrap();
+                // each pair keeps its TTL in the request of its region
+                for (pair, ttl) in req.pairs.iter().zip(&req.ttls) {
+                    assert_eq!(pair.value, vec![*ttl as u8]);
+                }
+                assert_eq!(req.pairs.len(), req.ttls.len());
+                Ok(Box::new(kvrpcpb::RawBatchPutResponse::default()) as Box<dyn Any>)
+            },
+        )));
+
+        let pair = |key: u8, ttl: u8| kvrpcpb::KvPair {
+            key: vec![key],
+            value: vec![ttl],
+            ..Default::default()
+        };
+        let put = kvrpcpb::RawBatchPutRequest {
+            pairs: vec![pair(20, 1), pair(1, 2), pair(30, 3), pair(2, 4)],
+            ttls: vec![1, 2, 3, 4],
+            ..Default::default()
+        };
+        let plan = crate::request::PlanBuilder::new(client, put)
+            .retry_multi_region(DEFAULT_REGION_BACKOFF)
+            .extract_error()
+            .plan();
+        plan.execute().await.unwrap();
+    }
 }
@@ -0,0 +1,99 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+use crate::{Key, RegionId, Result};
+use futures::Future;
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+
+/// The prefix of the sentinel keys written by [`self_check`](super::Client::self_check).
+pub(crate) const SELF_CHECK_PREFIX: &[u8] = b"tikv_client_self_check/";
+/// The last bytes of the sentinel keys, spread over the namespace so that the keys fall in
+/// different regions once the namespace is split.
+pub(crate) const SELF_CHECK_SUFFIXES: [u8; 4] = [0x00, 0x40, 0x80, 0xc0];
+
+/// The result of a [`self_check`](crate::RawClient::self_check).
+///
+/// The report is formatted with one line per step, to be attached to an issue.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct SelfCheckReport {
+    /// The steps in the order they ran.
+    pub steps: Vec<SelfCheckStep>,
+}
+
+/// A step of a [`SelfCheckReport`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SelfCheckStep {
+    /// `locate`, `put`, `get`, `delete` or `get_deleted`.
+    pub name: &'static str,
+    /// The sentinel key of the step.
+    pub key: Key,
+    /// The region of the key, if it was found.
+    pub region_id: Option<RegionId>,
+    pub latency: Duration,
+    /// Why the step failed, `None` if it succeeded.
+    pub error: Option<String>,
+}
+
+impl SelfCheckReport {
+    /// Whether all steps succeeded.
+    pub fn is_ok(&self) -> bool {
+        self.steps.iter().all(|step| step.error.is_none())
+    }
+
+    /// The steps which failed.
+    pub fn failures(&self) -> impl Iterator<Item = &SelfCheckStep> {
+        self.steps.iter().filter(|step| step.error.is_some())
+    }
+
+    /// The regions of the sentinel keys, ordered by id.
+    pub fn regions(&self) -> Vec<RegionId> {
+        let mut regions: Vec<RegionId> = self
+            .steps
+            .iter()
+            .filter_map(|step| step.region_id)
+            .collect();
+        regions.sort_unstable();
+        regions.dedup();
+        regions
+    }
+}
+
+impl SelfCheckStep {
+    /// Runs `future` as the step `name` on `key`, and the output of `future` if it succeeded.
+    pub(crate) async fn run<T>(
+        name: &'static str,
+        key: &Key,
+        region_id: Option<RegionId>,
+        future: impl Future<Output = Result<T>>,
+    ) -> (SelfCheckStep, Option<T>) {
+        let start = Instant::now();
+        let result = future.await;
+        let step = SelfCheckStep {
+            name,
+            key: key.clone(),
+            region_id,
+            latency: start.elapsed(),
+            error: result.as_ref().err().map(ToString::to_string),
+        };
+        (step, result.ok())
+    }
+}
+
+impl fmt::Display for SelfCheckReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for step in &self.steps {
+            write!(f, "{:<12}{:?}", step.name, step.key)?;
+            match step.region_id {
+                Some(region_id) => write!(f, " region {}", region_id)?,
+                None => write!(f, " region ?")?,
+            }
+            match &step.error {
+                Some(error) => writeln!(f, " {:?} failed: {}", step.latency, error)?,
+                None => writeln!(f, " {:?} ok", step.latency)?,
+            }
+        }
+        Ok(())
+    }
+}
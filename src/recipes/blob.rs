@@ -0,0 +1,240 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! Storing values which are too large for a single TiKV entry.
+//!
+//! TiKV rejects entries larger than its raft entry size limit (8MB by default), and large entries
+//! hurt performance well before the limit. [`BlobStore`] splits a large value into chunks, each
+//! stored under its own key, and re-assembles them on read. All chunks of a blob are written and
+//! read in a single transaction, so a blob is always read consistently.
+//!
+//! A blob stored at `key` occupies `key` itself (holding a small header) and the keys
+//! `key + "\0blob" + index` for each chunk. Other data must not be stored under those keys.
+
+use crate::{pd::PdClient, Key, Result, Transaction, Value};
+use std::{collections::HashMap, convert::TryInto};
+use tikv_client_common::internal_err;
+
+/// The default size of a chunk, 512KB.
+pub const DEFAULT_CHUNK_SIZE: usize = 512 * 1024;
+
+const CHUNK_KEY_SEPARATOR: &[u8] = b"\0blob";
+const HEADER_MAGIC: u8 = 0xb1;
+// magic (1 byte) + chunk count (4 bytes) + total length (8 bytes)
+const HEADER_LEN: usize = 13;
+
+/// Reads and writes values of any size as chunks in a transaction.
+///
+/// # Examples
+///
+/// ```rust,no_run
+/// # use tikv_client::{recipes::blob::BlobStore, TransactionClient};
+/// # use futures::prelude::*;
+/// # futures::executor::block_on(async {
+/// let client = TransactionClient::new(vec!["192.168.0.100"], None).await.unwrap();
+/// let blobs = BlobStore::default();
+/// let mut txn = client.begin_optimistic().await.unwrap();
+/// blobs.put(&mut txn, "large".to_owned(), vec![0u8; 32 * 1024 * 1024]).await.unwrap();
+/// txn.commit().await.unwrap();
+/// # });
+/// ```
+#[derive(Clone, Debug)]
+pub struct BlobStore {
+    chunk_size: usize,
+}
+
+impl Default for BlobStore {
+    fn default() -> BlobStore {
+        BlobStore {
+            chunk_size: DEFAULT_CHUNK_SIZE,
+        }
+    }
+}
+
+impl BlobStore {
+    /// Create a `BlobStore` which splits values into chunks of at most `chunk_size` bytes.
+    ///
+    /// Fails if `chunk_size` is zero.
+    pub fn new(chunk_size: usize) -> Result<BlobStore> {
+        if chunk_size == 0 {
+            return Err(internal_err!("chunk size of a blob store must be positive"));
+        }
+        Ok(BlobStore { chunk_size })
+    }
+
+    /// Write `value` as a blob at `key`, replacing any existing blob.
+    pub async fn put<PdC: PdClient>(
+        &self,
+        txn: &mut Transaction<PdC>,
+        key: impl Into<Key>,
+        value: impl Into<Value>,
+    ) -> Result<()> {
+        let key = key.into();
+        let value = value.into();
+        let old_chunk_count = match txn.get(key.clone()).await? {
+            Some(header) => Header::decode(&header)?.chunk_count,
+            None => 0,
+        };
+
+        let chunks: Vec<&[u8]> = value.chunks(self.chunk_size).collect();
+        let header = Header {
+            chunk_count: chunks.len() as u32,
+            total_len: value.len() as u64,
+        };
+        for (index, chunk) in chunks.into_iter().enumerate() {
+            txn.put(chunk_key(&key, index as u32), chunk.to_vec())
+                .await?;
+        }
+        for index in header.chunk_count..old_chunk_count {
+            txn.delete(chunk_key(&key, index)).await?;
+        }
+        txn.put(key, header.encode()).await
+    }
+
+    /// Read the blob at `key`.
+    ///
+    /// Returns `Ok(None)` if there is no blob at `key`.
+    pub async fn get<PdC: PdClient>(
+        &self,
+        txn: &mut Transaction<PdC>,
+        key: impl Into<Key>,
+    ) -> Result<Option<Value>> {
+        let key = key.into();
+        let header = match txn.get(key.clone()).await? {
+            Some(header) => Header::decode(&header)?,
+            None => return Ok(None),
+        };
+
+        let chunk_keys: Vec<Key> = (0..header.chunk_count)
+            .map(|index| chunk_key(&key, index))
+            .collect();
+        let mut chunks: HashMap<Key, Value> = txn
+            .batch_get(chunk_keys.clone())
+            .await?
+            .map(|pair| (pair.0, pair.1))
+            .collect();
+
+        let mut value = Vec::with_capacity(header.total_len as usize);
+        for chunk_key in chunk_keys {
+            match chunks.remove(&chunk_key) {
+                Some(chunk) => value.extend_from_slice(&chunk),
+                None => return Err(internal_err!("missing chunk {:?} of blob", chunk_key)),
+            }
+        }
+        if value.len() as u64 != header.total_len {
+            return Err(internal_err!(
+                "blob has length {}, expected {}",
+                value.len(),
+                header.total_len
+            ));
+        }
+        Ok(Some(value))
+    }
+
+    /// Delete the blob at `key`, including all its chunks.
+    ///
+    /// Deleting a non-existent blob will not result in an error.
+    pub async fn delete<PdC: PdClient>(
+        &self,
+        txn: &mut Transaction<PdC>,
+        key: impl Into<Key>,
+    ) -> Result<()> {
+        let key = key.into();
+        let header = match txn.get(key.clone()).await? {
+            Some(header) => Header::decode(&header)?,
+            None => return Ok(()),
+        };
+        for index in 0..header.chunk_count {
+            txn.delete(chunk_key(&key, index)).await?;
+        }
+        txn.delete(key).await
+    }
+}
+
+fn chunk_key(key: &Key, index: u32) -> Key {
+    let mut chunk_key: Vec<u8> = key.clone().into();
+    chunk_key.extend_from_slice(CHUNK_KEY_SEPARATOR);
+    chunk_key.extend_from_slice(&index.to_be_bytes());
+    chunk_key.into()
+}
+
+struct Header {
+    chunk_count: u32,
+    total_len: u64,
+}
+
+impl Header {
+    fn encode(&self) -> Value {
+        let mut value = Vec::with_capacity(HEADER_LEN);
+        value.push(HEADER_MAGIC);
+        value.extend_from_slice(&self.chunk_count.to_be_bytes());
+        value.extend_from_slice(&self.total_len.to_be_bytes());
+        value
+    }
+
+    fn decode(value: &[u8]) -> Result<Header> {
+        if value.len() != HEADER_LEN || value[0] != HEADER_MAGIC {
+            return Err(internal_err!("invalid blob header {:?}", value));
+        }
+        Ok(Header {
+            chunk_count: u32::from_be_bytes(value[1..5].try_into().unwrap()),
+            total_len: u64::from_be_bytes(value[5..].try_into().unwrap()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        mock::{MockKvClient, MockPdClient},
+        CheckLevel, TransactionOptions,
+    };
+    use slog::Logger;
+    use std::{any::Any, sync::Arc};
+    use tikv_client_proto::{kvrpcpb, pdpb::Timestamp};
+
+    #[tokio::test]
+    async fn test_blob_chunks() {
+        let pd_client = Arc::new(MockPdClient::new(MockKvClient::with_dispatch_hook(
+            |req: &dyn Any| {
+                // everything is read from the buffer of the transaction, except the first read of
+                // the header, which doesn't exist
+                assert!(req.downcast_ref::<kvrpcpb::GetRequest>().is_some());
+                let resp = kvrpcpb::GetResponse {
+                    not_found: true,
+                    ..Default::default()
+                };
+                Ok(Box::new(resp) as Box<dyn Any>)
+            },
+        )));
+        let mut txn = Transaction::new(
+            Timestamp::default(),
+            pd_client,
+            TransactionOptions::new_optimistic().drop_check(CheckLevel::None),
+            Logger::root(slog::Discard, o!()),
+        );
+        assert!(BlobStore::new(0).is_err());
+        let blobs = BlobStore::new(4).unwrap();
+
+        let value: Vec<u8> = (0..10).collect();
+        blobs.put(&mut txn, vec![1], value.clone()).await.unwrap();
+        assert_eq!(blobs.get(&mut txn, vec![1]).await.unwrap(), Some(value));
+        assert!(txn
+            .get(chunk_key(&vec![1].into(), 2))
+            .await
+            .unwrap()
+            .is_some());
+
+        // a shorter value removes the trailing chunks
+        blobs.put(&mut txn, vec![1], vec![42]).await.unwrap();
+        assert_eq!(blobs.get(&mut txn, vec![1]).await.unwrap(), Some(vec![42]));
+        assert!(txn
+            .get(chunk_key(&vec![1].into(), 2))
+            .await
+            .unwrap()
+            .is_none());
+
+        blobs.delete(&mut txn, vec![1]).await.unwrap();
+        assert_eq!(blobs.get(&mut txn, vec![1]).await.unwrap(), None);
+    }
+}
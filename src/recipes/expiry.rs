@@ -0,0 +1,324 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! Values which expire after a time to live, in transactions.
+//!
+//! TiKV only supports TTLs for raw keys, the mutations of transactions have no TTL. An
+//! [`ExpiryStore`] emulates them: it stores the expiration time of a value with the value, so
+//! that expired values are no longer read, and records the key in an expiry index, so that a
+//! purge job, [`purge_expired`](ExpiryStore::purge_expired), can find and delete the expired
+//! values transactionally.
+//!
+//! Times are the physical times of the timestamps of the transactions, in milliseconds: a value
+//! written with a TTL by a transaction started at `t` expires for the transactions started at
+//! `t + ttl` or later, whatever the clocks of the clients.
+//!
+//! A value stored at `key` occupies `key` itself, with a header holding its expiration time, and
+//! the key `index_prefix + expiration time + key` in the index. Other data must not be stored
+//! under the index prefix.
+
+use crate::{pd::PdClient, Key, Result, Transaction, TransactionClient, Value};
+use std::{convert::TryInto, time::Duration};
+use tikv_client_common::internal_err;
+
+/// The prefix of the keys of the expiry index by default.
+pub const DEFAULT_INDEX_PREFIX: &[u8] = b"\0expiry/";
+
+const HEADER_MAGIC: u8 = 0xe7;
+// magic (1 byte) + expiration time in ms (8 bytes), 0 if the value doesn't expire
+const HEADER_LEN: usize = 9;
+
+/// Reads and writes values with a time to live in a transaction.
+///
+/// # Examples
+///
+/// ```rust,no_run
+/// # use tikv_client::{recipes::expiry::ExpiryStore, TransactionClient};
+/// # use futures::prelude::*;
+/// # use std::time::Duration;
+/// # futures::executor::block_on(async {
+/// let client = TransactionClient::new(vec!["192.168.0.100"], None).await.unwrap();
+/// let sessions = ExpiryStore::default();
+/// let mut txn = client.begin_optimistic().await.unwrap();
+/// let ttl = Some(Duration::from_secs(3600));
+/// sessions.put(&mut txn, "session".to_owned(), "user".to_owned(), ttl).await.unwrap();
+/// txn.commit().await.unwrap();
+///
+/// // periodically, e.g. in a background task
+/// let purged = sessions.purge_expired(&client, 1024).await.unwrap();
+/// # });
+/// ```
+#[derive(Clone, Debug)]
+pub struct ExpiryStore {
+    index_prefix: Vec<u8>,
+}
+
+impl Default for ExpiryStore {
+    fn default() -> ExpiryStore {
+        ExpiryStore::new(DEFAULT_INDEX_PREFIX)
+    }
+}
+
+impl ExpiryStore {
+    /// Create an `ExpiryStore` whose expiry index is stored under `index_prefix`.
+    pub fn new(index_prefix: impl Into<Vec<u8>>) -> ExpiryStore {
+        ExpiryStore {
+            index_prefix: index_prefix.into(),
+        }
+    }
+
+    /// Write `value` at `key`, which expires `ttl` after the start of `txn`, or never if `ttl` is
+    /// `None`. Replaces any existing value and its expiration time.
+    pub async fn put<PdC: PdClient>(
+        &self,
+        txn: &mut Transaction<PdC>,
+        key: impl Into<Key>,
+        value: impl Into<Value>,
+        ttl: Option<Duration>,
+    ) -> Result<()> {
+        let key = key.into();
+        self.delete_index_entry(txn, &key).await?;
+        let expires_at = match ttl {
+            Some(ttl) => {
+                // the expiration time is never 0, which stands for no expiration
+                let expires_at = (start_time(txn) + ttl.as_millis() as u64).max(1);
+                txn.put(self.index_key(expires_at, &key), Vec::new())
+                    .await?;
+                expires_at
+            }
+            None => 0,
+        };
+        let mut stored = Header { expires_at }.encode();
+        stored.extend_from_slice(&value.into());
+        txn.put(key, stored).await
+    }
+
+    /// Read the value at `key`.
+    ///
+    /// Returns `Ok(None)` if there is no value at `key`, or if it has expired at the start of
+    /// `txn`, even if it was not purged yet.
+    pub async fn get<PdC: PdClient>(
+        &self,
+        txn: &mut Transaction<PdC>,
+        key: impl Into<Key>,
+    ) -> Result<Option<Value>> {
+        let mut stored = match txn.get(key).await? {
+            Some(stored) => stored,
+            None => return Ok(None),
+        };
+        let header = Header::decode(&stored)?;
+        if header.is_expired(start_time(txn)) {
+            return Ok(None);
+        }
+        stored.drain(..HEADER_LEN);
+        Ok(Some(stored))
+    }
+
+    /// The remaining time to live of the value at `key`, `None` if there is no value, or if it
+    /// never expires.
+    pub async fn ttl<PdC: PdClient>(
+        &self,
+        txn: &mut Transaction<PdC>,
+        key: impl Into<Key>,
+    ) -> Result<Option<Duration>> {
+        let header = match txn.get(key).await? {
+            Some(stored) => Header::decode(&stored)?,
+            None => return Ok(None),
+        };
+        let now = start_time(txn);
+        if header.expires_at == 0 || header.is_expired(now) {
+            return Ok(None);
+        }
+        Ok(Some(Duration::from_millis(header.expires_at - now)))
+    }
+
+    /// Delete the value at `key` and its entry in the expiry index.
+    ///
+    /// Deleting a non-existent value will not result in an error.
+    pub async fn delete<PdC: PdClient>(
+        &self,
+        txn: &mut Transaction<PdC>,
+        key: impl Into<Key>,
+    ) -> Result<()> {
+        let key = key.into();
+        self.delete_index_entry(txn, &key).await?;
+        txn.delete(key).await
+    }
+
+    /// Delete the values which have expired at the start of `txn`, at most `limit` of them,
+    /// returning how many were deleted.
+    pub async fn purge_expired_in<PdC: PdClient>(
+        &self,
+        txn: &mut Transaction<PdC>,
+        limit: u32,
+    ) -> Result<u32> {
+        let now = start_time(txn);
+        let end = self.index_key(now + 1, &Key::EMPTY);
+        let entries: Vec<Key> = txn
+            .scan_keys(self.index_prefix.clone()..end.into(), limit)
+            .await?
+            .collect();
+        for entry in &entries {
+            let key = Vec::from(entry.clone()).split_off(self.index_prefix.len() + 8);
+            txn.delete(key).await?;
+            txn.delete(entry.clone()).await?;
+        }
+        Ok(entries.len() as u32)
+    }
+
+    /// Delete all expired values, in transactions of at most `batch_size` values each, returning
+    /// how many were deleted.
+    ///
+    /// A transaction which conflicts with a concurrent write of the values it deletes fails the
+    /// purge, which can be run again.
+    pub async fn purge_expired(&self, client: &TransactionClient, batch_size: u32) -> Result<u64> {
+        let batch_size = batch_size.max(1);
+        let mut purged = 0;
+        loop {
+            let mut txn = client.begin_optimistic().await?;
+            let count = match self.purge_expired_in(&mut txn, batch_size).await {
+                Ok(count) => count,
+                Err(e) => {
+                    txn.rollback().await?;
+                    return Err(e);
+                }
+            };
+            txn.commit().await?;
+            purged += count as u64;
+            if count < batch_size {
+                return Ok(purged);
+            }
+        }
+    }
+
+    /// Removes the entry of the current value at `key` from the index, if it expires.
+    async fn delete_index_entry<PdC: PdClient>(
+        &self,
+        txn: &mut Transaction<PdC>,
+        key: &Key,
+    ) -> Result<()> {
+        if let Some(stored) = txn.get(key.clone()).await? {
+            let header = Header::decode(&stored)?;
+            if header.expires_at != 0 {
+                txn.delete(self.index_key(header.expires_at, key)).await?;
+            }
+        }
+        Ok(())
+    }
+
+    fn index_key(&self, expires_at: u64, key: &Key) -> Key {
+        let mut index_key = self.index_prefix.clone();
+        index_key.extend_from_slice(&expires_at.to_be_bytes());
+        index_key.extend_from_slice(key.into());
+        index_key.into()
+    }
+}
+
+/// The physical time of the start timestamp of `txn`, in milliseconds.
+fn start_time<PdC: PdClient>(txn: &Transaction<PdC>) -> u64 {
+    txn.start_timestamp().physical.max(0) as u64
+}
+
+struct Header {
+    expires_at: u64,
+}
+
+impl Header {
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at != 0 && self.expires_at <= now
+    }
+
+    fn encode(&self) -> Value {
+        let mut value = Vec::with_capacity(HEADER_LEN);
+        value.push(HEADER_MAGIC);
+        value.extend_from_slice(&self.expires_at.to_be_bytes());
+        value
+    }
+
+    fn decode(value: &[u8]) -> Result<Header> {
+        if value.len() < HEADER_LEN || value[0] != HEADER_MAGIC {
+            return Err(internal_err!("invalid expiry header {:?}", value));
+        }
+        Ok(Header {
+            expires_at: u64::from_be_bytes(value[1..HEADER_LEN].try_into().unwrap()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        mock::{MockKvClient, MockPdClient},
+        CheckLevel, TransactionOptions,
+    };
+    use slog::Logger;
+    use std::{any::Any, sync::Arc};
+    use tikv_client_proto::{kvrpcpb, pdpb::Timestamp};
+
+    #[tokio::test]
+    async fn test_expiry() {
+        // nothing is stored in TiKV, everything is read from the buffer of the transactions
+        let pd_client = Arc::new(MockPdClient::new(MockKvClient::with_dispatch_hook(
+            |req: &dyn Any| {
+                if req.is::<kvrpcpb::GetRequest>() {
+                    let resp = kvrpcpb::GetResponse {
+                        not_found: true,
+                        ..Default::default()
+                    };
+                    Ok(Box::new(resp) as Box<dyn Any>)
+                } else if req.is::<kvrpcpb::ScanRequest>() {
+                    Ok(Box::new(kvrpcpb::ScanResponse::default()) as Box<dyn Any>)
+                } else {
+                    unreachable!()
+                }
+            },
+        )));
+        let new_txn = |physical| {
+            let timestamp = Timestamp {
+                physical,
+                ..Default::default()
+            };
+            Transaction::new(
+                timestamp,
+                pd_client.clone(),
+                TransactionOptions::new_optimistic().drop_check(CheckLevel::None),
+                Logger::root(slog::Discard, o!()),
+            )
+        };
+        let store = ExpiryStore::new(b"i".to_vec());
+        let ttl = Some(Duration::from_millis(100));
+
+        let mut txn = new_txn(1000);
+        store.put(&mut txn, vec![1], vec![42], ttl).await.unwrap();
+        store.put(&mut txn, vec![2], vec![43], None).await.unwrap();
+        assert_eq!(store.get(&mut txn, vec![1]).await.unwrap(), Some(vec![42]));
+        assert_eq!(store.ttl(&mut txn, vec![1]).await.unwrap(), ttl);
+        assert_eq!(store.ttl(&mut txn, vec![2]).await.unwrap(), None);
+        // a rewrite moves the entry in the index
+        store.put(&mut txn, vec![3], vec![44], ttl).await.unwrap();
+        store
+            .put(&mut txn, vec![3], vec![45], Some(Duration::from_secs(1)))
+            .await
+            .unwrap();
+        assert!(txn
+            .get(store.index_key(1100, &vec![3].into()))
+            .await
+            .unwrap()
+            .is_none());
+
+        // a value expires at the start of the transaction with a zero TTL
+        let mut txn = new_txn(1100);
+        store
+            .put(&mut txn, vec![1], vec![42], Some(Duration::ZERO))
+            .await
+            .unwrap();
+        store.put(&mut txn, vec![2], vec![43], None).await.unwrap();
+        store.put(&mut txn, vec![3], vec![44], ttl).await.unwrap();
+        assert_eq!(store.get(&mut txn, vec![1]).await.unwrap(), None);
+        assert_eq!(store.get(&mut txn, vec![2]).await.unwrap(), Some(vec![43]));
+        assert_eq!(store.purge_expired_in(&mut txn, 10).await.unwrap(), 1);
+        assert!(txn.get(vec![1]).await.unwrap().is_none());
+        assert!(txn.get(vec![3]).await.unwrap().is_some());
+        assert_eq!(store.purge_expired_in(&mut txn, 10).await.unwrap(), 0);
+    }
+}
@@ -0,0 +1,6 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! Higher-level patterns built on top of the transactional API.
+
+pub mod blob;
+pub mod expiry;
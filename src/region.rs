@@ -23,10 +23,24 @@ pub struct RegionVerId {
 /// Information about a TiKV region and its leader.
 ///
 /// In TiKV all data is partitioned by range. Each partition is called a region.
+///
+/// Besides the peers of the region, it holds the states of the region which the client learned
+/// from PD and TiKV, e.g. to route requests around regions with unavailable peers. They are as of
+/// when the region was loaded from PD, or when TiKV last rejected a request to it.
 #[derive(new, Clone, Default, Debug, PartialEq)]
 pub struct RegionWithLeader {
     pub region: metapb::Region,
     pub leader: Option<metapb::Peer>,
+    /// The peers which the leader considers down, as reported by PD.
+    #[new(default)]
+    pub down_peers: Vec<metapb::Peer>,
+    /// The peers which are still catching up with the leader, as reported by PD.
+    #[new(default)]
+    pub pending_peers: Vec<metapb::Peer>,
+    /// Whether TiKV rejected a request because the region is being merged into another. It is
+    /// reset once the region is loaded again, after the merge.
+    #[new(default)]
+    pub merging: bool,
 }
 
 impl Eq for RegionWithLeader {}
@@ -80,6 +94,12 @@ impl RegionWithLeader {
         self.region.get_id()
     }
 
+    /// Whether some peers of the region are down or catching up, so that the region may not
+    /// tolerate the loss of another peer, and a replica read may not be served by any peer.
+    pub fn has_unavailable_peers(&self) -> bool {
+        !self.down_peers.is_empty() || !self.pending_peers.is_empty()
+    }
+
     pub fn get_store_id(&self) -> Result<StoreId> {
         self.leader
             .as_ref()
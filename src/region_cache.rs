@@ -1,21 +1,100 @@
 // Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
 
+//! Caching of region information fetched from PD.
+//!
+//! Where regions are cached is pluggable through the [`RegionCacheBackend`] trait, the default
+//! backend is [`InMemoryRegionCache`].
+
 use crate::{
+    clock::ClockHandle,
+    config::RegionRefresh,
     pd::{RetryClient, RetryClientTrait},
     region::{RegionId, RegionVerId, RegionWithLeader, StoreId},
+    stats::observe_region_cache,
+    trace::in_span,
     Key, Result,
 };
+use async_trait::async_trait;
 use std::{
     collections::{BTreeMap, HashMap, HashSet},
-    sync::Arc,
+    fmt,
+    sync::{Arc, Weak},
 };
 use tikv_client_common::Error;
 use tikv_client_pd::Cluster;
 use tikv_client_proto::metapb::{self, Store};
-use tokio::sync::{Notify, RwLock};
+use tokio::sync::{Mutex, Notify, RwLock};
 
 const MAX_RETRY_WAITING_CONCURRENT_REQUEST: usize = 4;
 
+/// Storage for cached regions.
+///
+/// A backend only stores regions, fetching regions from PD on a cache miss is done by the client.
+/// Implement this trait to keep regions somewhere other than the memory of a single client, e.g.
+/// in memory shared between processes, or to use a custom eviction policy. A backend may drop any
+/// region at any time, which just causes the region to be fetched from PD again.
+///
+/// Use [`Config::with_region_cache_backend`](crate::Config::with_region_cache_backend) to set the
+/// backend of a client.
+#[async_trait]
+pub trait RegionCacheBackend: Send + Sync + 'static {
+    /// The cached region which contains `key`, if any.
+    async fn get_by_key(&self, key: &Key) -> Option<RegionWithLeader>;
+
+    /// The cached region with the given id, if any.
+    async fn get_by_id(&self, id: RegionId) -> Option<RegionWithLeader>;
+
+    /// Cache `region`.
+    ///
+    /// Cached regions which have the same id as `region` or overlap with it are outdated and must
+    /// be removed.
+    async fn insert(&self, region: RegionWithLeader);
+
+    /// Set the leader of the cached region with the given version.
+    ///
+    /// Returns [`Error::EntryNotFoundInRegionCache`] if the region is not cached.
+    async fn update_leader(&self, ver_id: RegionVerId, leader: metapb::Peer) -> Result<()>;
+
+    /// Remove the region with the given version from the cache.
+    async fn invalidate(&self, ver_id: RegionVerId);
+}
+
+/// A [`RegionCacheBackend`] which can be set in a [`Config`](crate::Config).
+///
+/// Two handles are equal if they refer to the same backend.
+#[derive(Clone)]
+pub struct RegionCacheBackendHandle(Arc<dyn RegionCacheBackend>);
+
+impl RegionCacheBackendHandle {
+    pub fn new(backend: impl RegionCacheBackend) -> RegionCacheBackendHandle {
+        RegionCacheBackendHandle(Arc::new(backend))
+    }
+
+    pub(crate) fn into_inner(self) -> Arc<dyn RegionCacheBackend> {
+        self.0
+    }
+}
+
+impl From<Arc<dyn RegionCacheBackend>> for RegionCacheBackendHandle {
+    fn from(backend: Arc<dyn RegionCacheBackend>) -> RegionCacheBackendHandle {
+        RegionCacheBackendHandle(backend)
+    }
+}
+
+impl PartialEq for RegionCacheBackendHandle {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::as_ptr(&self.0) as *const () == Arc::as_ptr(&other.0) as *const ()
+    }
+}
+
+impl fmt::Debug for RegionCacheBackendHandle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("RegionCacheBackendHandle")
+            .field(&(Arc::as_ptr(&self.0) as *const ()))
+            .finish()
+    }
+}
+
 struct RegionCacheMap {
     /// RegionVerID -> Region. It stores the concrete region caches.
     /// RegionVerID is the unique identifer of a region *across time*.
@@ -28,9 +107,6 @@ struct RegionCacheMap {
     /// RegionID -> RegionVerID. Note: regions with identical ID doesn't necessarily
     /// mean they are the same, they can be different regions across time.
     id_to_ver_id: HashMap<RegionId, RegionVerId>,
-    /// We don't want to spawn multiple queries querying a same region id. If a
-    /// request is on its way, others will wait for its completion.
-    on_my_way_id: HashMap<RegionId, Arc<Notify>>,
 }
 
 impl RegionCacheMap {
@@ -39,23 +115,148 @@ impl RegionCacheMap {
             ver_id_to_region: HashMap::new(),
             key_to_ver_id: BTreeMap::new(),
             id_to_ver_id: HashMap::new(),
-            on_my_way_id: HashMap::new(),
         }
     }
 }
 
-pub struct RegionCache<Client = RetryClient<Cluster>> {
+/// The default [`RegionCacheBackend`], which keeps all regions in the memory of the client and
+/// never evicts them.
+pub struct InMemoryRegionCache {
     region_cache: RwLock<RegionCacheMap>,
+}
+
+impl Default for InMemoryRegionCache {
+    fn default() -> InMemoryRegionCache {
+        InMemoryRegionCache {
+            region_cache: RwLock::new(RegionCacheMap::new()),
+        }
+    }
+}
+
+#[async_trait]
+impl RegionCacheBackend for InMemoryRegionCache {
+    async fn get_by_key(&self, key: &Key) -> Option<RegionWithLeader> {
+        let cache = self.region_cache.read().await;
+        let (_, candidate_region_ver_id) = cache.key_to_ver_id.range(..=key).next_back()?;
+        let region = cache.ver_id_to_region.get(candidate_region_ver_id).unwrap();
+        if region.contains(key) {
+            Some(region.clone())
+        } else {
+            None
+        }
+    }
+
+    async fn get_by_id(&self, id: RegionId) -> Option<RegionWithLeader> {
+        let cache = self.region_cache.read().await;
+        let ver_id = cache.id_to_ver_id.get(&id)?;
+        Some(cache.ver_id_to_region.get(ver_id).unwrap().clone())
+    }
+
+    async fn insert(&self, region: RegionWithLeader) {
+        // FIXME: will it be the performance bottleneck?
+        let mut cache = self.region_cache.write().await;
+
+        let end_key = region.end_key();
+        let mut to_be_removed: HashSet<RegionVerId> = HashSet::new();
+
+        if let Some(ver_id) = cache.id_to_ver_id.get(&region.id()) {
+            if ver_id != &region.ver_id() {
+                to_be_removed.insert(ver_id.clone());
+            }
+        }
+
+        let mut search_range = {
+            if end_key.is_empty() {
+                cache.key_to_ver_id.range(..)
+            } else {
+                cache.key_to_ver_id.range(..end_key)
+            }
+        };
+        while let Some((_, ver_id_in_cache)) = search_range.next_back() {
+            let region_in_cache = cache.ver_id_to_region.get(ver_id_in_cache).unwrap();
+
+            if region_in_cache.region.end_key > region.region.start_key {
+                to_be_removed.insert(ver_id_in_cache.clone());
+            } else {
+                break;
+            }
+        }
+
+        for ver_id in to_be_removed {
+            let region_to_remove = cache.ver_id_to_region.remove(&ver_id).unwrap();
+            cache.key_to_ver_id.remove(&region_to_remove.start_key());
+            cache.id_to_ver_id.remove(&region_to_remove.id());
+        }
+        cache
+            .key_to_ver_id
+            .insert(region.start_key(), region.ver_id());
+        cache.id_to_ver_id.insert(region.id(), region.ver_id());
+        cache.ver_id_to_region.insert(region.ver_id(), region);
+    }
+
+    async fn update_leader(&self, ver_id: RegionVerId, leader: metapb::Peer) -> Result<()> {
+        let mut cache = self.region_cache.write().await;
+        let region_entry = cache
+            .ver_id_to_region
+            .get_mut(&ver_id)
+            .ok_or(Error::EntryNotFoundInRegionCache)?;
+        region_entry.leader = Some(leader);
+        Ok(())
+    }
+
+    async fn invalidate(&self, ver_id: RegionVerId) {
+        let mut cache = self.region_cache.write().await;
+        let region_entry = cache.ver_id_to_region.get(&ver_id);
+        if let Some(region) = region_entry {
+            let id = region.id();
+            let start_key = region.start_key();
+            cache.ver_id_to_region.remove(&ver_id);
+            cache.id_to_ver_id.remove(&id);
+            cache.key_to_ver_id.remove(&start_key);
+        }
+    }
+}
+
+pub struct RegionCache<Client = RetryClient<Cluster>> {
+    backend: Arc<dyn RegionCacheBackend>,
+    /// We don't want to spawn multiple queries querying a same region id. If a
+    /// request is on its way, others will wait for its completion.
+    on_my_way_id: Mutex<HashMap<RegionId, Arc<Notify>>>,
     store_cache: RwLock<HashMap<StoreId, Store>>,
     inner_client: Arc<Client>,
+    /// How many times each cached region was used since the hot regions were last refreshed, if
+    /// they are refreshed, see [`Config::with_region_refresh`](crate::Config::with_region_refresh).
+    hits: Option<std::sync::Mutex<HashMap<RegionId, u64>>>,
 }
 
 impl<Client> RegionCache<Client> {
     pub fn new(inner_client: Arc<Client>) -> RegionCache<Client> {
+        RegionCache::with_backend(inner_client, Arc::new(InMemoryRegionCache::default()))
+    }
+
+    pub fn with_backend(
+        inner_client: Arc<Client>,
+        backend: Arc<dyn RegionCacheBackend>,
+    ) -> RegionCache<Client> {
         RegionCache {
-            region_cache: RwLock::new(RegionCacheMap::new()),
+            backend,
+            on_my_way_id: Mutex::new(HashMap::new()),
             store_cache: RwLock::new(HashMap::new()),
             inner_client,
+            hits: None,
+        }
+    }
+
+    /// Count how many times each cached region is used, so that the most used regions can be
+    /// refreshed by [`refresh_hot_regions`](RegionCache::refresh_hot_regions).
+    pub fn with_hit_tracking(mut self) -> RegionCache<Client> {
+        self.hits = Some(Default::default());
+        self
+    }
+
+    fn record_hit(&self, id: RegionId) {
+        if let Some(hits) = &self.hits {
+            *hits.lock().unwrap().entry(id).or_default() += 1;
         }
     }
 }
@@ -63,49 +264,36 @@ impl<Client> RegionCache<Client> {
 impl<C: RetryClientTrait> RegionCache<C> {
     // Retrieve cache entry by key. If there's no entry, query PD and update cache.
     pub async fn get_region_by_key(&self, key: &Key) -> Result<RegionWithLeader> {
-        let region_cache_guard = self.region_cache.read().await;
-        let res = {
-            region_cache_guard
-                .key_to_ver_id
-                .range(..=key)
-                .next_back()
-                .map(|(x, y)| (x.clone(), y.clone()))
-        };
-
-        if let Some((_, candidate_region_ver_id)) = res {
-            let region = region_cache_guard
-                .ver_id_to_region
-                .get(&candidate_region_ver_id)
-                .unwrap();
-
-            if region.contains(key) {
-                return Ok(region.clone());
+        let region = self.backend.get_by_key(key).await;
+        observe_region_cache(region.is_some());
+        match region {
+            Some(region) => {
+                self.record_hit(region.id());
+                Ok(region)
             }
+            None => self.read_through_region_by_key(key.clone()).await,
         }
-        drop(region_cache_guard);
-        self.read_through_region_by_key(key.clone()).await
     }
 
     // Retrieve cache entry by RegionId. If there's no entry, query PD and update cache.
     pub async fn get_region_by_id(&self, id: RegionId) -> Result<RegionWithLeader> {
-        for _ in 0..=MAX_RETRY_WAITING_CONCURRENT_REQUEST {
-            let region_cache_guard = self.region_cache.read().await;
-
+        for attempt in 0..=MAX_RETRY_WAITING_CONCURRENT_REQUEST {
             // check cache
-            let ver_id = region_cache_guard.id_to_ver_id.get(&id);
-            if let Some(ver_id) = ver_id {
-                let region = region_cache_guard.ver_id_to_region.get(ver_id).unwrap();
-                return Ok(region.clone());
+            if let Some(region) = self.backend.get_by_id(id).await {
+                // a region loaded by a concurrent request was a miss
+                observe_region_cache(attempt == 0);
+                self.record_hit(id);
+                return Ok(region);
             }
 
             // check concurrent requests
-            let notify = region_cache_guard.on_my_way_id.get(&id).cloned();
-            drop(region_cache_guard);
+            let notify = self.on_my_way_id.lock().await.get(&id).cloned();
 
             if let Some(n) = notify {
                 n.notified().await;
                 continue;
             } else {
+                observe_region_cache(false);
                 return self.read_through_region_by_id(id).await;
             }
         }
@@ -125,31 +313,60 @@ impl<C: RetryClientTrait> RegionCache<C> {
 
     /// Force read through (query from PD) and update cache
     pub async fn read_through_region_by_key(&self, key: Key) -> Result<RegionWithLeader> {
-        let region = self.inner_client.clone().get_region(key.into()).await?;
+        let region = in_span!(
+            self.inner_client.clone().get_region(key.into()),
+            "region_lookup",
+            key = ?key
+        )
+        .await?;
         self.add_region(region.clone()).await;
         Ok(region)
     }
 
     /// Force read through (query from PD) and update cache
-    async fn read_through_region_by_id(&self, id: RegionId) -> Result<RegionWithLeader> {
+    pub async fn read_through_region_by_id(&self, id: RegionId) -> Result<RegionWithLeader> {
         // put a notify to let others know the region id is being queried
         let notify = Arc::new(Notify::new());
-        {
-            let mut region_cache_guard = self.region_cache.write().await;
-            region_cache_guard.on_my_way_id.insert(id, notify.clone());
-        }
+        self.on_my_way_id.lock().await.insert(id, notify.clone());
 
-        let region = self.inner_client.clone().get_region_by_id(id).await?;
-        self.add_region(region.clone()).await;
+        let region = in_span!(
+            self.inner_client.clone().get_region_by_id(id),
+            "region_lookup",
+            region_id = id
+        )
+        .await;
+        if let Ok(region) = &region {
+            self.add_region(region.clone()).await;
+        }
 
-        // notify others
+        // notify others, also if the lookup failed, so that they don't wait forever
         {
-            let mut region_cache_guard = self.region_cache.write().await;
+            let mut on_my_way_id = self.on_my_way_id.lock().await;
             notify.notify_waiters();
-            region_cache_guard.on_my_way_id.remove(&id);
+            on_my_way_id.remove(&id);
         }
 
-        Ok(region)
+        region
+    }
+
+    /// Load the `max_regions` regions which were used the most since the last refresh from PD
+    /// again, so that their leader changes and splits are known before a request to them fails.
+    /// Returns the number of regions refreshed.
+    pub async fn refresh_hot_regions(&self, max_regions: usize) -> usize {
+        let hits = match &self.hits {
+            Some(hits) => std::mem::take(&mut *hits.lock().unwrap()),
+            None => return 0,
+        };
+        let mut hits: Vec<(RegionId, u64)> = hits.into_iter().collect();
+        hits.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+        let mut refreshed = 0;
+        for (id, _) in hits.into_iter().take(max_regions) {
+            // a region which no longer exists is loaded by key when it is needed again
+            if self.read_through_region_by_id(id).await.is_ok() {
+                refreshed += 1;
+            }
+        }
+        refreshed
     }
 
     async fn read_through_store_by_id(&self, id: StoreId) -> Result<Store> {
@@ -159,45 +376,7 @@ impl<C: RetryClientTrait> RegionCache<C> {
     }
 
     pub async fn add_region(&self, region: RegionWithLeader) {
-        // FIXME: will it be the performance bottleneck?
-        let mut cache = self.region_cache.write().await;
-
-        let end_key = region.end_key();
-        let mut to_be_removed: HashSet<RegionVerId> = HashSet::new();
-
-        if let Some(ver_id) = cache.id_to_ver_id.get(&region.id()) {
-            if ver_id != &region.ver_id() {
-                to_be_removed.insert(ver_id.clone());
-            }
-        }
-
-        let mut search_range = {
-            if end_key.is_empty() {
-                cache.key_to_ver_id.range(..)
-            } else {
-                cache.key_to_ver_id.range(..end_key)
-            }
-        };
-        while let Some((_, ver_id_in_cache)) = search_range.next_back() {
-            let region_in_cache = cache.ver_id_to_region.get(ver_id_in_cache).unwrap();
-
-            if region_in_cache.region.end_key > region.region.start_key {
-                to_be_removed.insert(ver_id_in_cache.clone());
-            } else {
-                break;
-            }
-        }
-
-        for ver_id in to_be_removed {
-            let region_to_remove = cache.ver_id_to_region.remove(&ver_id).unwrap();
-            cache.key_to_ver_id.remove(&region_to_remove.start_key());
-            cache.id_to_ver_id.remove(&region_to_remove.id());
-        }
-        cache
-            .key_to_ver_id
-            .insert(region.start_key(), region.ver_id());
-        cache.id_to_ver_id.insert(region.id(), region.ver_id());
-        cache.ver_id_to_region.insert(region.ver_id(), region);
+        self.backend.insert(region).await
     }
 
     pub async fn update_leader(
@@ -205,34 +384,74 @@ impl<C: RetryClientTrait> RegionCache<C> {
         ver_id: crate::region::RegionVerId,
         leader: metapb::Peer,
     ) -> Result<()> {
-        let mut cache = self.region_cache.write().await;
-        let region_entry = cache
-            .ver_id_to_region
-            .get_mut(&ver_id)
-            .ok_or(Error::EntryNotFoundInRegionCache)?;
-        region_entry.leader = Some(leader);
-        Ok(())
+        self.backend.update_leader(ver_id, leader).await
     }
 
     pub async fn invalidate_region_cache(&self, ver_id: crate::region::RegionVerId) {
-        let mut cache = self.region_cache.write().await;
-        let region_entry = cache.ver_id_to_region.get(&ver_id);
-        if let Some(region) = region_entry {
-            let id = region.id();
-            let start_key = region.start_key();
-            cache.ver_id_to_region.remove(&ver_id);
-            cache.id_to_ver_id.remove(&id);
-            cache.key_to_ver_id.remove(&start_key);
+        self.backend.invalidate(ver_id).await
+    }
+
+    /// Replace the region with the given version by `regions`, e.g. the regions it was split
+    /// into, so that they don't have to be loaded from PD.
+    pub async fn replace_region(
+        &self,
+        ver_id: crate::region::RegionVerId,
+        regions: Vec<RegionWithLeader>,
+    ) {
+        self.backend.invalidate(ver_id).await;
+        for region in regions {
+            self.backend.insert(region).await;
+        }
+    }
+
+    /// Set whether the cached region with the given version is being merged, see
+    /// [`RegionWithLeader::merging`]. Nothing is done if the region is not cached.
+    pub async fn set_merging(&self, ver_id: RegionVerId, merging: bool) {
+        if let Some(mut region) = self.backend.get_by_id(ver_id.id).await {
+            if region.ver_id() == ver_id && region.merging != merging {
+                region.merging = merging;
+                self.backend.insert(region).await;
+            }
         }
     }
+
+    /// Remove the store with the given id from the cache, so that its address is loaded from PD
+    /// again.
+    pub async fn invalidate_store_cache(&self, id: StoreId) {
+        self.store_cache.write().await.remove(&id);
+    }
+}
+
+/// Refreshes the hot regions of `cache` every `refresh.interval` until the cache is dropped, see
+/// [`Config::with_region_refresh`](crate::Config::with_region_refresh).
+pub(crate) fn spawn_region_refresh<C>(
+    cache: Weak<RegionCache<C>>,
+    refresh: RegionRefresh,
+    clock: ClockHandle,
+) where
+    C: RetryClientTrait + Send + Sync + 'static,
+{
+    tokio::spawn(async move {
+        loop {
+            clock.sleep(refresh.interval).await;
+            let cache = match cache.upgrade() {
+                Some(cache) => cache,
+                None => return,
+            };
+            cache
+                .refresh_hot_regions(refresh.max_regions as usize)
+                .await;
+        }
+    });
 }
 
 #[cfg(test)]
 mod test {
-    use super::RegionCache;
+    use super::{InMemoryRegionCache, RegionCache, RegionCacheBackend};
     use crate::{
         pd::RetryClientTrait,
-        region::{RegionId, RegionWithLeader},
+        region::{RegionId, RegionVerId, RegionWithLeader},
+        stats::{test::recorded, Metric},
         Key, Result,
     };
     use async_trait::async_trait;
@@ -303,6 +522,10 @@ mod test {
         async fn update_safepoint(self: Arc<Self>, _safepoint: u64) -> Result<bool> {
             todo!()
         }
+
+        async fn get_safepoint(self: Arc<Self>) -> Result<u64> {
+            todo!()
+        }
     }
 
     #[tokio::test]
@@ -322,6 +545,7 @@ mod test {
                     store_id: 1,
                     ..Default::default()
                 }),
+                ..Default::default()
             },
         );
         retry_client.regions.lock().await.insert(
@@ -337,6 +561,7 @@ mod test {
                     store_id: 2,
                     ..Default::default()
                 }),
+                ..Default::default()
             },
         );
 
@@ -378,7 +603,8 @@ mod test {
     #[tokio::test]
     async fn test_add_disjoint_regions() {
         let retry_client = Arc::new(MockRetryClient::default());
-        let cache = RegionCache::new(retry_client.clone());
+        let backend = Arc::new(InMemoryRegionCache::default());
+        let cache = RegionCache::with_backend(retry_client.clone(), backend.clone());
         let region1 = region(1, vec![], vec![10]);
         let region2 = region(2, vec![10], vec![20]);
         let region3 = region(3, vec![30], vec![]);
@@ -391,13 +617,14 @@ mod test {
         expected_cache.insert(vec![10].into(), region2);
         expected_cache.insert(vec![30].into(), region3);
 
-        assert(&cache, &expected_cache).await
+        assert(&backend, &expected_cache).await
     }
 
     #[tokio::test]
     async fn test_add_intersecting_regions() {
         let retry_client = Arc::new(MockRetryClient::default());
-        let cache = RegionCache::new(retry_client.clone());
+        let backend = Arc::new(InMemoryRegionCache::default());
+        let cache = RegionCache::with_backend(retry_client.clone(), backend.clone());
 
         cache.add_region(region(1, vec![], vec![10])).await;
         cache.add_region(region(2, vec![10], vec![20])).await;
@@ -410,26 +637,26 @@ mod test {
         expected_cache.insert(vec![10].into(), region(2, vec![10], vec![20]));
         expected_cache.insert(vec![20].into(), region(5, vec![20], vec![35]));
         expected_cache.insert(vec![50].into(), region(4, vec![50], vec![60]));
-        assert(&cache, &expected_cache).await;
+        assert(&backend, &expected_cache).await;
 
         cache.add_region(region(6, vec![15], vec![25])).await;
         let mut expected_cache = BTreeMap::new();
         expected_cache.insert(vec![].into(), region(1, vec![], vec![10]));
         expected_cache.insert(vec![15].into(), region(6, vec![15], vec![25]));
         expected_cache.insert(vec![50].into(), region(4, vec![50], vec![60]));
-        assert(&cache, &expected_cache).await;
+        assert(&backend, &expected_cache).await;
 
         cache.add_region(region(7, vec![20], vec![])).await;
         let mut expected_cache = BTreeMap::new();
         expected_cache.insert(vec![].into(), region(1, vec![], vec![10]));
         expected_cache.insert(vec![20].into(), region(7, vec![20], vec![]));
-        assert(&cache, &expected_cache).await;
+        assert(&backend, &expected_cache).await;
 
         cache.add_region(region(8, vec![], vec![15])).await;
         let mut expected_cache = BTreeMap::new();
         expected_cache.insert(vec![].into(), region(8, vec![], vec![15]));
         expected_cache.insert(vec![20].into(), region(7, vec![20], vec![]));
-        assert(&cache, &expected_cache).await;
+        assert(&backend, &expected_cache).await;
     }
 
     #[tokio::test]
@@ -461,15 +688,146 @@ mod test {
         assert!(cache.get_region_by_key(&vec![20].into()).await.is_err());
         assert!(cache.get_region_by_key(&vec![25].into()).await.is_err());
         assert_eq!(cache.get_region_by_key(&vec![60].into()).await?, region4);
+
+        // the keys outside of the cached regions are looked up in PD
+        let hit = r#"counter tikv_region_cache_total ["hit"] 1"#;
+        let miss = r#"counter tikv_region_cache_total ["miss"] 1"#;
+        assert_eq!(
+            recorded(Metric::TikvRegionCacheTotal),
+            [hit, hit, hit, miss, miss, hit]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_replace_region() -> Result<()> {
+        let retry_client = Arc::new(MockRetryClient::default());
+        let cache = RegionCache::new(retry_client.clone());
+        let old = region(1, vec![], vec![]);
+        cache.add_region(old.clone()).await;
+
+        // the region was split
+        let mut left = region(1, vec![], vec![10]);
+        left.region.mut_region_epoch().set_version(1);
+        let right = region(2, vec![10], vec![]);
+        cache
+            .replace_region(old.ver_id(), vec![left.clone(), right.clone()])
+            .await;
+
+        assert_eq!(cache.get_region_by_key(&vec![5].into()).await?, left);
+        assert_eq!(cache.get_region_by_key(&vec![20].into()).await?, right);
+        assert_eq!(cache.get_region_by_id(1).await?, left);
+        assert_eq!(retry_client.get_region_count.load(SeqCst), 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_set_merging() -> Result<()> {
+        let retry_client = Arc::new(MockRetryClient::default());
+        let cache = RegionCache::new(retry_client.clone());
+        let region = region(1, vec![], vec![]);
+        cache.add_region(region.clone()).await;
+
+        cache.set_merging(region.ver_id(), true).await;
+        assert!(cache.get_region_by_id(1).await?.merging);
+        cache.set_merging(region.ver_id(), false).await;
+        assert!(!cache.get_region_by_id(1).await?.merging);
+
+        // another version of the region is left alone
+        let mut stale = region.ver_id();
+        stale.ver += 1;
+        cache.set_merging(stale, true).await;
+        assert!(!cache.get_region_by_id(1).await?.merging);
+        assert_eq!(retry_client.get_region_count.load(SeqCst), 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_refresh_hot_regions() -> Result<()> {
+        let retry_client = Arc::new(MockRetryClient::default());
+        let cache = RegionCache::new(retry_client.clone()).with_hit_tracking();
+        cache.add_region(region(1, vec![], vec![10])).await;
+        cache.add_region(region(2, vec![10], vec![20])).await;
+        cache.add_region(region(3, vec![20], vec![])).await;
+        // the leader of region 2 moved
+        let mut moved = region(2, vec![10], vec![20]);
+        moved.leader = Some(metapb::Peer {
+            store_id: 2,
+            ..Default::default()
+        });
+        retry_client.regions.lock().await.insert(2, moved);
+
+        cache.get_region_by_key(&vec![5].into()).await?;
+        for _ in 0..3 {
+            cache.get_region_by_id(2).await?;
+        }
+        assert_eq!(cache.refresh_hot_regions(1).await, 1);
+        assert_eq!(retry_client.get_region_count.load(SeqCst), 1);
+        assert_eq!(cache.get_region_by_id(2).await?.leader.unwrap().store_id, 2);
+
+        // the hits are counted from the last refresh on
+        assert_eq!(cache.refresh_hot_regions(1).await, 1);
+        assert_eq!(retry_client.get_region_count.load(SeqCst), 2);
+        assert_eq!(cache.refresh_hot_regions(1).await, 0);
+
+        // region 3 is unknown to PD, a failed refresh doesn't block the next lookup of it
+        let stale = cache.get_region_by_id(3).await?;
+        assert_eq!(cache.refresh_hot_regions(1).await, 0);
+        cache.invalidate_region_cache(stale.ver_id()).await;
+        retry_client.regions.lock().await.insert(3, stale);
+        assert_eq!(cache.get_region_by_id(3).await?.id(), 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_custom_backend() -> Result<()> {
+        // a backend which caches nothing
+        struct NoCache;
+
+        #[async_trait]
+        impl RegionCacheBackend for NoCache {
+            async fn get_by_key(&self, _key: &Key) -> Option<RegionWithLeader> {
+                None
+            }
+
+            async fn get_by_id(&self, _id: RegionId) -> Option<RegionWithLeader> {
+                None
+            }
+
+            async fn insert(&self, _region: RegionWithLeader) {}
+
+            async fn update_leader(
+                &self,
+                _ver_id: RegionVerId,
+                _leader: metapb::Peer,
+            ) -> Result<()> {
+                Err(Error::EntryNotFoundInRegionCache)
+            }
+
+            async fn invalidate(&self, _ver_id: RegionVerId) {}
+        }
+
+        let retry_client = Arc::new(MockRetryClient::default());
+        retry_client
+            .regions
+            .lock()
+            .await
+            .insert(1, region(1, vec![], vec![]));
+        let cache = RegionCache::with_backend(retry_client.clone(), Arc::new(NoCache));
+
+        assert_eq!(cache.get_region_by_id(1).await?.id(), 1);
+        assert_eq!(cache.get_region_by_key(&vec![5].into()).await?.id(), 1);
+        assert_eq!(cache.get_region_by_id(1).await?.id(), 1);
+        assert_eq!(retry_client.get_region_count.load(SeqCst), 3);
         Ok(())
     }
 
     // a helper function to assert the cache is in expected state
     async fn assert(
-        cache: &RegionCache<MockRetryClient>,
+        backend: &InMemoryRegionCache,
         expected_cache: &BTreeMap<Key, RegionWithLeader>,
     ) {
-        let guard = cache.region_cache.read().await;
+        let guard = backend.region_cache.read().await;
         let mut actual_keys = guard.ver_id_to_region.values().collect::<Vec<_>>();
         let mut expected_keys = expected_cache.values().collect::<Vec<_>>();
         actual_keys.sort_by_cached_key(|r| r.id());
@@ -0,0 +1,114 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+use crate::{store::RegionStore, Result};
+use std::{fmt, sync::Arc, time::Duration};
+use tikv_client_proto::kvrpcpb;
+
+/// A hook which amends the `kvrpcpb::Context` of each request before it is sent to TiKV, e.g. to
+/// set fields the client does not manage.
+///
+/// It is called last, so it may also override the fields set by the client. Changing the region,
+/// epoch or peer makes TiKV reject the request with a region error.
+#[derive(Clone)]
+pub struct ContextHook(Arc<dyn Fn(&mut kvrpcpb::Context) + Send + Sync>);
+
+impl ContextHook {
+    pub fn new(hook: impl Fn(&mut kvrpcpb::Context) + Send + Sync + 'static) -> ContextHook {
+        ContextHook(Arc::new(hook))
+    }
+}
+
+impl fmt::Debug for ContextHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ContextHook")
+    }
+}
+
+/// Hooks are equal if they are clones of each other.
+impl PartialEq for ContextHook {
+    fn eq(&self, other: &ContextHook) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for ContextHook {}
+
+/// The fields of the `kvrpcpb::Context` of a plan's requests which do not depend on the region.
+///
+/// This is the only place where the context of requests is assembled: the region, epoch and peer
+/// come from the store a request is sent to, the other fields from here.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RequestContext {
+    pub priority: kvrpcpb::CommandPri,
+    pub isolation_level: kvrpcpb::IsolationLevel,
+    /// Tags the requests, e.g. for TiKV to attribute resource usage to.
+    pub resource_group_tag: Vec<u8>,
+    /// If set, TiKV aborts a request once it has been processing it for longer.
+    pub max_execution_duration: Option<Duration>,
+    pub hook: Option<ContextHook>,
+}
+
+impl Default for RequestContext {
+    fn default() -> RequestContext {
+        RequestContext {
+            priority: kvrpcpb::CommandPri::Normal,
+            isolation_level: kvrpcpb::IsolationLevel::Si,
+            resource_group_tag: Vec::new(),
+            max_execution_duration: None,
+            hook: None,
+        }
+    }
+}
+
+impl RequestContext {
+    /// The context of a request sent to `store`.
+    pub fn build(&self, store: &RegionStore) -> Result<kvrpcpb::Context> {
+        let mut context = store.context()?;
+        context.set_priority(self.priority);
+        context.set_isolation_level(self.isolation_level);
+        context.resource_group_tag = self.resource_group_tag.clone();
+        if let Some(duration) = self.max_execution_duration {
+            context.set_max_execution_duration_ms(duration.as_millis() as u64);
+        }
+        if let Some(hook) = &self.hook {
+            (hook.0)(&mut context);
+        }
+        Ok(context)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mock::{MockKvClient, MockPdClient};
+
+    #[test]
+    fn test_build_context() {
+        let store = RegionStore::new(MockPdClient::region2(), Arc::new(MockKvClient::default()));
+        let mut request_context = RequestContext {
+            priority: kvrpcpb::CommandPri::Low,
+            isolation_level: kvrpcpb::IsolationLevel::Rc,
+            resource_group_tag: b"analytics".to_vec(),
+            max_execution_duration: Some(Duration::from_millis(1500)),
+            hook: None,
+        };
+        let context = request_context.build(&store).unwrap();
+        assert_eq!(context.region_id, 2);
+        assert_eq!(context.get_peer().store_id, 42);
+        assert_eq!(context.get_priority(), kvrpcpb::CommandPri::Low);
+        assert_eq!(context.get_isolation_level(), kvrpcpb::IsolationLevel::Rc);
+        assert_eq!(context.resource_group_tag, b"analytics");
+        assert_eq!(context.max_execution_duration_ms, 1500);
+
+        // the hook is called last, so it overrides the fields set by the client
+        request_context.hook = Some(ContextHook::new(|context| {
+            context.set_priority(kvrpcpb::CommandPri::High);
+            context.not_fill_cache = true;
+        }));
+        let context = request_context.build(&store).unwrap();
+        assert_eq!(context.region_id, 2);
+        assert_eq!(context.get_priority(), kvrpcpb::CommandPri::High);
+        assert!(context.not_fill_cache);
+        assert_eq!(context.resource_group_tag, b"analytics");
+    }
+}
@@ -0,0 +1,158 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+use crate::{backoff::Backoff, Error};
+use std::{fmt, sync::Arc, time::Duration};
+
+type ExhaustedCallback = dyn Fn(&Error, u32) -> Option<Backoff> + Send + Sync;
+
+/// What a request does once its retries are exhausted, i.e. once its [`Backoff`] gives up, set
+/// per class of operations in [`RetryOptions`](crate::RetryOptions).
+///
+/// E.g. an online service fails fast with the last error, while a batch job keeps retrying
+/// through a rolling restart of TiKV by extending the retries of its writes with a callback.
+#[derive(Clone, Default)]
+pub enum OnRetriesExhausted {
+    /// Fail with the error of the last attempt. This is the default.
+    #[default]
+    LastError,
+    /// Fail with [`RetriesExhausted`](Error::RetriesExhausted), which carries the errors of all
+    /// attempts as well as the last one.
+    History,
+    /// Call the callback with the error of the last attempt and the number of attempts so far.
+    /// If it returns a backoff, the request is retried with it, and the callback is called again
+    /// once that backoff gives up. Otherwise the request fails with the last error.
+    Callback(Arc<ExhaustedCallback>),
+}
+
+impl OnRetriesExhausted {
+    /// The policy calling `callback`, see [`Callback`](OnRetriesExhausted::Callback).
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use tikv_client::{Backoff, OnRetriesExhausted};
+    /// // retry for up to 100 attempts, backing off by one second
+    /// let policy = OnRetriesExhausted::callback(|_error, attempts| {
+    ///     (attempts < 100).then(|| Backoff::no_jitter_backoff(1000, 1000, 100 - attempts))
+    /// });
+    /// ```
+    pub fn callback(
+        callback: impl Fn(&Error, u32) -> Option<Backoff> + Send + Sync + 'static,
+    ) -> OnRetriesExhausted {
+        OnRetriesExhausted::Callback(Arc::new(callback))
+    }
+}
+
+impl fmt::Debug for OnRetriesExhausted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OnRetriesExhausted::LastError => f.write_str("LastError"),
+            OnRetriesExhausted::History => f.write_str("History"),
+            OnRetriesExhausted::Callback(_) => f.write_str("Callback"),
+        }
+    }
+}
+
+// Not derived, callbacks are only equal to their clones.
+impl PartialEq for OnRetriesExhausted {
+    fn eq(&self, other: &OnRetriesExhausted) -> bool {
+        match (self, other) {
+            (OnRetriesExhausted::Callback(a), OnRetriesExhausted::Callback(b)) => Arc::ptr_eq(a, b),
+            (a, b) => std::mem::discriminant(a) == std::mem::discriminant(b),
+        }
+    }
+}
+
+impl Eq for OnRetriesExhausted {}
+
+/// The retries of a plan: its backoff, and what to do once the backoff gives up.
+#[derive(Clone, Debug)]
+pub(crate) struct Retries {
+    pub backoff: Backoff,
+    on_exhausted: OnRetriesExhausted,
+    /// The number of failed attempts so far.
+    attempts: u32,
+    /// The errors of the failed attempts, only kept for [`OnRetriesExhausted::History`].
+    history: Vec<String>,
+}
+
+impl Retries {
+    pub fn new(backoff: Backoff, on_exhausted: OnRetriesExhausted) -> Retries {
+        Retries {
+            backoff,
+            on_exhausted,
+            attempts: 0,
+            history: Vec::new(),
+        }
+    }
+
+    /// The sleep before retrying the attempt which failed with `error`, `None` once the retries
+    /// are exhausted, in which case the plan fails with [`exhausted`](Retries::exhausted).
+    pub fn next_delay_duration(&mut self, error: &Error) -> Option<Duration> {
+        self.attempts += 1;
+        if self.on_exhausted == OnRetriesExhausted::History {
+            self.history.push(error.to_string());
+        }
+        if let Some(delay) = self.backoff.next_delay_duration() {
+            return Some(delay);
+        }
+        match &self.on_exhausted {
+            OnRetriesExhausted::Callback(callback) => {
+                self.backoff = callback(error, self.attempts)?;
+                self.backoff.next_delay_duration()
+            }
+            _ => None,
+        }
+    }
+
+    /// The error to fail with once the retries are exhausted, `error` being the last one.
+    pub fn exhausted(self, error: Error) -> Error {
+        match self.on_exhausted {
+            OnRetriesExhausted::History => Error::RetriesExhausted {
+                source: Box::new(error),
+                attempts: self.attempts,
+                history: self.history,
+            },
+            _ => error,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retries_exhausted() {
+        let error = || Error::StringError("busy".to_owned());
+        let backoff = Backoff::no_jitter_backoff(1, 1, 1);
+
+        let mut retries = Retries::new(backoff.clone(), OnRetriesExhausted::LastError);
+        assert!(retries.next_delay_duration(&error()).is_some());
+        assert!(retries.next_delay_duration(&error()).is_none());
+        assert!(matches!(retries.exhausted(error()), Error::StringError(_)));
+
+        let mut retries = Retries::new(backoff.clone(), OnRetriesExhausted::History);
+        assert!(retries.next_delay_duration(&error()).is_some());
+        assert!(retries.next_delay_duration(&error()).is_none());
+        match retries.exhausted(error()) {
+            Error::RetriesExhausted {
+                attempts, history, ..
+            } => {
+                assert_eq!(attempts, 2);
+                assert_eq!(history, vec!["busy", "busy"]);
+            }
+            e => panic!("unexpected error: {:?}", e),
+        }
+
+        // the callback extends the retries once
+        let policy = OnRetriesExhausted::callback(|_, attempts| {
+            (attempts < 3).then(|| Backoff::no_jitter_backoff(1, 1, 2))
+        });
+        let mut retries = Retries::new(backoff, policy);
+        for _ in 0..3 {
+            assert!(retries.next_delay_duration(&error()).is_some());
+        }
+        assert!(retries.next_delay_duration(&error()).is_none());
+    }
+}
@@ -9,17 +9,30 @@ use derive_new::new;
 use tikv_client_store::{HasKeyErrors, Request};
 
 pub use self::{
+    context::{ContextHook, RequestContext},
+    exhausted::OnRetriesExhausted,
     plan::{
-        Collect, CollectError, CollectSingle, CollectWithShard, DefaultProcessor, Dispatch,
-        ExtractError, Merge, MergeResponse, Plan, Process, ProcessResponse, ResolveLock,
-        ResponseWithShard, RetryableMultiRegion,
+        Collect, CollectError, CollectSingle, CollectWithShard, Deadline, DefaultProcessor,
+        Dispatch, ExtractError, Merge, MergeResponse, Plan, Process, ProcessResponse, ResolveLock,
+        ResolvedLocks, ResponseWithShard, RetryableMultiRegion,
+    },
+    plan_builder::{NoTarget, PlanBuilder, SingleKey},
+    progress::{Progress, ProgressCallback},
+    scan::{
+        pair_size, scan_items, scan_stream, scan_stream_with_regions, scan_with_byte_limit,
+        scan_with_byte_limit_concurrently, scan_with_limit, scan_with_limit_concurrently,
+        scan_with_limit_reverse, ScanItem, ScanOrder, ScanPrefetch,
     },
-    plan_builder::{PlanBuilder, SingleKey},
     shard::Shardable,
 };
+pub(crate) use self::{exhausted::Retries, progress::ProgressTracker, shard::is_transport_error};
 
+mod context;
+mod exhausted;
 pub mod plan;
 mod plan_builder;
+mod progress;
+mod scan;
 #[macro_use]
 mod shard;
 
@@ -28,6 +41,25 @@ mod shard;
 pub trait KvRequest: Request + Sized + Clone + Sync + Send + 'static {
     /// The expected response to the request.
     type Response: HasKeyErrors + HasLocks + Clone + Send + 'static;
+
+    /// Whether the request can be sent again after it failed without a response, see
+    /// [`Idempotence`].
+    const IDEMPOTENCE: Idempotence = Idempotence::Idempotent;
+}
+
+/// Whether a request can be sent again after it failed without a response, e.g. because the
+/// connection to TiKV was lost, in which case TiKV may or may not have applied it.
+///
+/// Region errors are not affected, TiKV does not apply a request which fails with a region error,
+/// so any request is retried after one.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Idempotence {
+    /// Applying the request again has no further effect, so it is retried blindly.
+    Idempotent,
+    /// Applying the request again may have another effect or fail, e.g. a compare-and-swap which
+    /// already succeeded fails the second time. The request is not retried, the caller has to
+    /// check whether it was applied.
+    RequiresStatusCheck,
 }
 
 #[derive(Clone, Debug, new, Eq, PartialEq)]
@@ -36,6 +68,12 @@ pub struct RetryOptions {
     pub region_backoff: Backoff,
     /// How to retry when a key is locked.
     pub lock_backoff: Backoff,
+    /// What reads do once their retries are exhausted.
+    #[new(default)]
+    pub on_read_exhausted: OnRetriesExhausted,
+    /// What writes do once their retries are exhausted.
+    #[new(default)]
+    pub on_write_exhausted: OnRetriesExhausted,
 }
 
 impl RetryOptions {
@@ -43,6 +81,8 @@ impl RetryOptions {
         RetryOptions {
             region_backoff: DEFAULT_REGION_BACKOFF,
             lock_backoff: OPTIMISTIC_BACKOFF,
+            on_read_exhausted: OnRetriesExhausted::LastError,
+            on_write_exhausted: OnRetriesExhausted::LastError,
         }
     }
 
@@ -50,6 +90,8 @@ impl RetryOptions {
         RetryOptions {
             region_backoff: DEFAULT_REGION_BACKOFF,
             lock_backoff: PESSIMISTIC_BACKOFF,
+            on_read_exhausted: OnRetriesExhausted::LastError,
+            on_write_exhausted: OnRetriesExhausted::LastError,
         }
     }
 
@@ -57,17 +99,47 @@ impl RetryOptions {
         RetryOptions {
             region_backoff: Backoff::no_backoff(),
             lock_backoff: Backoff::no_backoff(),
+            on_read_exhausted: OnRetriesExhausted::LastError,
+            on_write_exhausted: OnRetriesExhausted::LastError,
         }
     }
+
+    /// Set what reads, i.e. gets and scans, including the reads of transactions, do once their
+    /// retries are exhausted, instead of failing with the last error.
+    pub fn on_read_exhausted(mut self, policy: OnRetriesExhausted) -> RetryOptions {
+        self.on_read_exhausted = policy;
+        self
+    }
+
+    /// Set what writes, i.e. raw writes and the locks, commits and rollbacks of transactions, do
+    /// once their retries are exhausted, instead of failing with the last error.
+    pub fn on_write_exhausted(mut self, policy: OnRetriesExhausted) -> RetryOptions {
+        self.on_write_exhausted = policy;
+        self
+    }
+}
+
+/// What a deletion of a range of keys affected, see
+/// [`RawClient::delete_prefix`](crate::RawClient::delete_prefix) and
+/// [`TransactionClient::delete_prefix`](crate::TransactionClient::delete_prefix).
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct DeleteSummary {
+    /// The number of keys deleted, `None` if the keys were deleted by a range deletion in TiKV,
+    /// which does not report how many keys it deleted.
+    pub keys: Option<u64>,
+    /// The number of regions the range spans.
+    pub regions: usize,
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
     use crate::{
+        clock::{ClockHandle, MockClock},
         mock::{MockKvClient, MockPdClient},
+        stats::{test::recorded, Metric},
         store::store_stream_for_keys,
-        transaction::lowering::new_commit_request,
+        transaction::lowering::{new_commit_request, new_get_request},
         Error, Key, Result,
     };
     use grpcio::CallOption;
@@ -75,6 +147,7 @@ mod test {
         any::Any,
         iter,
         sync::{atomic::AtomicUsize, Arc},
+        time::Duration,
     };
     use tikv_client_proto::{kvrpcpb, pdpb::Timestamp, tikvpb::TikvClient};
     use tikv_client_store::HasRegionError;
@@ -117,9 +190,7 @@ mod test {
                 self
             }
 
-            fn set_context(&mut self, _: kvrpcpb::Context) {
-                unreachable!();
-            }
+            fn set_context(&mut self, _: kvrpcpb::Context) {}
         }
 
         #[async_trait]
@@ -176,6 +247,217 @@ mod test {
         assert_eq!(invoking_count.load(std::sync::atomic::Ordering::SeqCst), 4);
     }
 
+    #[tokio::test]
+    async fn test_transport_error_retry() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let pd_client = {
+            let count = count.clone();
+            Arc::new(MockPdClient::new(MockKvClient::with_dispatch_hook(
+                move |_: &dyn Any| {
+                    count.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                    let status = grpcio::RpcStatus::new(grpcio::RpcStatusCode::UNAVAILABLE);
+                    Err(Error::Grpc(grpcio::Error::RpcFailure(status)))
+                },
+            )))
+        };
+        let key: Key = "key".to_owned().into();
+
+        // a get is idempotent, the original call plus the 3 retries
+        let req = new_get_request(key.clone(), Timestamp::default());
+        let plan = crate::request::PlanBuilder::new(pd_client.clone(), req)
+            .retry_multi_region(Backoff::no_jitter_backoff(1, 1, 3))
+            .plan();
+        let err = plan.execute().await.unwrap_err();
+        assert!(matches!(err.without_request(), Error::Grpc(_)));
+        let request = err.failed_request().unwrap();
+        assert_eq!(request.request_type, "kv_get");
+        assert_eq!((request.region_id, request.store_id), (2, Some(42)));
+        assert_eq!(count.swap(0, std::sync::atomic::Ordering::SeqCst), 4);
+        let retry = r#"counter tikv_retry_total ["transport"] 1"#;
+        assert_eq!(recorded(Metric::TikvRetryTotal), [retry, retry, retry]);
+
+        // a commit may have been applied, it is not retried
+        let req = new_commit_request(iter::once(key), Timestamp::default(), Timestamp::default());
+        let plan = crate::request::PlanBuilder::new(pd_client, req)
+            .retry_multi_region(Backoff::no_jitter_backoff(1, 1, 3))
+            .plan();
+        let err = plan.execute().await.unwrap_err();
+        let request = err.failed_request().unwrap();
+        assert_eq!(request.request_type, "kv_commit");
+        assert_eq!((request.region_id, request.store_id), (2, Some(42)));
+        assert_eq!(count.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_epoch_not_match_retry() {
+        // the first get reports that region2 was split, the retry succeeds
+        let count = Arc::new(AtomicUsize::new(0));
+        let dispatched = count.clone();
+        let mut pd_client =
+            MockPdClient::new(MockKvClient::with_dispatch_hook(move |_: &dyn Any| {
+                let mut resp = kvrpcpb::GetResponse::default();
+                if dispatched.fetch_add(1, std::sync::atomic::Ordering::SeqCst) == 0 {
+                    let mut region = MockPdClient::region2();
+                    region.region.mut_region_epoch().set_version(1);
+                    region.region.set_end_key(vec![200]);
+                    let leader = region.leader.clone().unwrap();
+                    region.region.mut_peers().push(leader);
+                    let mut error = tikv_client_proto::errorpb::Error::default();
+                    error
+                        .mut_epoch_not_match()
+                        .mut_current_regions()
+                        .push(region.region);
+                    resp.set_region_error(error);
+                }
+                Ok(Box::new(resp) as Box<dyn Any>)
+            }));
+        let clock = MockClock::auto_advancing();
+        pd_client.clock = ClockHandle::new(clock.clone());
+
+        let req = new_get_request("key".to_owned().into(), Timestamp::default());
+        let plan = crate::request::PlanBuilder::new(Arc::new(pd_client), req)
+            .retry_multi_region(Backoff::no_jitter_backoff(100, 100, 3))
+            .plan();
+        assert!(plan.execute().await.is_ok());
+
+        // the regions reported by TiKV replace the cached region, the get is retried at once
+        assert_eq!(count.load(std::sync::atomic::Ordering::SeqCst), 2);
+        assert_eq!(clock.elapsed(), Duration::ZERO);
+
+        // both attempts are sent to the store of region2
+        assert_eq!(
+            recorded(Metric::TikvRetryTotal),
+            [r#"counter tikv_retry_total ["epoch_not_match"] 1"#]
+        );
+        let durations = recorded(Metric::TikvStoreRequestDurationSeconds);
+        assert_eq!(durations.len(), 2);
+        assert!(durations.iter().all(|record| record
+            .starts_with(r#"histogram tikv_store_request_duration_seconds ["kv_get", "42"]"#)));
+    }
+
+    #[tokio::test]
+    async fn test_is_witness_retry() {
+        // the first get is sent to a peer which became a witness, the retry succeeds
+        let count = Arc::new(AtomicUsize::new(0));
+        let dispatched = count.clone();
+        let mut pd_client =
+            MockPdClient::new(MockKvClient::with_dispatch_hook(move |_: &dyn Any| {
+                let mut resp = kvrpcpb::GetResponse::default();
+                if dispatched.fetch_add(1, std::sync::atomic::Ordering::SeqCst) == 0 {
+                    let mut error = tikv_client_proto::errorpb::Error::default();
+                    error.mut_is_witness().set_region_id(2);
+                    resp.set_region_error(error);
+                }
+                Ok(Box::new(resp) as Box<dyn Any>)
+            }));
+        pd_client.clock = ClockHandle::new(MockClock::auto_advancing());
+        let pd_client = Arc::new(pd_client);
+
+        let req = new_get_request("key".to_owned().into(), Timestamp::default());
+        let plan = crate::request::PlanBuilder::new(pd_client.clone(), req)
+            .retry_multi_region(Backoff::no_jitter_backoff(100, 100, 3))
+            .plan();
+        assert!(plan.execute().await.is_ok());
+
+        // the region is loaded from PD again to learn the new roles of its peers
+        assert_eq!(count.load(std::sync::atomic::Ordering::SeqCst), 2);
+        assert_eq!(
+            *pd_client.invalidated.lock().unwrap(),
+            vec![MockPdClient::region2().ver_id()]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_retry_stats() {
+        // the first get hits a stale command, the retry succeeds after a backoff
+        let count = Arc::new(AtomicUsize::new(0));
+        let dispatched = count.clone();
+        let mut pd_client =
+            MockPdClient::new(MockKvClient::with_dispatch_hook(move |_: &dyn Any| {
+                let mut resp = kvrpcpb::GetResponse::default();
+                if dispatched.fetch_add(1, std::sync::atomic::Ordering::SeqCst) == 0 {
+                    let mut error = tikv_client_proto::errorpb::Error::default();
+                    error.set_stale_command(Default::default());
+                    resp.set_region_error(error);
+                }
+                Ok(Box::new(resp) as Box<dyn Any>)
+            }));
+        pd_client.clock = ClockHandle::new(MockClock::auto_advancing());
+        let pd_client = Arc::new(pd_client);
+
+        let trace = crate::RpcTrace::new();
+        let req = new_get_request("key".to_owned().into(), Timestamp::default());
+        let plan = crate::request::PlanBuilder::new(pd_client, req)
+            .retry_multi_region(Backoff::no_jitter_backoff(100, 100, 3))
+            .plan();
+        trace.capture(plan.execute()).await.unwrap();
+
+        let stats = trace.retry_stats();
+        assert_eq!(
+            stats.region_errors.into_iter().collect::<Vec<_>>(),
+            [("stale_command", 1)]
+        );
+        assert_eq!(
+            stats.backoff.into_iter().collect::<Vec<_>>(),
+            [("region", Duration::from_millis(100))]
+        );
+
+        // requests outside of the trace are not counted
+        assert_eq!(
+            crate::RpcTrace::new().retry_stats(),
+            crate::RetryStats::default()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_lock_retry() {
+        // the first get finds the lock of a committed transaction, which is resolved
+        let gets = Arc::new(AtomicUsize::new(0));
+        let dispatched = gets.clone();
+        let pd_client = Arc::new(MockPdClient::new(MockKvClient::with_dispatch_hook(
+            move |req: &dyn Any| {
+                if req.is::<kvrpcpb::GetRequest>() {
+                    let mut resp = kvrpcpb::GetResponse::default();
+                    if dispatched.fetch_add(1, std::sync::atomic::Ordering::SeqCst) == 0 {
+                        resp.mut_error().set_locked(kvrpcpb::LockInfo {
+                            key: b"key".to_vec(),
+                            primary_lock: b"key".to_vec(),
+                            lock_version: 1,
+                            ..Default::default()
+                        });
+                    }
+                    Ok(Box::new(resp) as Box<dyn Any>)
+                } else if req.is::<kvrpcpb::CheckTxnStatusRequest>() {
+                    let resp = kvrpcpb::CheckTxnStatusResponse {
+                        commit_version: 2,
+                        ..Default::default()
+                    };
+                    Ok(Box::new(resp) as Box<dyn Any>)
+                } else if req.is::<kvrpcpb::ResolveLockRequest>() {
+                    Ok(Box::new(kvrpcpb::ResolveLockResponse::default()) as Box<dyn Any>)
+                } else {
+                    panic!("unexpected request")
+                }
+            },
+        )));
+
+        let req = new_get_request("key".to_owned().into(), Timestamp::default());
+        let plan = crate::request::PlanBuilder::new(pd_client, req)
+            .resolve_lock(Backoff::no_jitter_backoff(1, 1, 3))
+            .retry_multi_region(Backoff::no_jitter_backoff(1, 1, 3))
+            .plan();
+        assert!(plan.execute().await.is_ok());
+        assert_eq!(gets.load(std::sync::atomic::Ordering::SeqCst), 2);
+        assert_eq!(
+            recorded(Metric::TikvLockResolveTotal),
+            [r#"counter tikv_lock_resolve_total ["resolve"] 1"#]
+        );
+        assert_eq!(
+            recorded(Metric::TikvRetryTotal),
+            [r#"counter tikv_retry_total ["lock"] 1"#]
+        );
+    }
+
     #[tokio::test]
     async fn test_extract_error() {
         let pd_client = Arc::new(MockPdClient::new(MockKvClient::with_dispatch_hook(
@@ -1,22 +1,41 @@
 // Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
 
-use std::{marker::PhantomData, sync::Arc};
+use std::{
+    collections::HashSet,
+    marker::PhantomData,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use async_recursion::async_recursion;
 use async_trait::async_trait;
-use futures::{future::try_join_all, prelude::*};
-use tikv_client_proto::{errorpb, errorpb::EpochNotMatch, kvrpcpb};
+use futures::{
+    future::{try_join_all, Either},
+    prelude::*,
+};
+use tikv_client_proto::{errorpb, errorpb::EpochNotMatch, kvrpcpb, metapb};
 use tikv_client_store::{HasKeyErrors, HasRegionError, HasRegionErrors, KvClient};
-use tokio::sync::Semaphore;
+use tokio::{sync::Semaphore, task::JoinHandle};
 
 use crate::{
     backoff::Backoff,
+    clock::ClockHandle,
     pd::PdClient,
-    request::{KvRequest, Shardable},
-    stats::tikv_stats,
-    store::RegionStore,
-    transaction::{resolve_locks, HasLocks},
-    Error, Result,
+    region::{RegionWithLeader, StoreId},
+    request::{
+        is_transport_error, Idempotence, KvRequest, OnRetriesExhausted, ProgressCallback,
+        ProgressTracker, RequestContext, Retries, Shardable,
+    },
+    stats::{
+        observe_backoff, observe_region_error, observe_retry, region_error_kind,
+        tikv_stats_with_labels, MetricsLabels,
+    },
+    store::{in_call, RegionStore},
+    trace::{in_current_trace, in_span},
+    transaction::{
+        push_min_commit_ts, resolve_expired_locks, trace_lock, HasLocks, LockDecision, LockPolicy,
+    },
+    Error, FailedRequest, Result,
 };
 
 /// A plan for how to execute a request. A user builds up a plan with various
@@ -35,6 +54,16 @@ pub trait Plan: Sized + Clone + Sync + Send + 'static {
 pub struct Dispatch<Req: KvRequest> {
     pub request: Req,
     pub kv_client: Option<Arc<dyn KvClient + Send + Sync>>,
+    /// The store of `kv_client`, if it is a TiKV store of a region.
+    pub store_id: Option<StoreId>,
+    /// User-defined labels recorded in the metrics of the request.
+    pub labels: MetricsLabels,
+    /// The fields of the context of the request which do not depend on its region.
+    pub context: RequestContext,
+    /// If set, the request is cancelled and fails with `RequestTimeout` once it takes longer.
+    pub timeout: Option<Duration>,
+    /// If set, the request is cancelled and fails with `DeadlineExceeded` once it passes.
+    pub deadline: Option<Deadline>,
 }
 
 #[async_trait]
@@ -42,13 +71,39 @@ impl<Req: KvRequest> Plan for Dispatch<Req> {
     type Result = Req::Response;
 
     async fn execute(&self) -> Result<Self::Result> {
-        let stats = tikv_stats(self.request.label());
-        let result = self
+        let stats =
+            tikv_stats_with_labels(self.request.label(), &self.labels).with_store(self.store_id);
+        let kv_client = self
             .kv_client
             .as_ref()
-            .expect("Unreachable: kv_client has not been initialised in Dispatch")
-            .dispatch(&self.request)
-            .await;
+            .expect("Unreachable: kv_client has not been initialised in Dispatch");
+        let remaining = self
+            .deadline
+            .as_ref()
+            .map(Deadline::remaining)
+            .transpose()?;
+        let timeout = match (self.timeout, remaining) {
+            (Some(timeout), Some(remaining)) => Some(timeout.min(remaining)),
+            (timeout, remaining) => timeout.or(remaining),
+        };
+        let result = match timeout {
+            // the call is cancelled rather than abandoned, so TiKV stops processing it
+            Some(timeout) => kv_client
+                .dispatch_with_timeout(&self.request, timeout)
+                .await
+                .map_err(|e| match e {
+                    Error::Grpc(grpcio::Error::RpcFailure(status))
+                        if status.code() == grpcio::RpcStatusCode::DEADLINE_EXCEEDED =>
+                    {
+                        match &self.deadline {
+                            Some(deadline) if remaining == Some(timeout) => deadline.exceeded(),
+                            _ => Error::RequestTimeout { timeout },
+                        }
+                    }
+                    e => e,
+                }),
+            None => kv_client.dispatch(&self.request).await,
+        };
         let result = stats.done(result);
         result.map(|r| {
             *r.downcast()
@@ -57,12 +112,85 @@ impl<Req: KvRequest> Plan for Dispatch<Req> {
     }
 }
 
-const MULTI_REGION_CONCURRENCY: usize = 16;
+/// The time by which a plan must complete, including its retries, see
+/// [`PlanBuilder::deadline`](super::PlanBuilder::deadline).
+#[derive(Clone)]
+pub struct Deadline {
+    clock: ClockHandle,
+    at: Instant,
+    timeout: Duration,
+}
+
+impl Deadline {
+    /// The deadline `timeout` from now.
+    pub fn new(clock: ClockHandle, timeout: Duration) -> Deadline {
+        let at = clock.now() + timeout;
+        Deadline { clock, at, timeout }
+    }
+
+    /// The time left until the deadline, `DeadlineExceeded` if it has passed.
+    pub fn remaining(&self) -> Result<Duration> {
+        match self.at.checked_duration_since(self.clock.now()) {
+            Some(remaining) if !remaining.is_zero() => Ok(remaining),
+            _ => Err(self.exceeded()),
+        }
+    }
+
+    fn exceeded(&self) -> Error {
+        Error::DeadlineExceeded {
+            timeout: self.timeout,
+        }
+    }
+
+    /// Runs `future`, failing with `DeadlineExceeded` if it doesn't complete by the deadline, in
+    /// which case it is dropped, cancelling its requests.
+    async fn bound<T>(&self, future: impl Future<Output = Result<T>>) -> Result<T> {
+        let remaining = self.remaining()?;
+        futures::pin_mut!(future);
+        match future::select(future, self.clock.sleep(remaining)).await {
+            Either::Left((result, _)) => result,
+            Either::Right(_) => Err(self.exceeded()),
+        }
+    }
+}
+
+pub(crate) const MULTI_REGION_CONCURRENCY: usize = 16;
+
+/// Attaches the region, store and type of `request` to its error `e`.
+fn request_failed(e: Error, request: FailedRequest) -> Error {
+    Error::RequestFailed {
+        source: Box::new(e),
+        request,
+    }
+}
 
 pub struct RetryableMultiRegion<P: Plan, PdC: PdClient> {
     pub(super) inner: P,
     pub pd_client: Arc<PdC>,
     pub backoff: Backoff,
+    /// What the plan does once the backoff gives up.
+    pub on_exhausted: OnRetriesExhausted,
+    /// If set, called each time the plan completes a region.
+    pub progress: Option<ProgressCallback>,
+    /// The maximum number of requests in flight.
+    pub concurrency: usize,
+    /// If set, the shard of each region is split into requests of at most this size, see
+    /// [`Shardable::chunk_shard`].
+    pub chunk_size: Option<usize>,
+    /// If set, the plan fails with `DeadlineExceeded` instead of retrying once it passes.
+    pub deadline: Option<Deadline>,
+}
+
+/// The tasks sending the requests of a plan to the regions, which are aborted if the plan is
+/// dropped before they complete, so that dropping the future of an operation cancels its requests.
+struct AbortOnDrop<T>(Vec<JoinHandle<T>>);
+
+impl<T> Drop for AbortOnDrop<T> {
+    fn drop(&mut self) {
+        for handle in &self.0 {
+            handle.abort();
+        }
+    }
 }
 
 impl<P: Plan + Shardable, PdC: PdClient> RetryableMultiRegion<P, PdC>
@@ -73,26 +201,45 @@ where
     #[async_recursion]
     async fn single_plan_handler(
         pd_client: Arc<PdC>,
-        current_plan: P,
-        backoff: Backoff,
+        mut current_plan: P,
+        retries: Retries,
         permits: Arc<Semaphore>,
+        progress: Option<Arc<ProgressTracker>>,
+        chunk_size: Option<usize>,
     ) -> Result<<Self as Plan>::Result> {
-        let shards = current_plan.shards(&pd_client).collect::<Vec<_>>().await;
-        let mut handles = Vec::new();
-        for shard in shards {
+        let mut shards = Vec::new();
+        for shard in current_plan.shards(&pd_client).collect::<Vec<_>>().await {
             let (shard, region_store) = shard?;
+            match chunk_size {
+                Some(chunk_size) => shards.extend(
+                    current_plan
+                        .chunk_shard(shard, chunk_size)
+                        .into_iter()
+                        .map(|chunk| (chunk, region_store.clone())),
+                ),
+                None => shards.push((shard, region_store)),
+            }
+        }
+        current_plan.clear_shards();
+        if let Some(progress) = &progress {
+            progress.add_regions(shards.len());
+        }
+        let mut handles = AbortOnDrop(Vec::new());
+        for (shard, region_store) in shards {
             let mut clone = current_plan.clone();
             clone.apply_shard(shard, &region_store)?;
-            let handle = tokio::spawn(Self::single_shard_handler(
+            let handle = tokio::spawn(in_current_trace(Self::single_shard_handler(
                 pd_client.clone(),
                 clone,
                 region_store,
-                backoff.clone(),
+                retries.clone(),
                 permits.clone(),
-            ));
-            handles.push(handle);
+                progress.clone(),
+                chunk_size,
+            )));
+            handles.0.push(handle);
         }
-        Ok(try_join_all(handles)
+        Ok(try_join_all(handles.0.iter_mut())
             .await?
             .into_iter()
             .collect::<Result<Vec<_>>>()?
@@ -106,34 +253,135 @@ where
         pd_client: Arc<PdC>,
         plan: P,
         region_store: RegionStore,
-        mut backoff: Backoff,
+        mut retries: Retries,
         permits: Arc<Semaphore>,
+        progress: Option<Arc<ProgressTracker>>,
+        chunk_size: Option<usize>,
     ) -> Result<<Self as Plan>::Result> {
         // limit concurrent requests
         let permit = permits.acquire().await.unwrap();
-        let mut resp = plan.execute().await?;
+        let result = in_span!(
+            plan.execute(),
+            "region_request",
+            region_id = region_store.region_with_leader.id(),
+            store = region_store.address.as_deref().unwrap_or_default()
+        )
+        .await;
         drop(permit);
 
-        if let Some(e) = resp.key_errors() {
+        let request = FailedRequest {
+            request_type: plan.request_type(),
+            region_id: region_store.region_with_leader.id(),
+            store_id: region_store.store_id(),
+            region_error: None,
+        };
+        let mut resp = match result {
+            Ok(resp) => resp,
+            // the request may or may not have been applied, only retry it if that's safe
+            Err(e) if is_transport_error(&e) && plan.idempotence() == Idempotence::Idempotent => {
+                Self::raise_region_backoff(&pd_client, &region_store, &mut retries.backoff);
+                return match retries.next_delay_duration(&e) {
+                    Some(duration) => {
+                        // the leader may have moved away from the unreachable store
+                        pd_client
+                            .invalidate_region_cache(region_store.region_with_leader.ver_id())
+                            .await;
+                        observe_backoff("transport", duration);
+                        observe_retry("transport");
+                        pd_client.clock().sleep(duration).await;
+                        if let Some(progress) = &progress {
+                            progress.retry_region();
+                        }
+                        Self::single_plan_handler(
+                            pd_client, plan, retries, permits, progress, chunk_size,
+                        )
+                        .await
+                    }
+                    None => Err(retries.exhausted(request_failed(e, request))),
+                };
+            }
+            Err(e) => return Err(request_failed(e, request)),
+        };
+
+        let key_errors = resp.key_errors();
+        let region_error = resp.region_error();
+        if let Some(pressure) = pd_client.pressure() {
+            let busy = region_error.as_ref().filter(|e| e.has_server_is_busy());
+            pressure.observe(
+                region_store.store_id(),
+                busy.map(|e| e.get_server_is_busy()),
+            );
+        }
+        if let Some(region_backoffs) = pd_client.region_backoffs() {
+            if region_error.is_none() {
+                region_backoffs.on_success(region_store.region_with_leader.id());
+            }
+        }
+
+        if let Some(e) = key_errors {
+            if let Some(progress) = &progress {
+                progress.complete_region(region_store.region_with_leader.end_key());
+            }
             Ok(vec![Err(Error::MultipleKeyErrors(e))])
-        } else if let Some(e) = resp.region_error() {
-            match backoff.next_delay_duration() {
+        } else if let Some(e) = region_error {
+            observe_region_error(&e);
+            Self::raise_region_backoff(&pd_client, &region_store, &mut retries.backoff);
+            let request = FailedRequest {
+                region_error: Some(e.clone()),
+                ..request
+            };
+            match retries.next_delay_duration(&Error::RegionError(e.clone())) {
                 Some(duration) => {
+                    observe_retry(region_error_kind(&e));
                     let region_error_resolved =
-                        Self::handle_region_error(pd_client.clone(), e, region_store).await?;
+                        Self::handle_region_error(pd_client.clone(), e, region_store)
+                            .await
+                            .map_err(|e| request_failed(e, request))?;
                     // don't sleep if we have resolved the region error
                     if !region_error_resolved {
-                        futures_timer::Delay::new(duration).await;
+                        observe_backoff("region", duration);
+                        pd_client.clock().sleep(duration).await;
+                    }
+                    if let Some(progress) = &progress {
+                        progress.retry_region();
                     }
-                    Self::single_plan_handler(pd_client, plan, backoff, permits).await
+                    Self::single_plan_handler(
+                        pd_client, plan, retries, permits, progress, chunk_size,
+                    )
+                    .await
+                }
+                None if e.has_data_is_not_ready() => {
+                    let not_ready = e.get_data_is_not_ready();
+                    let e = Error::DataIsNotReady {
+                        region_id: not_ready.region_id,
+                        safe_ts: not_ready.safe_ts,
+                    };
+                    Err(retries.exhausted(request_failed(e, request)))
                 }
-                None => Err(Error::RegionError(e)),
+                None => Err(retries.exhausted(request_failed(Error::RegionError(e), request))),
             }
         } else {
+            if let Some(progress) = &progress {
+                progress.complete_region(region_store.region_with_leader.end_key());
+            }
+            // the merge was rolled back
+            if region_store.region_with_leader.merging {
+                pd_client
+                    .set_region_merging(region_store.region_with_leader.ver_id(), false)
+                    .await;
+            }
             Ok(vec![Ok(resp)])
         }
     }
 
+    /// Records a failed request to the region of `region_store`, and raises `backoff` to the
+    /// backoff level of the region.
+    fn raise_region_backoff(pd_client: &PdC, region_store: &RegionStore, backoff: &mut Backoff) {
+        if let Some(region_backoffs) = pd_client.region_backoffs() {
+            backoff.raise_level(region_backoffs.on_failure(region_store.region_with_leader.id()));
+        }
+    }
+
     // Returns
     // 1. Ok(true): error has been resolved, retry immediately
     // 2. Ok(false): backoff, and then retry
@@ -155,6 +403,8 @@ where
                     .await
                 {
                     Ok(_) => Ok(true),
+                    // the region is no longer cached, it is loaded from PD again
+                    Err(Error::EntryNotFoundInRegionCache) => Ok(true),
                     Err(e) => {
                         pd_client.invalidate_region_cache(ver_id).await;
                         Err(e)
@@ -169,8 +419,12 @@ where
                 Ok(false)
             }
         } else if e.has_store_not_match() {
+            // another store was started at the address of the store, load its address again
+            if let Some(store_id) = region_store.store_id() {
+                pd_client.invalidate_store_cache(store_id).await;
+            }
             pd_client.invalidate_region_cache(ver_id).await;
-            Ok(false)
+            Ok(true)
         } else if e.has_epoch_not_match() {
             Self::on_region_epoch_not_match(
                 pd_client.clone(),
@@ -181,11 +435,32 @@ where
         } else if e.has_stale_command() || e.has_region_not_found() {
             pd_client.invalidate_region_cache(ver_id).await;
             Ok(false)
+        } else if e.has_is_witness() {
+            // the peer became a witness, which doesn't serve reads, load its role from PD again
+            pd_client.invalidate_region_cache(ver_id).await;
+            Ok(false)
+        } else if e.has_data_is_not_ready() {
+            // the replica serving a stale read has not caught up with the timestamp of the read
+            Ok(false)
+        } else if e.has_proposal_in_merging_mode() {
+            // once the merge is done, the retry fails with `RegionNotFound` or `EpochNotMatch`,
+            // which loads the region again
+            pd_client.set_region_merging(ver_id, true).await;
+            Ok(false)
         } else if e.has_server_is_busy()
             || e.has_raft_entry_too_large()
             || e.has_max_timestamp_not_synced()
         {
             Err(Error::RegionError(e))
+        } else if e.has_api_version_not_matched() {
+            // TiKV doesn't accept the API version of the request, retrying doesn't help
+            Err(Error::ApiVersionNotMatched {
+                message: e.take_message(),
+            })
+        } else if e.has_ttl_not_enabled() {
+            Err(Error::TtlNotEnabled {
+                message: e.take_message(),
+            })
         } else {
             // TODO: pass the logger around
             // info!("unknwon region error: {:?}", e);
@@ -230,18 +505,43 @@ where
                 }
             }
         }
-        // TODO: finer grained processing
-        pd_client.invalidate_region_cache(ver_id).await;
-        Ok(false)
+        // The region was split or merged, or its peers changed. The regions now covering its
+        // range are cached, so that the request is retried at once without a PD round trip.
+        let leader_store = region_store.region_with_leader.get_store_id().ok();
+        let regions = error
+            .get_current_regions()
+            .iter()
+            .filter_map(|region| region_with_leader_on(region, leader_store))
+            .collect();
+        pd_client.replace_region_cache(ver_id, regions).await;
+        Ok(true)
     }
 }
 
+/// `region` with its peer on `store_id` as leader, the store of the leader of the region it
+/// replaced. `None` if the region has no peer on the store, it is then loaded from PD when needed.
+fn region_with_leader_on(
+    region: &metapb::Region,
+    store_id: Option<StoreId>,
+) -> Option<RegionWithLeader> {
+    let leader = region
+        .get_peers()
+        .iter()
+        .find(|peer| Some(peer.store_id) == store_id)?;
+    Some(RegionWithLeader::new(region.clone(), Some(leader.clone())))
+}
+
 impl<P: Plan, PdC: PdClient> Clone for RetryableMultiRegion<P, PdC> {
     fn clone(&self) -> Self {
         RetryableMultiRegion {
             inner: self.inner.clone(),
             pd_client: self.pd_client.clone(),
             backoff: self.backoff.clone(),
+            on_exhausted: self.on_exhausted.clone(),
+            progress: self.progress.clone(),
+            concurrency: self.concurrency,
+            chunk_size: self.chunk_size,
+            deadline: self.deadline.clone(),
         }
     }
 }
@@ -257,14 +557,25 @@ where
         // Limit the maximum concurrency of multi-region request. If there are
         // too many concurrent requests, TiKV is more likely to return a "TiKV
         // is busy" error
-        let concurrency_permits = Arc::new(Semaphore::new(MULTI_REGION_CONCURRENCY));
-        Self::single_plan_handler(
+        let concurrency_permits = Arc::new(Semaphore::new(self.concurrency.max(1)));
+        let progress = self
+            .progress
+            .clone()
+            .map(|callback| Arc::new(ProgressTracker::new(callback)));
+        let handler = Self::single_plan_handler(
             self.pd_client.clone(),
             self.inner.clone(),
-            self.backoff.clone(),
+            Retries::new(self.backoff.clone(), self.on_exhausted.clone()),
             concurrency_permits.clone(),
-        )
-        .await
+            progress,
+            self.chunk_size,
+        );
+        // the requests to all regions are a single call for the store queues
+        let handler = in_call(handler);
+        match &self.deadline {
+            Some(deadline) => deadline.bound(handler).await,
+            None => handler.await,
+        }
     }
 }
 
@@ -360,10 +671,28 @@ impl<P: Plan, Pr: Process<P::Result>> Plan for ProcessResponse<P, Pr> {
 #[derive(Clone, Copy, Debug)]
 pub struct DefaultProcessor;
 
+/// A plan which can be told to ignore the locks of some transactions.
+pub trait ResolvedLocks {
+    /// Ignore locks of the transactions started at `start_versions` in subsequent executions.
+    fn add_resolved_locks(&mut self, start_versions: &[u64]);
+}
+
+impl<Req: KvRequest> ResolvedLocks for Dispatch<Req> {
+    fn add_resolved_locks(&mut self, start_versions: &[u64]) {
+        self.request.add_resolved_locks(start_versions);
+    }
+}
+
 pub struct ResolveLock<P: Plan, PdC: PdClient> {
     pub inner: P,
     pub pd_client: Arc<PdC>,
     pub backoff: Backoff,
+    /// What the plan does once the backoff gives up.
+    pub on_exhausted: OnRetriesExhausted,
+    /// The timestamp of a read. If set, the `min_commit_ts` of live locks blocking the read is
+    /// pushed past it, so the read doesn't have to wait for the transactions holding the locks.
+    pub read_ts: Option<u64>,
+    pub lock_policy: LockPolicy,
 }
 
 impl<P: Plan, PdC: PdClient> Clone for ResolveLock<P, PdC> {
@@ -372,12 +701,15 @@ impl<P: Plan, PdC: PdClient> Clone for ResolveLock<P, PdC> {
             inner: self.inner.clone(),
             pd_client: self.pd_client.clone(),
             backoff: self.backoff.clone(),
+            on_exhausted: self.on_exhausted.clone(),
+            read_ts: self.read_ts,
+            lock_policy: self.lock_policy,
         }
     }
 }
 
 #[async_trait]
-impl<P: Plan, PdC: PdClient> Plan for ResolveLock<P, PdC>
+impl<P: Plan + ResolvedLocks, PdC: PdClient> Plan for ResolveLock<P, PdC>
 where
     P::Result: HasLocks,
 {
@@ -386,24 +718,70 @@ where
     async fn execute(&self) -> Result<Self::Result> {
         let mut result = self.inner.execute().await?;
         let mut clone = self.clone();
+        let mut retries = Retries::new(self.backoff.clone(), self.on_exhausted.clone());
+        let mut resolved_locks = HashSet::new();
         loop {
-            let locks = result.take_locks();
+            if self.lock_policy == LockPolicy::SkipLocked {
+                for lock in result.skip_locks() {
+                    trace_lock(&lock, LockDecision::Skip, Duration::from_secs(0));
+                }
+                return Ok(result);
+            }
+
+            let mut locks = result.take_locks();
             if locks.is_empty() {
                 return Ok(result);
             }
 
+            if self.lock_policy == LockPolicy::FailFast {
+                trace_locks(&locks, LockDecision::FailFast, Duration::from_secs(0));
+                let mut error = kvrpcpb::KeyError::default();
+                error.set_locked(locks.swap_remove(0));
+                return Err(Error::KeyError(error));
+            }
+
             if self.backoff.is_none() {
+                trace_locks(&locks, LockDecision::GiveUp, Duration::from_secs(0));
                 return Err(Error::ResolveLockError);
             }
 
             let pd_client = self.pd_client.clone();
-            if resolve_locks(locks, pd_client.clone()).await? {
-                result = self.inner.execute().await?;
+            let live_locks = resolve_expired_locks(locks, pd_client.clone()).await?;
+            if live_locks.is_empty() {
+                observe_retry("lock");
+                result = clone.inner.execute().await?;
+                continue;
+            }
+
+            let mut pushed = match self.read_ts {
+                Some(read_ts) => push_min_commit_ts(live_locks.clone(), read_ts, pd_client).await?,
+                None => Vec::new(),
+            };
+            pushed.retain(|start_version| resolved_locks.insert(*start_version));
+            if !pushed.is_empty() {
+                // the locks which are not pushed are encountered again by the retried request
+                for lock in &live_locks {
+                    let decision = if pushed.contains(&lock.lock_version) {
+                        LockDecision::PushMinCommitTs
+                    } else {
+                        LockDecision::Wait
+                    };
+                    trace_lock(lock, decision, Duration::from_secs(0));
+                }
+                clone.inner.add_resolved_locks(&pushed);
+                observe_retry("lock");
+                result = clone.inner.execute().await?;
             } else {
-                match clone.backoff.next_delay_duration() {
-                    None => return Err(Error::ResolveLockError),
+                match retries.next_delay_duration(&Error::ResolveLockError) {
+                    None => {
+                        trace_locks(&live_locks, LockDecision::GiveUp, Duration::from_secs(0));
+                        return Err(retries.exhausted(Error::ResolveLockError));
+                    }
                     Some(delay_duration) => {
-                        futures_timer::Delay::new(delay_duration).await;
+                        trace_locks(&live_locks, LockDecision::Wait, delay_duration);
+                        observe_backoff("lock", delay_duration);
+                        self.pd_client.clock().sleep(delay_duration).await;
+                        observe_retry("lock");
                         result = clone.inner.execute().await?;
                     }
                 }
@@ -412,6 +790,12 @@ where
     }
 }
 
+fn trace_locks(locks: &[kvrpcpb::LockInfo], decision: LockDecision, wait: Duration) {
+    for lock in locks {
+        trace_lock(lock, decision, wait);
+    }
+}
+
 /// When executed, the plan extracts errors from its inner plan, and returns an
 /// `Err` wrapping the error.
 ///
@@ -504,6 +888,10 @@ impl<Resp: HasLocks, Shard> HasLocks for ResponseWithShard<Resp, Shard> {
     fn take_locks(&mut self) -> Vec<kvrpcpb::LockInfo> {
         self.0.take_locks()
     }
+
+    fn skip_locks(&mut self) -> Vec<kvrpcpb::LockInfo> {
+        self.0.skip_locks()
+    }
 }
 
 impl<Resp: HasRegionError, Shard> HasRegionError for ResponseWithShard<Resp, Shard> {
@@ -531,6 +919,10 @@ mod test {
         }
     }
 
+    impl ResolvedLocks for ErrPlan {
+        fn add_resolved_locks(&mut self, _: &[u64]) {}
+    }
+
     impl Shardable for ErrPlan {
         type Shard = ();
 
@@ -552,10 +944,18 @@ mod test {
             inner: ResolveLock {
                 inner: ErrPlan,
                 backoff: Backoff::no_backoff(),
+                on_exhausted: OnRetriesExhausted::LastError,
                 pd_client: Arc::new(MockPdClient::default()),
+                read_ts: None,
+                lock_policy: LockPolicy::ResolveAndWait,
             },
             pd_client: Arc::new(MockPdClient::default()),
             backoff: Backoff::no_backoff(),
+            on_exhausted: OnRetriesExhausted::LastError,
+            progress: None,
+            concurrency: MULTI_REGION_CONCURRENCY,
+            chunk_size: None,
+            deadline: None,
         };
         assert!(plan.execute().await.is_err())
     }
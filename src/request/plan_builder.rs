@@ -1,24 +1,33 @@
 // Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
 
-use super::plan::PreserveShard;
+use super::plan::{PreserveShard, MULTI_REGION_CONCURRENCY};
 use crate::{
     backoff::Backoff,
     pd::PdClient,
     request::{
-        DefaultProcessor, Dispatch, ExtractError, KvRequest, Merge, MergeResponse, Plan, Process,
-        ProcessResponse, ResolveLock, RetryableMultiRegion, Shardable,
+        ContextHook, Deadline, DefaultProcessor, Dispatch, ExtractError, KvRequest, Merge,
+        MergeResponse, OnRetriesExhausted, Plan, Process, ProcessResponse, ProgressCallback,
+        RequestContext, ResolveLock, ResolvedLocks, RetryableMultiRegion, Shardable,
     },
+    stats::MetricsLabels,
     store::RegionStore,
-    transaction::HasLocks,
-    Result,
+    timestamp::TimestampExt,
+    transaction::{HasLocks, LockPolicy},
+    Result, Timestamp,
 };
-use std::{marker::PhantomData, sync::Arc};
-use tikv_client_store::{HasKeyErrors, HasRegionError, HasRegionErrors};
+use std::{marker::PhantomData, sync::Arc, time::Duration};
+use tikv_client_proto::kvrpcpb;
+use tikv_client_store::{HasKeyErrors, HasRegionError, HasRegionErrors, KvClient};
 
 /// Builder type for plans (see that module for more).
 pub struct PlanBuilder<PdC: PdClient, P: Plan, Ph: PlanBuilderPhase> {
     pd_client: Arc<PdC>,
     plan: P,
+    /// The deadline of the plan, see [`deadline`](PlanBuilder::deadline).
+    deadline: Option<Deadline>,
+    /// What the plan does once its retries are exhausted, see
+    /// [`on_retries_exhausted`](PlanBuilder::on_retries_exhausted).
+    on_exhausted: OnRetriesExhausted,
     phantom: PhantomData<Ph>,
 }
 
@@ -37,10 +46,69 @@ impl<PdC: PdClient, Req: KvRequest> PlanBuilder<PdC, Dispatch<Req>, NoTarget> {
             plan: Dispatch {
                 request,
                 kv_client: None,
+                store_id: None,
+                labels: MetricsLabels::default(),
+                context: RequestContext::default(),
+                timeout: None,
+                deadline: None,
             },
+            deadline: None,
+            on_exhausted: OnRetriesExhausted::LastError,
             phantom: PhantomData,
         }
     }
+
+    /// Record the request in the metrics with the user-defined `labels`.
+    pub fn labels(mut self, labels: MetricsLabels) -> Self {
+        self.plan.labels = labels;
+        self
+    }
+
+    /// Send the request with `priority` in TiKV.
+    pub fn priority(mut self, priority: kvrpcpb::CommandPri) -> Self {
+        self.plan.context.priority = priority;
+        self
+    }
+
+    /// Let TiKV read with `isolation_level`.
+    pub fn isolation_level(mut self, isolation_level: kvrpcpb::IsolationLevel) -> Self {
+        self.plan.context.isolation_level = isolation_level;
+        self
+    }
+
+    /// Tag the request with `tag` in TiKV.
+    pub fn resource_group_tag(mut self, tag: Vec<u8>) -> Self {
+        self.plan.context.resource_group_tag = tag;
+        self
+    }
+
+    /// Amend the context of the request with `hook`, if set, see [`ContextHook`].
+    pub fn context_hook(mut self, hook: Option<ContextHook>) -> Self {
+        self.plan.context.hook = hook;
+        self
+    }
+
+    /// Fail each request sent to TiKV which takes longer than `timeout`, if set.
+    pub fn timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.plan.timeout = timeout;
+        self.plan.context.max_execution_duration = timeout;
+        self
+    }
+
+    /// Fail the plan with `DeadlineExceeded` if it doesn't complete within `timeout` from now,
+    /// if set, including its retries. The requests sent to TiKV are cancelled at the deadline.
+    pub fn deadline(mut self, timeout: Option<Duration>) -> Self {
+        self.deadline = timeout.map(|timeout| Deadline::new(self.pd_client.clock(), timeout));
+        self.plan.deadline = self.deadline.clone();
+        self
+    }
+
+    /// Fail the plan according to `policy` once the retries after region errors or locked keys
+    /// are exhausted, instead of with the last error.
+    pub fn on_retries_exhausted(mut self, policy: OnRetriesExhausted) -> Self {
+        self.on_exhausted = policy;
+        self
+    }
 }
 
 impl<PdC: PdClient, P: Plan> PlanBuilder<PdC, P, Targetted> {
@@ -55,6 +123,7 @@ impl<PdC: PdClient, P: Plan, Ph: PlanBuilderPhase> PlanBuilder<PdC, P, Ph> {
     /// If there is a lock error, then resolve the lock and retry the request.
     pub fn resolve_lock(self, backoff: Backoff) -> PlanBuilder<PdC, ResolveLock<P, PdC>, Ph>
     where
+        P: ResolvedLocks,
         P::Result: HasLocks,
     {
         PlanBuilder {
@@ -62,12 +131,56 @@ impl<PdC: PdClient, P: Plan, Ph: PlanBuilderPhase> PlanBuilder<PdC, P, Ph> {
             plan: ResolveLock {
                 inner: self.plan,
                 backoff,
+                on_exhausted: self.on_exhausted.clone(),
                 pd_client: self.pd_client,
+                read_ts: None,
+                lock_policy: LockPolicy::ResolveAndWait,
             },
+            deadline: self.deadline,
+            on_exhausted: self.on_exhausted,
             phantom: PhantomData,
         }
     }
 
+    /// Like [`resolve_lock`](PlanBuilder::resolve_lock), for a read at `read_ts`, handling locks
+    /// according to `lock_policy`.
+    ///
+    /// If a lock is not expired, instead of only waiting for it, try to push the `min_commit_ts`
+    /// of its transaction past `read_ts`. If that succeeds, the lock is ignored by the retried
+    /// read.
+    pub fn resolve_lock_for_read(
+        self,
+        read_ts: Timestamp,
+        lock_policy: LockPolicy,
+        backoff: Backoff,
+    ) -> PlanBuilder<PdC, ResolveLock<P, PdC>, Ph>
+    where
+        P: ResolvedLocks,
+        P::Result: HasLocks,
+    {
+        PlanBuilder {
+            pd_client: self.pd_client.clone(),
+            plan: ResolveLock {
+                inner: self.plan,
+                backoff,
+                on_exhausted: self.on_exhausted.clone(),
+                pd_client: self.pd_client,
+                read_ts: Some(read_ts.version()),
+                lock_policy,
+            },
+            deadline: self.deadline,
+            on_exhausted: self.on_exhausted,
+            phantom: PhantomData,
+        }
+    }
+
+    /// Send the requests through `pd_client`, e.g. a client whose requests are replica reads. The
+    /// locks encountered by the requests are still resolved through the previous client.
+    pub fn read_through(mut self, pd_client: Arc<PdC>) -> Self {
+        self.pd_client = pd_client;
+        self
+    }
+
     /// Merge the results of a request. Usually used where a request is sent to multiple regions
     /// to combine the responses from each region.
     pub fn merge<In, M: Merge<In>>(self, merge: M) -> PlanBuilder<PdC, MergeResponse<P, In, M>, Ph>
@@ -82,6 +195,8 @@ impl<PdC: PdClient, P: Plan, Ph: PlanBuilderPhase> PlanBuilder<PdC, P, Ph> {
                 merge,
                 phantom: PhantomData,
             },
+            deadline: self.deadline,
+            on_exhausted: self.on_exhausted,
             phantom: PhantomData,
         }
     }
@@ -100,6 +215,8 @@ impl<PdC: PdClient, P: Plan, Ph: PlanBuilderPhase> PlanBuilder<PdC, P, Ph> {
                 inner: self.plan,
                 processor: DefaultProcessor,
             },
+            deadline: self.deadline,
+            on_exhausted: self.on_exhausted,
             phantom: PhantomData,
         }
     }
@@ -120,12 +237,45 @@ where
                 inner: self.plan,
                 pd_client: self.pd_client,
                 backoff,
+                on_exhausted: self.on_exhausted.clone(),
+                progress: None,
+                concurrency: MULTI_REGION_CONCURRENCY,
+                chunk_size: None,
+                deadline: self.deadline.clone(),
             },
+            deadline: self.deadline,
+            on_exhausted: self.on_exhausted,
             phantom: PhantomData,
         }
     }
 }
 
+impl<PdC: PdClient, P: Plan + Shardable> PlanBuilder<PdC, RetryableMultiRegion<P, PdC>, Targetted>
+where
+    P::Result: HasKeyErrors + HasRegionError,
+{
+    /// Report the progress of the plan to `callback`, if set, each time it completes a region.
+    pub fn progress(mut self, callback: Option<ProgressCallback>) -> Self {
+        self.plan.progress = callback;
+        self
+    }
+
+    /// Limit the number of requests in flight to `concurrency`, if set, instead of the default
+    /// of 16.
+    pub fn concurrency(mut self, concurrency: Option<usize>) -> Self {
+        if let Some(concurrency) = concurrency {
+            self.plan.concurrency = concurrency;
+        }
+        self
+    }
+
+    /// Split the shard of each region into requests of at most `chunk_size` keys, if set.
+    pub fn chunk_size(mut self, chunk_size: Option<usize>) -> Self {
+        self.plan.chunk_size = chunk_size;
+        self
+    }
+}
+
 impl<PdC: PdClient, R: KvRequest + SingleKey> PlanBuilder<PdC, Dispatch<R>, NoTarget> {
     /// Target the request at a single region. *Note*: single region plan will
     /// cannot automatically retry on region errors. It's only used for requests
@@ -134,7 +284,13 @@ impl<PdC: PdClient, R: KvRequest + SingleKey> PlanBuilder<PdC, Dispatch<R>, NoTa
         let key = self.plan.request.key();
         // TODO: retry when region error occurred
         let store = self.pd_client.clone().store_for_key(key.into()).await?;
-        set_single_region_store(self.plan, store, self.pd_client)
+        set_single_region_store(
+            self.plan,
+            store,
+            self.pd_client,
+            self.deadline,
+            self.on_exhausted,
+        )
     }
 }
 
@@ -144,7 +300,13 @@ impl<PdC: PdClient, R: KvRequest> PlanBuilder<PdC, Dispatch<R>, NoTarget> {
         self,
         store: RegionStore,
     ) -> Result<PlanBuilder<PdC, Dispatch<R>, Targetted>> {
-        set_single_region_store(self.plan, store, self.pd_client)
+        set_single_region_store(
+            self.plan,
+            store,
+            self.pd_client,
+            self.deadline,
+            self.on_exhausted,
+        )
     }
 }
 
@@ -159,6 +321,8 @@ where
                 inner: self.plan,
                 shard: None,
             },
+            deadline: self.deadline,
+            on_exhausted: self.on_exhausted,
             phantom: PhantomData,
         }
     }
@@ -172,22 +336,46 @@ where
         PlanBuilder {
             pd_client: self.pd_client,
             plan: ExtractError { inner: self.plan },
+            deadline: self.deadline,
+            on_exhausted: self.on_exhausted,
             phantom: self.phantom,
         }
     }
 }
 
+impl<PdC: PdClient, R: KvRequest> PlanBuilder<PdC, Dispatch<R>, NoTarget> {
+    /// Target the request at the store of `kv_client` rather than at a region, for store commands
+    /// which apply to all the data of a store, e.g. `UnsafeDestroyRange`.
+    pub fn store(
+        mut self,
+        kv_client: Arc<dyn KvClient + Send + Sync>,
+    ) -> PlanBuilder<PdC, Dispatch<R>, Targetted> {
+        self.plan.kv_client = Some(kv_client);
+        PlanBuilder {
+            plan: self.plan,
+            pd_client: self.pd_client,
+            deadline: self.deadline,
+            on_exhausted: self.on_exhausted,
+            phantom: PhantomData,
+        }
+    }
+}
+
 fn set_single_region_store<PdC: PdClient, R: KvRequest>(
     mut plan: Dispatch<R>,
     store: RegionStore,
     pd_client: Arc<PdC>,
+    deadline: Option<Deadline>,
+    on_exhausted: OnRetriesExhausted,
 ) -> Result<PlanBuilder<PdC, Dispatch<R>, Targetted>> {
-    plan.request
-        .set_context(store.region_with_leader.context()?);
+    plan.request.set_context(plan.context.build(&store)?);
+    plan.store_id = store.store_id();
     plan.kv_client = Some(store.client);
     Ok(PlanBuilder {
         plan,
         pd_client,
+        deadline,
+        on_exhausted,
         phantom: PhantomData,
     })
 }
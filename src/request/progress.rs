@@ -0,0 +1,88 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+use crate::Key;
+use std::{
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+/// The progress of an operation spanning multiple regions, reported to a [`ProgressCallback`]
+/// each time the operation completes a region.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Progress {
+    /// The number of regions completed so far.
+    pub regions_completed: usize,
+    /// The number of regions of the operation known so far. It grows when a region is split or
+    /// merged during the operation and its part of the operation is retried in the new regions.
+    pub regions_total: usize,
+    /// The end key of the region completed last, as stored in TiKV (i.e. including the keyspace
+    /// prefix, if any). Regions are processed concurrently, so they may complete out of order. An
+    /// empty key is the end of the key space.
+    pub current_key: Key,
+}
+
+/// A callback reporting the [`Progress`] of long multi-region operations, e.g. to drive a progress
+/// bar. It is called from the tasks processing the regions, so it should return quickly.
+#[derive(Clone)]
+pub struct ProgressCallback(Arc<dyn Fn(&Progress) + Send + Sync>);
+
+impl ProgressCallback {
+    pub fn new(callback: impl Fn(&Progress) + Send + Sync + 'static) -> ProgressCallback {
+        ProgressCallback(Arc::new(callback))
+    }
+}
+
+impl fmt::Debug for ProgressCallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ProgressCallback")
+    }
+}
+
+/// Callbacks are equal if they are clones of each other.
+impl PartialEq for ProgressCallback {
+    fn eq(&self, other: &ProgressCallback) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for ProgressCallback {}
+
+/// Tracks the regions of a single execution of a plan and reports them to a callback.
+pub(crate) struct ProgressTracker {
+    callback: ProgressCallback,
+    /// The regions completed and the regions in total.
+    regions: Mutex<(usize, usize)>,
+}
+
+impl ProgressTracker {
+    pub fn new(callback: ProgressCallback) -> ProgressTracker {
+        ProgressTracker {
+            callback,
+            regions: Mutex::new((0, 0)),
+        }
+    }
+
+    /// The operation was split into `count` more regions.
+    pub fn add_regions(&self, count: usize) {
+        self.regions.lock().unwrap().1 += count;
+    }
+
+    /// A region is retried, its part of the operation is added again in its current regions.
+    pub fn retry_region(&self) {
+        self.regions.lock().unwrap().1 -= 1;
+    }
+
+    /// A region ending at `end_key` is completed.
+    pub fn complete_region(&self, end_key: Key) {
+        let progress = {
+            let mut regions = self.regions.lock().unwrap();
+            regions.0 += 1;
+            Progress {
+                regions_completed: regions.0,
+                regions_total: regions.1,
+                current_key: end_key,
+            }
+        };
+        (self.callback.0)(&progress);
+    }
+}
@@ -0,0 +1,998 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+use crate::{
+    pd::PdClient,
+    region::{RegionId, RegionWithLeader},
+    request::plan::MULTI_REGION_CONCURRENCY,
+    store::in_call,
+    trace::in_current_trace,
+    BoundRange, Error, Key, KvPair, Result,
+};
+use futures::{prelude::*, stream::BoxStream};
+use std::sync::{
+    atomic::{AtomicU32, Ordering},
+    Arc,
+};
+use tokio::sync::{mpsc, OwnedSemaphorePermit, Semaphore};
+
+/// Bounds how far a [streaming scan](crate::RawClient::scan_stream) prefetches ahead of its
+/// consumer, and how many regions it scans concurrently.
+///
+/// The next batch is fetched while the consumer processes the current one. Fetching pauses once
+/// `depth` batches or `max_buffered_bytes` bytes of keys and values are buffered, and resumes
+/// when the consumer takes a batch.
+///
+/// By default, one region is scanned at a time. If several regions are scanned concurrently,
+/// the [`ScanOrder`] decides whether batches are yielded in the order of their keys, or as soon
+/// as they are fetched. When ordered, the buffer of each region scanned is bounded separately,
+/// by `depth` batches and `max_buffered_bytes / concurrency` bytes.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ScanPrefetch {
+    /// The maximum number of batches buffered, at least one.
+    pub depth: usize,
+    /// The maximum total size of the buffered batches. A single batch larger than this is still
+    /// buffered, but alone.
+    pub max_buffered_bytes: usize,
+    /// The maximum number of regions scanned concurrently, at least one.
+    pub concurrency: usize,
+    /// The order of the batches of regions scanned concurrently.
+    pub order: ScanOrder,
+}
+
+impl ScanPrefetch {
+    pub fn new(depth: usize, max_buffered_bytes: usize) -> ScanPrefetch {
+        ScanPrefetch {
+            depth,
+            max_buffered_bytes,
+            concurrency: 1,
+            order: ScanOrder::Ordered,
+        }
+    }
+
+    /// Scan up to `concurrency` regions concurrently, yielding their batches in `order`.
+    ///
+    /// # Examples
+    /// ```rust
+    /// # use tikv_client::{ScanOrder, ScanPrefetch};
+    /// let prefetch = ScanPrefetch::default().with_concurrency(4, ScanOrder::Unordered);
+    /// ```
+    pub fn with_concurrency(mut self, concurrency: usize, order: ScanOrder) -> ScanPrefetch {
+        self.concurrency = concurrency;
+        self.order = order;
+        self
+    }
+}
+
+impl Default for ScanPrefetch {
+    /// Two batches of at most 8 MiB in total, from one region at a time.
+    fn default() -> ScanPrefetch {
+        ScanPrefetch::new(2, 8 * 1024 * 1024)
+    }
+}
+
+/// The order of the batches of a streaming scan which scans several regions concurrently.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ScanOrder {
+    /// Batches are yielded in the order of their keys, so a slow region holds back the batches
+    /// of the regions after it.
+    Ordered,
+    /// Batches are yielded as soon as they are fetched. The pairs of each batch are still
+    /// ordered, and so are the batches of each region, but batches of different regions are
+    /// interleaved.
+    Unordered,
+}
+
+/// An item of a streaming scan annotated with the regions of the pairs, see
+/// [`RawClient::scan_stream_with_regions`](crate::RawClient::scan_stream_with_regions).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ScanItem {
+    /// The following pairs are from the region `region_id`, until the next marker. A marker is
+    /// yielded before the first pair and whenever the region changes, so a region has several
+    /// markers if its pairs are interleaved with the pairs of other regions.
+    Region(RegionId),
+    /// A pair and the region it was scanned from.
+    Pair(RegionId, KvPair),
+}
+
+/// Scan `range` region by region in ascending order, pushing the limit down to each region.
+///
+/// `scan_region` is called with a range within a single region (as known by the region cache) and
+/// the number of entries still needed. Scanning stops as soon as `limit` entries have been
+/// collected, so trailing regions are not scanned at all and each region is asked only for the
+/// remaining number of entries rather than the whole limit.
+///
+/// The first region is scanned alone. After that, as many regions as are likely to yield the
+/// remaining entries, judging by the entries per region so far, are scanned concurrently, up to
+/// [`MULTI_REGION_CONCURRENCY`]. So a limit which one region satisfies costs one request, and a
+/// limit spanning many sparse regions does not wait for them one by one.
+///
+/// The result contains at most `limit` entries.
+pub async fn scan_with_limit<PdC, T, F, Fut>(
+    pd_client: Arc<PdC>,
+    range: BoundRange,
+    limit: u32,
+    scan_region: F,
+) -> Result<Vec<T>>
+where
+    PdC: PdClient,
+    F: FnMut(BoundRange, u32) -> Fut,
+    Fut: Future<Output = Result<Vec<T>>>,
+{
+    scan_regions_with_limit(region_ranges(pd_client, range), limit, scan_region).await
+}
+
+/// Like [`scan_with_limit`], but scans `range` in descending order, from the last region to the
+/// first.
+///
+/// The regions of `range` are looked up in ascending order first: a region is looked up by a key
+/// it contains, and the end of `range` is exclusive, so the last region cannot be looked up by it.
+pub async fn scan_with_limit_reverse<PdC, T, F, Fut>(
+    pd_client: Arc<PdC>,
+    range: BoundRange,
+    limit: u32,
+    scan_region: F,
+) -> Result<Vec<T>>
+where
+    PdC: PdClient,
+    F: FnMut(BoundRange, u32) -> Fut,
+    Fut: Future<Output = Result<Vec<T>>>,
+{
+    let region_ranges: Vec<BoundRange> = region_ranges(pd_client, range).try_collect().await?;
+    let region_ranges = stream::iter(region_ranges.into_iter().rev().map(Ok));
+    scan_regions_with_limit(region_ranges, limit, scan_region).await
+}
+
+/// Scan the ranges of `region_ranges` in order until `limit` entries are collected, see
+/// [`scan_with_limit`].
+async fn scan_regions_with_limit<T, F, Fut>(
+    region_ranges: impl Stream<Item = Result<BoundRange>>,
+    limit: u32,
+    mut scan_region: F,
+) -> Result<Vec<T>>
+where
+    F: FnMut(BoundRange, u32) -> Fut,
+    Fut: Future<Output = Result<Vec<T>>>,
+{
+    let mut region_ranges = Box::pin(region_ranges);
+    let mut result = Vec::new();
+    let mut scanned_regions = 0;
+    while (result.len() as u32) < limit {
+        let remaining = limit - result.len() as u32;
+        let concurrency = match result.len().checked_div(scanned_regions) {
+            Some(0) => MULTI_REGION_CONCURRENCY,
+            Some(per_region) => (remaining as usize)
+                .div_ceil(per_region)
+                .min(MULTI_REGION_CONCURRENCY),
+            None => 1,
+        };
+        let mut scans = Vec::with_capacity(concurrency);
+        while scans.len() < concurrency {
+            match region_ranges.try_next().await? {
+                Some(region_range) => scans.push(scan_region(region_range, remaining)),
+                None => break,
+            }
+        }
+        if scans.is_empty() {
+            break;
+        }
+        scanned_regions += scans.len();
+        // the regions in flight may all be needed, so each is asked for all remaining entries
+        for mut entries in future::try_join_all(scans).await? {
+            entries.truncate(limit as usize - result.len());
+            result.append(&mut entries);
+        }
+    }
+    Ok(result)
+}
+
+/// Like [`scan_with_limit`], but also stops once the keys and values scanned exceed `max_bytes`.
+///
+/// Once some pairs are scanned, a region is asked for no more pairs than are likely to fit in the
+/// remaining bytes, judging by the average size of the pairs so far, and the rest of the region
+/// is scanned if they all fit. So at most about one such request is scanned beyond `max_bytes`.
+///
+/// Returns the pairs which fit in `max_bytes`, and the key of the first pair which did not fit,
+/// if any.
+pub async fn scan_with_byte_limit<PdC, F, Fut>(
+    pd_client: Arc<PdC>,
+    range: BoundRange,
+    limit: u32,
+    max_bytes: usize,
+    mut scan_region: F,
+) -> Result<(Vec<KvPair>, Option<Key>)>
+where
+    PdC: PdClient,
+    F: FnMut(BoundRange, u32) -> Fut,
+    Fut: Future<Output = Result<Vec<KvPair>>>,
+{
+    let (mut start_key, end_key) = range.into_keys();
+    let end_key = end_key.filter(|k| !k.is_empty());
+    let mut result = Vec::new();
+    let mut bytes: usize = 0;
+    while (result.len() as u32) < limit {
+        let region = pd_client.region_for_key(&start_key).await?;
+        let (region_range, is_last) = range_in_region(&region, start_key, &end_key);
+
+        let remaining = limit - result.len() as u32;
+        let count = match bytes.checked_div(result.len()) {
+            // one more than fits, to find the resume key
+            Some(average) => remaining.min(((max_bytes - bytes) / average.max(1) + 1) as u32),
+            None => remaining,
+        };
+        let mut entries = scan_region(region_range, count).await?;
+        entries.truncate(count as usize);
+        let is_partial = count < remaining && entries.len() == count as usize;
+        for (i, pair) in entries.iter().enumerate() {
+            bytes += pair_size(pair);
+            if bytes > max_bytes {
+                let resume_key = pair.key().clone();
+                entries.truncate(i);
+                result.append(&mut entries);
+                return Ok((result, Some(resume_key)));
+            }
+        }
+
+        let next_key = match entries.last() {
+            // the region may have more pairs after the last one
+            Some(last) if is_partial => {
+                let mut key = Vec::from(last.key().clone());
+                key.push(0);
+                Some(key.into())
+            }
+            _ if is_last => None,
+            _ => Some(region.end_key()),
+        };
+        result.append(&mut entries);
+        match next_key {
+            Some(key) => start_key = key,
+            None => break,
+        }
+    }
+    Ok((result, None))
+}
+
+/// Like [`scan_with_limit`], but scans up to `concurrency` regions of `range` concurrently.
+///
+/// The regions are scanned ahead of the entries collected, in the order of their keys, so each
+/// region is asked for the number of entries still needed when its scan starts, not counting the
+/// regions in flight, and up to `concurrency - 1` regions after the last one needed may be
+/// scanned for nothing.
+pub async fn scan_with_limit_concurrently<PdC, T, F, Fut>(
+    pd_client: Arc<PdC>,
+    range: BoundRange,
+    limit: u32,
+    concurrency: usize,
+    scan_region: F,
+) -> Result<Vec<T>>
+where
+    PdC: PdClient,
+    F: FnMut(BoundRange, u32) -> Fut,
+    Fut: Future<Output = Result<Vec<T>>>,
+{
+    if concurrency <= 1 {
+        return scan_with_limit(pd_client, range, limit, scan_region).await;
+    }
+    let remaining = Arc::new(AtomicU32::new(limit));
+    let mut scans = Box::pin(region_scans(
+        pd_client,
+        range,
+        remaining.clone(),
+        concurrency,
+        scan_region,
+    ));
+    let mut result = Vec::new();
+    while (result.len() as u32) < limit {
+        let mut entries = match scans.try_next().await? {
+            Some(entries) => entries,
+            None => break,
+        };
+        entries.truncate(limit as usize - result.len());
+        result.append(&mut entries);
+        remaining.store(limit - result.len() as u32, Ordering::SeqCst);
+    }
+    Ok(result)
+}
+
+/// Like [`scan_with_byte_limit`], but scans up to `concurrency` regions of `range` concurrently,
+/// see [`scan_with_limit_concurrently`].
+pub async fn scan_with_byte_limit_concurrently<PdC, F, Fut>(
+    pd_client: Arc<PdC>,
+    range: BoundRange,
+    limit: u32,
+    max_bytes: usize,
+    concurrency: usize,
+    scan_region: F,
+) -> Result<(Vec<KvPair>, Option<Key>)>
+where
+    PdC: PdClient,
+    F: FnMut(BoundRange, u32) -> Fut,
+    Fut: Future<Output = Result<Vec<KvPair>>>,
+{
+    if concurrency <= 1 {
+        return scan_with_byte_limit(pd_client, range, limit, max_bytes, scan_region).await;
+    }
+    let remaining = Arc::new(AtomicU32::new(limit));
+    let mut scans = Box::pin(region_scans(
+        pd_client,
+        range,
+        remaining.clone(),
+        concurrency,
+        scan_region,
+    ));
+    let mut result = Vec::new();
+    let mut bytes = 0;
+    while (result.len() as u32) < limit {
+        let mut entries = match scans.try_next().await? {
+            Some(entries) => entries,
+            None => break,
+        };
+        entries.truncate(limit as usize - result.len());
+        for (i, pair) in entries.iter().enumerate() {
+            bytes += pair_size(pair);
+            if bytes > max_bytes {
+                let resume_key = pair.key().clone();
+                entries.truncate(i);
+                result.append(&mut entries);
+                return Ok((result, Some(resume_key)));
+            }
+        }
+        result.append(&mut entries);
+        remaining.store(limit - result.len() as u32, Ordering::SeqCst);
+    }
+    Ok((result, None))
+}
+
+/// The entries of each region of `range`, scanning up to `concurrency` regions concurrently. Each
+/// region is asked for the number of entries `remaining` when its scan starts, which the caller
+/// lowers as it collects entries.
+fn region_scans<PdC, T, F, Fut>(
+    pd_client: Arc<PdC>,
+    range: BoundRange,
+    remaining: Arc<AtomicU32>,
+    concurrency: usize,
+    mut scan_region: F,
+) -> impl Stream<Item = Result<Vec<T>>>
+where
+    PdC: PdClient,
+    F: FnMut(BoundRange, u32) -> Fut,
+    Fut: Future<Output = Result<Vec<T>>>,
+{
+    region_ranges(pd_client, range)
+        .map_ok(move |region_range| scan_region(region_range, remaining.load(Ordering::SeqCst)))
+        .try_buffered(concurrency)
+}
+
+/// The size of the key and value of a pair, as counted against byte limits.
+pub fn pair_size(pair: &KvPair) -> usize {
+    pair.key().len() + pair.value().len()
+}
+
+/// The part of the range from `start_key` to `end_key` (`None` if unbounded) in `region`, and
+/// whether it reaches the end of the range.
+fn range_in_region(
+    region: &RegionWithLeader,
+    start_key: Key,
+    end_key: &Option<Key>,
+) -> (BoundRange, bool) {
+    let region_end = region.end_key();
+    let (scan_end, is_last): (Key, bool) = match end_key {
+        Some(end) if region_end.is_empty() || *end <= region_end => (end.clone(), true),
+        _ => (region_end.clone(), region_end.is_empty()),
+    };
+    let scan_end = if scan_end.is_empty() {
+        None
+    } else {
+        Some(scan_end)
+    };
+    ((start_key, scan_end).into(), is_last)
+}
+
+/// Scan `range` in batches of at most `batch_size` pairs, prefetching batches and scanning
+/// regions concurrently as configured by `prefetch`.
+///
+/// `scan_region` is called with a range within a single region and `batch_size`. Batches are
+/// fetched by a spawned task, which stops when the stream is dropped. The stream ends after the
+/// first error.
+pub fn scan_stream<PdC, F, Fut>(
+    pd_client: Arc<PdC>,
+    range: BoundRange,
+    batch_size: u32,
+    prefetch: ScanPrefetch,
+    scan_region: F,
+) -> BoxStream<'static, Result<Vec<KvPair>>>
+where
+    PdC: PdClient,
+    F: Fn(BoundRange, u32) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<Vec<KvPair>>> + Send + 'static,
+{
+    scan_stream_with_regions(pd_client, range, batch_size, prefetch, scan_region)
+        .map_ok(|(_, pairs)| pairs)
+        .boxed()
+}
+
+/// Like [`scan_stream`], but each batch comes with the id of its region, as known by the region
+/// cache when the batch was requested.
+pub fn scan_stream_with_regions<PdC, F, Fut>(
+    pd_client: Arc<PdC>,
+    range: BoundRange,
+    batch_size: u32,
+    prefetch: ScanPrefetch,
+    scan_region: F,
+) -> BoxStream<'static, Result<(RegionId, Vec<KvPair>)>>
+where
+    PdC: PdClient,
+    F: Fn(BoundRange, u32) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<Vec<KvPair>>> + Send + 'static,
+{
+    let concurrency = prefetch.concurrency.max(1);
+    // the receivers of the batches, in the order in which they are consumed
+    let (receivers_tx, receivers_rx) = mpsc::channel(concurrency);
+
+    // the requests to all regions are a single call for the store queues
+    tokio::spawn(in_current_trace(in_call(async move {
+        let pd_client = &pd_client;
+        let scan_region = &scan_region;
+        if concurrency == 1 {
+            let (batches, rx) = BatchSender::new(prefetch.depth, prefetch.max_buffered_bytes);
+            if receivers_tx.send(rx).await.is_ok() {
+                scan_range(pd_client, Ok(range), batch_size, scan_region, &batches).await;
+            }
+            return;
+        }
+        match prefetch.order {
+            ScanOrder::Unordered => {
+                // all regions send to one receiver, in the order in which batches are fetched
+                let (batches, rx) = BatchSender::new(prefetch.depth, prefetch.max_buffered_bytes);
+                if receivers_tx.send(rx).await.is_err() {
+                    return;
+                }
+                let batches = &batches;
+                region_ranges(pd_client.clone(), range)
+                    .for_each_concurrent(concurrency, move |range| async move {
+                        scan_range(pd_client, range, batch_size, scan_region, batches).await
+                    })
+                    .await;
+            }
+            ScanOrder::Ordered => {
+                // each region sends to its own receiver, which are consumed in the order of the
+                // regions, so the buffer of each region is bounded separately
+                let max_buffered_bytes = prefetch.max_buffered_bytes / concurrency;
+                let receivers_tx = &receivers_tx;
+                region_ranges(pd_client.clone(), range)
+                    .then(move |range| async move {
+                        let (batches, rx) = BatchSender::new(prefetch.depth, max_buffered_bytes);
+                        receivers_tx.send(rx).await.ok().map(|_| (range, batches))
+                    })
+                    .boxed()
+                    // the stream was dropped
+                    .take_while(|region| future::ready(region.is_some()))
+                    .filter_map(future::ready)
+                    .for_each_concurrent(concurrency, move |(range, batches)| async move {
+                        scan_range(pd_client, range, batch_size, scan_region, &batches).await
+                    })
+                    .await;
+            }
+        }
+    })));
+
+    // the permit of a batch is released once the consumer takes it
+    stream::unfold(
+        (receivers_rx, None::<mpsc::Receiver<Batch>>, false),
+        |(mut receivers_rx, mut current, done)| async move {
+            if done {
+                return None;
+            }
+            loop {
+                if let Some(rx) = &mut current {
+                    if let Some(batch) = rx.recv().await {
+                        let done = batch.is_err();
+                        let batch = batch.map(|(region_id, pairs, _permit)| (region_id, pairs));
+                        return Some((batch, (receivers_rx, current, done)));
+                    }
+                }
+                current = Some(receivers_rx.recv().await?);
+            }
+        },
+    )
+    .boxed()
+}
+
+type Batch = Result<(RegionId, Vec<KvPair>, OwnedSemaphorePermit)>;
+
+/// Sends the batches of a scan to its consumer, bounding the batches buffered by the depth of the
+/// channel and their bytes by the semaphore.
+struct BatchSender {
+    tx: mpsc::Sender<Batch>,
+    buffered_bytes: Arc<Semaphore>,
+    capacity: usize,
+}
+
+impl BatchSender {
+    fn new(depth: usize, max_buffered_bytes: usize) -> (BatchSender, mpsc::Receiver<Batch>) {
+        let (tx, rx) = mpsc::channel(depth.max(1));
+        let capacity = max_buffered_bytes.clamp(1, u32::MAX as usize);
+        let sender = BatchSender {
+            tx,
+            buffered_bytes: Arc::new(Semaphore::new(capacity)),
+            capacity,
+        };
+        (sender, rx)
+    }
+
+    /// Returns false if the stream was dropped.
+    async fn send(&self, region_id: RegionId, pairs: Vec<KvPair>) -> bool {
+        let size: usize = pairs.iter().map(pair_size).sum();
+        let permit = match self
+            .buffered_bytes
+            .clone()
+            .acquire_many_owned(size.min(self.capacity) as u32)
+            .await
+        {
+            Ok(permit) => permit,
+            Err(_) => return false,
+        };
+        self.tx.send(Ok((region_id, pairs, permit))).await.is_ok()
+    }
+
+    async fn send_err(&self, e: Error) {
+        let _ = self.tx.send(Err(e)).await;
+    }
+}
+
+/// Flattens the batches of a [`scan_stream_with_regions`] into pairs annotated with their regions,
+/// with a marker before the pairs of each region.
+pub fn scan_items(
+    batches: impl Stream<Item = Result<(RegionId, Vec<KvPair>)>>,
+) -> impl Stream<Item = Result<ScanItem>> {
+    let mut current = None;
+    batches
+        .map_ok(move |(region_id, pairs)| {
+            let marker = if current.replace(region_id) == Some(region_id) {
+                None
+            } else {
+                Some(ScanItem::Region(region_id))
+            };
+            let pairs = pairs
+                .into_iter()
+                .map(move |pair| ScanItem::Pair(region_id, pair));
+            stream::iter(marker.into_iter().chain(pairs).map(Ok))
+        })
+        .try_flatten()
+}
+
+/// The parts of `range` in each region, in ascending order. The stream ends after the first error.
+fn region_ranges<PdC: PdClient>(
+    pd_client: Arc<PdC>,
+    range: BoundRange,
+) -> impl Stream<Item = Result<BoundRange>> {
+    let (start_key, end_key) = range.into_keys();
+    // an empty end key means the range is unbounded
+    let end_key = end_key.filter(|k| !k.is_empty());
+    stream::try_unfold(Some(start_key), move |start_key| {
+        let pd_client = pd_client.clone();
+        let end_key = end_key.clone();
+        async move {
+            let start_key = match start_key {
+                Some(start_key) => start_key,
+                None => return Ok(None),
+            };
+            let region = pd_client.region_for_key(&start_key).await?;
+            let (region_range, is_last) = range_in_region(&region, start_key, &end_key);
+            let next_key = if is_last {
+                None
+            } else {
+                Some(region.end_key())
+            };
+            Ok(Some((region_range, next_key)))
+        }
+    })
+}
+
+/// Scan `range` region by region in ascending order in batches of at most `batch_size` pairs,
+/// sending the batches to `batches`. Stops after the first error, which is sent too, or when the
+/// stream is dropped.
+async fn scan_range<PdC, F, Fut>(
+    pd_client: &Arc<PdC>,
+    range: Result<BoundRange>,
+    batch_size: u32,
+    scan_region: &F,
+    batches: &BatchSender,
+) where
+    PdC: PdClient,
+    F: Fn(BoundRange, u32) -> Fut,
+    Fut: Future<Output = Result<Vec<KvPair>>>,
+{
+    let (mut start_key, end_key) = match range {
+        Ok(range) => range.into_keys(),
+        Err(e) => return batches.send_err(e).await,
+    };
+    // an empty end key means the range is unbounded
+    let end_key = end_key.filter(|k| !k.is_empty());
+    loop {
+        let region = match pd_client.region_for_key(&start_key).await {
+            Ok(region) => region,
+            Err(e) => return batches.send_err(e).await,
+        };
+        let (region_range, is_last) = range_in_region(&region, start_key, &end_key);
+        let pairs = match scan_region(region_range, batch_size).await {
+            Ok(pairs) => pairs,
+            Err(e) => return batches.send_err(e).await,
+        };
+        // a batch smaller than the batch size means the region is exhausted
+        let next_key = match pairs.last() {
+            Some(last) if pairs.len() as u32 >= batch_size => {
+                let mut key: Vec<u8> = last.key().clone().into();
+                key.push(0);
+                Some(Key::from(key))
+            }
+            _ if is_last => None,
+            _ => Some(region.end_key()),
+        };
+
+        if !pairs.is_empty() && !batches.send(region.id(), pairs).await {
+            return;
+        }
+        match next_key {
+            Some(key) => start_key = key,
+            None => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mock::{MockKvClient, MockPdClient};
+    use futures::executor::block_on;
+    use futures_timer::Delay;
+    use std::{ops::RangeBounds, sync::Mutex, time::Duration};
+
+    /// Returns `batch_size` pairs from the start of each range, or all keys of `keys` in the range.
+    fn scan_keys(keys: &[u8], range: BoundRange, batch_size: u32) -> Vec<KvPair> {
+        keys.iter()
+            .map(|k| Key::from(vec![*k]))
+            .filter(|k| range.contains(k))
+            .take(batch_size as usize)
+            .map(|k| KvPair::new(k, vec![0; 10]))
+            .collect()
+    }
+
+    #[test]
+    fn test_scan_with_limit() {
+        let pd_client = Arc::new(MockPdClient::new(MockKvClient::default()));
+        let calls = Mutex::new(Vec::new());
+        // every region returns 3 entries
+        let scan = |range: BoundRange, limit: u32| {
+            calls.lock().unwrap().push((range.into_keys(), limit));
+            async { Ok(vec![(); 3]) }
+        };
+
+        let result = block_on(scan_with_limit(
+            pd_client.clone(),
+            (vec![1], vec![251]).into(),
+            5,
+            scan,
+        ))
+        .unwrap();
+        assert_eq!(result.len(), 5);
+        assert_eq!(
+            calls.into_inner().unwrap(),
+            vec![
+                ((vec![1].into(), Some(vec![10].into())), 5),
+                ((vec![10].into(), Some(vec![250, 250].into())), 2),
+            ]
+        );
+
+        // the last region is scanned up to the end of the range
+        let calls = Mutex::new(Vec::new());
+        let scan = |range: BoundRange, limit: u32| {
+            calls.lock().unwrap().push((range.into_keys(), limit));
+            async { Ok(vec![(); 1]) }
+        };
+        let result = block_on(scan_with_limit(pd_client, (vec![11]..).into(), 10, scan)).unwrap();
+        assert_eq!(result.len(), 2);
+        assert_eq!(
+            calls.into_inner().unwrap(),
+            vec![
+                ((vec![11].into(), Some(vec![250, 250].into())), 10),
+                ((vec![250, 250].into(), None), 9),
+            ]
+        );
+    }
+
+    /// A scan returning one pair per region of keys [1, 11, 251], whose scans of the regions
+    /// other than the one containing `first` only return once both are in flight.
+    fn gated_scan(
+        first: u8,
+        calls: &Mutex<Vec<(Key, u32)>>,
+    ) -> impl FnMut(BoundRange, u32) -> future::BoxFuture<'static, Result<Vec<KvPair>>> + '_ {
+        let in_flight = Arc::new(tokio::sync::Barrier::new(2));
+        move |range: BoundRange, limit: u32| {
+            let (start, _) = range.clone().into_keys();
+            calls.lock().unwrap().push((start, limit));
+            let in_flight = in_flight.clone();
+            async move {
+                if !range.contains(&Key::from(vec![first])) {
+                    in_flight.wait().await;
+                }
+                Ok(scan_keys(&[1, 11, 251], range, limit))
+            }
+            .boxed()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_scan_with_limit_fans_out() {
+        let pd_client = Arc::new(MockPdClient::new(MockKvClient::default()));
+        // the first region yields one pair, so the two remaining pairs are expected from the next
+        // two regions, which are scanned concurrently
+        let calls = Mutex::new(Vec::new());
+        let pairs = scan_with_limit(pd_client.clone(), (..).into(), 3, gated_scan(1, &calls));
+        let pairs = tokio::time::timeout(Duration::from_secs(10), pairs)
+            .await
+            .expect("the regions are not scanned concurrently")
+            .unwrap();
+        let scanned: Vec<u8> = pairs
+            .iter()
+            .map(|p| Vec::from(p.key().clone())[0])
+            .collect();
+        assert_eq!(scanned, vec![1, 11, 251]);
+        assert_eq!(
+            calls.into_inner().unwrap(),
+            vec![
+                (vec![].into(), 3),
+                (vec![10].into(), 2),
+                (vec![250, 250].into(), 2),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_scan_with_limit_reverse_fans_out() {
+        let pd_client = Arc::new(MockPdClient::new(MockKvClient::default()));
+        // the last region yields one pair, so the two preceding regions are scanned concurrently
+        let calls = Mutex::new(Vec::new());
+        let pairs = scan_with_limit_reverse(pd_client, (..).into(), 3, gated_scan(251, &calls));
+        let pairs = tokio::time::timeout(Duration::from_secs(10), pairs)
+            .await
+            .expect("the regions are not scanned concurrently")
+            .unwrap();
+        let scanned: Vec<u8> = pairs
+            .iter()
+            .map(|p| Vec::from(p.key().clone())[0])
+            .collect();
+        assert_eq!(scanned, vec![251, 11, 1]);
+        assert_eq!(
+            calls.into_inner().unwrap(),
+            vec![
+                (vec![250, 250].into(), 3),
+                (vec![10].into(), 2),
+                (vec![].into(), 2),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_scan_with_byte_limit() {
+        let pd_client = Arc::new(MockPdClient::new(MockKvClient::default()));
+        let keys = [1, 2, 3, 11, 12];
+        // each pair is 11 bytes
+        let scan =
+            |range: BoundRange, limit: u32| future::ready(Ok(scan_keys(&keys, range, limit)));
+        let (pairs, resume_key) = block_on(scan_with_byte_limit(
+            pd_client.clone(),
+            (..).into(),
+            10,
+            50,
+            scan,
+        ))
+        .unwrap();
+        assert_eq!(pairs.len(), 4);
+        assert_eq!(resume_key, Some(vec![12].into()));
+
+        let (pairs, resume_key) = block_on(scan_with_byte_limit(
+            pd_client.clone(),
+            (..).into(),
+            10,
+            55,
+            scan,
+        ))
+        .unwrap();
+        assert_eq!(pairs.len(), 5);
+        assert_eq!(resume_key, None);
+
+        // once the size of the pairs is known, a region is asked for one more pair than fits, and
+        // scanned further if they all fit
+        let keys = [1, 11, 12, 13, 14, 15, 16];
+        let calls = Mutex::new(Vec::new());
+        let scan = |range: BoundRange, limit: u32| {
+            let (start, _) = range.clone().into_keys();
+            calls.lock().unwrap().push((start, limit));
+            let mut pairs = scan_keys(&keys, range, limit);
+            // the pairs from key 12 are half as large
+            for pair in &mut pairs {
+                if *pair.key() >= Key::from(vec![12]) {
+                    pair.1.truncate(4);
+                }
+            }
+            future::ready(Ok(pairs))
+        };
+        let (pairs, resume_key) =
+            block_on(scan_with_byte_limit(pd_client, (..).into(), 10, 40, scan)).unwrap();
+        assert_eq!(pairs.len(), 5);
+        assert_eq!(resume_key, Some(vec![15].into()));
+        assert_eq!(
+            calls.into_inner().unwrap(),
+            vec![
+                (vec![].into(), 10),
+                // 29 bytes left at 11 bytes per pair
+                (vec![10].into(), 3),
+                // 8 bytes left at 8 bytes per pair
+                (vec![13, 0].into(), 2),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_scan_with_limit_concurrently() {
+        let pd_client = Arc::new(MockPdClient::new(MockKvClient::default()));
+        let keys = [1, 2, 3, 11, 12, 251];
+        // the scans of the three regions only return once all of them are in flight, and the
+        // first region returns last, its pairs still come first
+        let in_flight = Arc::new(tokio::sync::Barrier::new(3));
+        let finished = Arc::new(tokio::sync::Semaphore::new(0));
+        let gated_scan = |range: BoundRange, limit: u32| {
+            let in_flight = in_flight.clone();
+            let finished = finished.clone();
+            async move {
+                in_flight.wait().await;
+                if range.contains(&Key::from(vec![1])) {
+                    finished.acquire_many(2).await.unwrap().forget();
+                } else {
+                    finished.add_permits(1);
+                }
+                Ok(scan_keys(&keys, range, limit))
+            }
+        };
+        let pairs = scan_with_limit_concurrently(pd_client.clone(), (..).into(), 10, 3, gated_scan);
+        let pairs = tokio::time::timeout(Duration::from_secs(10), pairs)
+            .await
+            .expect("the regions are not scanned concurrently")
+            .unwrap();
+        let scanned: Vec<u8> = pairs
+            .iter()
+            .map(|p| Vec::from(p.key().clone())[0])
+            .collect();
+        assert_eq!(scanned, keys);
+
+        let scan =
+            |range: BoundRange, limit: u32| future::ready(Ok(scan_keys(&keys, range, limit)));
+
+        let pairs = scan_with_limit_concurrently(pd_client.clone(), (..).into(), 4, 2, scan)
+            .await
+            .unwrap();
+        assert_eq!(pairs.len(), 4);
+        assert_eq!(pairs[3].key(), &Key::from(vec![11]));
+
+        // each pair is 11 bytes
+        let (pairs, resume_key) =
+            scan_with_byte_limit_concurrently(pd_client, (..).into(), 10, 50, 3, scan)
+                .await
+                .unwrap();
+        assert_eq!(pairs.len(), 4);
+        assert_eq!(resume_key, Some(vec![12].into()));
+    }
+
+    #[tokio::test]
+    async fn test_scan_stream() {
+        let pd_client = Arc::new(MockPdClient::new(MockKvClient::default()));
+        let keys = [1, 2, 3, 9, 11, 200];
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let calls_cloned = calls.clone();
+        let stream = scan_stream(
+            pd_client,
+            (vec![2]..).into(),
+            2,
+            ScanPrefetch::default(),
+            move |range: BoundRange, batch_size: u32| {
+                calls_cloned.lock().unwrap().push(range.clone().into_keys());
+                future::ready(Ok(scan_keys(&keys, range, batch_size)))
+            },
+        );
+        let batches: Vec<Vec<u8>> = stream
+            .map_ok(|pairs| {
+                pairs
+                    .into_iter()
+                    .map(|p| Vec::from(p.into_key())[0])
+                    .collect()
+            })
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(batches, vec![vec![2, 3], vec![9], vec![11, 200]]);
+        assert_eq!(
+            *calls.lock().unwrap(),
+            vec![
+                (vec![2].into(), Some(vec![10].into())),
+                (vec![3, 0].into(), Some(vec![10].into())),
+                (vec![10].into(), Some(vec![250, 250].into())),
+                (vec![200, 0].into(), Some(vec![250, 250].into())),
+                (vec![250, 250].into(), None),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_scan_stream_prefetch_bounds() {
+        // Reports each fetch of a batch. The test runs on a single thread, so the spawned task
+        // fetches batches until it waits for space in the buffer before the test resumes.
+        let fetching_scan = || {
+            let (fetched_tx, fetched_rx) = tokio::sync::mpsc::unbounded_channel();
+            let keys: Vec<u8> = (1..10).collect();
+            let scan = move |range: BoundRange, _: u32| {
+                fetched_tx.send(()).unwrap();
+                future::ready(Ok(scan_keys(&keys, range, 1)))
+            };
+            (scan, fetched_rx)
+        };
+
+        // the spawned task fetches two batches for the buffer and waits with a third
+        let (scan, mut fetched) = fetching_scan();
+        let pd_client = Arc::new(MockPdClient::new(MockKvClient::default()));
+        let mut stream = scan_stream(pd_client, (..).into(), 1, ScanPrefetch::new(2, 1024), scan);
+        for _ in 0..3 {
+            fetched.recv().await.unwrap();
+        }
+        assert!(fetched.try_recv().is_err());
+        stream.next().await.unwrap().unwrap();
+        fetched.recv().await.unwrap();
+        assert!(fetched.try_recv().is_err());
+
+        // each pair is 11 bytes, so only one batch fits
+        let (scan, mut fetched) = fetching_scan();
+        let pd_client = Arc::new(MockPdClient::new(MockKvClient::default()));
+        let stream = scan_stream(pd_client, (..).into(), 1, ScanPrefetch::new(8, 20), scan);
+        for _ in 0..2 {
+            fetched.recv().await.unwrap();
+        }
+        assert!(fetched.try_recv().is_err());
+        assert_eq!(stream.try_collect::<Vec<_>>().await.unwrap().len(), 9);
+    }
+
+    #[tokio::test]
+    async fn test_scan_stream_concurrent() {
+        let keys = [1, 2, 3, 11, 12, 251];
+        // the first region is slow
+        let scan = move |range: BoundRange, batch_size: u32| {
+            let delay = if range.contains(&Key::from(vec![1])) {
+                Duration::from_millis(50)
+            } else {
+                Duration::from_millis(0)
+            };
+            let pairs = scan_keys(&keys, range, batch_size);
+            async move {
+                Delay::new(delay).await;
+                Ok(pairs)
+            }
+        };
+        let scan_all = |order| {
+            let pd_client = Arc::new(MockPdClient::new(MockKvClient::default()));
+            let prefetch = ScanPrefetch::default().with_concurrency(3, order);
+            scan_stream(pd_client, (..).into(), 2, prefetch, scan)
+                .map_ok(|pairs| {
+                    pairs
+                        .into_iter()
+                        .map(|p| Vec::from(p.into_key())[0])
+                        .collect()
+                })
+                .try_collect::<Vec<Vec<u8>>>()
+        };
+
+        let batches = scan_all(ScanOrder::Ordered).await.unwrap();
+        assert_eq!(batches, vec![vec![1, 2], vec![3], vec![11, 12], vec![251]]);
+
+        let batches = scan_all(ScanOrder::Unordered).await.unwrap();
+        assert_eq!(batches.len(), 4);
+        assert_eq!(batches[2..], [vec![1, 2], vec![3]]);
+    }
+}
@@ -3,9 +3,9 @@
 use super::plan::PreserveShard;
 use crate::{
     pd::PdClient,
-    request::{Dispatch, KvRequest, Plan, ResolveLock},
+    request::{Dispatch, Idempotence, KvRequest, Plan, ResolveLock},
     store::RegionStore,
-    Result,
+    Error, Result,
 };
 use futures::stream::BoxStream;
 use std::sync::Arc;
@@ -24,6 +24,22 @@ macro_rules! impl_inner_shardable {
         fn apply_shard(&mut self, shard: Self::Shard, store: &RegionStore) -> Result<()> {
             self.inner.apply_shard(shard, store)
         }
+
+        fn idempotence(&self) -> Idempotence {
+            self.inner.idempotence()
+        }
+
+        fn request_type(&self) -> &'static str {
+            self.inner.request_type()
+        }
+
+        fn chunk_shard(&self, shard: Self::Shard, chunk_size: usize) -> Vec<Self::Shard> {
+            self.inner.chunk_shard(shard, chunk_size)
+        }
+
+        fn clear_shards(&mut self) {
+            self.inner.clear_shards()
+        }
     };
 }
 
@@ -35,7 +51,41 @@ pub trait Shardable {
         pd_client: &Arc<impl PdClient>,
     ) -> BoxStream<'static, Result<(Self::Shard, RegionStore)>>;
 
+    /// Restrict the request to `shard`. The context of the request is set by [`Dispatch`].
     fn apply_shard(&mut self, shard: Self::Shard, store: &RegionStore) -> Result<()>;
+
+    /// Whether a shard can be sent again after a [transport error](is_transport_error).
+    fn idempotence(&self) -> Idempotence {
+        Idempotence::Idempotent
+    }
+
+    /// The type of the request, e.g. `kv_prewrite`, carried by the errors of its shards, see
+    /// [`FailedRequest`](crate::FailedRequest).
+    fn request_type(&self) -> &'static str {
+        "unknown"
+    }
+
+    /// Split `shard` into shards of at most `chunk_size` items, each sent in its own request.
+    /// Only shards of keys are split, other shards are sent whole.
+    fn chunk_shard(&self, shard: Self::Shard, _chunk_size: usize) -> Vec<Self::Shard> {
+        vec![shard]
+    }
+
+    /// Drop the items the request was split into shards from, e.g. its keys, once its shards are
+    /// computed, so that the plan is not copied with all of them for each shard. The request is
+    /// only sent once a shard is applied to it.
+    fn clear_shards(&mut self) {}
+}
+
+/// Whether the request failed without a response because TiKV could not be reached, in which
+/// case it may or may not have been applied.
+pub fn is_transport_error(e: &Error) -> bool {
+    match e {
+        Error::Grpc(grpcio::Error::RpcFailure(status)) => {
+            status.code() == grpcio::RpcStatusCode::UNAVAILABLE
+        }
+        _ => false,
+    }
 }
 
 impl<Req: KvRequest + Shardable> Shardable for Dispatch<Req> {
@@ -50,7 +100,26 @@ impl<Req: KvRequest + Shardable> Shardable for Dispatch<Req> {
 
     fn apply_shard(&mut self, shard: Self::Shard, store: &RegionStore) -> Result<()> {
         self.kv_client = Some(store.client.clone());
-        self.request.apply_shard(shard, store)
+        self.store_id = store.store_id();
+        self.request.apply_shard(shard, store)?;
+        self.request.set_context(self.context.build(store)?);
+        Ok(())
+    }
+
+    fn idempotence(&self) -> Idempotence {
+        Req::IDEMPOTENCE
+    }
+
+    fn request_type(&self) -> &'static str {
+        self.request.label()
+    }
+
+    fn chunk_shard(&self, shard: Self::Shard, chunk_size: usize) -> Vec<Self::Shard> {
+        self.request.chunk_shard(shard, chunk_size)
+    }
+
+    fn clear_shards(&mut self) {
+        self.request.clear_shards()
     }
 }
 
@@ -68,6 +137,22 @@ impl<P: Plan + Shardable> Shardable for PreserveShard<P> {
         self.shard = Some(shard.clone());
         self.inner.apply_shard(shard, store)
     }
+
+    fn idempotence(&self) -> Idempotence {
+        self.inner.idempotence()
+    }
+
+    fn request_type(&self) -> &'static str {
+        self.inner.request_type()
+    }
+
+    fn chunk_shard(&self, shard: Self::Shard, chunk_size: usize) -> Vec<Self::Shard> {
+        self.inner.chunk_shard(shard, chunk_size)
+    }
+
+    fn clear_shards(&mut self) {
+        self.inner.clear_shards()
+    }
 }
 
 impl<P: Plan + Shardable, PdC: PdClient> Shardable for ResolveLock<P, PdC> {
@@ -96,9 +181,8 @@ macro_rules! shardable_key {
             fn apply_shard(
                 &mut self,
                 mut shard: Self::Shard,
-                store: &crate::store::RegionStore,
+                _store: &crate::store::RegionStore,
             ) -> crate::Result<()> {
-                self.set_context(store.region_with_leader.context()?);
                 assert!(shard.len() == 1);
                 self.set_key(shard.pop().unwrap());
                 Ok(())
@@ -128,12 +212,22 @@ macro_rules! shardable_keys {
             fn apply_shard(
                 &mut self,
                 shard: Self::Shard,
-                store: &crate::store::RegionStore,
+                _store: &crate::store::RegionStore,
             ) -> crate::Result<()> {
-                self.set_context(store.region_with_leader.context()?);
                 self.set_keys(shard.into_iter().map(Into::into).collect());
                 Ok(())
             }
+
+            fn chunk_shard(&self, shard: Self::Shard, chunk_size: usize) -> Vec<Self::Shard> {
+                shard
+                    .chunks(chunk_size.max(1))
+                    .map(<[Vec<u8>]>::to_vec)
+                    .collect()
+            }
+
+            fn clear_shards(&mut self) {
+                self.keys = Vec::new();
+            }
         }
     };
 }
@@ -156,10 +250,8 @@ macro_rules! shardable_range {
             fn apply_shard(
                 &mut self,
                 shard: Self::Shard,
-                store: &crate::store::RegionStore,
+                _store: &crate::store::RegionStore,
             ) -> crate::Result<()> {
-                self.set_context(store.region_with_leader.context()?);
-
                 self.set_start_key(shard.0.into());
                 self.set_end_key(shard.1.into());
                 Ok(())
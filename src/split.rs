@@ -0,0 +1,81 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! Splitting of regions at given keys, see
+//! [`TransactionClient::split_region_at`](crate::TransactionClient::split_region_at) and
+//! [`RawClient::split_region`](crate::RawClient::split_region).
+
+use crate::{
+    pd::PdClient,
+    region::RegionId,
+    request::{Collect, KvRequest, Merge, Shardable},
+    store::{store_stream_for_keys, RegionStore},
+    transaction::HasLocks,
+    Key, Result,
+};
+use futures::{future, prelude::*, stream::BoxStream};
+use std::sync::Arc;
+use tikv_client_proto::kvrpcpb;
+
+/// A request splitting the regions at `split_keys`. Raw keys are split as they are, TiKV encodes
+/// transactional keys first.
+pub fn new_split_region_request(
+    split_keys: impl IntoIterator<Item = Key>,
+    is_raw_kv: bool,
+) -> kvrpcpb::SplitRegionRequest {
+    kvrpcpb::SplitRegionRequest {
+        split_keys: split_keys.into_iter().map(Into::into).collect(),
+        is_raw_kv,
+        ..Default::default()
+    }
+}
+
+impl KvRequest for kvrpcpb::SplitRegionRequest {
+    type Response = kvrpcpb::SplitRegionResponse;
+}
+impl HasLocks for kvrpcpb::SplitRegionResponse {}
+
+impl Shardable for kvrpcpb::SplitRegionRequest {
+    type Shard = Vec<Vec<u8>>;
+
+    /// The keys which already start a region are left out, TiKV rejects them, so that a split is
+    /// a no-op for them. A region whose keys are all left out is not split.
+    fn shards(
+        &self,
+        pd_client: &Arc<impl PdClient>,
+    ) -> BoxStream<'static, Result<(Self::Shard, RegionStore)>> {
+        let mut keys = self.split_keys.clone();
+        keys.sort();
+        keys.dedup();
+        store_stream_for_keys(keys.into_iter(), pd_client.clone())
+            .try_filter_map(|(keys, store): (Vec<Vec<u8>>, RegionStore)| {
+                let start_key = store.region_with_leader.region.get_start_key();
+                let keys: Vec<Vec<u8>> = keys.into_iter().filter(|key| key != start_key).collect();
+                future::ok(if keys.is_empty() {
+                    None
+                } else {
+                    Some((keys, store))
+                })
+            })
+            .boxed()
+    }
+
+    fn apply_shard(&mut self, shard: Self::Shard, _store: &RegionStore) -> Result<()> {
+        self.split_keys = shard;
+        Ok(())
+    }
+}
+
+impl Merge<kvrpcpb::SplitRegionResponse> for Collect {
+    /// The ids of the regions resulting from the splits, in the order of their keys.
+    type Out = Vec<RegionId>;
+
+    fn merge(&self, input: Vec<Result<kvrpcpb::SplitRegionResponse>>) -> Result<Self::Out> {
+        let mut regions = input
+            .into_iter()
+            .map(|resp| Ok(resp?.regions))
+            .collect::<Result<Vec<_>>>()?
+            .concat();
+        regions.sort_by(|a, b| a.start_key.cmp(&b.start_key));
+        Ok(regions.into_iter().map(|region| region.id).collect())
+    }
+}
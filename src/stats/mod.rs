@@ -0,0 +1,578 @@
+// Copyright 2018 TiKV Project Authors. Licensed under Apache-2.0.
+
+#[cfg(feature = "prometheus")]
+mod prometheus;
+
+#[cfg(feature = "prometheus")]
+pub use self::prometheus::PrometheusSink;
+
+use crate::{region::StoreId, trace::current_trace, Result, RpcTrace};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    sync::OnceLock,
+    time::{Duration, Instant},
+};
+use tikv_client_proto::errorpb;
+
+/// A metric recorded by the client, see [`MetricsSink`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Metric {
+    TikvRequestTotal,
+    TikvRequestDurationSeconds,
+    TikvFailedRequestTotal,
+    TikvFailedRequestDurationSeconds,
+    TikvLabeledRequestTotal,
+    TikvLabeledRequestDurationSeconds,
+    TikvLabeledFailedRequestTotal,
+    TikvStoreRequestDurationSeconds,
+    TikvRegionErrorTotal,
+    TikvRetryTotal,
+    TikvRegionCacheTotal,
+    TikvLockResolveTotal,
+    TikvBackoffDurationSeconds,
+    TikvTxnBufferFilterTotal,
+    TikvResultBytes,
+    TikvTxnWaitDurationSeconds,
+    PdRequestTotal,
+    PdRequestDurationSeconds,
+    PdFailedRequestTotal,
+    PdFailedRequestDurationSeconds,
+    PdTsoQueueDepth,
+}
+
+impl Metric {
+    /// The Prometheus name of the metric, e.g. `tikv_request_duration_seconds`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Metric::TikvRequestTotal => "tikv_request_total",
+            Metric::TikvRequestDurationSeconds => "tikv_request_duration_seconds",
+            Metric::TikvFailedRequestTotal => "tikv_failed_request_total",
+            Metric::TikvFailedRequestDurationSeconds => "tikv_failed_request_duration_seconds",
+            Metric::TikvLabeledRequestTotal => "tikv_labeled_request_total",
+            Metric::TikvLabeledRequestDurationSeconds => "tikv_labeled_request_duration_seconds",
+            Metric::TikvLabeledFailedRequestTotal => "tikv_labeled_failed_request_total",
+            Metric::TikvStoreRequestDurationSeconds => "tikv_store_request_duration_seconds",
+            Metric::TikvRegionErrorTotal => "tikv_region_error_total",
+            Metric::TikvRetryTotal => "tikv_retry_total",
+            Metric::TikvRegionCacheTotal => "tikv_region_cache_total",
+            Metric::TikvLockResolveTotal => "tikv_lock_resolve_total",
+            Metric::TikvBackoffDurationSeconds => "tikv_backoff_duration_seconds",
+            Metric::TikvTxnBufferFilterTotal => "tikv_txn_buffer_filter_total",
+            Metric::TikvResultBytes => "tikv_result_bytes",
+            Metric::TikvTxnWaitDurationSeconds => "tikv_txn_wait_duration_seconds",
+            Metric::PdRequestTotal => "pd_request_total",
+            Metric::PdRequestDurationSeconds => "pd_request_duration_seconds",
+            Metric::PdFailedRequestTotal => "pd_failed_request_total",
+            Metric::PdFailedRequestDurationSeconds => "pd_failed_request_duration_seconds",
+            Metric::PdTsoQueueDepth => "pd_tso_queue_depth",
+        }
+    }
+
+    /// The names of the labels of the metric, in the order of the label values of its records.
+    pub fn label_names(self) -> &'static [&'static str] {
+        match self {
+            Metric::TikvLabeledRequestTotal
+            | Metric::TikvLabeledRequestDurationSeconds
+            | Metric::TikvLabeledFailedRequestTotal => &["type", "labels"],
+            Metric::TikvStoreRequestDurationSeconds => &["type", "store"],
+            Metric::TikvTxnWaitDurationSeconds => &["op", "phase"],
+            Metric::PdTsoQueueDepth => &[],
+            _ => &["type"],
+        }
+    }
+}
+
+/// A receiver of the metrics of the client, e.g. to export them to statsd or as OTLP metrics,
+/// see [`set_metrics_sink`].
+///
+/// Each record carries the values of the labels of its [`Metric`], in the order of
+/// [`Metric::label_names`]. Durations are recorded in seconds. The callbacks are called on the hot
+/// path of requests, so they should only buffer or aggregate the records.
+pub trait MetricsSink: Send + Sync + 'static {
+    /// Add `value` to the counter `metric`.
+    fn counter(&self, metric: Metric, labels: &[&str], value: u64);
+
+    /// Record `value` in the histogram `metric`.
+    fn histogram(&self, metric: Metric, labels: &[&str], value: f64);
+
+    /// Add `delta`, which may be negative, to the gauge `metric`.
+    fn gauge(&self, metric: Metric, labels: &[&str], delta: i64);
+}
+
+/// Record the metrics of all clients in `sink` instead of the default sink, which is the
+/// [`PrometheusSink`] if the `prometheus` feature is enabled, and none otherwise.
+///
+/// The sink can only be set once, before the first metric is recorded, e.g. before any client is
+/// created. Returns false, dropping `sink`, if that is too late.
+///
+/// # Examples
+///
+/// ```rust
+/// # use tikv_client::{set_metrics_sink, Metric, MetricsSink};
+/// struct LogSink;
+///
+/// impl MetricsSink for LogSink {
+///     fn counter(&self, metric: Metric, labels: &[&str], value: u64) {
+///         println!("{} {:?} +{}", metric.name(), labels, value);
+///     }
+///
+///     fn histogram(&self, metric: Metric, labels: &[&str], value: f64) {
+///         println!("{} {:?} {}", metric.name(), labels, value);
+///     }
+///
+///     fn gauge(&self, metric: Metric, labels: &[&str], delta: i64) {
+///         println!("{} {:?} {:+}", metric.name(), labels, delta);
+///     }
+/// }
+///
+/// assert!(set_metrics_sink(LogSink));
+/// ```
+pub fn set_metrics_sink(sink: impl MetricsSink) -> bool {
+    SINK.set(Some(Box::new(sink))).is_ok()
+}
+
+/// The sink metrics are recorded in, if any.
+fn sink() -> Option<&'static dyn MetricsSink> {
+    SINK.get_or_init(default_sink).as_deref()
+}
+
+fn counter(metric: Metric, labels: &[&str], value: u64) {
+    if let Some(sink) = sink() {
+        sink.counter(metric, labels, value);
+    }
+}
+
+fn histogram(metric: Metric, labels: &[&str], value: f64) {
+    if let Some(sink) = sink() {
+        sink.histogram(metric, labels, value);
+    }
+}
+
+/// User-defined labels which are attached to the metrics of TiKV requests, e.g. the tenant a
+/// transaction works for.
+///
+/// Requests with labels are additionally recorded in the `tikv_labeled_request_total`,
+/// `tikv_labeled_request_duration_seconds` and `tikv_labeled_failed_request_total` metrics, with
+/// all labels rendered into the `labels` label as `key1=value1,key2=value2`, sorted by key. Every
+/// distinct set of labels creates new time series, so labels should only take a small number of
+/// values.
+///
+/// # Examples
+///
+/// ```rust
+/// # use tikv_client::MetricsLabels;
+/// let labels = MetricsLabels::new().with("tenant", "acme").with("endpoint", "checkout");
+/// assert_eq!(labels.to_string(), "endpoint=checkout,tenant=acme");
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MetricsLabels {
+    labels: BTreeMap<String, String>,
+}
+
+impl MetricsLabels {
+    pub fn new() -> MetricsLabels {
+        MetricsLabels::default()
+    }
+
+    /// Add the label `key` with `value`, replacing any previous value of `key`.
+    pub fn with(mut self, key: impl Into<String>, value: impl Into<String>) -> MetricsLabels {
+        self.labels.insert(key.into(), value.into());
+        self
+    }
+
+    /// The value of the label `key`, if any.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.labels.get(key).map(String::as_str)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.labels.is_empty()
+    }
+
+    /// Merge `other` into these labels. Labels in `other` take precedence.
+    pub(crate) fn merge(&self, other: &MetricsLabels) -> MetricsLabels {
+        let mut labels = self.labels.clone();
+        labels.extend(other.labels.iter().map(|(k, v)| (k.clone(), v.clone())));
+        MetricsLabels { labels }
+    }
+}
+
+impl fmt::Display for MetricsLabels {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (key, value)) in self.labels.iter().enumerate() {
+            if i > 0 {
+                write!(f, ",")?;
+            }
+            write!(f, "{}={}", key, value)?;
+        }
+        Ok(())
+    }
+}
+
+/// The metrics of the requests to a service.
+pub struct RequestMetrics {
+    total: Metric,
+    duration: Metric,
+    failed_total: Metric,
+    failed_duration: Metric,
+}
+
+const TIKV_REQUEST_METRICS: RequestMetrics = RequestMetrics {
+    total: Metric::TikvRequestTotal,
+    duration: Metric::TikvRequestDurationSeconds,
+    failed_total: Metric::TikvFailedRequestTotal,
+    failed_duration: Metric::TikvFailedRequestDurationSeconds,
+};
+
+const PD_REQUEST_METRICS: RequestMetrics = RequestMetrics {
+    total: Metric::PdRequestTotal,
+    duration: Metric::PdRequestDurationSeconds,
+    failed_total: Metric::PdFailedRequestTotal,
+    failed_duration: Metric::PdFailedRequestDurationSeconds,
+};
+
+pub struct RequestStats {
+    start: Instant,
+    cmd: &'static str,
+    metrics: &'static RequestMetrics,
+    sink: Option<&'static dyn MetricsSink>,
+    /// Rendered user labels, see [`tikv_stats_with_labels`].
+    labels: Option<String>,
+    /// The store the request is sent to, if known, see [`with_store`](RequestStats::with_store).
+    store: Option<String>,
+    /// The trace the request is recorded in, and whether it is sent to `pd` or `tikv`.
+    trace: Option<(RpcTrace, &'static str)>,
+}
+
+impl RequestStats {
+    pub fn new(cmd: &'static str, metrics: &'static RequestMetrics) -> Self {
+        let sink = sink();
+        if let Some(sink) = sink {
+            sink.counter(metrics.total, &[cmd], 1);
+        }
+        RequestStats {
+            start: Instant::now(),
+            cmd,
+            metrics,
+            sink,
+            labels: None,
+            store: None,
+            trace: None,
+        }
+    }
+
+    /// Also record the duration of the request in `tikv_store_request_duration_seconds`,
+    /// labelled by the store it is sent to, if known.
+    pub fn with_store(mut self, store_id: Option<StoreId>) -> Self {
+        self.store = store_id.map(|id| id.to_string());
+        self
+    }
+
+    pub fn done<R>(&self, r: Result<R>) -> Result<R> {
+        if let Some(sink) = self.sink {
+            let cmd = [self.cmd];
+            let elapsed = duration_to_sec(self.start.elapsed());
+            if r.is_ok() {
+                sink.histogram(self.metrics.duration, &cmd, elapsed);
+            } else {
+                sink.histogram(self.metrics.failed_duration, &cmd, elapsed);
+                sink.counter(self.metrics.failed_total, &cmd, 1);
+            }
+            if let Some(store) = &self.store {
+                let labels = [self.cmd, store.as_str()];
+                sink.histogram(Metric::TikvStoreRequestDurationSeconds, &labels, elapsed);
+            }
+            if let Some(labels) = &self.labels {
+                let labels = [self.cmd, labels.as_str()];
+                sink.histogram(Metric::TikvLabeledRequestDurationSeconds, &labels, elapsed);
+                if r.is_err() {
+                    sink.counter(Metric::TikvLabeledFailedRequestTotal, &labels, 1);
+                }
+            }
+        }
+        if let Some((trace, category)) = &self.trace {
+            trace.record(
+                self.cmd,
+                category,
+                self.start,
+                r.is_ok(),
+                self.labels.clone(),
+            );
+        }
+        r
+    }
+}
+
+pub fn tikv_stats(cmd: &'static str) -> RequestStats {
+    let mut stats = RequestStats::new(cmd, &TIKV_REQUEST_METRICS);
+    stats.trace = current_trace().map(|trace| (trace, "tikv"));
+    stats
+}
+
+/// Like [`tikv_stats`], but also records the request in the labelled metrics unless `labels` is
+/// empty.
+pub fn tikv_stats_with_labels(cmd: &'static str, labels: &MetricsLabels) -> RequestStats {
+    let mut stats = tikv_stats(cmd);
+    if !labels.is_empty() {
+        let labels = labels.to_string();
+        if let Some(sink) = stats.sink {
+            sink.counter(Metric::TikvLabeledRequestTotal, &[cmd, labels.as_str()], 1);
+        }
+        stats.labels = Some(labels);
+    }
+    stats
+}
+
+pub fn pd_stats(cmd: &'static str) -> RequestStats {
+    let mut stats = RequestStats::new(cmd, &PD_REQUEST_METRICS);
+    stats.trace = current_trace().map(|trace| (trace, "pd"));
+    stats
+}
+
+/// Records a region error returned by TiKV, labelled by the kind of the error, also in the
+/// [`RpcTrace`] of the current task, if any.
+pub fn observe_region_error(e: &errorpb::Error) {
+    let kind = region_error_kind(e);
+    counter(Metric::TikvRegionErrorTotal, &[kind], 1);
+    if let Some(trace) = current_trace() {
+        trace.record_region_error(kind);
+    }
+}
+
+/// Records the time spent sleeping before a retry, also in the [`RpcTrace`] of the current task,
+/// if any. `kind` is the reason of the backoff, e.g., "region" or "lock".
+pub fn observe_backoff(kind: &'static str, duration: Duration) {
+    histogram(
+        Metric::TikvBackoffDurationSeconds,
+        &[kind],
+        duration_to_sec(duration),
+    );
+    if let Some(trace) = current_trace() {
+        trace.record_backoff(kind, duration);
+    }
+}
+
+/// Records a retry of a request to TiKV, labelled by the class of the error which caused it:
+/// "transport", "lock", or the kind of a region error, e.g. "not_leader".
+pub fn observe_retry(class: &'static str) {
+    counter(Metric::TikvRetryTotal, &[class], 1);
+}
+
+/// Records a lookup in the region cache, which is a miss if the region had to be loaded from PD.
+pub fn observe_region_cache(hit: bool) {
+    let label = if hit { "hit" } else { "miss" };
+    counter(Metric::TikvRegionCacheTotal, &[label], 1);
+}
+
+/// Records a lock encountered by a request, labelled by what was decided about it, e.g.
+/// "resolve" or "wait".
+pub fn observe_lock(decision: &'static str) {
+    counter(Metric::TikvLockResolveTotal, &[decision], 1);
+}
+
+pub fn region_error_kind(e: &errorpb::Error) -> &'static str {
+    if e.has_not_leader() {
+        "not_leader"
+    } else if e.has_region_not_found() {
+        "region_not_found"
+    } else if e.has_key_not_in_region() {
+        "key_not_in_region"
+    } else if e.has_epoch_not_match() {
+        "epoch_not_match"
+    } else if e.has_server_is_busy() {
+        "server_is_busy"
+    } else if e.has_stale_command() {
+        "stale_command"
+    } else if e.has_store_not_match() {
+        "store_not_match"
+    } else if e.has_raft_entry_too_large() {
+        "raft_entry_too_large"
+    } else if e.has_max_timestamp_not_synced() {
+        "max_timestamp_not_synced"
+    } else {
+        "unknown"
+    }
+}
+
+/// Records the lookups in the key filter of a transaction buffer over its lifetime: `negatives`
+/// which the filter allowed to skip the buffer, and `positives` which it did not.
+pub fn observe_buffer_filter(negatives: u64, positives: u64) {
+    for (label, count) in [("negative", negatives), ("positive", positives)] {
+        if count > 0 {
+            counter(Metric::TikvTxnBufferFilterTotal, &[label], count);
+        }
+    }
+}
+
+/// Records the total size of the keys and values returned by a call of a client, e.g. a raw
+/// scan, labelled by the kind of the call.
+pub fn observe_result_bytes(cmd: &'static str, bytes: usize) {
+    histogram(Metric::TikvResultBytes, &[cmd], bytes as f64);
+}
+
+/// Records the time a transactional operation `op`, e.g. "begin" or "commit", spent waiting in
+/// `phase`: "tso" for timestamps from PD, or "kv" for requests to TiKV.
+pub fn observe_txn_wait(op: &'static str, phase: &'static str, duration: Duration) {
+    histogram(
+        Metric::TikvTxnWaitDurationSeconds,
+        &[op, phase],
+        duration_to_sec(duration),
+    );
+}
+
+/// Counts a request for a timestamp in the TSO queue depth until it is dropped.
+pub struct TsoQueueGuard(());
+
+/// Enters a request for a timestamp into the TSO queue depth, see [`TsoQueueGuard`].
+pub fn enter_tso_queue() -> TsoQueueGuard {
+    if let Some(sink) = sink() {
+        sink.gauge(Metric::PdTsoQueueDepth, &[], 1);
+    }
+    TsoQueueGuard(())
+}
+
+impl Drop for TsoQueueGuard {
+    fn drop(&mut self) {
+        if let Some(sink) = sink() {
+            sink.gauge(Metric::PdTsoQueueDepth, &[], -1);
+        }
+    }
+}
+
+static SINK: OnceLock<Option<Box<dyn MetricsSink>>> = OnceLock::new();
+
+#[cfg(all(feature = "prometheus", not(test)))]
+fn default_sink() -> Option<Box<dyn MetricsSink>> {
+    Some(Box::new(PrometheusSink))
+}
+
+#[cfg(all(not(feature = "prometheus"), not(test)))]
+fn default_sink() -> Option<Box<dyn MetricsSink>> {
+    None
+}
+
+/// The unit tests record the metrics of all tests in [`RecordingSink`](test::RecordingSink).
+#[cfg(test)]
+fn default_sink() -> Option<Box<dyn MetricsSink>> {
+    Some(Box::new(test::RecordingSink))
+}
+
+/// Convert Duration to seconds.
+#[inline]
+fn duration_to_sec(d: Duration) -> f64 {
+    let nanos = f64::from(d.subsec_nanos());
+    // In most cases, we can't have so large Duration, so here just panic if overflow now.
+    d.as_secs() as f64 + (nanos / 1_000_000_000.0)
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use std::cell::RefCell;
+
+    #[test]
+    fn test_region_error_kind() {
+        let mut e = errorpb::Error::default();
+        assert_eq!(region_error_kind(&e), "unknown");
+        e.set_epoch_not_match(errorpb::EpochNotMatch::default());
+        assert_eq!(region_error_kind(&e), "epoch_not_match");
+        e.set_not_leader(errorpb::NotLeader::default());
+        assert_eq!(region_error_kind(&e), "not_leader");
+
+        let mut e = errorpb::Error::default();
+        e.set_server_is_busy(errorpb::ServerIsBusy::default());
+        assert_eq!(region_error_kind(&e), "server_is_busy");
+    }
+
+    #[test]
+    fn test_metrics_labels() {
+        assert_eq!(MetricsLabels::new().to_string(), "");
+        let client = MetricsLabels::new().with("tenant", "a").with("app", "x");
+        assert_eq!(client.to_string(), "app=x,tenant=a");
+        let txn = MetricsLabels::new()
+            .with("tenant", "b")
+            .with("endpoint", "e");
+        let merged = client.merge(&txn);
+        assert_eq!(merged.to_string(), "app=x,endpoint=e,tenant=b");
+        assert_eq!(merged.get("tenant"), Some("b"));
+        assert_eq!(client.merge(&MetricsLabels::new()), client);
+    }
+
+    /// Records the metrics of each test in the thread it runs in.
+    pub(super) struct RecordingSink;
+
+    thread_local! {
+        static RECORDS: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+    }
+
+    /// The metrics recorded so far by the current thread, e.g. `histogram
+    /// tikv_txn_wait_duration_seconds ["commit", "kv"] 0.001`.
+    pub(crate) fn recorded_metrics() -> Vec<String> {
+        RECORDS.with(|records| records.borrow().clone())
+    }
+
+    /// The metrics recorded so far by the current thread with the name `metric`.
+    pub(crate) fn recorded(metric: Metric) -> Vec<String> {
+        let prefix = format!(" {} ", metric.name());
+        recorded_metrics()
+            .into_iter()
+            .filter(|record| record.contains(&prefix))
+            .collect()
+    }
+
+    impl RecordingSink {
+        fn record(kind: &str, metric: Metric, labels: &[&str], value: impl fmt::Display) {
+            assert_eq!(labels.len(), metric.label_names().len(), "{:?}", metric);
+            let record = format!("{} {} {:?} {}", kind, metric.name(), labels, value);
+            RECORDS.with(|records| records.borrow_mut().push(record));
+        }
+    }
+
+    impl MetricsSink for RecordingSink {
+        fn counter(&self, metric: Metric, labels: &[&str], value: u64) {
+            RecordingSink::record("counter", metric, labels, value);
+        }
+
+        fn histogram(&self, metric: Metric, labels: &[&str], value: f64) {
+            RecordingSink::record("histogram", metric, labels, value);
+        }
+
+        fn gauge(&self, metric: Metric, labels: &[&str], delta: i64) {
+            RecordingSink::record("gauge", metric, labels, delta);
+        }
+    }
+
+    #[test]
+    fn test_metrics_sink() {
+        // the sink can't be replaced once it is used
+        assert!(sink().is_some());
+        assert!(!set_metrics_sink(RecordingSink));
+        let labels = MetricsLabels::new().with("app", "x");
+        let stats = tikv_stats_with_labels("sink_test", &labels);
+        stats.done(Ok(())).unwrap();
+        observe_backoff("sink_test", Duration::from_millis(1500));
+        observe_retry("sink_test");
+        let stats = tikv_stats("sink_test").with_store(Some(4));
+        stats.done(Ok(())).unwrap();
+        drop(enter_tso_queue());
+
+        let records = recorded_metrics();
+        for expected in &[
+            r#"counter tikv_request_total ["sink_test"] 1"#,
+            r#"counter tikv_labeled_request_total ["sink_test", "app=x"] 1"#,
+            r#"histogram tikv_backoff_duration_seconds ["sink_test"] 1.5"#,
+            r#"counter tikv_retry_total ["sink_test"] 1"#,
+            "gauge pd_tso_queue_depth [] 1",
+            "gauge pd_tso_queue_depth [] -1",
+        ] {
+            assert!(records.contains(&expected.to_string()), "{}", expected);
+        }
+        assert!(records
+            .iter()
+            .any(|record| record.starts_with("histogram tikv_request_duration_seconds")));
+        assert!(records.iter().any(|record| record
+            .starts_with(r#"histogram tikv_store_request_duration_seconds ["sink_test", "4"]"#)));
+    }
+}
@@ -0,0 +1,188 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+use super::{Metric, MetricsSink};
+use ::prometheus::{
+    exponential_buckets, register_histogram_vec, register_int_counter_vec, register_int_gauge,
+    HistogramVec, IntCounterVec, IntGauge,
+};
+
+/// The default [`MetricsSink`], which records the metrics in the default Prometheus registry, to
+/// be collected with `prometheus::gather`. Only enabled by the `prometheus` feature.
+///
+/// The metrics are registered when they are first recorded.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PrometheusSink;
+
+impl MetricsSink for PrometheusSink {
+    fn counter(&self, metric: Metric, labels: &[&str], value: u64) {
+        let counter: &IntCounterVec = match metric {
+            Metric::TikvRequestTotal => &TIKV_REQUEST_COUNTER_VEC,
+            Metric::TikvFailedRequestTotal => &TIKV_FAILED_REQUEST_COUNTER_VEC,
+            Metric::TikvLabeledRequestTotal => &TIKV_LABELED_REQUEST_COUNTER_VEC,
+            Metric::TikvLabeledFailedRequestTotal => &TIKV_LABELED_FAILED_REQUEST_COUNTER_VEC,
+            Metric::TikvRegionErrorTotal => &TIKV_REGION_ERROR_COUNTER_VEC,
+            Metric::TikvRetryTotal => &TIKV_RETRY_COUNTER_VEC,
+            Metric::TikvRegionCacheTotal => &TIKV_REGION_CACHE_COUNTER_VEC,
+            Metric::TikvLockResolveTotal => &TIKV_LOCK_RESOLVE_COUNTER_VEC,
+            Metric::TikvTxnBufferFilterTotal => &TXN_BUFFER_FILTER_COUNTER_VEC,
+            Metric::PdRequestTotal => &PD_REQUEST_COUNTER_VEC,
+            Metric::PdFailedRequestTotal => &PD_FAILED_REQUEST_COUNTER_VEC,
+            _ => unreachable!("{} is not a counter", metric.name()),
+        };
+        counter.with_label_values(labels).inc_by(value);
+    }
+
+    fn histogram(&self, metric: Metric, labels: &[&str], value: f64) {
+        let histogram: &HistogramVec = match metric {
+            Metric::TikvRequestDurationSeconds => &TIKV_REQUEST_DURATION_HISTOGRAM_VEC,
+            Metric::TikvFailedRequestDurationSeconds => &TIKV_FAILED_REQUEST_DURATION_HISTOGRAM_VEC,
+            Metric::TikvLabeledRequestDurationSeconds => {
+                &TIKV_LABELED_REQUEST_DURATION_HISTOGRAM_VEC
+            }
+            Metric::TikvStoreRequestDurationSeconds => &TIKV_STORE_REQUEST_DURATION_HISTOGRAM_VEC,
+            Metric::TikvBackoffDurationSeconds => &TIKV_BACKOFF_DURATION_HISTOGRAM_VEC,
+            Metric::TikvResultBytes => &TIKV_RESULT_BYTES_HISTOGRAM_VEC,
+            Metric::TikvTxnWaitDurationSeconds => &TXN_WAIT_DURATION_HISTOGRAM_VEC,
+            Metric::PdRequestDurationSeconds => &PD_REQUEST_DURATION_HISTOGRAM_VEC,
+            Metric::PdFailedRequestDurationSeconds => &PD_FAILED_REQUEST_DURATION_HISTOGRAM_VEC,
+            _ => unreachable!("{} is not a histogram", metric.name()),
+        };
+        histogram.with_label_values(labels).observe(value);
+    }
+
+    fn gauge(&self, metric: Metric, _labels: &[&str], delta: i64) {
+        match metric {
+            Metric::PdTsoQueueDepth => PD_TSO_QUEUE_DEPTH_GAUGE.add(delta),
+            _ => unreachable!("{} is not a gauge", metric.name()),
+        }
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref TIKV_REQUEST_DURATION_HISTOGRAM_VEC: HistogramVec = register_histogram_vec!(
+        "tikv_request_duration_seconds",
+        "Bucketed histogram of TiKV requests duration",
+        &["type"]
+    )
+    .unwrap();
+    static ref TIKV_REQUEST_COUNTER_VEC: IntCounterVec = register_int_counter_vec!(
+        "tikv_request_total",
+        "Total number of requests sent to TiKV",
+        &["type"]
+    )
+    .unwrap();
+    static ref TIKV_FAILED_REQUEST_DURATION_HISTOGRAM_VEC: HistogramVec = register_histogram_vec!(
+        "tikv_failed_request_duration_seconds",
+        "Bucketed histogram of failed TiKV requests duration",
+        &["type"]
+    )
+    .unwrap();
+    static ref TIKV_FAILED_REQUEST_COUNTER_VEC: IntCounterVec = register_int_counter_vec!(
+        "tikv_failed_request_total",
+        "Total number of failed requests sent to TiKV",
+        &["type"]
+    )
+    .unwrap();
+    static ref TIKV_LABELED_REQUEST_DURATION_HISTOGRAM_VEC: HistogramVec = register_histogram_vec!(
+        "tikv_labeled_request_duration_seconds",
+        "Bucketed histogram of TiKV requests duration by user-defined labels",
+        &["type", "labels"]
+    )
+    .unwrap();
+    static ref TIKV_LABELED_REQUEST_COUNTER_VEC: IntCounterVec = register_int_counter_vec!(
+        "tikv_labeled_request_total",
+        "Total number of requests sent to TiKV by user-defined labels",
+        &["type", "labels"]
+    )
+    .unwrap();
+    static ref TIKV_LABELED_FAILED_REQUEST_COUNTER_VEC: IntCounterVec = register_int_counter_vec!(
+        "tikv_labeled_failed_request_total",
+        "Total number of failed requests sent to TiKV by user-defined labels",
+        &["type", "labels"]
+    )
+    .unwrap();
+    static ref TIKV_REGION_ERROR_COUNTER_VEC: IntCounterVec = register_int_counter_vec!(
+        "tikv_region_error_total",
+        "Total number of region errors returned by TiKV",
+        &["type"]
+    )
+    .unwrap();
+    static ref TIKV_STORE_REQUEST_DURATION_HISTOGRAM_VEC: HistogramVec = register_histogram_vec!(
+        "tikv_store_request_duration_seconds",
+        "Bucketed histogram of TiKV requests duration by store",
+        &["type", "store"]
+    )
+    .unwrap();
+    static ref TIKV_RETRY_COUNTER_VEC: IntCounterVec = register_int_counter_vec!(
+        "tikv_retry_total",
+        "Total number of retried TiKV requests by the class of the error",
+        &["type"]
+    )
+    .unwrap();
+    static ref TIKV_REGION_CACHE_COUNTER_VEC: IntCounterVec = register_int_counter_vec!(
+        "tikv_region_cache_total",
+        "Total number of hits and misses of the region cache",
+        &["type"]
+    )
+    .unwrap();
+    static ref TIKV_LOCK_RESOLVE_COUNTER_VEC: IntCounterVec = register_int_counter_vec!(
+        "tikv_lock_resolve_total",
+        "Total number of locks encountered by TiKV requests by what was decided about them",
+        &["type"]
+    )
+    .unwrap();
+    static ref TIKV_BACKOFF_DURATION_HISTOGRAM_VEC: HistogramVec = register_histogram_vec!(
+        "tikv_backoff_duration_seconds",
+        "Bucketed histogram of time spent in backoff before retrying TiKV requests",
+        &["type"]
+    )
+    .unwrap();
+    static ref TXN_BUFFER_FILTER_COUNTER_VEC: IntCounterVec = register_int_counter_vec!(
+        "tikv_txn_buffer_filter_total",
+        "Total number of lookups in the key filter of transaction buffers",
+        &["type"]
+    )
+    .unwrap();
+    static ref PD_REQUEST_DURATION_HISTOGRAM_VEC: HistogramVec = register_histogram_vec!(
+        "pd_request_duration_seconds",
+        "Bucketed histogram of PD requests duration",
+        &["type"]
+    )
+    .unwrap();
+    static ref PD_REQUEST_COUNTER_VEC: IntCounterVec = register_int_counter_vec!(
+        "pd_request_total",
+        "Total number of requests sent to PD",
+        &["type"]
+    )
+    .unwrap();
+    static ref PD_FAILED_REQUEST_DURATION_HISTOGRAM_VEC: HistogramVec = register_histogram_vec!(
+        "pd_failed_request_duration_seconds",
+        "Bucketed histogram of failed PD requests duration",
+        &["type"]
+    )
+    .unwrap();
+    static ref PD_FAILED_REQUEST_COUNTER_VEC: IntCounterVec = register_int_counter_vec!(
+        "pd_failed_request_total",
+        "Total number of failed requests sent to PD",
+        &["type"]
+    )
+    .unwrap();
+    static ref TIKV_RESULT_BYTES_HISTOGRAM_VEC: HistogramVec = register_histogram_vec!(
+        "tikv_result_bytes",
+        "Bucketed histogram of the size of keys and values returned by client calls",
+        &["type"],
+        exponential_buckets(64.0, 4.0, 12).unwrap()
+    )
+    .unwrap();
+    static ref PD_TSO_QUEUE_DEPTH_GAUGE: IntGauge = register_int_gauge!(
+        "pd_tso_queue_depth",
+        "Number of requests for timestamps waiting for PD"
+    )
+    .unwrap();
+    static ref TXN_WAIT_DURATION_HISTOGRAM_VEC: HistogramVec = register_histogram_vec!(
+        "tikv_txn_wait_duration_seconds",
+        "Bucketed histogram of the time transactional operations wait for PD or TiKV",
+        &["op", "phase"]
+    )
+    .unwrap();
+}
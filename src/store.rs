@@ -1,31 +1,492 @@
 // Copyright 2019 TiKV Project Authors. Licensed under Apache-2.0.
 
-use crate::{pd::PdClient, region::RegionWithLeader, BoundRange, Key, Result};
+use crate::{
+    pd::PdClient,
+    region::{RegionWithLeader, StoreId},
+    request::is_transport_error,
+    BoundRange, Key, Result,
+};
+use async_trait::async_trait;
 use derive_new::new;
 use futures::{prelude::*, stream::BoxStream};
 use std::{
+    any::Any,
     cmp::{max, min},
-    sync::Arc,
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tikv_client_common::internal_err;
+use tikv_client_proto::{
+    cdcpb::{ChangeDataEvent, ChangeDataRequest},
+    kvrpcpb, metapb,
 };
-use tikv_client_proto::kvrpcpb;
-use tikv_client_store::{KvClient, KvConnect, TikvConnect};
+use tikv_client_store::{ImportRequest, KvClient, KvConnect, Request, TikvConnect};
+use tokio::sync::oneshot;
 
 #[derive(new, Clone)]
 pub struct RegionStore {
     pub region_with_leader: RegionWithLeader,
     pub client: Arc<dyn KvClient + Send + Sync>,
+    /// If set, requests are stale reads served by this peer instead of the leader.
+    #[new(default)]
+    pub replica: Option<metapb::Peer>,
+    /// If true, requests served by the `replica` are replica reads instead of stale reads: the
+    /// replica serves them once it has caught up with the leader, so they read the latest data.
+    #[new(default)]
+    pub replica_read: bool,
+    /// The API version of requests sent to the store.
+    #[new(value = "kvrpcpb::ApiVersion::V1")]
+    pub api_version: kvrpcpb::ApiVersion,
+    /// The address of the store requests are sent to, if known.
+    #[new(default)]
+    pub address: Option<String>,
+}
+
+impl RegionStore {
+    /// The context of requests sent to the store.
+    pub fn context(&self) -> Result<kvrpcpb::Context> {
+        let mut context = self.region_with_leader.context()?;
+        if let Some(peer) = &self.replica {
+            context.set_peer(peer.clone());
+            if self.replica_read {
+                context.replica_read = true;
+            } else {
+                context.stale_read = true;
+            }
+        }
+        context.set_api_version(self.api_version);
+        Ok(context)
+    }
+
+    /// The store requests are sent to, the store of the replica if set, else of the leader.
+    pub fn store_id(&self) -> Option<StoreId> {
+        match &self.replica {
+            Some(peer) => Some(peer.store_id),
+            None => self.region_with_leader.get_store_id().ok(),
+        }
+    }
 }
 
 pub trait KvConnectStore: KvConnect {
     fn connect_to_store(&self, region: RegionWithLeader, address: String) -> Result<RegionStore> {
         log::info!("connect to tikv endpoint: {:?}", &address);
         let client = self.connect(address.as_str())?;
-        Ok(RegionStore::new(region, Arc::new(client)))
+        let mut store = RegionStore::new(region, Arc::new(client));
+        store.address = Some(address);
+        Ok(store)
     }
 }
 
 impl KvConnectStore for TikvConnect {}
 
+tokio::task_local! {
+    /// The call of the client which the requests sent by the current task belong to.
+    static CALL: Option<u64>;
+}
+
+static NEXT_CALL: AtomicU64 = AtomicU64::new(0);
+
+/// Runs `future` as a call of the client, whose requests share the slots of the store queues
+/// fairly with other calls, see [`StoreQueue`]. Nested calls belong to the outer call.
+pub(crate) fn in_call<F: Future>(future: F) -> impl Future<Output = F::Output> {
+    CALL.scope(Some(current_call().unwrap_or_else(new_call)), future)
+}
+
+/// Let `future`, which is spawned as a new task, send its requests as part of the call of the
+/// current task, if any.
+pub(crate) fn in_current_call<F: Future>(future: F) -> impl Future<Output = F::Output> {
+    CALL.scope(current_call(), future)
+}
+
+fn current_call() -> Option<u64> {
+    CALL.try_with(|call| *call).ok().flatten()
+}
+
+fn new_call() -> u64 {
+    NEXT_CALL.fetch_add(1, Ordering::Relaxed)
+}
+
+/// The outbound queue of a single TiKV store.
+///
+/// All requests sent to a store wait in this queue before being dispatched. At most `capacity`
+/// requests are in flight at any time. The requests waiting for a slot are queued by the call of
+/// the client they belong to, and the calls are served in turn, one request at a time. Therefore
+/// a point get waits for at most one request of each call queued before it, however many regions
+/// a scan queued requests for.
+#[derive(Clone)]
+pub struct StoreQueue {
+    slots: Arc<Mutex<Slots>>,
+    capacity: usize,
+    /// The latency of the store, and when it was last sampled.
+    latency: Arc<Mutex<(StoreLatency, Option<Instant>)>>,
+    probation: Arc<Mutex<Probation>>,
+    counters: Arc<Mutex<QueueCounters>>,
+}
+
+/// The free slots of a [`StoreQueue`], and the requests waiting for one.
+struct Slots {
+    available: usize,
+    /// The requests waiting for a slot by their call, the calls in the order they are served. A
+    /// call which is served moves to the back while further requests of it are waiting.
+    waiting: VecDeque<(u64, VecDeque<oneshot::Sender<()>>)>,
+}
+
+/// A slot of a [`StoreQueue`], freed when dropped.
+pub(crate) struct Slot<'a>(&'a StoreQueue);
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        self.0.release();
+    }
+}
+
+/// A request waiting for a slot, which frees the slot if it is handed one after being cancelled.
+struct Waiter<'a> {
+    queue: &'a StoreQueue,
+    receiver: oneshot::Receiver<()>,
+    served: bool,
+}
+
+impl Drop for Waiter<'_> {
+    fn drop(&mut self) {
+        if !self.served {
+            self.receiver.close();
+            if self.receiver.try_recv().is_ok() {
+                self.queue.release();
+            }
+        }
+    }
+}
+
+/// The counters of the requests which went through a [`StoreQueue`].
+#[derive(Default)]
+struct QueueCounters {
+    requests: u64,
+    queued: usize,
+    peak_in_flight: usize,
+    queue_wait: Duration,
+    max_queue_wait: Duration,
+}
+
+/// Counts a request as queued until it is dropped, also if the request is cancelled.
+struct Queued<'a>(&'a Mutex<QueueCounters>);
+
+impl<'a> Queued<'a> {
+    fn new(counters: &'a Mutex<QueueCounters>) -> Queued<'a> {
+        counters.lock().unwrap().queued += 1;
+        Queued(counters)
+    }
+}
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        self.0.lock().unwrap().queued -= 1;
+    }
+}
+
+/// The statistics of the connection of the client to a TiKV store, see
+/// [`TransactionClient::connection_stats`](crate::TransactionClient::connection_stats).
+///
+/// The client multiplexes the requests to a store over a single gRPC connection, each request
+/// being an HTTP/2 stream, with at most [`max_streams`](ConnectionStats::max_streams) streams open
+/// at once. Further requests wait in the queue of the store until a stream is free. gRPC doesn't
+/// expose the time a stream is stalled by HTTP/2 flow control, so the time requests wait for a
+/// stream is reported as the stall time of the connection.
+///
+/// If requests often wait in the queue while the latency of the store stays low, the connection
+/// is the bottleneck and more streams per store help, see
+/// [`Config::with_store_concurrency`](crate::Config::with_store_concurrency). If requests rarely
+/// wait and streams are left unused, the client itself is the bottleneck and more client
+/// instances help. If the latency grows with the number of streams, the store is saturated.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ConnectionStats {
+    /// The address of the store.
+    pub address: String,
+    /// The maximum number of concurrent streams, the concurrency of the store.
+    pub max_streams: usize,
+    /// The number of streams open now.
+    pub in_flight: usize,
+    /// The largest number of streams open at once so far.
+    pub peak_in_flight: usize,
+    /// The number of requests waiting for a stream now.
+    pub queued: usize,
+    /// The number of requests sent to the store so far.
+    pub requests: u64,
+    /// The total time requests waited for a stream.
+    pub queue_wait: Duration,
+    /// The longest time a request waited for a stream.
+    pub max_queue_wait: Duration,
+    /// The moving average of the time requests take once sent, `None` until a request reached
+    /// the store.
+    pub average_latency: Option<Duration>,
+}
+
+impl ConnectionStats {
+    /// The average time requests waited for a stream.
+    pub fn average_queue_wait(&self) -> Duration {
+        if self.requests == 0 {
+            Duration::ZERO
+        } else {
+            self.queue_wait.div_f64(self.requests as f64)
+        }
+    }
+}
+
+/// The weight of the latest request in the average latency of a store.
+const LATENCY_EWMA_WEIGHT: f64 = 0.2;
+
+/// How long a store is on probation after a request did not reach it. The probation doubles with
+/// each further request in a row which did not reach the store, up to [`MAX_STORE_PROBATION`].
+const STORE_PROBATION: Duration = Duration::from_secs(1);
+const MAX_STORE_PROBATION: Duration = Duration::from_secs(60);
+
+/// The requests in a row which did not reach a store, and until when the store is on probation.
+#[derive(Default)]
+struct Probation {
+    failures: u32,
+    until: Option<Instant>,
+}
+
+impl Probation {
+    fn on_failure(&mut self, now: Instant) {
+        self.failures = self.failures.saturating_add(1);
+        let probation = STORE_PROBATION * 2u32.saturating_pow(self.failures - 1);
+        self.until = Some(now + probation.min(MAX_STORE_PROBATION));
+    }
+
+    fn on_success(&mut self) {
+        *self = Probation::default();
+    }
+
+    fn is_on(&self, now: Instant) -> bool {
+        matches!(self.until, Some(until) if now < until)
+    }
+}
+
+/// The latency of the requests to a store, see [`StoreQueue::latency`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct StoreLatency {
+    /// The exponentially weighted moving average of the time requests take once dispatched,
+    /// `None` until a request reached the store.
+    pub average: Option<Duration>,
+    /// Whether the store is off probation. A request which did not reach the store puts it on
+    /// probation, for longer with each such request in a row, and one which reached it ends the
+    /// probation. Replica reads avoid the stores on probation.
+    pub healthy: bool,
+}
+
+impl StoreQueue {
+    /// A queue with `capacity` slots, at least one, so that requests can be dispatched.
+    pub fn new(capacity: usize) -> StoreQueue {
+        let capacity = capacity.max(1);
+        let latency = StoreLatency {
+            average: None,
+            healthy: true,
+        };
+        StoreQueue {
+            slots: Arc::new(Mutex::new(Slots {
+                available: capacity,
+                waiting: VecDeque::new(),
+            })),
+            capacity,
+            latency: Arc::new(Mutex::new((latency, None))),
+            probation: Default::default(),
+            counters: Default::default(),
+        }
+    }
+
+    /// The number of requests which can be dispatched without waiting.
+    pub fn available(&self) -> usize {
+        self.slots.lock().unwrap().available
+    }
+
+    /// The latency of the requests dispatched through the queue so far.
+    pub fn latency(&self) -> StoreLatency {
+        let mut latency = self.latency.lock().unwrap().0;
+        latency.healthy = !self.probation.lock().unwrap().is_on(Instant::now());
+        latency
+    }
+
+    /// Whether the latency of the store was last sampled at least `interval` ago, or never, so
+    /// that a request should be sent to sample it again. Returns true at most once per interval,
+    /// so that only one request is sent to a store which may be slow.
+    pub fn should_sample(&self, interval: Duration) -> bool {
+        let mut latency = self.latency.lock().unwrap();
+        match latency.1 {
+            Some(sampled) if sampled.elapsed() < interval => false,
+            _ => {
+                latency.1 = Some(Instant::now());
+                true
+            }
+        }
+    }
+
+    /// The statistics of the requests dispatched through the queue to the store at `address`.
+    pub fn stats(&self, address: String) -> ConnectionStats {
+        let counters = self.counters.lock().unwrap();
+        ConnectionStats {
+            address,
+            max_streams: self.capacity,
+            in_flight: self.capacity - self.available(),
+            peak_in_flight: counters.peak_in_flight,
+            queued: counters.queued,
+            requests: counters.requests,
+            queue_wait: counters.queue_wait,
+            max_queue_wait: counters.max_queue_wait,
+            average_latency: self.latency().average,
+        }
+    }
+
+    /// Wait for a free slot, counting the request and the time it waited. The request waits
+    /// behind those of its call, see [`StoreQueue`].
+    async fn acquire(&self) -> Result<Slot<'_>> {
+        let start = Instant::now();
+        let receiver = {
+            let mut slots = self.slots.lock().unwrap();
+            if slots.available > 0 && slots.waiting.is_empty() {
+                slots.available -= 1;
+                None
+            } else {
+                // a request outside of any call is a call of its own
+                let call = current_call().unwrap_or_else(new_call);
+                let (sender, receiver) = oneshot::channel();
+                match slots
+                    .waiting
+                    .iter_mut()
+                    .find(|(waiting, _)| *waiting == call)
+                {
+                    Some((_, senders)) => senders.push_back(sender),
+                    None => slots
+                        .waiting
+                        .push_back((call, VecDeque::from(vec![sender]))),
+                }
+                Some(receiver)
+            }
+        };
+        if let Some(receiver) = receiver {
+            let _queued = Queued::new(&self.counters);
+            let mut waiter = Waiter {
+                queue: self,
+                receiver,
+                served: false,
+            };
+            (&mut waiter.receiver)
+                .await
+                .map_err(|_| internal_err!("the queue of the store is closed"))?;
+            waiter.served = true;
+        }
+        let slot = Slot(self);
+        let wait = start.elapsed();
+        let mut counters = self.counters.lock().unwrap();
+        counters.requests += 1;
+        counters.queue_wait += wait;
+        counters.max_queue_wait = counters.max_queue_wait.max(wait);
+        counters.peak_in_flight = counters
+            .peak_in_flight
+            .max(self.capacity - self.available());
+        Ok(slot)
+    }
+
+    /// Take `slots` free slots without dispatching requests, so that requests have to wait.
+    #[cfg(test)]
+    pub(crate) fn hold(&self, slots: usize) -> Vec<Slot<'_>> {
+        self.slots.lock().unwrap().available -= slots;
+        (0..slots).map(|_| Slot(self)).collect()
+    }
+
+    /// Hand a freed slot to the next waiting request of the call whose turn it is, or make it
+    /// available.
+    fn release(&self) {
+        let mut slots = self.slots.lock().unwrap();
+        while let Some((call, mut senders)) = slots.waiting.pop_front() {
+            let sender = senders.pop_front();
+            if !senders.is_empty() {
+                slots.waiting.push_back((call, senders));
+            }
+            // the request may have been cancelled
+            if let Some(Ok(())) = sender.map(|sender| sender.send(())) {
+                return;
+            }
+        }
+        slots.available += 1;
+    }
+
+    /// Record a request which took `elapsed` once dispatched, or which did not reach the store.
+    fn record(&self, elapsed: Duration, reached: bool) {
+        let mut probation = self.probation.lock().unwrap();
+        if reached {
+            probation.on_success();
+        } else {
+            probation.on_failure(Instant::now());
+        }
+        let mut latency = self.latency.lock().unwrap();
+        if reached {
+            latency.0.average = Some(match latency.0.average {
+                Some(average) => {
+                    average.mul_f64(1.0 - LATENCY_EWMA_WEIGHT)
+                        + elapsed.mul_f64(LATENCY_EWMA_WEIGHT)
+                }
+                None => elapsed,
+            });
+        }
+        latency.1 = Some(Instant::now());
+    }
+}
+
+/// A [`KvClient`] which dispatches requests through a [`StoreQueue`].
+#[derive(new, Clone)]
+pub struct QueuedKvClient<C> {
+    inner: C,
+    queue: StoreQueue,
+}
+
+#[async_trait]
+impl<C: KvClient + Send + Sync> KvClient for QueuedKvClient<C> {
+    async fn dispatch(&self, req: &dyn Request) -> Result<Box<dyn Any>> {
+        let _permit = self.queue.acquire().await?;
+        let start = Instant::now();
+        let result = self.inner.dispatch(req).await;
+        let reached = !matches!(&result, Err(e) if is_transport_error(e));
+        self.queue.record(start.elapsed(), reached);
+        result
+    }
+
+    async fn dispatch_with_timeout(
+        &self,
+        req: &dyn Request,
+        timeout: Duration,
+    ) -> Result<Box<dyn Any>> {
+        let _permit = self.queue.acquire().await?;
+        let start = Instant::now();
+        let result = self.inner.dispatch_with_timeout(req, timeout).await;
+        let reached = !matches!(&result, Err(e) if is_transport_error(e));
+        self.queue.record(start.elapsed(), reached);
+        result
+    }
+
+    async fn dispatch_import(
+        &self,
+        req: &dyn ImportRequest,
+        timeout: Duration,
+    ) -> Result<Box<dyn Any>> {
+        // imports are few and long, they would hold the slots of the queue for a long time
+        self.inner.dispatch_import(req, timeout).await
+    }
+
+    /// Event feeds last as long as the subscriptions they carry, they don't take a slot of the
+    /// queue.
+    fn event_feed(
+        &self,
+        requests: BoxStream<'static, ChangeDataRequest>,
+    ) -> Result<BoxStream<'static, Result<ChangeDataEvent>>> {
+        self.inner.event_feed(requests)
+    }
+}
+
 /// Maps keys to a stream of stores. `key_data` must be sorted in increasing order
 pub fn store_stream_for_keys<K, KOut, PdC>(
     key_data: impl Iterator<Item = K> + Send + Sync + 'static,
@@ -67,27 +528,8 @@ pub fn store_stream_for_range<PdC: PdClient>(
         .boxed()
 }
 
-pub fn store_stream_for_range_by_start_key<PdC: PdClient>(
-    start_key: Key,
-    pd_client: Arc<PdC>,
-) -> BoxStream<'static, Result<(Vec<u8>, RegionStore)>> {
-    let bnd_range = BoundRange::range_from(start_key.clone());
-    pd_client
-        .stores_for_range(bnd_range)
-        .map_ok(move |store| {
-            let region_range = store.region_with_leader.range();
-            (
-                range_intersection(region_range, (start_key.clone(), vec![].into()))
-                    .0
-                    .into(),
-                store,
-            )
-        })
-        .boxed()
-}
-
 /// The range used for request should be the intersection of `region_range` and `range`.
-fn range_intersection(region_range: (Key, Key), range: (Key, Key)) -> (Key, Key) {
+pub(crate) fn range_intersection(region_range: (Key, Key), range: (Key, Key)) -> (Key, Key) {
     let (lower, upper) = region_range;
     let up = if upper.is_empty() {
         range.1
@@ -114,3 +556,249 @@ pub fn store_stream_for_ranges<PdC: PdClient>(
         })
         .boxed()
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mock::{MockKvClient, MockPdClient};
+
+    #[test]
+    fn test_replica_context() {
+        let region = MockPdClient::region1();
+        let mut store = RegionStore::new(region.clone(), Arc::new(MockKvClient::default()));
+        let context = store.context().unwrap();
+        assert_eq!(context.get_peer(), region.leader.as_ref().unwrap());
+        assert!(!context.stale_read);
+
+        let replica = metapb::Peer {
+            id: 2,
+            store_id: 42,
+            ..Default::default()
+        };
+        store.replica = Some(replica.clone());
+        let context = store.context().unwrap();
+        assert_eq!(context.get_peer(), &replica);
+        assert!(context.stale_read);
+        assert_eq!(context.region_id, region.id());
+        assert_eq!(context.get_api_version(), kvrpcpb::ApiVersion::V1);
+
+        store.api_version = kvrpcpb::ApiVersion::V2;
+        let context = store.context().unwrap();
+        assert_eq!(context.get_api_version(), kvrpcpb::ApiVersion::V2);
+
+        store.replica_read = true;
+        let context = store.context().unwrap();
+        assert_eq!(context.get_peer(), &replica);
+        assert!(context.replica_read);
+        assert!(!context.stale_read);
+    }
+
+    #[tokio::test]
+    async fn test_store_queue_wait() {
+        let queue = StoreQueue::new(1);
+        let client = QueuedKvClient::new(
+            MockKvClient::with_dispatch_hook(|_| {
+                Ok(Box::new(kvrpcpb::GetResponse::default()) as Box<dyn Any>)
+            }),
+            queue.clone(),
+        );
+
+        // hold the only slot, so that the request has to wait in the queue
+        let slots = queue.hold(1);
+        let request = kvrpcpb::GetRequest::default();
+        let mut fut = client.dispatch(&request);
+        assert!(futures::poll!(&mut fut).is_pending());
+        drop(slots);
+
+        assert!(fut.await.is_ok());
+        assert_eq!(queue.available(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_store_queue_fairness() {
+        let dispatched = Arc::new(Mutex::new(Vec::new()));
+        let hook_dispatched = dispatched.clone();
+        let queue = StoreQueue::new(1);
+        let client = QueuedKvClient::new(
+            MockKvClient::with_dispatch_hook(move |req: &dyn Any| {
+                let req: &kvrpcpb::GetRequest = req.downcast_ref().unwrap();
+                hook_dispatched.lock().unwrap().push(req.key.clone());
+                Ok(Box::new(kvrpcpb::GetResponse::default()) as Box<dyn Any>)
+            }),
+            queue.clone(),
+        );
+
+        // a call queues three requests, then another call queues one
+        let slots = queue.hold(1);
+        let scan = kvrpcpb::GetRequest {
+            key: b"scan".to_vec(),
+            ..Default::default()
+        };
+        let mut scans: Vec<_> = (0..3)
+            .map(|_| Box::pin(CALL.scope(Some(1), client.dispatch(&scan))))
+            .collect();
+        for fut in &mut scans {
+            assert!(futures::poll!(fut).is_pending());
+        }
+        let get = kvrpcpb::GetRequest {
+            key: b"get".to_vec(),
+            ..Default::default()
+        };
+        let mut get = Box::pin(CALL.scope(Some(2), client.dispatch(&get)));
+        assert!(futures::poll!(&mut get).is_pending());
+        assert_eq!(queue.stats(String::new()).queued, 4);
+        drop(slots);
+
+        // the calls take turns, the get doesn't wait for the remaining requests of the first call
+        let (scanned, got) = future::join(future::join_all(scans), get).await;
+        assert!(scanned.iter().all(Result::is_ok) && got.is_ok());
+        assert_eq!(
+            *dispatched.lock().unwrap(),
+            [&b"scan"[..], b"get", b"scan", b"scan"]
+        );
+        assert_eq!(queue.available(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_store_queue_cancelled_waiter() {
+        let queue = StoreQueue::new(1);
+        let client = QueuedKvClient::new(
+            MockKvClient::with_dispatch_hook(|_| {
+                Ok(Box::new(kvrpcpb::GetResponse::default()) as Box<dyn Any>)
+            }),
+            queue.clone(),
+        );
+        let request = kvrpcpb::GetRequest::default();
+
+        // the slot is handed to a waiter which is cancelled before it runs
+        let slots = queue.hold(1);
+        let mut fut = Box::pin(client.dispatch(&request));
+        assert!(futures::poll!(&mut fut).is_pending());
+        drop(slots);
+        drop(fut);
+        assert_eq!(queue.available(), 1);
+
+        // a cancelled waiter is skipped
+        let slots = queue.hold(1);
+        let mut cancelled = Box::pin(client.dispatch(&request));
+        assert!(futures::poll!(&mut cancelled).is_pending());
+        let mut fut = Box::pin(client.dispatch(&request));
+        assert!(futures::poll!(&mut fut).is_pending());
+        drop(cancelled);
+        drop(slots);
+        assert!(fut.await.is_ok());
+        assert_eq!(queue.available(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_store_queue_min_capacity() {
+        // a queue without slots would never dispatch requests
+        let queue = StoreQueue::new(0);
+        let client = QueuedKvClient::new(
+            MockKvClient::with_dispatch_hook(|_| {
+                Ok(Box::new(kvrpcpb::GetResponse::default()) as Box<dyn Any>)
+            }),
+            queue.clone(),
+        );
+        assert_eq!(queue.available(), 1);
+        let request = kvrpcpb::GetRequest::default();
+        assert!(client.dispatch(&request).await.is_ok());
+    }
+
+    #[test]
+    fn test_store_latency() {
+        let queue = StoreQueue::new(1);
+        assert_eq!(queue.latency().average, None);
+        assert!(queue.should_sample(Duration::from_secs(10)));
+        // sampled at most once per interval
+        assert!(!queue.should_sample(Duration::from_secs(10)));
+        assert!(queue.should_sample(Duration::from_secs(0)));
+
+        queue.record(Duration::from_millis(100), true);
+        assert_eq!(queue.latency().average, Some(Duration::from_millis(100)));
+        queue.record(Duration::from_millis(200), true);
+        assert_eq!(queue.latency().average, Some(Duration::from_millis(120)));
+        assert!(queue.latency().healthy);
+        assert!(!queue.should_sample(Duration::from_secs(10)));
+
+        // a request which did not reach the store doesn't change the average
+        queue.record(Duration::from_secs(2), false);
+        assert_eq!(
+            queue.latency(),
+            StoreLatency {
+                average: Some(Duration::from_millis(120)),
+                healthy: false,
+            }
+        );
+    }
+
+    #[test]
+    fn test_store_probation() {
+        let now = Instant::now();
+        let mut probation = Probation::default();
+        assert!(!probation.is_on(now));
+
+        // the probation doubles with each failure in a row, up to the maximum
+        probation.on_failure(now);
+        assert!(probation.is_on(now + Duration::from_millis(999)));
+        assert!(!probation.is_on(now + Duration::from_secs(1)));
+        probation.on_failure(now);
+        assert!(probation.is_on(now + Duration::from_millis(1999)));
+        assert!(!probation.is_on(now + Duration::from_secs(2)));
+        for _ in 0..40 {
+            probation.on_failure(now);
+        }
+        assert!(probation.is_on(now + Duration::from_secs(59)));
+        assert!(!probation.is_on(now + MAX_STORE_PROBATION));
+
+        // a success ends the probation, the next failure starts over
+        probation.on_success();
+        assert!(!probation.is_on(now));
+        probation.on_failure(now);
+        assert!(!probation.is_on(now + Duration::from_secs(1)));
+    }
+
+    #[tokio::test]
+    async fn test_connection_stats() {
+        let queue = StoreQueue::new(2);
+        let client = QueuedKvClient::new(
+            MockKvClient::with_dispatch_hook(|_| {
+                Ok(Box::new(kvrpcpb::GetResponse::default()) as Box<dyn Any>)
+            }),
+            queue.clone(),
+        );
+        let stats = queue.stats("store1".to_owned());
+        assert_eq!(stats.max_streams, 2);
+        assert_eq!(stats.requests, 0);
+        assert_eq!(stats.average_queue_wait(), Duration::ZERO);
+
+        // hold both slots, so that the request has to wait for a stream
+        let permits = queue.hold(2);
+        let request = kvrpcpb::GetRequest::default();
+        let mut fut = client.dispatch(&request);
+        assert!(futures::poll!(&mut fut).is_pending());
+        let stats = queue.stats("store1".to_owned());
+        assert_eq!(stats.in_flight, 2);
+        assert_eq!(stats.queued, 1);
+        assert_eq!(stats.requests, 0);
+        drop(permits);
+        assert!(fut.await.is_ok());
+
+        // a cancelled request leaves the queue
+        let permits = queue.hold(2);
+        let mut fut = client.dispatch(&request);
+        assert!(futures::poll!(&mut fut).is_pending());
+        drop(fut);
+        drop(permits);
+
+        let stats = queue.stats("store1".to_owned());
+        assert_eq!(stats.address, "store1");
+        assert_eq!(stats.in_flight, 0);
+        assert_eq!(stats.peak_in_flight, 1);
+        assert_eq!(stats.queued, 0);
+        assert_eq!(stats.requests, 1);
+        assert!(stats.max_queue_wait <= stats.queue_wait);
+        assert_eq!(stats.average_queue_wait(), stats.queue_wait);
+        assert!(stats.average_latency.is_some());
+    }
+}
@@ -0,0 +1,429 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! Bootstrapping of TiKV clusters for integration tests, behind the `test-cluster` feature.
+//!
+//! A [`TestCluster`] either attaches to a running cluster or starts one with
+//! [tiup playground](https://docs.pingcap.com/tidb/stable/tiup-playground) or docker compose, and
+//! waits until it serves requests. Each test then gets its own [`TestNamespace`], whose keys are
+//! isolated from the keys of the other tests, and which removes its keys when cleaned up.
+//!
+//! ```rust,no_run
+//! # use tikv_client::{test_cluster::{TestCluster, TestClusterOptions}, Result};
+//! # use futures::prelude::*;
+//! # fn main() -> Result<()> {
+//! # futures::executor::block_on(async {
+//! let cluster = TestCluster::from_env(TestClusterOptions::new()).await?;
+//! let namespace = cluster.namespace("test_put");
+//! let client = namespace.transaction_client();
+//! let mut txn = client.begin_optimistic().await?;
+//! txn.put("key".to_owned(), "value".to_owned()).await?;
+//! txn.commit().await?;
+//! namespace.cleanup().await?;
+//! # Ok(())
+//! # })}
+//! ```
+
+use crate::{Cluster, ColumnFamily, Config, Error, RawClient, Result, TransactionClient};
+use futures_timer::Delay;
+use slog::{Drain, Logger};
+use std::{
+    env, io,
+    path::{Path, PathBuf},
+    process::{Child, Command},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+/// The environment variable with the comma separated addresses of the PD of a running cluster,
+/// which [`TestCluster::from_env`] attaches to.
+pub const ENV_PD_ADDRS: &str = "PD_ADDRS";
+/// The address of the PD of a tiup playground.
+const PLAYGROUND_PD_ADDR: &str = "127.0.0.1:2379";
+const DEFAULT_READY_TIMEOUT: Duration = Duration::from_secs(120);
+const READY_POLL_INTERVAL: Duration = Duration::from_millis(500);
+/// The namespace of the key written to check that a cluster is ready.
+const PROBE_NAMESPACE: &str = "__test_cluster_probe";
+
+/// Makes the names of the namespaces of a process unique.
+static NAMESPACE_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Options of a [`TestCluster`].
+#[derive(Clone, Debug)]
+pub struct TestClusterOptions {
+    pub(crate) config: Config,
+    pub(crate) ready_timeout: Duration,
+    pub(crate) tiup_version: Option<String>,
+    pub(crate) tikv_count: usize,
+    pub(crate) logger: Option<Logger>,
+}
+
+impl Default for TestClusterOptions {
+    fn default() -> TestClusterOptions {
+        TestClusterOptions {
+            config: Config::default(),
+            ready_timeout: DEFAULT_READY_TIMEOUT,
+            tiup_version: None,
+            tikv_count: 1,
+            logger: None,
+        }
+    }
+}
+
+impl TestClusterOptions {
+    pub fn new() -> TestClusterOptions {
+        TestClusterOptions::default()
+    }
+
+    /// The configuration of the clients of the cluster.
+    pub fn config(mut self, config: Config) -> TestClusterOptions {
+        self.config = config;
+        self
+    }
+
+    /// How long to wait for the cluster to serve requests, 120 seconds by default.
+    pub fn ready_timeout(mut self, timeout: Duration) -> TestClusterOptions {
+        self.ready_timeout = timeout;
+        self
+    }
+
+    /// The version of the cluster started by tiup, e.g. `v5.2.0`, the latest by default.
+    pub fn tiup_version(mut self, version: impl Into<String>) -> TestClusterOptions {
+        self.tiup_version = Some(version.into());
+        self
+    }
+
+    /// The number of TiKV stores of the cluster started by tiup, one by default.
+    pub fn tikv_count(mut self, count: usize) -> TestClusterOptions {
+        self.tikv_count = count;
+        self
+    }
+
+    /// The logger of the clients of the cluster and of the cluster itself, which logs to stdout
+    /// at the info level by default.
+    pub fn logger(mut self, logger: Logger) -> TestClusterOptions {
+        self.logger = Some(logger);
+        self
+    }
+
+    fn logger_or_default(&self) -> Logger {
+        self.logger.clone().unwrap_or_else(|| {
+            let plain = slog_term::PlainSyncDecorator::new(std::io::stdout());
+            Logger::root(
+                slog_term::FullFormat::new(plain)
+                    .build()
+                    .filter_level(slog::Level::Info)
+                    .fuse(),
+                o!(),
+            )
+        })
+    }
+}
+
+/// The process running a cluster started by a [`TestCluster`], which is stopped with it.
+enum Launched {
+    Playground(Child),
+    DockerCompose(PathBuf),
+}
+
+/// A TiKV cluster for integration tests, which serves requests.
+///
+/// A cluster started by the `TestCluster` is stopped when it is dropped.
+pub struct TestCluster {
+    pd_endpoints: Vec<String>,
+    cluster: Cluster,
+    launched: Option<Launched>,
+    logger: Logger,
+}
+
+impl TestCluster {
+    /// Attach to the cluster whose PD is at `pd_endpoints` and wait until it is ready.
+    pub async fn attach<S: Into<String>>(
+        pd_endpoints: Vec<S>,
+        options: TestClusterOptions,
+    ) -> Result<TestCluster> {
+        let pd_endpoints = pd_endpoints.into_iter().map(Into::into).collect();
+        TestCluster::wait_ready(pd_endpoints, None, &options).await
+    }
+
+    /// Attach to the cluster at the addresses in `$PD_ADDRS` if it is set, otherwise start a
+    /// cluster with tiup playground.
+    pub async fn from_env(options: TestClusterOptions) -> Result<TestCluster> {
+        match env::var(ENV_PD_ADDRS) {
+            Ok(addrs) => TestCluster::attach(addrs.split(',').collect(), options).await,
+            Err(_) => TestCluster::start_playground(options).await,
+        }
+    }
+
+    /// Start a cluster with `tiup playground`, which must be installed, and wait until it is
+    /// ready. The PD of the playground listens on the default port, so only one playground can
+    /// run on a host.
+    pub async fn start_playground(options: TestClusterOptions) -> Result<TestCluster> {
+        let mut command = Command::new("tiup");
+        command.arg("playground");
+        if let Some(version) = &options.tiup_version {
+            command.arg(version);
+        }
+        command
+            .args(["--mode", "tikv-slim", "--without-monitor"])
+            .args(["--kv", &options.tikv_count.to_string()]);
+        let child = command
+            .spawn()
+            .map_err(|e| launch_err("tiup playground", e))?;
+        let launched = Launched::Playground(child);
+        let pd_endpoints = vec![PLAYGROUND_PD_ADDR.to_owned()];
+        TestCluster::wait_ready(pd_endpoints, Some(launched), &options).await
+    }
+
+    /// Start the cluster of the compose file `file` with `docker compose up`, and wait until it
+    /// is ready. `pd_endpoints` are the addresses the compose file publishes PD at.
+    ///
+    /// Blocks the thread until docker compose has started the containers.
+    pub async fn start_docker_compose<S: Into<String>>(
+        file: impl AsRef<Path>,
+        pd_endpoints: Vec<S>,
+        options: TestClusterOptions,
+    ) -> Result<TestCluster> {
+        let file = file.as_ref().to_owned();
+        let status = Command::new("docker")
+            .arg("compose")
+            .arg("-f")
+            .arg(&file)
+            .args(["up", "-d"])
+            .status()
+            .map_err(|e| launch_err("docker compose", e))?;
+        let launched = Launched::DockerCompose(file);
+        if !status.success() {
+            stop(launched, &options.logger_or_default());
+            return Err(Error::StringError(format!(
+                "docker compose up failed: {}",
+                status
+            )));
+        }
+        let pd_endpoints = pd_endpoints.into_iter().map(Into::into).collect();
+        TestCluster::wait_ready(pd_endpoints, Some(launched), &options).await
+    }
+
+    /// Connects to the cluster and writes a key until it succeeds, or stops the launched cluster
+    /// and fails once the timeout expires.
+    async fn wait_ready(
+        pd_endpoints: Vec<String>,
+        mut launched: Option<Launched>,
+        options: &TestClusterOptions,
+    ) -> Result<TestCluster> {
+        let logger = options.logger_or_default();
+        let start = Instant::now();
+        loop {
+            match probe(&pd_endpoints, &options.config, &logger).await {
+                Ok(cluster) => {
+                    return Ok(TestCluster {
+                        pd_endpoints,
+                        cluster,
+                        launched,
+                        logger,
+                    })
+                }
+                Err(e) if start.elapsed() > options.ready_timeout => {
+                    if let Some(launched) = launched.take() {
+                        stop(launched, &logger);
+                    }
+                    return Err(Error::StringError(format!(
+                        "cluster at {:?} is not ready after {:?}: {}",
+                        pd_endpoints, options.ready_timeout, e
+                    )));
+                }
+                Err(_) => Delay::new(READY_POLL_INTERVAL).await,
+            }
+        }
+    }
+
+    /// The addresses of the PD of the cluster.
+    pub fn pd_endpoints(&self) -> &[String] {
+        &self.pd_endpoints
+    }
+
+    /// The connections to the cluster, shared by the clients of all namespaces.
+    pub fn cluster(&self) -> &Cluster {
+        &self.cluster
+    }
+
+    /// A new namespace for a test, see [`TestNamespace`].
+    ///
+    /// The name of the namespace is `name` followed by the id of the process and a counter, so
+    /// tests running concurrently, in one process or in several, never share keys.
+    pub fn namespace(&self, name: &str) -> TestNamespace {
+        let name = unique_name(name);
+        TestNamespace {
+            raw_client: RawClient::new_with_cluster(&self.cluster).namespace(&name),
+            transaction_client: TransactionClient::new_with_cluster(&self.cluster).namespace(&name),
+            name,
+        }
+    }
+}
+
+impl Drop for TestCluster {
+    fn drop(&mut self) {
+        if let Some(launched) = self.launched.take() {
+            stop(launched, &self.logger);
+        }
+    }
+}
+
+/// The keys of one test in a [`TestCluster`].
+///
+/// The clients of the namespace prefix their keys with its name, see
+/// [`RawClient::namespace`](crate::RawClient::namespace) and
+/// [`TransactionClient::namespace`](crate::TransactionClient::namespace), so scans and range
+/// deletions never reach the keys of other tests.
+pub struct TestNamespace {
+    name: String,
+    raw_client: RawClient,
+    transaction_client: TransactionClient,
+}
+
+impl TestNamespace {
+    /// The unique name of the namespace.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// A raw client whose keys are in the namespace.
+    pub fn raw_client(&self) -> &RawClient {
+        &self.raw_client
+    }
+
+    /// A transactional client whose keys are in the namespace.
+    pub fn transaction_client(&self) -> &TransactionClient {
+        &self.transaction_client
+    }
+
+    /// Delete all raw and transactional keys of the namespace.
+    ///
+    /// This can't be done when the namespace is dropped, so tests must call it themselves; the
+    /// keys of a test which didn't are left behind, but no other test reads them.
+    pub async fn cleanup(self) -> Result<()> {
+        for cf in [
+            ColumnFamily::Default,
+            ColumnFamily::Lock,
+            ColumnFamily::Write,
+        ] {
+            self.raw_client.with_cf(cf).delete_range(..).await?;
+        }
+        self.transaction_client.unsafe_destroy_range(..).await
+    }
+}
+
+/// Connects to the cluster and writes and deletes a raw key.
+async fn probe(pd_endpoints: &[String], config: &Config, logger: &Logger) -> Result<Cluster> {
+    let cluster =
+        Cluster::connect(pd_endpoints.to_vec(), config.clone(), Some(logger.clone())).await?;
+    let client = RawClient::new_with_cluster(&cluster).namespace(PROBE_NAMESPACE);
+    client.put("probe".to_owned(), "probe".to_owned()).await?;
+    client.delete("probe".to_owned()).await?;
+    Ok(cluster)
+}
+
+/// Stops a launched cluster, logging failures since this runs on drop.
+fn stop(launched: Launched, logger: &Logger) {
+    let result = match launched {
+        // tiup tears the playground down when interrupted, but not when killed
+        Launched::Playground(mut child) => Command::new("kill")
+            .args(["-INT", &child.id().to_string()])
+            .status()
+            .and_then(|_| child.wait())
+            .map(|_| ()),
+        Launched::DockerCompose(file) => Command::new("docker")
+            .arg("compose")
+            .arg("-f")
+            .arg(&file)
+            .args(["down", "-v"])
+            .status()
+            .and_then(|status| {
+                if status.success() {
+                    Ok(())
+                } else {
+                    let message = format!("docker compose down failed: {}", status);
+                    Err(io::Error::other(message))
+                }
+            }),
+    };
+    if let Err(e) = result {
+        warn!(logger, "failed to stop the test cluster: {}", e);
+    }
+}
+
+/// `name` followed by the id of the process and a counter, see [`TestCluster::namespace`].
+fn unique_name(name: &str) -> String {
+    format!(
+        "{}-{}-{}",
+        name,
+        std::process::id(),
+        NAMESPACE_COUNTER.fetch_add(1, Ordering::SeqCst)
+    )
+}
+
+fn launch_err(what: &str, e: std::io::Error) -> Error {
+    Error::StringError(format!("failed to start {}: {}", what, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    /// Collects the messages of the records logged through it.
+    struct Messages(Arc<Mutex<Vec<String>>>);
+
+    impl Drain for Messages {
+        type Ok = ();
+        type Err = slog::Never;
+
+        fn log(
+            &self,
+            record: &slog::Record,
+            _: &slog::OwnedKVList,
+        ) -> std::result::Result<(), slog::Never> {
+            self.0.lock().unwrap().push(record.msg().to_string());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_options() {
+        let options = TestClusterOptions::new();
+        assert_eq!(options.ready_timeout, DEFAULT_READY_TIMEOUT);
+        assert_eq!(options.tiup_version, None);
+        assert_eq!(options.tikv_count, 1);
+        assert!(options.logger.is_none());
+
+        let options = options
+            .ready_timeout(Duration::from_secs(10))
+            .tiup_version("v5.2.0")
+            .tikv_count(3)
+            .logger(Logger::root(slog::Discard, o!()));
+        assert_eq!(options.ready_timeout, Duration::from_secs(10));
+        assert_eq!(options.tiup_version.as_deref(), Some("v5.2.0"));
+        assert_eq!(options.tikv_count, 3);
+        assert!(options.logger.is_some());
+    }
+
+    #[test]
+    fn test_unique_name() {
+        let first = unique_name("test_put");
+        let second = unique_name("test_put");
+        assert_ne!(first, second);
+        let prefix = format!("test_put-{}-", std::process::id());
+        assert!(first.starts_with(&prefix) && second.starts_with(&prefix));
+    }
+
+    #[test]
+    fn test_stop_logs_failures() {
+        // docker compose fails to stop the cluster of a missing file, or isn't installed
+        let messages = Arc::new(Mutex::new(Vec::new()));
+        let logger = Logger::root(Messages(messages.clone()), o!());
+        let file = PathBuf::from("/nonexistent/docker-compose.yml");
+        stop(Launched::DockerCompose(file), &logger);
+        let messages = messages.lock().unwrap();
+        assert_eq!(messages.len(), 1);
+        assert!(messages[0].starts_with("failed to stop the test cluster: "));
+    }
+}
@@ -0,0 +1,375 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! Timelines of the RPCs sent for single operations, see [`RpcTrace`].
+
+use futures::Future;
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+tokio::task_local! {
+    /// The trace the RPCs sent by the current task are recorded in, if any.
+    static CURRENT: Option<RpcTrace>;
+}
+
+/// A timeline of the RPCs sent to PD and TiKV while performing an operation, for offline latency
+/// analysis.
+///
+/// RPCs are only recorded while a future is run by [`capture`](RpcTrace::capture), including
+/// those sent by the tasks the client spawns for it, e.g. the requests to the regions of a scan.
+/// Other operations are not affected. The timeline is exported by
+/// [`to_chrome_trace`](RpcTrace::to_chrome_trace) as JSON in the Chrome trace event format, which
+/// can be opened in Perfetto or `chrome://tracing`. The region errors returned by TiKV and the
+/// time spent in backoff before retries are counted in [`retry_stats`](RpcTrace::retry_stats).
+///
+/// # Examples
+/// ```rust,no_run
+/// # use tikv_client::{RawClient, RpcTrace};
+/// # futures::executor::block_on(async {
+/// let client = RawClient::new(vec!["192.168.0.100"], None).await.unwrap();
+/// let trace = RpcTrace::new();
+/// let value = trace.capture(client.get("key".to_owned())).await.unwrap();
+/// std::fs::write("get.json", trace.to_chrome_trace()).unwrap();
+/// # });
+/// ```
+#[derive(Clone, Debug)]
+pub struct RpcTrace {
+    start: Instant,
+    events: Arc<Mutex<Vec<RpcEvent>>>,
+    retries: Arc<Mutex<RetryStats>>,
+}
+
+/// An RPC recorded in an [`RpcTrace`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RpcEvent {
+    /// The RPC, e.g. `raw_get` or `get_region`.
+    pub name: &'static str,
+    /// `pd` or `tikv`.
+    pub category: &'static str,
+    /// When the RPC was sent, since the trace was created.
+    pub start: Duration,
+    pub duration: Duration,
+    /// Whether the RPC succeeded.
+    pub ok: bool,
+    /// The rendered [`MetricsLabels`](crate::MetricsLabels) of the request, if any.
+    pub labels: Option<String>,
+}
+
+/// The region errors and backoffs of the requests captured by an [`RpcTrace`], see
+/// [`RpcTrace::retry_stats`].
+///
+/// Many region errors, e.g. `not_leader` or `epoch_not_match`, mean that the cluster was moving
+/// regions while the requests were sent, while `server_is_busy` means that TiKV was overloaded.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct RetryStats {
+    /// The number of region errors returned by TiKV, by their kind, e.g. `not_leader`.
+    pub region_errors: BTreeMap<&'static str, u64>,
+    /// The time spent sleeping before retries, by the reason of the backoff, e.g. `region`,
+    /// `transport` or `lock`.
+    pub backoff: BTreeMap<&'static str, Duration>,
+}
+
+impl RetryStats {
+    /// The total time spent sleeping before retries.
+    pub fn total_backoff(&self) -> Duration {
+        self.backoff.values().sum()
+    }
+}
+
+impl RpcTrace {
+    pub fn new() -> RpcTrace {
+        RpcTrace {
+            start: Instant::now(),
+            events: Arc::new(Mutex::new(Vec::new())),
+            retries: Arc::new(Mutex::new(RetryStats::default())),
+        }
+    }
+
+    /// Run `future`, recording the RPCs it sends in this trace.
+    ///
+    /// A trace can capture several futures, even concurrently; their RPCs are recorded in the same
+    /// timeline.
+    pub async fn capture<F: Future>(&self, future: F) -> F::Output {
+        CURRENT.scope(Some(self.clone()), future).await
+    }
+
+    /// The RPCs recorded so far, in the order in which they completed. RPCs of work the client
+    /// continues in the background, e.g. committing the secondary keys of a transaction, may be
+    /// recorded after the captured future completed.
+    pub fn events(&self) -> Vec<RpcEvent> {
+        self.events.lock().unwrap().clone()
+    }
+
+    /// The region errors and backoffs of the captured requests so far.
+    pub fn retry_stats(&self) -> RetryStats {
+        self.retries.lock().unwrap().clone()
+    }
+
+    /// The recorded RPCs as JSON in the Chrome trace event format.
+    ///
+    /// Each RPC is a complete (`X`) event with timestamps in microseconds since the trace was
+    /// created. Concurrent RPCs are placed on different threads of the timeline, so that the
+    /// events of each thread do not overlap.
+    pub fn to_chrome_trace(&self) -> String {
+        let mut events = self.events();
+        events.sort_by_key(|event| event.start);
+        // the end of the last event of each thread
+        let mut threads: Vec<Duration> = Vec::new();
+        let mut json = String::from("{\"traceEvents\":[");
+        for (i, event) in events.iter().enumerate() {
+            let end = event.start + event.duration;
+            let tid = match threads
+                .iter()
+                .position(|thread_end| *thread_end <= event.start)
+            {
+                Some(tid) => {
+                    threads[tid] = end;
+                    tid
+                }
+                None => {
+                    threads.push(end);
+                    threads.len() - 1
+                }
+            };
+            if i > 0 {
+                json.push(',');
+            }
+            write!(
+                json,
+                "{{\"name\":\"{}\",\"cat\":\"{}\",\"ph\":\"X\",\"ts\":{},\"dur\":{},\"pid\":1,\
+                 \"tid\":{},\"args\":{{\"ok\":{}",
+                event.name,
+                event.category,
+                event.start.as_micros(),
+                event.duration.as_micros(),
+                tid + 1,
+                event.ok,
+            )
+            .unwrap();
+            if let Some(labels) = &event.labels {
+                json.push_str(",\"labels\":");
+                push_json_string(&mut json, labels);
+            }
+            json.push_str("}}");
+        }
+        json.push_str("],\"displayTimeUnit\":\"ms\"}");
+        json
+    }
+
+    pub(crate) fn record(
+        &self,
+        name: &'static str,
+        category: &'static str,
+        start: Instant,
+        ok: bool,
+        labels: Option<String>,
+    ) {
+        let event = RpcEvent {
+            name,
+            category,
+            start: start.saturating_duration_since(self.start),
+            duration: start.elapsed(),
+            ok,
+            labels,
+        };
+        self.events.lock().unwrap().push(event);
+    }
+
+    pub(crate) fn record_region_error(&self, kind: &'static str) {
+        *self
+            .retries
+            .lock()
+            .unwrap()
+            .region_errors
+            .entry(kind)
+            .or_default() += 1;
+    }
+
+    pub(crate) fn record_backoff(&self, kind: &'static str, duration: Duration) {
+        *self
+            .retries
+            .lock()
+            .unwrap()
+            .backoff
+            .entry(kind)
+            .or_default() += duration;
+    }
+}
+
+impl Default for RpcTrace {
+    fn default() -> RpcTrace {
+        RpcTrace::new()
+    }
+}
+
+/// The trace of the current task, if it runs in [`RpcTrace::capture`].
+pub(crate) fn current_trace() -> Option<RpcTrace> {
+    CURRENT.try_with(Clone::clone).ok().flatten()
+}
+
+/// Let `future`, which is spawned as a new task, record its RPCs in the trace of the current task.
+/// Its requests also belong to the call of the current task, see
+/// [`in_current_call`](crate::store::in_current_call). If the `tracing` feature is enabled, it
+/// also runs in the current `tracing` span.
+pub(crate) fn in_current_trace<F: Future>(future: F) -> impl Future<Output = F::Output> {
+    #[cfg(feature = "tracing")]
+    let future = tracing::Instrument::in_current_span(future);
+    let future = crate::store::in_current_call(future);
+    CURRENT.scope(current_trace(), future)
+}
+
+/// Runs `future` in a `tracing` span at the debug level if the `tracing` feature is enabled, the
+/// remaining arguments being those of `tracing::debug_span!`. Otherwise returns `future` as is.
+///
+/// The span is created before `future` is evaluated, so its fields may borrow what the future
+/// takes, e.g. `in_span!(self.prewrite(), "prewrite", start_ts = self.start_version.version())`.
+#[cfg(feature = "tracing")]
+macro_rules! in_span {
+    ($future:expr, $($span:tt)+) => {{
+        let span = tracing::debug_span!($($span)+);
+        tracing::Instrument::instrument($future, span)
+    }};
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! in_span {
+    ($future:expr, $($span:tt)+) => {
+        $future
+    };
+}
+
+pub(crate) use in_span;
+
+fn push_json_string(json: &mut String, s: &str) {
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            c if c.is_control() => write!(json, "\\u{:04x}", c as u32).unwrap(),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// The names of spans and of their parents, see [`record_spans`].
+    #[cfg(feature = "tracing")]
+    pub(crate) type Spans = Arc<Mutex<Vec<(&'static str, Option<&'static str>)>>>;
+
+    /// Records the names of the spans created in the current thread and the names of their
+    /// parents, see [`record_spans`].
+    #[cfg(feature = "tracing")]
+    #[derive(Default)]
+    struct SpanRecorder {
+        /// The metadata of the spans, by their ids minus one.
+        metadata: Mutex<Vec<&'static tracing::Metadata<'static>>>,
+        /// The spans with the names of their parents.
+        spans: Spans,
+        /// The ids of the entered spans, innermost last.
+        entered: Mutex<Vec<tracing::Id>>,
+    }
+
+    #[cfg(feature = "tracing")]
+    impl tracing::Subscriber for SpanRecorder {
+        fn enabled(&self, _: &tracing::Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &tracing::span::Attributes<'_>) -> tracing::Id {
+            let mut metadata = self.metadata.lock().unwrap();
+            let parent = if span.is_contextual() {
+                self.entered.lock().unwrap().last().cloned()
+            } else {
+                span.parent().cloned()
+            };
+            let parent = parent.map(|id| metadata[id.into_u64() as usize - 1].name());
+            let name = span.metadata().name();
+            self.spans.lock().unwrap().push((name, parent));
+            metadata.push(span.metadata());
+            tracing::Id::from_u64(metadata.len() as u64)
+        }
+
+        fn record(&self, _: &tracing::Id, _: &tracing::span::Record<'_>) {}
+
+        fn record_follows_from(&self, _: &tracing::Id, _: &tracing::Id) {}
+
+        fn event(&self, _: &tracing::Event<'_>) {}
+
+        fn enter(&self, span: &tracing::Id) {
+            self.entered.lock().unwrap().push(span.clone());
+        }
+
+        fn exit(&self, span: &tracing::Id) {
+            let mut entered = self.entered.lock().unwrap();
+            if let Some(i) = entered.iter().rposition(|id| id == span) {
+                entered.remove(i);
+            }
+        }
+
+        fn current_span(&self) -> tracing_core::span::Current {
+            match self.entered.lock().unwrap().last() {
+                Some(id) => {
+                    let metadata = self.metadata.lock().unwrap()[id.into_u64() as usize - 1];
+                    tracing_core::span::Current::new(id.clone(), metadata)
+                }
+                None => tracing_core::span::Current::none(),
+            }
+        }
+    }
+
+    /// Records the spans created in the current thread until the guard is dropped, as the names
+    /// of the spans and of their parents.
+    #[cfg(feature = "tracing")]
+    pub(crate) fn record_spans() -> (tracing::subscriber::DefaultGuard, Spans) {
+        let recorder = SpanRecorder::default();
+        let spans = recorder.spans.clone();
+        (tracing::subscriber::set_default(recorder), spans)
+    }
+
+    #[tokio::test]
+    async fn test_rpc_trace() {
+        let trace = RpcTrace::new();
+        assert!(current_trace().is_none());
+        trace
+            .capture(async {
+                let start = Instant::now();
+                std::thread::sleep(Duration::from_millis(1));
+                current_trace()
+                    .unwrap()
+                    .record("get_region", "pd", start, true, None);
+                // spawned tasks record in the trace of the task spawning them
+                tokio::spawn(in_current_trace(async move {
+                    let labels = Some("tenant=\"a\"".to_owned());
+                    current_trace()
+                        .unwrap()
+                        .record("raw_get", "tikv", start, false, labels);
+                }))
+                .await
+                .unwrap();
+            })
+            .await;
+        assert!(current_trace().is_none());
+
+        let events = trace.events();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].name, "get_region");
+        assert_eq!(events[1].category, "tikv");
+        assert!(!events[1].ok);
+
+        let json: serde_json::Value = serde_json::from_str(&trace.to_chrome_trace()).unwrap();
+        let events = json["traceEvents"].as_array().unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0]["ph"], "X");
+        assert_eq!(events[1]["args"]["labels"], "tenant=\"a\"");
+        // the events overlap, so they are on different threads
+        assert_ne!(events[0]["tid"], events[1]["tid"]);
+    }
+}
@@ -1,17 +1,164 @@
 // Copyright 2019 TiKV Project Authors. Licensed under Apache-2.0.
 
-use crate::{BoundRange, Key, KvPair, Result, Value};
+use crate::{stats::observe_buffer_filter, BoundRange, Error, Key, KvPair, Result, Value};
 use std::{
     collections::{btree_map::Entry, BTreeMap, HashMap},
+    convert::{TryFrom, TryInto},
     future::Future,
+    sync::atomic::{AtomicU64, Ordering},
 };
 use tikv_client_proto::kvrpcpb;
 
+/// How the buffer of a transaction coalesces several writes to the same key into the single
+/// mutation which is prewritten.
+///
+/// A put or a delete replaces any earlier write of the key, e.g. a put after a delete is
+/// prewritten as a put, and a delete after a put as a delete. Locking a written key keeps the
+/// write. The rules here decide what happens to the existence check of an
+/// [`insert`](crate::Transaction::insert) when the key is written again, or when the key was
+/// deleted before the insert:
+///
+/// | Writes           | Default           | Otherwise |
+/// |------------------|-------------------|-----------|
+/// | insert, put      | insert            | put       |
+/// | insert, delete   | check not exists  | delete    |
+/// | delete, insert   | put               | insert    |
+///
+/// In pessimistic transactions, an insert followed by a delete is always prewritten as a delete.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct CoalescingRules {
+    put_after_insert_checks: bool,
+    delete_after_insert_checks: bool,
+    insert_after_delete_checks: bool,
+}
+
+impl Default for CoalescingRules {
+    fn default() -> CoalescingRules {
+        CoalescingRules {
+            put_after_insert_checks: true,
+            delete_after_insert_checks: true,
+            insert_after_delete_checks: false,
+        }
+    }
+}
+
+impl CoalescingRules {
+    pub fn new() -> CoalescingRules {
+        CoalescingRules::default()
+    }
+
+    /// Set whether a put after an insert keeps checking that the key did not exist, i.e. is
+    /// prewritten as an insert of the new value rather than as a put.
+    pub fn put_after_insert_checks(mut self, checks: bool) -> CoalescingRules {
+        self.put_after_insert_checks = checks;
+        self
+    }
+
+    /// Set whether a delete after an insert keeps checking that the key did not exist, i.e. is
+    /// prewritten as a check-not-exists mutation, which writes nothing, rather than as a delete.
+    pub fn delete_after_insert_checks(mut self, checks: bool) -> CoalescingRules {
+        self.delete_after_insert_checks = checks;
+        self
+    }
+
+    /// Set whether an insert after a delete checks that the key did not exist before the
+    /// transaction, i.e. is prewritten as an insert rather than as a put. Such a transaction
+    /// fails if the deleted key existed.
+    pub fn insert_after_delete_checks(mut self, checks: bool) -> CoalescingRules {
+        self.insert_after_delete_checks = checks;
+        self
+    }
+}
+
+/// A mutation buffered by a transaction, which is prewritten when the transaction commits, see
+/// [`Transaction::buffered_mutations`](crate::Transaction::buffered_mutations).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Mutation {
+    /// The key is written with the value.
+    Put(Key, Value),
+    /// The key is deleted.
+    Delete(Key),
+    /// The key is written with the value, and must not exist before, see
+    /// [`insert`](crate::Transaction::insert).
+    Insert(Key, Value),
+    /// The key must not exist, e.g. because it was inserted and deleted again, see
+    /// [`CoalescingRules`].
+    CheckNotExists(Key),
+    /// The key is locked without being written, e.g. by
+    /// [`lock_keys`](crate::Transaction::lock_keys).
+    Lock(Key),
+}
+
+impl Mutation {
+    /// The key of the mutation.
+    pub fn key(&self) -> &Key {
+        match self {
+            Mutation::Put(key, _)
+            | Mutation::Delete(key)
+            | Mutation::Insert(key, _)
+            | Mutation::CheckNotExists(key)
+            | Mutation::Lock(key) => key,
+        }
+    }
+
+    pub(crate) fn key_mut(&mut self) -> &mut Key {
+        match self {
+            Mutation::Put(key, _)
+            | Mutation::Delete(key)
+            | Mutation::Insert(key, _)
+            | Mutation::CheckNotExists(key)
+            | Mutation::Lock(key) => key,
+        }
+    }
+}
+
+/// A protobuf mutation staged by another system, e.g. for
+/// [`extend_mutations`](crate::Transaction::extend_mutations).
+///
+/// Rollbacks and pessimistic locks are only written by the client itself, and mutations with an
+/// assertion must be written by [`put_with_assertion`](crate::Transaction::put_with_assertion)
+/// or [`delete_with_assertion`](crate::Transaction::delete_with_assertion), so they are rejected.
+impl TryFrom<kvrpcpb::Mutation> for Mutation {
+    type Error = Error;
+
+    fn try_from(mut mutation: kvrpcpb::Mutation) -> Result<Mutation> {
+        let key: Key = mutation.take_key().into();
+        let unsupported = |key: Key, message: &str| Error::UnsupportedMutation {
+            key: key.into(),
+            message: message.to_owned(),
+        };
+        if mutation.get_assertion() != kvrpcpb::Assertion::None {
+            return Err(unsupported(
+                key,
+                "mutations with assertions are not supported",
+            ));
+        }
+        Ok(match mutation.get_op() {
+            kvrpcpb::Op::Put => Mutation::Put(key, mutation.take_value()),
+            kvrpcpb::Op::Del => Mutation::Delete(key),
+            kvrpcpb::Op::Insert => Mutation::Insert(key, mutation.take_value()),
+            kvrpcpb::Op::CheckNotExists => Mutation::CheckNotExists(key),
+            kvrpcpb::Op::Lock => Mutation::Lock(key),
+            op => return Err(unsupported(key, &format!("{:?} is not supported", op))),
+        })
+    }
+}
+
 /// A caching layer which buffers reads and writes in a transaction.
 pub struct Buffer {
     primary_key: Option<Key>,
     entry_map: BTreeMap<Key, BufferEntry>,
+    // Every key in `entry_map` is also in `key_filter`.
+    key_filter: KeyFilter,
+    // The lookups which the filter let skip `entry_map`, and those which it did not. They are
+    // reported to the metrics sink when the buffer is dropped rather than on every lookup.
+    filter_negatives: AtomicU64,
+    filter_positives: AtomicU64,
     is_pessimistic: bool,
+    rules: CoalescingRules,
+    // What TiKV verifies about the existence of keys before the transaction when they are
+    // prewritten.
+    assertions: HashMap<Key, kvrpcpb::Assertion>,
 }
 
 impl Buffer {
@@ -19,10 +166,21 @@ impl Buffer {
         Buffer {
             primary_key: None,
             entry_map: BTreeMap::new(),
+            key_filter: KeyFilter::new(),
+            filter_negatives: AtomicU64::new(0),
+            filter_positives: AtomicU64::new(0),
             is_pessimistic,
+            rules: CoalescingRules::default(),
+            assertions: HashMap::new(),
         }
     }
 
+    /// Coalesce writes to the same key according to `rules`.
+    pub fn with_coalescing_rules(mut self, rules: CoalescingRules) -> Buffer {
+        self.rules = rules;
+        self
+    }
+
     /// Get the primary key of the buffer.
     pub fn get_primary_key(&self) -> Option<Key> {
         self.primary_key.clone()
@@ -33,6 +191,14 @@ impl Buffer {
         self.primary_key.get_or_insert_with(|| key.clone());
     }
 
+    /// Whether the key exists as far as the buffer knows, `None` if it has to be read from TiKV.
+    pub fn exists(&self, key: &Key) -> Option<bool> {
+        match self.get_from_mutations(key) {
+            MutationValue::Determined(value) => Some(value.is_some()),
+            MutationValue::Undetermined => None,
+        }
+    }
+
     /// Get a value from the buffer.
     /// If the returned value is None, it means the key doesn't exist in buffer yet.
     pub fn get(&self, key: &Key) -> Option<Value> {
@@ -106,40 +272,60 @@ impl Buffer {
     }
 
     /// Run `f` to fetch entries in `range` from TiKV. Combine them with mutations in local buffer. Returns the results.
+    ///
+    /// The results are the first `limit` entries in ascending order of their keys, or in
+    /// descending order if `reverse` is set. If `key_only` is set, `f` fetches the keys without
+    /// their values, which are not cached then.
     pub async fn scan_and_fetch<F, Fut>(
         &mut self,
         range: BoundRange,
         limit: u32,
+        reverse: bool,
+        key_only: bool,
         f: F,
     ) -> Result<impl Iterator<Item = KvPair>>
     where
         F: FnOnce(BoundRange, u32) -> Fut,
         Fut: Future<Output = Result<Vec<KvPair>>>,
     {
-        // read from local buffer
-        let mutation_range = self.entry_map.range(range.clone());
-
-        // fetch from TiKV
-        // fetch more entries because some of them may be deleted.
-        let redundant_limit = limit
-            + mutation_range
-                .clone()
-                .filter(|(_, m)| matches!(m, BufferEntry::Del))
-                .count() as u32;
-
-        let mut results = f(range, redundant_limit)
-            .await?
+        let fetched = f(range.clone(), self.scan_limit(range.clone(), limit)).await?;
+        Ok(self.merge_scanned(range, limit, reverse, key_only, fetched))
+    }
+
+    /// The number of entries to fetch from TiKV for the first `limit` entries in `range`: more,
+    /// because some of them may be deleted by the local buffer.
+    pub fn scan_limit(&self, range: BoundRange, limit: u32) -> u32 {
+        limit
+            + self
+                .entry_map
+                .range(range)
+                .filter(|(_, m)| matches!(m, BufferEntry::Del | BufferEntry::CheckNotExist))
+                .count() as u32
+    }
+
+    /// Combine `fetched`, the entries in `range` fetched from TiKV with a limit of
+    /// [`scan_limit`](Buffer::scan_limit), with mutations in local buffer. Returns the results,
+    /// like [`scan_and_fetch`](Buffer::scan_and_fetch).
+    pub fn merge_scanned(
+        &mut self,
+        range: BoundRange,
+        limit: u32,
+        reverse: bool,
+        key_only: bool,
+        fetched: Vec<KvPair>,
+    ) -> impl Iterator<Item = KvPair> {
+        let mut results = fetched
             .into_iter()
             .map(|pair| pair.into())
             .collect::<HashMap<Key, Value>>();
 
         // override using local data
-        for (k, m) in mutation_range {
+        for (k, m) in self.entry_map.range(range) {
             match m {
-                BufferEntry::Put(v) => {
+                BufferEntry::Put(v) | BufferEntry::Insert(v) => {
                     results.insert(k.clone(), v.clone());
                 }
-                BufferEntry::Del => {
+                BufferEntry::Del | BufferEntry::CheckNotExist => {
                     results.remove(k);
                 }
                 _ => {}
@@ -147,8 +333,10 @@ impl Buffer {
         }
 
         // update local buffer
-        for (k, v) in &results {
-            self.update_cache(k.clone(), Some(v.clone()));
+        if !key_only {
+            for (k, v) in &results {
+                self.update_cache(k.clone(), Some(v.clone()));
+            }
         }
 
         let mut res = results
@@ -156,13 +344,29 @@ impl Buffer {
             .map(|(k, v)| KvPair::new(k, v))
             .collect::<Vec<_>>();
         res.sort_by_cached_key(|x| x.key().clone());
+        if reverse {
+            res.reverse();
+        }
 
-        Ok(res.into_iter().take(limit as usize))
+        res.into_iter().take(limit as usize)
+    }
+
+    /// The values written to the keys in `range`, ordered by key, `None` for deleted keys.
+    pub fn writes_in_range(&self, range: BoundRange) -> Vec<(Key, Option<Value>)> {
+        self.entry_map
+            .range(range)
+            .filter_map(|(k, m)| match m {
+                BufferEntry::Put(v) | BufferEntry::Insert(v) => Some((k.clone(), Some(v.clone()))),
+                BufferEntry::Del | BufferEntry::CheckNotExist => Some((k.clone(), None)),
+                _ => None,
+            })
+            .collect()
     }
 
     /// Lock the given key if necessary.
     pub fn lock(&mut self, key: Key) {
         self.primary_key.get_or_insert_with(|| key.clone());
+        self.add_to_filter(&key);
         let value = self
             .entry_map
             .entry(key)
@@ -174,13 +378,34 @@ impl Buffer {
         }
     }
 
+    /// Whether the key is locked and not written.
+    pub fn is_locked_only(&self, key: &Key) -> bool {
+        matches!(self.entry_map.get(key), Some(BufferEntry::Locked(_)))
+    }
+
+    /// Unlock a key which is locked and not written, keeping the value read from it, if any.
+    pub fn unlock(&mut self, key: &Key) {
+        match self.entry_map.remove(key) {
+            Some(BufferEntry::Locked(Some(value))) => {
+                self.entry_map
+                    .insert(key.clone(), BufferEntry::Cached(value));
+            }
+            Some(BufferEntry::Locked(None)) | None => {}
+            Some(entry) => {
+                self.entry_map.insert(key.clone(), entry);
+            }
+        }
+    }
+
     /// Put a value into the buffer (does not write through).
     pub fn put(&mut self, key: Key, value: Value) {
+        let checks = self.rules.put_after_insert_checks;
         let mut entry = self.entry_map.entry(key.clone());
         match entry {
             Entry::Occupied(ref mut o)
-                if matches!(o.get(), BufferEntry::Insert(_))
-                    || matches!(o.get(), BufferEntry::CheckNotExist) =>
+                if checks
+                    && (matches!(o.get(), BufferEntry::Insert(_))
+                        || matches!(o.get(), BufferEntry::CheckNotExist)) =>
             {
                 o.insert(BufferEntry::Insert(value));
             }
@@ -190,9 +415,10 @@ impl Buffer {
 
     /// Mark a value as Insert mutation into the buffer (does not write through).
     pub fn insert(&mut self, key: Key, value: Value) {
+        let checks = self.rules.insert_after_delete_checks;
         let mut entry = self.entry_map.entry(key.clone());
         match entry {
-            Entry::Occupied(ref mut o) if matches!(o.get(), BufferEntry::Del) => {
+            Entry::Occupied(ref mut o) if !checks && matches!(o.get(), BufferEntry::Del) => {
                 o.insert(BufferEntry::Put(value));
             }
             _ => self.insert_entry(key, BufferEntry::Insert(value)),
@@ -201,12 +427,12 @@ impl Buffer {
 
     /// Mark a value as deleted.
     pub fn delete(&mut self, key: Key) {
-        let is_pessimistic = self.is_pessimistic;
+        let checks = !self.is_pessimistic && self.rules.delete_after_insert_checks;
         let mut entry = self.entry_map.entry(key.clone());
 
         match entry {
             Entry::Occupied(ref mut o)
-                if !is_pessimistic
+                if checks
                     && (matches!(o.get(), BufferEntry::Insert(_))
                         || matches!(o.get(), BufferEntry::CheckNotExist)) =>
             {
@@ -216,11 +442,52 @@ impl Buffer {
         }
     }
 
+    /// Let TiKV verify `assertion` about the existence of `key` before the transaction when its
+    /// mutation is prewritten. The assertion holds for any later writes of the key.
+    pub fn assert(&mut self, key: Key, assertion: kvrpcpb::Assertion) {
+        self.assertions.insert(key, assertion);
+    }
+
+    /// Buffer `mutation` like the write it stands for.
+    pub fn apply(&mut self, mutation: Mutation) {
+        match mutation {
+            Mutation::Put(key, value) => self.put(key, value),
+            Mutation::Delete(key) => self.delete(key),
+            Mutation::Insert(key, value) => self.insert(key, value),
+            Mutation::CheckNotExists(key) => self.insert_entry(key, BufferEntry::CheckNotExist),
+            Mutation::Lock(key) => self.lock(key),
+        }
+    }
+
+    /// The buffered mutations, ordered by key.
+    pub fn mutations(&self) -> Vec<Mutation> {
+        self.entry_map
+            .iter()
+            .filter_map(|(key, entry)| {
+                let key = key.clone();
+                Some(match entry {
+                    BufferEntry::Cached(_) => return None,
+                    BufferEntry::Locked(_) => Mutation::Lock(key),
+                    BufferEntry::Put(value) => Mutation::Put(key, value.clone()),
+                    BufferEntry::Del => Mutation::Delete(key),
+                    BufferEntry::Insert(value) => Mutation::Insert(key, value.clone()),
+                    BufferEntry::CheckNotExist => Mutation::CheckNotExists(key),
+                })
+            })
+            .collect()
+    }
+
     /// Converts the buffered mutations to the proto buffer version
     pub fn to_proto_mutations(&self) -> Vec<kvrpcpb::Mutation> {
         self.entry_map
             .iter()
-            .filter_map(|(key, mutation)| mutation.to_proto_with_key(key))
+            .filter_map(|(key, mutation)| {
+                let mut pb = mutation.to_proto_with_key(key)?;
+                if let Some(assertion) = self.assertions.get(key) {
+                    pb.set_assertion(*assertion);
+                }
+                Some(pb)
+            })
             .collect()
     }
 
@@ -235,7 +502,41 @@ impl Buffer {
             .sum()
     }
 
+    /// The state of the buffer, which [`restore`](Buffer::restore) returns to.
+    pub fn snapshot(&self) -> BufferSnapshot {
+        BufferSnapshot {
+            primary_key: self.primary_key.clone(),
+            entry_map: self.entry_map.clone(),
+            assertions: self.assertions.clone(),
+        }
+    }
+
+    /// Discard the writes, locks and reads buffered since `snapshot` was taken. If
+    /// `keep_primary_lock` is true, a primary key chosen since then stays the primary key and
+    /// locked, e.g. because its pessimistic lock holds the status of the transaction.
+    pub fn restore(&mut self, snapshot: BufferSnapshot, keep_primary_lock: bool) {
+        let primary_key = self.primary_key.take();
+        self.primary_key = snapshot.primary_key;
+        self.entry_map = snapshot.entry_map;
+        self.assertions = snapshot.assertions;
+        // keys released since the snapshot may be missing from a rebuilt filter
+        self.key_filter = KeyFilter::with_capacity(self.key_filter.capacity);
+        for key in self.entry_map.keys() {
+            self.key_filter.insert(key);
+        }
+        if keep_primary_lock && self.primary_key.is_none() {
+            if let Some(key) = primary_key {
+                self.lock(key);
+            }
+        }
+    }
+
     fn get_from_mutations(&self, key: &Key) -> MutationValue {
+        if !self.key_filter.may_contain(key) {
+            self.filter_negatives.fetch_add(1, Ordering::Relaxed);
+            return MutationValue::Undetermined;
+        }
+        self.filter_positives.fetch_add(1, Ordering::Relaxed);
         self.entry_map
             .get(key)
             .map(BufferEntry::get_value)
@@ -248,6 +549,7 @@ impl Buffer {
                 self.entry_map.insert(key, BufferEntry::Locked(Some(value)));
             }
             None => {
+                self.add_to_filter(&key);
                 self.entry_map.insert(key, BufferEntry::Cached(value));
             }
             Some(BufferEntry::Cached(v)) | Some(BufferEntry::Locked(Some(v))) => {
@@ -269,8 +571,111 @@ impl Buffer {
         if !matches!(entry, BufferEntry::Cached(_) | BufferEntry::CheckNotExist) {
             self.primary_key.get_or_insert_with(|| key.clone());
         }
+        // only new keys count towards the capacity of the filter
+        if !self.key_filter.may_contain(&key) || !self.entry_map.contains_key(&key) {
+            self.add_to_filter(&key);
+        }
         self.entry_map.insert(key, entry);
     }
+
+    fn add_to_filter(&mut self, key: &Key) {
+        if self.key_filter.is_full() {
+            self.key_filter = KeyFilter::with_capacity(self.key_filter.capacity * 2);
+            for k in self.entry_map.keys() {
+                self.key_filter.insert(k);
+            }
+        }
+        self.key_filter.insert(key);
+    }
+}
+
+impl Drop for Buffer {
+    fn drop(&mut self) {
+        observe_buffer_filter(
+            *self.filter_negatives.get_mut(),
+            *self.filter_positives.get_mut(),
+        );
+    }
+}
+
+/// The state of a [`Buffer`] at some point, see [`Buffer::snapshot`].
+#[derive(Debug, Clone)]
+pub struct BufferSnapshot {
+    primary_key: Option<Key>,
+    entry_map: BTreeMap<Key, BufferEntry>,
+    assertions: HashMap<Key, kvrpcpb::Assertion>,
+}
+
+const KEY_FILTER_INITIAL_CAPACITY: usize = 1024;
+const KEY_FILTER_BITS_PER_KEY: usize = 10;
+const KEY_FILTER_HASHES: u64 = 4;
+
+// A bloom filter of the keys in the buffer. It lets reads of keys which are not in the buffer skip
+// probing the map, which is costly when the buffer holds many large keys.
+//
+// The filter is rebuilt with a doubled capacity when it is full, so the false positive rate stays
+// low as the buffer grows. Keys are never removed from the filter.
+struct KeyFilter {
+    bits: Vec<u64>,
+    capacity: usize,
+    len: usize,
+}
+
+impl KeyFilter {
+    fn new() -> KeyFilter {
+        KeyFilter::with_capacity(KEY_FILTER_INITIAL_CAPACITY)
+    }
+
+    fn with_capacity(capacity: usize) -> KeyFilter {
+        let bit_count = capacity * KEY_FILTER_BITS_PER_KEY;
+        KeyFilter {
+            bits: vec![0; bit_count.div_ceil(64)],
+            capacity,
+            len: 0,
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        self.len >= self.capacity
+    }
+
+    fn insert(&mut self, key: &Key) {
+        for bit in self.bit_indices(key) {
+            self.bits[bit / 64] |= 1 << (bit % 64);
+        }
+        self.len += 1;
+    }
+
+    // Returns false if the key is definitely not in the filter.
+    fn may_contain(&self, key: &Key) -> bool {
+        self.bit_indices(key)
+            .all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+
+    fn bit_indices(&self, key: &Key) -> impl Iterator<Item = usize> {
+        let hash = KeyFilter::hash(key.into());
+        let (h1, h2) = (hash & 0xffff_ffff, hash >> 32);
+        let bit_count = (self.bits.len() * 64) as u64;
+        (0..KEY_FILTER_HASHES)
+            .map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % bit_count) as usize)
+    }
+
+    // A multiplicative hash of the key eight bytes at a time, in the manner of FxHash. It is much
+    // cheaper than SipHash, and the filter does not need resistance to collisions.
+    fn hash(bytes: &[u8]) -> u64 {
+        const SEED: u64 = 0x51_7c_c1_b7_27_22_0a_95;
+        let mut hash = bytes.len() as u64;
+        let mut chunks = bytes.chunks_exact(8);
+        for chunk in &mut chunks {
+            let word = u64::from_le_bytes(chunk.try_into().unwrap());
+            hash = (hash.rotate_left(5) ^ word).wrapping_mul(SEED);
+        }
+        let mut tail = [0; 8];
+        tail[..chunks.remainder().len()].copy_from_slice(chunks.remainder());
+        hash = (hash.rotate_left(5) ^ u64::from_le_bytes(tail)).wrapping_mul(SEED);
+        // mix the high bits into the low half, which is one of the two hashes of a key
+        hash ^ (hash >> 29)
+    }
 }
 
 // The state of a key-value pair in the buffer.
@@ -372,6 +777,48 @@ mod tests {
     use futures::{executor::block_on, future::ready};
     use tikv_client_common::internal_err;
 
+    #[test]
+    fn key_filter_grows_with_buffer() {
+        let mut buffer = Buffer::new(false);
+        let count = KEY_FILTER_INITIAL_CAPACITY * 3;
+        for i in 0..count {
+            buffer.put(format!("key{}", i).into_bytes().into(), b"value".to_vec());
+        }
+        assert!(buffer.key_filter.capacity >= count);
+        for i in 0..count {
+            assert_eq!(
+                buffer.get(&format!("key{}", i).into_bytes().into()),
+                Some(b"value".to_vec())
+            );
+        }
+
+        let false_positives = (0..count)
+            .filter(|i| {
+                buffer
+                    .key_filter
+                    .may_contain(&format!("other{}", i).into_bytes().into())
+            })
+            .count();
+        assert!(false_positives < count / 10);
+        assert_eq!(buffer.get(&b"other".to_vec().into()), None);
+    }
+
+    #[test]
+    fn key_filter_counts_new_keys() {
+        let mut buffer = Buffer::new(false);
+        for _ in 0..KEY_FILTER_INITIAL_CAPACITY * 2 {
+            buffer.put(b"key".to_vec().into(), b"value".to_vec());
+            buffer.delete(b"key".to_vec().into());
+        }
+        assert_eq!(buffer.key_filter.len, 1);
+        assert_eq!(buffer.key_filter.capacity, KEY_FILTER_INITIAL_CAPACITY);
+
+        assert_eq!(buffer.get(&b"key".to_vec().into()), None);
+        assert_eq!(buffer.get(&b"other".to_vec().into()), None);
+        assert_eq!(*buffer.filter_positives.get_mut(), 1);
+        assert_eq!(*buffer.filter_negatives.get_mut(), 1);
+    }
+
     #[test]
     fn set_and_get_from_buffer() {
         let mut buffer = Buffer::new(false);
@@ -430,6 +877,80 @@ mod tests {
         );
     }
 
+    #[test]
+    fn scan_merges_buffer() {
+        let key = |k: &[u8]| Key::from(k.to_vec());
+        let mut buffer = Buffer::new(false);
+        buffer.put(key(b"key1"), b"put".to_vec());
+        buffer.insert(key(b"key2"), b"inserted".to_vec());
+        buffer.delete(key(b"key3"));
+        // inserted and deleted again, so it must not exist
+        buffer.insert(key(b"key4"), b"inserted".to_vec());
+        buffer.delete(key(b"key4"));
+        let stored = |_, limit| {
+            assert_eq!(limit, 4);
+            ready(Ok(vec![
+                KvPair(key(b"key3"), b"stored".to_vec()),
+                KvPair(key(b"key5"), b"stored".to_vec()),
+            ]))
+        };
+
+        let pairs: Vec<KvPair> = block_on(buffer.scan_and_fetch(
+            (b"key0".to_vec()..b"key9".to_vec()).into(),
+            2,
+            false,
+            false,
+            stored,
+        ))
+        .unwrap()
+        .collect();
+        assert_eq!(
+            pairs,
+            vec![
+                KvPair(key(b"key1"), b"put".to_vec()),
+                KvPair(key(b"key2"), b"inserted".to_vec())
+            ]
+        );
+        assert_eq!(buffer.get(&key(b"key5")), Some(b"stored".to_vec()));
+
+        // the values of a key-only scan are not cached
+        let mut buffer = Buffer::new(false);
+        let keys: Vec<KvPair> = block_on(buffer.scan_and_fetch(
+            (b"key0".to_vec()..b"key9".to_vec()).into(),
+            1,
+            false,
+            true,
+            |_, _| ready(Ok(vec![KvPair(key(b"key5"), Vec::new())])),
+        ))
+        .unwrap()
+        .collect();
+        assert_eq!(keys, vec![KvPair(key(b"key5"), Vec::new())]);
+        assert_eq!(buffer.get(&key(b"key5")), None);
+    }
+
+    #[test]
+    fn buffered_mutations() {
+        let key = |k: &[u8]| Key::from(k.to_vec());
+        let mut buffer = Buffer::new(false);
+        block_on(buffer.get_or_else(key(b"key0"), |_| ready(Ok(Some(b"read".to_vec()))))).unwrap();
+        buffer.put(key(b"key1"), b"value1".to_vec());
+        buffer.delete(key(b"key2"));
+        buffer.insert(key(b"key3"), b"value3".to_vec());
+        buffer.insert(key(b"key4"), b"value4".to_vec());
+        buffer.delete(key(b"key4"));
+        buffer.lock(key(b"key5"));
+        assert_eq!(
+            buffer.mutations(),
+            vec![
+                Mutation::Put(key(b"key1"), b"value1".to_vec()),
+                Mutation::Delete(key(b"key2")),
+                Mutation::Insert(key(b"key3"), b"value3".to_vec()),
+                Mutation::CheckNotExists(key(b"key4")),
+                Mutation::Lock(key(b"key5")),
+            ]
+        );
+    }
+
     #[test]
     fn repeat_reads_are_cached() {
         let k1: Key = b"key1".to_vec().into();
@@ -510,5 +1031,111 @@ mod tests {
         buffer.delete(key.clone());
         buffer.insert(key.clone(), b"value1".to_vec());
         assert_entry!(key, BufferEntry::Put(_));
+
+        // Put + Delete = Delete
+        let key: Key = b"key4".to_vec().into();
+        buffer.put(key.clone(), b"value1".to_vec());
+        buffer.delete(key.clone());
+        assert_entry!(key, BufferEntry::Del);
+
+        // Delete + Put = Put
+        buffer.put(key.clone(), b"value2".to_vec());
+        assert_entry!(key, BufferEntry::Put(_));
+    }
+
+    #[test]
+    fn coalescing_rules() {
+        let rules = CoalescingRules::new()
+            .put_after_insert_checks(false)
+            .delete_after_insert_checks(false)
+            .insert_after_delete_checks(true);
+        let mut buffer = Buffer::new(false).with_coalescing_rules(rules);
+
+        macro_rules! assert_entry {
+            ($key: ident, $p: pat) => {
+                assert!(matches!(buffer.entry_map.get(&$key), Some(&$p),))
+            };
+        }
+
+        // Insert + Put = Put
+        let key: Key = b"key1".to_vec().into();
+        buffer.insert(key.clone(), b"value1".to_vec());
+        buffer.put(key.clone(), b"value2".to_vec());
+        assert_entry!(key, BufferEntry::Put(_));
+
+        // Insert + Delete = Delete
+        let key: Key = b"key2".to_vec().into();
+        buffer.insert(key.clone(), b"value1".to_vec());
+        buffer.delete(key.clone());
+        assert_entry!(key, BufferEntry::Del);
+
+        // Delete + Insert = Insert
+        buffer.insert(key.clone(), b"value2".to_vec());
+        assert_entry!(key, BufferEntry::Insert(_));
+
+        // in pessimistic transactions, Insert + Delete = Delete regardless of the rules
+        let mut pessimistic = Buffer::new(true);
+        pessimistic.insert(key.clone(), b"value1".to_vec());
+        pessimistic.delete(key.clone());
+        assert!(matches!(
+            pessimistic.entry_map.get(&key),
+            Some(&BufferEntry::Del)
+        ));
+    }
+
+    #[test]
+    fn assertions() {
+        let mut buffer = Buffer::new(false);
+        let key1: Key = b"key1".to_vec().into();
+        let key2: Key = b"key2".to_vec().into();
+        buffer.put(key1.clone(), b"value1".to_vec());
+        buffer.assert(key1.clone(), kvrpcpb::Assertion::NotExist);
+        // the assertion holds for later writes
+        buffer.put(key1, b"value2".to_vec());
+        buffer.delete(key2.clone());
+        buffer.assert(key2, kvrpcpb::Assertion::Exist);
+        buffer.put(b"key3".to_vec().into(), b"value3".to_vec());
+
+        let assertions: Vec<kvrpcpb::Assertion> = buffer
+            .to_proto_mutations()
+            .iter()
+            .map(|m| m.get_assertion())
+            .collect();
+        assert_eq!(
+            assertions,
+            vec![
+                kvrpcpb::Assertion::NotExist,
+                kvrpcpb::Assertion::Exist,
+                kvrpcpb::Assertion::None
+            ]
+        );
+    }
+
+    #[test]
+    fn snapshot_and_restore() {
+        let mut buffer = Buffer::new(true);
+        let key1: Key = b"key1".to_vec().into();
+        let key2: Key = b"key2".to_vec().into();
+        let snapshot = buffer.snapshot();
+        buffer.lock(key1.clone());
+        buffer.put(key2.clone(), b"value2".to_vec());
+        buffer.assert(key2.clone(), kvrpcpb::Assertion::NotExist);
+
+        // the primary key stays locked
+        buffer.restore(snapshot.clone(), true);
+        assert_eq!(buffer.get_primary_key(), Some(key1.clone()));
+        assert!(buffer.is_locked_only(&key1));
+        assert_eq!(buffer.get(&key2), None);
+        assert_eq!(buffer.to_proto_mutations().len(), 1);
+
+        buffer.put(key2.clone(), b"value2".to_vec());
+        let snapshot2 = buffer.snapshot();
+        buffer.delete(key2.clone());
+        buffer.restore(snapshot2, true);
+        assert_eq!(buffer.get(&key2), Some(b"value2".to_vec()));
+
+        buffer.restore(snapshot, false);
+        assert_eq!(buffer.get_primary_key(), None);
+        assert!(buffer.to_proto_mutations().is_empty());
     }
 }
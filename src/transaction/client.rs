@@ -1,21 +1,48 @@
 // Copyright 2019 TiKV Project Authors. Licensed under Apache-2.0.
 
-use super::{requests::new_scan_lock_request, resolve_locks};
+use super::{
+    gc,
+    purge::delete_in_batches,
+    resolve_locks_in_range,
+    stale::bounded_stale_timestamp,
+    transaction::{is_lock_conflict, is_txn_conflict},
+    PurgeOptions, PurgeProgress, ResolveLocksSummary,
+};
 use crate::{
     backoff::{DEFAULT_REGION_BACKOFF, OPTIMISTIC_BACKOFF},
+    cdc::{change_feed, ChangeEvent, ChangeFeedOptions},
+    cluster::fetch_store_stats,
     config::Config,
+    coprocessor::{coprocessor_stream, decode_checksum, new_checksum_request},
+    import::{import_pairs, ingest_files, ImportOptions, ImportSummary, SstFile},
+    namespace::Namespace,
     pd::{PdClient, PdRpcClient},
-    request::Plan,
+    pressure::PressureTracker,
+    region::{RegionId, RegionWithLeader},
+    request::{Collect, DeleteSummary, Plan, PlanBuilder, RetryOptions},
+    split::new_split_region_request,
+    stats::{observe_txn_wait, MetricsLabels},
     timestamp::TimestampExt,
-    transaction::{Snapshot, Transaction, TransactionOptions},
-    Result,
+    transaction::{
+        export::{diff_pairs, scan_at, write_batches},
+        lowering::new_mvcc_get_by_start_ts_request,
+        ExportSink, HeartbeatScheduler, KeyDiff, LockPolicy, MvccInfo, ReadOptions, Snapshot,
+        Transaction, TransactionOptions,
+    },
+    BoundRange, Cluster, ClusterInfo, ClusterPressure, CommandPriority, ConnectionStats,
+    CoprocessorRequest, CoprocessorResponse, Error, Key, KvPair, PdMember, RawChecksum, Result,
+    StoreStats, Value,
 };
+use futures::{future::BoxFuture, prelude::*, stream::BoxStream};
 use slog::{Drain, Logger};
-use std::{mem, sync::Arc};
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
 use tikv_client_proto::{kvrpcpb, pdpb::Timestamp};
 
-// FIXME: cargo-culted value
-const SCAN_LOCK_BATCH_SIZE: u32 = 1024;
+/// The number of pairs scanned by each request of [`export`](Client::export).
+pub const EXPORT_BATCH_SIZE: u32 = 1024;
 
 /// The TiKV transactional `Client` is used to interact with TiKV using transactional requests.
 ///
@@ -35,6 +62,23 @@ const SCAN_LOCK_BATCH_SIZE: u32 = 1024;
 /// awaited to execute.
 pub struct Client {
     pd: Arc<PdRpcClient>,
+    /// Sends heartbeats for all transactions of the client.
+    heartbeats: HeartbeatScheduler<PdRpcClient>,
+    /// Labels attached to the metrics and logs of all transactions of the client.
+    metrics_labels: MetricsLabels,
+    /// The versions and features of the cluster, fetched when connecting.
+    cluster_info: Arc<ClusterInfo>,
+    /// If set, keys are in the namespace, see [`namespace`](Client::namespace).
+    namespace: Option<Namespace>,
+    /// If set, replaces the retry options of the transactions begun with the default options,
+    /// see [`with_retry_options`](Client::with_retry_options).
+    retry_options: Option<RetryOptions>,
+    /// The priority of the requests of the transactions which leave it unset, see
+    /// [`with_priority`](Client::with_priority).
+    priority: CommandPriority,
+    /// Tags the requests of the transactions which are not tagged, see
+    /// [`with_resource_group_tag`](Client::with_resource_group_tag).
+    resource_group_tag: Vec<u8>,
     logger: Logger,
 }
 
@@ -104,7 +148,235 @@ impl Client {
         debug!(logger, "creating new transactional client");
         let pd_endpoints: Vec<String> = pd_endpoints.into_iter().map(Into::into).collect();
         let pd = Arc::new(PdRpcClient::connect(&pd_endpoints, config, true, logger.clone()).await?);
-        Ok(Client { pd, logger })
+        let (pd, namespace) = in_keyspace(pd);
+        let heartbeats = HeartbeatScheduler::new(pd.clone());
+        let cluster_info = Arc::new(ClusterInfo::fetch(pd.clone(), &logger).await);
+        Ok(Client {
+            pd,
+            heartbeats,
+            metrics_labels: MetricsLabels::default(),
+            cluster_info,
+            namespace,
+            retry_options: None,
+            priority: CommandPriority::Normal,
+            resource_group_tag: Vec::new(),
+            logger,
+        })
+    }
+
+    /// Create a transactional [`Client`] which shares the connections and the region cache of
+    /// `cluster` with all other clients created from it.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # use tikv_client::{Cluster, Config, TransactionClient};
+    /// # use futures::prelude::*;
+    /// # futures::executor::block_on(async {
+    /// let cluster = Cluster::connect(vec!["192.168.0.100"], Config::default(), None)
+    ///     .await
+    ///     .unwrap();
+    /// let client = TransactionClient::new_with_cluster(&cluster);
+    /// # });
+    /// ```
+    pub fn new_with_cluster(cluster: &Cluster) -> Client {
+        let (pd, namespace) = in_keyspace(cluster.pd_client(true));
+        let heartbeats = HeartbeatScheduler::new(pd.clone());
+        Client {
+            pd,
+            heartbeats,
+            metrics_labels: MetricsLabels::default(),
+            cluster_info: cluster.shared_info(),
+            namespace,
+            retry_options: None,
+            priority: CommandPriority::Normal,
+            resource_group_tag: Vec::new(),
+            logger: cluster.logger().clone(),
+        }
+    }
+
+    /// The versions and features of the cluster, fetched when the client connected.
+    ///
+    /// Transactions don't use features the cluster doesn't support, e.g., if async commit is not
+    /// supported, transactions with [`use_async_commit`](TransactionOptions::use_async_commit) are
+    /// committed with two-phase commit.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # use tikv_client::{Config, TransactionClient};
+    /// # use futures::prelude::*;
+    /// # futures::executor::block_on(async {
+    /// let client = TransactionClient::new(vec!["192.168.0.100"], None)
+    ///     .await
+    ///     .unwrap();
+    /// if let Some(version) = client.cluster_info().min_tikv_version() {
+    ///     println!("the oldest TiKV runs {}", version);
+    /// }
+    /// # });
+    /// ```
+    pub fn cluster_info(&self) -> &ClusterInfo {
+        &self.cluster_info
+    }
+
+    /// How loaded the cluster appears from the responses to the requests of the client and its
+    /// clones, see [`ClusterPressure`].
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # use tikv_client::TransactionClient;
+    /// # futures::executor::block_on(async {
+    /// let client = TransactionClient::new(vec!["192.168.0.100"], None).await.unwrap();
+    /// if client.cluster_pressure().busy_ratio() > 0.1 {
+    ///     // shed load
+    /// }
+    /// # });
+    /// ```
+    pub fn cluster_pressure(&self) -> ClusterPressure {
+        self.pd
+            .pressure()
+            .map(PressureTracker::pressure)
+            .unwrap_or_default()
+    }
+
+    /// The statistics of the connections of the client and its clones to the stores, ordered by
+    /// address, see [`ConnectionStats`].
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # use tikv_client::TransactionClient;
+    /// # futures::executor::block_on(async {
+    /// let client = TransactionClient::new(vec!["192.168.0.100"], None).await.unwrap();
+    /// for stats in client.connection_stats().await {
+    ///     println!("{}: {:?} waiting per request", stats.address, stats.average_queue_wait());
+    /// }
+    /// # });
+    /// ```
+    pub async fn connection_stats(&self) -> Vec<ConnectionStats> {
+        self.pd.connection_stats().await
+    }
+
+    /// Attach `labels` to the metrics and logs of all transactions and snapshots of the client.
+    ///
+    /// Labels set in the [`TransactionOptions`] of a transaction take precedence over the labels
+    /// of the client.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # use tikv_client::{Config, MetricsLabels, TransactionClient};
+    /// # use futures::prelude::*;
+    /// # futures::executor::block_on(async {
+    /// let client = TransactionClient::new(vec!["192.168.0.100"], None)
+    ///     .await
+    ///     .unwrap()
+    ///     .with_metrics_labels(MetricsLabels::new().with("tenant", "acme"));
+    /// # });
+    /// ```
+    pub fn with_metrics_labels(mut self, labels: MetricsLabels) -> Client {
+        self.metrics_labels = labels;
+        self
+    }
+
+    /// Retry the requests of the transactions begun with the default options, e.g. by
+    /// [`begin_optimistic`](Client::begin_optimistic), with `retry_options` instead of the
+    /// defaults of their kind. The transactions begun with
+    /// [`begin_with_options`](Client::begin_with_options) are retried with the
+    /// [`retry_options`](TransactionOptions::retry_options) of their options.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # use tikv_client::{Backoff, RetryOptions, TransactionClient};
+    /// # use futures::prelude::*;
+    /// # futures::executor::block_on(async {
+    /// // wait longer for regions to be ready, but fail fast on locks
+    /// let retry_options = RetryOptions::new(
+    ///     Backoff::decorrelated_jitter_backoff(10, 2000, 20),
+    ///     Backoff::equal_jitter_backoff(2, 100, 3),
+    /// );
+    /// let client = TransactionClient::new(vec!["192.168.0.100"], None)
+    ///     .await
+    ///     .unwrap()
+    ///     .with_retry_options(retry_options);
+    /// # });
+    /// ```
+    pub fn with_retry_options(mut self, retry_options: RetryOptions) -> Client {
+        self.retry_options = Some(retry_options);
+        self
+    }
+
+    /// Send the requests of all transactions and snapshots of the client with `priority` in
+    /// TiKV, e.g. `Low` for a client running background jobs, so that they don't starve the
+    /// requests of online traffic.
+    ///
+    /// The transactions whose [`TransactionOptions`] set another priority than `Normal` keep
+    /// theirs.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # use tikv_client::{CommandPriority, TransactionClient};
+    /// # use futures::prelude::*;
+    /// # futures::executor::block_on(async {
+    /// let client = TransactionClient::new(vec!["192.168.0.100"], None)
+    ///     .await
+    ///     .unwrap()
+    ///     .with_priority(CommandPriority::Low)
+    ///     .with_resource_group_tag("analytics");
+    /// # });
+    /// ```
+    pub fn with_priority(mut self, priority: CommandPriority) -> Client {
+        self.priority = priority;
+        self
+    }
+
+    /// Tag the requests of all transactions and snapshots of the client with `tag`, which TiKV
+    /// attributes their resource usage to, see [`with_priority`](Client::with_priority).
+    ///
+    /// The transactions whose [`TransactionOptions`] set a tag keep theirs.
+    pub fn with_resource_group_tag(mut self, tag: impl Into<Vec<u8>>) -> Client {
+        self.resource_group_tag = tag.into();
+        self
+    }
+
+    /// Create a new client sharing the connections of `self`, but whose keys are in the
+    /// namespace `name`, e.g. to share a cluster between components without their keys colliding.
+    ///
+    /// The keys of the transactions and snapshots of the new client are prefixed with `name/`
+    /// before they are sent to TiKV, and the prefix is removed from keys returned by TiKV. Ranges
+    /// are restricted to the namespace, so the scans and range deletions of the new client, e.g.
+    /// [`unsafe_destroy_range`](Client::unsafe_destroy_range), never reach the keys of other
+    /// namespaces. Namespaces nest: the namespace of a namespaced client is created in its
+    /// namespace. The ranges of [`coprocessor`](Client::coprocessor) requests are not prefixed.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # use tikv_client::TransactionClient;
+    /// # futures::executor::block_on(async {
+    /// let client = TransactionClient::new(vec!["192.168.0.100"], None).await.unwrap();
+    /// let orders = client.namespace("orders");
+    /// let mut txn = orders.begin_optimistic().await.unwrap();
+    /// // writes the key "orders/42"
+    /// txn.put("42".to_owned(), "pending".to_owned()).await.unwrap();
+    /// txn.commit().await.unwrap();
+    /// # });
+    /// ```
+    pub fn namespace(&self, name: &str) -> Client {
+        Client {
+            pd: self.pd.clone(),
+            heartbeats: self.heartbeats.clone(),
+            metrics_labels: self.metrics_labels.clone(),
+            cluster_info: self.cluster_info.clone(),
+            namespace: Some(Namespace::new(self.namespace.as_ref(), name)),
+            retry_options: self.retry_options.clone(),
+            priority: self.priority,
+            resource_group_tag: self.resource_group_tag.clone(),
+            logger: self.logger.clone(),
+        }
     }
 
     /// Creates a new optimistic [`Transaction`].
@@ -131,8 +403,9 @@ impl Client {
     /// ```
     pub async fn begin_optimistic(&self) -> Result<Transaction> {
         debug!(self.logger, "creating new optimistic transaction");
-        let timestamp = self.current_timestamp().await?;
-        Ok(self.new_transaction(timestamp, TransactionOptions::new_optimistic()))
+        let timestamp = self.begin_timestamp().await?;
+        let options = self.default_options(TransactionOptions::new_optimistic());
+        Ok(self.new_transaction(timestamp, options))
     }
 
     /// Creates a new optimistic [`Transaction`] with snapshot timestamp.
@@ -143,8 +416,12 @@ impl Client {
     /// Write operations do not lock data in TiKV, thus the commit request may fail due to a write
     /// conflict.
     pub fn begin_optimistic_snapshot(&self, timestamp: Timestamp) -> Result<Transaction> {
-        debug!(self.logger, "creating new optimistic transaction with timestamp");
-        Ok(self.new_transaction(timestamp, TransactionOptions::new_optimistic()))
+        debug!(
+            self.logger,
+            "creating new optimistic transaction with timestamp"
+        );
+        let options = self.default_options(TransactionOptions::new_optimistic());
+        Ok(self.new_transaction(timestamp, options))
     }
 
     /// Creates a new pessimistic [`Transaction`] with snapshot timestamp.
@@ -152,8 +429,12 @@ impl Client {
     /// Write operations will lock the data until committed, thus commit requests should not suffer
     /// from write conflicts.
     pub fn begin_pessimistic_snapshot(&self, timestamp: Timestamp) -> Result<Transaction> {
-        debug!(self.logger, "creating new pessimistic transaction with timestamp");
-        Ok(self.new_transaction(timestamp, TransactionOptions::new_pessimistic()))
+        debug!(
+            self.logger,
+            "creating new pessimistic transaction with timestamp"
+        );
+        let options = self.default_options(TransactionOptions::new_pessimistic());
+        Ok(self.new_transaction(timestamp, options))
     }
 
     /// Creates a new pessimistic [`Transaction`].
@@ -177,8 +458,9 @@ impl Client {
     /// ```
     pub async fn begin_pessimistic(&self) -> Result<Transaction> {
         debug!(self.logger, "creating new pessimistic transaction");
-        let timestamp = self.current_timestamp().await?;
-        Ok(self.new_transaction(timestamp, TransactionOptions::new_pessimistic()))
+        let timestamp = self.begin_timestamp().await?;
+        let options = self.default_options(TransactionOptions::new_pessimistic());
+        Ok(self.new_transaction(timestamp, options))
     }
 
     /// Create a new customized [`Transaction`].
@@ -202,10 +484,146 @@ impl Client {
     /// ```
     pub async fn begin_with_options(&self, options: TransactionOptions) -> Result<Transaction> {
         debug!(self.logger, "creating new customized transaction");
-        let timestamp = self.current_timestamp().await?;
+        let timestamp = self.begin_timestamp().await?;
         Ok(self.new_transaction(timestamp, options))
     }
 
+    /// Atomically read `keys`, compute their new values with `f` and write them, in a pessimistic
+    /// transaction, see [`Transaction::update`]. Returns the commit timestamp, if anything was
+    /// written.
+    ///
+    /// If the transaction fails because of a conflict with another transaction, it is rolled
+    /// back and run again with the values written by the other transaction, so `f` may be called
+    /// several times.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # use tikv_client::TransactionClient;
+    /// # futures::executor::block_on(async {
+    /// # let client = TransactionClient::new(vec!["192.168.0.100"], None).await.unwrap();
+    /// // increment a counter
+    /// client
+    ///     .rmw(vec!["counter".to_owned()], |values| {
+    ///         let count = values[0].as_ref().map_or(0, |v| v[0]);
+    ///         vec![Some(vec![count + 1])]
+    ///     })
+    ///     .await
+    ///     .unwrap();
+    /// # });
+    /// ```
+    pub async fn rmw(
+        &self,
+        keys: impl IntoIterator<Item = impl Into<Key>>,
+        mut f: impl FnMut(Vec<Option<Value>>) -> Vec<Option<Value>>,
+    ) -> Result<Option<Timestamp>> {
+        debug!(self.logger, "invoking read-modify-write");
+        let keys: Vec<Key> = keys.into_iter().map(Into::into).collect();
+        let mut backoff = OPTIMISTIC_BACKOFF;
+        loop {
+            let mut txn = self.begin_pessimistic().await?;
+            let result = match txn.update(keys.clone(), &mut f).await {
+                Ok(()) => txn.commit().await,
+                Err(e) => Err(e),
+            };
+            let e = match result {
+                Ok(commit_ts) => return Ok(commit_ts),
+                // the transaction may have been committed, it must not be run again
+                Err(e @ Error::UndeterminedError(_)) => return Err(e),
+                Err(e) => e,
+            };
+            if let Err(rollback_err) = txn.rollback().await {
+                debug!(
+                    self.logger,
+                    "failed to roll back read-modify-write: {}", rollback_err
+                );
+            }
+            match backoff.next_delay_duration() {
+                Some(delay) if is_lock_conflict(&e) => {
+                    debug!(self.logger, "read-modify-write conflicted, retrying"; "error" => ?e);
+                    self.pd.clock().sleep(delay).await;
+                }
+                _ => return Err(e),
+            }
+        }
+    }
+
+    /// Run `f` in a new optimistic transaction and commit it, running it again in a new
+    /// transaction if it conflicts with another transaction. Returns the result of `f`.
+    ///
+    /// If `f` or the commit fails because of a conflict, e.g. a
+    /// [`WriteConflict`](Error::WriteConflict) or a lock which could not be resolved, the
+    /// transaction is rolled back and `f` is run again in a transaction with a new start
+    /// timestamp, after a backoff, up to 10 times. Other errors, including the errors returned by
+    /// `f`, are returned once the transaction is rolled back. `f` should thus have no effect
+    /// besides the operations on the transaction. If whether the transaction committed is
+    /// [undetermined](Error::UndeterminedError), it is not run again.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # use tikv_client::{Result, TransactionClient};
+    /// # futures::executor::block_on(async {
+    /// # let client = TransactionClient::new(vec!["192.168.0.100"], None).await.unwrap();
+    /// // move a balance between two accounts
+    /// let moved = client
+    ///     .with_transaction(|txn| {
+    ///         Box::pin(async move {
+    ///             let balance = txn.get("alice".to_owned()).await?.unwrap_or_default();
+    ///             txn.put("bob".to_owned(), balance.clone()).await?;
+    ///             txn.delete("alice".to_owned()).await?;
+    ///             Result::Ok(balance.len())
+    ///         })
+    ///     })
+    ///     .await
+    ///     .unwrap();
+    /// # });
+    /// ```
+    pub async fn with_transaction<T, F>(&self, f: F) -> Result<T>
+    where
+        F: for<'a> FnMut(&'a mut Transaction) -> BoxFuture<'a, Result<T>>,
+    {
+        let options = self.default_options(TransactionOptions::new_optimistic());
+        self.with_transaction_options(options, f).await
+    }
+
+    /// Like [`with_transaction`](Client::with_transaction), with transactions created with
+    /// `options`, see [`begin_with_options`](Client::begin_with_options).
+    pub async fn with_transaction_options<T, F>(
+        &self,
+        options: TransactionOptions,
+        mut f: F,
+    ) -> Result<T>
+    where
+        F: for<'a> FnMut(&'a mut Transaction) -> BoxFuture<'a, Result<T>>,
+    {
+        debug!(self.logger, "invoking with_transaction");
+        let mut backoff = OPTIMISTIC_BACKOFF;
+        loop {
+            let mut txn = self.begin_with_options(options.clone()).await?;
+            let result = match f(&mut txn).await {
+                Ok(value) => txn.commit().await.map(|_| value),
+                Err(e) => Err(e),
+            };
+            let e = match result {
+                Ok(value) => return Ok(value),
+                // the transaction may have been committed, it must not be run again
+                Err(e @ Error::UndeterminedError(_)) => return Err(e),
+                Err(e) => e,
+            };
+            if let Err(rollback_err) = txn.rollback().await {
+                debug!(self.logger, "failed to roll back: {}", rollback_err);
+            }
+            match backoff.next_delay_duration() {
+                Some(delay) if is_txn_conflict(&e) => {
+                    debug!(self.logger, "transaction conflicted, retrying"; "error" => ?e);
+                    self.pd.clock().sleep(delay).await;
+                }
+                _ => return Err(e),
+            }
+        }
+    }
+
     /// Create a new [`Snapshot`](Snapshot) at the given [`Timestamp`](Timestamp).
     pub fn snapshot(&self, timestamp: Timestamp, options: TransactionOptions) -> Snapshot {
         debug!(self.logger, "creating new snapshot");
@@ -213,6 +631,122 @@ impl Client {
         Snapshot::new(self.new_transaction(timestamp, options.read_only()), logger)
     }
 
+    /// Create a new [`Snapshot`] for reading data which is at most `max_staleness` old.
+    ///
+    /// The snapshot reads at the current timestamp minus `max_staleness`. Its reads are stale
+    /// reads, which can be served by any replica that has caught up with that timestamp, not just
+    /// by the leader. Replicas on stores in the zone set by
+    /// [`Config::with_zone`](crate::Config::with_zone) are preferred, so reads usually do not leave
+    /// the zone of the client. If a replica is not caught up, the read is retried after a backoff.
+    ///
+    /// No transaction can hold locks on data older than the timestamps replicas have caught up
+    /// with, so a lock encountered by a stale read is returned as an error rather than resolved.
+    ///
+    /// See also [`snapshot_stale`](Client::snapshot_stale), which reads at a given timestamp.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # use tikv_client::{Config, TransactionClient};
+    /// # use futures::prelude::*;
+    /// # use std::time::Duration;
+    /// # futures::executor::block_on(async {
+    /// let client = TransactionClient::new(vec!["192.168.0.100"], None)
+    ///     .await
+    ///     .unwrap();
+    /// let mut snapshot = client.stale_snapshot(Duration::from_secs(5)).await.unwrap();
+    /// let value = snapshot.get("key".to_owned()).await.unwrap();
+    /// # });
+    /// ```
+    pub async fn stale_snapshot(&self, max_staleness: Duration) -> Result<Snapshot> {
+        debug!(self.logger, "creating new stale snapshot");
+        let current = self.current_timestamp().await?;
+        let timestamp = Timestamp {
+            physical: (current.physical - max_staleness.as_millis() as i64).max(0),
+            ..Default::default()
+        };
+        Ok(self.snapshot_stale(timestamp))
+    }
+
+    /// Create a new [`Snapshot`] for reading the newest data which replicas can serve without
+    /// waiting, as long as it is at most `max_staleness` old.
+    ///
+    /// Unlike a [`stale_snapshot`](Client::stale_snapshot), which always reads `max_staleness`
+    /// into the past, the snapshot reads at the newest timestamp all TiKV stores have caught up
+    /// with, as reported by each store, if that is within `max_staleness`. Otherwise it reads at
+    /// the current timestamp minus `max_staleness`, like a stale snapshot. The reads of the
+    /// snapshot are stale reads like those of a stale snapshot.
+    ///
+    /// Creating the snapshot sends a request to each store, so it is more expensive than creating
+    /// a stale snapshot.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # use tikv_client::TransactionClient;
+    /// # use std::time::Duration;
+    /// # futures::executor::block_on(async {
+    /// let client = TransactionClient::new(vec!["192.168.0.100"], None).await.unwrap();
+    /// let mut snapshot = client
+    ///     .snapshot_with_max_staleness(Duration::from_secs(5))
+    ///     .await
+    ///     .unwrap();
+    /// let value = snapshot.get("key".to_owned()).await.unwrap();
+    /// # });
+    /// ```
+    pub async fn snapshot_with_max_staleness(&self, max_staleness: Duration) -> Result<Snapshot> {
+        debug!(self.logger, "creating new bounded stale snapshot");
+        let current = self.current_timestamp().await?;
+        let timestamp = bounded_stale_timestamp(
+            self.pd.clone(),
+            current,
+            max_staleness,
+            self.metrics_labels.clone(),
+        )
+        .await?;
+        Ok(self.snapshot_stale(timestamp))
+    }
+
+    /// Create a new [`Snapshot`] whose reads are stale reads at `timestamp`.
+    ///
+    /// Like the reads of a [`stale_snapshot`](Client::stale_snapshot), the reads can be served by
+    /// any replica which has caught up with `timestamp`, and a lock encountered by a read is
+    /// returned as an error rather than resolved. If a replica does not catch up before the
+    /// retries of a read are exhausted, e.g. because `timestamp` is too recent, the read fails
+    /// with [`DataIsNotReady`](Error::DataIsNotReady), wrapped in a
+    /// [`RequestFailed`](Error::RequestFailed) error.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # use tikv_client::TransactionClient;
+    /// # futures::executor::block_on(async {
+    /// let client = TransactionClient::new(vec!["192.168.0.100"], None).await.unwrap();
+    /// let timestamp = client.current_timestamp().await.unwrap();
+    /// // ... later, read the data as of `timestamp` from the closest replica
+    /// let mut snapshot = client.snapshot_stale(timestamp);
+    /// let value = snapshot.get("key".to_owned()).await.unwrap();
+    /// # });
+    /// ```
+    pub fn snapshot_stale(&self, timestamp: Timestamp) -> Snapshot {
+        debug!(self.logger, "creating new stale snapshot at a timestamp");
+        let options = self
+            .default_options(TransactionOptions::new_optimistic())
+            .read_only()
+            .read_options(ReadOptions::new().lock_policy(LockPolicy::FailFast))
+            .inherit_metrics_labels(&self.metrics_labels)
+            .inherit_request_tags(self.priority, &self.resource_group_tag);
+        let logger = self.logger.new(o!("child" => 1));
+        let transaction = Transaction::new(
+            timestamp,
+            Arc::new(self.pd.with_stale_read()),
+            options,
+            self.logger.new(o!("child" => 1)),
+        )
+        .with_namespace(self.namespace.clone());
+        Snapshot::new(transaction, logger)
+    }
+
     /// Retrieve the current [`Timestamp`].
     ///
     /// # Examples
@@ -231,6 +765,463 @@ impl Client {
         self.pd.clone().get_timestamp().await
     }
 
+    /// List the members of the PD cluster.
+    ///
+    /// The result tells which member is the leader and whether each member could be reached.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # use tikv_client::{Config, TransactionClient};
+    /// # use futures::prelude::*;
+    /// # futures::executor::block_on(async {
+    /// let client = TransactionClient::new(vec!["192.168.0.100"], None)
+    ///     .await
+    ///     .unwrap();
+    /// let members = client.pd_members().await.unwrap();
+    /// # });
+    /// ```
+    pub async fn pd_members(&self) -> Result<Vec<PdMember>> {
+        self.pd.clone().members().await
+    }
+
+    /// The disk usage and the number of regions of the stores of the cluster, as last reported to
+    /// PD, see [`StoreStats`].
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # use tikv_client::{Config, TransactionClient};
+    /// # use futures::prelude::*;
+    /// # futures::executor::block_on(async {
+    /// let client = TransactionClient::new(vec!["192.168.0.100"], None)
+    ///     .await
+    ///     .unwrap();
+    /// let stats = client.store_stats().await.unwrap();
+    /// let almost_full = stats.iter().any(|store| store.available_ratio() < 0.1);
+    /// # });
+    /// ```
+    pub async fn store_stats(&self) -> Result<Vec<StoreStats>> {
+        fetch_store_stats(self.pd.clone()).await
+    }
+
+    /// Look up the region with `id` in PD, e.g. to find the keys of a region referenced by the
+    /// logs of TiKV. The cached entry of the region is refreshed with the result.
+    ///
+    /// The keys of the region are decoded, i.e. they are keys as used by transactions.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # use tikv_client::{Config, TransactionClient};
+    /// # use futures::prelude::*;
+    /// # futures::executor::block_on(async {
+    /// let client = TransactionClient::new(vec!["192.168.0.100"], None)
+    ///     .await
+    ///     .unwrap();
+    /// let region = client.region_by_id(42).await.unwrap();
+    /// let (start_key, end_key) = region.range();
+    /// # });
+    /// ```
+    pub async fn region_by_id(&self, id: RegionId) -> Result<RegionWithLeader> {
+        self.pd.refresh_region_for_id(id).await
+    }
+
+    /// The region which contains `key`, as cached by the client, or loaded from PD if it isn't
+    /// cached. Besides its peers, the region holds the states the client learned about it, e.g.
+    /// whether some of its peers are down or whether it is being merged, so that requests can be
+    /// routed around it, see [`RegionWithLeader`].
+    ///
+    /// The keys of the region are decoded, i.e. they are keys as used by transactions.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # use tikv_client::{Config, TransactionClient};
+    /// # use futures::prelude::*;
+    /// # futures::executor::block_on(async {
+    /// let client = TransactionClient::new(vec!["192.168.0.100"], None)
+    ///     .await
+    ///     .unwrap();
+    /// let region = client.region_for_key("TiKV".to_owned()).await.unwrap();
+    /// if region.merging || region.has_unavailable_peers() {
+    ///     // defer the writes to the region
+    /// }
+    /// # });
+    /// ```
+    pub async fn region_for_key(&self, key: impl Into<Key>) -> Result<RegionWithLeader> {
+        let key = match &self.namespace {
+            Some(namespace) => namespace.encode_key(key.into()),
+            None => key.into(),
+        };
+        self.pd.region_for_key(&key).await
+    }
+
+    /// Find a key written by the transaction started at `start_ts` and read its MVCC history,
+    /// `None` if the transaction wrote no key whose history was not garbage collected.
+    ///
+    /// Every region is asked for the keys of the transaction, and the first of them is returned,
+    /// so this is a debugging tool, e.g. to find the primary key of a transaction from the start
+    /// timestamp of one of its locks. See
+    /// [`Transaction::get_all_versions`](crate::Transaction::get_all_versions) to read the history
+    /// of a given key.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # use tikv_client::{Config, TransactionClient};
+    /// # use futures::prelude::*;
+    /// # futures::executor::block_on(async {
+    /// # let client = TransactionClient::new(vec!["192.168.0.100"], None).await.unwrap();
+    /// # let start_ts = client.current_timestamp().await.unwrap();
+    /// if let Some((key, history)) = client.get_by_start_ts(start_ts).await.unwrap() {
+    ///     let primary = history.lock.map(|lock| lock.primary);
+    /// }
+    /// # });
+    /// ```
+    pub async fn get_by_start_ts(&self, start_ts: Timestamp) -> Result<Option<(Key, MvccInfo)>> {
+        debug!(self.logger, "invoking get_by_start_ts request");
+        let request = new_mvcc_get_by_start_ts_request(start_ts);
+        let plan = PlanBuilder::new(self.pd.clone(), request)
+            .labels(self.metrics_labels.clone())
+            .retry_multi_region(DEFAULT_REGION_BACKOFF)
+            .merge(Collect)
+            .plan();
+        let found = plan.execute().await?;
+        Ok(match (found, &self.namespace) {
+            (Some((key, info)), Some(namespace)) => {
+                // the transaction wrote in another namespace
+                namespace.decode_key(key).ok().map(|key| (key, info))
+            }
+            (found, _) => found,
+        })
+    }
+
+    /// Delete all keys starting with `prefix` in optimistic transactions.
+    ///
+    /// Unlike a range deletion, the keys are deleted transactionally: concurrent transactions
+    /// conflict with the deletion and older snapshots can still read the deleted keys. The cost is
+    /// that every key is scanned and written. The keys are deleted in batches of
+    /// [`PURGE_BATCH_SIZE`](crate::PURGE_BATCH_SIZE) keys like a [`purge`](Client::purge), each in
+    /// its own transaction, so a large prefix doesn't exceed the transaction size limit of TiKV,
+    /// but a failed deletion may have deleted some of the keys. [`RawClient::delete_prefix`](crate::RawClient::delete_prefix)
+    /// deletes raw keys without scanning them.
+    ///
+    /// Returns [`EmptyPrefix`](crate::Error::EmptyPrefix) if `prefix` is empty, rather than
+    /// deleting all keys.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # use tikv_client::{Config, TransactionClient};
+    /// # use futures::prelude::*;
+    /// # futures::executor::block_on(async {
+    /// let client = TransactionClient::new(vec!["192.168.0.100"], None)
+    ///     .await
+    ///     .unwrap();
+    /// let summary = client.delete_prefix("user/42/".to_owned()).await.unwrap();
+    /// # });
+    /// ```
+    pub async fn delete_prefix(&self, prefix: impl Into<Key>) -> Result<DeleteSummary> {
+        debug!(self.logger, "invoking transactional delete_prefix request");
+        let range = BoundRange::non_empty_prefix(prefix)?;
+        let regions = self.count_regions(range.clone()).await?;
+
+        let mut keys = 0;
+        delete_in_batches(
+            || self.begin_optimistic(),
+            range,
+            &PurgeOptions::default(),
+            self.pd.clock(),
+            |count, _| keys += count as u64,
+        )
+        .await?;
+        Ok(DeleteSummary {
+            keys: Some(keys),
+            regions,
+        })
+    }
+
+    /// Delete all keys in `ranges`, e.g. all data of a user who asked to be forgotten, reporting
+    /// the progress of the deletion and throttling it as set by `options`.
+    ///
+    /// The ranges are purged one after the other. The keys of a range are deleted
+    /// transactionally in batches, each deleted by its own optimistic transaction, so concurrent
+    /// transactions conflict with the deletion of a batch rather than of the whole range, and the
+    /// purge is not limited by the transaction size limit of TiKV. With
+    /// [`destroy_ranges_spanning`](PurgeOptions::destroy_ranges_spanning), the ranges spanning
+    /// many regions are destroyed at once instead.
+    ///
+    /// The purge is not atomic: if it fails, e.g. because a batch conflicted with another
+    /// transaction, the keys deleted so far stay deleted, and running it again deletes the rest.
+    /// Returns the progress of the purge once it is done.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # use tikv_client::{BoundRange, PurgeOptions, TransactionClient};
+    /// # use futures::prelude::*;
+    /// # futures::executor::block_on(async {
+    /// let client = TransactionClient::new(vec!["192.168.0.100"], None)
+    ///     .await
+    ///     .unwrap();
+    /// let ranges = vec![
+    ///     BoundRange::prefix("user/42/".to_owned()),
+    ///     BoundRange::prefix("order/user/42/".to_owned()),
+    /// ];
+    /// let options = PurgeOptions::new()
+    ///     .max_keys_per_sec(10_000)
+    ///     .progress(|progress| println!("deleted {} keys", progress.keys_deleted));
+    /// let progress = client.purge(ranges, options).await.unwrap();
+    /// # });
+    /// ```
+    pub async fn purge(
+        &self,
+        ranges: impl IntoIterator<Item = impl Into<BoundRange>>,
+        options: PurgeOptions,
+    ) -> Result<PurgeProgress> {
+        debug!(self.logger, "invoking transactional purge request");
+        let ranges: Vec<BoundRange> = ranges.into_iter().map(Into::into).collect();
+        let mut progress = PurgeProgress {
+            ranges_total: ranges.len(),
+            ..Default::default()
+        };
+        let report = |progress: &PurgeProgress| {
+            if let Some(callback) = &options.progress {
+                callback(progress);
+            }
+        };
+        for range in ranges {
+            let destroy = match options.destroy_min_regions {
+                Some(min_regions) => self.count_regions(range.clone()).await? >= min_regions,
+                None => false,
+            };
+            if destroy {
+                self.unsafe_destroy_range(range).await?;
+                progress.ranges_destroyed += 1;
+            } else {
+                delete_in_batches(
+                    || self.begin_optimistic(),
+                    range,
+                    &options,
+                    self.pd.clock(),
+                    |count, last| {
+                        progress.keys_deleted += count as u64;
+                        progress.current_key = Some(last);
+                        report(&progress);
+                    },
+                )
+                .await?;
+            }
+            progress.ranges_completed += 1;
+            progress.current_key = None;
+            report(&progress);
+        }
+        Ok(progress)
+    }
+
+    /// Export the pairs in `range` as of `timestamp` to `sink`, returning the number of pairs
+    /// exported.
+    ///
+    /// All pairs are read at `timestamp`, so the export is a consistent view of the range, however
+    /// long it takes. The range is scanned region by region in batches of
+    /// [`EXPORT_BATCH_SIZE`] pairs, the next batch being fetched while the sink writes the current
+    /// one, and the batches are written to the sink in the order of their keys.
+    ///
+    /// Like the reads of a [stale snapshot](Client::stale_snapshot), the scans are stale reads,
+    /// which can be served by any replica that has caught up with `timestamp`, and fail on locks.
+    /// `timestamp` should therefore be a few seconds old, and newer than the
+    /// [GC safepoint](Client::watch_gc_safepoint) until the export finishes.
+    ///
+    /// Raw keys are not versioned, so they cannot be exported as of a timestamp.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # use tikv_client::{Config, KvPair, Timestamp, TransactionClient};
+    /// # use futures::prelude::*;
+    /// # futures::executor::block_on(async {
+    /// let client = TransactionClient::new(vec!["192.168.0.100"], None)
+    ///     .await
+    ///     .unwrap();
+    /// # let timestamp = Timestamp::default();
+    /// let mut pairs: Vec<KvPair> = Vec::new();
+    /// let exported = client
+    ///     .export("TiDB".to_owned().."TiKV".to_owned(), timestamp, &mut pairs)
+    ///     .await
+    ///     .unwrap();
+    /// # });
+    /// ```
+    pub async fn export(
+        &self,
+        range: impl Into<BoundRange>,
+        timestamp: Timestamp,
+        sink: &mut dyn ExportSink,
+    ) -> Result<u64> {
+        debug!(self.logger, "invoking transactional export request");
+        write_batches(self.stale_scan(range.into(), timestamp), sink).await
+    }
+
+    /// The keys in `range` whose values differ between the snapshots at `from` and at `to`, with
+    /// their value in each snapshot, ordered by key.
+    ///
+    /// Both snapshots are scanned like by [`export`](Client::export), and the scans are merged
+    /// as they progress, so the cost is that of exporting the range twice, however few keys
+    /// changed. Both timestamps should be a few seconds old and newer than the GC safepoint. The
+    /// stream ends after the first error.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # use tikv_client::{Timestamp, TransactionClient};
+    /// # use futures::prelude::*;
+    /// # futures::executor::block_on(async {
+    /// let client = TransactionClient::new(vec!["192.168.0.100"], None)
+    ///     .await
+    ///     .unwrap();
+    /// # let (from, to) = (Timestamp::default(), Timestamp::default());
+    /// let mut diffs = client.diff("TiDB".to_owned().."TiKV".to_owned(), from, to);
+    /// while let Some(diff) = diffs.try_next().await.unwrap() {
+    ///     match (diff.old, diff.new) {
+    ///         (None, Some(_)) => println!("inserted {:?}", diff.key),
+    ///         (Some(_), None) => println!("deleted {:?}", diff.key),
+    ///         _ => println!("updated {:?}", diff.key),
+    ///     }
+    /// }
+    /// # });
+    /// ```
+    pub fn diff(
+        &self,
+        range: impl Into<BoundRange>,
+        from: Timestamp,
+        to: Timestamp,
+    ) -> BoxStream<'static, Result<KeyDiff>> {
+        debug!(self.logger, "invoking transactional diff request");
+        let range = range.into();
+        diff_pairs(
+            self.stale_scan(range.clone(), from),
+            self.stale_scan(range, to),
+        )
+    }
+
+    /// Evaluate a coprocessor `request` on the data as of `timestamp`, returning the result of
+    /// each region of its ranges, in the order of the regions.
+    ///
+    /// The request is sent to the leaders of the regions, up to 16 of them at once. Region errors
+    /// are retried on the regions they split into, and the locks older than `timestamp` are
+    /// resolved like by the reads of a [`Snapshot`]. The stream ends after the first error.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # use tikv_client::{CoprocessorRequest, CoprocessorRequestType, TransactionClient};
+    /// # use futures::prelude::*;
+    /// # futures::executor::block_on(async {
+    /// let client = TransactionClient::new(vec!["192.168.0.100"], None)
+    ///     .await
+    ///     .unwrap();
+    /// let timestamp = client.current_timestamp().await.unwrap();
+    /// # let dag: Vec<u8> = Vec::new();
+    /// // `dag` is a serialized `tipb::DagRequest`
+    /// let request = CoprocessorRequest::new(
+    ///     CoprocessorRequestType::Dag,
+    ///     dag,
+    ///     vec!["t1_r".to_owned().."t1_s".to_owned()],
+    /// );
+    /// let mut responses = client.coprocessor(request, timestamp);
+    /// while let Some(response) = responses.try_next().await.unwrap() {
+    ///     println!("region {}: {} bytes", response.region_id, response.data.len());
+    /// }
+    /// # });
+    /// ```
+    pub fn coprocessor(
+        &self,
+        request: CoprocessorRequest,
+        timestamp: Timestamp,
+    ) -> BoxStream<'static, Result<CoprocessorResponse>> {
+        debug!(self.logger, "invoking coprocessor request");
+        coprocessor_stream(
+            self.pd.clone(),
+            request,
+            timestamp,
+            self.metrics_labels.clone(),
+        )
+    }
+
+    /// Compute the checksum of the pairs in `range` as of `timestamp` in TiKV, e.g. to verify a
+    /// migration between clusters.
+    ///
+    /// Each region computes the checksum of its part of the range with a coprocessor checksum
+    /// request, and the checksums of the regions are combined. The checksum is computed like
+    /// [`RawClient::checksum`](crate::RawClient::checksum), so the checksum of pairs read by the
+    /// client can be computed with [`RawChecksum::of_pairs`].
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # use tikv_client::TransactionClient;
+    /// # use futures::prelude::*;
+    /// # futures::executor::block_on(async {
+    /// let client = TransactionClient::new(vec!["192.168.0.100"], None)
+    ///     .await
+    ///     .unwrap();
+    /// let timestamp = client.current_timestamp().await.unwrap();
+    /// let checksum = client
+    ///     .checksum("TiDB".to_owned().."TiKV".to_owned(), timestamp)
+    ///     .await
+    ///     .unwrap();
+    /// println!("{} pairs, {} bytes", checksum.total_kvs, checksum.total_bytes);
+    /// # });
+    /// ```
+    pub async fn checksum(
+        &self,
+        range: impl Into<BoundRange>,
+        timestamp: Timestamp,
+    ) -> Result<RawChecksum> {
+        debug!(self.logger, "invoking transactional checksum request");
+        let request = new_checksum_request(self.encode_range(range.into()));
+        self.coprocessor(request, timestamp)
+            .and_then(|response| future::ready(decode_checksum(response)))
+            .try_fold(RawChecksum::default(), |checksum, region_checksum| {
+                future::ready(Ok(checksum ^ region_checksum))
+            })
+            .await
+    }
+
+    /// Watch the GC safepoint of the cluster.
+    ///
+    /// The returned stream yields the current safepoint, then each new safepoint as GC advances,
+    /// checking PD every `interval`. Long-running readers can use it to abort or take a new
+    /// snapshot before data at their read timestamp is garbage collected.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # use tikv_client::{Config, TransactionClient, TimestampExt};
+    /// # use futures::prelude::*;
+    /// # use std::time::Duration;
+    /// # futures::executor::block_on(async {
+    /// let client = TransactionClient::new(vec!["192.168.0.100"], None)
+    ///     .await
+    ///     .unwrap();
+    /// let read_ts = client.current_timestamp().await.unwrap();
+    /// let mut safepoints = client.watch_gc_safepoint(Duration::from_secs(10));
+    /// while let Some(safepoint) = safepoints.try_next().await.unwrap() {
+    ///     if safepoint.version() >= read_ts.version() {
+    ///         // data at `read_ts` may have been garbage collected
+    ///         break;
+    ///     }
+    /// }
+    /// # });
+    /// ```
+    pub fn watch_gc_safepoint(&self, interval: Duration) -> BoxStream<'static, Result<Timestamp>> {
+        self.pd
+            .clone()
+            .watch_gc_safepoint(interval)
+            .map_ok(Timestamp::from_version)
+            .boxed()
+    }
+
     /// Request garbage collection (GC) of the TiKV cluster.
     ///
     /// GC deletes MVCC records whose timestamp is lower than the given `safepoint`. We must guarantee
@@ -244,52 +1235,344 @@ impl Client {
     /// 2. updating PD's known safepoint
     ///
     /// This is a simplified version of [GC in TiDB](https://docs.pingcap.com/tidb/stable/garbage-collection-overview).
-    /// We skip the second step "delete ranges" which is an optimization for TiDB.
+    /// The second step "delete ranges", which is an optimization for TiDB, is performed by
+    /// [`gc_with_destroy_ranges`](Client::gc_with_destroy_ranges).
     pub async fn gc(&self, safepoint: Timestamp) -> Result<bool> {
+        self.gc_with_destroy_ranges(safepoint, Vec::<BoundRange>::new())
+            .await
+    }
+
+    /// Like [`gc`](Client::gc), but also destroys the data in `destroy_ranges` with
+    /// [`unsafe_destroy_range`](Client::unsafe_destroy_range) after the locks are resolved and
+    /// before the safepoint is updated, like TiDB deletes the ranges of dropped tables.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # use tikv_client::TransactionClient;
+    /// # futures::executor::block_on(async {
+    /// let client = TransactionClient::new(vec!["192.168.0.100"], None)
+    ///     .await
+    ///     .unwrap();
+    /// # let safepoint = client.current_timestamp().await.unwrap();
+    /// // the keys of a dropped table
+    /// let dropped = vec!["t1_".to_owned().."t2_".to_owned()];
+    /// client.gc_with_destroy_ranges(safepoint, dropped).await.unwrap();
+    /// # });
+    /// ```
+    pub async fn gc_with_destroy_ranges(
+        &self,
+        safepoint: Timestamp,
+        destroy_ranges: impl IntoIterator<Item = impl Into<BoundRange>>,
+    ) -> Result<bool> {
         debug!(self.logger, "invoking transactional gc request");
-        // scan all locks with ts <= safepoint
-        let mut locks: Vec<kvrpcpb::LockInfo> = vec![];
-        let mut start_key = vec![];
-        loop {
-            let req = new_scan_lock_request(
-                mem::take(&mut start_key),
-                safepoint.version(),
-                SCAN_LOCK_BATCH_SIZE,
-            );
+        gc::gc(
+            self.pd.clone(),
+            safepoint,
+            destroy_ranges
+                .into_iter()
+                .map(|range| self.encode_range(range.into()))
+                .collect(),
+            self.metrics_labels.clone(),
+            &self.logger,
+        )
+        .await
+    }
 
-            let plan = crate::request::PlanBuilder::new(self.pd.clone(), req)
-                .resolve_lock(OPTIMISTIC_BACKOFF)
-                .retry_multi_region(DEFAULT_REGION_BACKOFF)
-                .merge(crate::request::Collect)
-                .plan();
-            let res: Vec<kvrpcpb::LockInfo> = plan.execute().await?;
+    /// Destroy all data in `range` at once in all TiKV stores, including all MVCC versions and
+    /// locks.
+    ///
+    /// The data is deleted from the storage engine directly, bypassing transactions and Raft, so
+    /// it must no longer be read or written, e.g. because its table was dropped. Reads of the
+    /// range at any timestamp see inconsistent data during and after the deletion.
+    pub async fn unsafe_destroy_range(&self, range: impl Into<BoundRange>) -> Result<()> {
+        debug!(self.logger, "invoking unsafe_destroy_range request");
+        let range = self.encode_range(range.into());
+        gc::unsafe_destroy_range(self.pd.clone(), range, self.metrics_labels.clone()).await
+    }
 
-            if res.is_empty() {
-                break;
-            }
-            start_key = res.last().unwrap().key.clone();
-            start_key.push(0);
-            locks.extend(res);
-        }
+    /// Load `pairs` in bulk through the `ImportSST` service of the stores, instead of prewriting
+    /// and committing them, which is orders of magnitude too slow for tens of gigabytes.
+    ///
+    /// The pairs must be sorted by key, without duplicate keys. They are imported in chunks of
+    /// [`region_size`](ImportOptions::region_size) bytes, region by region: each store holding a
+    /// replica of the region builds SST files from the pairs it receives, and the leader of the
+    /// region then ingests them. By default, the regions are split at the start of each chunk and
+    /// scattered across the stores first, see [`split_regions`](ImportOptions::split_regions).
+    ///
+    /// The pairs are committed at a timestamp fetched from PD when the import starts. The import
+    /// bypasses transactions: it overwrites the values of existing keys regardless of their locks,
+    /// and it is not atomic, the regions ingested before a failure keep their pairs. It should
+    /// only load ranges which are neither read nor written until it completes.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # use tikv_client::{ImportOptions, KvPair, TransactionClient};
+    /// # use futures::prelude::*;
+    /// # futures::executor::block_on(async {
+    /// let client = TransactionClient::new(vec!["192.168.0.100"], None).await.unwrap();
+    /// let pairs = (0..1_000_000u32)
+    ///     .map(|i| Ok(KvPair::new(i.to_be_bytes().to_vec(), b"value".to_vec())));
+    /// let summary = client
+    ///     .import(stream::iter(pairs), ImportOptions::new())
+    ///     .await
+    ///     .unwrap();
+    /// println!("imported {} pairs", summary.pairs);
+    /// # });
+    /// ```
+    pub async fn import(
+        &self,
+        pairs: impl Stream<Item = Result<KvPair>>,
+        options: ImportOptions,
+    ) -> Result<ImportSummary> {
+        debug!(self.logger, "invoking import request");
+        let commit_ts = self.current_timestamp().await?;
+        let namespace = self.namespace.clone();
+        let pairs = pairs.map_ok(move |KvPair(key, value)| match &namespace {
+            Some(namespace) => KvPair(namespace.encode_key(key), value),
+            None => KvPair(key, value),
+        });
+        import_pairs(self.pd.clone(), pairs, commit_ts.version(), &options).await
+    }
 
-        // resolve locks
-        // FIXME: (1) this is inefficient (2) when region error occurred
-        resolve_locks(locks, self.pd.clone()).await?;
+    /// Ingest SST files built outside of the client, like [`import`](Client::import) ingests
+    /// the files built by the stores.
+    ///
+    /// Each file must fit in a region. The files are uploaded to each store holding a replica of
+    /// their region, and then ingested by the leader of the region, one file after the other.
+    /// The keys of the files are not prefixed by the namespace of the client.
+    pub async fn ingest_sst_files(
+        &self,
+        files: Vec<SstFile>,
+        options: ImportOptions,
+    ) -> Result<ImportSummary> {
+        debug!(self.logger, "invoking ingest_sst_files request");
+        ingest_files(self.pd.clone(), files, &options).await
+    }
 
-        // update safepoint to PD
-        let res: bool = self
-            .pd
+    /// Split the regions at `split_keys` and scatter the resulting regions across the stores,
+    /// returning their ids, e.g. to pre-split a hot range before loading data into it.
+    ///
+    /// Keys which already start a region are skipped. PD moves the peers of the scattered
+    /// regions in the background, so they may still be moving when this returns.
+    ///
+    /// # Examples
+    /// ```rust,no_run
+    /// # use tikv_client::TransactionClient;
+    /// # use futures::prelude::*;
+    /// # futures::executor::block_on(async {
+    /// # let client = TransactionClient::new(vec!["192.168.0.100"], None).await.unwrap();
+    /// let split_keys = (1..16u8).map(|i| vec![i << 4]);
+    /// let regions = client.split_region_at(split_keys).await.unwrap();
+    /// # });
+    /// ```
+    pub async fn split_region_at(
+        &self,
+        split_keys: impl IntoIterator<Item = impl Into<Key>>,
+    ) -> Result<Vec<RegionId>> {
+        debug!(self.logger, "invoking split_region_at request");
+        let split_keys = split_keys.into_iter().map(|key| match &self.namespace {
+            Some(namespace) => namespace.encode_key(key.into()),
+            None => key.into(),
+        });
+        let request = new_split_region_request(split_keys, false);
+        let plan = PlanBuilder::new(self.pd.clone(), request)
+            .labels(self.metrics_labels.clone())
+            .retry_multi_region(DEFAULT_REGION_BACKOFF)
+            .merge(Collect)
+            .plan();
+        let region_ids = plan.execute().await?;
+        self.pd.clone().scatter_regions(region_ids.clone()).await?;
+        Ok(region_ids)
+    }
+
+    /// Subscribe to the committed changes of the rows in `range`, delivered as a stream of
+    /// [`ChangeEvent`]s, e.g. to replicate them downstream without running TiCDC.
+    ///
+    /// The changes are delivered in the order of their commit timestamps, followed regularly by a
+    /// resolved timestamp, before which all changes have been delivered: a downstream applying the
+    /// changes up to a resolved timestamp has a consistent snapshot of the range. The feed follows
+    /// the splits and merges of the regions of the range and the moves of their leaders. It fails
+    /// when it can't recover, e.g. when a store kept failing; it can then be resumed from the last
+    /// resolved timestamp, see [`ChangeFeedOptions::checkpoint_ts`].
+    ///
+    /// The changes are held in memory until they are resolved, so a region whose resolved
+    /// timestamp doesn't advance, e.g. because of a long running transaction, holds back the
+    /// changes of the whole range.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # use tikv_client::{ChangeEvent, ChangeFeedOptions, TransactionClient};
+    /// # use futures::prelude::*;
+    /// # futures::executor::block_on(async {
+    /// # let client = TransactionClient::new(vec!["192.168.0.100"], None).await.unwrap();
+    /// let mut changes = client
+    ///     .subscribe_changes("k1".to_owned().."k2".to_owned(), ChangeFeedOptions::new())
+    ///     .await
+    ///     .unwrap();
+    /// while let Some(event) = changes.try_next().await.unwrap() {
+    ///     match event {
+    ///         ChangeEvent::Row(change) => println!("{:?} at {}", change.op, change.commit_ts),
+    ///         ChangeEvent::Resolved(ts) => println!("resolved at {}", ts),
+    ///     }
+    /// }
+    /// # });
+    /// ```
+    pub async fn subscribe_changes(
+        &self,
+        range: impl Into<BoundRange>,
+        options: ChangeFeedOptions,
+    ) -> Result<BoxStream<'static, Result<ChangeEvent>>> {
+        debug!(self.logger, "invoking subscribe_changes request");
+        let range = self.encode_range(range.into());
+        let cluster_id = self.pd.cluster_id().await;
+        let changes = change_feed(self.pd.clone(), range, cluster_id, options).await?;
+        Ok(match self.namespace.clone() {
+            Some(namespace) => changes
+                .and_then(move |event| {
+                    future::ready(match event {
+                        ChangeEvent::Row(mut change) => {
+                            namespace.decode_key(change.key.clone()).map(|key| {
+                                change.key = key;
+                                ChangeEvent::Row(change)
+                            })
+                        }
+                        resolved => Ok(resolved),
+                    })
+                })
+                .boxed(),
+            None => changes,
+        })
+    }
+
+    /// Resolve the expired locks in `range` of transactions started before `before_ts`.
+    ///
+    /// Unlike [`gc`](Client::gc), this neither covers the whole key space nor updates the
+    /// safepoint. It clears the locks left by a writer which crashed, so that readers blocked on
+    /// them can proceed right away instead of waiting for the next GC. The TTL of a lock must
+    /// have expired for it to be resolved: a lock whose TTL has not expired may belong to a live
+    /// writer, and is left alone.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # use tikv_client::{Config, TransactionClient};
+    /// # use futures::prelude::*;
+    /// # futures::executor::block_on(async {
+    /// let client = TransactionClient::new(vec!["192.168.0.100"], None)
+    ///     .await
+    ///     .unwrap();
+    /// let now = client.current_timestamp().await.unwrap();
+    /// let summary = client
+    ///     .resolve_locks_in_range("k1".to_owned().."k2".to_owned(), now)
+    ///     .await
+    ///     .unwrap();
+    /// println!("resolved {} locks, {} are live", summary.resolved, summary.live);
+    /// # });
+    /// ```
+    pub async fn resolve_locks_in_range(
+        &self,
+        range: impl Into<BoundRange>,
+        before_ts: Timestamp,
+    ) -> Result<ResolveLocksSummary> {
+        debug!(
+            self.logger,
+            "invoking transactional resolve_locks_in_range request"
+        );
+        resolve_locks_in_range(
+            self.encode_range(range.into()),
+            before_ts.version(),
+            self.pd.clone(),
+            self.metrics_labels.clone(),
+        )
+        .await
+    }
+
+    /// Fetches the start timestamp of a new transaction, recording the wait for PD.
+    async fn begin_timestamp(&self) -> Result<Timestamp> {
+        let start = Instant::now();
+        let timestamp = self.current_timestamp().await;
+        let wait = start.elapsed();
+        observe_txn_wait("begin", "tso", wait);
+        debug!(self.logger, "fetched start timestamp"; "tso_wait" => ?wait);
+        timestamp
+    }
+
+    /// Scans `range` as of `timestamp` with stale reads, in batches of [`EXPORT_BATCH_SIZE`] pairs.
+    fn stale_scan(
+        &self,
+        range: BoundRange,
+        timestamp: Timestamp,
+    ) -> BoxStream<'static, Result<Vec<KvPair>>> {
+        let batches = scan_at(
+            Arc::new(self.pd.with_stale_read()),
+            self.encode_range(range),
+            timestamp,
+            self.metrics_labels.clone(),
+        );
+        // the keys are decoded once scanned, since the scan continues after the last key
+        match self.namespace.clone() {
+            Some(namespace) => batches
+                .and_then(move |pairs| future::ready(namespace.decode_pairs(pairs)))
+                .boxed(),
+            None => batches,
+        }
+    }
+
+    /// The number of regions `range` spans.
+    async fn count_regions(&self, range: BoundRange) -> Result<usize> {
+        self.pd
             .clone()
-            .update_safepoint(safepoint.version())
-            .await?;
-        if !res {
-            info!(self.logger, "new safepoint != user-specified safepoint");
+            .stores_for_range(self.encode_range(range))
+            .try_fold(0, |regions, _| future::ready(Ok(regions + 1)))
+            .await
+    }
+
+    fn encode_range(&self, range: BoundRange) -> BoundRange {
+        match &self.namespace {
+            Some(namespace) => namespace.encode_range(range),
+            None => range,
+        }
+    }
+
+    /// `options` with the retry options of the client, if any.
+    fn default_options(&self, options: TransactionOptions) -> TransactionOptions {
+        match &self.retry_options {
+            Some(retry_options) => options.retry_options(retry_options.clone()),
+            None => options,
         }
-        Ok(res)
     }
 
     fn new_transaction(&self, timestamp: Timestamp, options: TransactionOptions) -> Transaction {
         let logger = self.logger.new(o!("child" => 1));
-        Transaction::new(timestamp, self.pd.clone(), options, logger)
+        let options = options
+            .inherit_metrics_labels(&self.metrics_labels)
+            .inherit_request_tags(self.priority, &self.resource_group_tag);
+        let supported = options.clone().supported_by(&self.cluster_info);
+        if supported != options {
+            info!(
+                self.logger,
+                "disabled transaction features which the cluster doesn't support"
+            );
+        }
+        Transaction::new(timestamp, self.pd.clone(), supported, logger)
+            .with_heartbeat_scheduler(self.heartbeats.clone())
+            .with_namespace(self.namespace.clone())
+            .with_replica_read()
+    }
+}
+
+/// `pd` and the root namespace of the clients using it: if the keys of the clients are in a
+/// keyspace, see [`Config::with_keyspace`], requests use API V2 and the keys are in the namespace
+/// of the keyspace.
+fn in_keyspace(pd: Arc<PdRpcClient>) -> (Arc<PdRpcClient>, Option<Namespace>) {
+    match pd.keyspace() {
+        Some(keyspace) => (
+            Arc::new(pd.with_api_version(kvrpcpb::ApiVersion::V2)),
+            Some(Namespace::keyspace(keyspace)),
+        ),
+        None => (pd, None),
     }
 }
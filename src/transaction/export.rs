@@ -0,0 +1,318 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+use super::{lowering::new_scan_request, LockPolicy, EXPORT_BATCH_SIZE};
+use crate::{
+    backoff::{DEFAULT_REGION_BACKOFF, OPTIMISTIC_BACKOFF},
+    pd::PdClient,
+    request::{scan_stream, Collect, Plan, PlanBuilder, ScanPrefetch},
+    stats::MetricsLabels,
+    BoundRange, Key, KvPair, Result, Value,
+};
+use async_trait::async_trait;
+use futures::{prelude::*, stream::BoxStream};
+use std::{cmp::Ordering, sync::Arc};
+use tikv_client_proto::pdpb::Timestamp;
+
+/// The destination of an [`export`](crate::TransactionClient::export), e.g. a file or an object
+/// store.
+#[async_trait]
+pub trait ExportSink: Send {
+    /// Write a batch of pairs. Batches are written in the order of their keys, and the keys of a
+    /// batch are ordered.
+    async fn write(&mut self, pairs: Vec<KvPair>) -> Result<()>;
+
+    /// Called once after all pairs have been written, e.g. to flush buffered pairs.
+    async fn finish(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Collects the exported pairs in memory.
+#[async_trait]
+impl ExportSink for Vec<KvPair> {
+    async fn write(&mut self, pairs: Vec<KvPair>) -> Result<()> {
+        self.extend(pairs);
+        Ok(())
+    }
+}
+
+/// Scans the encoded `range` as of `timestamp` in batches of [`EXPORT_BATCH_SIZE`] pairs, failing
+/// on locks. The reads are stale reads if `pd` sends them as such.
+pub(crate) fn scan_at<PdC: PdClient>(
+    pd: Arc<PdC>,
+    range: BoundRange,
+    timestamp: Timestamp,
+    labels: MetricsLabels,
+) -> BoxStream<'static, Result<Vec<KvPair>>> {
+    scan_stream(
+        pd.clone(),
+        range,
+        EXPORT_BATCH_SIZE,
+        ScanPrefetch::default(),
+        move |range, limit| {
+            let request = new_scan_request(range, timestamp.clone(), limit, false, false);
+            let plan = PlanBuilder::new(pd.clone(), request)
+                .labels(labels.clone())
+                .resolve_lock_for_read(timestamp.clone(), LockPolicy::FailFast, OPTIMISTIC_BACKOFF)
+                .retry_multi_region(DEFAULT_REGION_BACKOFF)
+                .merge(Collect)
+                .plan();
+            async move { plan.execute().await }
+        },
+    )
+}
+
+/// Writes `batches` to `sink` in order and finishes it, returning the number of pairs written.
+/// The sink is not finished if a batch fails.
+pub(crate) async fn write_batches(
+    mut batches: BoxStream<'static, Result<Vec<KvPair>>>,
+    sink: &mut dyn ExportSink,
+) -> Result<u64> {
+    let mut written = 0;
+    while let Some(pairs) = batches.try_next().await? {
+        written += pairs.len() as u64;
+        sink.write(pairs).await?;
+    }
+    sink.finish().await?;
+    Ok(written)
+}
+
+/// The change of a key between two snapshots, see [`diff`](crate::TransactionClient::diff).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct KeyDiff {
+    pub key: Key,
+    /// The value in the older snapshot, `None` if the key was inserted.
+    pub old: Option<Value>,
+    /// The value in the newer snapshot, `None` if the key was deleted.
+    pub new: Option<Value>,
+}
+
+/// Merges the batches of pairs of two scans of the same range, ordered by key, into the keys
+/// whose values differ. The stream ends after the first error.
+pub(crate) fn diff_pairs(
+    old: BoxStream<'static, Result<Vec<KvPair>>>,
+    new: BoxStream<'static, Result<Vec<KvPair>>>,
+) -> BoxStream<'static, Result<KeyDiff>> {
+    let flatten = |batches: BoxStream<'static, Result<Vec<KvPair>>>| {
+        Box::pin(
+            batches
+                .map_ok(|pairs| stream::iter(pairs.into_iter().map(Ok)))
+                .try_flatten()
+                .peekable(),
+        )
+    };
+    stream::unfold(
+        (flatten(old), flatten(new), false),
+        |(mut old, mut new, done)| async move {
+            if done {
+                return None;
+            }
+            loop {
+                // errors are taken first
+                let order = match (old.as_mut().peek().await, new.as_mut().peek().await) {
+                    (None, None) => return None,
+                    (Some(Err(_)), _) | (Some(_), None) => Ordering::Less,
+                    (_, Some(Err(_))) | (None, Some(_)) => Ordering::Greater,
+                    (Some(Ok(old_pair)), Some(Ok(new_pair))) => old_pair.key().cmp(new_pair.key()),
+                };
+                let diff = match order {
+                    Ordering::Less => old.next().await.unwrap().map(|pair| KeyDiff {
+                        key: pair.0,
+                        old: Some(pair.1),
+                        new: None,
+                    }),
+                    Ordering::Greater => new.next().await.unwrap().map(|pair| KeyDiff {
+                        key: pair.0,
+                        old: None,
+                        new: Some(pair.1),
+                    }),
+                    Ordering::Equal => {
+                        let pairs = (old.next().await.unwrap(), new.next().await.unwrap());
+                        match pairs {
+                            (Ok(old_pair), Ok(new_pair)) if old_pair.1 == new_pair.1 => continue,
+                            (Ok(old_pair), Ok(new_pair)) => Ok(KeyDiff {
+                                key: new_pair.0,
+                                old: Some(old_pair.1),
+                                new: Some(new_pair.1),
+                            }),
+                            (Err(e), _) | (_, Err(e)) => Err(e),
+                        }
+                    }
+                };
+                let done = diff.is_err();
+                return Some((diff, (old, new, done)));
+            }
+        },
+    )
+    .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        mock::{MockKvClient, MockPdClient},
+        Error, TimestampExt,
+    };
+    use std::any::Any;
+    use tikv_client_proto::kvrpcpb;
+
+    #[tokio::test]
+    async fn test_scan_at() {
+        let pd_client = Arc::new(MockPdClient::new(MockKvClient::with_dispatch_hook(
+            |req: &dyn Any| {
+                let req = req.downcast_ref::<kvrpcpb::ScanRequest>().unwrap();
+                assert_eq!(req.version, 7);
+                assert_eq!(req.limit, EXPORT_BATCH_SIZE);
+                let pairs = [1u8, 2, 11, 12, 251]
+                    .iter()
+                    .map(|&k| vec![k])
+                    .filter(|k| *k >= req.start_key && (req.end_key.is_empty() || *k < req.end_key))
+                    .map(|k| {
+                        // key 12 is locked
+                        let error = (k == [12]).then(|| kvrpcpb::KeyError {
+                            locked: Some(kvrpcpb::LockInfo {
+                                key: k.clone(),
+                                primary_lock: k.clone(),
+                                lock_version: 5,
+                                lock_ttl: 3000,
+                                ..Default::default()
+                            }),
+                            ..Default::default()
+                        });
+                        kvrpcpb::KvPair {
+                            value: k.clone(),
+                            key: k,
+                            error,
+                        }
+                    })
+                    .collect();
+                let resp = kvrpcpb::ScanResponse {
+                    pairs,
+                    ..Default::default()
+                };
+                Ok(Box::new(resp) as Box<dyn Any>)
+            },
+        )));
+        let scan = |range: BoundRange| {
+            scan_at(
+                pd_client.clone(),
+                range,
+                Timestamp::from_version(7),
+                MetricsLabels::default(),
+            )
+        };
+
+        // each region is a batch
+        let keys = |batch: Vec<KvPair>| -> Vec<Vec<u8>> {
+            batch.into_iter().map(|p| p.into_key().into()).collect()
+        };
+        let batches: Vec<Vec<KvPair>> = scan((vec![1]..vec![12]).into())
+            .try_collect()
+            .await
+            .unwrap();
+        let batches: Vec<_> = batches.into_iter().map(keys).collect();
+        assert_eq!(batches, vec![vec![vec![1], vec![2]], vec![vec![11]]]);
+
+        // the scan fails on the lock instead of resolving it
+        let results: Vec<Result<Vec<KvPair>>> = scan((..).into()).collect().await;
+        assert!(results[0].is_ok());
+        assert!(results.last().unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_write_batches() {
+        #[derive(Default)]
+        struct RecordingSink {
+            pairs: Vec<KvPair>,
+            finished: bool,
+        }
+
+        #[async_trait]
+        impl ExportSink for RecordingSink {
+            async fn write(&mut self, pairs: Vec<KvPair>) -> Result<()> {
+                if pairs.iter().any(|pair| pair.value().is_empty()) {
+                    return Err(Error::StringError("empty value".to_owned()));
+                }
+                self.pairs.extend(pairs);
+                Ok(())
+            }
+
+            async fn finish(&mut self) -> Result<()> {
+                self.finished = true;
+                Ok(())
+            }
+        }
+
+        let pair = |key: u8, value: u8| KvPair::new(vec![key], vec![value]);
+        let mut sink = RecordingSink::default();
+        let batches = stream::iter(vec![Ok(vec![pair(1, 1), pair(2, 2)]), Ok(vec![pair(3, 3)])]);
+        assert_eq!(write_batches(batches.boxed(), &mut sink).await.unwrap(), 3);
+        assert_eq!(sink.pairs, vec![pair(1, 1), pair(2, 2), pair(3, 3)]);
+        assert!(sink.finished);
+
+        // the sink is not finished after a failed scan or write
+        let mut sink = RecordingSink::default();
+        let batches = stream::iter(vec![
+            Ok(vec![pair(1, 1)]),
+            Err(Error::StringError("scan failed".to_owned())),
+        ]);
+        assert!(write_batches(batches.boxed(), &mut sink).await.is_err());
+        assert_eq!(sink.pairs, vec![pair(1, 1)]);
+        assert!(!sink.finished);
+
+        let mut sink = RecordingSink::default();
+        let batches = stream::iter(vec![Ok(vec![KvPair::new(vec![1], vec![])])]);
+        assert!(write_batches(batches.boxed(), &mut sink).await.is_err());
+        assert!(!sink.finished);
+
+        // the pairs are collected by a vector
+        let mut pairs = Vec::new();
+        let batches = stream::iter(vec![Ok(vec![pair(1, 1)])]);
+        assert_eq!(write_batches(batches.boxed(), &mut pairs).await.unwrap(), 1);
+        assert_eq!(pairs, vec![pair(1, 1)]);
+    }
+
+    #[tokio::test]
+    async fn test_diff_pairs() {
+        let pair = |key: u8, value: u8| KvPair::new(vec![key], vec![value]);
+        let old = stream::iter(vec![
+            Ok(vec![pair(1, 1), pair(2, 2)]),
+            Ok(vec![]),
+            Ok(vec![pair(4, 4), pair(6, 6)]),
+        ])
+        .boxed();
+        let new = stream::iter(vec![
+            Ok(vec![pair(2, 2), pair(3, 3)]),
+            Ok(vec![pair(4, 5), pair(6, 6), pair(7, 7)]),
+        ])
+        .boxed();
+        let diffs: Vec<KeyDiff> = diff_pairs(old, new).try_collect().await.unwrap();
+        let diff = |key: u8, old: Option<u8>, new: Option<u8>| KeyDiff {
+            key: vec![key].into(),
+            old: old.map(|v| vec![v]),
+            new: new.map(|v| vec![v]),
+        };
+        assert_eq!(
+            diffs,
+            vec![
+                diff(1, Some(1), None),
+                diff(3, None, Some(3)),
+                diff(4, Some(4), Some(5)),
+                diff(7, None, Some(7)),
+            ]
+        );
+
+        // the diff ends after an error
+        let old = stream::iter(vec![
+            Ok(vec![pair(1, 1)]),
+            Err(Error::StringError("scan failed".to_owned())),
+        ])
+        .boxed();
+        let new = stream::iter(vec![Ok(vec![pair(1, 2), pair(2, 2)])]).boxed();
+        let results: Vec<Result<KeyDiff>> = diff_pairs(old, new).collect().await;
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].as_ref().unwrap(), &diff(1, Some(1), Some(2)));
+        assert!(results[1].is_err());
+    }
+}
@@ -0,0 +1,209 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+use crate::{BoundRange, CoprocessorRequest, CoprocessorResponse, Key, KvPair, Result};
+use regex::bytes::Regex;
+use std::{
+    fmt,
+    ops::{Bound, RangeBounds},
+    sync::Arc,
+};
+
+/// A filter of the pairs of a [`scan_filtered`](crate::Transaction::scan_filtered).
+///
+/// A pair matches if its key starts with the [`key_prefix`](ScanFilter::key_prefix), its value
+/// matches the [`value_regex`](ScanFilter::value_regex) and the length of its value is within
+/// the [`value_len`](ScanFilter::value_len) bounds. Unset conditions match any pair.
+///
+/// The key prefix narrows the range of the scan, so only the keys with the prefix are read. The
+/// conditions on values are evaluated by the coprocessor of TiKV if a [`FilterPushdown`] compiles
+/// the filter into a coprocessor request, otherwise by the client on the scanned pairs.
+///
+/// # Examples
+///
+/// ```rust
+/// # use tikv_client::{KvPair, ScanFilter};
+/// let filter = ScanFilter::new()
+///     .key_prefix("user/".to_owned())
+///     .value_regex(r#""active":true"#)
+///     .unwrap()
+///     .value_len(..1024);
+/// assert!(filter.matches(&KvPair::new("user/1".to_owned(), r#"{"active":true}"#.to_owned())));
+/// assert!(!filter.matches(&KvPair::new("team/1".to_owned(), r#"{"active":true}"#.to_owned())));
+/// ```
+#[derive(Clone, Default)]
+pub struct ScanFilter {
+    key_prefix: Option<Key>,
+    value_regex: Option<Regex>,
+    value_len: Option<(Bound<usize>, Bound<usize>)>,
+    pushdown: Option<Arc<dyn FilterPushdown>>,
+}
+
+/// Compiles [`ScanFilter`]s into coprocessor requests, for clusters whose coprocessor can evaluate
+/// them, e.g. with a coprocessor plugin or an encoding of the values understood by the DAG
+/// executors of TiKV.
+pub trait FilterPushdown: Send + Sync + 'static {
+    /// The request evaluating `filter` on the keys in `range`, `None` if the filter can't be
+    /// pushed down, in which case it is evaluated by the client.
+    fn compile(&self, filter: &ScanFilter, range: BoundRange) -> Option<CoprocessorRequest>;
+
+    /// The pairs matching the filter in the response of a region, ordered by key.
+    fn decode(&self, response: CoprocessorResponse) -> Result<Vec<KvPair>>;
+}
+
+impl ScanFilter {
+    /// A filter matching all pairs.
+    pub fn new() -> ScanFilter {
+        ScanFilter::default()
+    }
+
+    /// Only match the keys starting with `prefix`.
+    pub fn key_prefix(mut self, prefix: impl Into<Key>) -> ScanFilter {
+        self.key_prefix = Some(prefix.into());
+        self
+    }
+
+    /// Only match the values containing a match of the regular expression `pattern`, in the
+    /// syntax of the `regex` crate. The values are matched as bytes, so they don't need to be
+    /// valid UTF-8.
+    pub fn value_regex(mut self, pattern: &str) -> Result<ScanFilter> {
+        self.value_regex = Some(Regex::new(pattern)?);
+        Ok(self)
+    }
+
+    /// Only match the values whose length in bytes is in `len`.
+    pub fn value_len(mut self, len: impl RangeBounds<usize>) -> ScanFilter {
+        self.value_len = Some((len.start_bound().cloned(), len.end_bound().cloned()));
+        self
+    }
+
+    /// Evaluate the filter with the coprocessor requests compiled by `pushdown`.
+    pub fn pushdown(mut self, pushdown: Arc<dyn FilterPushdown>) -> ScanFilter {
+        self.pushdown = Some(pushdown);
+        self
+    }
+
+    /// The prefix keys must start with, if any.
+    pub fn get_key_prefix(&self) -> Option<&Key> {
+        self.key_prefix.as_ref()
+    }
+
+    /// The pattern of the regular expression values must match, if any.
+    pub fn get_value_regex(&self) -> Option<&str> {
+        self.value_regex.as_ref().map(Regex::as_str)
+    }
+
+    /// The bounds of the length of the values, if any.
+    pub fn get_value_len(&self) -> Option<(Bound<usize>, Bound<usize>)> {
+        self.value_len
+    }
+
+    /// Whether `pair` matches all conditions of the filter.
+    pub fn matches(&self, pair: &KvPair) -> bool {
+        let key: &[u8] = pair.key().into();
+        let value = pair.value();
+        let prefix_matches = match &self.key_prefix {
+            Some(prefix) => key.starts_with(<&[u8]>::from(prefix)),
+            None => true,
+        };
+        prefix_matches
+            && self.value_len.is_none_or(|len| len.contains(&value.len()))
+            && self
+                .value_regex
+                .as_ref()
+                .is_none_or(|regex| regex.is_match(value))
+    }
+
+    /// The part of `range` whose keys start with the key prefix, `None` if there is none.
+    pub(crate) fn restrict(&self, range: BoundRange) -> Option<BoundRange> {
+        let prefix = match &self.key_prefix {
+            Some(prefix) => prefix,
+            None => return Some(range),
+        };
+        let (start, end) = range.into_keys();
+        let (prefix_start, prefix_end) = BoundRange::prefix(prefix.clone()).into_keys();
+        let start = start.max(prefix_start);
+        let end = match (end, prefix_end) {
+            (Some(end), Some(prefix_end)) => Some(end.min(prefix_end)),
+            (end, prefix_end) => end.or(prefix_end),
+        };
+        match end {
+            Some(end) if end <= start => None,
+            Some(end) => Some((start..end).into()),
+            None => Some((start..).into()),
+        }
+    }
+
+    /// The pushdown of the filter and the request it compiled the filter into for `range`, if
+    /// any.
+    pub(crate) fn compile(
+        &self,
+        range: BoundRange,
+    ) -> Option<(Arc<dyn FilterPushdown>, CoprocessorRequest)> {
+        let pushdown = self.pushdown.clone()?;
+        let request = pushdown.compile(self, range)?;
+        Some((pushdown, request))
+    }
+}
+
+impl fmt::Debug for ScanFilter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ScanFilter")
+            .field("key_prefix", &self.key_prefix)
+            .field("value_regex", &self.get_value_regex())
+            .field("value_len", &self.value_len)
+            .field("pushdown", &self.pushdown.is_some())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scan_filter() {
+        let pair = |key: &str, value: &[u8]| KvPair::new(key.to_owned(), value.to_vec());
+        let filter = ScanFilter::new();
+        assert!(filter.matches(&pair("a", b"")));
+
+        let filter = ScanFilter::new()
+            .key_prefix("k".to_owned())
+            .value_regex("^v[0-9]+")
+            .unwrap()
+            .value_len(2..=3);
+        assert!(filter.matches(&pair("k1", b"v1")));
+        assert!(filter.matches(&pair("k", b"v12")));
+        assert!(!filter.matches(&pair("j1", b"v1")));
+        assert!(!filter.matches(&pair("k1", b"x1")));
+        assert!(!filter.matches(&pair("k1", b"v123")));
+        // values are matched as bytes
+        assert!(filter.matches(&pair("k1", &[b'v', b'1', 0xff])));
+
+        assert!(ScanFilter::new().value_regex("(").is_err());
+    }
+
+    #[test]
+    fn test_scan_filter_restrict() {
+        let range =
+            |start: &str, end: &str| -> BoundRange { (start.to_owned()..end.to_owned()).into() };
+        let filter = ScanFilter::new();
+        assert_eq!(filter.restrict(range("a", "z")), Some(range("a", "z")));
+
+        let filter = ScanFilter::new().key_prefix("k".to_owned());
+        assert_eq!(filter.restrict(range("a", "z")), Some(range("k", "l")));
+        assert_eq!(filter.restrict(range("k5", "z")), Some(range("k5", "l")));
+        assert_eq!(filter.restrict(range("a", "k5")), Some(range("k", "k5")));
+        assert_eq!(
+            filter.restrict(BoundRange::range_from(Key::EMPTY)),
+            Some(range("k", "l"))
+        );
+        assert_eq!(filter.restrict(range("a", "c")), None);
+        assert_eq!(filter.restrict(range("l", "z")), None);
+
+        let filter = ScanFilter::new().key_prefix(vec![0xff]);
+        assert_eq!(
+            filter.restrict(BoundRange::range_from(vec![1].into())),
+            Some(BoundRange::range_from(vec![0xff].into()))
+        );
+    }
+}
@@ -0,0 +1,134 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+use crate::{
+    cluster::is_tikv_store,
+    pd::PdClient,
+    request::{Plan, PlanBuilder},
+    stats::MetricsLabels,
+    timestamp::TimestampExt,
+    transaction::{lowering::new_unsafe_destroy_range_request, resolve_locks, scan_locks},
+    BoundRange, Key, Result, Timestamp,
+};
+use futures::future;
+use slog::Logger;
+use std::sync::Arc;
+
+/// Runs the GC of the cluster up to `safepoint`, destroying `destroy_ranges` before the
+/// safepoint is updated, see [`gc`](crate::TransactionClient::gc).
+pub(crate) async fn gc(
+    pd_client: Arc<impl PdClient>,
+    safepoint: Timestamp,
+    destroy_ranges: Vec<BoundRange>,
+    labels: MetricsLabels,
+    logger: &Logger,
+) -> Result<bool> {
+    // scan all locks with ts <= safepoint
+    let locks = scan_locks(
+        BoundRange::range_from(Key::EMPTY),
+        safepoint.version(),
+        pd_client.clone(),
+        labels.clone(),
+    )
+    .await?;
+
+    // resolve locks
+    resolve_locks(locks, pd_client.clone()).await?;
+
+    for range in destroy_ranges {
+        unsafe_destroy_range(pd_client.clone(), range, labels.clone()).await?;
+    }
+
+    // update safepoint to PD
+    let res: bool = pd_client.update_safepoint(safepoint.version()).await?;
+    if !res {
+        info!(logger, "new safepoint != user-specified safepoint");
+    }
+    Ok(res)
+}
+
+/// Destroys the data in `range` in all TiKV stores, see
+/// [`unsafe_destroy_range`](crate::TransactionClient::unsafe_destroy_range).
+pub(crate) async fn unsafe_destroy_range(
+    pd_client: Arc<impl PdClient>,
+    range: BoundRange,
+    labels: MetricsLabels,
+) -> Result<()> {
+    let request = new_unsafe_destroy_range_request(range);
+    let stores = pd_client.clone().stores().await?;
+    let requests = stores.into_iter().filter(is_tikv_store).map(|store| {
+        let pd_client = pd_client.clone();
+        let request = request.clone();
+        let labels = labels.clone();
+        async move {
+            let kv_client = pd_client.store_client(store.get_address()).await?;
+            let plan = PlanBuilder::new(pd_client, request)
+                .labels(labels)
+                .store(kv_client)
+                .extract_error()
+                .plan();
+            plan.execute().await
+        }
+    });
+    future::try_join_all(requests).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{MockKvClient, MockPdClient};
+    use std::{
+        any::Any,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Mutex,
+        },
+    };
+    use tikv_client_proto::kvrpcpb;
+
+    #[tokio::test]
+    async fn test_gc() {
+        let logger = Logger::root(slog::Discard, o!());
+        let destroyed = Arc::new(Mutex::new(Vec::new()));
+        let destroyed_cloned = destroyed.clone();
+        let scans = Arc::new(AtomicUsize::new(0));
+        let scans_cloned = scans.clone();
+        let pd_client = Arc::new(MockPdClient::new(MockKvClient::with_dispatch_hook(
+            move |req: &dyn Any| {
+                if let Some(req) = req.downcast_ref::<kvrpcpb::ScanLockRequest>() {
+                    assert_eq!(req.max_version, 10);
+                    scans_cloned.fetch_add(1, Ordering::SeqCst);
+                    Ok(Box::new(kvrpcpb::ScanLockResponse::default()) as Box<dyn Any>)
+                } else if let Some(req) = req.downcast_ref::<kvrpcpb::UnsafeDestroyRangeRequest>() {
+                    let range = (req.start_key.clone(), req.end_key.clone());
+                    destroyed_cloned.lock().unwrap().push(range);
+                    let resp = kvrpcpb::UnsafeDestroyRangeResponse::default();
+                    Ok(Box::new(resp) as Box<dyn Any>)
+                } else {
+                    unreachable!()
+                }
+            },
+        )));
+        let destroy_ranges = vec![
+            (vec![1]..vec![2]).into(),
+            BoundRange::range_from(vec![3].into()),
+        ];
+        let res = gc(
+            pd_client.clone(),
+            Timestamp::from_version(10),
+            destroy_ranges,
+            MetricsLabels::default(),
+            &logger,
+        )
+        .await
+        .unwrap();
+        assert!(res);
+        // the locks of each region are scanned
+        assert_eq!(scans.load(Ordering::SeqCst), 3);
+        // each range is destroyed in each of the 3 stores
+        let mut expected = vec![(vec![1], vec![2]); 3];
+        expected.extend(vec![(vec![3], vec![]); 3]);
+        assert_eq!(*destroyed.lock().unwrap(), expected);
+        assert_eq!(pd_client.clone().get_gc_safepoint().await.unwrap(), 10);
+    }
+}
@@ -0,0 +1,340 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+use super::transaction::{heartbeat_lock_ttl, TransactionStatus};
+use crate::{
+    backoff::Backoff,
+    pd::PdClient,
+    request::{CollectSingle, Plan, PlanBuilder},
+    timestamp::TimestampExt,
+    transaction::lowering::new_heart_beat_request,
+    Key, Result, StoreId, Timestamp,
+};
+use futures::{future::join_all, stream, StreamExt};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::sync::RwLock;
+
+/// The longest time the scheduler sleeps, so that newly registered transactions with a short
+/// heartbeat interval are not delayed by longer ones.
+const MAX_TICK: Duration = Duration::from_secs(1);
+
+/// The most heartbeats the scheduler sends to a single store at a time.
+const HEARTBEATS_PER_STORE: usize = 16;
+
+/// Keeps the locks of many transactions alive from a single task.
+///
+/// Without a scheduler, each transaction runs its own timer and task to send heartbeats. The
+/// scheduler instead wakes up once for all heartbeats which are due, batches them by the store which
+/// leads the region of their primary key, and sends each batch with at most
+/// `HEARTBEATS_PER_STORE` requests in flight. TiKV has no request which sends heartbeats for
+/// several transactions, so each transaction still needs its own request.
+pub(crate) struct HeartbeatScheduler<PdC: PdClient> {
+    rpc: Arc<PdC>,
+    inner: Arc<Mutex<SchedulerInner>>,
+}
+
+struct SchedulerInner {
+    // start version -> transaction
+    transactions: HashMap<u64, ScheduledHeartbeat>,
+    is_running: bool,
+}
+
+#[derive(Clone)]
+struct ScheduledHeartbeat {
+    start_ts: Timestamp,
+    primary_key: Key,
+    start_instant: Instant,
+    interval: Duration,
+    next_heartbeat: Instant,
+    region_backoff: Backoff,
+    status: Arc<RwLock<TransactionStatus>>,
+}
+
+impl<PdC: PdClient> Clone for HeartbeatScheduler<PdC> {
+    fn clone(&self) -> Self {
+        HeartbeatScheduler {
+            rpc: self.rpc.clone(),
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<PdC: PdClient> HeartbeatScheduler<PdC> {
+    pub fn new(rpc: Arc<PdC>) -> HeartbeatScheduler<PdC> {
+        HeartbeatScheduler {
+            rpc,
+            inner: Arc::new(Mutex::new(SchedulerInner {
+                transactions: HashMap::new(),
+                is_running: false,
+            })),
+        }
+    }
+
+    /// Send heartbeats for the transaction every `interval` until it is committed, rolled back or
+    /// dropped.
+    pub(super) fn register(
+        &self,
+        start_ts: Timestamp,
+        primary_key: Key,
+        start_instant: Instant,
+        interval: Duration,
+        region_backoff: Backoff,
+        status: Arc<RwLock<TransactionStatus>>,
+    ) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.transactions.insert(
+            start_ts.version(),
+            ScheduledHeartbeat {
+                start_ts,
+                primary_key,
+                start_instant,
+                interval,
+                next_heartbeat: self.rpc.clock().now() + interval,
+                region_backoff,
+                status,
+            },
+        );
+        if !inner.is_running {
+            inner.is_running = true;
+            tokio::spawn(self.clone().run());
+        }
+    }
+
+    /// The number of transactions whose heartbeats are scheduled.
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().transactions.len()
+    }
+
+    async fn run(self) {
+        loop {
+            let next_heartbeat = {
+                let mut inner = self.inner.lock().unwrap();
+                match inner.transactions.values().map(|t| t.next_heartbeat).min() {
+                    Some(next_heartbeat) => next_heartbeat,
+                    None => {
+                        inner.is_running = false;
+                        return;
+                    }
+                }
+            };
+            let clock = self.rpc.clock();
+            let sleep = next_heartbeat.saturating_duration_since(clock.now());
+            clock.sleep(sleep.min(MAX_TICK)).await;
+
+            let due: Vec<ScheduledHeartbeat> = {
+                let mut inner = self.inner.lock().unwrap();
+                let now = clock.now();
+                inner
+                    .transactions
+                    .values_mut()
+                    .filter(|t| t.next_heartbeat <= now)
+                    .map(|t| {
+                        t.next_heartbeat = now + t.interval;
+                        t.clone()
+                    })
+                    .collect()
+            };
+
+            let mut finished = Vec::new();
+            let mut heartbeats = Vec::new();
+            for transaction in due {
+                if transaction.status.read().await.is_finished() {
+                    finished.push(transaction.start_ts.version());
+                } else {
+                    heartbeats.push(transaction);
+                }
+            }
+            let batches = self.batch_by_store(heartbeats).await;
+            let results = join_all(batches.into_values().map(|batch| {
+                stream::iter(batch)
+                    .map(|transaction| {
+                        let rpc = self.rpc.clone();
+                        async move {
+                            let start_version = transaction.start_ts.version();
+                            (start_version, transaction.send(rpc).await)
+                        }
+                    })
+                    .buffer_unordered(HEARTBEATS_PER_STORE)
+                    .collect::<Vec<_>>()
+            }))
+            .await;
+            for (start_version, result) in results.into_iter().flatten() {
+                if let Err(err) = result {
+                    log::error!("Error: While sending heartbeat. {}", err);
+                    finished.push(start_version);
+                }
+            }
+
+            let mut inner = self.inner.lock().unwrap();
+            for start_version in finished {
+                inner.transactions.remove(&start_version);
+            }
+        }
+    }
+
+    // Heartbeats whose store is unknown, e.g. because the region of their primary key could not
+    // be loaded, are batched under `None`; sending them locates the region again.
+    async fn batch_by_store(
+        &self,
+        heartbeats: Vec<ScheduledHeartbeat>,
+    ) -> HashMap<Option<StoreId>, Vec<ScheduledHeartbeat>> {
+        let mut batches: HashMap<_, Vec<_>> = HashMap::new();
+        for transaction in heartbeats {
+            let store_id = self
+                .rpc
+                .region_for_key(&transaction.primary_key)
+                .await
+                .ok()
+                .and_then(|region| region.get_store_id().ok());
+            batches.entry(store_id).or_default().push(transaction);
+        }
+        batches
+    }
+}
+
+impl ScheduledHeartbeat {
+    async fn send(self, rpc: Arc<impl PdClient>) -> Result<()> {
+        let request = new_heart_beat_request(
+            self.start_ts,
+            self.primary_key,
+            heartbeat_lock_ttl(rpc.clock().elapsed_since(self.start_instant), self.interval),
+        );
+        let plan = PlanBuilder::new(rpc, request)
+            .retry_multi_region(self.region_backoff)
+            .merge(CollectSingle)
+            .plan();
+        plan.execute().await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        clock::{Clock, ClockHandle, MockClock},
+        mock::{MockKvClient, MockPdClient},
+    };
+    use futures_timer::Delay;
+    use std::{
+        any::Any,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+    use tikv_client_proto::kvrpcpb;
+
+    #[tokio::test]
+    async fn test_heartbeat_scheduler() {
+        let heartbeats = Arc::new(AtomicUsize::new(0));
+        let heartbeats_cloned = heartbeats.clone();
+        let pd_client = Arc::new(MockPdClient::new(MockKvClient::with_dispatch_hook(
+            move |req: &dyn Any| {
+                assert!(req.downcast_ref::<kvrpcpb::TxnHeartBeatRequest>().is_some());
+                heartbeats_cloned.fetch_add(1, Ordering::SeqCst);
+                Ok(Box::new(kvrpcpb::TxnHeartBeatResponse::default()) as Box<dyn Any>)
+            },
+        )));
+        let scheduler = HeartbeatScheduler::new(pd_client);
+        let active = Arc::new(RwLock::new(TransactionStatus::Active));
+        let committed = Arc::new(RwLock::new(TransactionStatus::Active));
+        for (version, status) in [(1, &active), (2, &committed)].iter() {
+            scheduler.register(
+                Timestamp::from_version(*version),
+                vec![*version as u8].into(),
+                Instant::now(),
+                Duration::from_millis(10),
+                Backoff::no_backoff(),
+                (*status).clone(),
+            );
+        }
+        *committed.write().await = TransactionStatus::Committed;
+
+        while scheduler.len() > 1 {
+            Delay::new(Duration::from_millis(10)).await;
+        }
+        while heartbeats.load(Ordering::SeqCst) < 2 {
+            Delay::new(Duration::from_millis(10)).await;
+        }
+        assert_eq!(scheduler.len(), 1);
+
+        *active.write().await = TransactionStatus::Dropped;
+        while scheduler.len() > 0 {
+            Delay::new(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_heartbeat_batches() {
+        let pd_client = Arc::new(MockPdClient::default());
+        let scheduler = HeartbeatScheduler::new(pd_client);
+        let heartbeats = [vec![1], vec![11], vec![2], vec![251, 0], vec![12]]
+            .iter()
+            .enumerate()
+            .map(|(version, key)| ScheduledHeartbeat {
+                start_ts: Timestamp::from_version(version as u64),
+                primary_key: key.clone().into(),
+                start_instant: Instant::now(),
+                interval: Duration::from_secs(1),
+                next_heartbeat: Instant::now(),
+                region_backoff: Backoff::no_backoff(),
+                status: Arc::new(RwLock::new(TransactionStatus::Active)),
+            })
+            .collect();
+
+        let batches = scheduler.batch_by_store(heartbeats).await;
+        let mut batches: Vec<(Option<StoreId>, Vec<u64>)> = batches
+            .into_iter()
+            .map(|(store_id, batch)| {
+                let versions = batch.iter().map(|t| t.start_ts.version()).collect();
+                (store_id, versions)
+            })
+            .collect();
+        batches.sort();
+        assert_eq!(
+            batches,
+            vec![
+                (Some(41), vec![0, 2]),
+                (Some(42), vec![1, 4]),
+                (Some(43), vec![3])
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_heartbeat_scheduler_mock_clock() {
+        let ttls = Arc::new(Mutex::new(Vec::new()));
+        let ttls_cloned = ttls.clone();
+        let mut pd_client =
+            MockPdClient::new(MockKvClient::with_dispatch_hook(move |req: &dyn Any| {
+                let req = req.downcast_ref::<kvrpcpb::TxnHeartBeatRequest>().unwrap();
+                ttls_cloned.lock().unwrap().push(req.advise_lock_ttl);
+                Ok(Box::new(kvrpcpb::TxnHeartBeatResponse::default()) as Box<dyn Any>)
+            }));
+        let clock = MockClock::new();
+        pd_client.clock = ClockHandle::new(clock.clone());
+        let scheduler = HeartbeatScheduler::new(Arc::new(pd_client));
+        scheduler.register(
+            Timestamp::from_version(1),
+            vec![1].into(),
+            clock.now(),
+            Duration::from_secs(600),
+            Backoff::no_backoff(),
+            Arc::new(RwLock::new(TransactionStatus::Active)),
+        );
+
+        // twenty minutes pass without waiting for them
+        for sent in 1..=2 {
+            clock.advance(Duration::from_secs(600));
+            while ttls.lock().unwrap().len() < sent {
+                Delay::new(Duration::from_millis(10)).await;
+            }
+        }
+        assert_eq!(
+            *ttls.lock().unwrap(),
+            vec![600_000 + 1_200_000, 1_200_000 + 1_200_000]
+        );
+    }
+}
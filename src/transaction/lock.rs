@@ -4,45 +4,67 @@ use crate::{
     backoff::{Backoff, DEFAULT_REGION_BACKOFF, OPTIMISTIC_BACKOFF},
     pd::PdClient,
     region::RegionVerId,
-    request::{CollectSingle, Plan},
+    request::{Collect, CollectSingle, Plan},
+    stats::{observe_lock, MetricsLabels},
     timestamp::TimestampExt,
-    transaction::requests,
-    Error, Result,
+    trace::in_span,
+    transaction::{requests, requests::TransactionStatusKind},
+    BoundRange, Error, Result,
 };
+use futures::TryStreamExt;
 use log::debug;
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{btree_map::Entry, BTreeMap, HashMap, HashSet},
+    mem,
     sync::Arc,
+    time::Duration,
 };
 use tikv_client_proto::{kvrpcpb, pdpb::Timestamp};
 
 const RESOLVE_LOCK_RETRY_LIMIT: usize = 10;
+// FIXME: cargo-culted value
+const SCAN_LOCK_BATCH_SIZE: u32 = 1024;
+
+/// What a [`resolve_locks_in_range`](crate::TransactionClient::resolve_locks_in_range) did.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct ResolveLocksSummary {
+    /// The number of expired locks which were resolved.
+    pub resolved: usize,
+    /// The number of locks which were left, because their TTL had not expired yet.
+    pub live: usize,
+}
 
 /// _Resolves_ the given locks. Returns whether all the given locks are resolved.
 ///
 /// If a key has a lock, the latest status of the key is unknown. We need to "resolve" the lock,
 /// which means the key is finally either committed or rolled back, before we read the value of
-/// the key. We first use `CleanupRequest` to let the status of the primary lock converge and get
-/// its status (committed or rolled back). Then, we use the status of its primary lock to determine
-/// the status of the other keys in the same transaction.
+/// the key. We first use `CheckTxnStatusRequest` to let the status of the primary lock converge
+/// and get its status (committed or rolled back), see [`check_txn_status`]. Then, we use the
+/// status of its primary lock to determine the status of the other keys in the same transaction.
+///
+/// The locks are resolved in batches, see [`batch_resolve_expired_locks`].
 pub async fn resolve_locks(
     locks: Vec<kvrpcpb::LockInfo>,
     pd_client: Arc<impl PdClient>,
 ) -> Result<bool> {
+    Ok(batch_resolve_expired_locks(locks, pd_client)
+        .await?
+        .is_empty())
+}
+
+/// Resolves the expired locks of `locks`, see [`resolve_locks`]. Returns the locks which are not
+/// expired.
+pub async fn resolve_expired_locks(
+    locks: Vec<kvrpcpb::LockInfo>,
+    pd_client: Arc<impl PdClient>,
+) -> Result<Vec<kvrpcpb::LockInfo>> {
     debug!("resolving locks");
-    let ts = pd_client.clone().get_timestamp().await?;
-    let mut has_live_locks = false;
-    let expired_locks = locks.into_iter().filter(|lock| {
-        let expired = ts.physical - Timestamp::from_version(lock.lock_version).physical
-            >= lock.lock_ttl as i64;
-        if !expired {
-            has_live_locks = true;
-        }
-        expired
-    });
+    let (expired_locks, mut live_locks, current_ts) =
+        partition_expired_locks(locks, pd_client.clone()).await?;
 
-    // records the commit version of each primary lock (representing the status of the transaction)
-    let mut commit_versions: HashMap<u64, u64> = HashMap::new();
+    // records the commit version of each primary lock (representing the status of the
+    // transaction), `None` if the transaction is alive
+    let mut commit_versions: HashMap<u64, Option<u64>> = HashMap::new();
     let mut clean_regions: HashMap<u64, HashSet<RegionVerId>> = HashMap::new();
     for lock in expired_locks {
         let region_ver_id = pd_client
@@ -61,24 +83,35 @@ pub async fn resolve_locks(
         let commit_version = match commit_versions.get(&lock.lock_version) {
             Some(&commit_version) => commit_version,
             None => {
-                let request = requests::new_cleanup_request(lock.primary_lock, lock.lock_version);
-                let plan = crate::request::PlanBuilder::new(pd_client.clone(), request)
-                    .resolve_lock(OPTIMISTIC_BACKOFF)
-                    .retry_multi_region(DEFAULT_REGION_BACKOFF)
-                    .merge(CollectSingle)
-                    .post_process_default()
-                    .plan();
-                let commit_version = plan.execute().await?;
+                let commit_version = in_span!(
+                    check_txn_status(&lock, current_ts, pd_client.clone()),
+                    "check_txn_status",
+                    start_ts = lock.lock_version
+                )
+                .await?;
                 commit_versions.insert(lock.lock_version, commit_version);
                 commit_version
             }
         };
+        let commit_version = match commit_version {
+            Some(commit_version) => commit_version,
+            None => {
+                live_locks.push(lock);
+                continue;
+            }
+        };
 
-        let cleaned_region = resolve_lock_with_retry(
-            &lock.key,
-            lock.lock_version,
-            commit_version,
-            pd_client.clone(),
+        trace_lock(&lock, LockDecision::Resolve, Duration::from_secs(0));
+        let cleaned_region = in_span!(
+            resolve_lock_with_retry(
+                &lock.key,
+                lock.lock_version,
+                commit_version,
+                pd_client.clone(),
+            ),
+            "resolve_lock",
+            start_ts = lock.lock_version,
+            commit_ts = commit_version
         )
         .await?;
         clean_regions
@@ -86,7 +119,291 @@ pub async fn resolve_locks(
             .or_insert_with(HashSet::new)
             .insert(cleaned_region);
     }
-    Ok(!has_live_locks)
+    Ok(live_locks)
+}
+
+/// Resolves the expired locks of `locks` like [`resolve_expired_locks`], but with a single
+/// `ResolveLockRequest` per region listing the status of their transactions, instead of a request
+/// per lock. Returns the locks which are not expired.
+pub async fn batch_resolve_expired_locks(
+    locks: Vec<kvrpcpb::LockInfo>,
+    pd_client: Arc<impl PdClient>,
+) -> Result<Vec<kvrpcpb::LockInfo>> {
+    debug!("batch resolving locks");
+    let (expired_locks, mut live_locks, current_ts) =
+        partition_expired_locks(locks, pd_client.clone()).await?;
+    if expired_locks.is_empty() {
+        return Ok(live_locks);
+    }
+
+    // the status of each transaction is checked once, whatever its number of locks
+    let mut commit_versions: BTreeMap<u64, Option<u64>> = BTreeMap::new();
+    let mut keys = Vec::with_capacity(expired_locks.len());
+    for lock in expired_locks {
+        let commit_version = match commit_versions.entry(lock.lock_version) {
+            Entry::Occupied(entry) => *entry.get(),
+            Entry::Vacant(entry) => {
+                let commit_version = in_span!(
+                    check_txn_status(&lock, current_ts, pd_client.clone()),
+                    "check_txn_status",
+                    start_ts = lock.lock_version
+                )
+                .await?;
+                *entry.insert(commit_version)
+            }
+        };
+        if commit_version.is_none() {
+            live_locks.push(lock);
+            continue;
+        }
+        trace_lock(&lock, LockDecision::Resolve, Duration::from_secs(0));
+        keys.push(lock.key);
+    }
+    if keys.is_empty() {
+        return Ok(live_locks);
+    }
+
+    let txn_infos = commit_versions
+        .into_iter()
+        .filter_map(|(txn, status)| status.map(|status| kvrpcpb::TxnInfo { txn, status }))
+        .collect();
+    in_span!(
+        batch_resolve_lock_with_retry(keys, txn_infos, pd_client),
+        "batch_resolve_lock"
+    )
+    .await?;
+    Ok(live_locks)
+}
+
+/// Splits `locks` into the expired locks and the live ones, at the current timestamp, which is
+/// returned along with them.
+async fn partition_expired_locks(
+    locks: Vec<kvrpcpb::LockInfo>,
+    pd_client: Arc<impl PdClient>,
+) -> Result<(Vec<kvrpcpb::LockInfo>, Vec<kvrpcpb::LockInfo>, u64)> {
+    let current_ts = pd_client.get_timestamp().await?.version();
+    let (expired_locks, live_locks) = locks
+        .into_iter()
+        .partition(|lock| is_expired(lock.lock_version, lock.lock_ttl, current_ts));
+    Ok((expired_locks, live_locks, current_ts))
+}
+
+/// Whether a lock of the transaction started at `lock_version` with a TTL of `lock_ttl`
+/// milliseconds has expired at `current_ts`.
+fn is_expired(lock_version: u64, lock_ttl: u64, current_ts: u64) -> bool {
+    let current = Timestamp::from_version(current_ts).physical;
+    current - Timestamp::from_version(lock_version).physical >= lock_ttl as i64
+}
+
+/// Lets the status of the transaction of the expired `lock` converge at `current_ts`. Returns its
+/// commit version, 0 if it was rolled back, or `None` if it is still alive.
+///
+/// `CheckTxnStatus` rolls the transaction back once its primary lock expired. Unlike `Cleanup`,
+/// it respects the TTL of the primary lock, which heartbeats extend beyond the TTL of the other
+/// locks, so a long-running transaction is not rolled back while it is alive. The primary lock
+/// of an async commit transaction doesn't tell whether it committed, its secondary locks are
+/// checked instead, see [`check_secondary_locks`]. If one of them is not an async commit lock,
+/// the transaction fell back to 2PC, and its primary lock is checked again as a 2PC lock.
+async fn check_txn_status(
+    lock: &kvrpcpb::LockInfo,
+    current_ts: u64,
+    pd_client: Arc<impl PdClient>,
+) -> Result<Option<u64>> {
+    let status = check_primary_lock(lock, current_ts, false, pd_client.clone()).await?;
+    let commit_version = match status {
+        TransactionStatusKind::Locked(ttl, primary)
+            if primary.use_async_commit && is_expired(primary.lock_version, ttl, current_ts) =>
+        {
+            check_secondary_locks(primary, pd_client.clone()).await?
+        }
+        status => return Ok(to_commit_version(status)),
+    };
+    match commit_version {
+        Some(commit_version) => Ok(Some(commit_version)),
+        None => {
+            // `force_sync_commit` lets TiKV roll the expired primary lock back like a 2PC lock
+            let status = check_primary_lock(lock, current_ts, true, pd_client).await?;
+            Ok(to_commit_version(status))
+        }
+    }
+}
+
+/// Sends a `CheckTxnStatusRequest` for the primary lock of the transaction holding `lock`.
+async fn check_primary_lock(
+    lock: &kvrpcpb::LockInfo,
+    current_ts: u64,
+    force_sync_commit: bool,
+    pd_client: Arc<impl PdClient>,
+) -> Result<TransactionStatusKind> {
+    let request = requests::new_check_txn_status_request(
+        lock.primary_lock.clone(),
+        lock.lock_version,
+        0,
+        current_ts,
+        true,
+        force_sync_commit,
+        lock.get_lock_type() == kvrpcpb::Op::PessimisticLock,
+    );
+    let plan = crate::request::PlanBuilder::new(pd_client, request)
+        .retry_multi_region(DEFAULT_REGION_BACKOFF)
+        .merge(CollectSingle)
+        .post_process_default()
+        .plan();
+    Ok(plan.execute().await?.kind)
+}
+
+/// The commit version of a transaction of `status`, 0 if it was rolled back, or `None` if it is
+/// still locked.
+fn to_commit_version(status: TransactionStatusKind) -> Option<u64> {
+    match status {
+        TransactionStatusKind::Committed(ts) => Some(ts.version()),
+        TransactionStatusKind::RolledBack => Some(0),
+        TransactionStatusKind::Locked(..) => None,
+    }
+}
+
+/// Decides the status of an expired async commit transaction from its `primary` lock and its
+/// secondary locks. Returns the commit version of the transaction, 0 if it was rolled back, or
+/// `None` if one of its secondary locks is not an async commit lock.
+///
+/// The transaction committed if one of its secondaries did. A secondary locked without async
+/// commit means the client fell back to 2PC, so the transaction must be resolved from its primary
+/// lock alone. The transaction is rolled back if one of its secondaries is not locked by it, e.g.
+/// because it was not prewritten before the client failed. `CheckSecondaryLocks` leaves a
+/// rollback record on such secondaries, so that they can't be prewritten later. Otherwise all the
+/// locks were prewritten, so the transaction commits at the largest `min_commit_ts` of them, as
+/// its client would have.
+async fn check_secondary_locks(
+    primary: kvrpcpb::LockInfo,
+    pd_client: Arc<impl PdClient>,
+) -> Result<Option<u64>> {
+    let request = requests::new_check_secondary_locks_request(
+        primary.secondaries.clone(),
+        primary.lock_version,
+    );
+    let plan = crate::request::PlanBuilder::new(pd_client, request)
+        .retry_multi_region(DEFAULT_REGION_BACKOFF)
+        .merge(Collect)
+        .plan();
+    let status = plan.execute().await?;
+    if let Some(commit_ts) = status.commit_ts {
+        return Ok(Some(commit_ts.version()));
+    }
+    if status.locks.values().any(|lock| !lock.use_async_commit) {
+        return Ok(None);
+    }
+    if status.locks.len() < primary.secondaries.len() {
+        return Ok(Some(0));
+    }
+    Ok(Some(
+        status
+            .locks
+            .values()
+            .map(|lock| lock.min_commit_ts)
+            .fold(primary.min_commit_ts, u64::max),
+    ))
+}
+
+/// Scans the locks in `range` of transactions started before `max_version`.
+pub async fn scan_locks(
+    range: BoundRange,
+    max_version: u64,
+    pd_client: Arc<impl PdClient>,
+    labels: MetricsLabels,
+) -> Result<Vec<kvrpcpb::LockInfo>> {
+    let (mut start_key, end_key) = range.into_keys();
+    let end_key: Vec<u8> = end_key.map(Into::into).unwrap_or_default();
+    let mut locks = Vec::new();
+    loop {
+        let request = requests::new_scan_lock_request(
+            mem::take(&mut start_key).into(),
+            end_key.clone(),
+            max_version,
+            SCAN_LOCK_BATCH_SIZE,
+        );
+        let plan = crate::request::PlanBuilder::new(pd_client.clone(), request)
+            .labels(labels.clone())
+            .resolve_lock(OPTIMISTIC_BACKOFF)
+            .retry_multi_region(DEFAULT_REGION_BACKOFF)
+            .merge(Collect)
+            .plan();
+        let res: Vec<kvrpcpb::LockInfo> = plan.execute().await?;
+        if res.is_empty() {
+            return Ok(locks);
+        }
+        let mut next_key = res.last().unwrap().key.clone();
+        next_key.push(0);
+        start_key = next_key.into();
+        locks.extend(res);
+    }
+}
+
+/// Resolves the expired locks in `range` of transactions started before `max_version`.
+pub async fn resolve_locks_in_range(
+    range: BoundRange,
+    max_version: u64,
+    pd_client: Arc<impl PdClient>,
+    labels: MetricsLabels,
+) -> Result<ResolveLocksSummary> {
+    let locks = scan_locks(range, max_version, pd_client.clone(), labels).await?;
+    let total = locks.len();
+    let live = batch_resolve_expired_locks(locks, pd_client).await?.len();
+    Ok(ResolveLocksSummary {
+        resolved: total - live,
+        live,
+    })
+}
+
+/// Pushes the `min_commit_ts` of the transactions holding `locks` past `read_ts`, so that a read
+/// at `read_ts` can proceed without waiting for them.
+///
+/// Returns the start versions of the transactions which can no longer commit at or before
+/// `read_ts`. A read may ignore their locks by setting the `resolved_locks` of its context.
+pub async fn push_min_commit_ts(
+    locks: Vec<kvrpcpb::LockInfo>,
+    read_ts: u64,
+    pd_client: Arc<impl PdClient>,
+) -> Result<Vec<u64>> {
+    debug!("pushing min_commit_ts of locks");
+    let current_ts = pd_client.clone().get_timestamp().await?.version();
+    let mut checked = HashSet::new();
+    let mut pushed = Vec::new();
+    for lock in locks {
+        // the min_commit_ts of async commit transactions is on every lock, not just the primary
+        if lock.use_async_commit || !checked.insert(lock.lock_version) {
+            continue;
+        }
+        let is_pessimistic = lock.get_lock_type() == kvrpcpb::Op::PessimisticLock;
+        let request = requests::new_check_txn_status_request(
+            lock.primary_lock,
+            lock.lock_version,
+            read_ts,
+            current_ts,
+            false,
+            false,
+            is_pessimistic,
+        );
+        let plan = crate::request::PlanBuilder::new(pd_client.clone(), request)
+            .retry_multi_region(DEFAULT_REGION_BACKOFF)
+            .merge(CollectSingle)
+            .post_process_default()
+            .plan();
+        // if the status can't be checked, e.g. the primary lock is not written yet, the read
+        // has to wait for the lock as usual
+        let can_ignore = match plan.execute().await.map(|status| status.kind) {
+            Ok(TransactionStatusKind::Committed(ts)) => ts.version() > read_ts,
+            Ok(TransactionStatusKind::RolledBack) => true,
+            Ok(TransactionStatusKind::Locked(_, primary)) => primary.min_commit_ts > read_ts,
+            Err(e) => {
+                debug!("failed to push min_commit_ts: {:?}", e);
+                false
+            }
+        };
+        if can_ignore {
+            pushed.push(lock.lock_version);
+        }
+    }
+    Ok(pushed)
 }
 
 async fn resolve_lock_with_retry(
@@ -132,10 +449,125 @@ async fn resolve_lock_with_retry(
     Err(error.expect("no error is impossible"))
 }
 
+/// Resolves the locks of the transactions in `txn_infos` in the regions of `keys`, with a request
+/// per region. The requests have no keys, TiKV resolves all the locks of the transactions in the
+/// region. The keys of the regions which returned a region error are grouped again and retried.
+async fn batch_resolve_lock_with_retry(
+    mut keys: Vec<Vec<u8>>,
+    txn_infos: Vec<kvrpcpb::TxnInfo>,
+    pd_client: Arc<impl PdClient>,
+) -> Result<()> {
+    debug!("batch resolving locks with retry");
+    let mut error = None;
+    for i in 0..RESOLVE_LOCK_RETRY_LIMIT {
+        if keys.is_empty() {
+            return Ok(());
+        }
+        debug!("batch resolving locks: attempt {}", (i + 1));
+        keys.sort();
+        keys.dedup();
+        let mut regions = pd_client
+            .clone()
+            .group_keys_by_region::<_, Vec<u8>>(mem::take(&mut keys).into_iter());
+        while let Some((region_id, region_keys)) = regions.try_next().await? {
+            let store = pd_client.clone().store_for_id(region_id).await?;
+            let ver_id = store.region_with_leader.ver_id();
+            let request = requests::new_batch_resolve_lock_request(txn_infos.clone());
+            let plan = crate::request::PlanBuilder::new(pd_client.clone(), request)
+                .single_region_with_store(store)
+                .await?
+                .extract_error()
+                .plan();
+            match plan.execute().await {
+                Ok(_) => {}
+                // Retry on region error
+                Err(Error::ExtractedErrors(mut errors)) => {
+                    // ResolveLockResponse can have at most 1 error
+                    match errors.pop() {
+                        e @ Some(Error::RegionError(_)) => {
+                            pd_client.invalidate_region_cache(ver_id).await;
+                            keys.extend(region_keys);
+                            error = e;
+                        }
+                        Some(e) => return Err(e),
+                        None => unreachable!(),
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    match error {
+        Some(error) if !keys.is_empty() => Err(error),
+        _ => Ok(()),
+    }
+}
+
 pub trait HasLocks {
     fn take_locks(&mut self) -> Vec<kvrpcpb::LockInfo> {
         Vec::new()
     }
+
+    /// Remove the results for locked keys from the response, and return the locks of those keys.
+    ///
+    /// Responses without results per key keep their locks, which are then reported as errors.
+    fn skip_locks(&mut self) -> Vec<kvrpcpb::LockInfo> {
+        Vec::new()
+    }
+}
+
+/// What a request did about a lock it encountered.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LockDecision {
+    /// The locked key was left out of the result, see
+    /// [`LockPolicy::SkipLocked`](crate::LockPolicy::SkipLocked).
+    Skip,
+    /// The lock was returned as an error, see
+    /// [`LockPolicy::FailFast`](crate::LockPolicy::FailFast).
+    FailFast,
+    /// The lock was expired and has been resolved.
+    Resolve,
+    /// The `min_commit_ts` of the lock was pushed past the timestamp of the read, so the read
+    /// ignores the lock.
+    PushMinCommitTs,
+    /// The lock is alive, the request is retried after waiting.
+    Wait,
+    /// The lock is alive and the request gave up waiting for it.
+    GiveUp,
+}
+
+impl LockDecision {
+    /// The label of the decision in the metrics.
+    fn label(self) -> &'static str {
+        match self {
+            LockDecision::Skip => "skip",
+            LockDecision::FailFast => "fail_fast",
+            LockDecision::Resolve => "resolve",
+            LockDecision::PushMinCommitTs => "push_min_commit_ts",
+            LockDecision::Wait => "wait",
+            LockDecision::GiveUp => "give_up",
+        }
+    }
+}
+
+/// Records a lock encountered by a request in the metrics, and emits a trace event for it if the
+/// `tracing` feature is enabled.
+///
+/// `wait` is how long the request waits before it is retried because of the lock.
+#[allow(unused_variables)]
+pub fn trace_lock(lock: &kvrpcpb::LockInfo, decision: LockDecision, wait: Duration) {
+    observe_lock(decision.label());
+    #[cfg(feature = "tracing")]
+    tracing::trace!(
+        target: "tikv_client::lock",
+        key = ?crate::Key::from(lock.key.clone()),
+        primary_key = ?crate::Key::from(lock.primary_lock.clone()),
+        start_ts = lock.lock_version,
+        ttl_ms = lock.lock_ttl,
+        decision = ?decision,
+        wait_ms = wait.as_millis() as u64,
+        "encountered lock"
+    );
 }
 
 #[cfg(test)]
@@ -145,6 +577,61 @@ mod tests {
     use std::any::Any;
     use tikv_client_proto::errorpb;
 
+    #[tokio::test]
+    async fn test_resolve_expired_locks() {
+        let client = Arc::new(MockPdClient::new(MockKvClient::with_dispatch_hook(
+            |_: &dyn Any| panic!("live locks must not be resolved"),
+        )));
+        let lock = kvrpcpb::LockInfo {
+            key: vec![1],
+            primary_lock: vec![1],
+            lock_version: 1,
+            lock_ttl: 100,
+            ..Default::default()
+        };
+        let live_locks = resolve_expired_locks(vec![lock.clone()], client.clone())
+            .await
+            .unwrap();
+        assert_eq!(live_locks, vec![lock]);
+        assert!(!resolve_locks(live_locks, client).await.unwrap());
+    }
+
+    #[cfg(feature = "tracing")]
+    #[tokio::test]
+    async fn test_resolve_lock_spans() {
+        let (_guard, spans) = crate::trace::tests::record_spans();
+        let client = Arc::new(MockPdClient::new(MockKvClient::with_dispatch_hook(
+            |req: &dyn Any| {
+                if req.is::<kvrpcpb::CheckTxnStatusRequest>() {
+                    let resp = kvrpcpb::CheckTxnStatusResponse {
+                        commit_version: 2,
+                        ..Default::default()
+                    };
+                    Ok(Box::new(resp) as Box<dyn Any>)
+                } else {
+                    Ok(Box::new(kvrpcpb::ResolveLockResponse::default()) as Box<dyn Any>)
+                }
+            },
+        )));
+        let lock = kvrpcpb::LockInfo {
+            key: vec![1],
+            primary_lock: vec![1],
+            lock_version: 1,
+            ..Default::default()
+        };
+        let live_locks = resolve_expired_locks(vec![lock], client).await.unwrap();
+        assert!(live_locks.is_empty());
+
+        let spans = spans.lock().unwrap();
+        for span in &[
+            ("check_txn_status", None),
+            ("resolve_lock", None),
+            ("region_request", Some("check_txn_status")),
+        ] {
+            assert!(spans.contains(span), "{:?} in {:?}", span, spans);
+        }
+    }
+
     #[tokio::test]
     async fn test_resolve_lock_with_retry() {
         // Test resolve lock within retry limit
@@ -177,4 +664,228 @@ mod tests {
             .await
             .expect_err("should return error");
     }
+
+    #[tokio::test]
+    async fn test_push_min_commit_ts() {
+        let client = Arc::new(MockPdClient::new(MockKvClient::with_dispatch_hook(
+            |req: &dyn Any| {
+                let req: &kvrpcpb::CheckTxnStatusRequest = req.downcast_ref().unwrap();
+                assert_eq!(req.caller_start_ts, 10);
+                // only the transaction started at 5 can be pushed
+                let min_commit_ts = if req.lock_ts == 5 { 11 } else { 9 };
+                let resp = kvrpcpb::CheckTxnStatusResponse {
+                    lock_ttl: 100,
+                    lock_info: Some(kvrpcpb::LockInfo {
+                        lock_version: req.lock_ts,
+                        min_commit_ts,
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                };
+                Ok(Box::new(resp) as Box<dyn Any>)
+            },
+        )));
+
+        let lock = |key: u8, lock_version: u64| kvrpcpb::LockInfo {
+            key: vec![key],
+            primary_lock: vec![1],
+            lock_version,
+            lock_ttl: 100,
+            ..Default::default()
+        };
+        let locks = vec![lock(1, 5), lock(2, 5), lock(3, 6)];
+        let pushed = push_min_commit_ts(locks, 10, client).await.unwrap();
+        assert_eq!(pushed, vec![5]);
+    }
+
+    #[tokio::test]
+    async fn test_resolve_locks_in_range() {
+        let resolved = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let resolved_cloned = resolved.clone();
+        let client = Arc::new(MockPdClient::new(MockKvClient::with_dispatch_hook(
+            move |req: &dyn Any| {
+                if let Some(req) = req.downcast_ref::<kvrpcpb::ScanLockRequest>() {
+                    // the lock of key 2 is live, the others are expired
+                    let locks = [(1u8, 0), (2, 100), (20, 0)]
+                        .iter()
+                        .map(|(key, lock_ttl)| kvrpcpb::LockInfo {
+                            key: vec![*key],
+                            primary_lock: vec![*key],
+                            lock_version: *key as u64,
+                            lock_ttl: *lock_ttl,
+                            ..Default::default()
+                        })
+                        .filter(|lock| {
+                            lock.key >= req.start_key
+                                && (req.end_key.is_empty() || lock.key < req.end_key)
+                        })
+                        .collect();
+                    let resp = kvrpcpb::ScanLockResponse {
+                        locks,
+                        ..Default::default()
+                    };
+                    Ok(Box::new(resp) as Box<dyn Any>)
+                } else if req.is::<kvrpcpb::CheckTxnStatusRequest>() {
+                    let resp = kvrpcpb::CheckTxnStatusResponse::default();
+                    Ok(Box::new(resp) as Box<dyn Any>)
+                } else if let Some(req) = req.downcast_ref::<kvrpcpb::ResolveLockRequest>() {
+                    let txns = req.txn_infos.iter().map(|txn_info| txn_info.txn);
+                    resolved_cloned.lock().unwrap().extend(txns);
+                    assert!(req.keys.is_empty());
+                    Ok(Box::new(kvrpcpb::ResolveLockResponse::default()) as Box<dyn Any>)
+                } else {
+                    unreachable!()
+                }
+            },
+        )));
+
+        let range = BoundRange::from(vec![1u8]..vec![10u8]);
+        let summary = resolve_locks_in_range(range, 10, client, MetricsLabels::default())
+            .await
+            .unwrap();
+        assert_eq!(
+            summary,
+            ResolveLocksSummary {
+                resolved: 1,
+                live: 1
+            }
+        );
+        // the lock out of the range is not resolved
+        assert_eq!(*resolved.lock().unwrap(), vec![1]);
+    }
+
+    #[tokio::test]
+    async fn test_batch_resolve_expired_locks() {
+        let checks = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let checks_cloned = checks.clone();
+        let resolved = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let resolved_cloned = resolved.clone();
+        let client = Arc::new(MockPdClient::new(MockKvClient::with_dispatch_hook(
+            move |req: &dyn Any| {
+                if let Some(req) = req.downcast_ref::<kvrpcpb::CheckTxnStatusRequest>() {
+                    checks_cloned.lock().unwrap().push(req.lock_ts);
+                    assert!(req.rollback_if_not_exist);
+                    // the transaction started at 2 was committed at 3
+                    let resp = kvrpcpb::CheckTxnStatusResponse {
+                        commit_version: if req.lock_ts == 2 { 3 } else { 0 },
+                        ..Default::default()
+                    };
+                    Ok(Box::new(resp) as Box<dyn Any>)
+                } else if let Some(req) = req.downcast_ref::<kvrpcpb::ResolveLockRequest>() {
+                    let txn_infos: Vec<(u64, u64)> = req
+                        .txn_infos
+                        .iter()
+                        .map(|txn_info| (txn_info.txn, txn_info.status))
+                        .collect();
+                    // TiKV rejects batch requests with keys
+                    assert!(req.keys.is_empty());
+                    assert_eq!(req.start_version, 0);
+                    resolved_cloned
+                        .lock()
+                        .unwrap()
+                        .push((req.get_context().region_id, txn_infos));
+                    Ok(Box::new(kvrpcpb::ResolveLockResponse::default()) as Box<dyn Any>)
+                } else {
+                    unreachable!()
+                }
+            },
+        )));
+
+        let lock = |key: u8, lock_version: u64, lock_ttl: u64| kvrpcpb::LockInfo {
+            key: vec![key],
+            primary_lock: vec![lock_version as u8],
+            lock_version,
+            lock_ttl,
+            ..Default::default()
+        };
+        let locks = vec![
+            lock(1, 1, 0),
+            lock(5, 2, 0),
+            lock(20, 1, 0),
+            lock(21, 2, 0),
+            lock(30, 3, 100),
+        ];
+        let live_locks = batch_resolve_expired_locks(locks, client).await.unwrap();
+        assert_eq!(live_locks, vec![lock(30, 3, 100)]);
+        // the status of each transaction is checked once
+        assert_eq!(*checks.lock().unwrap(), vec![1, 2]);
+        // a request per region, instead of a request per lock
+        let mut resolved = resolved.lock().unwrap().clone();
+        resolved.sort();
+        let txn_infos = vec![(1, 0), (2, 3)];
+        assert_eq!(resolved, vec![(1, txn_infos.clone()), (2, txn_infos)]);
+    }
+
+    #[tokio::test]
+    async fn test_check_txn_status() {
+        // the primary locks of the transactions started at 1 and 4 are alive, the others expired;
+        // all transactions but 1 use async commit, secondary 3 of the transaction started at 3
+        // is not locked, and the one of the transaction started at 5 is not an async commit lock
+        let client = Arc::new(MockPdClient::new(MockKvClient::with_dispatch_hook(
+            |req: &dyn Any| {
+                if let Some(req) = req.downcast_ref::<kvrpcpb::CheckTxnStatusRequest>() {
+                    if req.force_sync_commit {
+                        // the transaction fell back to 2PC, its expired primary is rolled back
+                        assert_eq!(req.lock_ts, 5);
+                        let resp = kvrpcpb::CheckTxnStatusResponse::default();
+                        return Ok(Box::new(resp) as Box<dyn Any>);
+                    }
+                    let alive = req.lock_ts == 1 || req.lock_ts == 4;
+                    let resp = kvrpcpb::CheckTxnStatusResponse {
+                        lock_ttl: if alive { 1 << 30 } else { 100 },
+                        lock_info: Some(kvrpcpb::LockInfo {
+                            lock_version: req.lock_ts,
+                            use_async_commit: req.lock_ts != 1,
+                            min_commit_ts: 5,
+                            secondaries: vec![vec![2], vec![3]],
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    };
+                    Ok(Box::new(resp) as Box<dyn Any>)
+                } else if let Some(req) = req.downcast_ref::<kvrpcpb::CheckSecondaryLocksRequest>()
+                {
+                    assert!([2, 3, 5].contains(&req.start_version));
+                    let locks = req
+                        .keys
+                        .iter()
+                        .filter(|key| req.start_version != 3 || key[0] != 3)
+                        .map(|key| kvrpcpb::LockInfo {
+                            key: key.clone(),
+                            lock_version: req.start_version,
+                            min_commit_ts: 4 + key[0] as u64,
+                            use_async_commit: req.start_version != 5 || key[0] != 3,
+                            ..Default::default()
+                        })
+                        .collect();
+                    let resp = kvrpcpb::CheckSecondaryLocksResponse {
+                        locks,
+                        ..Default::default()
+                    };
+                    Ok(Box::new(resp) as Box<dyn Any>)
+                } else {
+                    unreachable!()
+                }
+            },
+        )));
+
+        let current_ts = Timestamp {
+            physical: 1000,
+            ..Default::default()
+        }
+        .version();
+        let mut statuses = Vec::new();
+        for lock_version in 1..=5 {
+            let lock = kvrpcpb::LockInfo {
+                key: vec![1],
+                primary_lock: vec![1],
+                lock_version,
+                ..Default::default()
+            };
+            let status = check_txn_status(&lock, current_ts, client.clone()).await;
+            statuses.push(status.unwrap());
+        }
+        // the transaction started at 2 commits at the largest min_commit_ts of its locks
+        assert_eq!(statuses, vec![None, Some(7), Some(0), None, Some(0)]);
+    }
 }
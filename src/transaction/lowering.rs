@@ -3,7 +3,11 @@
 /// This module provides constructor functions for requests which take arguments as high-level
 /// types (i.e., the types from the client crate) and converts these to the types used in the
 /// generated protobuf code, then calls the low-level ctor functions in the requests module.
-use crate::{timestamp::TimestampExt, transaction::requests, BoundRange, Key};
+use crate::{
+    timestamp::TimestampExt,
+    transaction::{requests, PessimisticLockOptions},
+    BoundRange, Key,
+};
 use std::iter::Iterator;
 use tikv_client_proto::{kvrpcpb, pdpb::Timestamp};
 
@@ -26,9 +30,16 @@ pub fn new_scan_request(
     key_only: bool,
 ) -> kvrpcpb::ScanRequest {
     let (start_key, end_key) = range.into_keys();
+    let end_key = end_key.unwrap_or_default();
+    // a reverse scan starts at the end of the range, which is exclusive either way
+    let (start_key, end_key) = if reverse {
+        (end_key, start_key)
+    } else {
+        (start_key, end_key)
+    };
     requests::new_scan_request(
         start_key.into(),
-        end_key.unwrap_or_default().into(),
+        end_key.into(),
         timestamp.version(),
         limit,
         reverse,
@@ -43,10 +54,55 @@ pub fn new_resolve_lock_request(
     requests::new_resolve_lock_request(start_version.version(), commit_version.version())
 }
 
+/// A batch request resolving the locks in a region of the transactions started at the first
+/// timestamp of each pair and committed at the second, or rolled back if it is the default.
+pub fn new_batch_resolve_lock_request(
+    txn_status: impl Iterator<Item = (Timestamp, Timestamp)>,
+) -> kvrpcpb::ResolveLockRequest {
+    requests::new_batch_resolve_lock_request(
+        txn_status
+            .map(|(start_version, commit_version)| kvrpcpb::TxnInfo {
+                txn: start_version.version(),
+                status: commit_version.version(),
+            })
+            .collect(),
+    )
+}
+
 pub fn new_cleanup_request(key: Key, start_version: Timestamp) -> kvrpcpb::CleanupRequest {
     requests::new_cleanup_request(key.into(), start_version.version())
 }
 
+pub fn new_check_txn_status_request(
+    primary_key: Key,
+    lock_ts: Timestamp,
+    caller_start_ts: Timestamp,
+    current_ts: Timestamp,
+    rollback_if_not_exist: bool,
+    force_sync_commit: bool,
+    resolving_pessimistic_lock: bool,
+) -> kvrpcpb::CheckTxnStatusRequest {
+    requests::new_check_txn_status_request(
+        primary_key.into(),
+        lock_ts.version(),
+        caller_start_ts.version(),
+        current_ts.version(),
+        rollback_if_not_exist,
+        force_sync_commit,
+        resolving_pessimistic_lock,
+    )
+}
+
+pub fn new_check_secondary_locks_request(
+    keys: impl Iterator<Item = Key>,
+    start_version: Timestamp,
+) -> kvrpcpb::CheckSecondaryLocksRequest {
+    requests::new_check_secondary_locks_request(
+        keys.map(Into::into).collect(),
+        start_version.version(),
+    )
+}
+
 pub fn new_prewrite_request(
     mutations: Vec<kvrpcpb::Mutation>,
     primary_lock: Key,
@@ -140,7 +196,7 @@ pub fn new_pessimistic_lock_request(
     start_version: Timestamp,
     lock_ttl: u64,
     for_update_ts: Timestamp,
-    need_value: bool,
+    options: &PessimisticLockOptions,
 ) -> kvrpcpb::PessimisticLockRequest {
     requests::new_pessimistic_lock_request(
         locks
@@ -156,7 +212,7 @@ pub fn new_pessimistic_lock_request(
         start_version.version(),
         lock_ttl,
         for_update_ts.version(),
-        need_value,
+        options,
     )
 }
 
@@ -165,7 +221,7 @@ pub fn new_scan_lock_request(
     safepoint: Timestamp,
     limit: u32,
 ) -> kvrpcpb::ScanLockRequest {
-    requests::new_scan_lock_request(start_key.into(), safepoint.version(), limit)
+    requests::new_scan_lock_request(start_key.into(), vec![], safepoint.version(), limit)
 }
 
 pub fn new_heart_beat_request(
@@ -183,3 +239,15 @@ pub fn new_delete_range_request(
     requests::new_delete_range_request(start_key.into(), end_key.unwrap_or_default().into())
 }
 
+pub fn new_unsafe_destroy_range_request(range: BoundRange) -> kvrpcpb::UnsafeDestroyRangeRequest {
+    let (start_key, end_key) = range.into_keys();
+    requests::new_unsafe_destroy_range_request(start_key.into(), end_key.unwrap_or_default().into())
+}
+
+pub fn new_mvcc_get_by_key_request(key: Key) -> kvrpcpb::MvccGetByKeyRequest {
+    requests::new_mvcc_get_by_key_request(key.into())
+}
+
+pub fn new_mvcc_get_by_start_ts_request(start_ts: Timestamp) -> kvrpcpb::MvccGetByStartTsRequest {
+    requests::new_mvcc_get_by_start_ts_request(start_ts.version())
+}
@@ -8,19 +8,44 @@
 //!
 //! **Warning:** It is not advisable to use both raw and transactional functionality in the same keyspace.
 
-pub use client::Client;
-pub(crate) use lock::{resolve_locks, HasLocks};
+pub use buffer::{CoalescingRules, Mutation};
+pub use client::{Client, EXPORT_BATCH_SIZE};
+pub use export::{ExportSink, KeyDiff};
+pub use filter::{FilterPushdown, ScanFilter};
+pub(crate) use heartbeat::HeartbeatScheduler;
+pub use lock::ResolveLocksSummary;
+pub(crate) use lock::{
+    push_min_commit_ts, resolve_expired_locks, resolve_locks, resolve_locks_in_range, scan_locks,
+    trace_lock, HasLocks, LockDecision,
+};
+pub use mvcc::{MvccInfo, MvccKind, MvccLock, MvccValue, MvccWrite};
+pub use purge::{PurgeOptions, PurgeProgress, PURGE_BATCH_SIZE};
+pub use read_cache::ReadCache;
+pub use scan_cache::ScanCache;
 pub use snapshot::Snapshot;
 #[doc(hidden)]
 pub use transaction::HeartbeatOption;
-pub use transaction::{CheckLevel, Transaction, TransactionOptions};
+pub use transaction::{
+    CheckLevel, LockPolicy, PessimisticLockOptions, ReadOptions, ReplicaRead, Savepoint,
+    Transaction, TransactionOptions, COMMIT_CHUNK_SIZE, SCAN_STREAM_BATCH_SIZE,
+};
 
 mod buffer;
 mod client;
+mod export;
+mod filter;
+mod gc;
+mod heartbeat;
 pub mod lowering;
 #[macro_use]
 mod requests;
 mod lock;
+mod mvcc;
+mod operation_log;
+mod purge;
+mod read_cache;
+mod scan_cache;
 mod snapshot;
+mod stale;
 #[allow(clippy::module_inception)]
 mod transaction;
@@ -0,0 +1,218 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! The MVCC history of keys as stored by TiKV, to diagnose reads without tikv-ctl, see
+//! [`Transaction::get_all_versions`](crate::Transaction::get_all_versions) and
+//! [`TransactionClient::get_by_start_ts`](crate::TransactionClient::get_by_start_ts).
+
+use crate::{Key, Value};
+use tikv_client_proto::kvrpcpb;
+
+/// The MVCC history of a key: its lock, its writes and its values, as stored by TiKV in the lock,
+/// write and default column families.
+///
+/// Only the versions which were not garbage collected are returned.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct MvccInfo {
+    /// The lock of the key, if it is locked.
+    pub lock: Option<MvccLock>,
+    /// The writes of the key, from the latest commit to the oldest.
+    pub writes: Vec<MvccWrite>,
+    /// The values of the key which are too long to be stored in their writes, from the latest to
+    /// the oldest.
+    pub values: Vec<MvccValue>,
+}
+
+impl MvccInfo {
+    /// The value of the key read at `timestamp`, `None` if the key did not exist then.
+    ///
+    /// The lock of the key is ignored, so the value is the one read once the lock is resolved if
+    /// it is rolled back, or if it is committed after `timestamp`.
+    pub fn value_at(&self, timestamp: u64) -> Option<Value> {
+        let write = self
+            .writes
+            .iter()
+            .filter(|write| write.commit_ts <= timestamp)
+            .find(|write| matches!(write.kind, MvccKind::Put | MvccKind::Delete))?;
+        if write.kind == MvccKind::Delete {
+            return None;
+        }
+        match &write.short_value {
+            Some(value) => Some(value.clone()),
+            None => self
+                .values
+                .iter()
+                .find(|value| value.start_ts == write.start_ts)
+                .map(|value| value.value.clone()),
+        }
+    }
+}
+
+impl From<kvrpcpb::MvccInfo> for MvccInfo {
+    fn from(info: kvrpcpb::MvccInfo) -> MvccInfo {
+        MvccInfo {
+            lock: info.lock.map(Into::into),
+            writes: info.writes.into_iter().map(Into::into).collect(),
+            values: info.values.into_iter().map(Into::into).collect(),
+        }
+    }
+}
+
+/// The kind of a lock or a write in the history of a key.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum MvccKind {
+    /// The key is written with a value.
+    Put,
+    /// The key is deleted.
+    Delete,
+    /// The key is locked without being written, e.g. by
+    /// [`lock_keys`](crate::Transaction::lock_keys).
+    Lock,
+    /// The transaction was rolled back, only a write.
+    Rollback,
+    /// The key is locked by a pessimistic lock, only a lock.
+    PessimisticLock,
+}
+
+impl From<kvrpcpb::Op> for MvccKind {
+    fn from(op: kvrpcpb::Op) -> MvccKind {
+        match op {
+            // TiKV stores the checks of insertions as the writes they check
+            kvrpcpb::Op::Put | kvrpcpb::Op::Insert => MvccKind::Put,
+            kvrpcpb::Op::Del => MvccKind::Delete,
+            kvrpcpb::Op::Lock | kvrpcpb::Op::CheckNotExists => MvccKind::Lock,
+            kvrpcpb::Op::Rollback => MvccKind::Rollback,
+            kvrpcpb::Op::PessimisticLock => MvccKind::PessimisticLock,
+        }
+    }
+}
+
+/// The lock of a key, see [`MvccInfo`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MvccLock {
+    pub kind: MvccKind,
+    /// The start timestamp of the transaction holding the lock.
+    pub start_ts: u64,
+    /// The primary key of the transaction holding the lock.
+    pub primary: Key,
+    /// The value written by the transaction, if it is short enough to be stored in the lock.
+    pub short_value: Option<Value>,
+    /// The time to live of the lock in milliseconds, since the physical time of `start_ts`.
+    pub ttl: u64,
+    /// The `for_update_ts` of a pessimistic lock, 0 for other locks.
+    pub for_update_ts: u64,
+    /// The number of keys the transaction writes in the region of the key.
+    pub txn_size: u64,
+    /// Whether the transaction commits asynchronously.
+    pub use_async_commit: bool,
+    /// The secondary keys of the transaction, if it commits asynchronously and the key is its
+    /// primary key.
+    pub secondaries: Vec<Key>,
+    /// The start timestamps of the transactions rolled back on the key while it was locked.
+    pub rollback_ts: Vec<u64>,
+}
+
+impl From<kvrpcpb::MvccLock> for MvccLock {
+    fn from(mut lock: kvrpcpb::MvccLock) -> MvccLock {
+        MvccLock {
+            kind: lock.get_type().into(),
+            start_ts: lock.start_ts,
+            primary: lock.take_primary().into(),
+            short_value: Some(lock.take_short_value()).filter(|value| !value.is_empty()),
+            ttl: lock.ttl,
+            for_update_ts: lock.for_update_ts,
+            txn_size: lock.txn_size,
+            use_async_commit: lock.use_async_commit,
+            secondaries: lock
+                .take_secondaries()
+                .into_iter()
+                .map(Into::into)
+                .collect(),
+            rollback_ts: lock.take_rollback_ts(),
+        }
+    }
+}
+
+/// A write of a key, see [`MvccInfo`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MvccWrite {
+    pub kind: MvccKind,
+    /// The start timestamp of the transaction which wrote the key.
+    pub start_ts: u64,
+    /// The commit timestamp of the transaction, equal to `start_ts` for a rollback.
+    pub commit_ts: u64,
+    /// The value written, if it is short enough to be stored in the write, otherwise it is one of
+    /// the [`values`](MvccInfo::values).
+    pub short_value: Option<Value>,
+    /// Whether a rollback of another transaction with the same timestamp overlaps the write.
+    pub has_overlapped_rollback: bool,
+    /// The commit timestamp after which the write is no longer valid, if TiKV set a GC fence on
+    /// it.
+    pub gc_fence: Option<u64>,
+}
+
+impl From<kvrpcpb::MvccWrite> for MvccWrite {
+    fn from(mut write: kvrpcpb::MvccWrite) -> MvccWrite {
+        MvccWrite {
+            kind: write.get_type().into(),
+            start_ts: write.start_ts,
+            commit_ts: write.commit_ts,
+            short_value: Some(write.take_short_value()).filter(|value| !value.is_empty()),
+            has_overlapped_rollback: write.has_overlapped_rollback,
+            gc_fence: write.has_gc_fence.then_some(write.gc_fence),
+        }
+    }
+}
+
+/// A value of a key too long to be stored in its write, see [`MvccInfo`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MvccValue {
+    /// The start timestamp of the transaction which wrote the value.
+    pub start_ts: u64,
+    pub value: Value,
+}
+
+impl From<kvrpcpb::MvccValue> for MvccValue {
+    fn from(value: kvrpcpb::MvccValue) -> MvccValue {
+        MvccValue {
+            start_ts: value.start_ts,
+            value: value.value,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_value_at() {
+        let write = |kind: MvccKind, start_ts: u64, short_value: &[u8]| MvccWrite {
+            kind,
+            start_ts,
+            commit_ts: start_ts + 1,
+            short_value: Some(short_value.to_vec()).filter(|value| !value.is_empty()),
+            has_overlapped_rollback: false,
+            gc_fence: None,
+        };
+        let info = MvccInfo {
+            lock: None,
+            writes: vec![
+                write(MvccKind::Rollback, 9, b""),
+                write(MvccKind::Lock, 7, b""),
+                write(MvccKind::Put, 5, b"short"),
+                write(MvccKind::Delete, 3, b""),
+                write(MvccKind::Put, 1, b""),
+            ],
+            values: vec![MvccValue {
+                start_ts: 1,
+                value: b"long".to_vec(),
+            }],
+        };
+        assert_eq!(info.value_at(1), None);
+        assert_eq!(info.value_at(2), Some(b"long".to_vec()));
+        assert_eq!(info.value_at(4), None);
+        // locks and rollbacks don't change the value
+        assert_eq!(info.value_at(6), Some(b"short".to_vec()));
+        assert_eq!(info.value_at(20), Some(b"short".to_vec()));
+    }
+}
@@ -0,0 +1,152 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+use crate::{Error, Key, WriteOrigin};
+use std::collections::{HashMap, VecDeque};
+
+/// The maximum number of entries kept by an [`OperationLog`], older entries are dropped.
+const MAX_ENTRIES: usize = 256;
+
+/// The operations performed on a transaction, without their keys and values, see
+/// [`TransactionOptions::record_operations`](crate::TransactionOptions::record_operations).
+///
+/// Consecutive runs of the same operation are kept as one entry with their number.
+///
+/// The log also keeps which write operation wrote each key last, to attach it to the assertions
+/// which fail when the transaction commits. Unlike the entries, these are kept for all keys.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct OperationLog {
+    entries: VecDeque<(&'static str, usize)>,
+    /// The number of operations dropped from the front of the log.
+    dropped: usize,
+    /// The latest write operation of each key written, by encoded key.
+    writes: HashMap<Key, WriteOrigin>,
+    /// The number of write operations recorded.
+    write_count: u64,
+}
+
+impl OperationLog {
+    pub fn record(&mut self, operation: &'static str) {
+        match self.entries.back_mut() {
+            Some((last, count)) if *last == operation => *count += 1,
+            _ => {
+                if self.entries.len() == MAX_ENTRIES {
+                    let (_, count) = self.entries.pop_front().unwrap();
+                    self.dropped += count;
+                }
+                self.entries.push_back((operation, 1));
+            }
+        }
+    }
+
+    /// Records that the write operation `operation` wrote `keys`.
+    pub fn record_write<'a>(
+        &mut self,
+        operation: &'static str,
+        keys: impl IntoIterator<Item = &'a Key>,
+    ) {
+        let origin = WriteOrigin {
+            operation,
+            index: self.write_count,
+        };
+        self.write_count += 1;
+        for key in keys {
+            self.writes.insert(key.clone(), origin.clone());
+        }
+    }
+
+    /// Sets the operations which wrote the keys of the failed assertions in `e`.
+    pub fn attribute_assertions(&self, e: &mut Error) {
+        match e {
+            Error::AssertionFailed {
+                key, written_by, ..
+            } => *written_by = self.writes.get(&Key::from(key.clone())).cloned(),
+            Error::MultipleKeyErrors(errors) | Error::ExtractedErrors(errors) => {
+                for e in errors {
+                    self.attribute_assertions(e);
+                }
+            }
+            Error::UndeterminedError(e)
+            | Error::RetriesExhausted { source: e, .. }
+            | Error::RequestFailed { source: e, .. } => self.attribute_assertions(e),
+            _ => {}
+        }
+    }
+
+    /// The entries of the log in order, e.g. `["get", "put x3", "commit"]`.
+    pub fn entries(&self) -> Vec<String> {
+        let dropped = (self.dropped > 0).then(|| format!("{} earlier operations", self.dropped));
+        let entries = self.entries.iter().map(|(operation, count)| match count {
+            1 => operation.to_string(),
+            count => format!("{} x{}", operation, count),
+        });
+        dropped.into_iter().chain(entries).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Assertion;
+
+    #[test]
+    fn test_operation_log() {
+        let mut log = OperationLog::default();
+        assert!(log.entries().is_empty());
+        log.record("get");
+        log.record("put");
+        log.record("put");
+        log.record("put");
+        log.record("get");
+        assert_eq!(log.entries(), vec!["get", "put x3", "get"]);
+
+        // the oldest entries are dropped once the log is full
+        let mut log = OperationLog::default();
+        log.record("put");
+        log.record("put");
+        for _ in 0..MAX_ENTRIES / 2 {
+            log.record("get");
+            log.record("delete");
+        }
+        log.record("commit");
+        let entries = log.entries();
+        assert_eq!(entries.len(), MAX_ENTRIES + 1);
+        assert_eq!(entries[0], "3 earlier operations");
+        assert_eq!(entries[1], "delete");
+        assert_eq!(entries[MAX_ENTRIES], "commit");
+    }
+
+    #[test]
+    fn test_attribute_assertions() {
+        let mut log = OperationLog::default();
+        let key = |k: u8| Key::from(vec![k]);
+        log.record_write("insert", &[key(1)]);
+        log.record_write("put", &[key(2), key(3)]);
+        log.record_write("put_with_assertion", &[key(1)]);
+        let failed = |k: u8| Error::AssertionFailed {
+            key: vec![k],
+            assertion: Assertion::NotExist,
+            start_ts: 1,
+            existing_start_ts: 0,
+            existing_commit_ts: 0,
+            written_by: None,
+        };
+
+        let mut e = Error::MultipleKeyErrors(vec![failed(1), failed(3), failed(4)]);
+        log.attribute_assertions(&mut e);
+        let written_by: Vec<_> = match e {
+            Error::MultipleKeyErrors(errors) => errors
+                .into_iter()
+                .map(|e| match e {
+                    Error::AssertionFailed { written_by, .. } => written_by,
+                    _ => unreachable!(),
+                })
+                .collect(),
+            _ => unreachable!(),
+        };
+        let origin = |operation, index| Some(WriteOrigin { operation, index });
+        assert_eq!(
+            written_by,
+            vec![origin("put_with_assertion", 2), origin("put", 1), None]
+        );
+    }
+}
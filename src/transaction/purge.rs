@@ -0,0 +1,271 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+use crate::{clock::ClockHandle, pd::PdClient, BoundRange, Key, Result, Transaction};
+use std::{fmt, future::Future, sync::Arc, time::Duration};
+
+type ProgressCallback = dyn Fn(&PurgeProgress) + Send + Sync;
+
+/// The number of keys deleted by each transaction of a [`purge`](crate::TransactionClient::purge)
+/// by default.
+pub const PURGE_BATCH_SIZE: u32 = 1024;
+
+/// Options of a [`purge`](crate::TransactionClient::purge).
+#[derive(Clone)]
+pub struct PurgeOptions {
+    pub(crate) batch_size: u32,
+    pub(crate) max_keys_per_sec: Option<u32>,
+    pub(crate) destroy_min_regions: Option<usize>,
+    pub(crate) progress: Option<Arc<ProgressCallback>>,
+}
+
+impl Default for PurgeOptions {
+    fn default() -> PurgeOptions {
+        PurgeOptions {
+            batch_size: PURGE_BATCH_SIZE,
+            max_keys_per_sec: None,
+            destroy_min_regions: None,
+            progress: None,
+        }
+    }
+}
+
+impl fmt::Debug for PurgeOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PurgeOptions")
+            .field("batch_size", &self.batch_size)
+            .field("max_keys_per_sec", &self.max_keys_per_sec)
+            .field("destroy_min_regions", &self.destroy_min_regions)
+            .finish()
+    }
+}
+
+impl PurgeOptions {
+    pub fn new() -> PurgeOptions {
+        PurgeOptions::default()
+    }
+
+    /// Delete at most `batch_size` keys in each transaction, [`PURGE_BATCH_SIZE`] by default.
+    /// Larger batches need fewer transactions, but conflict with more concurrent writers.
+    pub fn batch_size(mut self, batch_size: u32) -> PurgeOptions {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// Throttle the transactional deletes to `max_keys_per_sec` keys per second on average, so
+    /// that the purge doesn't compete with the foreground traffic. Not throttled by default.
+    pub fn max_keys_per_sec(mut self, max_keys_per_sec: u32) -> PurgeOptions {
+        self.max_keys_per_sec = Some(max_keys_per_sec.max(1));
+        self
+    }
+
+    /// Destroy the ranges spanning at least `min_regions` regions at once with
+    /// [`unsafe_destroy_range`](crate::TransactionClient::unsafe_destroy_range) instead of
+    /// deleting their keys transactionally.
+    ///
+    /// Destroying a range is much faster, but it bypasses transactions: it is only safe if the
+    /// range is no longer read or written, see
+    /// [`unsafe_destroy_range`](crate::TransactionClient::unsafe_destroy_range).
+    pub fn destroy_ranges_spanning(mut self, min_regions: usize) -> PurgeOptions {
+        self.destroy_min_regions = Some(min_regions);
+        self
+    }
+
+    /// Report the progress of the purge to `callback` after each batch of keys deleted and each
+    /// range destroyed. It is called from the task running the purge, so it should return
+    /// quickly.
+    pub fn progress(mut self, callback: impl Fn(&PurgeProgress) + Send + Sync + 'static) -> Self {
+        self.progress = Some(Arc::new(callback));
+        self
+    }
+}
+
+/// The progress of a [`purge`](crate::TransactionClient::purge), reported after each batch of
+/// keys deleted and each range destroyed, and returned once the purge is done.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct PurgeProgress {
+    /// The number of ranges purged so far. Ranges are purged one after the other, in the order
+    /// in which they were given.
+    pub ranges_completed: usize,
+    /// The number of ranges to purge.
+    pub ranges_total: usize,
+    /// The number of keys deleted transactionally so far. Destroyed ranges don't report how many
+    /// keys they contained.
+    pub keys_deleted: u64,
+    /// The number of ranges destroyed so far, see
+    /// [`destroy_ranges_spanning`](PurgeOptions::destroy_ranges_spanning).
+    pub ranges_destroyed: usize,
+    /// The last key deleted in the range being purged, `None` at the start of a range. A purge
+    /// which failed can be resumed from the key after it.
+    pub current_key: Option<Key>,
+}
+
+/// Deletes the keys of `range` in transactions begun by `begin`, each deleting at most
+/// `options.batch_size` keys, throttled by `options.max_keys_per_sec`. `on_batch` is called with
+/// the number of keys and the last key of each batch committed.
+pub(crate) async fn delete_in_batches<PdC, Fut>(
+    begin: impl Fn() -> Fut,
+    range: BoundRange,
+    options: &PurgeOptions,
+    clock: ClockHandle,
+    mut on_batch: impl FnMut(u32, Key),
+) -> Result<()>
+where
+    PdC: PdClient,
+    Fut: Future<Output = Result<Transaction<PdC>>>,
+{
+    let (mut start, end) = range.into_keys();
+    loop {
+        let batch_start = clock.now();
+        let mut txn = begin().await?;
+        let deleted: Result<Vec<Key>> = async {
+            let keys: Vec<Key> = txn
+                .scan_keys((start.clone(), end.clone()), options.batch_size)
+                .await?
+                .collect();
+            for key in &keys {
+                txn.delete(key.clone()).await?;
+            }
+            Ok(keys)
+        }
+        .await;
+        let keys = match deleted {
+            Ok(keys) if keys.is_empty() => {
+                txn.rollback().await?;
+                return Ok(());
+            }
+            Ok(keys) => {
+                txn.commit().await?;
+                keys
+            }
+            Err(e) => {
+                txn.rollback().await?;
+                return Err(e);
+            }
+        };
+
+        let count = keys.len() as u32;
+        let last = keys.into_iter().last().unwrap();
+        let mut next = Vec::from(last.clone());
+        next.push(0);
+        start = next.into();
+        on_batch(count, last);
+
+        if let Some(max_keys_per_sec) = options.max_keys_per_sec {
+            let min_duration = Duration::from_secs_f64(count as f64 / max_keys_per_sec as f64);
+            let elapsed = clock.elapsed_since(batch_start);
+            if elapsed < min_duration {
+                clock.sleep(min_duration - elapsed).await;
+            }
+        }
+        // the range is exhausted
+        if count < options.batch_size {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        clock::MockClock,
+        mock::{MockKvClient, MockPdClient},
+        timestamp::TimestampExt,
+        transaction::HeartbeatOption,
+        Timestamp, TransactionOptions,
+    };
+    use slog::Logger;
+    use std::{
+        any::Any,
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Mutex,
+        },
+    };
+    use tikv_client_proto::kvrpcpb;
+
+    #[tokio::test]
+    async fn test_delete_in_batches() {
+        // the stored keys are k0..k4, the keys deleted are those prewritten
+        let deleted = Arc::new(Mutex::new(Vec::new()));
+        let prewritten = deleted.clone();
+        let pd_client = Arc::new(MockPdClient::new(MockKvClient::with_dispatch_hook(
+            move |req: &dyn Any| {
+                if let Some(req) = req.downcast_ref::<kvrpcpb::ScanRequest>() {
+                    assert!(req.key_only);
+                    let pairs = (0..5u8)
+                        .map(|i| vec![b'k', b'0' + i])
+                        .filter(|key| key >= &req.start_key && key < &req.end_key)
+                        .take(req.limit as usize)
+                        .map(|key| kvrpcpb::KvPair {
+                            key,
+                            ..Default::default()
+                        })
+                        .collect();
+                    let resp = kvrpcpb::ScanResponse {
+                        pairs,
+                        ..Default::default()
+                    };
+                    Ok(Box::new(resp) as Box<dyn Any>)
+                } else if let Some(req) = req.downcast_ref::<kvrpcpb::PrewriteRequest>() {
+                    prewritten
+                        .lock()
+                        .unwrap()
+                        .extend(req.mutations.iter().map(|m| m.key.clone()));
+                    Ok(Box::new(kvrpcpb::PrewriteResponse::default()) as Box<dyn Any>)
+                } else if req.is::<kvrpcpb::CommitRequest>() {
+                    Ok(Box::new(kvrpcpb::CommitResponse::default()) as Box<dyn Any>)
+                } else {
+                    unreachable!()
+                }
+            },
+        )));
+        let clock = MockClock::auto_advancing();
+        let ts = Arc::new(AtomicU64::new(0));
+        let begin = || {
+            let pd_client = pd_client.clone();
+            let ts = ts.fetch_add(1, Ordering::SeqCst);
+            async move {
+                Ok(Transaction::new(
+                    Timestamp::from_version(ts),
+                    pd_client,
+                    TransactionOptions::new_optimistic()
+                        .heartbeat_option(HeartbeatOption::NoHeartbeat),
+                    Logger::root(slog::Discard, o!()),
+                ))
+            }
+        };
+        let options = PurgeOptions::new().batch_size(2).max_keys_per_sec(10);
+        let mut batches = Vec::new();
+        delete_in_batches(
+            begin,
+            (b"k1".to_vec()..b"k9".to_vec()).into(),
+            &options,
+            ClockHandle::new(clock.clone()),
+            |count, last| batches.push((count, last)),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            batches,
+            vec![
+                (2, Key::from(b"k2".to_vec())),
+                (2, Key::from(b"k4".to_vec()))
+            ]
+        );
+        assert_eq!(
+            *deleted.lock().unwrap(),
+            vec![
+                b"k1".to_vec(),
+                b"k2".to_vec(),
+                b"k3".to_vec(),
+                b"k4".to_vec()
+            ]
+        );
+        // the last batch was full, so the range is scanned once more; the batches of 2 keys at
+        // 10 keys per second took 200ms each
+        assert_eq!(ts.load(Ordering::SeqCst), 3);
+        assert_eq!(clock.elapsed(), Duration::from_millis(400));
+    }
+}
@@ -0,0 +1,147 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+use crate::{Key, Value};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+/// A bounded, least-recently-used cache of values read by point gets.
+///
+/// Entries are keyed by the key and the timestamp of the read. Since data at a given timestamp
+/// never changes, a cached value can be shared by all snapshots and transactions reading at the
+/// same timestamp. A `ReadCache` is cheap to clone and clones share the same cache, so one cache
+/// can be passed to many transactions with
+/// [`TransactionOptions::read_cache`](crate::TransactionOptions::read_cache).
+///
+/// Writes made by a transaction invalidate the written keys in the cache of that transaction.
+#[derive(Clone)]
+pub struct ReadCache {
+    inner: Arc<Mutex<ReadCacheInner>>,
+}
+
+struct ReadCacheInner {
+    capacity: usize,
+    // The last access tick is stored alongside the value to find the entry in `lru`.
+    entries: HashMap<(Key, u64), (Option<Value>, u64)>,
+    // Access tick -> entry, the first entry is the least recently used one.
+    lru: BTreeMap<u64, (Key, u64)>,
+    tick: u64,
+}
+
+impl ReadCache {
+    /// Create a cache which holds at most `capacity` entries.
+    pub fn new(capacity: usize) -> ReadCache {
+        ReadCache {
+            inner: Arc::new(Mutex::new(ReadCacheInner {
+                capacity,
+                entries: HashMap::new(),
+                lru: BTreeMap::new(),
+                tick: 0,
+            })),
+        }
+    }
+
+    /// Returns `Some(value)` if a read of `key` at `version` is cached. The inner `value` is
+    /// `None` if the key was read and did not exist.
+    pub(crate) fn get(&self, key: &Key, version: u64) -> Option<Option<Value>> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.tick += 1;
+        let tick = inner.tick;
+        let cache_key = (key.clone(), version);
+        let (value, old_tick) = match inner.entries.get_mut(&cache_key) {
+            Some((value, last_tick)) => {
+                let old_tick = *last_tick;
+                *last_tick = tick;
+                (value.clone(), old_tick)
+            }
+            None => return None,
+        };
+        inner.lru.remove(&old_tick);
+        inner.lru.insert(tick, cache_key);
+        Some(value)
+    }
+
+    pub(crate) fn insert(&self, key: Key, version: u64, value: Option<Value>) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.capacity == 0 {
+            return;
+        }
+        inner.tick += 1;
+        let tick = inner.tick;
+        let cache_key = (key, version);
+        if let Some((_, old_tick)) = inner.entries.insert(cache_key.clone(), (value, tick)) {
+            inner.lru.remove(&old_tick);
+        }
+        inner.lru.insert(tick, cache_key);
+
+        while inner.entries.len() > inner.capacity {
+            let oldest = *inner.lru.keys().next().unwrap();
+            let evicted = inner.lru.remove(&oldest).unwrap();
+            inner.entries.remove(&evicted);
+        }
+    }
+
+    /// Remove the read of `key` at `version` from the cache.
+    pub(crate) fn invalidate(&self, key: &Key, version: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some((_, tick)) = inner.entries.remove(&(key.clone(), version)) {
+            inner.lru.remove(&tick);
+        }
+    }
+
+    /// The number of cached entries.
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl PartialEq for ReadCache {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.inner, &other.inner)
+    }
+}
+
+impl fmt::Debug for ReadCache {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let inner = self.inner.lock().unwrap();
+        f.debug_struct("ReadCache")
+            .field("capacity", &inner.capacity)
+            .field("len", &inner.entries.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_cache_lru() {
+        let cache = ReadCache::new(2);
+        cache.insert(b"k1".to_vec().into(), 1, Some(b"v1".to_vec()));
+        cache.insert(b"k2".to_vec().into(), 1, None);
+        assert_eq!(
+            cache.get(&b"k1".to_vec().into(), 1),
+            Some(Some(b"v1".to_vec()))
+        );
+        assert_eq!(cache.get(&b"k2".to_vec().into(), 1), Some(None));
+        assert_eq!(cache.get(&b"k1".to_vec().into(), 2), None);
+
+        // k1 is used more recently than k2
+        cache.get(&b"k1".to_vec().into(), 1);
+        cache.insert(b"k3".to_vec().into(), 1, Some(b"v3".to_vec()));
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(&b"k2".to_vec().into(), 1), None);
+        assert!(cache.get(&b"k1".to_vec().into(), 1).is_some());
+
+        cache.invalidate(&b"k1".to_vec().into(), 1);
+        assert_eq!(cache.get(&b"k1".to_vec().into(), 1), None);
+        assert_eq!(cache.len(), 1);
+    }
+}
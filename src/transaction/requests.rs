@@ -4,12 +4,12 @@ use crate::{
     collect_first,
     pd::PdClient,
     request::{
-        Collect, CollectSingle, CollectWithShard, DefaultProcessor, KvRequest, Merge, Process,
-        ResponseWithShard, Shardable, SingleKey,
+        Collect, CollectSingle, CollectWithShard, DefaultProcessor, Idempotence, KvRequest, Merge,
+        Process, ResponseWithShard, Shardable, SingleKey,
     },
-    store::{store_stream_for_keys, store_stream_for_range_by_start_key, RegionStore},
+    store::{store_stream_for_keys, store_stream_for_range, RegionStore},
     timestamp::TimestampExt,
-    transaction::HasLocks,
+    transaction::{HasLocks, MvccInfo, PessimisticLockOptions},
     util::iter::FlatMapOkIterExt,
     Key, KvPair, Result, Value,
 };
@@ -42,6 +42,20 @@ macro_rules! pair_locks {
                         .collect()
                 }
             }
+
+            fn skip_locks(&mut self) -> Vec<kvrpcpb::LockInfo> {
+                let mut skipped = Vec::new();
+                self.pairs.retain(|pair| {
+                    match pair.error.as_ref().and_then(|error| error.locked.as_ref()) {
+                        Some(lock) => {
+                            skipped.push(lock.clone());
+                            false
+                        }
+                        None => true,
+                    }
+                });
+                skipped
+            }
         }
     };
 }
@@ -140,7 +154,34 @@ impl KvRequest for kvrpcpb::ScanRequest {
     type Response = kvrpcpb::ScanResponse;
 }
 
-shardable_range!(kvrpcpb::ScanRequest);
+impl Shardable for kvrpcpb::ScanRequest {
+    type Shard = (Vec<u8>, Vec<u8>);
+
+    fn shards(
+        &self,
+        pd_client: &Arc<impl PdClient>,
+    ) -> BoxStream<'static, Result<(Self::Shard, RegionStore)>> {
+        // a reverse scan scans from `start_key` down to `end_key`
+        let range = if self.reverse {
+            (self.end_key.clone(), self.start_key.clone())
+        } else {
+            (self.start_key.clone(), self.end_key.clone())
+        };
+        store_stream_for_range(range, pd_client.clone())
+    }
+
+    fn apply_shard(&mut self, shard: Self::Shard, _store: &RegionStore) -> Result<()> {
+        let (start_key, end_key) = shard;
+        if self.reverse {
+            self.set_start_key(end_key);
+            self.set_end_key(start_key);
+        } else {
+            self.set_start_key(start_key);
+            self.set_end_key(end_key);
+        }
+        Ok(())
+    }
+}
 
 impl Merge<kvrpcpb::ScanResponse> for Collect {
     type Out = Vec<KvPair>;
@@ -164,10 +205,20 @@ pub fn new_resolve_lock_request(
     req
 }
 
-// Note: ResolveLockRequest is a special one: it can be sent to a specified
-// region without keys. So it's not Shardable. And we don't automatically retry
-// on its region errors (in the Plan level). The region error must be manually
-// handled (in the upper level).
+/// A request resolving all the locks of the transactions in `txn_infos` in a region, each
+/// committed at its `status`, or rolled back if it is 0. TiKV rejects batch requests with keys.
+pub fn new_batch_resolve_lock_request(
+    txn_infos: Vec<kvrpcpb::TxnInfo>,
+) -> kvrpcpb::ResolveLockRequest {
+    let mut req = kvrpcpb::ResolveLockRequest::default();
+    req.set_txn_infos(txn_infos);
+
+    req
+}
+
+// Note: ResolveLockRequest is a special one: it can be sent to a specified region without keys.
+// It's not shardable, so we don't automatically retry on its region errors (in the Plan level).
+// They must be manually handled (in the upper level).
 impl KvRequest for kvrpcpb::ResolveLockRequest {
     type Response = kvrpcpb::ResolveLockResponse;
 }
@@ -247,22 +298,40 @@ impl Shardable for kvrpcpb::PrewriteRequest {
         store_stream_for_keys(mutations.into_iter(), pd_client.clone())
     }
 
-    fn apply_shard(&mut self, shard: Self::Shard, store: &RegionStore) -> Result<()> {
-        self.set_context(store.region_with_leader.context()?);
+    fn apply_shard(&mut self, shard: Self::Shard, _store: &RegionStore) -> Result<()> {
+        // Only if there is only one request to send
+        if self.try_one_pc && shard.len() != self.secondaries.len() + 1 {
+            self.set_try_one_pc(false);
+        }
 
         // Only need to set secondary keys if we're sending the primary key.
-        if self.use_async_commit && !self.mutations.iter().any(|m| m.key == self.primary_lock) {
+        let has_primary = shard.iter().any(|m| m.key == self.primary_lock);
+        if !(self.try_one_pc || (self.use_async_commit && has_primary)) {
             self.set_secondaries(vec![]);
         }
 
-        // Only if there is only one request to send
-        if self.try_one_pc && shard.len() != self.secondaries.len() + 1 {
-            self.set_try_one_pc(false);
+        // either all mutations are pessimistic locks or none is, see `clear_shards`
+        if let Some(&is_pessimistic_lock) = self.is_pessimistic_lock.first() {
+            self.set_is_pessimistic_lock(vec![is_pessimistic_lock; shard.len()]);
         }
-
         self.set_mutations(shard);
         Ok(())
     }
+
+    fn chunk_shard(&self, shard: Self::Shard, chunk_size: usize) -> Vec<Self::Shard> {
+        // the values are moved into the chunks rather than copied
+        let mut mutations = shard.into_iter().peekable();
+        let mut chunks = Vec::new();
+        while mutations.peek().is_some() {
+            chunks.push(mutations.by_ref().take(chunk_size.max(1)).collect());
+        }
+        chunks
+    }
+
+    fn clear_shards(&mut self) {
+        self.mutations = Vec::new();
+        self.is_pessimistic_lock.truncate(1);
+    }
 }
 
 pub fn new_commit_request(
@@ -280,6 +349,9 @@ pub fn new_commit_request(
 
 impl KvRequest for kvrpcpb::CommitRequest {
     type Response = kvrpcpb::CommitResponse;
+    // whether the transaction is committed is undetermined after a lost response, which the
+    // transaction checks with the status of its primary key rather than guessing with a retry
+    const IDEMPOTENCE: Idempotence = Idempotence::RequiresStatusCheck;
 }
 
 shardable_keys!(kvrpcpb::CommitRequest);
@@ -326,7 +398,7 @@ pub fn new_pessimistic_lock_request(
     start_version: u64,
     lock_ttl: u64,
     for_update_ts: u64,
-    need_value: bool,
+    options: &PessimisticLockOptions,
 ) -> kvrpcpb::PessimisticLockRequest {
     let mut req = kvrpcpb::PessimisticLockRequest::default();
     req.set_mutations(mutations);
@@ -336,9 +408,10 @@ pub fn new_pessimistic_lock_request(
     req.set_for_update_ts(for_update_ts);
     // FIXME: make them configurable
     req.set_is_first_lock(false);
-    req.set_wait_timeout(0);
+    req.set_wait_timeout(options.wait_timeout_ms());
     req.set_force(false);
-    req.set_return_values(need_value);
+    req.set_return_values(options.return_values);
+    req.set_check_existence(options.check_existence);
     // FIXME: support large transaction
     req.set_min_commit_ts(0);
 
@@ -347,6 +420,8 @@ pub fn new_pessimistic_lock_request(
 
 impl KvRequest for kvrpcpb::PessimisticLockRequest {
     type Response = kvrpcpb::PessimisticLockResponse;
+    // the values returned by a retried lock are read at a later time than the original lock
+    const IDEMPOTENCE: Idempotence = Idempotence::RequiresStatusCheck;
 }
 
 impl Shardable for kvrpcpb::PessimisticLockRequest {
@@ -361,8 +436,7 @@ impl Shardable for kvrpcpb::PessimisticLockRequest {
         store_stream_for_keys(mutations.into_iter(), pd_client.clone())
     }
 
-    fn apply_shard(&mut self, shard: Self::Shard, store: &RegionStore) -> Result<()> {
-        self.set_context(store.region_with_leader.context()?);
+    fn apply_shard(&mut self, shard: Self::Shard, _store: &RegionStore) -> Result<()> {
         self.set_mutations(shard);
         Ok(())
     }
@@ -387,19 +461,19 @@ impl Merge<ResponseWithShard<kvrpcpb::PessimisticLockResponse, Vec<kvrpcpb::Muta
                 let values = resp.take_values();
                 let values_len = values.len();
                 let not_founds = resp.take_not_founds();
-                let kvpairs = mutations
-                    .into_iter()
-                    .map(|m| m.key)
-                    .zip(values)
-                    .map(KvPair::from);
-                assert_eq!(kvpairs.len(), values_len);
+                let keys = mutations.into_iter().map(|m| m.key);
                 if not_founds.is_empty() {
+                    let kvpairs = keys.zip(values).map(KvPair::from);
+                    assert_eq!(kvpairs.len(), values_len);
                     // Legacy TiKV does not distiguish not existing key and existing key
                     // that with empty value. We assume that key does not exist if value
                     // is empty.
                     Either::Left(kvpairs.filter(|kvpair| !kvpair.value().is_empty()))
                 } else {
-                    assert_eq!(kvpairs.len(), not_founds.len());
+                    assert_eq!(keys.len(), not_founds.len());
+                    // only the existence of the keys is returned without `return_values`
+                    let values = values.into_iter().chain(iter::repeat_with(Vec::new));
+                    let kvpairs = keys.zip(values).map(KvPair::from);
                     Either::Right(kvpairs.zip(not_founds).filter_map(|(kvpair, not_found)| {
                         if not_found {
                             None
@@ -415,11 +489,13 @@ impl Merge<ResponseWithShard<kvrpcpb::PessimisticLockResponse, Vec<kvrpcpb::Muta
 
 pub fn new_scan_lock_request(
     start_key: Vec<u8>,
+    end_key: Vec<u8>,
     safepoint: u64,
     limit: u32,
 ) -> kvrpcpb::ScanLockRequest {
     let mut req = kvrpcpb::ScanLockRequest::default();
     req.set_start_key(start_key);
+    req.set_end_key(end_key);
     req.set_max_version(safepoint);
     req.set_limit(limit);
     req
@@ -430,18 +506,21 @@ impl KvRequest for kvrpcpb::ScanLockRequest {
 }
 
 impl Shardable for kvrpcpb::ScanLockRequest {
-    type Shard = Vec<u8>;
+    type Shard = (Vec<u8>, Vec<u8>);
 
     fn shards(
         &self,
         pd_client: &Arc<impl PdClient>,
     ) -> BoxStream<'static, Result<(Self::Shard, RegionStore)>> {
-        store_stream_for_range_by_start_key(self.start_key.clone().into(), pd_client.clone())
+        store_stream_for_range(
+            (self.start_key.clone(), self.end_key.clone()),
+            pd_client.clone(),
+        )
     }
 
-    fn apply_shard(&mut self, shard: Self::Shard, store: &RegionStore) -> Result<()> {
-        self.set_context(store.region_with_leader.context()?);
-        self.set_start_key(shard);
+    fn apply_shard(&mut self, shard: Self::Shard, _store: &RegionStore) -> Result<()> {
+        self.set_start_key(shard.0);
+        self.set_end_key(shard.1);
         Ok(())
     }
 }
@@ -483,8 +562,7 @@ impl Shardable for kvrpcpb::TxnHeartBeatRequest {
         crate::store::store_stream_for_keys(std::iter::once(self.key().clone()), pd_client.clone())
     }
 
-    fn apply_shard(&mut self, mut shard: Self::Shard, store: &RegionStore) -> Result<()> {
-        self.set_context(store.region_with_leader.context()?);
+    fn apply_shard(&mut self, mut shard: Self::Shard, _store: &RegionStore) -> Result<()> {
         assert!(shard.len() == 1);
         self.primary_lock = shard.pop().unwrap();
         Ok(())
@@ -507,6 +585,26 @@ impl Process<kvrpcpb::TxnHeartBeatResponse> for DefaultProcessor {
     }
 }
 
+pub fn new_check_txn_status_request(
+    primary_key: Vec<u8>,
+    lock_ts: u64,
+    caller_start_ts: u64,
+    current_ts: u64,
+    rollback_if_not_exist: bool,
+    force_sync_commit: bool,
+    resolving_pessimistic_lock: bool,
+) -> kvrpcpb::CheckTxnStatusRequest {
+    let mut req = kvrpcpb::CheckTxnStatusRequest::default();
+    req.set_primary_key(primary_key);
+    req.set_lock_ts(lock_ts);
+    req.set_caller_start_ts(caller_start_ts);
+    req.set_current_ts(current_ts);
+    req.set_rollback_if_not_exist(rollback_if_not_exist);
+    req.set_force_sync_commit(force_sync_commit);
+    req.set_resolving_pessimistic_lock(resolving_pessimistic_lock);
+    req
+}
+
 impl KvRequest for kvrpcpb::CheckTxnStatusRequest {
     type Response = kvrpcpb::CheckTxnStatusResponse;
 }
@@ -521,8 +619,7 @@ impl Shardable for kvrpcpb::CheckTxnStatusRequest {
         crate::store::store_stream_for_keys(std::iter::once(self.key().clone()), pd_client.clone())
     }
 
-    fn apply_shard(&mut self, mut shard: Self::Shard, store: &RegionStore) -> Result<()> {
-        self.set_context(store.region_with_leader.context()?);
+    fn apply_shard(&mut self, mut shard: Self::Shard, _store: &RegionStore) -> Result<()> {
         assert!(shard.len() == 1);
         self.set_primary_key(shard.pop().unwrap());
         Ok(())
@@ -535,6 +632,8 @@ impl SingleKey for kvrpcpb::CheckTxnStatusRequest {
     }
 }
 
+collect_first!(kvrpcpb::CheckTxnStatusResponse);
+
 impl Process<kvrpcpb::CheckTxnStatusResponse> for DefaultProcessor {
     type Out = TransactionStatus;
 
@@ -576,6 +675,16 @@ impl From<(u64, u64, Option<kvrpcpb::LockInfo>)> for TransactionStatusKind {
     }
 }
 
+pub fn new_check_secondary_locks_request(
+    keys: Vec<Vec<u8>>,
+    start_version: u64,
+) -> kvrpcpb::CheckSecondaryLocksRequest {
+    let mut req = kvrpcpb::CheckSecondaryLocksRequest::default();
+    req.set_keys(keys);
+    req.set_start_version(start_version);
+    req
+}
+
 impl KvRequest for kvrpcpb::CheckSecondaryLocksRequest {
     type Response = kvrpcpb::CheckSecondaryLocksResponse;
 }
@@ -672,3 +781,103 @@ impl KvRequest for kvrpcpb::DeleteRangeRequest  {
 impl HasLocks for kvrpcpb::DeleteRangeResponse {}
 
 shardable_range!(kvrpcpb::DeleteRangeRequest);
+
+pub fn new_unsafe_destroy_range_request(
+    start_key: Vec<u8>,
+    end_key: Vec<u8>,
+) -> kvrpcpb::UnsafeDestroyRangeRequest {
+    let mut req = kvrpcpb::UnsafeDestroyRangeRequest::default();
+    req.set_start_key(start_key);
+    req.set_end_key(end_key);
+
+    req
+}
+
+impl KvRequest for kvrpcpb::UnsafeDestroyRangeRequest {
+    type Response = kvrpcpb::UnsafeDestroyRangeResponse;
+}
+impl HasLocks for kvrpcpb::UnsafeDestroyRangeResponse {}
+
+pub fn new_store_safe_ts_request() -> kvrpcpb::StoreSafeTsRequest {
+    // an empty key range covers all regions of the store
+    kvrpcpb::StoreSafeTsRequest::default()
+}
+
+impl KvRequest for kvrpcpb::StoreSafeTsRequest {
+    type Response = kvrpcpb::StoreSafeTsResponse;
+}
+impl HasLocks for kvrpcpb::StoreSafeTsResponse {}
+
+pub fn new_mvcc_get_by_key_request(key: Vec<u8>) -> kvrpcpb::MvccGetByKeyRequest {
+    let mut req = kvrpcpb::MvccGetByKeyRequest::default();
+    req.set_key(key);
+    req
+}
+
+impl KvRequest for kvrpcpb::MvccGetByKeyRequest {
+    type Response = kvrpcpb::MvccGetByKeyResponse;
+}
+impl HasLocks for kvrpcpb::MvccGetByKeyResponse {}
+
+shardable_key!(kvrpcpb::MvccGetByKeyRequest);
+collect_first!(kvrpcpb::MvccGetByKeyResponse);
+impl SingleKey for kvrpcpb::MvccGetByKeyRequest {
+    fn key(&self) -> &Vec<u8> {
+        &self.key
+    }
+}
+
+impl Process<kvrpcpb::MvccGetByKeyResponse> for DefaultProcessor {
+    type Out = MvccInfo;
+
+    fn process(&self, input: Result<kvrpcpb::MvccGetByKeyResponse>) -> Result<Self::Out> {
+        Ok(input?.take_info().into())
+    }
+}
+
+pub fn new_mvcc_get_by_start_ts_request(start_ts: u64) -> kvrpcpb::MvccGetByStartTsRequest {
+    let mut req = kvrpcpb::MvccGetByStartTsRequest::default();
+    req.set_start_ts(start_ts);
+    req
+}
+
+impl KvRequest for kvrpcpb::MvccGetByStartTsRequest {
+    type Response = kvrpcpb::MvccGetByStartTsResponse;
+}
+impl HasLocks for kvrpcpb::MvccGetByStartTsResponse {}
+
+// the request has no key, it is sent to every region, each looking for the transaction in its keys
+impl Shardable for kvrpcpb::MvccGetByStartTsRequest {
+    type Shard = (Vec<u8>, Vec<u8>);
+
+    fn shards(
+        &self,
+        pd_client: &Arc<impl PdClient>,
+    ) -> BoxStream<'static, Result<(Self::Shard, RegionStore)>> {
+        store_stream_for_range((vec![], vec![]), pd_client.clone())
+    }
+
+    fn apply_shard(&mut self, _shard: Self::Shard, _store: &RegionStore) -> Result<()> {
+        Ok(())
+    }
+}
+
+// the first key written by the transaction in any region, and its history
+impl Merge<kvrpcpb::MvccGetByStartTsResponse> for Collect {
+    type Out = Option<(Key, MvccInfo)>;
+
+    fn merge(&self, input: Vec<Result<kvrpcpb::MvccGetByStartTsResponse>>) -> Result<Self::Out> {
+        let mut first: Option<(Key, MvccInfo)> = None;
+        for resp in input {
+            let mut resp = resp?;
+            if resp.key.is_empty() {
+                continue;
+            }
+            let key = Key::from(resp.take_key());
+            if first.as_ref().is_none_or(|(first, _)| key < *first) {
+                first = Some((key, resp.take_info().into()));
+            }
+        }
+        Ok(first)
+    }
+}
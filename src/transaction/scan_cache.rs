@@ -0,0 +1,253 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+use crate::{BoundRange, Key, KvPair};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+/// A bounded, least-recently-used cache of the pairs read by scans, for read-mostly workloads
+/// which scan the same ranges over and over again.
+///
+/// Entries are keyed by the range, the limit and the direction of the scan and by the timestamp
+/// of the read. Like a [`ReadCache`](crate::ReadCache), a `ScanCache` is cheap to clone and clones
+/// share the same cache, so one cache can be passed to many transactions with
+/// [`TransactionOptions::scan_cache`](crate::TransactionOptions::scan_cache). It is the second
+/// level beneath the buffer of each transaction: a transaction overlays its own writes on the
+/// cached pairs.
+///
+/// The cache holds at most `capacity` bytes of keys and values, a scan which would take more is
+/// not cached. Writes made by a transaction invalidate the cached scans of that transaction which
+/// cover the written keys.
+#[derive(Clone)]
+pub struct ScanCache {
+    inner: Arc<Mutex<ScanCacheInner>>,
+}
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+struct ScanKey {
+    start: Key,
+    end: Option<Key>,
+    limit: u32,
+    reverse: bool,
+    key_only: bool,
+    version: u64,
+}
+
+impl ScanKey {
+    fn covers(&self, key: &Key, version: u64) -> bool {
+        self.version == version
+            && key >= &self.start
+            && self.end.as_ref().is_none_or(|end| key < end)
+    }
+}
+
+struct ScanCacheEntry {
+    pairs: Vec<KvPair>,
+    size: usize,
+    // The last access tick, to find the entry in `lru`.
+    tick: u64,
+}
+
+struct ScanCacheInner {
+    capacity: usize,
+    size: usize,
+    entries: HashMap<ScanKey, ScanCacheEntry>,
+    // Access tick -> entry, the first entry is the least recently used one.
+    lru: BTreeMap<u64, ScanKey>,
+    tick: u64,
+}
+
+impl ScanCacheInner {
+    fn remove(&mut self, scan_key: &ScanKey) {
+        if let Some(entry) = self.entries.remove(scan_key) {
+            self.lru.remove(&entry.tick);
+            self.size -= entry.size;
+        }
+    }
+}
+
+impl ScanCache {
+    /// Create a cache which holds at most `capacity` bytes of scanned keys and values.
+    pub fn new(capacity: usize) -> ScanCache {
+        ScanCache {
+            inner: Arc::new(Mutex::new(ScanCacheInner {
+                capacity,
+                size: 0,
+                entries: HashMap::new(),
+                lru: BTreeMap::new(),
+                tick: 0,
+            })),
+        }
+    }
+
+    /// Returns the pairs of a scan of `range` at `version` if it is cached.
+    pub(crate) fn get(
+        &self,
+        range: BoundRange,
+        limit: u32,
+        reverse: bool,
+        key_only: bool,
+        version: u64,
+    ) -> Option<Vec<KvPair>> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.tick += 1;
+        let tick = inner.tick;
+        let scan_key = scan_key(range, limit, reverse, key_only, version);
+        let (pairs, old_tick) = match inner.entries.get_mut(&scan_key) {
+            Some(entry) => {
+                let old_tick = entry.tick;
+                entry.tick = tick;
+                (entry.pairs.clone(), old_tick)
+            }
+            None => return None,
+        };
+        inner.lru.remove(&old_tick);
+        inner.lru.insert(tick, scan_key);
+        Some(pairs)
+    }
+
+    pub(crate) fn insert(
+        &self,
+        range: BoundRange,
+        limit: u32,
+        reverse: bool,
+        key_only: bool,
+        version: u64,
+        pairs: Vec<KvPair>,
+    ) {
+        let scan_key = scan_key(range, limit, reverse, key_only, version);
+        let size = scan_key.start.len()
+            + scan_key.end.as_ref().map_or(0, Key::len)
+            + pairs
+                .iter()
+                .map(|pair| pair.key().len() + pair.value().len())
+                .sum::<usize>();
+        let mut inner = self.inner.lock().unwrap();
+        inner.remove(&scan_key);
+        if size > inner.capacity {
+            return;
+        }
+        inner.tick += 1;
+        let tick = inner.tick;
+        inner.size += size;
+        inner.lru.insert(tick, scan_key.clone());
+        inner
+            .entries
+            .insert(scan_key, ScanCacheEntry { pairs, size, tick });
+
+        while inner.size > inner.capacity {
+            let oldest = inner.lru.values().next().unwrap().clone();
+            inner.remove(&oldest);
+        }
+    }
+
+    /// Remove the scans at `version` whose range contains `key` from the cache.
+    pub(crate) fn invalidate(&self, key: &Key, version: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        let stale: Vec<ScanKey> = inner
+            .entries
+            .keys()
+            .filter(|scan_key| scan_key.covers(key, version))
+            .cloned()
+            .collect();
+        for scan_key in &stale {
+            inner.remove(scan_key);
+        }
+    }
+
+    /// The number of cached scans.
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The number of bytes of keys and values held by the cache.
+    pub fn size(&self) -> usize {
+        self.inner.lock().unwrap().size
+    }
+}
+
+fn scan_key(range: BoundRange, limit: u32, reverse: bool, key_only: bool, version: u64) -> ScanKey {
+    let (start, end) = range.into_keys();
+    ScanKey {
+        start,
+        end,
+        limit,
+        reverse,
+        key_only,
+        version,
+    }
+}
+
+impl PartialEq for ScanCache {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.inner, &other.inner)
+    }
+}
+
+impl fmt::Debug for ScanCache {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let inner = self.inner.lock().unwrap();
+        f.debug_struct("ScanCache")
+            .field("capacity", &inner.capacity)
+            .field("size", &inner.size)
+            .field("len", &inner.entries.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scan_cache() {
+        let range = |start: &str, end: &str| -> BoundRange {
+            (start.as_bytes().to_vec()..end.as_bytes().to_vec()).into()
+        };
+        let pairs = |keys: &[&str]| -> Vec<KvPair> {
+            keys.iter()
+                .map(|key| KvPair::new(key.as_bytes().to_vec(), b"vv".to_vec()))
+                .collect()
+        };
+        // a scan takes 2 bytes for its range and 4 bytes for each pair
+        let cache = ScanCache::new(25);
+        cache.insert(range("a", "c"), 10, false, false, 1, pairs(&["a1", "b1"]));
+        cache.insert(range("c", "e"), 10, false, false, 1, pairs(&["c1", "d1"]));
+        assert_eq!(cache.size(), 20);
+        assert_eq!(
+            cache.get(range("a", "c"), 10, false, false, 1),
+            Some(pairs(&["a1", "b1"]))
+        );
+        assert_eq!(cache.get(range("a", "c"), 5, false, false, 1), None);
+        assert_eq!(cache.get(range("a", "c"), 10, true, false, 1), None);
+        assert_eq!(cache.get(range("a", "c"), 10, false, false, 2), None);
+
+        // the scan of a..c is used more recently than the one of c..e
+        cache.insert(range("e", "g"), 10, false, false, 1, pairs(&["e1"]));
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.size(), 16);
+        assert_eq!(cache.get(range("c", "e"), 10, false, false, 1), None);
+        assert!(cache.get(range("a", "c"), 10, false, false, 1).is_some());
+
+        // a scan larger than the cache is not cached
+        let large = pairs(&["g1", "g2", "g3", "h1", "h2", "h3"]);
+        cache.insert(range("g", "i"), 10, false, false, 1, large);
+        assert_eq!(cache.get(range("g", "i"), 10, false, false, 1), None);
+        assert_eq!(cache.len(), 2);
+
+        // only the scans at the version of the write which cover the key are invalidated
+        cache.invalidate(&b"b".to_vec().into(), 2);
+        cache.invalidate(&b"c".to_vec().into(), 1);
+        assert_eq!(cache.len(), 2);
+        cache.invalidate(&b"b".to_vec().into(), 1);
+        assert_eq!(cache.get(range("a", "c"), 10, false, false, 1), None);
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.size(), 6);
+    }
+}
@@ -1,6 +1,8 @@
 // Copyright 2019 TiKV Project Authors. Licensed under Apache-2.0.
 
-use crate::{BoundRange, Key, KvPair, Result, Transaction, Value};
+use crate::{
+    BoundRange, Key, KvPair, ReadOptions, ReplicaRead, Result, ScanFilter, Transaction, Value,
+};
 use derive_new::new;
 use futures::stream::BoxStream;
 use slog::Logger;
@@ -20,6 +22,20 @@ pub struct Snapshot {
 }
 
 impl Snapshot {
+    /// Set the options for subsequent reads of this snapshot.
+    pub fn set_read_options(&mut self, options: ReadOptions) {
+        self.transaction.set_read_options(options);
+    }
+
+    /// Set which replicas serve subsequent reads of this snapshot, see
+    /// [`TransactionOptions::replica_read`](crate::TransactionOptions::replica_read).
+    ///
+    /// The reads of a [stale snapshot](crate::TransactionClient::stale_snapshot) can be served by
+    /// any replica already, they become replica reads served by the given replicas instead.
+    pub fn set_replica_read(&mut self, replica_read: ReplicaRead) {
+        self.transaction.set_replica_read(replica_read);
+    }
+
     /// Get the value associated with the given key.
     pub async fn get(&mut self, key: impl Into<Key>) -> Result<Option<Value>> {
         debug!(self.logger, "invoking get request on snapshot");
@@ -51,15 +67,48 @@ impl Snapshot {
         self.transaction.scan(range, limit).await
     }
 
+    /// Scan a range as a stream, fetching the pairs in batches as the stream is consumed.
+    pub async fn scan_stream(
+        &mut self,
+        range: impl Into<BoundRange>,
+    ) -> Result<BoxStream<'static, Result<KvPair>>> {
+        debug!(self.logger, "invoking scan_stream request on snapshot");
+        self.transaction.scan_stream(range).await
+    }
+
+    /// Scan the pairs of a range which match `filter` as a stream, evaluating the filter in the
+    /// coprocessor of TiKV if it can be pushed down.
+    pub async fn scan_filtered(
+        &mut self,
+        range: impl Into<BoundRange>,
+        filter: ScanFilter,
+    ) -> Result<BoxStream<'static, Result<KvPair>>> {
+        debug!(self.logger, "invoking scan_filtered request on snapshot");
+        self.transaction.scan_filtered(range, filter).await
+    }
+
+    /// Scan a range in reverse, return at most `limit` key-value pairs from the end of the range,
+    /// in descending order.
     pub async fn scan_reverse(
         &mut self,
         range: impl Into<BoundRange>,
         limit: u32,
     ) -> Result<impl Iterator<Item = KvPair>> {
-        debug!(self.logger, "invoking scan request on snapshot");
+        debug!(self.logger, "invoking scan_reverse request on snapshot");
         self.transaction.scan_reverse(range, limit).await
     }
 
+    /// Scan several ranges concurrently, return at most `limit_per_range` key-value pairs of each
+    /// range, each tagged with the index of its range in `ranges`.
+    pub async fn scan_ranges(
+        &mut self,
+        ranges: impl IntoIterator<Item = impl Into<BoundRange>>,
+        limit_per_range: u32,
+    ) -> Result<impl Iterator<Item = (usize, KvPair)>> {
+        debug!(self.logger, "invoking scan_ranges request on snapshot");
+        self.transaction.scan_ranges(ranges, limit_per_range).await
+    }
+
     /// Scan a range, return at most `limit` keys that lying in the range.
     pub async fn scan_keys(
         &mut self,
@@ -70,13 +119,17 @@ impl Snapshot {
         self.transaction.scan_keys(range, limit).await
     }
 
-    #[allow(dead_code)]
+    /// Scan a range in reverse, return at most `limit` keys from the end of the range, in
+    /// descending order.
     pub async fn scan_keys_reverse(
         &mut self,
         range: impl Into<BoundRange>,
         limit: u32,
     ) -> Result<impl Iterator<Item = Key>> {
-        debug!(self.logger, "invoking scan_keys request on snapshot");
+        debug!(
+            self.logger,
+            "invoking scan_keys_reverse request on snapshot"
+        );
         self.transaction.scan_keys_reverse(range, limit).await
     }
 }
@@ -0,0 +1,119 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+use crate::{
+    cluster::is_tikv_store,
+    pd::PdClient,
+    request::{Plan, PlanBuilder},
+    stats::MetricsLabels,
+    timestamp::TimestampExt,
+    transaction::requests::new_store_safe_ts_request,
+    Result, Timestamp,
+};
+use futures::future;
+use std::{sync::Arc, time::Duration};
+
+/// The newest timestamp at most `max_staleness` older than `current` which all TiKV stores have
+/// caught up with, see
+/// [`snapshot_with_max_staleness`](crate::TransactionClient::snapshot_with_max_staleness).
+///
+/// If the stores have not caught up with the oldest timestamp the staleness allows, that
+/// timestamp is returned anyway, and the stale reads at it wait for the replicas to catch up.
+pub(crate) async fn bounded_stale_timestamp(
+    pd_client: Arc<impl PdClient>,
+    current: Timestamp,
+    max_staleness: Duration,
+    labels: MetricsLabels,
+) -> Result<Timestamp> {
+    let oldest = Timestamp {
+        physical: (current.physical - max_staleness.as_millis() as i64).max(0),
+        ..Default::default()
+    };
+    let safe_ts = min_store_safe_ts(pd_client, labels).await?;
+    Ok(match safe_ts {
+        Some(safe_ts) if safe_ts > oldest.version() => {
+            Timestamp::from_version(safe_ts.min(current.version()))
+        }
+        _ => oldest,
+    })
+}
+
+/// The minimum `safe_ts` of the regions of all TiKV stores, i.e. the newest timestamp every
+/// replica can serve stale reads at. A store which can't be reached counts as not caught up at
+/// all. `None` if there are no stores.
+async fn min_store_safe_ts(
+    pd_client: Arc<impl PdClient>,
+    labels: MetricsLabels,
+) -> Result<Option<u64>> {
+    let stores = pd_client.clone().stores().await?;
+    let requests = stores.into_iter().filter(is_tikv_store).map(|store| {
+        let pd_client = pd_client.clone();
+        let labels = labels.clone();
+        async move {
+            let safe_ts: Result<u64> = async {
+                let kv_client = pd_client.store_client(store.get_address()).await?;
+                let plan = PlanBuilder::new(pd_client, new_store_safe_ts_request())
+                    .labels(labels)
+                    .store(kv_client)
+                    .plan();
+                Ok(plan.execute().await?.safe_ts)
+            }
+            .await;
+            safe_ts.unwrap_or(0)
+        }
+    });
+    Ok(future::join_all(requests).await.into_iter().min())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{MockKvClient, MockPdClient};
+    use std::{
+        any::Any,
+        sync::atomic::{AtomicU64, Ordering},
+    };
+    use tikv_client_proto::kvrpcpb;
+
+    #[tokio::test]
+    async fn test_bounded_stale_timestamp() {
+        let version = |physical| {
+            let timestamp = Timestamp {
+                physical,
+                ..Default::default()
+            };
+            timestamp.version()
+        };
+        // each of the three stores of the mock reports one of the safe timestamps, in ms
+        let pd_client = |reported: [i64; 3]| {
+            let requests = AtomicU64::new(0);
+            Arc::new(MockPdClient::new(MockKvClient::with_dispatch_hook(
+                move |req: &dyn Any| {
+                    assert!(req.is::<kvrpcpb::StoreSafeTsRequest>());
+                    let i = requests.fetch_add(1, Ordering::SeqCst) as usize;
+                    let resp = kvrpcpb::StoreSafeTsResponse {
+                        safe_ts: version(reported[i]),
+                    };
+                    Ok(Box::new(resp) as Box<dyn Any>)
+                },
+            )))
+        };
+        let current = Timestamp {
+            physical: 110,
+            logical: 3,
+            ..Default::default()
+        };
+        let max_staleness = Duration::from_millis(50);
+        let bounded = |reported| {
+            let pd_client = pd_client(reported);
+            let labels = MetricsLabels::default();
+            bounded_stale_timestamp(pd_client, current.clone(), max_staleness, labels)
+        };
+
+        // all stores caught up with 90ms, which is within the staleness bound
+        let timestamp = bounded([100, 90, 95]).await.unwrap();
+        assert_eq!(timestamp.version(), version(90));
+        // a store lags behind the bound, the oldest timestamp within the bound is read
+        let timestamp = bounded([100, 40, 95]).await.unwrap();
+        assert_eq!((timestamp.physical, timestamp.logical), (60, 0));
+    }
+}
@@ -2,21 +2,47 @@
 
 use crate::{
     backoff::{Backoff, DEFAULT_REGION_BACKOFF},
+    config::SizeLimits,
+    coprocessor::coprocessor_stream,
+    key_validator,
+    namespace::Namespace,
     pd::{PdClient, PdRpcClient},
     request::{
+        plan::MULTI_REGION_CONCURRENCY, scan_stream, scan_with_limit, scan_with_limit_reverse,
         Collect, CollectError, CollectSingle, CollectWithShard, Plan, PlanBuilder, RetryOptions,
+        ScanPrefetch,
     },
+    stats::{observe_txn_wait, MetricsLabels},
     timestamp::TimestampExt,
-    transaction::{buffer::Buffer, lowering::*},
-    BoundRange, Error, Key, KvPair, Result, Value,
+    trace::{in_current_trace, in_span},
+    transaction::{
+        buffer::{Buffer, BufferSnapshot, CoalescingRules, Mutation},
+        heartbeat::HeartbeatScheduler,
+        lowering::*,
+        operation_log::OperationLog,
+        requests::{new_check_txn_status_request, TransactionStatusKind},
+        MvccInfo, ReadCache, ScanCache,
+    },
+    Assertion, BoundRange, ClusterInfo, CommandPriority, ContextHook, Error, Key, KvPair, Result,
+    ScanFilter, Value,
 };
 use derive_new::new;
 use fail::fail_point;
-use futures::{prelude::*, stream::BoxStream};
+use futures::{future::BoxFuture, prelude::*, stream::BoxStream};
 use slog::Logger;
-use std::{iter, ops::RangeBounds, sync::Arc, time::Instant};
+use std::{
+    cmp::Ordering,
+    collections::{BTreeSet, HashMap, HashSet},
+    iter, mem,
+    ops::RangeBounds,
+    sync::{
+        atomic::{self, AtomicU64},
+        Arc, Mutex,
+    },
+    time::Instant,
+};
 use tikv_client_proto::{kvrpcpb, pdpb::Timestamp};
-use tokio::{sync::RwLock, time::Duration};
+use tokio::{sync::RwLock, task::JoinHandle, time::Duration};
 
 /// An undo-able set of actions on the dataset.
 ///
@@ -65,6 +91,37 @@ pub struct Transaction<PdC: PdClient = PdRpcClient> {
     rpc: Arc<PdC>,
     options: TransactionOptions,
     is_heartbeat_started: bool,
+    /// Sends the heartbeats of this transaction if set, otherwise the transaction starts its own
+    /// heartbeat task.
+    heartbeat_scheduler: Option<HeartbeatScheduler<PdC>>,
+    /// The pessimistic locks to roll back when the transaction exceeds its maximum lifetime. Set
+    /// once the first lock is acquired, which starts the task rolling them back.
+    expiring_locks: Option<Arc<Mutex<ExpiringLocks>>>,
+    /// The task rolling back the pessimistic locks once the transaction exceeds its maximum
+    /// lifetime, aborted once the transaction commits, rolls back or is dropped.
+    expiry_task: Option<JoinHandle<()>>,
+    /// The pipelined pessimistic locks which may still be in flight, with the latest
+    /// `for_update_ts` of each.
+    pending_locks: Vec<JoinHandle<(Timestamp, Result<Vec<KvPair>>)>>,
+    /// The keys locked by the pessimistic locks of the transaction.
+    locked_keys: BTreeSet<Key>,
+    /// The ids of the savepoints which the transaction can roll back to, in the order in which
+    /// they were taken.
+    savepoints: Vec<u64>,
+    /// If set, reads are sent through this client as replica reads, see
+    /// [`TransactionOptions::replica_read`].
+    replica_rpc: Option<Arc<PdC>>,
+    /// If set, keys are in the namespace, see
+    /// [`TransactionClient::namespace`](crate::TransactionClient::namespace).
+    namespace: Option<Namespace>,
+    /// The operations performed on the transaction, if they are recorded, see
+    /// [`TransactionOptions::record_operations`].
+    operations: Option<OperationLog>,
+    /// The parent of the spans of the operations of the transaction, see [`Transaction::span`].
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
+    /// The commit timestamp, once the transaction is committed, see [`Transaction::commit_ts`].
+    commit_ts: Option<Timestamp>,
     start_instant: Instant,
     logger: Logger,
 }
@@ -81,18 +138,57 @@ impl<PdC: PdClient> Transaction<PdC> {
         } else {
             TransactionStatus::Active
         };
+        let logger = if options.metrics_labels.is_empty() {
+            logger
+        } else {
+            logger.new(o!("labels" => options.metrics_labels.to_string()))
+        };
+        let start_instant = rpc.clock().now();
+        let operations = options.record_operations.then(OperationLog::default);
+        #[cfg(feature = "tracing")]
+        let span = tracing::info_span!(
+            "transaction",
+            start_ts = timestamp.version(),
+            pessimistic = options.is_pessimistic()
+        );
         Transaction {
             status: Arc::new(RwLock::new(status)),
             timestamp,
-            buffer: Buffer::new(options.is_pessimistic()),
+            buffer: Buffer::new(options.is_pessimistic())
+                .with_coalescing_rules(options.coalescing_rules),
             rpc,
             options,
             is_heartbeat_started: false,
-            start_instant: std::time::Instant::now(),
+            heartbeat_scheduler: None,
+            expiring_locks: None,
+            expiry_task: None,
+            pending_locks: Vec::new(),
+            locked_keys: BTreeSet::new(),
+            savepoints: Vec::new(),
+            replica_rpc: None,
+            namespace: None,
+            operations,
+            #[cfg(feature = "tracing")]
+            span,
+            commit_ts: None,
+            start_instant,
             logger,
         }
     }
 
+    pub(crate) fn with_heartbeat_scheduler(
+        mut self,
+        scheduler: HeartbeatScheduler<PdC>,
+    ) -> Transaction<PdC> {
+        self.heartbeat_scheduler = Some(scheduler);
+        self
+    }
+
+    pub(crate) fn with_namespace(mut self, namespace: Option<Namespace>) -> Transaction<PdC> {
+        self.namespace = namespace;
+        self
+    }
+
     /// Create a new 'get' request
     ///
     /// Once resolved this request will result in the fetching of the value associated with the
@@ -113,22 +209,55 @@ impl<PdC: PdClient> Transaction<PdC> {
     /// ```
     pub async fn get(&mut self, key: impl Into<Key>) -> Result<Option<Value>> {
         debug!(self.logger, "invoking transactional get request");
+        self.record_operation("get");
         self.check_allow_operation().await?;
+        let key = key.into();
+        if self.options.read_options.lock_policy == LockPolicy::SkipLocked {
+            // a skipped key must not be buffered or cached as non-existent
+            return Ok(self
+                .batch_get(iter::once(key))
+                .await?
+                .next()
+                .map(|pair| pair.1));
+        }
+        let key = self.encode_key(key);
         let timestamp = self.timestamp.clone();
         let rpc = self.rpc.clone();
-        let key = key.into();
+        let read_rpc = self.read_rpc();
         let retry_options = self.options.retry_options.clone();
+        let read_cache = self.options.read_cache.clone();
+        let lock_policy = self.options.read_options.lock_policy;
+        let labels = self.options.metrics_labels.clone();
+        let context_hook = self.options.context_hook.clone();
+        let priority = self.options.priority;
+        let resource_group_tag = self.options.resource_group_tag.clone();
+        let deadline = self.options.deadline;
 
         self.buffer
             .get_or_else(key, |key| async move {
-                let request = new_get_request(key, timestamp);
+                let version = timestamp.version();
+                if let Some(value) = read_cache.as_ref().and_then(|c| c.get(&key, version)) {
+                    return Ok(value);
+                }
+                let request = new_get_request(key.clone(), timestamp.clone());
                 let plan = PlanBuilder::new(rpc, request)
-                    .resolve_lock(retry_options.lock_backoff)
+                    .labels(labels)
+                    .context_hook(context_hook)
+                    .priority(priority.into())
+                    .resource_group_tag(resource_group_tag)
+                    .deadline(deadline)
+                    .on_retries_exhausted(retry_options.on_read_exhausted.clone())
+                    .resolve_lock_for_read(timestamp, lock_policy, retry_options.lock_backoff)
+                    .read_through(read_rpc)
                     .retry_multi_region(DEFAULT_REGION_BACKOFF)
                     .merge(CollectSingle)
                     .post_process_default()
                     .plan();
-                plan.execute().await
+                let value = plan.execute().await?;
+                if let Some(cache) = read_cache {
+                    cache.insert(key, version, value.clone());
+                }
+                Ok(value)
             })
             .await
     }
@@ -177,13 +306,15 @@ impl<PdC: PdClient> Transaction<PdC> {
     /// ```
     pub async fn get_for_update(&mut self, key: impl Into<Key>) -> Result<Option<Value>> {
         debug!(self.logger, "invoking transactional get_for_update request");
+        self.record_operation("get_for_update");
         self.check_allow_operation().await?;
         if !self.is_pessimistic() {
             let key = key.into();
             self.lock_keys(iter::once(key.clone())).await?;
             self.get(key).await
         } else {
-            let mut pairs = self.pessimistic_lock(iter::once(key.into()), true).await?;
+            let key = self.encode_key(key.into());
+            let mut pairs = self.pessimistic_lock(iter::once(key), true).await?;
             debug_assert!(pairs.len() <= 1);
             match pairs.pop() {
                 Some(pair) => Ok(Some(pair.1)),
@@ -208,10 +339,110 @@ impl<PdC: PdClient> Transaction<PdC> {
     /// ```
     pub async fn key_exists(&mut self, key: impl Into<Key>) -> Result<bool> {
         debug!(self.logger, "invoking transactional key_exists request");
+        self.record_operation("key_exists");
         let key = key.into();
         Ok(self.scan_keys(key.clone()..=key, 1).await?.next().is_some())
     }
 
+    /// Check whether each of `keys` exists, in the order of the keys.
+    ///
+    /// Like [`key_exists`](Transaction::key_exists), only the keys are read from TiKV, without
+    /// their values, so that e.g. checking index entries doesn't pay for transferring values.
+    /// TiKV has no key-only batch gets, so each key is read by a key-only scan of the key alone
+    /// rather than by a batch get. The keys are checked concurrently, the keys written by the
+    /// transaction are checked in its buffer.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # use tikv_client::{Config, TransactionClient};
+    /// # futures::executor::block_on(async {
+    /// # let client = TransactionClient::new(vec!["192.168.0.100"], None).await.unwrap();
+    /// let mut txn = client.begin_optimistic().await.unwrap();
+    /// let keys = vec!["index/alice".to_owned(), "index/bob".to_owned()];
+    /// let exist = txn.batch_key_exists(keys).await.unwrap();
+    /// txn.commit().await.unwrap();
+    /// # });
+    /// ```
+    pub async fn batch_key_exists(
+        &mut self,
+        keys: impl IntoIterator<Item = impl Into<Key>>,
+    ) -> Result<Vec<bool>> {
+        debug!(
+            self.logger,
+            "invoking transactional batch_key_exists request"
+        );
+        self.record_operation("batch_key_exists");
+        self.check_allow_operation().await?;
+        let keys: Vec<Key> = keys
+            .into_iter()
+            .map(|key| self.encode_key(key.into()))
+            .collect();
+        let rpc = self.rpc.clone();
+        let scan = self.region_scan(false, true);
+        let buffer = &self.buffer;
+        stream::iter(keys)
+            .map(|key| {
+                let buffered = buffer.exists(&key);
+                let rpc = rpc.clone();
+                let scan = &scan;
+                async move {
+                    if let Some(exists) = buffered {
+                        return Ok(exists);
+                    }
+                    let range = BoundRange::from(key.clone()..=key);
+                    Ok(!scan_with_limit(rpc, range, 1, scan).await?.is_empty())
+                }
+            })
+            .buffered(MULTI_REGION_CONCURRENCY)
+            .try_collect()
+            .await
+    }
+
+    /// Read the MVCC history of `key` as stored by TiKV: its lock, its writes and its values at
+    /// all timestamps which were not garbage collected, see [`MvccInfo`].
+    ///
+    /// This is a debugging tool, e.g. to find out why a read returns an unexpected value. The
+    /// history is read as is, regardless of the timestamp of the transaction: the lock is not
+    /// resolved, the writes of the transaction are not in its buffer, and the read is not
+    /// consistent with the other reads of the transaction.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # use tikv_client::{Config, TransactionClient};
+    /// # futures::executor::block_on(async {
+    /// # let client = TransactionClient::new(vec!["192.168.0.100"], None).await.unwrap();
+    /// let mut txn = client.begin_optimistic().await.unwrap();
+    /// let history = txn.get_all_versions("k1".to_owned()).await.unwrap();
+    /// for write in history.writes {
+    ///     println!("{:?} at {}", write.kind, write.commit_ts);
+    /// }
+    /// txn.rollback().await.unwrap();
+    /// # });
+    /// ```
+    pub async fn get_all_versions(&mut self, key: impl Into<Key>) -> Result<MvccInfo> {
+        debug!(
+            self.logger,
+            "invoking transactional get_all_versions request"
+        );
+        self.record_operation("get_all_versions");
+        self.check_allow_operation().await?;
+        let request = new_mvcc_get_by_key_request(self.encode_key(key.into()));
+        let plan = PlanBuilder::new(self.rpc.clone(), request)
+            .labels(self.options.metrics_labels.clone())
+            .context_hook(self.options.context_hook.clone())
+            .priority(self.options.priority.into())
+            .resource_group_tag(self.options.resource_group_tag.clone())
+            .deadline(self.options.deadline)
+            .on_retries_exhausted(self.options.retry_options.on_read_exhausted.clone())
+            .retry_multi_region(self.options.retry_options.region_backoff.clone())
+            .merge(CollectSingle)
+            .post_process_default()
+            .plan();
+        plan.execute().await
+    }
+
     /// Create a new 'batch get' request.
     ///
     /// Once resolved this request will result in the fetching of the values associated with the
@@ -245,16 +476,36 @@ impl<PdC: PdClient> Transaction<PdC> {
         keys: impl IntoIterator<Item = impl Into<Key>>,
     ) -> Result<impl Iterator<Item = KvPair>> {
         debug!(self.logger, "invoking transactional batch_get request");
+        self.record_operation("batch_get");
         self.check_allow_operation().await?;
         let timestamp = self.timestamp.clone();
         let rpc = self.rpc.clone();
+        let read_rpc = self.read_rpc();
         let retry_options = self.options.retry_options.clone();
+        let lock_policy = self.options.read_options.lock_policy;
+        let labels = self.options.metrics_labels.clone();
+        let context_hook = self.options.context_hook.clone();
+        let priority = self.options.priority;
+        let resource_group_tag = self.options.resource_group_tag.clone();
+        let deadline = self.options.deadline;
+        let keys: Vec<Key> = keys
+            .into_iter()
+            .map(|k| self.encode_key(k.into()))
+            .collect();
 
-        self.buffer
-            .batch_get_or_else(keys.into_iter().map(|k| k.into()), move |keys| async move {
-                let request = new_batch_get_request(keys, timestamp);
+        let pairs = self
+            .buffer
+            .batch_get_or_else(keys.into_iter(), move |keys| async move {
+                let request = new_batch_get_request(keys, timestamp.clone());
                 let plan = PlanBuilder::new(rpc, request)
-                    .resolve_lock(retry_options.lock_backoff)
+                    .labels(labels)
+                    .context_hook(context_hook)
+                    .priority(priority.into())
+                    .resource_group_tag(resource_group_tag)
+                    .deadline(deadline)
+                    .on_retries_exhausted(retry_options.on_read_exhausted.clone())
+                    .resolve_lock_for_read(timestamp, lock_policy, retry_options.lock_backoff)
+                    .read_through(read_rpc)
                     .retry_multi_region(retry_options.region_backoff)
                     .merge(Collect)
                     .plan();
@@ -262,7 +513,8 @@ impl<PdC: PdClient> Transaction<PdC> {
                     .await
                     .map(|r| r.into_iter().map(Into::into).collect())
             })
-            .await
+            .await?;
+        Ok(self.decode_pairs(pairs)?.into_iter())
     }
 
     /// Create a new 'batch get for update' request.
@@ -300,14 +552,84 @@ impl<PdC: PdClient> Transaction<PdC> {
             self.logger,
             "invoking transactional batch_get_for_update request"
         );
+        self.record_operation("batch_get_for_update");
         self.check_allow_operation().await?;
         let keys: Vec<Key> = keys.into_iter().map(|k| k.into()).collect();
         if !self.is_pessimistic() {
             self.lock_keys(keys.clone()).await?;
             Ok(self.batch_get(keys).await?.collect())
         } else {
-            self.pessimistic_lock(keys, true).await
+            let keys: Vec<Key> = keys.into_iter().map(|key| self.encode_key(key)).collect();
+            let pairs = self.pessimistic_lock(keys, true).await?;
+            self.decode_pairs(pairs)
+        }
+    }
+
+    /// Read `keys` for update, then write the values computed from them by `f`.
+    ///
+    /// `f` is called with the current value of each key, in the order of `keys`, and returns the
+    /// new value of each key in the same order, `None` to delete it. The keys are locked like by
+    /// [`batch_get_for_update`](Transaction::batch_get_for_update), so no other transaction can
+    /// write them in between.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `f` does not return a value for each key.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # use tikv_client::{Value, TransactionClient};
+    /// # futures::executor::block_on(async {
+    /// # let client = TransactionClient::new(vec!["192.168.0.100"], None).await.unwrap();
+    /// let mut txn = client.begin_pessimistic().await.unwrap();
+    /// // move the value of "from" to "to"
+    /// txn.update(vec!["from".to_owned(), "to".to_owned()], |values| vec![None, values[0].clone()])
+    ///     .await
+    ///     .unwrap();
+    /// txn.commit().await.unwrap();
+    /// # });
+    /// ```
+    pub async fn update(
+        &mut self,
+        keys: impl IntoIterator<Item = impl Into<Key>>,
+        f: impl FnOnce(Vec<Option<Value>>) -> Vec<Option<Value>>,
+    ) -> Result<()> {
+        debug!(self.logger, "invoking transactional update request");
+        self.record_operation("update");
+        let keys: Vec<Key> = keys.into_iter().map(Into::into).collect();
+        let encoded_keys: Vec<Key> = keys
+            .iter()
+            .map(|key| self.encode_key(key.clone()))
+            .collect();
+        for key in &encoded_keys {
+            self.check_write(key, None)?;
+        }
+        let current: HashMap<Key, Value> = self
+            .batch_get_for_update(keys.clone())
+            .await?
+            .into_iter()
+            .map(|pair| (pair.0, pair.1))
+            .collect();
+        let values = f(keys.iter().map(|key| current.get(key).cloned()).collect());
+        assert_eq!(
+            values.len(),
+            keys.len(),
+            "`update` must return a value for each key"
+        );
+        for (key, value) in encoded_keys.iter().zip(&values) {
+            self.check_write(key, value.as_deref())?;
         }
+        // the keys are locked already
+        for (key, value) in encoded_keys.into_iter().zip(values) {
+            self.invalidate_read_cache(&key);
+            self.record_write("update", iter::once(&key));
+            match value {
+                Some(value) => self.buffer.put(key, value),
+                None => self.buffer.delete(key),
+            }
+        }
+        Ok(())
     }
 
     /// Create a new 'scan' request.
@@ -344,6 +666,7 @@ impl<PdC: PdClient> Transaction<PdC> {
         limit: u32,
     ) -> Result<impl Iterator<Item = KvPair>> {
         debug!(self.logger, "invoking transactional scan request");
+        self.record_operation("scan");
         self.scan_inner(range, limit, false, false).await
     }
 
@@ -380,6 +703,7 @@ impl<PdC: PdClient> Transaction<PdC> {
         limit: u32,
     ) -> Result<impl Iterator<Item = Key>> {
         debug!(self.logger, "invoking transactional scan_keys request");
+        self.record_operation("scan_keys");
         Ok(self
             .scan_inner(range, limit, false, true)
             .await?
@@ -394,25 +718,231 @@ impl<PdC: PdClient> Transaction<PdC> {
         range: impl Into<BoundRange>,
         limit: u32,
     ) -> Result<impl Iterator<Item = Key>> {
-        debug!(self.logger, "invoking transactional scan_keys request");
+        debug!(
+            self.logger,
+            "invoking transactional scan_keys_reverse request"
+        );
+        self.record_operation("scan_keys_reverse");
         Ok(self
-            .scan_inner(range, limit, false, true)
+            .scan_inner(range, limit, true, true)
             .await?
             .map(KvPair::into_key))
     }
 
+    /// Scan the key-value pairs in `range` as a stream, ordered by key.
+    ///
+    /// Unlike [`scan`](Transaction::scan), the pairs are not collected up to a limit: the range is
+    /// scanned region by region in batches of [`SCAN_STREAM_BATCH_SIZE`] pairs, and the next batch
+    /// is fetched as the stream is consumed, so a large range can be read without holding it in
+    /// memory. The writes of the transaction to the range are included as they were when the
+    /// stream was created. The stream ends after the first error.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # use tikv_client::{KvPair, TransactionClient};
+    /// # use futures::prelude::*;
+    /// # futures::executor::block_on(async {
+    /// # let client = TransactionClient::new(vec!["192.168.0.100"], None).await.unwrap();
+    /// let mut txn = client.begin_optimistic().await.unwrap();
+    /// let mut pairs = txn.scan_stream("a".to_owned().."z".to_owned()).await.unwrap();
+    /// while let Some(pair) = pairs.try_next().await.unwrap() {
+    ///     // process the pair...
+    /// }
+    /// txn.commit().await.unwrap();
+    /// # });
+    /// ```
+    pub async fn scan_stream(
+        &mut self,
+        range: impl Into<BoundRange>,
+    ) -> Result<BoxStream<'static, Result<KvPair>>> {
+        debug!(self.logger, "invoking transactional scan_stream request");
+        self.record_operation("scan_stream");
+        self.check_allow_operation().await?;
+        let range = self.encode_range(range.into());
+        let writes = self.buffer.writes_in_range(range.clone());
+        let scanned = scan_stream(
+            self.rpc.clone(),
+            range,
+            SCAN_STREAM_BATCH_SIZE,
+            ScanPrefetch::default(),
+            self.region_scan(false, false),
+        )
+        .map_ok(|pairs| stream::iter(pairs.into_iter().map(Ok)))
+        .try_flatten()
+        .boxed();
+        let pairs = overlay_writes(scanned, writes);
+        Ok(match self.namespace.clone() {
+            Some(namespace) => pairs
+                .and_then(move |KvPair(key, value)| {
+                    future::ready(namespace.decode_key(key).map(|key| KvPair(key, value)))
+                })
+                .boxed(),
+            None => pairs,
+        })
+    }
+
+    /// Scan the key-value pairs in `range` which match `filter` as a stream, ordered by key.
+    ///
+    /// Only the keys in `range` with the key prefix of the filter are scanned. If the filter has a
+    /// [`FilterPushdown`](crate::FilterPushdown) which compiles it into a coprocessor request, and
+    /// the transaction has not written to the range, the coprocessor of TiKV evaluates the filter
+    /// and only the matching pairs are sent to the client. Otherwise the range is scanned like by
+    /// [`scan_stream`](Transaction::scan_stream), and the client drops the pairs which do not
+    /// match. The stream ends after the first error.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # use tikv_client::{ScanFilter, TransactionClient};
+    /// # use futures::prelude::*;
+    /// # futures::executor::block_on(async {
+    /// # let client = TransactionClient::new(vec!["192.168.0.100"], None).await.unwrap();
+    /// let mut txn = client.begin_optimistic().await.unwrap();
+    /// let filter = ScanFilter::new()
+    ///     .key_prefix("user/".to_owned())
+    ///     .value_len(1024..);
+    /// let mut large = txn.scan_filtered(.., filter).await.unwrap();
+    /// while let Some(pair) = large.try_next().await.unwrap() {
+    ///     // process the pair...
+    /// }
+    /// txn.commit().await.unwrap();
+    /// # });
+    /// ```
+    pub async fn scan_filtered(
+        &mut self,
+        range: impl Into<BoundRange>,
+        filter: ScanFilter,
+    ) -> Result<BoxStream<'static, Result<KvPair>>> {
+        debug!(self.logger, "invoking transactional scan_filtered request");
+        self.record_operation("scan_filtered");
+        let range = match filter.restrict(range.into()) {
+            Some(range) => range,
+            None => return Ok(stream::empty().boxed()),
+        };
+        let encoded_range = self.encode_range(range.clone());
+        if let Some((pushdown, request)) = filter.compile(encoded_range.clone()) {
+            if self.buffer.writes_in_range(encoded_range).is_empty() {
+                self.check_allow_operation().await?;
+                let responses = coprocessor_stream(
+                    self.rpc.clone(),
+                    request,
+                    self.timestamp.clone(),
+                    self.options.metrics_labels.clone(),
+                );
+                let namespace = self.namespace.clone();
+                return Ok(responses
+                    .and_then(move |response| {
+                        let pairs = pushdown.decode(response);
+                        future::ready(match &namespace {
+                            Some(namespace) => {
+                                pairs.and_then(|pairs| namespace.decode_pairs(pairs))
+                            }
+                            None => pairs,
+                        })
+                    })
+                    .map_ok(|pairs| stream::iter(pairs.into_iter().map(Ok)))
+                    .try_flatten()
+                    .boxed());
+            }
+        }
+        let pairs = self.scan_stream(range).await?;
+        Ok(pairs
+            .try_filter(move |pair| future::ready(filter.matches(pair)))
+            .boxed())
+    }
+
     /// Create a 'scan_reverse' request.
     ///
-    /// Similar to [`scan`](Transaction::scan), but scans in the reverse direction.
+    /// Similar to [`scan`](Transaction::scan), but scans in the reverse direction: returns the last
+    /// `limit` pairs of the range, in descending order of their keys. The regions of the range are
+    /// scanned from the last one, so the scan stops once the last regions hold enough pairs.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # use tikv_client::{KvPair, TransactionClient};
+    /// # futures::executor::block_on(async {
+    /// # let client = TransactionClient::new(vec!["192.168.0.100"], None).await.unwrap();
+    /// let mut txn = client.begin_optimistic().await.unwrap();
+    /// // the 10 largest keys of the range
+    /// let result: Vec<KvPair> = txn
+    ///     .scan_reverse("a".to_owned().."z".to_owned(), 10)
+    ///     .await
+    ///     .unwrap()
+    ///     .collect();
+    /// txn.commit().await.unwrap();
+    /// # });
+    /// ```
     pub async fn scan_reverse(
         &mut self,
         range: impl Into<BoundRange>,
         limit: u32,
     ) -> Result<impl Iterator<Item = KvPair>> {
-        debug!(self.logger, "invoking transactional scan request");
+        debug!(self.logger, "invoking transactional scan_reverse request");
+        self.record_operation("scan_reverse");
         self.scan_inner(range, limit, true, false).await
     }
 
+    /// Scan several ranges, return at most `limit_per_range` key-value pairs of each range, each
+    /// tagged with the index of its range in `ranges`.
+    ///
+    /// The pairs are ordered by range, then by key. The ranges are scanned concurrently, so many
+    /// small ranges, e.g. the ranges of a lookup in a secondary index, take about as long to scan
+    /// as one of them. Overlapping ranges are scanned independently, so a pair can be returned for
+    /// each range it lies in.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # use tikv_client::{BoundRange, TransactionClient};
+    /// # futures::executor::block_on(async {
+    /// # let client = TransactionClient::new(vec!["192.168.0.100"], None).await.unwrap();
+    /// let mut txn = client.begin_optimistic().await.unwrap();
+    /// let ranges: Vec<BoundRange> = vec![
+    ///     ("index/alice/".to_owned().."index/alice0".to_owned()).into(),
+    ///     ("index/bob/".to_owned().."index/bob0".to_owned()).into(),
+    /// ];
+    /// for (range, pair) in txn.scan_ranges(ranges, 10).await.unwrap() {
+    ///     // process the pair of the range...
+    /// }
+    /// txn.commit().await.unwrap();
+    /// # });
+    /// ```
+    pub async fn scan_ranges(
+        &mut self,
+        ranges: impl IntoIterator<Item = impl Into<BoundRange>>,
+        limit_per_range: u32,
+    ) -> Result<impl Iterator<Item = (usize, KvPair)>> {
+        debug!(self.logger, "invoking transactional scan_ranges request");
+        self.record_operation("scan_ranges");
+        self.check_allow_operation().await?;
+        let ranges: Vec<BoundRange> = ranges
+            .into_iter()
+            .map(|range| self.encode_range(range.into()))
+            .collect();
+        let fetch = self.range_scan(false, false);
+        let buffer = &self.buffer;
+        let fetched: Vec<Vec<KvPair>> = stream::iter(ranges.clone())
+            .map(|range| {
+                let limit = buffer.scan_limit(range.clone(), limit_per_range);
+                fetch(range, limit)
+            })
+            .buffered(MULTI_REGION_CONCURRENCY)
+            .try_collect()
+            .await?;
+
+        let mut result = Vec::new();
+        for (index, (range, fetched)) in ranges.into_iter().zip(fetched).enumerate() {
+            let pairs = self
+                .buffer
+                .merge_scanned(range, limit_per_range, false, false, fetched);
+            let pairs = self.decode_pairs(pairs)?;
+            result.extend(pairs.into_iter().map(|pair| (index, pair)));
+        }
+        Ok(result.into_iter())
+    }
+
 
     /// Sets the value associated with the given key.
     ///
@@ -432,13 +962,18 @@ impl<PdC: PdClient> Transaction<PdC> {
     /// ```
     pub async fn put(&mut self, key: impl Into<Key>, value: impl Into<Value>) -> Result<()> {
         debug!(self.logger, "invoking transactional put request");
+        self.record_operation("put");
         self.check_allow_operation().await?;
-        let key = key.into();
+        let key = self.encode_key(key.into());
+        let value = value.into();
+        self.check_write(&key, Some(value.as_slice()))?;
         if self.is_pessimistic() {
             self.pessimistic_lock(iter::once(key.clone()), false)
                 .await?;
         }
-        self.buffer.put(key, value.into());
+        self.invalidate_read_cache(&key);
+        self.record_write("put", iter::once(&key));
+        self.buffer.put(key, value);
         Ok(())
     }
 
@@ -447,6 +982,10 @@ impl<PdC: PdClient> Transaction<PdC> {
     /// Similar to [`put'], but it has an additional constraint that the key should not exist
     /// before this operation.
     ///
+    /// If the key exists in TiKV, the commit fails with
+    /// [`KeyAlreadyExists`](crate::Error::KeyAlreadyExists), or in a pessimistic transaction the
+    /// insert itself.
+    ///
     /// # Examples
     ///
     /// ```rust,no_run
@@ -463,8 +1002,11 @@ impl<PdC: PdClient> Transaction<PdC> {
     /// ```
     pub async fn insert(&mut self, key: impl Into<Key>, value: impl Into<Value>) -> Result<()> {
         debug!(self.logger, "invoking transactional insert request");
+        self.record_operation("insert");
         self.check_allow_operation().await?;
-        let key = key.into();
+        let key = self.encode_key(key.into());
+        let value = value.into();
+        self.check_write(&key, Some(value.as_slice()))?;
         if self.buffer.get(&key).is_some() {
             return Err(Error::DuplicateKeyInsertion);
         }
@@ -475,7 +1017,9 @@ impl<PdC: PdClient> Transaction<PdC> {
             )
             .await?;
         }
-        self.buffer.insert(key, value.into());
+        self.invalidate_read_cache(&key);
+        self.record_write("insert", iter::once(&key));
+        self.buffer.insert(key, value);
         Ok(())
     }
 
@@ -498,919 +1042,4813 @@ impl<PdC: PdClient> Transaction<PdC> {
     /// ```
     pub async fn delete(&mut self, key: impl Into<Key>) -> Result<()> {
         debug!(self.logger, "invoking transactional delete request");
+        self.record_operation("delete");
         self.check_allow_operation().await?;
-        let key = key.into();
+        let key = self.encode_key(key.into());
+        self.check_write(&key, None)?;
         if self.is_pessimistic() {
             self.pessimistic_lock(iter::once(key.clone()), false)
                 .await?;
         }
+        self.invalidate_read_cache(&key);
+        self.record_write("delete", iter::once(&key));
         self.buffer.delete(key);
         Ok(())
     }
 
-    pub async fn delete_range(&mut self, range: impl Into<BoundRange>) -> Result<()> {
-        let request = new_delete_range_request(range.into());
-        let plan = crate::request::PlanBuilder::new(self.rpc.clone(), request)
-            .retry_multi_region(DEFAULT_REGION_BACKOFF)
-            .plan();
-        plan.execute().await;
-        Ok(())
-    }
-
-    /// Lock the given keys without mutating their values.
+    /// Like [`put`](Transaction::put), but lets TiKV verify `assertion` about whether the key
+    /// existed before the transaction when it is prewritten, e.g. for a layer on top which tracks
+    /// the existence of keys.
     ///
-    /// In optimistic mode, write conflicts are not checked until commit.
-    /// So use this command to indicate that
-    /// "I do not want to commit if the value associated with this key has been modified".
-    /// It's useful to avoid the *write skew* anomaly.
+    /// If the assertion does not hold, the commit fails with
+    /// [`AssertionFailed`](crate::Error::AssertionFailed). In pessimistic transactions, the lock
+    /// of the key verifies the assertion as well.
     ///
-    /// In pessimistic mode, it is similar to [`batch_get_for_update`](Transaction::batch_get_for_update),
-    /// except that it does not read values.
+    /// `assertion` is an [`Assertion`](crate::Assertion), or its protobuf counterpart.
     ///
     /// # Examples
     ///
     /// ```rust,no_run
-    /// # use tikv_client::{Config, TransactionClient};
+    /// # use tikv_client::{Assertion, Config, TransactionClient};
     /// # use futures::prelude::*;
     /// # futures::executor::block_on(async {
     /// # let client = TransactionClient::new(vec!["192.168.0.100"], None).await.unwrap();
     /// let mut txn = client.begin_optimistic().await.unwrap();
-    /// txn.lock_keys(vec!["TiKV".to_owned(), "Rust".to_owned()]);
-    /// // ... Do some actions.
+    /// // the key is known to exist, the put updates it
+    /// txn.put_with_assertion("foo".to_owned(), "FOO".to_owned(), Assertion::Exist)
+    ///     .await
+    ///     .unwrap();
     /// txn.commit().await.unwrap();
     /// # });
     /// ```
-    pub async fn lock_keys(
+    pub async fn put_with_assertion(
         &mut self,
-        keys: impl IntoIterator<Item = impl Into<Key>>,
+        key: impl Into<Key>,
+        value: impl Into<Value>,
+        assertion: impl Into<Assertion>,
     ) -> Result<()> {
-        debug!(self.logger, "invoking transactional lock_keys request");
+        debug!(
+            self.logger,
+            "invoking transactional put_with_assertion request"
+        );
+        self.record_operation("put_with_assertion");
         self.check_allow_operation().await?;
-        match self.options.kind {
-            TransactionKind::Optimistic => {
-                for key in keys {
-                    self.buffer.lock(key.into());
-                }
-            }
-            TransactionKind::Pessimistic(_) => {
-                self.pessimistic_lock(keys.into_iter().map(|k| k.into()), false)
-                    .await?;
-            }
+        let assertion = kvrpcpb::Assertion::from(assertion.into());
+        let key = self.encode_key(key.into());
+        let value = value.into();
+        self.check_write(&key, Some(value.as_slice()))?;
+        if self.is_pessimistic() {
+            self.pessimistic_lock(iter::once((key.clone(), assertion)), false)
+                .await?;
         }
+        self.invalidate_read_cache(&key);
+        self.record_write("put_with_assertion", iter::once(&key));
+        self.buffer.put(key.clone(), value);
+        self.buffer.assert(key, assertion);
         Ok(())
     }
 
-    /// Commits the actions of the transaction. On success, we return the commit timestamp (or
-    /// `None` if there was nothing to commit).
+    /// Like [`delete`](Transaction::delete), but lets TiKV verify `assertion` about whether the
+    /// key existed before the transaction, see
+    /// [`put_with_assertion`](Transaction::put_with_assertion).
+    pub async fn delete_with_assertion(
+        &mut self,
+        key: impl Into<Key>,
+        assertion: impl Into<Assertion>,
+    ) -> Result<()> {
+        debug!(
+            self.logger,
+            "invoking transactional delete_with_assertion request"
+        );
+        self.record_operation("delete_with_assertion");
+        self.check_allow_operation().await?;
+        let assertion = kvrpcpb::Assertion::from(assertion.into());
+        let key = self.encode_key(key.into());
+        self.check_write(&key, None)?;
+        if self.is_pessimistic() {
+            self.pessimistic_lock(iter::once((key.clone(), assertion)), false)
+                .await?;
+        }
+        self.invalidate_read_cache(&key);
+        self.record_write("delete_with_assertion", iter::once(&key));
+        self.buffer.delete(key.clone());
+        self.buffer.assert(key, assertion);
+        Ok(())
+    }
+
+    /// Buffer `mutations` in bulk, each as if it was written by the corresponding call, e.g.
+    /// [`put`](Transaction::put) for a [`Mutation::Put`] or [`lock_keys`](Transaction::lock_keys)
+    /// for a [`Mutation::Lock`], e.g. to commit mutations staged by another system.
+    ///
+    /// All mutations are checked, and their keys locked in a single request in a pessimistic
+    /// transaction, before any of them is buffered: if an error is returned, none of them is.
+    /// Protobuf mutations can be converted with `Mutation::try_from`.
     ///
     /// # Examples
     ///
     /// ```rust,no_run
-    /// # use tikv_client::{Config, Timestamp, TransactionClient};
-    /// # use futures::prelude::*;
+    /// # use tikv_client::{Mutation, TransactionClient};
     /// # futures::executor::block_on(async {
     /// # let client = TransactionClient::new(vec!["192.168.0.100"], None).await.unwrap();
+    /// let mutations = vec![
+    ///     Mutation::Put("key".to_owned().into(), b"value".to_vec()),
+    ///     Mutation::Delete("other".to_owned().into()),
+    /// ];
     /// let mut txn = client.begin_optimistic().await.unwrap();
-    /// // ... Do some actions.
-    /// let result: Timestamp = txn.commit().await.unwrap().unwrap();
+    /// txn.extend_mutations(mutations).await.unwrap();
+    /// txn.commit().await.unwrap();
     /// # });
     /// ```
-    pub async fn commit(&mut self) -> Result<Option<Timestamp>> {
-        debug!(self.logger, "commiting transaction");
-        {
-            let mut status = self.status.write().await;
-            if !matches!(
-                *status,
-                TransactionStatus::StartedCommit | TransactionStatus::Active
-            ) {
-                return Err(Error::OperationAfterCommitError);
-            }
-            *status = TransactionStatus::StartedCommit;
+    pub async fn extend_mutations(
+        &mut self,
+        mutations: impl IntoIterator<Item = Mutation>,
+    ) -> Result<()> {
+        debug!(
+            self.logger,
+            "invoking transactional extend_mutations request"
+        );
+        self.record_operation("extend_mutations");
+        self.check_allow_operation().await?;
+        // the keys written with a value by the earlier mutations, which can't be inserted
+        let mut written = HashSet::new();
+        let mut encoded = Vec::new();
+        for mut mutation in mutations {
+            let key = self.encode_key(mutation.key().clone());
+            match &mutation {
+                Mutation::Put(_, value) => self.check_write(&key, Some(value.as_slice()))?,
+                Mutation::Insert(_, value) => {
+                    self.check_write(&key, Some(value.as_slice()))?;
+                    if written.contains(&key) || self.buffer.get(&key).is_some() {
+                        return Err(Error::DuplicateKeyInsertion);
+                    }
+                }
+                _ => self.check_write(&key, None)?,
+            }
+            match &mutation {
+                Mutation::Put(..) | Mutation::Insert(..) => {
+                    written.insert(key.clone());
+                }
+                Mutation::Delete(_) | Mutation::CheckNotExists(_) => {
+                    written.remove(&key);
+                }
+                Mutation::Lock(_) => {}
+            }
+            *mutation.key_mut() = key;
+            encoded.push(mutation);
         }
-
-        let primary_key = self.buffer.get_primary_key();
-        let mutations = self.buffer.to_proto_mutations();
-        if mutations.is_empty() {
-            assert!(primary_key.is_none());
-            return Ok(None);
+        if encoded.is_empty() {
+            return Ok(());
         }
 
-        self.start_auto_heartbeat().await;
-
-        let res = Committer::new(
-            primary_key,
-            mutations,
-            self.timestamp.clone(),
-            self.rpc.clone(),
-            self.options.clone(),
-            self.buffer.get_write_size() as u64,
-            self.start_instant,
-            self.logger.new(o!("child" => 1)),
-        )
-        .commit()
-        .await;
-
-        if res.is_ok() {
-            let mut status = self.status.write().await;
-            *status = TransactionStatus::Committed;
+        if self.is_pessimistic() {
+            let locks: Vec<(Key, kvrpcpb::Assertion)> = encoded
+                .iter()
+                .map(|mutation| {
+                    let assertion = match mutation {
+                        Mutation::Insert(..) | Mutation::CheckNotExists(_) => {
+                            kvrpcpb::Assertion::NotExist
+                        }
+                        _ => kvrpcpb::Assertion::None,
+                    };
+                    (mutation.key().clone(), assertion)
+                })
+                .collect();
+            self.pessimistic_lock(locks, false).await?;
         }
-        res
+        let writes: Vec<Key> = encoded
+            .iter()
+            .filter(|mutation| !matches!(mutation, Mutation::Lock(_)))
+            .map(|mutation| mutation.key().clone())
+            .collect();
+        for key in &writes {
+            self.invalidate_read_cache(key);
+        }
+        self.record_write("extend_mutations", &writes);
+        for mutation in encoded {
+            self.buffer.apply(mutation);
+        }
+        Ok(())
     }
 
-    /// Rollback the transaction.
+    pub async fn delete_range(&mut self, range: impl Into<BoundRange>) -> Result<()> {
+        self.record_operation("delete_range");
+        let range = range.into();
+        let (start, end) = range.clone().into_keys();
+        key_validator::validate_range(self.rpc.key_validators(), &start, end.as_ref())?;
+        let request = new_delete_range_request(self.encode_range(range));
+        let plan = crate::request::PlanBuilder::new(self.rpc.clone(), request)
+            .labels(self.options.metrics_labels.clone())
+            .context_hook(self.options.context_hook.clone())
+            .priority(self.options.priority.into())
+            .resource_group_tag(self.options.resource_group_tag.clone())
+            .deadline(self.options.deadline)
+            .on_retries_exhausted(self.options.retry_options.on_write_exhausted.clone())
+            .retry_multi_region(DEFAULT_REGION_BACKOFF)
+            .plan();
+        plan.execute().await;
+        Ok(())
+    }
+
+    /// Lock the given keys without mutating their values.
     ///
-    /// If it succeeds, all mutations made by this transaction will be discarded.
+    /// In optimistic mode, write conflicts are not checked until commit.
+    /// So use this command to indicate that
+    /// "I do not want to commit if the value associated with this key has been modified".
+    /// It's useful to avoid the *write skew* anomaly.
+    ///
+    /// In pessimistic mode, it is similar to [`batch_get_for_update`](Transaction::batch_get_for_update),
+    /// except that it does not read values.
+    ///
+    /// Pessimistic locks are not retried if TiKV can't be reached, because the keys may have been
+    /// locked already. The error is returned instead, and the transaction should be rolled back.
     ///
     /// # Examples
     ///
     /// ```rust,no_run
-    /// # use tikv_client::{Config, Timestamp, TransactionClient};
+    /// # use tikv_client::{Config, TransactionClient};
     /// # use futures::prelude::*;
     /// # futures::executor::block_on(async {
     /// # let client = TransactionClient::new(vec!["192.168.0.100"], None).await.unwrap();
     /// let mut txn = client.begin_optimistic().await.unwrap();
+    /// txn.lock_keys(vec!["TiKV".to_owned(), "Rust".to_owned()]);
     /// // ... Do some actions.
-    /// txn.rollback().await.unwrap();
+    /// txn.commit().await.unwrap();
     /// # });
     /// ```
-    pub async fn rollback(&mut self) -> Result<()> {
-        debug!(self.logger, "rolling back transaction");
-        {
-            let status = self.status.read().await;
-            if !matches!(
-                *status,
-                TransactionStatus::StartedRollback
-                    | TransactionStatus::Active
-                    | TransactionStatus::StartedCommit
-            ) {
-                return Err(Error::OperationAfterCommitError);
+    pub async fn lock_keys(
+        &mut self,
+        keys: impl IntoIterator<Item = impl Into<Key>>,
+    ) -> Result<()> {
+        debug!(self.logger, "invoking transactional lock_keys request");
+        self.record_operation("lock_keys");
+        self.check_allow_operation().await?;
+        match self.options.kind {
+            TransactionKind::Optimistic => {
+                for key in keys {
+                    let key = self.encode_key(key.into());
+                    self.buffer.lock(key);
+                }
+            }
+            TransactionKind::Pessimistic(_) => {
+                let keys: Vec<Key> = keys
+                    .into_iter()
+                    .map(|k| self.encode_key(k.into()))
+                    .collect();
+                self.pessimistic_lock(keys, false).await?;
             }
         }
-
-        {
-            let mut status = self.status.write().await;
-            *status = TransactionStatus::StartedRollback;
-        }
-
-        let primary_key = self.buffer.get_primary_key();
-        let mutations = self.buffer.to_proto_mutations();
-        let res = Committer::new(
-            primary_key,
-            mutations,
-            self.timestamp.clone(),
-            self.rpc.clone(),
-            self.options.clone(),
-            self.buffer.get_write_size() as u64,
-            self.start_instant,
-            self.logger.new(o!("child" => 1)),
-        )
-        .rollback()
-        .await;
-
-        if res.is_ok() {
-            let mut status = self.status.write().await;
-            *status = TransactionStatus::Rolledback;
-        }
-        res
-    }
-
-    /// Get the start timestamp of this transaction.
-    pub fn start_timestamp(&self) -> Timestamp {
-        self.timestamp.clone()
+        Ok(())
     }
 
-    /// Send a heart beat message to keep the transaction alive on the server and update its TTL.
+    /// Lock the given keys like [`lock_keys`](Transaction::lock_keys), with `options` controlling
+    /// how long the locks wait for other transactions and what they return.
     ///
-    /// Returns the TTL set on the transaction's locks by TiKV.
-    #[doc(hidden)]
-    pub async fn send_heart_beat(&mut self) -> Result<u64> {
-        debug!(self.logger, "sending heart_beat");
-        self.check_allow_operation().await?;
-        let primary_key = match self.buffer.get_primary_key() {
-            Some(k) => k,
-            None => return Err(Error::NoPrimaryKey),
-        };
-        let request = new_heart_beat_request(
-            self.timestamp.clone(),
-            primary_key,
-            self.start_instant.elapsed().as_millis() as u64 + DEFAULT_LOCK_TTL,
+    /// Returns the locked keys which exist, with their values if
+    /// [`return_values`](PessimisticLockOptions::return_values) is set and empty values
+    /// otherwise. Nothing is returned unless `return_values` or
+    /// [`check_existence`](PessimisticLockOptions::check_existence) is set.
+    ///
+    /// In optimistic mode, the keys are locked when the transaction commits, so the
+    /// [`wait_timeout`](PessimisticLockOptions::wait_timeout) doesn't apply, and the keys are read
+    /// by a [`batch_get`](Transaction::batch_get) if their values or existence are requested.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # use tikv_client::{PessimisticLockOptions, TransactionClient};
+    /// # use std::time::Duration;
+    /// # futures::executor::block_on(async {
+    /// # let client = TransactionClient::new(vec!["192.168.0.100"], None).await.unwrap();
+    /// let mut txn = client.begin_pessimistic().await.unwrap();
+    /// let options = PessimisticLockOptions::new()
+    ///     .wait_timeout(Duration::from_millis(100))
+    ///     .check_existence(true);
+    /// // fails if another transaction holds the lock of "TiKV" for more than 100ms
+    /// let existing = txn.lock_keys_with_options(vec!["TiKV".to_owned()], options).await;
+    /// // ... Do some actions.
+    /// txn.commit().await.unwrap();
+    /// # });
+    /// ```
+    pub async fn lock_keys_with_options(
+        &mut self,
+        keys: impl IntoIterator<Item = impl Into<Key>>,
+        options: PessimisticLockOptions,
+    ) -> Result<Vec<KvPair>> {
+        debug!(
+            self.logger,
+            "invoking transactional lock_keys_with_options request"
         );
-        let plan = PlanBuilder::new(self.rpc.clone(), request)
-            .resolve_lock(self.options.retry_options.lock_backoff.clone())
-            .retry_multi_region(self.options.retry_options.region_backoff.clone())
-            .merge(CollectSingle)
-            .post_process_default()
-            .plan();
-        plan.execute().await
+        self.record_operation("lock_keys_with_options");
+        self.check_allow_operation().await?;
+        let keys: Vec<Key> = keys.into_iter().map(|k| k.into()).collect();
+        if !self.is_pessimistic() {
+            for key in &keys {
+                let key = self.encode_key(key.clone());
+                self.buffer.lock(key);
+            }
+            if !options.needs_result() {
+                return Ok(Vec::new());
+            }
+            let pairs = self.batch_get(keys).await?;
+            Ok(if options.return_values {
+                pairs.collect()
+            } else {
+                pairs
+                    .map(|pair| KvPair::new(pair.into_key(), Vec::new()))
+                    .collect()
+            })
+        } else {
+            let keys: Vec<Key> = keys.into_iter().map(|key| self.encode_key(key)).collect();
+            let pairs = self.pessimistic_lock_with_options(keys, options).await?;
+            self.decode_pairs(pairs)
+        }
     }
 
-    async fn scan_inner(
+    /// Lock the given keys, which must not exist, e.g. before inserting rows whose uniqueness the
+    /// transaction checks, so that no other transaction inserts them between the check and the
+    /// insert.
+    ///
+    /// Keys which do not exist can be locked like any other key: the lock is on the key, not on its
+    /// value. Other transactions can't write a locked key until this transaction commits or rolls
+    /// back, so the keys still don't exist when the transaction writes them. Locking a range of
+    /// keys which don't exist, i.e. a gap, is not supported by TiKV.
+    ///
+    /// In pessimistic mode, the keys are locked at once, and the lock fails with
+    /// [`KeyAlreadyExists`](crate::Error::KeyAlreadyExists) if one of them exists. If the locks
+    /// are [pipelined](TransactionOptions::pipelined_pessimistic_lock), the error is returned by
+    /// the commit instead. In optimistic mode, the keys are locked when the transaction commits,
+    /// and the commit fails with [`AssertionFailed`](crate::Error::AssertionFailed) if one of them
+    /// exists, or with a write conflict if one of them was written since the transaction started.
+    ///
+    /// If the transaction already read or wrote a value of one of the keys, it fails at once with
+    /// [`KeyAlreadyExists`](crate::Error::KeyAlreadyExists).
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # use tikv_client::{Config, TransactionClient};
+    /// # use futures::prelude::*;
+    /// # futures::executor::block_on(async {
+    /// # let client = TransactionClient::new(vec!["192.168.0.100"], None).await.unwrap();
+    /// let mut txn = client.begin_pessimistic().await.unwrap();
+    /// // fails if the email is taken, otherwise nobody else can take it until the commit
+    /// txn.lock_absent_keys(vec!["email/alice@example.com".to_owned()])
+    ///     .await
+    ///     .unwrap();
+    /// txn.put("user/alice".to_owned(), "...".to_owned()).await.unwrap();
+    /// txn.put("email/alice@example.com".to_owned(), "user/alice".to_owned())
+    ///     .await
+    ///     .unwrap();
+    /// txn.commit().await.unwrap();
+    /// # });
+    /// ```
+    pub async fn lock_absent_keys(
         &mut self,
-        range: impl Into<BoundRange>,
-        limit: u32,
-        reverse: bool,
-        key_only: bool,
-    ) -> Result<impl Iterator<Item = KvPair>> {
+        keys: impl IntoIterator<Item = impl Into<Key>>,
+    ) -> Result<()> {
+        debug!(
+            self.logger,
+            "invoking transactional lock_absent_keys request"
+        );
+        self.record_operation("lock_absent_keys");
         self.check_allow_operation().await?;
-        let timestamp = self.timestamp.clone();
-        let rpc = self.rpc.clone();
-        let retry_options = self.options.retry_options.clone();
+        let keys: Vec<Key> = keys
+            .into_iter()
+            .map(|k| self.encode_key(k.into()))
+            .collect();
+        if let Some(key) = keys.iter().find(|key| self.buffer.get(key).is_some()) {
+            return Err(Error::KeyAlreadyExists {
+                key: key.clone().into(),
+            });
+        }
+        self.record_write("lock_absent_keys", &keys);
+        match self.options.kind {
+            TransactionKind::Optimistic => {
+                for key in keys {
+                    self.buffer.lock(key.clone());
+                    self.buffer.assert(key, kvrpcpb::Assertion::NotExist);
+                }
+            }
+            TransactionKind::Pessimistic(_) => {
+                let locks = keys
+                    .iter()
+                    .map(|key| (key.clone(), kvrpcpb::Assertion::NotExist));
+                self.pessimistic_lock(locks, false)
+                    .await
+                    .map_err(already_exists_error)?;
+                for key in keys {
+                    self.buffer.assert(key, kvrpcpb::Assertion::NotExist);
+                }
+            }
+        }
+        Ok(())
+    }
 
-        self.buffer
-            .scan_and_fetch(
-                range.into(),
-                limit,
-                move |new_range, new_limit| async move {
-                    let request = new_scan_request(new_range, timestamp, new_limit, reverse, key_only);
-                    let plan = PlanBuilder::new(rpc, request)
-                        .resolve_lock(retry_options.lock_backoff)
-                        .retry_multi_region(retry_options.region_backoff)
-                        .merge(Collect)
-                        .plan();
-                    plan.execute()
-                        .await
-                        .map(|r| r.into_iter().map(Into::into).collect())
-                },
-            )
-            .await
+    /// The keys locked by the pessimistic locks of the transaction, ordered by key.
+    ///
+    /// These are the keys read by [`get_for_update`](Transaction::get_for_update) or locked by
+    /// [`lock_keys`](Transaction::lock_keys), and the keys written by a pessimistic transaction.
+    /// The keys of an optimistic transaction are only locked when it commits, so there are none.
+    pub fn locked_keys(&self) -> Vec<Key> {
+        match &self.namespace {
+            // all keys of the transaction are in its namespace
+            Some(namespace) => self
+                .locked_keys
+                .iter()
+                .filter_map(|key| namespace.decode_key(key.clone()).ok())
+                .collect(),
+            None => self.locked_keys.iter().cloned().collect(),
+        }
     }
 
-    /// Pessimistically lock the keys, and optionally retrieve corresponding values.
-    /// If a key does not exist, the corresponding pair will not appear in the result.
+    /// The mutations buffered by the transaction, ordered by key, which are prewritten when it
+    /// commits.
     ///
-    /// Once resolved it acquires locks on the keys in TiKV.
-    /// A lock prevents other transactions from mutating the entry until it is released.
+    /// Several writes of a key are coalesced into a single mutation, see [`CoalescingRules`]. The
+    /// keys which the transaction only read are not included.
     ///
-    /// # Panics
+    /// # Examples
     ///
-    /// Only valid for pessimistic transactions, panics if called on an optimistic transaction.
-    async fn pessimistic_lock(
-        &mut self,
-        keys: impl IntoIterator<Item = impl PessimisticLock>,
-        need_value: bool,
-    ) -> Result<Vec<KvPair>> {
-        debug!(self.logger, "acquiring pessimistic lock");
-        assert!(
-            matches!(self.options.kind, TransactionKind::Pessimistic(_)),
-            "`pessimistic_lock` is only valid to use with pessimistic transactions"
-        );
-
-        let keys: Vec<_> = keys.into_iter().collect();
-        if keys.is_empty() {
-            return Ok(vec![]);
+    /// ```rust,no_run
+    /// # use tikv_client::{Mutation, TransactionClient};
+    /// # futures::executor::block_on(async {
+    /// # let client = TransactionClient::new(vec!["192.168.0.100"], None).await.unwrap();
+    /// let mut txn = client.begin_optimistic().await.unwrap();
+    /// txn.put("key".to_owned(), "value".to_owned()).await.unwrap();
+    /// txn.delete("key".to_owned()).await.unwrap();
+    /// assert_eq!(
+    ///     txn.buffered_mutations(),
+    ///     vec![Mutation::Delete("key".to_owned().into())]
+    /// );
+    /// # });
+    /// ```
+    pub fn buffered_mutations(&self) -> Vec<Mutation> {
+        let mutations = self.buffer.mutations();
+        match &self.namespace {
+            // all keys of the transaction are in its namespace
+            Some(namespace) => mutations
+                .into_iter()
+                .filter_map(|mut mutation| {
+                    let key = namespace.decode_key(mutation.key().clone()).ok()?;
+                    *mutation.key_mut() = key;
+                    Some(mutation)
+                })
+                .collect(),
+            None => mutations,
         }
+    }
 
-        let first_key = keys[0].clone().key();
-        // we do not set the primary key here, because pessimistic lock request
-        // can fail, in which case the keys may not be part of the transaction.
-        let primary_lock = self
-            .buffer
-            .get_primary_key()
-            .unwrap_or_else(|| first_key.clone());
-        let for_update_ts = self.rpc.clone().get_timestamp().await?;
-        self.options.push_for_update_ts(for_update_ts.clone());
-        let request = new_pessimistic_lock_request(
-            keys.clone().into_iter(),
-            primary_lock,
+    /// Release the pessimistic locks of `keys`, which the transaction decided it won't write, so
+    /// that other transactions can write them before it finishes.
+    ///
+    /// Keys which are not locked, which were written by the transaction, or which are the primary
+    /// key of the transaction keep their state: the locks of the written keys are needed to
+    /// commit them, and the lock of the primary key holds the status of the transaction. A
+    /// released key can be locked again later. Does nothing in an optimistic transaction.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # use tikv_client::{Config, TransactionClient};
+    /// # futures::executor::block_on(async {
+    /// # let client = TransactionClient::new(vec!["192.168.0.100"], None).await.unwrap();
+    /// let mut txn = client.begin_pessimistic().await.unwrap();
+    /// let order = txn.get_for_update("order".to_owned()).await.unwrap();
+    /// let stock = txn.get_for_update("stock".to_owned()).await.unwrap();
+    /// // ... the order can't be served, the stock is left as it is
+    /// txn.release_locks(vec!["stock".to_owned()]).await.unwrap();
+    /// // ... ask the user for a new order, while others can update the stock
+    /// txn.commit().await.unwrap();
+    /// # });
+    /// ```
+    pub async fn release_locks(
+        &mut self,
+        keys: impl IntoIterator<Item = impl Into<Key>>,
+    ) -> Result<()> {
+        debug!(self.logger, "invoking transactional release_locks request");
+        self.record_operation("release_locks");
+        self.check_allow_operation().await?;
+        if !self.is_pessimistic() {
+            return Ok(());
+        }
+        // pipelined locks are rolled back once they are in place
+        self.wait_for_pending_locks().await?;
+        let primary_key = self.buffer.get_primary_key();
+        let keys: Vec<Key> = keys
+            .into_iter()
+            .map(|key| self.encode_key(key.into()))
+            .filter(|key| {
+                self.locked_keys.contains(key)
+                    && self.buffer.is_locked_only(key)
+                    && primary_key.as_ref() != Some(key)
+            })
+            .collect();
+        self.rollback_pessimistic_locks(&keys).await?;
+        for key in &keys {
+            self.buffer.unlock(key);
+        }
+        Ok(())
+    }
+
+    /// The current state of the transaction, which [`rollback_to`](Transaction::rollback_to)
+    /// returns to.
+    ///
+    /// Savepoints can be nested: rolling back to a savepoint discards the savepoints taken after
+    /// it, but keeps the savepoint itself and the savepoints taken before it.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # use tikv_client::{Config, TransactionClient};
+    /// # futures::executor::block_on(async {
+    /// # let client = TransactionClient::new(vec!["192.168.0.100"], None).await.unwrap();
+    /// let mut txn = client.begin_pessimistic().await.unwrap();
+    /// txn.put("order".to_owned(), "...".to_owned()).await.unwrap();
+    /// let savepoint = txn.savepoint();
+    /// txn.put("stock".to_owned(), "...".to_owned()).await.unwrap();
+    /// // ... the stock can't be updated after all, but the order is kept
+    /// txn.rollback_to(&savepoint).await.unwrap();
+    /// txn.commit().await.unwrap();
+    /// # });
+    /// ```
+    pub fn savepoint(&mut self) -> Savepoint {
+        self.record_operation("savepoint");
+        let id = NEXT_SAVEPOINT_ID.fetch_add(1, atomic::Ordering::Relaxed);
+        self.savepoints.push(id);
+        Savepoint {
+            id,
+            buffer: self.buffer.snapshot(),
+            locked_keys: self.locked_keys.clone(),
+        }
+    }
+
+    /// Discard the writes, locks and reads of the transaction since `savepoint` was taken, e.g.
+    /// to undo a failed statement without rolling back the whole transaction.
+    ///
+    /// In a pessimistic transaction, the pessimistic locks acquired since the savepoint are rolled
+    /// back, except for the lock of the primary key, which holds the status of the transaction.
+    /// The values read since the savepoint are read again when they are needed.
+    ///
+    /// Fails with [`InvalidSavepoint`](crate::Error::InvalidSavepoint) if `savepoint` was not
+    /// taken by this transaction, or was discarded by a rollback to an earlier savepoint.
+    pub async fn rollback_to(&mut self, savepoint: &Savepoint) -> Result<()> {
+        debug!(self.logger, "invoking transactional rollback_to request");
+        self.record_operation("rollback_to");
+        self.check_allow_operation().await?;
+        let position = self
+            .savepoints
+            .iter()
+            .position(|id| *id == savepoint.id)
+            .ok_or(Error::InvalidSavepoint)?;
+        if self.is_pessimistic() {
+            // pipelined locks are rolled back once they are in place
+            self.wait_for_pending_locks().await?;
+            let primary_key = self.buffer.get_primary_key();
+            let keys: Vec<Key> = self
+                .locked_keys
+                .difference(&savepoint.locked_keys)
+                .filter(|key| primary_key.as_ref() != Some(*key))
+                .cloned()
+                .collect();
+            self.rollback_pessimistic_locks(&keys).await?;
+        }
+        // the keys released since the savepoint stay released
+        let released: Vec<Key> = savepoint
+            .locked_keys
+            .difference(&self.locked_keys)
+            .cloned()
+            .collect();
+        self.buffer
+            .restore(savepoint.buffer.clone(), self.is_pessimistic());
+        for key in &released {
+            self.buffer.unlock(key);
+        }
+        self.savepoints.truncate(position + 1);
+        Ok(())
+    }
+
+    /// Rolls back the pessimistic locks of `keys`, and forgets that they are locked.
+    async fn rollback_pessimistic_locks(&mut self, keys: &[Key]) -> Result<()> {
+        if keys.is_empty() {
+            return Ok(());
+        }
+        let for_update_ts = match &self.options.kind {
+            TransactionKind::Pessimistic(for_update_ts) => for_update_ts.clone(),
+            TransactionKind::Optimistic => unreachable!(),
+        };
+        let request = new_pessimistic_rollback_request(
+            keys.iter().cloned(),
             self.timestamp.clone(),
-            DEFAULT_LOCK_TTL,
             for_update_ts,
-            need_value,
         );
         let plan = PlanBuilder::new(self.rpc.clone(), request)
+            .labels(self.options.metrics_labels.clone())
+            .context_hook(self.options.context_hook.clone())
+            .priority(self.options.priority.into())
+            .resource_group_tag(self.options.resource_group_tag.clone())
+            .deadline(self.options.deadline)
+            .on_retries_exhausted(self.options.retry_options.on_write_exhausted.clone())
             .resolve_lock(self.options.retry_options.lock_backoff.clone())
-            .preserve_shard()
             .retry_multi_region(self.options.retry_options.region_backoff.clone())
-            .merge(CollectWithShard)
+            .extract_error()
             .plan();
-        let pairs = plan.execute().await;
+        plan.execute().await?;
 
-        // primary key will be set here if needed
-        self.buffer.primary_key_or(&first_key);
+        if let Some(locks) = &self.expiring_locks {
+            locks.lock().unwrap().keys.retain(|key| !keys.contains(key));
+        }
+        for key in keys {
+            self.locked_keys.remove(key);
+        }
+        Ok(())
+    }
+
+    /// Commits the actions of the transaction. On success, we return the commit timestamp (or
+    /// `None` if there was nothing to commit).
+    ///
+    /// Prewrites are retried if TiKV can't be reached, but the commit of the primary key is not:
+    /// if its response is lost, the client checks whether the primary key was committed, and
+    /// commits it again while it is still locked. Only if that fails too, e.g. because TiKV stays
+    /// unreachable, the commit fails with [`UndeterminedError`](crate::Error::UndeterminedError),
+    /// and the transaction may or may not be committed.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # use tikv_client::{Config, Timestamp, TransactionClient};
+    /// # use futures::prelude::*;
+    /// # futures::executor::block_on(async {
+    /// # let client = TransactionClient::new(vec!["192.168.0.100"], None).await.unwrap();
+    /// let mut txn = client.begin_optimistic().await.unwrap();
+    /// // ... Do some actions.
+    /// let result: Timestamp = txn.commit().await.unwrap().unwrap();
+    /// # });
+    /// ```
+    pub async fn commit(&mut self) -> Result<Option<Timestamp>> {
+        self.record_operation("commit");
+        let res = in_span!(
+            self.try_commit(),
+            parent: &self.span,
+            "commit",
+            start_ts = self.timestamp.version()
+        )
+        .await;
+        match (&self.operations, res) {
+            (Some(operations), Err(mut e)) => {
+                operations.attribute_assertions(&mut e);
+                Err(Error::CommitFailed {
+                    source: Box::new(e),
+                    operations: operations.entries(),
+                })
+            }
+            (_, res) => res,
+        }
+    }
+
+    async fn try_commit(&mut self) -> Result<Option<Timestamp>> {
+        debug!(self.logger, "commiting transaction");
+        self.expire_if_overdue().await;
+        {
+            let mut status = self.status.write().await;
+            if *status == TransactionStatus::Expired {
+                return Err(self.expired_error());
+            }
+            if !matches!(
+                *status,
+                TransactionStatus::StartedCommit | TransactionStatus::Active
+            ) {
+                return Err(Error::OperationAfterCommitError);
+            }
+            *status = TransactionStatus::StartedCommit;
+        }
+        self.abort_expiry_task();
+
+        self.wait_for_pending_locks().await?;
+
+        let primary_key = self.buffer.get_primary_key();
+        let mutations = self.buffer.to_proto_mutations();
+        if mutations.is_empty() {
+            assert!(primary_key.is_none());
+            return Ok(None);
+        }
 
         self.start_auto_heartbeat().await;
 
-        for key in keys {
-            self.buffer.lock(key.key());
-        }
+        let res = Committer::new(
+            primary_key,
+            mutations,
+            self.timestamp.clone(),
+            self.rpc.clone(),
+            self.options.clone(),
+            self.buffer.get_write_size() as u64,
+            self.start_instant,
+            self.logger.new(o!("child" => 1)),
+        )
+        .commit()
+        .await;
+
+        if let Ok(commit_ts) = &res {
+            let mut status = self.status.write().await;
+            *status = TransactionStatus::Committed;
+            self.commit_ts = commit_ts.clone();
+        }
+        res
+    }
+
+    /// Rollback the transaction.
+    ///
+    /// If it succeeds, all mutations made by this transaction will be discarded.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// # use tikv_client::{Config, Timestamp, TransactionClient};
+    /// # use futures::prelude::*;
+    /// # futures::executor::block_on(async {
+    /// # let client = TransactionClient::new(vec!["192.168.0.100"], None).await.unwrap();
+    /// let mut txn = client.begin_optimistic().await.unwrap();
+    /// // ... Do some actions.
+    /// txn.rollback().await.unwrap();
+    /// # });
+    /// ```
+    pub async fn rollback(&mut self) -> Result<()> {
+        debug!(self.logger, "rolling back transaction");
+        {
+            let status = self.status.read().await;
+            // the locks of an expired transaction have been rolled back already, unless that
+            // failed
+            if *status == TransactionStatus::Expired {
+                drop(status);
+                let locks = match &self.expiring_locks {
+                    Some(locks) => locks,
+                    None => return Ok(()),
+                };
+                return rollback_expiring_locks(
+                    self.rpc.clone(),
+                    locks,
+                    self.timestamp.clone(),
+                    &self.options,
+                )
+                .await;
+            }
+            if !matches!(
+                *status,
+                TransactionStatus::StartedRollback
+                    | TransactionStatus::Active
+                    | TransactionStatus::StartedCommit
+            ) {
+                return Err(Error::OperationAfterCommitError);
+            }
+        }
+
+        {
+            let mut status = self.status.write().await;
+            *status = TransactionStatus::StartedRollback;
+        }
+        self.abort_expiry_task();
+
+        // the keys of failed locks are rolled back too, which does nothing if they are not locked
+        if let Err(e) = self.wait_for_pending_locks().await {
+            debug!(self.logger, "pipelined pessimistic lock failed"; "error" => %e);
+        }
+
+        let primary_key = self.buffer.get_primary_key();
+        let mutations = self.buffer.to_proto_mutations();
+        let rollback = Committer::new(
+            primary_key,
+            mutations,
+            self.timestamp.clone(),
+            self.rpc.clone(),
+            self.options.clone(),
+            self.buffer.get_write_size() as u64,
+            self.start_instant,
+            self.logger.new(o!("child" => 1)),
+        )
+        .rollback();
+        let res = in_span!(
+            rollback,
+            parent: &self.span,
+            "rollback",
+            start_ts = self.timestamp.version()
+        )
+        .await;
+
+        if res.is_ok() {
+            let mut status = self.status.write().await;
+            *status = TransactionStatus::Rolledback;
+        }
+        res
+    }
+
+    /// Get the start timestamp of this transaction.
+    pub fn start_timestamp(&self) -> Timestamp {
+        self.timestamp.clone()
+    }
+
+    /// The commit timestamp of this transaction, the version its writes are readable at, once it
+    /// is committed.
+    ///
+    /// `None` before a successful [`commit`](Transaction::commit), and if the transaction had
+    /// nothing to commit.
+    pub fn commit_ts(&self) -> Option<Timestamp> {
+        self.commit_ts.clone()
+    }
+
+    /// The `tracing` span of this transaction, with its start timestamp as a field. Only available
+    /// with the `tracing` feature.
+    ///
+    /// It is the parent of the spans of committing, rolling back and locking keys, which in turn
+    /// are the parents of the spans of prewriting, resolving locks, looking up regions and the
+    /// requests to each region.
+    #[cfg(feature = "tracing")]
+    pub fn span(&self) -> &tracing::Span {
+        &self.span
+    }
+
+    /// Send a heart beat message to keep the transaction alive on the server and update its TTL.
+    ///
+    /// Returns the TTL set on the transaction's locks by TiKV.
+    #[doc(hidden)]
+    pub async fn send_heart_beat(&mut self) -> Result<u64> {
+        debug!(self.logger, "sending heart_beat");
+        self.check_allow_operation().await?;
+        let primary_key = match self.buffer.get_primary_key() {
+            Some(k) => k,
+            None => return Err(Error::NoPrimaryKey),
+        };
+        let request = new_heart_beat_request(
+            self.timestamp.clone(),
+            primary_key,
+            heartbeat_lock_ttl(
+                self.rpc.clock().elapsed_since(self.start_instant),
+                self.options.heartbeat_period(),
+            ),
+        );
+        let plan = PlanBuilder::new(self.rpc.clone(), request)
+            .labels(self.options.metrics_labels.clone())
+            .context_hook(self.options.context_hook.clone())
+            .priority(self.options.priority.into())
+            .resource_group_tag(self.options.resource_group_tag.clone())
+            .deadline(self.options.deadline)
+            .on_retries_exhausted(self.options.retry_options.on_write_exhausted.clone())
+            .resolve_lock(self.options.retry_options.lock_backoff.clone())
+            .retry_multi_region(self.options.retry_options.region_backoff.clone())
+            .merge(CollectSingle)
+            .post_process_default()
+            .plan();
+        plan.execute().await
+    }
+
+    async fn scan_inner(
+        &mut self,
+        range: impl Into<BoundRange>,
+        limit: u32,
+        reverse: bool,
+        key_only: bool,
+    ) -> Result<impl Iterator<Item = KvPair>> {
+        self.check_allow_operation().await?;
+        let fetch = self.range_scan(reverse, key_only);
+        let range = self.encode_range(range.into());
+        let pairs = self
+            .buffer
+            .scan_and_fetch(range, limit, reverse, key_only, fetch)
+            .await?;
+        Ok(self.decode_pairs(pairs)?.into_iter())
+    }
+
+    /// Returns a function scanning at most `limit` pairs of a range at the start timestamp of the
+    /// transaction, across regions, through the scan cache of the transaction if it has one. See
+    /// [`region_scan`](Transaction::region_scan) for `reverse` and `key_only`.
+    fn range_scan(
+        &self,
+        reverse: bool,
+        key_only: bool,
+    ) -> impl Fn(BoundRange, u32) -> BoxFuture<'static, Result<Vec<KvPair>>> + Send + Sync + 'static
+    {
+        let rpc = self.rpc.clone();
+        let scan = Arc::new(self.region_scan(reverse, key_only));
+        let version = self.timestamp.version();
+        // skipped keys must not be cached as non-existent
+        let scan_cache = match self.options.read_options.lock_policy {
+            LockPolicy::SkipLocked => None,
+            _ => self.options.scan_cache.clone(),
+        };
+        move |range, limit| {
+            let rpc = rpc.clone();
+            let scan = scan.clone();
+            let scan_cache = scan_cache.clone();
+            async move {
+                let cached = scan_cache
+                    .as_ref()
+                    .and_then(|cache| cache.get(range.clone(), limit, reverse, key_only, version));
+                if let Some(pairs) = cached {
+                    return Ok(pairs);
+                }
+                let pairs = if reverse {
+                    scan_with_limit_reverse(rpc, range.clone(), limit, &*scan).await?
+                } else {
+                    scan_with_limit(rpc, range.clone(), limit, &*scan).await?
+                };
+                if let Some(cache) = scan_cache {
+                    cache.insert(range, limit, reverse, key_only, version, pairs.clone());
+                }
+                Ok(pairs)
+            }
+            .boxed()
+        }
+    }
+
+    /// Returns a function scanning a range within one region at the start timestamp of the
+    /// transaction, at most `limit` pairs in ascending order of their keys, or in descending order
+    /// if `reverse` is set.
+    fn region_scan(
+        &self,
+        reverse: bool,
+        key_only: bool,
+    ) -> impl Fn(BoundRange, u32) -> BoxFuture<'static, Result<Vec<KvPair>>> + Send + Sync + 'static
+    {
+        let timestamp = self.timestamp.clone();
+        let rpc = self.rpc.clone();
+        let read_rpc = self.read_rpc();
+        let retry_options = self.options.retry_options.clone();
+        let lock_policy = self.options.read_options.lock_policy;
+        let labels = self.options.metrics_labels.clone();
+        let context_hook = self.options.context_hook.clone();
+        let priority = self.options.priority;
+        let resource_group_tag = self.options.resource_group_tag.clone();
+        let deadline = self.options.deadline;
+        move |range, limit| {
+            let request = new_scan_request(range, timestamp.clone(), limit, reverse, key_only);
+            let plan = PlanBuilder::new(rpc.clone(), request)
+                .labels(labels.clone())
+                .context_hook(context_hook.clone())
+                .priority(priority.into())
+                .resource_group_tag(resource_group_tag.clone())
+                .deadline(deadline)
+                .on_retries_exhausted(retry_options.on_read_exhausted.clone())
+                .resolve_lock_for_read(
+                    timestamp.clone(),
+                    lock_policy,
+                    retry_options.lock_backoff.clone(),
+                )
+                .read_through(read_rpc.clone())
+                .retry_multi_region(retry_options.region_backoff.clone())
+                .merge(Collect)
+                .plan();
+            async move {
+                plan.execute()
+                    .await
+                    .map(|r| r.into_iter().map(Into::into).collect::<Vec<KvPair>>())
+            }
+            .boxed()
+        }
+    }
+
+    /// Pessimistically lock the keys, and optionally retrieve corresponding values.
+    /// If a key does not exist, the corresponding pair will not appear in the result.
+    ///
+    /// Once resolved it acquires locks on the keys in TiKV.
+    /// A lock prevents other transactions from mutating the entry until it is released.
+    ///
+    /// # Panics
+    ///
+    /// Only valid for pessimistic transactions, panics if called on an optimistic transaction.
+    async fn pessimistic_lock(
+        &mut self,
+        keys: impl IntoIterator<Item = impl PessimisticLock + Send + 'static>,
+        need_value: bool,
+    ) -> Result<Vec<KvPair>> {
+        let options = PessimisticLockOptions::new().return_values(need_value);
+        self.pessimistic_lock_with_options(keys, options).await
+    }
+
+    /// Like [`pessimistic_lock`](Transaction::pessimistic_lock), returning the pairs requested by
+    /// `lock_options`.
+    async fn pessimistic_lock_with_options(
+        &mut self,
+        keys: impl IntoIterator<Item = impl PessimisticLock + Send + 'static>,
+        lock_options: PessimisticLockOptions,
+    ) -> Result<Vec<KvPair>> {
+        debug!(self.logger, "acquiring pessimistic lock");
+        assert!(
+            matches!(self.options.kind, TransactionKind::Pessimistic(_)),
+            "`pessimistic_lock` is only valid to use with pessimistic transactions"
+        );
+
+        let keys: Vec<_> = keys.into_iter().collect();
+        if keys.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let first_key = keys[0].clone().key();
+        // we do not set the primary key here, because pessimistic lock request
+        // can fail, in which case the keys may not be part of the transaction.
+        let primary_lock = self
+            .buffer
+            .get_primary_key()
+            .unwrap_or_else(|| first_key.clone());
+        let for_update_ts = self.rpc.clone().get_timestamp().await?;
+        self.options.push_for_update_ts(for_update_ts.clone());
+        let lock = in_span!(
+            acquire_pessimistic_lock(
+                self.rpc.clone(),
+                keys.clone(),
+                primary_lock,
+                self.timestamp.clone(),
+                for_update_ts,
+                self.options.clone(),
+                lock_options.clone(),
+                self.logger.clone(),
+            ),
+            parent: &self.span,
+            "pessimistic_lock",
+            start_ts = self.timestamp.version(),
+            for_update_ts = for_update_ts.version()
+        );
+        let pairs = if self.options.pipelined_pessimistic_lock && !lock_options.needs_result() {
+            // the result is awaited before the transaction commits or rolls back
+            self.pending_locks
+                .push(tokio::spawn(in_current_trace(lock)));
+            Ok(vec![])
+        } else {
+            let (for_update_ts, pairs) = lock.await;
+            self.options.push_for_update_ts(for_update_ts);
+            pairs
+        };
+
+        // primary key will be set here if needed
+        self.buffer.primary_key_or(&first_key);
+
+        self.start_auto_heartbeat().await;
+
+        if self.options.max_lifetime.is_some() {
+            self.track_expiring_locks(keys.iter().map(|key| key.clone().key()));
+        }
+        for key in keys {
+            let key = key.key();
+            self.locked_keys.insert(key.clone());
+            self.buffer.lock(key);
+        }
+
+        pairs
+    }
+
+    /// Waits for the pipelined pessimistic locks in flight, see
+    /// [`pipelined_pessimistic_lock`](TransactionOptions::pipelined_pessimistic_lock). Returns the
+    /// first error of them, after all of them completed.
+    async fn wait_for_pending_locks(&mut self) -> Result<()> {
+        let mut res = Ok(());
+        for handle in std::mem::take(&mut self.pending_locks) {
+            let locked = match handle.await {
+                Ok((for_update_ts, pairs)) => {
+                    self.options.push_for_update_ts(for_update_ts);
+                    pairs.map(|_| ())
+                }
+                Err(e) => Err(e.into()),
+            };
+            if res.is_ok() {
+                res = locked;
+            }
+        }
+        res
+    }
+
+    /// Checks a write of the encoded `key` against the key validators and the size limits of the
+    /// transaction.
+    fn check_write(&self, key: &Key, value: Option<&[u8]>) -> Result<()> {
+        let validators = self.rpc.key_validators();
+        if !validators.is_empty() {
+            // the validators check the keys as given to the transaction
+            let key = match &self.namespace {
+                Some(namespace) => namespace.decode_key(key.clone())?,
+                None => key.clone(),
+            };
+            key_validator::validate_key(validators, &key)?;
+        }
+        self.options
+            .size_limits
+            .unwrap_or_else(|| self.rpc.size_limits())
+            .check(key, value)
+    }
+
+    /// Records `operation` in the operation log, if the transaction records its operations.
+    fn record_operation(&mut self, operation: &'static str) {
+        if let Some(operations) = &mut self.operations {
+            operations.record(operation);
+        }
+    }
+
+    /// Records that the write operation `operation` wrote `keys`, if the transaction records its
+    /// operations, so that the operations which wrote the keys of failed assertions are known.
+    fn record_write<'a>(
+        &mut self,
+        operation: &'static str,
+        keys: impl IntoIterator<Item = &'a Key>,
+    ) {
+        if let Some(operations) = &mut self.operations {
+            operations.record_write(operation, keys);
+        }
+    }
+
+    /// Checks if the transaction can perform arbitrary operations.
+    async fn check_allow_operation(&self) -> Result<()> {
+        self.expire_if_overdue().await;
+        let status = self.status.read().await;
+        match *status {
+            TransactionStatus::ReadOnly | TransactionStatus::Active => Ok(()),
+            TransactionStatus::Committed
+            | TransactionStatus::Rolledback
+            | TransactionStatus::StartedCommit
+            | TransactionStatus::StartedRollback
+            | TransactionStatus::Dropped => Err(Error::OperationAfterCommitError),
+            TransactionStatus::Expired => Err(self.expired_error()),
+        }
+    }
+
+    /// Expires the transaction if it exceeded its maximum lifetime, and the task rolling back its
+    /// locks has not done so yet.
+    async fn expire_if_overdue(&self) {
+        let max_lifetime = match self.options.max_lifetime {
+            Some(max_lifetime) if self.is_pessimistic() => max_lifetime,
+            _ => return,
+        };
+        if self.rpc.clock().elapsed_since(self.start_instant) < max_lifetime {
+            return;
+        }
+        let locks = self.expiring_locks.clone().unwrap_or_default();
+        let res = expire(
+            self.rpc.clone(),
+            &self.status,
+            &locks,
+            self.timestamp.clone(),
+            &self.options,
+        )
+        .await;
+        if let Err(e) = res {
+            warn!(
+                self.logger,
+                "failed to roll back an expired transaction: {}", e
+            );
+        }
+    }
+
+    fn expired_error(&self) -> Error {
+        Error::TransactionExpired {
+            max_lifetime: self.options.max_lifetime.unwrap_or_default(),
+        }
+    }
+
+    /// Records the pessimistic locks of `keys` to be rolled back when the transaction expires,
+    /// starting the task which rolls them back on the first lock.
+    fn track_expiring_locks(&mut self, keys: impl Iterator<Item = Key>) {
+        let for_update_ts = match &self.options.kind {
+            TransactionKind::Pessimistic(for_update_ts) => for_update_ts.clone(),
+            TransactionKind::Optimistic => unreachable!(),
+        };
+        let locks = match &self.expiring_locks {
+            Some(locks) => locks.clone(),
+            None => {
+                let locks = Arc::new(Mutex::new(ExpiringLocks::default()));
+                self.expiring_locks = Some(locks.clone());
+                self.start_expiry_task(locks.clone());
+                locks
+            }
+        };
+        let mut locks = locks.lock().unwrap();
+        locks.keys.extend(keys);
+        locks.for_update_ts = for_update_ts;
+    }
+
+    fn start_expiry_task(&mut self, locks: Arc<Mutex<ExpiringLocks>>) {
+        let max_lifetime = match self.options.max_lifetime {
+            Some(max_lifetime) => max_lifetime,
+            None => return,
+        };
+        let rpc = self.rpc.clone();
+        let status = self.status.clone();
+        let start_ts = self.timestamp.clone();
+        let options = self.options.clone();
+        let start_instant = self.start_instant;
+        let logger = self.logger.clone();
+        self.expiry_task = Some(tokio::spawn(async move {
+            let clock = rpc.clock();
+            clock
+                .sleep(max_lifetime.saturating_sub(clock.elapsed_since(start_instant)))
+                .await;
+            if let Err(e) = expire(rpc, &status, &locks, start_ts, &options).await {
+                warn!(logger, "failed to roll back an expired transaction: {}", e);
+            }
+        }));
+    }
+
+    fn abort_expiry_task(&mut self) {
+        if let Some(task) = self.expiry_task.take() {
+            task.abort();
+        }
+    }
+
+    /// Set the options for subsequent reads of this transaction.
+    pub fn set_read_options(&mut self, options: ReadOptions) {
+        self.options.read_options = options;
+    }
+
+    /// The client for reads, which sends replica reads if they are enabled.
+    fn read_rpc(&self) -> Arc<PdC> {
+        self.replica_rpc.as_ref().unwrap_or(&self.rpc).clone()
+    }
+
+    fn encode_key(&self, key: Key) -> Key {
+        match &self.namespace {
+            Some(namespace) => namespace.encode_key(key),
+            None => key,
+        }
+    }
+
+    fn encode_range(&self, range: BoundRange) -> BoundRange {
+        match &self.namespace {
+            Some(namespace) => namespace.encode_range(range),
+            None => range,
+        }
+    }
+
+    fn decode_pairs(&self, pairs: impl IntoIterator<Item = KvPair>) -> Result<Vec<KvPair>> {
+        let pairs = pairs.into_iter().collect();
+        match &self.namespace {
+            Some(namespace) => namespace.decode_pairs(pairs),
+            None => Ok(pairs),
+        }
+    }
+
+    fn invalidate_read_cache(&self, key: &Key) {
+        if let Some(cache) = &self.options.read_cache {
+            cache.invalidate(key, self.timestamp.version());
+        }
+        if let Some(cache) = &self.options.scan_cache {
+            cache.invalidate(key, self.timestamp.version());
+        }
+    }
+
+    fn is_pessimistic(&self) -> bool {
+        matches!(self.options.kind, TransactionKind::Pessimistic(_))
+    }
+
+    async fn start_auto_heartbeat(&mut self) {
+        debug!(self.logger, "starting auto_heartbeat");
+        if !self.options.heartbeat_option.is_auto_heartbeat() || self.is_heartbeat_started {
+            return;
+        }
+        self.is_heartbeat_started = true;
+
+        let status = self.status.clone();
+        let primary_key = self
+            .buffer
+            .get_primary_key()
+            .expect("Primary key should exist");
+        let start_ts = self.timestamp.clone();
+        let region_backoff = self.options.retry_options.region_backoff.clone();
+        let rpc = self.rpc.clone();
+        let heartbeat_interval = self.options.heartbeat_period();
+        let start_instant = self.start_instant;
+
+        if let Some(scheduler) = &self.heartbeat_scheduler {
+            scheduler.register(
+                start_ts,
+                primary_key,
+                start_instant,
+                heartbeat_interval,
+                region_backoff,
+                status,
+            );
+            return;
+        }
+
+        let heartbeat_task = async move {
+            loop {
+                rpc.clock().sleep(heartbeat_interval).await;
+                if status.read().await.is_finished() {
+                    break;
+                }
+                let request = new_heart_beat_request(
+                    start_ts.clone(),
+                    primary_key.clone(),
+                    heartbeat_lock_ttl(
+                        rpc.clock().elapsed_since(start_instant),
+                        heartbeat_interval,
+                    ),
+                );
+                let plan = PlanBuilder::new(rpc.clone(), request)
+                    .retry_multi_region(region_backoff.clone())
+                    .merge(CollectSingle)
+                    .plan();
+                plan.execute().await?;
+            }
+            Ok::<(), Error>(())
+        };
+
+        tokio::spawn(async {
+            if let Err(err) = heartbeat_task.await {
+                log::error!("Error: While sending heartbeat. {}", err);
+            }
+        });
+    }
+}
+
+impl Transaction {
+    /// Send the reads through a client for replica reads, if the options of the transaction ask
+    /// for it.
+    pub(crate) fn with_replica_read(mut self) -> Transaction {
+        self.set_replica_read(self.options.replica_read);
+        self
+    }
+
+    /// Set which replicas serve subsequent reads of this transaction, see
+    /// [`TransactionOptions::replica_read`].
+    pub fn set_replica_read(&mut self, replica_read: ReplicaRead) {
+        self.options.replica_read = replica_read;
+        self.replica_rpc = match replica_read {
+            ReplicaRead::Leader => None,
+            kind => Some(Arc::new(self.rpc.with_replica_read_kind(kind))),
+        };
+    }
+}
+
+impl<PdC: PdClient> Drop for Transaction<PdC> {
+    fn drop(&mut self) {
+        debug!(self.logger, "dropping transaction");
+        if std::thread::panicking() {
+            self.abort_expiry_task();
+            return;
+        }
+        let mut status = futures::executor::block_on(self.status.write());
+        // the task of an expired transaction may still be rolling back its locks, otherwise it
+        // would find the transaction dropped and do nothing
+        if *status != TransactionStatus::Expired {
+            if let Some(task) = self.expiry_task.take() {
+                task.abort();
+            }
+        }
+        if *status == TransactionStatus::Active {
+            match self.options.check_level {
+                CheckLevel::Panic => {
+                    panic!("Dropping an active transaction. Consider commit or rollback it.")
+                }
+                CheckLevel::Warn => warn!(
+                    self.logger,
+                    "Dropping an active transaction. Consider commit or rollback it."
+                ),
+
+                CheckLevel::None => {}
+            }
+        }
+        *status = TransactionStatus::Dropped;
+    }
+}
+
+/// The ids of the savepoints, unique among all transactions.
+static NEXT_SAVEPOINT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// The state of a transaction at some point, see [`Transaction::savepoint`].
+#[derive(Clone, Debug)]
+pub struct Savepoint {
+    id: u64,
+    buffer: BufferSnapshot,
+    locked_keys: BTreeSet<Key>,
+}
+
+/// The pessimistic locks acquired by a transaction with a maximum lifetime.
+#[derive(Default)]
+struct ExpiringLocks {
+    keys: Vec<Key>,
+    /// The latest `for_update_ts` of the locks.
+    for_update_ts: Timestamp,
+}
+
+/// Marks an active transaction as expired, which stops its heartbeats, and rolls back its
+/// pessimistic locks. Does nothing if the transaction is no longer active, e.g. if it started to
+/// commit.
+async fn expire<PdC: PdClient>(
+    rpc: Arc<PdC>,
+    status: &RwLock<TransactionStatus>,
+    locks: &Mutex<ExpiringLocks>,
+    start_ts: Timestamp,
+    options: &TransactionOptions,
+) -> Result<()> {
+    {
+        let mut status = status.write().await;
+        if *status != TransactionStatus::Active {
+            return Ok(());
+        }
+        *status = TransactionStatus::Expired;
+    }
+    rollback_expiring_locks(rpc, locks, start_ts, options).await
+}
+
+/// Rolls back the pessimistic locks of an expired transaction. The keys are kept if that fails,
+/// so that a [`rollback`](Transaction::rollback) of the transaction retries.
+async fn rollback_expiring_locks<PdC: PdClient>(
+    rpc: Arc<PdC>,
+    locks: &Mutex<ExpiringLocks>,
+    start_ts: Timestamp,
+    options: &TransactionOptions,
+) -> Result<()> {
+    let (keys, for_update_ts) = {
+        let mut locks = locks.lock().unwrap();
+        (std::mem::take(&mut locks.keys), locks.for_update_ts.clone())
+    };
+    if keys.is_empty() {
+        return Ok(());
+    }
+    let request = new_pessimistic_rollback_request(keys.iter().cloned(), start_ts, for_update_ts);
+    let plan = PlanBuilder::new(rpc, request)
+        .labels(options.metrics_labels.clone())
+        .context_hook(options.context_hook.clone())
+        .priority(options.priority.into())
+        .resource_group_tag(options.resource_group_tag.clone())
+        .deadline(options.deadline)
+        .on_retries_exhausted(options.retry_options.on_write_exhausted.clone())
+        .resolve_lock(options.retry_options.lock_backoff.clone())
+        .retry_multi_region(options.retry_options.region_backoff.clone())
+        .extract_error()
+        .plan();
+    if let Err(e) = plan.execute().await {
+        locks.lock().unwrap().keys.extend(keys);
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// Overlays `writes`, the values written by a transaction ordered by key or `None` for deleted
+/// keys, on the pairs scanned from TiKV. The stream ends after the first error.
+fn overlay_writes(
+    scanned: BoxStream<'static, Result<KvPair>>,
+    writes: Vec<(Key, Option<Value>)>,
+) -> BoxStream<'static, Result<KvPair>> {
+    stream::unfold(
+        (
+            Box::pin(scanned.peekable()),
+            writes.into_iter().peekable(),
+            false,
+        ),
+        |(mut scanned, mut writes, done)| async move {
+            if done {
+                return None;
+            }
+            loop {
+                // errors are taken first
+                let order = match (scanned.as_mut().peek().await, writes.peek()) {
+                    (None, None) => return None,
+                    (Some(Err(_)), _) | (Some(_), None) => Ordering::Less,
+                    (None, Some(_)) => Ordering::Greater,
+                    (Some(Ok(pair)), Some((key, _))) => pair.key().cmp(key),
+                };
+                if order != Ordering::Greater {
+                    let pair = scanned.next().await.unwrap();
+                    if order == Ordering::Less {
+                        let done = pair.is_err();
+                        return Some((pair, (scanned, writes, done)));
+                    }
+                }
+                // the write replaces the scanned pair of the same key
+                if let (key, Some(value)) = writes.next().unwrap() {
+                    return Some((Ok(KvPair::new(key, value)), (scanned, writes, false)));
+                }
+            }
+        },
+    )
+    .boxed()
+}
+
+/// Locks `keys` for the transaction started at `start_ts`, first with `for_update_ts`. After a
+/// write conflict, the lock is retried with a new `for_update_ts`, up to
+/// [`pessimistic_lock_retries`](TransactionOptions::pessimistic_lock_retries) times. Returns the
+/// latest `for_update_ts` along with the result, which is the locked pairs requested by
+/// `lock_options`.
+#[allow(clippy::too_many_arguments)]
+async fn acquire_pessimistic_lock<PdC: PdClient>(
+    rpc: Arc<PdC>,
+    keys: Vec<impl PessimisticLock>,
+    primary_lock: Key,
+    start_ts: Timestamp,
+    mut for_update_ts: Timestamp,
+    options: TransactionOptions,
+    lock_options: PessimisticLockOptions,
+    logger: Logger,
+) -> (Timestamp, Result<Vec<KvPair>>) {
+    // TiKV already waited for the live locks as long as the timeout allows, only the expired
+    // locks are resolved before locking again
+    let mut lock_backoff = options.retry_options.lock_backoff.clone();
+    if lock_options.wait_timeout.is_some() {
+        lock_backoff = lock_backoff.with_max_attempts(0);
+    }
+    let mut attempts = 0;
+    loop {
+        let request = new_pessimistic_lock_request(
+            keys.clone().into_iter(),
+            primary_lock.clone(),
+            start_ts.clone(),
+            DEFAULT_LOCK_TTL,
+            for_update_ts.clone(),
+            &lock_options,
+        );
+        let plan = PlanBuilder::new(rpc.clone(), request)
+            .labels(options.metrics_labels.clone())
+            .context_hook(options.context_hook.clone())
+            .priority(options.priority.into())
+            .resource_group_tag(options.resource_group_tag.clone())
+            .deadline(options.deadline)
+            .on_retries_exhausted(options.retry_options.on_write_exhausted.clone())
+            .resolve_lock(lock_backoff.clone())
+            .preserve_shard()
+            .retry_multi_region(options.retry_options.region_backoff.clone())
+            .merge(CollectWithShard)
+            .plan();
+        match plan.execute().await {
+            // a newer transaction wrote a key after `for_update_ts`, locking again with a newer
+            // `for_update_ts` reads and locks the latest version
+            Err(e) if attempts < options.pessimistic_lock_retries && is_lock_conflict(&e) => {
+                attempts += 1;
+                debug!(logger, "retrying pessimistic lock"; "error" => %e);
+                for_update_ts = match rpc.clone().get_timestamp().await {
+                    Ok(ts) => ts,
+                    Err(e) => return (for_update_ts, Err(e)),
+                };
+            }
+            pairs => return (for_update_ts, pairs),
+        }
+    }
+}
+
+/// The error of a lock which failed because one of the keys exists, flattened to the
+/// [`KeyAlreadyExists`](Error::KeyAlreadyExists) error of that key. Other errors are returned as
+/// is.
+fn already_exists_error(e: Error) -> Error {
+    let is_already_exists = |e: &Error| matches!(e, Error::KeyAlreadyExists { .. });
+    match e {
+        Error::MultipleKeyErrors(errors) | Error::ExtractedErrors(errors)
+            if errors.iter().any(is_already_exists) =>
+        {
+            errors.into_iter().find(is_already_exists).unwrap()
+        }
+        e => e,
+    }
+}
+
+/// Whether a pessimistic lock failed because a key was written after its `for_update_ts`, or the
+/// lock was lost, so that locking again with a new `for_update_ts` may succeed.
+pub(super) fn is_lock_conflict(e: &Error) -> bool {
+    match e {
+        Error::WriteConflict { .. } => true,
+        Error::KeyError(e) => {
+            e.get_retryable().contains("PessimisticLockNotFound")
+                || e.get_abort().contains("PessimisticLockNotFound")
+        }
+        Error::MultipleKeyErrors(errors) | Error::ExtractedErrors(errors) => {
+            !errors.is_empty() && errors.iter().all(is_lock_conflict)
+        }
+        Error::RequestFailed { source, .. } => is_lock_conflict(source),
+        _ => false,
+    }
+}
+
+/// Whether a transaction failed because of a conflict with another transaction, so that running
+/// it again with a new start timestamp may succeed.
+pub(super) fn is_txn_conflict(e: &Error) -> bool {
+    match e {
+        Error::Deadlock { .. } | Error::ResolveLockError => true,
+        Error::KeyError(e) if e.has_locked() || e.has_conflict() => true,
+        Error::CommitFailed { source, .. }
+        | Error::RetriesExhausted { source, .. }
+        | Error::RequestFailed { source, .. } => is_txn_conflict(source),
+        Error::MultipleKeyErrors(errors) | Error::ExtractedErrors(errors) => {
+            !errors.is_empty() && errors.iter().all(is_txn_conflict)
+        }
+        e => is_lock_conflict(e),
+    }
+}
+
+/// The default max TTL of a lock in milliseconds. Also called `ManagedLockTTL` in TiDB.
+const MAX_TTL: u64 = 20000;
+/// The default number of times a pessimistic lock is retried after a write conflict.
+const DEFAULT_PESSIMISTIC_LOCK_RETRIES: u32 = 3;
+/// The default TTL of a lock in milliseconds.
+pub(super) const DEFAULT_LOCK_TTL: u64 = 3000;
+/// The number of pairs of each batch fetched by [`Transaction::scan_stream`].
+pub const SCAN_STREAM_BATCH_SIZE: u32 = 256;
+/// The default maximum number of keys prewritten or committed by each request, see
+/// [`TransactionOptions::commit_chunk_size`].
+pub const COMMIT_CHUNK_SIZE: usize = 4096;
+/// The default heartbeat interval
+const DEFAULT_HEARTBEAT_INTERVAL: Duration = Duration::from_millis(MAX_TTL / 2);
+
+/// The TTL a heartbeat sets for the primary lock of a transaction started `age` ago which sends
+/// heartbeats every `interval`. The lock outlives the next heartbeat, even if one is lost, so a
+/// long-running transaction keeps its locks. With the default interval it is `MAX_TTL`.
+pub(super) fn heartbeat_lock_ttl(age: Duration, interval: Duration) -> u64 {
+    age.as_millis() as u64 + (2 * interval.as_millis() as u64).max(DEFAULT_LOCK_TTL)
+}
+/// TiKV recommends each RPC packet should be less than around 1MB. We keep KV size of
+/// each request below 16KB.
+const TXN_COMMIT_BATCH_SIZE: u64 = 16 * 1024;
+const TTL_FACTOR: f64 = 6000.0;
+/// How long after prewriting an async commit or 1PC transaction may still be committed, bounds
+/// the `max_commit_ts` TiKV may choose.
+const ASYNC_COMMIT_SAFE_WINDOW: Duration = Duration::from_secs(2);
+
+/// Optimistic or pessimistic transaction.
+#[derive(Clone, PartialEq, Debug)]
+pub enum TransactionKind {
+    Optimistic,
+    /// Argument is the transaction's for_update_ts
+    Pessimistic(Timestamp),
+}
+
+/// Options for configuring a transaction.
+///
+/// `TransactionOptions` has a builder-style API.
+#[derive(Clone, PartialEq, Debug)]
+pub struct TransactionOptions {
+    /// Optimistic or pessimistic (default) transaction.
+    kind: TransactionKind,
+    /// Try using 1pc rather than 2pc (default is to always use 2pc).
+    try_one_pc: bool,
+    /// Try to use async commit (default is not to).
+    async_commit: bool,
+    /// Is the transaction read only? (Default is no).
+    read_only: bool,
+    /// How to retry in the event of certain errors.
+    retry_options: RetryOptions,
+    /// What to do if the transaction is dropped without an attempt to commit or rollback
+    check_level: CheckLevel,
+    #[doc(hidden)]
+    heartbeat_option: HeartbeatOption,
+    /// A cache for point gets shared with other transactions (default is no cache).
+    read_cache: Option<ReadCache>,
+    /// A cache for scans shared with other transactions (default is no cache).
+    scan_cache: Option<ScanCache>,
+    /// Options for reads, which can be changed for each read.
+    read_options: ReadOptions,
+    /// User-defined labels attached to the metrics and logs of the transaction.
+    metrics_labels: MetricsLabels,
+    /// How many times a pessimistic lock is retried with a new `for_update_ts` after a write
+    /// conflict.
+    pessimistic_lock_retries: u32,
+    /// How several writes to the same key are coalesced into the mutation which is prewritten.
+    coalescing_rules: CoalescingRules,
+    /// The priority of the requests of the transaction in TiKV (default is normal).
+    priority: CommandPriority,
+    /// Tags the requests of the transaction, e.g. for TiKV to attribute resource usage to
+    /// (default is no tag).
+    resource_group_tag: Vec<u8>,
+    /// If set, amends the context of each request of the transaction.
+    context_hook: Option<ContextHook>,
+    /// If set, a pessimistic transaction is rolled back once it has been running for longer.
+    max_lifetime: Option<Duration>,
+    /// If set, each request of the transaction fails once it has been running for longer.
+    deadline: Option<Duration>,
+    /// Whether the operations of the transaction are recorded (default is not to).
+    record_operations: bool,
+    /// Whether pessimistic locks which read no values are awaited at commit (default is not to).
+    pipelined_pessimistic_lock: bool,
+    /// Which replicas serve the reads (default is the leaders).
+    replica_read: ReplicaRead,
+    /// The maximum sizes of the keys and values written (default is those of the client).
+    size_limits: Option<SizeLimits>,
+    /// Whether the commit timestamp is verified and after any timestamp issued before the commit
+    /// (default is neither).
+    external_consistency: bool,
+    /// The maximum number of keys prewritten, committed or rolled back by each request.
+    commit_chunk_size: usize,
+    /// The maximum number of requests prewriting, committing or rolling back keys in flight.
+    commit_concurrency: usize,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum HeartbeatOption {
+    NoHeartbeat,
+    FixedTime(Duration),
+}
+
+impl Default for TransactionOptions {
+    fn default() -> TransactionOptions {
+        Self::new_pessimistic()
+    }
+}
+
+impl TransactionOptions {
+    /// Default options for an optimistic transaction.
+    pub fn new_optimistic() -> TransactionOptions {
+        TransactionOptions {
+            kind: TransactionKind::Optimistic,
+            try_one_pc: false,
+            async_commit: false,
+            read_only: false,
+            retry_options: RetryOptions::default_optimistic(),
+            check_level: CheckLevel::Panic,
+            heartbeat_option: HeartbeatOption::FixedTime(DEFAULT_HEARTBEAT_INTERVAL),
+            read_cache: None,
+            scan_cache: None,
+            read_options: ReadOptions::default(),
+            metrics_labels: MetricsLabels::default(),
+            pessimistic_lock_retries: DEFAULT_PESSIMISTIC_LOCK_RETRIES,
+            coalescing_rules: CoalescingRules::default(),
+            priority: CommandPriority::Normal,
+            resource_group_tag: Vec::new(),
+            context_hook: None,
+            max_lifetime: None,
+            deadline: None,
+            record_operations: false,
+            pipelined_pessimistic_lock: false,
+            replica_read: ReplicaRead::Leader,
+            size_limits: None,
+            external_consistency: false,
+            commit_chunk_size: COMMIT_CHUNK_SIZE,
+            commit_concurrency: MULTI_REGION_CONCURRENCY,
+        }
+    }
+
+    /// Default options for a pessimistic transaction.
+    pub fn new_pessimistic() -> TransactionOptions {
+        TransactionOptions {
+            kind: TransactionKind::Pessimistic(Timestamp::from_version(0)),
+            try_one_pc: false,
+            async_commit: false,
+            read_only: false,
+            retry_options: RetryOptions::default_pessimistic(),
+            check_level: CheckLevel::Panic,
+            heartbeat_option: HeartbeatOption::FixedTime(DEFAULT_HEARTBEAT_INTERVAL),
+            read_cache: None,
+            scan_cache: None,
+            read_options: ReadOptions::default(),
+            metrics_labels: MetricsLabels::default(),
+            pessimistic_lock_retries: DEFAULT_PESSIMISTIC_LOCK_RETRIES,
+            coalescing_rules: CoalescingRules::default(),
+            priority: CommandPriority::Normal,
+            resource_group_tag: Vec::new(),
+            context_hook: None,
+            max_lifetime: None,
+            deadline: None,
+            record_operations: false,
+            pipelined_pessimistic_lock: false,
+            replica_read: ReplicaRead::Leader,
+            size_limits: None,
+            external_consistency: false,
+            commit_chunk_size: COMMIT_CHUNK_SIZE,
+            commit_concurrency: MULTI_REGION_CONCURRENCY,
+        }
+    }
+
+    /// Try to use async commit.
+    ///
+    /// The transaction is committed once all keys are prewritten, with a commit timestamp chosen by
+    /// TiKV, which saves the request for a timestamp to PD and the commit of the primary key. If
+    /// TiKV falls back to two-phase commit for some keys, e.g. because there are too many
+    /// secondary keys, so does the transaction.
+    pub fn use_async_commit(mut self) -> TransactionOptions {
+        self.async_commit = true;
+        self
+    }
+
+    /// Try to use 1PC.
+    ///
+    /// If all mutations of the transaction are in one region, TiKV commits them with the prewrite
+    /// request, in a single RPC and without a timestamp from PD. Otherwise, or if TiKV falls back
+    /// to prewriting them, the transaction is committed with two-phase commit.
+    pub fn try_one_pc(mut self) -> TransactionOptions {
+        self.try_one_pc = true;
+        self
+    }
+
+    /// Make the commit of the transaction externally consistent, and verify its commit timestamp.
+    ///
+    /// Async commit and 1PC commit at a timestamp chosen by TiKV, which may be earlier than
+    /// timestamps PD issued before the commit started, so a transaction another client starts
+    /// after the commit returns may not read its writes. With this option, the transaction
+    /// fetches a timestamp from PD before prewriting, and TiKV commits at that timestamp at the
+    /// earliest, like two-phase commit does. It costs a request to PD, which async commit and 1PC
+    /// save otherwise.
+    ///
+    /// Before prewriting, the commit fails with [`TxnTooOld`](crate::Error::TxnTooOld) if the
+    /// start timestamp is before the GC safe point, and with two-phase commit, it fails with
+    /// [`InvalidCommitTs`](crate::Error::InvalidCommitTs) if the commit timestamp issued by PD is
+    /// not after the start timestamp and `for_update_ts` of the transaction, before the primary key
+    /// is committed.
+    pub fn external_consistency(mut self) -> TransactionOptions {
+        self.external_consistency = true;
+        self
+    }
+
+    /// Split the keys of each region into requests of at most `chunk_size` keys when prewriting,
+    /// committing or rolling back the transaction, instead of [`COMMIT_CHUNK_SIZE`].
+    ///
+    /// Smaller requests keep the Raft log entries of TiKV small and let the keys of a region be
+    /// written concurrently, see [`commit_concurrency`](TransactionOptions::commit_concurrency).
+    /// The transaction is only committed with 1PC if all its keys are prewritten by one request.
+    pub fn commit_chunk_size(mut self, chunk_size: usize) -> TransactionOptions {
+        self.commit_chunk_size = chunk_size.max(1);
+        self
+    }
+
+    /// Limit the requests prewriting, committing or rolling back the keys of the transaction in
+    /// flight to `concurrency`, instead of the default of 16.
+    ///
+    /// The requests are sent concurrently to all the regions of the keys, and to the chunks of
+    /// keys of each region, see [`commit_chunk_size`](TransactionOptions::commit_chunk_size). The
+    /// values of the keys are released as the requests prewriting them complete.
+    pub fn commit_concurrency(mut self, concurrency: usize) -> TransactionOptions {
+        self.commit_concurrency = concurrency.max(1);
+        self
+    }
+
+    /// Make the transaction read only.
+    pub fn read_only(mut self) -> TransactionOptions {
+        self.read_only = true;
+        self
+    }
+
+    /// Don't automatically resolve locks and retry if keys are locked.
+    pub fn no_resolve_locks(mut self) -> TransactionOptions {
+        self.retry_options.lock_backoff = Backoff::no_backoff();
+        self
+    }
+
+    /// Don't automatically resolve regions with PD if we have outdated region information.
+    pub fn no_resolve_regions(mut self) -> TransactionOptions {
+        self.retry_options.region_backoff = Backoff::no_backoff();
+        self
+    }
+
+    /// Set RetryOptions.
+    pub fn retry_options(mut self, options: RetryOptions) -> TransactionOptions {
+        self.retry_options = options;
+        self
+    }
+
+    /// Set the initial ReadOptions, see [`Transaction::set_read_options`].
+    pub fn read_options(mut self, options: ReadOptions) -> TransactionOptions {
+        self.read_options = options;
+        self
+    }
+
+    /// Cache the results of point gets in `cache`.
+    ///
+    /// The cache can be shared by many transactions and snapshots, reads at the same timestamp
+    /// are served from the cache instead of sending an RPC.
+    pub fn read_cache(mut self, cache: ReadCache) -> TransactionOptions {
+        self.read_cache = Some(cache);
+        self
+    }
+
+    /// Cache the pairs read by scans in `cache`.
+    ///
+    /// The cache can be shared by many transactions and snapshots, scans of the same range with
+    /// the same limit at the same timestamp are served from the cache instead of sending RPCs.
+    /// Scans which skip locked keys are not cached, see [`LockPolicy::SkipLocked`].
+    pub fn scan_cache(mut self, cache: ScanCache) -> TransactionOptions {
+        self.scan_cache = Some(cache);
+        self
+    }
+
+    /// Attach `labels` to the metrics of all requests of the transaction and to its logs.
+    ///
+    /// Labels set on the [`Client`](crate::TransactionClient::with_metrics_labels) are added to
+    /// these, labels set here take precedence.
+    pub fn metrics_labels(mut self, labels: MetricsLabels) -> TransactionOptions {
+        self.metrics_labels = labels;
+        self
+    }
+
+    /// Disable the features the cluster doesn't support, so that they don't fail at request time.
+    pub(super) fn supported_by(mut self, cluster: &ClusterInfo) -> TransactionOptions {
+        if !cluster.supports_async_commit() {
+            self.async_commit = false;
+        }
+        if !cluster.supports_one_pc() {
+            self.try_one_pc = false;
+        }
+        self
+    }
+
+    /// Add the labels of the client to the labels of the transaction.
+    pub(super) fn inherit_metrics_labels(mut self, labels: &MetricsLabels) -> TransactionOptions {
+        self.metrics_labels = labels.merge(&self.metrics_labels);
+        self
+    }
+
+    /// Use the priority and the resource group tag of the client, unless set.
+    pub(super) fn inherit_request_tags(
+        mut self,
+        priority: CommandPriority,
+        resource_group_tag: &[u8],
+    ) -> TransactionOptions {
+        if self.priority == CommandPriority::Normal {
+            self.priority = priority;
+        }
+        if self.resource_group_tag.is_empty() {
+            self.resource_group_tag = resource_group_tag.to_vec();
+        }
+        self
+    }
+
+    /// Set how many times a pessimistic lock is retried after a write conflict, e.g. a lock by
+    /// [`get_for_update`](Transaction::get_for_update) or [`lock_keys`](Transaction::lock_keys).
+    ///
+    /// A write conflict means that another transaction committed a write to one of the keys after
+    /// the lock's `for_update_ts`. Like TiDB, the lock is retried with a new `for_update_ts`, so
+    /// it reads and locks the latest version of the keys. The error is returned once the retries
+    /// are used up. Three retries by default.
+    pub fn pessimistic_lock_retries(mut self, retries: u32) -> TransactionOptions {
+        self.pessimistic_lock_retries = retries;
+        self
+    }
+
+    /// Set how several writes to the same key are coalesced into the mutation which is
+    /// prewritten, see [`CoalescingRules`].
+    pub fn coalescing_rules(mut self, rules: CoalescingRules) -> TransactionOptions {
+        self.coalescing_rules = rules;
+        self
+    }
+
+    /// Set the priority of the requests of the transaction in TiKV, e.g. `Low` for background
+    /// jobs, so that the scheduler of TiKV serves them after the requests of higher priority.
+    ///
+    /// A transaction left at the `Normal` priority uses the priority of its
+    /// [`Client`](crate::TransactionClient::with_priority).
+    pub fn priority(mut self, priority: CommandPriority) -> TransactionOptions {
+        self.priority = priority;
+        self
+    }
+
+    /// Tag the requests of the transaction with `tag`, which TiKV attributes their resource usage
+    /// to, e.g. the name of the job running the transaction.
+    ///
+    /// An untagged transaction uses the tag of its
+    /// [`Client`](crate::TransactionClient::with_resource_group_tag).
+    pub fn resource_group_tag(mut self, tag: impl Into<Vec<u8>>) -> TransactionOptions {
+        self.resource_group_tag = tag.into();
+        self
+    }
+
+    /// Amend the context of each request of the transaction sent to TiKV with `hook`, see
+    /// [`ContextHook`].
+    pub fn context_hook(mut self, hook: ContextHook) -> TransactionOptions {
+        self.context_hook = Some(hook);
+        self
+    }
+
+    /// Cap the lifetime of a pessimistic transaction, counted from its start, at `max_lifetime`.
+    ///
+    /// Once the lifetime is exceeded, the transaction stops sending heartbeats and its pessimistic
+    /// locks are rolled back, so that a forgotten transaction, e.g. of an interactive session,
+    /// does not block other transactions. Later operations, including commit, fail with
+    /// [`TransactionExpired`](Error::TransactionExpired), and a rollback only retries rolling back
+    /// the locks if that failed. A commit which has already started is not interrupted. Optimistic transactions
+    /// hold no locks before they commit and ignore the option.
+    pub fn max_lifetime(mut self, max_lifetime: Duration) -> TransactionOptions {
+        self.max_lifetime = Some(max_lifetime);
+        self
+    }
+
+    /// Fail each request sent to TiKV which doesn't complete within `timeout`, including its
+    /// retries and the resolution of the locks it encounters, with
+    /// [`DeadlineExceeded`](Error::DeadlineExceeded) instead of retrying further, e.g. while a
+    /// region is unavailable. The requests in flight are cancelled at the deadline.
+    ///
+    /// The deadline also applies to the requests of snapshots. If the deadline of the commit of
+    /// the primary key is exceeded, whether the transaction is committed is checked like after a
+    /// failed commit request, and it may be [undetermined](Error::UndeterminedError).
+    pub fn deadline(mut self, timeout: Duration) -> TransactionOptions {
+        self.deadline = Some(timeout);
+        self
+    }
+
+    /// Record the operations performed on the transaction, e.g. `put` or `scan`, without their
+    /// keys and values, and attach them to the errors of its commit, which fail with
+    /// [`CommitFailed`](Error::CommitFailed), to find out what a failing transaction did.
+    ///
+    /// The write operation which wrote each key last is recorded too: if an assertion fails when
+    /// the transaction commits, [`AssertionFailed`](Error::AssertionFailed) tells which operation
+    /// buffered the mutation of the key.
+    ///
+    /// Consecutive runs of an operation are recorded once with their number, e.g. `put x3`, and
+    /// only the latest operations of a long transaction are kept. An operation implemented with
+    /// other operations, e.g. [`get_for_update`](Transaction::get_for_update) in an optimistic
+    /// transaction, is followed by them in the log.
+    pub fn record_operations(mut self) -> TransactionOptions {
+        self.record_operations = true;
+        self
+    }
+
+    /// Don't wait for pessimistic locks which read no values, like TiDB's pipelined pessimistic
+    /// locks.
+    ///
+    /// The lock requests of [`lock_keys`](Transaction::lock_keys) and of writes, e.g.
+    /// [`put`](Transaction::put), are sent in the background, and the operation returns at once.
+    /// Their results are awaited before the transaction prewrites: if a lock failed, the commit
+    /// fails with its error, and the transaction should be rolled back, which also waits for the
+    /// locks in flight. Locks which read values, e.g. by
+    /// [`get_for_update`](Transaction::get_for_update), are still awaited.
+    ///
+    /// This saves a round trip to TiKV per lock, at the cost of a failed lock being reported only
+    /// at commit, after the rest of the transaction was executed.
+    pub fn pipelined_pessimistic_lock(mut self) -> TransactionOptions {
+        self.pipelined_pessimistic_lock = true;
+        self
+    }
+
+    /// Let the replicas of `replica_read` serve the reads of the transaction instead of the
+    /// leaders, to offload the leaders of a read-heavy workload.
+    ///
+    /// Gets, batch gets and scans are sent as replica reads. A replica serves a read once it has
+    /// caught up with the leader, so it reads the same data as the leader would, at the cost of a
+    /// round trip between the replica and the leader. Among the replicas of the kind, one is
+    /// chosen like the replicas of stale reads, see
+    /// [`Config::with_replica_selection`](crate::Config::with_replica_selection). Locking reads,
+    /// e.g. [`get_for_update`](Transaction::get_for_update), writes and the resolution of the
+    /// locks found by reads are still sent to the leaders.
+    ///
+    /// # Examples
+    /// ```rust,no_run
+    /// # use tikv_client::{ReplicaRead, TransactionClient, TransactionOptions};
+    /// # futures::executor::block_on(async {
+    /// let client = TransactionClient::new(vec!["192.168.0.100"], None).await.unwrap();
+    /// let options = TransactionOptions::new_optimistic().replica_read(ReplicaRead::Follower);
+    /// let mut txn = client.begin_with_options(options).await.unwrap();
+    /// let value = txn.get("key".to_owned()).await.unwrap();
+    /// txn.commit().await.unwrap();
+    /// # });
+    /// ```
+    pub fn replica_read(mut self, replica_read: ReplicaRead) -> TransactionOptions {
+        self.replica_read = replica_read;
+        self
+    }
+
+    /// Check the keys and values written by the transaction against `size_limits` instead of the
+    /// limits of the client, see [`Config::with_size_limits`](crate::Config::with_size_limits).
+    pub fn size_limits(mut self, size_limits: SizeLimits) -> TransactionOptions {
+        self.size_limits = Some(size_limits);
+        self
+    }
+
+    /// Set the behavior when dropping a transaction without an attempt to commit or rollback it.
+    pub fn drop_check(mut self, level: CheckLevel) -> TransactionOptions {
+        self.check_level = level;
+        self
+    }
+
+    fn push_for_update_ts(&mut self, for_update_ts: Timestamp) {
+        match &mut self.kind {
+            TransactionKind::Optimistic => unreachable!(),
+            TransactionKind::Pessimistic(old_for_update_ts) => {
+                self.kind = TransactionKind::Pessimistic(Timestamp::from_version(std::cmp::max(
+                    old_for_update_ts.version(),
+                    for_update_ts.version(),
+                )));
+            }
+        }
+    }
+
+    pub fn heartbeat_option(mut self, heartbeat_option: HeartbeatOption) -> TransactionOptions {
+        self.heartbeat_option = heartbeat_option;
+        self
+    }
+
+    /// Send a heartbeat for the primary lock of the transaction every `interval`, which is 10
+    /// seconds by default.
+    ///
+    /// Heartbeats start once the transaction acquires its first lock and stop once it is
+    /// committed, rolled back or dropped. Each heartbeat extends the TTL of the lock to twice the
+    /// interval past the age of the transaction, so that other transactions do not resolve the
+    /// locks of a long-running transaction.
+    pub fn heartbeat_interval(self, interval: Duration) -> TransactionOptions {
+        self.heartbeat_option(HeartbeatOption::FixedTime(interval))
+    }
+
+    /// The interval of heartbeats, the default one if they are not sent automatically.
+    fn heartbeat_period(&self) -> Duration {
+        match self.heartbeat_option {
+            HeartbeatOption::NoHeartbeat => DEFAULT_HEARTBEAT_INTERVAL,
+            HeartbeatOption::FixedTime(interval) => interval,
+        }
+    }
+
+    // Returns true if these options describe a pessimistic transaction.
+    pub fn is_pessimistic(&self) -> bool {
+        match self.kind {
+            TransactionKind::Pessimistic(_) => true,
+            TransactionKind::Optimistic => false,
+        }
+    }
+}
+
+/// What a read does when it encounters a key locked by another transaction.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum LockPolicy {
+    /// Resolve the lock if its transaction has expired, otherwise wait for the transaction to
+    /// finish (the default).
+    ResolveAndWait,
+    /// Leave locked keys out of the result, as if they did not exist.
+    ///
+    /// Locked keys count towards the limit of a scan in each region, so a scan can return fewer
+    /// entries than its limit even if there are more keys in the range. Such scans are not suitable
+    /// for pagination.
+    SkipLocked,
+    /// Return a [`KeyError`](crate::Error::KeyError) holding the lock, see
+    /// [`Error::lock`](crate::Error::lock).
+    FailFast,
+}
+
+/// Which replicas serve the reads of a transaction, see [`TransactionOptions::replica_read`].
+///
+/// Witnesses, which only keep the Raft log of a region, never serve reads.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ReplicaRead {
+    /// The leader of each region (the default).
+    Leader,
+    /// A follower of each region, or the leader if the region has no followers.
+    Follower,
+    /// A learner of each region, or the leader if the region has no learners.
+    Learner,
+    /// The leader of each region, or a follower while the store of the leader is unreachable.
+    PreferLeader,
+}
+
+/// Options for reads in a transaction.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ReadOptions {
+    lock_policy: LockPolicy,
+}
+
+impl Default for ReadOptions {
+    fn default() -> ReadOptions {
+        ReadOptions {
+            lock_policy: LockPolicy::ResolveAndWait,
+        }
+    }
+}
+
+impl ReadOptions {
+    pub fn new() -> ReadOptions {
+        ReadOptions::default()
+    }
+
+    /// Set what reads do when they encounter a lock.
+    pub fn lock_policy(mut self, policy: LockPolicy) -> ReadOptions {
+        self.lock_policy = policy;
+        self
+    }
+}
+
+/// Options of [`Transaction::lock_keys_with_options`].
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct PessimisticLockOptions {
+    pub(crate) wait_timeout: Option<Duration>,
+    pub(crate) return_values: bool,
+    pub(crate) check_existence: bool,
+}
+
+impl PessimisticLockOptions {
+    pub fn new() -> PessimisticLockOptions {
+        PessimisticLockOptions::default()
+    }
+
+    /// Wait at most `timeout` for other transactions to release their locks on the keys, then
+    /// fail with [`ResolveLockError`](crate::Error::ResolveLockError). A zero timeout doesn't
+    /// wait at all. Locks of expired transactions are resolved either way.
+    ///
+    /// By default, TiKV waits for the locks as long as its `wait-for-lock-timeout` allows, and
+    /// the lock is retried with the lock backoff of the transaction after that.
+    pub fn wait_timeout(mut self, timeout: Duration) -> PessimisticLockOptions {
+        self.wait_timeout = Some(timeout);
+        self
+    }
+
+    /// Return the values of the locked keys. Not set by default.
+    pub fn return_values(mut self, return_values: bool) -> PessimisticLockOptions {
+        self.return_values = return_values;
+        self
+    }
+
+    /// Return which of the locked keys exist, without their values unless
+    /// [`return_values`](PessimisticLockOptions::return_values) is set. Unlike `return_values`,
+    /// it tells empty values apart from keys which don't exist. Not set by default.
+    pub fn check_existence(mut self, check_existence: bool) -> PessimisticLockOptions {
+        self.check_existence = check_existence;
+        self
+    }
+
+    pub(crate) fn needs_result(&self) -> bool {
+        self.return_values || self.check_existence
+    }
+
+    /// The `wait_timeout` of a lock request: 0 is the timeout of TiKV, a negative timeout doesn't
+    /// wait.
+    pub(crate) fn wait_timeout_ms(&self) -> i64 {
+        match self.wait_timeout {
+            None => 0,
+            Some(timeout) if timeout.is_zero() => -1,
+            Some(timeout) => timeout.as_millis().clamp(1, i64::MAX as u128) as i64,
+        }
+    }
+}
+
+/// Determines what happens when a transaction is dropped without being rolled back or committed.
+///
+/// The default is to panic.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub enum CheckLevel {
+    /// The program will panic.
+    ///
+    /// Note that if the thread is already panicking, then we will not double-panic and abort, but
+    /// just ignore the issue.
+    Panic,
+    /// Log a warning.
+    Warn,
+    /// Do nothing
+    None,
+}
+
+impl HeartbeatOption {
+    pub fn is_auto_heartbeat(&self) -> bool {
+        !matches!(self, HeartbeatOption::NoHeartbeat)
+    }
+}
+
+/// A struct wrapping the details of two-phase commit protocol (2PC).
+///
+/// The two phases are `prewrite` and `commit`.
+/// Generally, the `prewrite` phase is to send data to all regions and write them.
+/// The `commit` phase is to mark all written data as successfully committed.
+///
+/// The committer implements `prewrite`, `commit` and `rollback` functions.
+#[allow(clippy::too_many_arguments)]
+#[derive(new)]
+struct Committer<PdC: PdClient = PdRpcClient> {
+    primary_key: Option<Key>,
+    mutations: Vec<kvrpcpb::Mutation>,
+    start_version: Timestamp,
+    rpc: Arc<PdC>,
+    options: TransactionOptions,
+    #[new(default)]
+    undetermined: bool,
+    /// The time spent waiting for timestamps from PD.
+    #[new(default)]
+    tso_wait: Duration,
+    /// The time spent waiting for requests to TiKV.
+    #[new(default)]
+    kv_wait: Duration,
+    write_size: u64,
+    start_instant: Instant,
+    logger: Logger,
+}
+
+impl<PdC: PdClient> Committer<PdC> {
+    async fn commit(mut self) -> Result<Option<Timestamp>> {
+        debug!(self.logger, "committing");
+
+        if self.options.external_consistency {
+            self.check_safe_point().await?;
+        }
+
+        let start = Instant::now();
+        let min_commit_ts = in_span!(
+            self.prewrite(),
+            "prewrite",
+            start_ts = self.start_version.version(),
+            keys = self.mutations.len()
+        )
+        .await;
+        self.observe_kv_wait(start.elapsed());
+        let min_commit_ts = min_commit_ts?;
+
+        fail_point!("after-prewrite");
+
+        // If we didn't use 1pc, prewrite will set `try_one_pc` to false.
+        if self.options.try_one_pc {
+            self.trace_waits();
+            return Ok(min_commit_ts);
+        }
+
+        let commit_ts = match min_commit_ts {
+            Some(min_commit_ts) if self.options.async_commit => min_commit_ts,
+            _ => {
+                if self.options.async_commit {
+                    debug!(self.logger, "falling back to two-phase commit");
+                    self.options.async_commit = false;
+                }
+                let commit_primary = in_span!(
+                    self.commit_primary(),
+                    "commit_primary",
+                    start_ts = self.start_version.version()
+                );
+                match commit_primary.await {
+                    Ok(commit_ts) => commit_ts,
+                    Err(e) => {
+                        return if self.undetermined {
+                            Err(Error::UndeterminedError(Box::new(e)))
+                        } else {
+                            Err(e)
+                        };
+                    }
+                }
+            }
+        };
+        self.trace_waits();
+        // the secondary keys are committed in the background, but still recorded in the trace of
+        // the commit, if any
+        let commit_secondary = in_span!(
+            self.commit_secondary(commit_ts.clone()),
+            "commit_secondary",
+            start_ts = self.start_version.version(),
+            commit_ts = commit_ts.version()
+        )
+        .map(|res| {
+            if let Err(e) = res {
+                log::warn!("Failed to commit secondary keys: {}", e);
+            }
+        });
+        tokio::spawn(in_current_trace(commit_secondary));
+        Ok(Some(commit_ts))
+    }
+
+    async fn prewrite(&mut self) -> Result<Option<Timestamp>> {
+        debug!(self.logger, "prewriting");
+        let primary_lock = self.primary_key.clone().unwrap();
+        let elapsed = self
+            .rpc
+            .clock()
+            .elapsed_since(self.start_instant)
+            .as_millis() as u64;
+        let lock_ttl = self.calc_txn_lock_ttl();
+        let has_assertions = self
+            .mutations
+            .iter()
+            .any(|m| m.get_assertion() != kvrpcpb::Assertion::None);
+        let secondaries = if self.options.async_commit || self.options.try_one_pc {
+            self.mutations
+                .iter()
+                .filter(|m| self.primary_key.as_ref().unwrap() != m.key.as_ref())
+                .map(|m| m.key.clone())
+                .collect()
+        } else {
+            Vec::new()
+        };
+        let mutations = self.take_values();
+        let mut request = match &self.options.kind {
+            TransactionKind::Optimistic => new_prewrite_request(
+                mutations,
+                primary_lock,
+                self.start_version.clone(),
+                lock_ttl + elapsed,
+            ),
+            TransactionKind::Pessimistic(for_update_ts) => new_pessimistic_prewrite_request(
+                mutations,
+                primary_lock,
+                self.start_version.clone(),
+                lock_ttl + elapsed,
+                for_update_ts.clone(),
+            ),
+        };
+
+        request.use_async_commit = self.options.async_commit;
+        request.try_one_pc = self.options.try_one_pc;
+        // TiKV only verifies assertions at a level other than `Off`
+        if has_assertions {
+            request.set_assertion_level(kvrpcpb::AssertionLevel::Strict);
+        }
+        // only needed to commit asynchronously, or to check that 1PC sends a single request
+        request.secondaries = secondaries;
+        if self.options.async_commit || self.options.try_one_pc {
+            // TiKV chooses the commit timestamp between these bounds
+            let mut min_commit_ts = self.min_commit_ts();
+            let mut max_commit_ts = self.start_version.clone();
+            if self.options.external_consistency {
+                // like the commit timestamp of two-phase commit, the commit timestamp chosen by
+                // TiKV is not before any timestamp PD issued before the commit started
+                let latest = self.get_timestamp().await?;
+                min_commit_ts = min_commit_ts.max(latest.version());
+                max_commit_ts.physical = max_commit_ts.physical.max(latest.physical);
+            }
+            let window = elapsed + ASYNC_COMMIT_SAFE_WINDOW.as_millis() as u64;
+            max_commit_ts.physical += window as i64;
+            request.set_min_commit_ts(min_commit_ts);
+            request.set_max_commit_ts(max_commit_ts.version());
+        }
+
+        let plan = PlanBuilder::new(self.rpc.clone(), request)
+            .labels(self.options.metrics_labels.clone())
+            .context_hook(self.options.context_hook.clone())
+            .priority(self.options.priority.into())
+            .resource_group_tag(self.options.resource_group_tag.clone())
+            .deadline(self.options.deadline)
+            .on_retries_exhausted(self.options.retry_options.on_write_exhausted.clone())
+            .resolve_lock(self.options.retry_options.lock_backoff.clone())
+            .retry_multi_region(self.options.retry_options.region_backoff.clone())
+            .chunk_size(Some(self.options.commit_chunk_size))
+            .concurrency(Some(self.options.commit_concurrency))
+            .merge(CollectError)
+            .extract_error()
+            .plan();
+        let response = plan.execute().await?;
+
+        if self.options.try_one_pc && response.len() == 1 {
+            match Timestamp::try_from_version(response[0].one_pc_commit_ts) {
+                Some(commit_ts) => return Ok(Some(commit_ts)),
+                // TiKV prewrote the keys instead, e.g. because the mutations are too large
+                None => debug!(self.logger, "1PC failed, falling back to two-phase commit"),
+            }
+        }
+
+        self.options.try_one_pc = false;
+
+        // TiKV returns no `min_commit_ts` for the keys it does not commit asynchronously, then the
+        // whole transaction falls back to two-phase commit
+        let min_commit_ts = response
+            .iter()
+            .map(|r| {
+                assert_eq!(r.one_pc_commit_ts, 0);
+                Timestamp::try_from_version(r.min_commit_ts)
+            })
+            .collect::<Option<Vec<_>>>()
+            .and_then(|ts| ts.into_iter().max_by_key(Timestamp::version));
+
+        Ok(min_commit_ts)
+    }
+
+    /// Commits the primary key and returns the commit version
+    async fn commit_primary(&mut self) -> Result<Timestamp> {
+        debug!(self.logger, "committing primary");
+        let primary_key = self.primary_key.clone().into_iter();
+        let commit_version = self.get_timestamp().await?;
+        if self.options.external_consistency && commit_version.version() < self.min_commit_ts() {
+            return Err(Error::InvalidCommitTs {
+                start_ts: self.start_version.version(),
+                commit_ts: commit_version.version(),
+                min_commit_ts: self.min_commit_ts(),
+            });
+        }
+        let req = new_commit_request(
+            primary_key,
+            self.start_version.clone(),
+            commit_version.clone(),
+        );
+        let plan = PlanBuilder::new(self.rpc.clone(), req)
+            .labels(self.options.metrics_labels.clone())
+            .context_hook(self.options.context_hook.clone())
+            .priority(self.options.priority.into())
+            .resource_group_tag(self.options.resource_group_tag.clone())
+            .deadline(self.options.deadline)
+            .on_retries_exhausted(self.options.retry_options.on_write_exhausted.clone())
+            .resolve_lock(self.options.retry_options.lock_backoff.clone())
+            .retry_multi_region(self.options.retry_options.region_backoff.clone())
+            .extract_error()
+            .plan();
+        let start = Instant::now();
+        let result = plan.execute().await;
+        self.observe_kv_wait(start.elapsed());
+        match result {
+            Ok(_) => Ok(commit_version),
+            // We don't know whether the transaction is committed or not if we fail to receive
+            // the response. Then, we check the primary key, and only if that fails too, we mark
+            // the transaction as undetermined and propagate the error to the user. The same
+            // applies if the commit was cancelled at its deadline.
+            Err(e) if is_lost_response(&e) => match self.check_commit(&commit_version).await {
+                Ok(true) => Ok(commit_version),
+                Ok(false) => Err(e),
+                Err(check_err) => {
+                    debug!(self.logger, "failed to check the commit"; "error" => ?check_err);
+                    self.undetermined = true;
+                    Err(e)
+                }
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    /// After the response to the commit of the primary key was lost, checks whether the commit
+    /// was applied. While the primary key is still locked, it is committed again with the same
+    /// `commit_version`, which has no effect if the lost commit is applied after all.
+    ///
+    /// Returns whether the transaction is committed, or an error if that is still unknown once
+    /// the region backoff of the transaction is exhausted.
+    async fn check_commit(&mut self, commit_version: &Timestamp) -> Result<bool> {
+        let primary_key = self.primary_key.clone().unwrap();
+        let mut backoff = self.options.retry_options.region_backoff.clone();
+        loop {
+            // with neither a caller nor a current timestamp, the status is only read, the lock is
+            // neither pushed nor rolled back
+            let request = new_check_txn_status_request(
+                primary_key.clone().into(),
+                self.start_version.version(),
+                0,
+                0,
+                false,
+                false,
+                false,
+            );
+            let plan = PlanBuilder::new(self.rpc.clone(), request)
+                .labels(self.options.metrics_labels.clone())
+                .context_hook(self.options.context_hook.clone())
+                .priority(self.options.priority.into())
+                .resource_group_tag(self.options.resource_group_tag.clone())
+                .deadline(self.options.deadline)
+                .on_retries_exhausted(self.options.retry_options.on_write_exhausted.clone())
+                .retry_multi_region(self.options.retry_options.region_backoff.clone())
+                .merge(CollectSingle)
+                .post_process_default()
+                .plan();
+            match plan.execute().await?.kind {
+                TransactionStatusKind::Committed(_) => return Ok(true),
+                TransactionStatusKind::RolledBack => return Ok(false),
+                TransactionStatusKind::Locked(..) => {}
+            }
+
+            debug!(
+                self.logger,
+                "primary key is still locked, committing it again"
+            );
+            let request = new_commit_request(
+                iter::once(primary_key.clone()),
+                self.start_version.clone(),
+                commit_version.clone(),
+            );
+            let plan = PlanBuilder::new(self.rpc.clone(), request)
+                .labels(self.options.metrics_labels.clone())
+                .context_hook(self.options.context_hook.clone())
+                .priority(self.options.priority.into())
+                .resource_group_tag(self.options.resource_group_tag.clone())
+                .deadline(self.options.deadline)
+                .on_retries_exhausted(self.options.retry_options.on_write_exhausted.clone())
+                .retry_multi_region(self.options.retry_options.region_backoff.clone())
+                .extract_error()
+                .plan();
+            let e = match plan.execute().await {
+                Ok(_) => return Ok(true),
+                Err(e) => e,
+            };
+            match backoff.next_delay_duration() {
+                Some(delay) => self.rpc.clock().sleep(delay).await,
+                None => return Err(e),
+            }
+        }
+    }
+
+    /// Fails if the start timestamp is before the GC safe point, then the versions read by the
+    /// transaction may have been garbage collected before it commits.
+    async fn check_safe_point(&self) -> Result<()> {
+        let safe_point = self.rpc.clone().get_gc_safepoint().await?;
+        if self.start_version.version() < safe_point {
+            return Err(Error::TxnTooOld {
+                start_ts: self.start_version.version(),
+                safe_point,
+            });
+        }
+        Ok(())
+    }
+
+    /// The smallest timestamp the transaction may commit at, after its start and `for_update_ts`.
+    fn min_commit_ts(&self) -> u64 {
+        let last_read_ts = match &self.options.kind {
+            TransactionKind::Optimistic => self.start_version.version(),
+            TransactionKind::Pessimistic(for_update_ts) => {
+                for_update_ts.version().max(self.start_version.version())
+            }
+        };
+        last_read_ts + 1
+    }
+
+    /// Takes the mutations to prewrite, leaving only their keys to commit or roll back the
+    /// transaction, so that the values are released as the requests prewriting them complete.
+    fn take_values(&mut self) -> Vec<kvrpcpb::Mutation> {
+        let keys = self
+            .mutations
+            .iter()
+            .map(|m| kvrpcpb::Mutation {
+                op: m.op,
+                key: m.key.clone(),
+                ..Default::default()
+            })
+            .collect();
+        mem::replace(&mut self.mutations, keys)
+    }
+
+    async fn get_timestamp(&mut self) -> Result<Timestamp> {
+        let start = Instant::now();
+        let timestamp = self.rpc.clone().get_timestamp().await;
+        self.tso_wait += start.elapsed();
+        observe_txn_wait("commit", "tso", start.elapsed());
+        timestamp
+    }
+
+    fn observe_kv_wait(&mut self, wait: Duration) {
+        self.kv_wait += wait;
+        observe_txn_wait("commit", "kv", wait);
+    }
+
+    /// Logs how long the commit waited for PD and for TiKV, secondary keys are committed in the
+    /// background and are not included.
+    fn trace_waits(&self) {
+        debug!(
+            self.logger,
+            "committed";
+            "tso_wait" => ?self.tso_wait,
+            "kv_wait" => ?self.kv_wait
+        );
+    }
+
+    async fn commit_secondary(self, commit_version: Timestamp) -> Result<()> {
+        debug!(self.logger, "committing secondary");
+        let mutations_len = self.mutations.len();
+        let primary_only = mutations_len == 1;
+        let mutations = self.mutations.into_iter();
+
+        let req = if self.options.async_commit {
+            let keys = mutations.map(|m| m.key.into());
+            new_commit_request(keys, self.start_version, commit_version)
+        } else if primary_only {
+            return Ok(());
+        } else {
+            let primary_key = self.primary_key.unwrap();
+            let keys = mutations
+                .map(|m| m.key.into())
+                .filter(|key| &primary_key != key);
+            new_commit_request(keys, self.start_version, commit_version)
+        };
+        let plan = PlanBuilder::new(self.rpc, req)
+            .labels(self.options.metrics_labels)
+            .context_hook(self.options.context_hook)
+            .priority(self.options.priority.into())
+            .resource_group_tag(self.options.resource_group_tag)
+            .deadline(self.options.deadline)
+            .on_retries_exhausted(self.options.retry_options.on_write_exhausted)
+            .resolve_lock(self.options.retry_options.lock_backoff)
+            .retry_multi_region(self.options.retry_options.region_backoff)
+            .chunk_size(Some(self.options.commit_chunk_size))
+            .concurrency(Some(self.options.commit_concurrency))
+            .extract_error()
+            .plan();
+        plan.execute().await?;
+        Ok(())
+    }
+
+    async fn rollback(self) -> Result<()> {
+        debug!(self.logger, "rolling back");
+        if self.options.kind == TransactionKind::Optimistic && self.mutations.is_empty() {
+            return Ok(());
+        }
+        let keys = self
+            .mutations
+            .into_iter()
+            .map(|mutation| mutation.key.into());
+        match self.options.kind {
+            TransactionKind::Optimistic => {
+                let req = new_batch_rollback_request(keys, self.start_version);
+                let plan = PlanBuilder::new(self.rpc, req)
+                    .labels(self.options.metrics_labels)
+                    .context_hook(self.options.context_hook)
+                    .priority(self.options.priority.into())
+                    .resource_group_tag(self.options.resource_group_tag)
+                    .deadline(self.options.deadline)
+                    .on_retries_exhausted(self.options.retry_options.on_write_exhausted)
+                    .resolve_lock(self.options.retry_options.lock_backoff)
+                    .retry_multi_region(self.options.retry_options.region_backoff)
+                    .chunk_size(Some(self.options.commit_chunk_size))
+                    .concurrency(Some(self.options.commit_concurrency))
+                    .extract_error()
+                    .plan();
+                plan.execute().await?;
+            }
+            TransactionKind::Pessimistic(for_update_ts) => {
+                let req = new_pessimistic_rollback_request(keys, self.start_version, for_update_ts);
+                let plan = PlanBuilder::new(self.rpc, req)
+                    .labels(self.options.metrics_labels)
+                    .context_hook(self.options.context_hook)
+                    .priority(self.options.priority.into())
+                    .resource_group_tag(self.options.resource_group_tag)
+                    .deadline(self.options.deadline)
+                    .on_retries_exhausted(self.options.retry_options.on_write_exhausted)
+                    .resolve_lock(self.options.retry_options.lock_backoff)
+                    .retry_multi_region(self.options.retry_options.region_backoff)
+                    .chunk_size(Some(self.options.commit_chunk_size))
+                    .concurrency(Some(self.options.commit_concurrency))
+                    .extract_error()
+                    .plan();
+                plan.execute().await?;
+            }
+        }
+        Ok(())
+    }
+
+    fn calc_txn_lock_ttl(&mut self) -> u64 {
+        let mut lock_ttl = DEFAULT_LOCK_TTL;
+        if self.write_size > TXN_COMMIT_BATCH_SIZE {
+            let size_mb = self.write_size as f64 / 1024.0 / 1024.0;
+            lock_ttl = (TTL_FACTOR * size_mb.sqrt()) as u64;
+            lock_ttl = lock_ttl.min(MAX_TTL).max(DEFAULT_LOCK_TTL);
+        }
+        lock_ttl
+    }
+}
+
+/// Whether a request failed without a response, so that it may or may not have been applied.
+fn is_lost_response(e: &Error) -> bool {
+    match e {
+        Error::Grpc(_) | Error::DeadlineExceeded { .. } => true,
+        Error::RetriesExhausted { source, .. } | Error::RequestFailed { source, .. } => {
+            is_lost_response(source)
+        }
+        _ => false,
+    }
+}
+
+#[derive(PartialEq)]
+pub(super) enum TransactionStatus {
+    /// The transaction is read-only [`Snapshot`](super::Snapshot), no need to commit or rollback or panic on drop.
+    ReadOnly,
+    /// The transaction have not been committed or rolled back.
+    Active,
+    /// The transaction has committed.
+    Committed,
+    /// The transaction has tried to commit. Only `commit` is allowed.
+    StartedCommit,
+    /// The transaction has rolled back.
+    Rolledback,
+    /// The transaction has tried to rollback. Only `rollback` is allowed.
+    StartedRollback,
+    /// The transaction has been dropped.
+    Dropped,
+    /// The transaction exceeded its maximum lifetime and its locks were rolled back.
+    Expired,
+}
+
+impl TransactionStatus {
+    /// Returns true if the transaction no longer holds any locks which need heartbeats.
+    pub(super) fn is_finished(&self) -> bool {
+        matches!(
+            self,
+            TransactionStatus::Rolledback
+                | TransactionStatus::Committed
+                | TransactionStatus::Dropped
+                | TransactionStatus::Expired
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        mock::{MockKvClient, MockPdClient},
+        namespace::Namespace,
+        transaction::HeartbeatOption,
+        Assertion, BoundRange, CheckLevel, ClockHandle, ClusterInfo, CommandPriority, ContextHook,
+        CoprocessorRequest, CoprocessorRequestType, CoprocessorResponse, Error, FilterPushdown,
+        Key, KvPair, LockPolicy, MetricsLabels, MockClock, Mutation, MvccKind,
+        PessimisticLockOptions, ReadCache, ReadOptions, ReplicaRead, ScanCache, ScanFilter,
+        SizeLimits, TimestampExt, Transaction, TransactionOptions,
+    };
+    use fail::FailScenario;
+    use futures::TryStreamExt;
+    use slog::{Drain, Logger};
+    use std::{
+        any::Any,
+        convert::TryFrom,
+        io,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+        time::Duration,
+    };
+    use tikv_client_proto::{coprocessor, errorpb, kvrpcpb, pdpb::Timestamp};
+
+    // Answers the requests of locking, committing and rolling back a transaction with empty
+    // responses, for dispatch hooks which only inspect some of the requests.
+    fn empty_response(req: &dyn Any) -> crate::Result<Box<dyn Any>> {
+        if req.is::<kvrpcpb::PessimisticLockRequest>() {
+            Ok(Box::new(kvrpcpb::PessimisticLockResponse::default()))
+        } else if req.is::<kvrpcpb::PrewriteRequest>() {
+            Ok(Box::new(kvrpcpb::PrewriteResponse::default()))
+        } else if req.is::<kvrpcpb::CommitRequest>() {
+            Ok(Box::new(kvrpcpb::CommitResponse::default()))
+        } else if req.is::<kvrpcpb::TxnHeartBeatRequest>() {
+            Ok(Box::new(kvrpcpb::TxnHeartBeatResponse::default()))
+        } else if req.is::<kvrpcpb::PessimisticRollbackRequest>() {
+            Ok(Box::new(kvrpcpb::PessimisticRollbackResponse::default()))
+        } else if req.is::<kvrpcpb::BatchRollbackRequest>() {
+            Ok(Box::new(kvrpcpb::BatchRollbackResponse::default()))
+        } else {
+            panic!("unexpected request")
+        }
+    }
+
+    #[tokio::test]
+    async fn test_optimistic_heartbeat() -> Result<(), io::Error> {
+        let plain = slog_term::PlainSyncDecorator::new(std::io::stdout());
+        let logger = Logger::root(
+            slog_term::FullFormat::new(plain)
+                .build()
+                .filter_level(slog::Level::Info)
+                .fuse(),
+            o!(),
+        );
+        let scenario = FailScenario::setup();
+        fail::cfg("after-prewrite", "sleep(1500)").unwrap();
+        let heartbeats = Arc::new(AtomicUsize::new(0));
+        let heartbeats_cloned = heartbeats.clone();
+        let pd_client = Arc::new(MockPdClient::new(MockKvClient::with_dispatch_hook(
+            move |req: &dyn Any| {
+                if req.downcast_ref::<kvrpcpb::TxnHeartBeatRequest>().is_some() {
+                    heartbeats_cloned.fetch_add(1, Ordering::SeqCst);
+                    Ok(Box::new(kvrpcpb::TxnHeartBeatResponse::default()) as Box<dyn Any>)
+                } else {
+                    empty_response(req)
+                }
+            },
+        )));
+        let key1 = "key1".to_owned();
+        let mut heartbeat_txn = Transaction::new(
+            Timestamp::default(),
+            pd_client,
+            TransactionOptions::new_optimistic()
+                .heartbeat_option(HeartbeatOption::FixedTime(Duration::from_secs(1))),
+            logger.new(o!("child" => 1)),
+        );
+        heartbeat_txn.put(key1.clone(), "foo").await.unwrap();
+        let heartbeat_txn_handle = tokio::task::spawn_blocking(move || {
+            assert!(futures::executor::block_on(heartbeat_txn.commit()).is_ok())
+        });
+        assert_eq!(heartbeats.load(Ordering::SeqCst), 0);
+        heartbeat_txn_handle.await.unwrap();
+        assert_eq!(heartbeats.load(Ordering::SeqCst), 1);
+        scenario.teardown();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_pessimistic_heartbeat() -> Result<(), io::Error> {
+        let plain = slog_term::PlainSyncDecorator::new(std::io::stdout());
+
+        let logger = Logger::root(
+            slog_term::FullFormat::new(plain)
+                .build()
+                .filter_level(slog::Level::Info)
+                .fuse(),
+            o!(),
+        );
+        let heartbeats = Arc::new(AtomicUsize::new(0));
+        let heartbeats_cloned = heartbeats.clone();
+        let pd_client = Arc::new(MockPdClient::new(MockKvClient::with_dispatch_hook(
+            move |req: &dyn Any| {
+                if req.downcast_ref::<kvrpcpb::TxnHeartBeatRequest>().is_some() {
+                    heartbeats_cloned.fetch_add(1, Ordering::SeqCst);
+                    Ok(Box::new(kvrpcpb::TxnHeartBeatResponse::default()) as Box<dyn Any>)
+                } else {
+                    empty_response(req)
+                }
+            },
+        )));
+        let key1 = "key1".to_owned();
+        let mut heartbeat_txn = Transaction::new(
+            Timestamp::default(),
+            pd_client,
+            TransactionOptions::new_pessimistic()
+                .heartbeat_option(HeartbeatOption::FixedTime(Duration::from_secs(1))),
+            logger.new(o!("child" => 1)),
+        );
+        heartbeat_txn.put(key1.clone(), "foo").await.unwrap();
+        assert_eq!(heartbeats.load(Ordering::SeqCst), 0);
+        tokio::time::sleep(tokio::time::Duration::from_millis(1500)).await;
+        assert_eq!(heartbeats.load(Ordering::SeqCst), 1);
+        let heartbeat_txn_handle = tokio::spawn(async move {
+            assert!(heartbeat_txn.commit().await.is_ok());
+        });
+        heartbeat_txn_handle.await.unwrap();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_commit_wait_metrics() {
+        let logger = Logger::root(slog::Discard, o!());
+        let pd_client = Arc::new(MockPdClient::new(MockKvClient::with_dispatch_hook(
+            empty_response,
+        )));
+        let mut txn = Transaction::new(
+            Timestamp::default(),
+            pd_client,
+            TransactionOptions::new_optimistic().heartbeat_option(HeartbeatOption::NoHeartbeat),
+            logger,
+        );
+        txn.put(vec![1], vec![1]).await.unwrap();
+        txn.commit().await.unwrap();
+
+        // the waits for PD and for TiKV are recorded separately
+        let records = crate::stats::test::recorded_metrics();
+        for prefix in &[
+            r#"histogram tikv_txn_wait_duration_seconds ["commit", "tso"]"#,
+            r#"histogram tikv_txn_wait_duration_seconds ["commit", "kv"]"#,
+        ] {
+            assert!(
+                records.iter().any(|record| record.starts_with(prefix)),
+                "{}",
+                prefix
+            );
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    #[tokio::test]
+    async fn test_tracing_spans() {
+        let (_guard, spans) = crate::trace::tests::record_spans();
+        let pd_client = Arc::new(MockPdClient::new(MockKvClient::with_dispatch_hook(
+            empty_response,
+        )));
+        let mut txn = Transaction::new(
+            Timestamp::default(),
+            pd_client,
+            TransactionOptions::new_pessimistic().heartbeat_option(HeartbeatOption::NoHeartbeat),
+            Logger::root(slog::Discard, o!()),
+        );
+        txn.lock_keys(vec!["key1".to_owned()]).await.unwrap();
+        txn.put("key2".to_owned(), "value".to_owned())
+            .await
+            .unwrap();
+        txn.commit().await.unwrap();
+
+        // the operations are children of the transaction, and the phases of the commit are
+        // children of the commit
+        let spans = spans.lock().unwrap();
+        for span in &[
+            ("transaction", None),
+            ("pessimistic_lock", Some("transaction")),
+            ("commit", Some("transaction")),
+            ("prewrite", Some("commit")),
+            ("commit_primary", Some("commit")),
+            ("region_request", Some("prewrite")),
+            ("region_request", Some("commit_primary")),
+        ] {
+            assert!(spans.contains(span), "{:?} in {:?}", span, spans);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_context_hook() {
+        let logger = Logger::root(slog::Discard, o!());
+        let requests = Arc::new(AtomicUsize::new(0));
+        let requests_cloned = requests.clone();
+        let pd_client = Arc::new(MockPdClient::new(MockKvClient::with_dispatch_hook(
+            move |req: &dyn Any| {
+                let context =
+                    if let Some(req) = req.downcast_ref::<kvrpcpb::PessimisticLockRequest>() {
+                        req.get_context()
+                    } else if let Some(req) = req.downcast_ref::<kvrpcpb::PrewriteRequest>() {
+                        req.get_context()
+                    } else if let Some(req) = req.downcast_ref::<kvrpcpb::CommitRequest>() {
+                        req.get_context()
+                    } else {
+                        panic!("unexpected request")
+                    };
+                requests_cloned.fetch_add(1, Ordering::SeqCst);
+                assert!(context.not_fill_cache);
+                assert_eq!(context.get_priority(), kvrpcpb::CommandPri::High);
+                empty_response(req)
+            },
+        )));
+        let options = TransactionOptions::new_pessimistic()
+            .heartbeat_option(HeartbeatOption::NoHeartbeat)
+            .priority(CommandPriority::Low)
+            .context_hook(ContextHook::new(|context| {
+                context.not_fill_cache = true;
+                context.set_priority(kvrpcpb::CommandPri::High);
+            }));
+        let mut txn = Transaction::new(Timestamp::default(), pd_client, options, logger);
+        txn.put(vec![1], vec![1]).await.unwrap();
+        txn.commit().await.unwrap();
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_prewrite_assertions() {
+        let logger = Logger::root(slog::Discard, o!());
+        let prewrites = Arc::new(AtomicUsize::new(0));
+        let prewrites_cloned = prewrites.clone();
+        let pd_client = Arc::new(MockPdClient::new(MockKvClient::with_dispatch_hook(
+            move |req: &dyn Any| {
+                if let Some(req) = req.downcast_ref::<kvrpcpb::PrewriteRequest>() {
+                    prewrites_cloned.fetch_add(1, Ordering::SeqCst);
+                    assert_eq!(req.get_assertion_level(), kvrpcpb::AssertionLevel::Strict);
+                    let assertions: Vec<kvrpcpb::Assertion> =
+                        req.mutations.iter().map(|m| m.get_assertion()).collect();
+                    assert_eq!(
+                        assertions,
+                        vec![kvrpcpb::Assertion::Exist, kvrpcpb::Assertion::NotExist]
+                    );
+                    Ok(Box::new(kvrpcpb::PrewriteResponse::default()) as Box<dyn Any>)
+                } else {
+                    empty_response(req)
+                }
+            },
+        )));
+        let mut txn = Transaction::new(
+            Timestamp::default(),
+            pd_client,
+            TransactionOptions::new_optimistic().heartbeat_option(HeartbeatOption::NoHeartbeat),
+            logger,
+        );
+        txn.delete_with_assertion(vec![1], Assertion::Exist)
+            .await
+            .unwrap();
+        txn.put_with_assertion(vec![2], vec![2], Assertion::NotExist)
+            .await
+            .unwrap();
+        txn.commit().await.unwrap();
+        assert_eq!(prewrites.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_extend_mutations() {
+        let logger = Logger::root(slog::Discard, o!());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let requests_cloned = requests.clone();
+        let pd_client = Arc::new(MockPdClient::new(MockKvClient::with_dispatch_hook(
+            move |req: &dyn Any| {
+                let mut requests = requests_cloned.lock().unwrap();
+                if let Some(req) = req.downcast_ref::<kvrpcpb::PessimisticLockRequest>() {
+                    let keys = req.mutations.iter().map(|m| m.key.clone()).collect();
+                    requests.push(("lock", keys));
+                    let resp = kvrpcpb::PessimisticLockResponse::default();
+                    Ok(Box::new(resp) as Box<dyn Any>)
+                } else if let Some(req) = req.downcast_ref::<kvrpcpb::PrewriteRequest>() {
+                    let keys = req.mutations.iter().map(|m| m.key.clone()).collect();
+                    requests.push(("prewrite", keys));
+                    Ok(Box::new(kvrpcpb::PrewriteResponse::default()) as Box<dyn Any>)
+                } else {
+                    empty_response(req)
+                }
+            },
+        )));
+        let options = TransactionOptions::new_pessimistic()
+            .heartbeat_option(HeartbeatOption::NoHeartbeat)
+            .drop_check(CheckLevel::None);
+        let mut txn = Transaction::new(Timestamp::default(), pd_client, options, logger);
+        let key = |k: u8| Key::from(vec![k]);
+        let mut delete = kvrpcpb::Mutation::default();
+        delete.set_op(kvrpcpb::Op::Del);
+        delete.set_key(vec![4]);
+        let mutations = vec![
+            Mutation::Put(key(1), vec![1]),
+            Mutation::Insert(key(2), vec![2]),
+            Mutation::Lock(key(3)),
+            Mutation::try_from(delete).unwrap(),
+        ];
+        txn.extend_mutations(mutations.clone()).await.unwrap();
+        // all keys are locked by a single request
+        assert_eq!(
+            *requests.lock().unwrap(),
+            vec![("lock", vec![vec![1], vec![2], vec![3], vec![4]])]
+        );
+        assert_eq!(txn.buffered_mutations(), mutations);
+
+        // nothing is buffered if a mutation is rejected
+        let duplicate = vec![
+            Mutation::Put(key(5), vec![5]),
+            Mutation::Insert(key(2), vec![2]),
+        ];
+        assert!(matches!(
+            txn.extend_mutations(duplicate).await,
+            Err(Error::DuplicateKeyInsertion)
+        ));
+        assert_eq!(txn.buffered_mutations(), mutations);
+        let mut rollback = kvrpcpb::Mutation::default();
+        rollback.set_op(kvrpcpb::Op::Rollback);
+        assert!(matches!(
+            Mutation::try_from(rollback),
+            Err(Error::UnsupportedMutation { .. })
+        ));
+
+        txn.commit().await.unwrap();
+        assert_eq!(
+            requests.lock().unwrap()[1],
+            ("prewrite", vec![vec![1], vec![2], vec![3], vec![4]])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_lock_absent_keys() {
+        let logger = Logger::root(slog::Discard, o!());
+        let pd_client = Arc::new(MockPdClient::new(MockKvClient::with_dispatch_hook(
+            move |req: &dyn Any| {
+                if let Some(req) = req.downcast_ref::<kvrpcpb::PessimisticLockRequest>() {
+                    assert!(req
+                        .mutations
+                        .iter()
+                        .all(|m| m.get_assertion() == kvrpcpb::Assertion::NotExist));
+                    // the key [2] exists
+                    let errors = req
+                        .mutations
+                        .iter()
+                        .filter(|m| m.key == vec![2])
+                        .map(|m| kvrpcpb::KeyError {
+                            already_exist: Some(kvrpcpb::AlreadyExist { key: m.key.clone() }),
+                            ..Default::default()
+                        })
+                        .collect();
+                    let resp = kvrpcpb::PessimisticLockResponse {
+                        errors,
+                        ..Default::default()
+                    };
+                    Ok(Box::new(resp) as Box<dyn Any>)
+                } else if let Some(req) = req.downcast_ref::<kvrpcpb::PrewriteRequest>() {
+                    let mutation = &req.mutations[0];
+                    assert_eq!(mutation.get_op(), kvrpcpb::Op::Lock);
+                    assert_eq!(mutation.get_assertion(), kvrpcpb::Assertion::NotExist);
+                    Ok(Box::new(kvrpcpb::PrewriteResponse::default()) as Box<dyn Any>)
+                } else {
+                    empty_response(req)
+                }
+            },
+        )));
+
+        let mut txn = Transaction::new(
+            Timestamp::default(),
+            pd_client.clone(),
+            TransactionOptions::new_pessimistic()
+                .heartbeat_option(HeartbeatOption::NoHeartbeat)
+                .drop_check(CheckLevel::None),
+            logger.clone(),
+        );
+        txn.lock_absent_keys(vec![vec![1]]).await.unwrap();
+        assert_eq!(txn.locked_keys(), vec![Key::from(vec![1])]);
+        match txn.lock_absent_keys(vec![vec![2]]).await {
+            Err(Error::KeyAlreadyExists { key }) => assert_eq!(key, vec![2]),
+            res => panic!("unexpected result: {:?}", res),
+        }
+
+        // optimistic transactions lock the keys when they commit
+        let mut txn = Transaction::new(
+            Timestamp::default(),
+            pd_client,
+            TransactionOptions::new_optimistic().heartbeat_option(HeartbeatOption::NoHeartbeat),
+            logger,
+        );
+        txn.lock_absent_keys(vec![vec![1]]).await.unwrap();
+        // keys written by the transaction exist without asking TiKV
+        txn.put(vec![3], vec![3]).await.unwrap();
+        match txn.lock_absent_keys(vec![vec![3]]).await {
+            Err(Error::KeyAlreadyExists { key }) => assert_eq!(key, vec![3]),
+            res => panic!("unexpected result: {:?}", res),
+        }
+        txn.commit().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_max_lifetime() {
+        let logger = Logger::root(slog::Discard, o!());
+        let rolled_back = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let rolled_back_cloned = rolled_back.clone();
+        let mut pd_client =
+            MockPdClient::new(MockKvClient::with_dispatch_hook(move |req: &dyn Any| {
+                if let Some(req) = req.downcast_ref::<kvrpcpb::PessimisticRollbackRequest>() {
+                    rolled_back_cloned
+                        .lock()
+                        .unwrap()
+                        .extend(req.keys.iter().cloned());
+                    Ok(Box::new(kvrpcpb::PessimisticRollbackResponse::default()) as Box<dyn Any>)
+                } else {
+                    empty_response(req)
+                }
+            }));
+        let clock = MockClock::new();
+        pd_client.clock = ClockHandle::new(clock.clone());
+        let mut txn = Transaction::new(
+            Timestamp::default(),
+            Arc::new(pd_client),
+            TransactionOptions::new_pessimistic()
+                .heartbeat_option(HeartbeatOption::NoHeartbeat)
+                .max_lifetime(Duration::from_secs(60)),
+            logger,
+        );
+        txn.put(vec![1], vec![1]).await.unwrap();
+        txn.lock_keys(vec![vec![2]]).await.unwrap();
+
+        clock.advance(Duration::from_secs(30));
+        txn.put(vec![3], vec![3]).await.unwrap();
+        assert!(rolled_back.lock().unwrap().is_empty());
+
+        // the locks are rolled back without any operation on the transaction
+        clock.advance(Duration::from_secs(30));
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while rolled_back.lock().unwrap().is_empty() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("the locks are not rolled back");
+        assert_eq!(
+            *rolled_back.lock().unwrap(),
+            vec![vec![1], vec![2], vec![3]]
+        );
+
+        assert!(matches!(
+            txn.put(vec![4], vec![4]).await,
+            Err(Error::TransactionExpired { max_lifetime }) if max_lifetime.as_secs() == 60
+        ));
+        assert!(matches!(
+            txn.commit().await,
+            Err(Error::TransactionExpired { .. })
+        ));
+        txn.rollback().await.unwrap();
+        assert_eq!(rolled_back.lock().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_max_lifetime_rollback_retries() {
+        let logger = Logger::root(slog::Discard, o!());
+        let rollbacks = Arc::new(AtomicUsize::new(0));
+        let rollbacks_cloned = rollbacks.clone();
+        let mut pd_client =
+            MockPdClient::new(MockKvClient::with_dispatch_hook(move |req: &dyn Any| {
+                if req.is::<kvrpcpb::PessimisticRollbackRequest>() {
+                    // the rollback of the expired transaction fails once
+                    if rollbacks_cloned.fetch_add(1, Ordering::SeqCst) == 0 {
+                        Err(Error::Unimplemented)
+                    } else {
+                        Ok(Box::new(kvrpcpb::PessimisticRollbackResponse::default())
+                            as Box<dyn Any>)
+                    }
+                } else {
+                    empty_response(req)
+                }
+            }));
+        let clock = MockClock::new();
+        pd_client.clock = ClockHandle::new(clock.clone());
+        let mut txn = Transaction::new(
+            Timestamp::default(),
+            Arc::new(pd_client),
+            TransactionOptions::new_pessimistic()
+                .heartbeat_option(HeartbeatOption::NoHeartbeat)
+                .max_lifetime(Duration::from_secs(60)),
+            logger,
+        );
+        txn.lock_keys(vec![vec![1]]).await.unwrap();
+
+        clock.advance(Duration::from_secs(60));
+        assert!(matches!(
+            txn.put(vec![2], vec![2]).await,
+            Err(Error::TransactionExpired { .. })
+        ));
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while rollbacks.load(Ordering::SeqCst) == 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("the locks are not rolled back");
 
-        pairs
+        // the rollback retries, and is done afterwards
+        txn.rollback().await.unwrap();
+        assert_eq!(rollbacks.load(Ordering::SeqCst), 2);
+        txn.rollback().await.unwrap();
+        assert_eq!(rollbacks.load(Ordering::SeqCst), 2);
     }
 
-    /// Checks if the transaction can perform arbitrary operations.
-    async fn check_allow_operation(&self) -> Result<()> {
-        let status = self.status.read().await;
-        match *status {
-            TransactionStatus::ReadOnly | TransactionStatus::Active => Ok(()),
-            TransactionStatus::Committed
-            | TransactionStatus::Rolledback
-            | TransactionStatus::StartedCommit
-            | TransactionStatus::StartedRollback
-            | TransactionStatus::Dropped => Err(Error::OperationAfterCommitError),
-        }
+    #[tokio::test]
+    async fn test_max_lifetime_task_aborted() {
+        let logger = Logger::root(slog::Discard, o!());
+        let pd_client = Arc::new(MockPdClient::new(MockKvClient::with_dispatch_hook(
+            empty_response,
+        )));
+        let mut txn = Transaction::new(
+            Timestamp::default(),
+            pd_client,
+            TransactionOptions::new_pessimistic()
+                .heartbeat_option(HeartbeatOption::NoHeartbeat)
+                .max_lifetime(Duration::from_secs(3600)),
+            logger,
+        );
+        txn.lock_keys(vec![vec![1]]).await.unwrap();
+        assert!(txn.expiry_task.is_some());
+        txn.rollback().await.unwrap();
+        assert!(txn.expiry_task.is_none());
     }
 
-    fn is_pessimistic(&self) -> bool {
-        matches!(self.options.kind, TransactionKind::Pessimistic(_))
+    #[tokio::test]
+    async fn test_read_cache() {
+        let logger = Logger::root(slog::Discard, o!());
+        let gets = Arc::new(AtomicUsize::new(0));
+        let gets_cloned = gets.clone();
+        let pd_client = Arc::new(MockPdClient::new(MockKvClient::with_dispatch_hook(
+            move |req: &dyn Any| {
+                assert!(req.downcast_ref::<kvrpcpb::GetRequest>().is_some());
+                gets_cloned.fetch_add(1, Ordering::SeqCst);
+                let mut resp = kvrpcpb::GetResponse::default();
+                resp.set_value(b"foo".to_vec());
+                Ok(Box::new(resp) as Box<dyn Any>)
+            },
+        )));
+        let cache = ReadCache::new(16);
+        let options = TransactionOptions::new_optimistic()
+            .read_only()
+            .read_cache(cache.clone());
+
+        let mut txn1 = Transaction::new(
+            Timestamp::default(),
+            pd_client.clone(),
+            options.clone(),
+            logger.clone(),
+        );
+        assert_eq!(
+            txn1.get("key1".to_owned()).await.unwrap(),
+            Some(b"foo".to_vec())
+        );
+        assert_eq!(gets.load(Ordering::SeqCst), 1);
+
+        // another transaction at the same timestamp reads from the cache
+        let mut txn2 = Transaction::new(Timestamp::default(), pd_client, options, logger);
+        assert_eq!(
+            txn2.get("key1".to_owned()).await.unwrap(),
+            Some(b"foo".to_vec())
+        );
+        assert_eq!(gets.load(Ordering::SeqCst), 1);
+        assert_eq!(cache.len(), 1);
     }
 
-    async fn start_auto_heartbeat(&mut self) {
-        debug!(self.logger, "starting auto_heartbeat");
-        if !self.options.heartbeat_option.is_auto_heartbeat() || self.is_heartbeat_started {
-            return;
-        }
-        self.is_heartbeat_started = true;
+    #[tokio::test]
+    async fn test_scan_cache() {
+        let logger = Logger::root(slog::Discard, o!());
+        let scans = Arc::new(AtomicUsize::new(0));
+        let scans_cloned = scans.clone();
+        let pd_client = Arc::new(MockPdClient::new(MockKvClient::with_dispatch_hook(
+            move |req: &dyn Any| {
+                let req: &kvrpcpb::ScanRequest = req.downcast_ref().unwrap();
+                scans_cloned.fetch_add(1, Ordering::SeqCst);
+                let pairs = vec![b"k1".to_vec(), b"k2".to_vec()]
+                    .into_iter()
+                    .filter(|key| key >= &req.start_key && key < &req.end_key)
+                    .map(|key| kvrpcpb::KvPair {
+                        key,
+                        value: b"v".to_vec(),
+                        ..Default::default()
+                    })
+                    .collect();
+                let resp = kvrpcpb::ScanResponse {
+                    pairs,
+                    ..Default::default()
+                };
+                Ok(Box::new(resp) as Box<dyn Any>)
+            },
+        )));
+        let cache = ScanCache::new(1024);
+        let options = TransactionOptions::new_optimistic()
+            .heartbeat_option(HeartbeatOption::NoHeartbeat)
+            .drop_check(CheckLevel::None)
+            .scan_cache(cache.clone());
 
-        let status = self.status.clone();
-        let primary_key = self
-            .buffer
-            .get_primary_key()
-            .expect("Primary key should exist");
-        let start_ts = self.timestamp.clone();
-        let region_backoff = self.options.retry_options.region_backoff.clone();
-        let rpc = self.rpc.clone();
-        let heartbeat_interval = match self.options.heartbeat_option {
-            HeartbeatOption::NoHeartbeat => DEFAULT_HEARTBEAT_INTERVAL,
-            HeartbeatOption::FixedTime(heartbeat_interval) => heartbeat_interval,
-        };
-        let start_instant = self.start_instant;
+        let mut txn1 = Transaction::new(
+            Timestamp::default(),
+            pd_client.clone(),
+            options.clone(),
+            logger.clone(),
+        );
+        let pairs = txn1.scan(b"k".to_vec()..b"l".to_vec(), 10).await.unwrap();
+        assert_eq!(pairs.count(), 2);
+        assert_eq!(scans.load(Ordering::SeqCst), 1);
 
-        let heartbeat_task = async move {
-            loop {
-                tokio::time::sleep(heartbeat_interval).await;
-                {
-                    let status = status.read().await;
-                    if matches!(
-                        *status,
-                        TransactionStatus::Rolledback
-                            | TransactionStatus::Committed
-                            | TransactionStatus::Dropped
-                    ) {
-                        break;
-                    }
-                }
-                let request = new_heart_beat_request(
-                    start_ts.clone(),
-                    primary_key.clone(),
-                    start_instant.elapsed().as_millis() as u64 + DEFAULT_LOCK_TTL,
-                );
-                let plan = PlanBuilder::new(rpc.clone(), request)
-                    .retry_multi_region(region_backoff.clone())
-                    .merge(CollectSingle)
-                    .plan();
-                plan.execute().await?;
-            }
-            Ok::<(), Error>(())
-        };
+        // another transaction at the same timestamp reads from the cache
+        let mut txn2 = Transaction::new(Timestamp::default(), pd_client, options, logger);
+        let pairs = txn2.scan(b"k".to_vec()..b"l".to_vec(), 10).await.unwrap();
+        let keys: Vec<Key> = pairs.map(KvPair::into_key).collect();
+        assert_eq!(keys, vec![b"k1".to_vec().into(), b"k2".to_vec().into()]);
+        assert_eq!(scans.load(Ordering::SeqCst), 1);
 
-        tokio::spawn(async {
-            if let Err(err) = heartbeat_task.await {
-                log::error!("Error: While sending heartbeat. {}", err);
-            }
-        });
+        // a write to the range invalidates the cached scan
+        txn2.put(b"k3".to_vec(), b"v".to_vec()).await.unwrap();
+        assert!(cache.is_empty());
+        let pairs = txn2.scan(b"k".to_vec()..b"l".to_vec(), 10).await.unwrap();
+        assert_eq!(pairs.count(), 3);
+        assert_eq!(scans.load(Ordering::SeqCst), 2);
     }
-}
 
-impl<PdC: PdClient> Drop for Transaction<PdC> {
-    fn drop(&mut self) {
-        debug!(self.logger, "dropping transaction");
-        if std::thread::panicking() {
-            return;
-        }
-        let mut status = futures::executor::block_on(self.status.write());
-        if *status == TransactionStatus::Active {
-            match self.options.check_level {
-                CheckLevel::Panic => {
-                    panic!("Dropping an active transaction. Consider commit or rollback it.")
-                }
-                CheckLevel::Warn => warn!(
-                    self.logger,
-                    "Dropping an active transaction. Consider commit or rollback it."
-                ),
+    #[tokio::test]
+    async fn test_lock_policy() {
+        let logger = Logger::root(slog::Discard, o!());
+        let pd_client = Arc::new(MockPdClient::new(MockKvClient::with_dispatch_hook(
+            |req: &dyn Any| {
+                let req: &kvrpcpb::BatchGetRequest = req.downcast_ref().unwrap();
+                let pairs = req
+                    .keys
+                    .iter()
+                    .map(|key| {
+                        let mut pair = kvrpcpb::KvPair::default();
+                        pair.set_key(key.clone());
+                        if key == b"locked" {
+                            let mut error = kvrpcpb::KeyError::default();
+                            error.set_locked(kvrpcpb::LockInfo {
+                                key: key.clone(),
+                                lock_version: 7,
+                                ..Default::default()
+                            });
+                            pair.set_error(error);
+                        } else {
+                            pair.set_value(b"foo".to_vec());
+                        }
+                        pair
+                    })
+                    .collect();
+                let resp = kvrpcpb::BatchGetResponse {
+                    pairs,
+                    ..Default::default()
+                };
+                Ok(Box::new(resp) as Box<dyn Any>)
+            },
+        )));
+        let keys = vec!["free".to_owned(), "locked".to_owned()];
 
-                CheckLevel::None => {}
+        let options = TransactionOptions::new_optimistic()
+            .read_only()
+            .read_options(ReadOptions::new().lock_policy(LockPolicy::SkipLocked));
+        let mut txn = Transaction::new(
+            Timestamp::default(),
+            pd_client.clone(),
+            options,
+            logger.clone(),
+        );
+        let pairs: Vec<KvPair> = txn.batch_get(keys.clone()).await.unwrap().collect();
+        assert_eq!(pairs, vec![KvPair::new("free".to_owned(), b"foo".to_vec())]);
+        assert_eq!(txn.get("locked".to_owned()).await.unwrap(), None);
+
+        let options = TransactionOptions::new_optimistic()
+            .read_only()
+            .read_options(ReadOptions::new().lock_policy(LockPolicy::FailFast));
+        let mut txn = Transaction::new(Timestamp::default(), pd_client, options, logger);
+        match txn.batch_get(keys).await {
+            Err(e) if matches!(e.without_request(), Error::KeyError(_)) => {
+                let lock = e.lock().unwrap();
+                assert_eq!(lock.key, b"locked");
+                assert_eq!(lock.start_ts, 7);
             }
+            Err(e) => panic!("unexpected error: {:?}", e),
+            Ok(_) => panic!("locked key is not reported"),
         }
-        *status = TransactionStatus::Dropped;
     }
-}
 
-/// The default max TTL of a lock in milliseconds. Also called `ManagedLockTTL` in TiDB.
-const MAX_TTL: u64 = 20000;
-/// The default TTL of a lock in milliseconds.
-const DEFAULT_LOCK_TTL: u64 = 3000;
-/// The default heartbeat interval
-const DEFAULT_HEARTBEAT_INTERVAL: Duration = Duration::from_millis(MAX_TTL / 2);
-/// TiKV recommends each RPC packet should be less than around 1MB. We keep KV size of
-/// each request below 16KB.
-const TXN_COMMIT_BATCH_SIZE: u64 = 16 * 1024;
-const TTL_FACTOR: f64 = 6000.0;
+    #[tokio::test]
+    async fn test_pessimistic_lock_retry() {
+        let logger = Logger::root(slog::Discard, o!());
+        let locks = Arc::new(AtomicUsize::new(0));
+        let locks_cloned = locks.clone();
+        let pd_client = Arc::new(MockPdClient::new(MockKvClient::with_dispatch_hook(
+            move |req: &dyn Any| {
+                assert!(req
+                    .downcast_ref::<kvrpcpb::PessimisticLockRequest>()
+                    .is_some());
+                let mut resp = kvrpcpb::PessimisticLockResponse::default();
+                // every other lock conflicts
+                if locks_cloned
+                    .fetch_add(1, Ordering::SeqCst)
+                    .is_multiple_of(2)
+                {
+                    let mut error = kvrpcpb::KeyError::default();
+                    error.set_conflict(kvrpcpb::WriteConflict::default());
+                    resp.errors.push(error);
+                }
+                Ok(Box::new(resp) as Box<dyn Any>)
+            },
+        )));
+        let options = TransactionOptions::new_pessimistic()
+            .heartbeat_option(HeartbeatOption::NoHeartbeat)
+            .drop_check(CheckLevel::None);
 
-/// Optimistic or pessimistic transaction.
-#[derive(Clone, PartialEq, Debug)]
-pub enum TransactionKind {
-    Optimistic,
-    /// Argument is the transaction's for_update_ts
-    Pessimistic(Timestamp),
-}
+        let mut txn = Transaction::new(
+            Timestamp::default(),
+            pd_client.clone(),
+            options.clone(),
+            logger.clone(),
+        );
+        txn.lock_keys(vec!["key1".to_owned()]).await.unwrap();
+        assert_eq!(locks.swap(0, Ordering::SeqCst), 2);
 
-/// Options for configuring a transaction.
-///
-/// `TransactionOptions` has a builder-style API.
-#[derive(Clone, PartialEq, Debug)]
-pub struct TransactionOptions {
-    /// Optimistic or pessimistic (default) transaction.
-    kind: TransactionKind,
-    /// Try using 1pc rather than 2pc (default is to always use 2pc).
-    try_one_pc: bool,
-    /// Try to use async commit (default is not to).
-    async_commit: bool,
-    /// Is the transaction read only? (Default is no).
-    read_only: bool,
-    /// How to retry in the event of certain errors.
-    retry_options: RetryOptions,
-    /// What to do if the transaction is dropped without an attempt to commit or rollback
-    check_level: CheckLevel,
-    #[doc(hidden)]
-    heartbeat_option: HeartbeatOption,
-}
+        let mut txn = Transaction::new(
+            Timestamp::default(),
+            pd_client,
+            options.pessimistic_lock_retries(0),
+            logger,
+        );
+        assert!(txn.lock_keys(vec!["key1".to_owned()]).await.is_err());
+        assert_eq!(locks.load(Ordering::SeqCst), 1);
+    }
 
-#[derive(Clone, PartialEq, Debug)]
-pub enum HeartbeatOption {
-    NoHeartbeat,
-    FixedTime(Duration),
-}
+    #[test]
+    fn test_inherit_metrics_labels() {
+        let client = MetricsLabels::new().with("tenant", "a").with("app", "x");
+        let options = TransactionOptions::new_optimistic()
+            .metrics_labels(MetricsLabels::new().with("tenant", "b"))
+            .inherit_metrics_labels(&client);
+        assert_eq!(options.metrics_labels.to_string(), "app=x,tenant=b");
 
-impl Default for TransactionOptions {
-    fn default() -> TransactionOptions {
-        Self::new_pessimistic()
+        let options = TransactionOptions::new_optimistic().inherit_metrics_labels(&client);
+        assert_eq!(options.metrics_labels, client);
     }
-}
 
-impl TransactionOptions {
-    /// Default options for an optimistic transaction.
-    pub fn new_optimistic() -> TransactionOptions {
-        TransactionOptions {
-            kind: TransactionKind::Optimistic,
-            try_one_pc: false,
-            async_commit: false,
-            read_only: false,
-            retry_options: RetryOptions::default_optimistic(),
-            check_level: CheckLevel::Panic,
-            heartbeat_option: HeartbeatOption::FixedTime(DEFAULT_HEARTBEAT_INTERVAL),
-        }
-    }
+    #[test]
+    fn test_inherit_request_tags() {
+        let options = TransactionOptions::new_optimistic()
+            .priority(CommandPriority::High)
+            .inherit_request_tags(CommandPriority::Low, b"client");
+        assert_eq!(options.priority, CommandPriority::High);
+        assert_eq!(options.resource_group_tag, b"client");
 
-    /// Default options for a pessimistic transaction.
-    pub fn new_pessimistic() -> TransactionOptions {
-        TransactionOptions {
-            kind: TransactionKind::Pessimistic(Timestamp::from_version(0)),
-            try_one_pc: false,
-            async_commit: false,
-            read_only: false,
-            retry_options: RetryOptions::default_pessimistic(),
-            check_level: CheckLevel::Panic,
-            heartbeat_option: HeartbeatOption::FixedTime(DEFAULT_HEARTBEAT_INTERVAL),
-        }
+        let options = TransactionOptions::new_optimistic()
+            .resource_group_tag("txn")
+            .inherit_request_tags(CommandPriority::Low, b"client");
+        assert_eq!(options.priority, CommandPriority::Low);
+        assert_eq!(options.resource_group_tag, b"txn");
     }
 
-    /// Try to use async commit.
-    pub fn use_async_commit(mut self) -> TransactionOptions {
-        self.async_commit = true;
-        self
+    #[tokio::test]
+    async fn test_request_tags() {
+        let logger = Logger::root(slog::Discard, o!());
+        let requests = Arc::new(AtomicUsize::new(0));
+        let requests_cloned = requests.clone();
+        let pd_client = Arc::new(MockPdClient::new(MockKvClient::with_dispatch_hook(
+            move |req: &dyn Any| {
+                requests_cloned.fetch_add(1, Ordering::SeqCst);
+                let context = if let Some(req) = req.downcast_ref::<kvrpcpb::GetRequest>() {
+                    req.get_context()
+                } else if let Some(req) = req.downcast_ref::<kvrpcpb::PrewriteRequest>() {
+                    req.get_context()
+                } else if let Some(req) = req.downcast_ref::<kvrpcpb::CommitRequest>() {
+                    req.get_context()
+                } else {
+                    panic!("unexpected request")
+                };
+                assert_eq!(context.get_priority(), kvrpcpb::CommandPri::Low);
+                assert_eq!(context.resource_group_tag, b"analytics");
+                if req.is::<kvrpcpb::GetRequest>() {
+                    let resp = kvrpcpb::GetResponse {
+                        not_found: true,
+                        ..Default::default()
+                    };
+                    Ok(Box::new(resp) as Box<dyn Any>)
+                } else {
+                    empty_response(req)
+                }
+            },
+        )));
+        let mut txn = Transaction::new(
+            Timestamp::from_version(5),
+            pd_client,
+            TransactionOptions::new_optimistic()
+                .priority(CommandPriority::Low)
+                .resource_group_tag("analytics")
+                .heartbeat_option(HeartbeatOption::NoHeartbeat),
+            logger,
+        );
+        assert_eq!(txn.get(vec![1]).await.unwrap(), None);
+        txn.put(vec![2], vec![2]).await.unwrap();
+        txn.commit().await.unwrap();
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
     }
 
-    /// Try to use 1pc.
-    pub fn try_one_pc(mut self) -> TransactionOptions {
-        self.try_one_pc = true;
-        self
-    }
+    #[test]
+    fn test_options_supported_by() {
+        let options = TransactionOptions::new_optimistic()
+            .use_async_commit()
+            .try_one_pc();
+        assert_eq!(
+            options.clone().supported_by(&ClusterInfo::default()),
+            options
+        );
 
-    /// Make the transaction read only.
-    pub fn read_only(mut self) -> TransactionOptions {
-        self.read_only = true;
-        self
+        let mut old = ClusterInfo::default();
+        old.tikv_versions.insert(1, semver::Version::new(4, 0, 16));
+        let supported = options.supported_by(&old);
+        assert!(!supported.async_commit);
+        assert!(!supported.try_one_pc);
     }
 
-    /// Don't automatically resolve locks and retry if keys are locked.
-    pub fn no_resolve_locks(mut self) -> TransactionOptions {
-        self.retry_options.lock_backoff = Backoff::no_backoff();
-        self
+    #[tokio::test]
+    async fn test_async_commit() {
+        async fn commit(min_commit_ts: [u64; 2]) -> Vec<(Vec<Vec<u8>>, u64)> {
+            let logger = Logger::root(slog::Discard, o!());
+            let commits = Arc::new(std::sync::Mutex::new(Vec::new()));
+            let commits_cloned = commits.clone();
+            let pd_client = Arc::new(MockPdClient::new(MockKvClient::with_dispatch_hook(
+                move |req: &dyn Any| {
+                    if let Some(req) = req.downcast_ref::<kvrpcpb::PrewriteRequest>() {
+                        assert!(req.use_async_commit);
+                        assert_eq!(req.min_commit_ts, 6);
+                        assert!(req.max_commit_ts > req.min_commit_ts);
+                        // the keys are in different regions
+                        let region = (req.mutations[0].key[0] >= 10) as usize;
+                        let resp = kvrpcpb::PrewriteResponse {
+                            min_commit_ts: min_commit_ts[region],
+                            ..Default::default()
+                        };
+                        Ok(Box::new(resp) as Box<dyn Any>)
+                    } else if let Some(req) = req.downcast_ref::<kvrpcpb::CommitRequest>() {
+                        commits_cloned
+                            .lock()
+                            .unwrap()
+                            .push((req.keys.clone(), req.commit_version));
+                        Ok(Box::new(kvrpcpb::CommitResponse::default()) as Box<dyn Any>)
+                    } else {
+                        empty_response(req)
+                    }
+                },
+            )));
+            let mut txn = Transaction::new(
+                Timestamp::from_version(5),
+                pd_client,
+                TransactionOptions::new_optimistic()
+                    .use_async_commit()
+                    .heartbeat_option(HeartbeatOption::NoHeartbeat),
+                logger,
+            );
+            txn.put(vec![1], vec![1]).await.unwrap();
+            txn.put(vec![20], vec![20]).await.unwrap();
+            let commit_ts = txn.commit().await.unwrap().unwrap();
+            // wait for the secondary keys to be committed in the background
+            tokio::time::timeout(Duration::from_secs(10), async {
+                while commits.lock().unwrap().len() < 2 {
+                    tokio::task::yield_now().await;
+                }
+            })
+            .await
+            .expect("the secondary keys are not committed");
+            let commits = commits.lock().unwrap().clone();
+            assert!(commits.iter().all(|(_, ts)| *ts == commit_ts.version()));
+            commits
+        }
+
+        // committed with the largest min_commit_ts, without committing the primary key first
+        let mut commits = commit([7, 9]).await;
+        commits.sort();
+        assert_eq!(commits, vec![(vec![vec![1]], 9), (vec![vec![20]], 9)]);
+
+        // the second region falls back to two-phase commit, so the primary key is committed first
+        // with a timestamp from PD
+        let commits = commit([7, 0]).await;
+        assert_eq!(commits, vec![(vec![vec![1]], 0), (vec![vec![20]], 0)]);
     }
 
-    /// Don't automatically resolve regions with PD if we have outdated region information.
-    pub fn no_resolve_regions(mut self) -> TransactionOptions {
-        self.retry_options.region_backoff = Backoff::no_backoff();
-        self
+    #[tokio::test]
+    async fn test_external_consistency() {
+        let logger = Logger::root(slog::Discard, o!());
+        let commits = Arc::new(AtomicUsize::new(0));
+        let commits_cloned = commits.clone();
+        let pd_client = Arc::new(MockPdClient::new(MockKvClient::with_dispatch_hook(
+            move |req: &dyn Any| {
+                if req.downcast_ref::<kvrpcpb::CommitRequest>().is_some() {
+                    commits_cloned.fetch_add(1, Ordering::SeqCst);
+                    Ok(Box::new(kvrpcpb::CommitResponse::default()) as Box<dyn Any>)
+                } else {
+                    empty_response(req)
+                }
+            },
+        )));
+        let begin = |options: TransactionOptions| {
+            Transaction::new(
+                Timestamp::from_version(5),
+                pd_client.clone(),
+                options
+                    .heartbeat_option(HeartbeatOption::NoHeartbeat)
+                    .drop_check(CheckLevel::None),
+                logger.clone(),
+            )
+        };
+
+        // without the option, the transaction commits at the timestamp of PD, which is always 0
+        let mut txn = begin(TransactionOptions::new_optimistic());
+        txn.put(vec![1], vec![1]).await.unwrap();
+        assert_eq!(txn.commit_ts(), None);
+        let commit_ts = txn.commit().await.unwrap();
+        assert_eq!(txn.commit_ts(), commit_ts);
+        assert_eq!(txn.commit_ts().unwrap().version(), 0);
+        assert_eq!(commits.swap(0, Ordering::SeqCst), 1);
+
+        // a commit timestamp before the start of the transaction is rejected before the primary
+        // key is committed
+        let mut txn = begin(TransactionOptions::new_optimistic().external_consistency());
+        txn.put(vec![1], vec![1]).await.unwrap();
+        assert!(matches!(
+            txn.commit().await,
+            Err(Error::InvalidCommitTs {
+                start_ts: 5,
+                commit_ts: 0,
+                min_commit_ts: 6,
+            })
+        ));
+        assert_eq!(txn.commit_ts(), None);
+        assert_eq!(commits.load(Ordering::SeqCst), 0);
+
+        // a transaction started before the GC safe point is rejected before it is prewritten
+        pd_client.safepoint.store(6, Ordering::SeqCst);
+        let mut txn = begin(TransactionOptions::new_optimistic().external_consistency());
+        txn.put(vec![1], vec![1]).await.unwrap();
+        assert!(matches!(
+            txn.commit().await,
+            Err(Error::TxnTooOld {
+                start_ts: 5,
+                safe_point: 6,
+            })
+        ));
     }
 
-    /// Set RetryOptions.
-    pub fn retry_options(mut self, options: RetryOptions) -> TransactionOptions {
-        self.retry_options = options;
-        self
-    }
+    #[tokio::test]
+    async fn test_update() {
+        let logger = Logger::root(slog::Discard, o!());
+        let mutations = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mutations_cloned = mutations.clone();
+        let pd_client = Arc::new(MockPdClient::new(MockKvClient::with_dispatch_hook(
+            move |req: &dyn Any| {
+                if let Some(req) = req.downcast_ref::<kvrpcpb::BatchGetRequest>() {
+                    // only keys 1 and 2 exist
+                    let pairs = req
+                        .keys
+                        .iter()
+                        .filter(|key| key[0] < 3)
+                        .map(|key| {
+                            let mut pair = kvrpcpb::KvPair::default();
+                            pair.set_key(key.clone());
+                            pair.set_value(vec![key[0] * 10]);
+                            pair
+                        })
+                        .collect();
+                    let resp = kvrpcpb::BatchGetResponse {
+                        pairs,
+                        ..Default::default()
+                    };
+                    Ok(Box::new(resp) as Box<dyn Any>)
+                } else if let Some(req) = req.downcast_ref::<kvrpcpb::PrewriteRequest>() {
+                    mutations_cloned
+                        .lock()
+                        .unwrap()
+                        .extend(req.mutations.iter().cloned());
+                    Ok(Box::new(kvrpcpb::PrewriteResponse::default()) as Box<dyn Any>)
+                } else {
+                    empty_response(req)
+                }
+            },
+        )));
+        let mut txn = Transaction::new(
+            Timestamp::default(),
+            pd_client,
+            TransactionOptions::new_optimistic().heartbeat_option(HeartbeatOption::NoHeartbeat),
+            logger,
+        );
+        txn.update(vec![vec![1], vec![2], vec![3]], |values| {
+            assert_eq!(values, vec![Some(vec![10]), Some(vec![20]), None]);
+            vec![None, values[0].clone(), Some(vec![30])]
+        })
+        .await
+        .unwrap();
+        txn.commit().await.unwrap();
 
-    /// Set the behavior when dropping a transaction without an attempt to commit or rollback it.
-    pub fn drop_check(mut self, level: CheckLevel) -> TransactionOptions {
-        self.check_level = level;
-        self
+        let mut mutations = mutations.lock().unwrap().clone();
+        mutations.sort_by(|a, b| a.key.cmp(&b.key));
+        let mutations: Vec<_> = mutations
+            .into_iter()
+            .map(|m| (m.get_op(), m.key, m.value))
+            .collect();
+        assert_eq!(
+            mutations,
+            vec![
+                (kvrpcpb::Op::Del, vec![1], vec![]),
+                (kvrpcpb::Op::Put, vec![2], vec![10]),
+                (kvrpcpb::Op::Put, vec![3], vec![30]),
+            ]
+        );
     }
 
-    fn push_for_update_ts(&mut self, for_update_ts: Timestamp) {
-        match &mut self.kind {
-            TransactionKind::Optimistic => unreachable!(),
-            TransactionKind::Pessimistic(old_for_update_ts) => {
-                self.kind = TransactionKind::Pessimistic(Timestamp::from_version(std::cmp::max(
-                    old_for_update_ts.version(),
-                    for_update_ts.version(),
-                )));
+    #[tokio::test]
+    async fn test_record_operations() {
+        let logger = Logger::root(slog::Discard, o!());
+        let pd_client = Arc::new(MockPdClient::new(MockKvClient::with_dispatch_hook(
+            move |req: &dyn Any| {
+                if req.is::<kvrpcpb::GetRequest>() {
+                    let resp = kvrpcpb::GetResponse {
+                        not_found: true,
+                        ..Default::default()
+                    };
+                    Ok(Box::new(resp) as Box<dyn Any>)
+                } else if req.is::<kvrpcpb::PrewriteRequest>() {
+                    let resp = kvrpcpb::PrewriteResponse {
+                        errors: vec![kvrpcpb::KeyError {
+                            abort: "aborted".to_owned(),
+                            ..Default::default()
+                        }],
+                        ..Default::default()
+                    };
+                    Ok(Box::new(resp) as Box<dyn Any>)
+                } else {
+                    empty_response(req)
+                }
+            },
+        )));
+        let mut txn = Transaction::new(
+            Timestamp::default(),
+            pd_client,
+            TransactionOptions::new_optimistic()
+                .record_operations()
+                .heartbeat_option(HeartbeatOption::NoHeartbeat),
+            logger,
+        );
+        txn.get(vec![1]).await.unwrap();
+        txn.put(vec![1], vec![1]).await.unwrap();
+        txn.put(vec![2], vec![2]).await.unwrap();
+        txn.delete(vec![3]).await.unwrap();
+        match txn.commit().await {
+            Err(Error::CommitFailed { source, operations }) => {
+                assert!(!matches!(*source, Error::CommitFailed { .. }));
+                assert_eq!(operations, vec!["get", "put x2", "delete", "commit"]);
             }
+            other => panic!("unexpected result: {:?}", other),
         }
     }
 
-    pub fn heartbeat_option(mut self, heartbeat_option: HeartbeatOption) -> TransactionOptions {
-        self.heartbeat_option = heartbeat_option;
-        self
-    }
-
-    // Returns true if these options describe a pessimistic transaction.
-    pub fn is_pessimistic(&self) -> bool {
-        match self.kind {
-            TransactionKind::Pessimistic(_) => true,
-            TransactionKind::Optimistic => false,
+    #[tokio::test]
+    async fn test_one_pc() {
+        // returns the commit ts and the keys of the commit requests
+        async fn commit(keys: Vec<Vec<u8>>, one_pc_commit_ts: u64) -> (u64, Vec<Vec<u8>>) {
+            let logger = Logger::root(slog::Discard, o!());
+            let committed = Arc::new(std::sync::Mutex::new(Vec::new()));
+            let committed_cloned = committed.clone();
+            let pd_client = Arc::new(MockPdClient::new(MockKvClient::with_dispatch_hook(
+                move |req: &dyn Any| {
+                    if let Some(req) = req.downcast_ref::<kvrpcpb::PrewriteRequest>() {
+                        // 1PC is only tried if all keys are in one region
+                        let one_pc = req.try_one_pc;
+                        assert_eq!(one_pc, req.mutations.len() == 2);
+                        let resp = kvrpcpb::PrewriteResponse {
+                            one_pc_commit_ts: if one_pc { one_pc_commit_ts } else { 0 },
+                            ..Default::default()
+                        };
+                        Ok(Box::new(resp) as Box<dyn Any>)
+                    } else if let Some(req) = req.downcast_ref::<kvrpcpb::CommitRequest>() {
+                        committed_cloned
+                            .lock()
+                            .unwrap()
+                            .extend(req.keys.iter().cloned());
+                        Ok(Box::new(kvrpcpb::CommitResponse::default()) as Box<dyn Any>)
+                    } else {
+                        empty_response(req)
+                    }
+                },
+            )));
+            let mut txn = Transaction::new(
+                Timestamp::from_version(5),
+                pd_client,
+                TransactionOptions::new_optimistic()
+                    .try_one_pc()
+                    .heartbeat_option(HeartbeatOption::NoHeartbeat),
+                logger,
+            );
+            for key in keys {
+                txn.put(key.clone(), key).await.unwrap();
+            }
+            let commit_ts = txn.commit().await.unwrap().unwrap();
+            let committed = committed.lock().unwrap().clone();
+            (commit_ts.version(), committed)
         }
+
+        // committed by the prewrite
+        assert_eq!(commit(vec![vec![1], vec![2]], 42).await, (42, vec![]));
+        // TiKV fell back to prewriting, so the primary key is committed with a timestamp from PD
+        assert_eq!(commit(vec![vec![1], vec![2]], 0).await, (0, vec![vec![1]]));
+        // the keys are in different regions
+        assert_eq!(
+            commit(vec![vec![1], vec![20]], 42).await,
+            (0, vec![vec![1]])
+        );
     }
-}
 
-/// Determines what happens when a transaction is dropped without being rolled back or committed.
-///
-/// The default is to panic.
-#[derive(Clone, Eq, PartialEq, Debug)]
-pub enum CheckLevel {
-    /// The program will panic.
-    ///
-    /// Note that if the thread is already panicking, then we will not double-panic and abort, but
-    /// just ignore the issue.
-    Panic,
-    /// Log a warning.
-    Warn,
-    /// Do nothing
-    None,
-}
+    #[test]
+    fn test_heartbeat_lock_ttl() {
+        use super::{heartbeat_lock_ttl, DEFAULT_HEARTBEAT_INTERVAL, DEFAULT_LOCK_TTL, MAX_TTL};
 
-impl HeartbeatOption {
-    pub fn is_auto_heartbeat(&self) -> bool {
-        !matches!(self, HeartbeatOption::NoHeartbeat)
+        assert_eq!(
+            TransactionOptions::new_optimistic().heartbeat_interval(Duration::from_secs(5)),
+            TransactionOptions::new_optimistic()
+                .heartbeat_option(HeartbeatOption::FixedTime(Duration::from_secs(5)))
+        );
+        // the lock outlives two heartbeats
+        assert_eq!(
+            heartbeat_lock_ttl(Duration::from_secs(60), DEFAULT_HEARTBEAT_INTERVAL),
+            60_000 + MAX_TTL
+        );
+        assert_eq!(
+            heartbeat_lock_ttl(Duration::from_secs(60), Duration::from_secs(30)),
+            120_000
+        );
+        assert_eq!(
+            heartbeat_lock_ttl(Duration::ZERO, Duration::from_millis(100)),
+            DEFAULT_LOCK_TTL
+        );
     }
-}
-
-/// A struct wrapping the details of two-phase commit protocol (2PC).
-///
-/// The two phases are `prewrite` and `commit`.
-/// Generally, the `prewrite` phase is to send data to all regions and write them.
-/// The `commit` phase is to mark all written data as successfully committed.
-///
-/// The committer implements `prewrite`, `commit` and `rollback` functions.
-#[allow(clippy::too_many_arguments)]
-#[derive(new)]
-struct Committer<PdC: PdClient = PdRpcClient> {
-    primary_key: Option<Key>,
-    mutations: Vec<kvrpcpb::Mutation>,
-    start_version: Timestamp,
-    rpc: Arc<PdC>,
-    options: TransactionOptions,
-    #[new(default)]
-    undetermined: bool,
-    write_size: u64,
-    start_instant: Instant,
-    logger: Logger,
-}
 
-impl<PdC: PdClient> Committer<PdC> {
-    async fn commit(mut self) -> Result<Option<Timestamp>> {
-        debug!(self.logger, "committing");
+    #[tokio::test]
+    async fn test_lost_commit_response() {
+        // returns the result of the commit and the number of commit requests
+        async fn commit(status: kvrpcpb::CheckTxnStatusResponse) -> (Result<(), Error>, usize) {
+            let logger = Logger::root(slog::Discard, o!());
+            let commits = Arc::new(AtomicUsize::new(0));
+            let commits_cloned = commits.clone();
+            let pd_client = Arc::new(MockPdClient::new(MockKvClient::with_dispatch_hook(
+                move |req: &dyn Any| {
+                    if let Some(req) = req.downcast_ref::<kvrpcpb::CheckTxnStatusRequest>() {
+                        // only reads the status
+                        assert_eq!(req.lock_ts, 5);
+                        assert_eq!(req.current_ts, 0);
+                        assert!(!req.rollback_if_not_exist);
+                        Ok(Box::new(status.clone()) as Box<dyn Any>)
+                    } else if req.is::<kvrpcpb::CommitRequest>() {
+                        if commits_cloned.fetch_add(1, Ordering::SeqCst) == 0 {
+                            let status = grpcio::RpcStatus::new(grpcio::RpcStatusCode::UNAVAILABLE);
+                            Err(Error::Grpc(grpcio::Error::RpcFailure(status)))
+                        } else {
+                            empty_response(req)
+                        }
+                    } else {
+                        empty_response(req)
+                    }
+                },
+            )));
+            let mut txn = Transaction::new(
+                Timestamp::from_version(5),
+                pd_client,
+                TransactionOptions::new_optimistic().heartbeat_option(HeartbeatOption::NoHeartbeat),
+                logger,
+            );
+            txn.put(vec![1], vec![1]).await.unwrap();
+            let result = txn.commit().await.map(|_| ());
+            (result, commits.load(Ordering::SeqCst))
+        }
 
-        let min_commit_ts = self.prewrite().await?;
+        // the lost commit was applied
+        let committed = kvrpcpb::CheckTxnStatusResponse {
+            commit_version: 6,
+            ..Default::default()
+        };
+        let (result, commits) = commit(committed).await;
+        assert!(result.is_ok());
+        assert_eq!(commits, 1);
 
-        fail_point!("after-prewrite");
+        // the primary key is still locked, so it is committed again
+        let locked = kvrpcpb::CheckTxnStatusResponse {
+            lock_ttl: 100,
+            lock_info: Some(kvrpcpb::LockInfo::default()),
+            ..Default::default()
+        };
+        let (result, commits) = commit(locked).await;
+        assert!(result.is_ok());
+        assert_eq!(commits, 2);
 
-        // If we didn't use 1pc, prewrite will set `try_one_pc` to false.
-        if self.options.try_one_pc {
-            return Ok(min_commit_ts);
+        // the transaction was rolled back, so it certainly failed
+        let (result, commits) = commit(kvrpcpb::CheckTxnStatusResponse::default()).await;
+        match result {
+            Err(Error::RequestFailed { source, request }) => {
+                assert!(matches!(*source, Error::Grpc(_)));
+                assert_eq!(request.request_type, "kv_commit");
+            }
+            other => panic!("unexpected result: {:?}", other),
         }
+        assert_eq!(commits, 1);
+    }
 
-        let commit_ts = if self.options.async_commit {
-            // FIXME: min_commit_ts == 0 => fallback to normal 2PC
-            min_commit_ts.unwrap()
-        } else {
-            match self.commit_primary().await {
-                Ok(commit_ts) => commit_ts,
-                Err(e) => {
-                    return if self.undetermined {
-                        Err(Error::UndeterminedError(Box::new(e)))
-                    } else {
-                        Err(e)
-                    };
+    #[tokio::test]
+    async fn test_pipelined_pessimistic_lock() {
+        let logger = Logger::root(slog::Discard, o!());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let requests_cloned = requests.clone();
+        let pd_client = Arc::new(MockPdClient::new(MockKvClient::with_dispatch_hook(
+            move |req: &dyn Any| {
+                let mut requests = requests_cloned.lock().unwrap();
+                if let Some(req) = req.downcast_ref::<kvrpcpb::PessimisticLockRequest>() {
+                    requests.push("lock");
+                    let mut resp = kvrpcpb::PessimisticLockResponse::default();
+                    if req.mutations.iter().any(|m| m.key == b"fail") {
+                        let mut error = kvrpcpb::KeyError::default();
+                        error.set_abort("deadlock".to_owned());
+                        resp.errors.push(error);
+                    }
+                    Ok(Box::new(resp) as Box<dyn Any>)
+                } else if req.is::<kvrpcpb::PrewriteRequest>() {
+                    requests.push("prewrite");
+                    Ok(Box::new(kvrpcpb::PrewriteResponse::default()) as Box<dyn Any>)
+                } else if req.is::<kvrpcpb::CommitRequest>() {
+                    // not recorded, the secondary keys are committed in the background
+                    Ok(Box::new(kvrpcpb::CommitResponse::default()) as Box<dyn Any>)
+                } else if req.is::<kvrpcpb::PessimisticRollbackRequest>() {
+                    requests.push("rollback");
+                    let resp = kvrpcpb::PessimisticRollbackResponse::default();
+                    Ok(Box::new(resp) as Box<dyn Any>)
+                } else {
+                    empty_response(req)
                 }
-            }
-        };
-        tokio::spawn(self.commit_secondary(commit_ts.clone()).map(|res| {
-            if let Err(e) = res {
-                log::warn!("Failed to commit secondary keys: {}", e);
-            }
-        }));
-        Ok(Some(commit_ts))
-    }
+            },
+        )));
+        let options = TransactionOptions::new_pessimistic()
+            .pipelined_pessimistic_lock()
+            .heartbeat_option(HeartbeatOption::NoHeartbeat);
 
-    async fn prewrite(&mut self) -> Result<Option<Timestamp>> {
-        debug!(self.logger, "prewriting");
-        let primary_lock = self.primary_key.clone().unwrap();
-        let elapsed = self.start_instant.elapsed().as_millis() as u64;
-        let lock_ttl = self.calc_txn_lock_ttl();
-        let mut request = match &self.options.kind {
-            TransactionKind::Optimistic => new_prewrite_request(
-                self.mutations.clone(),
-                primary_lock,
-                self.start_version.clone(),
-                lock_ttl + elapsed,
-            ),
-            TransactionKind::Pessimistic(for_update_ts) => new_pessimistic_prewrite_request(
-                self.mutations.clone(),
-                primary_lock,
-                self.start_version.clone(),
-                lock_ttl + elapsed,
-                for_update_ts.clone(),
-            ),
-        };
+        // the locks are awaited before the prewrite
+        let mut txn = Transaction::new(
+            Timestamp::default(),
+            pd_client.clone(),
+            options.clone(),
+            logger.clone(),
+        );
+        txn.lock_keys(vec!["key1".to_owned()]).await.unwrap();
+        txn.put("key2".to_owned(), "value".to_owned())
+            .await
+            .unwrap();
+        txn.commit().await.unwrap();
+        assert_eq!(requests.lock().unwrap()[..3], ["lock", "lock", "prewrite"]);
 
-        request.use_async_commit = self.options.async_commit;
-        request.try_one_pc = self.options.try_one_pc;
-        request.secondaries = self
-            .mutations
-            .iter()
-            .filter(|m| self.primary_key.as_ref().unwrap() != m.key.as_ref())
-            .map(|m| m.key.clone())
-            .collect();
-        // FIXME set max_commit_ts and min_commit_ts
+        // a failed lock fails the commit, and is rolled back
+        requests.lock().unwrap().clear();
+        let mut txn = Transaction::new(Timestamp::default(), pd_client, options, logger);
+        txn.lock_keys(vec!["fail".to_owned()]).await.unwrap();
+        assert!(txn.commit().await.is_err());
+        assert_eq!(*requests.lock().unwrap(), ["lock"]);
+        txn.rollback().await.unwrap();
+        assert_eq!(*requests.lock().unwrap(), ["lock", "rollback"]);
+    }
 
-        let plan = PlanBuilder::new(self.rpc.clone(), request)
-            .resolve_lock(self.options.retry_options.lock_backoff.clone())
-            .retry_multi_region(self.options.retry_options.region_backoff.clone())
-            .merge(CollectError)
-            .extract_error()
-            .plan();
-        let response = plan.execute().await?;
+    #[tokio::test]
+    async fn test_lock_keys_with_options() {
+        // "k1" exists, "k2" doesn't, "locked" is locked by a live transaction
+        let requests = Arc::new(AtomicUsize::new(0));
+        let requests_cloned = requests.clone();
+        let pd_client = Arc::new(MockPdClient::new(MockKvClient::with_dispatch_hook(
+            move |req: &dyn Any| {
+                let req = req
+                    .downcast_ref::<kvrpcpb::PessimisticLockRequest>()
+                    .expect("unexpected request");
+                requests_cloned.fetch_add(1, Ordering::SeqCst);
+                assert_eq!(req.wait_timeout, 100);
+                assert!(req.check_existence && !req.return_values);
+                let mut resp = kvrpcpb::PessimisticLockResponse::default();
+                if req.mutations.iter().any(|m| m.key == b"locked") {
+                    let mut error = kvrpcpb::KeyError::default();
+                    error.set_locked(kvrpcpb::LockInfo {
+                        key: b"locked".to_vec(),
+                        primary_lock: b"locked".to_vec(),
+                        lock_ttl: 1 << 40,
+                        ..Default::default()
+                    });
+                    resp.errors.push(error);
+                } else {
+                    resp.not_founds = req.mutations.iter().map(|m| m.key == b"k2").collect();
+                }
+                Ok(Box::new(resp) as Box<dyn Any>)
+            },
+        )));
+        let options = TransactionOptions::new_pessimistic()
+            .heartbeat_option(HeartbeatOption::NoHeartbeat)
+            .drop_check(CheckLevel::None);
+        let mut txn = Transaction::new(
+            Timestamp::default(),
+            pd_client,
+            options,
+            Logger::root(slog::Discard, o!()),
+        );
+        let lock_options = PessimisticLockOptions::new()
+            .wait_timeout(Duration::from_millis(100))
+            .check_existence(true);
 
-        if self.options.try_one_pc && response.len() == 1 {
-            if response[0].one_pc_commit_ts == 0 {
-                return Err(Error::OnePcFailure);
-            }
+        let pairs = txn
+            .lock_keys_with_options(vec![b"k1".to_vec(), b"k2".to_vec()], lock_options.clone())
+            .await
+            .unwrap();
+        assert_eq!(pairs, vec![KvPair::new(b"k1".to_vec(), Vec::new())]);
 
-            return Ok(Timestamp::try_from_version(response[0].one_pc_commit_ts));
-        }
+        // the live lock is not waited for again after TiKV's wait timed out
+        requests.store(0, Ordering::SeqCst);
+        let result = txn
+            .lock_keys_with_options(vec![b"locked".to_vec()], lock_options)
+            .await;
+        assert!(matches!(
+            result.unwrap_err().without_request(),
+            Error::ResolveLockError
+        ));
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
 
-        self.options.try_one_pc = false;
+        let no_wait = PessimisticLockOptions::new().wait_timeout(Duration::ZERO);
+        assert_eq!(no_wait.wait_timeout_ms(), -1);
+        assert_eq!(PessimisticLockOptions::new().wait_timeout_ms(), 0);
+    }
 
-        let min_commit_ts = response
-            .iter()
-            .map(|r| {
-                assert_eq!(r.one_pc_commit_ts, 0);
-                r.min_commit_ts
-            })
-            .max()
-            .map(Timestamp::from_version);
+    #[tokio::test]
+    async fn test_release_locks() {
+        let logger = Logger::root(slog::Discard, o!());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let requests_cloned = requests.clone();
+        let pd_client = Arc::new(MockPdClient::new(MockKvClient::with_dispatch_hook(
+            move |req: &dyn Any| {
+                let mut requests = requests_cloned.lock().unwrap();
+                if req.is::<kvrpcpb::PessimisticLockRequest>() {
+                    let resp = kvrpcpb::PessimisticLockResponse::default();
+                    Ok(Box::new(resp) as Box<dyn Any>)
+                } else if let Some(req) = req.downcast_ref::<kvrpcpb::PessimisticRollbackRequest>()
+                {
+                    requests.push(("rollback", req.keys.clone()));
+                    let resp = kvrpcpb::PessimisticRollbackResponse::default();
+                    Ok(Box::new(resp) as Box<dyn Any>)
+                } else if let Some(req) = req.downcast_ref::<kvrpcpb::PrewriteRequest>() {
+                    let keys = req.mutations.iter().map(|m| m.key.clone()).collect();
+                    requests.push(("prewrite", keys));
+                    Ok(Box::new(kvrpcpb::PrewriteResponse::default()) as Box<dyn Any>)
+                } else {
+                    empty_response(req)
+                }
+            },
+        )));
+        let options = TransactionOptions::new_pessimistic()
+            .heartbeat_option(HeartbeatOption::NoHeartbeat)
+            .drop_check(CheckLevel::None);
+        let mut txn = Transaction::new(Timestamp::default(), pd_client, options, logger);
+        assert!(txn.locked_keys().is_empty());
+        txn.lock_keys(vec![vec![1], vec![2], vec![3]])
+            .await
+            .unwrap();
+        txn.put(vec![2], vec![2]).await.unwrap();
+        let key = |k: u8| Key::from(vec![k]);
+        assert_eq!(txn.locked_keys(), vec![key(1), key(2), key(3)]);
 
-        Ok(min_commit_ts)
+        // the primary key, written keys and keys which are not locked are not released
+        txn.release_locks(vec![vec![1], vec![2], vec![3], vec![4]])
+            .await
+            .unwrap();
+        assert_eq!(*requests.lock().unwrap(), vec![("rollback", vec![vec![3]])]);
+        assert_eq!(txn.locked_keys(), vec![key(1), key(2)]);
+
+        // the released key is not prewritten
+        txn.commit().await.unwrap();
+        assert_eq!(
+            requests.lock().unwrap()[1],
+            ("prewrite", vec![vec![1], vec![2]])
+        );
     }
 
-    /// Commits the primary key and returns the commit version
-    async fn commit_primary(&mut self) -> Result<Timestamp> {
-        debug!(self.logger, "committing primary");
-        let primary_key = self.primary_key.clone().into_iter();
-        let commit_version = self.rpc.clone().get_timestamp().await?;
-        let req = new_commit_request(
-            primary_key,
-            self.start_version.clone(),
-            commit_version.clone(),
+    #[tokio::test]
+    async fn test_rollback_to_savepoint() {
+        let logger = Logger::root(slog::Discard, o!());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let requests_cloned = requests.clone();
+        let pd_client = Arc::new(MockPdClient::new(MockKvClient::with_dispatch_hook(
+            move |req: &dyn Any| {
+                let mut requests = requests_cloned.lock().unwrap();
+                if req.is::<kvrpcpb::PessimisticLockRequest>() {
+                    let resp = kvrpcpb::PessimisticLockResponse::default();
+                    Ok(Box::new(resp) as Box<dyn Any>)
+                } else if let Some(req) = req.downcast_ref::<kvrpcpb::PessimisticRollbackRequest>()
+                {
+                    requests.push(("rollback", req.keys.clone()));
+                    let resp = kvrpcpb::PessimisticRollbackResponse::default();
+                    Ok(Box::new(resp) as Box<dyn Any>)
+                } else if let Some(req) = req.downcast_ref::<kvrpcpb::PrewriteRequest>() {
+                    let keys = req.mutations.iter().map(|m| m.key.clone()).collect();
+                    requests.push(("prewrite", keys));
+                    Ok(Box::new(kvrpcpb::PrewriteResponse::default()) as Box<dyn Any>)
+                } else {
+                    empty_response(req)
+                }
+            },
+        )));
+        let options = TransactionOptions::new_pessimistic()
+            .heartbeat_option(HeartbeatOption::NoHeartbeat)
+            .drop_check(CheckLevel::None);
+        let mut txn = Transaction::new(Timestamp::default(), pd_client, options, logger);
+        let key = |k: u8| Key::from(vec![k]);
+        let first = txn.savepoint();
+        txn.put(vec![1], vec![1]).await.unwrap();
+        let second = txn.savepoint();
+        txn.put(vec![2], vec![2]).await.unwrap();
+        txn.lock_keys(vec![vec![3]]).await.unwrap();
+        let third = txn.savepoint();
+
+        // the locks since the savepoint are rolled back
+        txn.rollback_to(&second).await.unwrap();
+        assert_eq!(
+            *requests.lock().unwrap(),
+            vec![("rollback", vec![vec![2], vec![3]])]
         );
-        let plan = PlanBuilder::new(self.rpc.clone(), req)
-            .resolve_lock(self.options.retry_options.lock_backoff.clone())
-            .retry_multi_region(self.options.retry_options.region_backoff.clone())
-            .extract_error()
-            .plan();
-        plan.execute()
-            .inspect_err(|e| {
-                // We don't know whether the transaction is committed or not if we fail to receive
-                // the response. Then, we mark the transaction as undetermined and propagate the
-                // error to the user.
-                if let Error::Grpc(_) = e {
-                    self.undetermined = true;
+        assert_eq!(txn.locked_keys(), vec![key(1)]);
+        assert!(matches!(
+            txn.rollback_to(&third).await,
+            Err(Error::InvalidSavepoint)
+        ));
+
+        // the lock of the primary key is kept
+        txn.put(vec![2], vec![3]).await.unwrap();
+        txn.rollback_to(&first).await.unwrap();
+        assert_eq!(requests.lock().unwrap()[1], ("rollback", vec![vec![2]]));
+        assert_eq!(txn.locked_keys(), vec![key(1)]);
+        txn.put(vec![4], vec![4]).await.unwrap();
+        txn.commit().await.unwrap();
+        assert_eq!(
+            requests.lock().unwrap()[2],
+            ("prewrite", vec![vec![1], vec![4]])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_batch_key_exists() {
+        let logger = Logger::root(slog::Discard, o!());
+        let scans = Arc::new(AtomicUsize::new(0));
+        let scans_cloned = scans.clone();
+        let pd_client = Arc::new(MockPdClient::new(MockKvClient::with_dispatch_hook(
+            move |req: &dyn Any| {
+                let req = req.downcast_ref::<kvrpcpb::ScanRequest>().unwrap();
+                assert!(req.key_only);
+                scans_cloned.fetch_add(1, Ordering::SeqCst);
+                // only key 3 is stored
+                let mut resp = kvrpcpb::ScanResponse::default();
+                if req.start_key == vec![3] {
+                    resp.pairs.push(kvrpcpb::KvPair {
+                        key: vec![3],
+                        ..Default::default()
+                    });
                 }
-            })
-            .await?;
+                Ok(Box::new(resp) as Box<dyn Any>)
+            },
+        )));
+        let options = TransactionOptions::new_optimistic()
+            .heartbeat_option(HeartbeatOption::NoHeartbeat)
+            .drop_check(CheckLevel::None);
+        let mut txn = Transaction::new(Timestamp::default(), pd_client, options, logger);
+        txn.put(vec![1], vec![1]).await.unwrap();
+        txn.delete(vec![2]).await.unwrap();
+
+        let exist = txn
+            .batch_key_exists(vec![vec![1], vec![2], vec![3], vec![4]])
+            .await
+            .unwrap();
+        assert_eq!(exist, vec![true, false, true, false]);
+        // the written keys are checked in the buffer
+        assert_eq!(scans.load(Ordering::SeqCst), 2);
+    }
 
-        Ok(commit_version)
+    #[tokio::test]
+    async fn test_size_limits() {
+        let logger = Logger::root(slog::Discard, o!());
+        let pd_client = Arc::new(MockPdClient::new(MockKvClient::with_dispatch_hook(
+            |_: &dyn Any| panic!("unexpected request"),
+        )));
+        let options = TransactionOptions::new_pessimistic()
+            .heartbeat_option(HeartbeatOption::NoHeartbeat)
+            .drop_check(CheckLevel::None)
+            .size_limits(SizeLimits {
+                max_key_size: 2,
+                max_value_size: 2,
+            });
+        let mut txn = Transaction::new(Timestamp::default(), pd_client, options, logger);
+        // oversized writes fail before the keys are locked
+        assert!(matches!(
+            txn.put(vec![1, 2, 3], vec![1]).await,
+            Err(Error::KeyTooLarge { key, size: 3, limit: 2 }) if key == vec![1, 2, 3]
+        ));
+        assert!(matches!(
+            txn.insert(vec![1], vec![1, 2, 3]).await,
+            Err(Error::ValueTooLarge { key, size: 3, limit: 2 }) if key == vec![1]
+        ));
+        assert!(matches!(
+            txn.delete(vec![0; 3]).await,
+            Err(Error::KeyTooLarge { .. })
+        ));
+        assert!(txn.locked_keys().is_empty());
     }
 
-    async fn commit_secondary(self, commit_version: Timestamp) -> Result<()> {
-        debug!(self.logger, "committing secondary");
-        let mutations_len = self.mutations.len();
-        let primary_only = mutations_len == 1;
-        let mutations = self.mutations.into_iter();
+    #[tokio::test]
+    async fn test_scan_stream() {
+        let logger = Logger::root(slog::Discard, o!());
+        let pd_client = Arc::new(MockPdClient::new(MockKvClient::with_dispatch_hook(
+            move |req: &dyn Any| {
+                let req = req.downcast_ref::<kvrpcpb::ScanRequest>().unwrap();
+                let pairs = [1u8, 2, 3, 12, 13]
+                    .iter()
+                    .map(|&k| vec![k])
+                    .filter(|k| *k >= req.start_key && (req.end_key.is_empty() || *k < req.end_key))
+                    .take(req.limit as usize)
+                    .map(|k| kvrpcpb::KvPair {
+                        value: k.clone(),
+                        key: k,
+                        ..Default::default()
+                    })
+                    .collect();
+                let resp = kvrpcpb::ScanResponse {
+                    pairs,
+                    ..Default::default()
+                };
+                Ok(Box::new(resp) as Box<dyn Any>)
+            },
+        )));
+        let mut txn = Transaction::new(
+            Timestamp::default(),
+            pd_client,
+            TransactionOptions::new_optimistic().drop_check(CheckLevel::None),
+            logger,
+        );
+        txn.put(vec![2], vec![20]).await.unwrap();
+        txn.delete(vec![3]).await.unwrap();
+        txn.put(vec![11], vec![11]).await.unwrap();
+        txn.put(vec![13], vec![13]).await.unwrap();
+        let pairs: Vec<KvPair> = txn
+            .scan_stream(vec![1]..vec![13])
+            .await
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        // the scan spans both regions, and includes the writes of the transaction
+        assert_eq!(
+            pairs,
+            vec![
+                KvPair::new(vec![1], vec![1]),
+                KvPair::new(vec![2], vec![20]),
+                KvPair::new(vec![11], vec![11]),
+                KvPair::new(vec![12], vec![12]),
+            ]
+        );
+    }
 
-        let req = if self.options.async_commit {
-            let keys = mutations.map(|m| m.key.into());
-            new_commit_request(keys, self.start_version, commit_version)
-        } else if primary_only {
-            return Ok(());
-        } else {
-            let primary_key = self.primary_key.unwrap();
-            let keys = mutations
-                .map(|m| m.key.into())
-                .filter(|key| &primary_key != key);
-            new_commit_request(keys, self.start_version, commit_version)
-        };
-        let plan = PlanBuilder::new(self.rpc, req)
-            .resolve_lock(self.options.retry_options.lock_backoff)
-            .retry_multi_region(self.options.retry_options.region_backoff)
-            .extract_error()
-            .plan();
-        plan.execute().await?;
-        Ok(())
+    #[tokio::test]
+    async fn test_scan_ranges() {
+        let logger = Logger::root(slog::Discard, o!());
+        let pd_client = Arc::new(MockPdClient::new(MockKvClient::with_dispatch_hook(
+            move |req: &dyn Any| {
+                let req = req.downcast_ref::<kvrpcpb::ScanRequest>().unwrap();
+                let pairs = [1u8, 2, 3, 12, 13]
+                    .iter()
+                    .map(|&k| vec![k])
+                    .filter(|k| *k >= req.start_key && (req.end_key.is_empty() || *k < req.end_key))
+                    .take(req.limit as usize)
+                    .map(|k| kvrpcpb::KvPair {
+                        value: k.clone(),
+                        key: k,
+                        ..Default::default()
+                    })
+                    .collect();
+                let resp = kvrpcpb::ScanResponse {
+                    pairs,
+                    ..Default::default()
+                };
+                Ok(Box::new(resp) as Box<dyn Any>)
+            },
+        )));
+        let mut txn = Transaction::new(
+            Timestamp::default(),
+            pd_client,
+            TransactionOptions::new_optimistic().drop_check(CheckLevel::None),
+            logger,
+        );
+        txn.put(vec![2], vec![20]).await.unwrap();
+        txn.delete(vec![3]).await.unwrap();
+        txn.put(vec![11], vec![11]).await.unwrap();
+        let ranges: Vec<BoundRange> = vec![
+            (vec![12]..).into(),
+            (vec![1]..vec![4]).into(),
+            (vec![5]..vec![6]).into(),
+            (vec![3]..vec![12]).into(),
+        ];
+        let pairs: Vec<(usize, KvPair)> = txn.scan_ranges(ranges, 2).await.unwrap().collect();
+        // the pairs are grouped by range, deleted keys are skipped and empty ranges are left out
+        assert_eq!(
+            pairs,
+            vec![
+                (0, KvPair::new(vec![12], vec![12])),
+                (0, KvPair::new(vec![13], vec![13])),
+                (1, KvPair::new(vec![1], vec![1])),
+                (1, KvPair::new(vec![2], vec![20])),
+                (3, KvPair::new(vec![11], vec![11])),
+            ]
+        );
     }
 
-    async fn rollback(self) -> Result<()> {
-        debug!(self.logger, "rolling back");
-        if self.options.kind == TransactionKind::Optimistic && self.mutations.is_empty() {
-            return Ok(());
+    #[tokio::test]
+    async fn test_scan_filtered() {
+        struct MockPushdown;
+
+        async fn filtered(txn: &mut Transaction<MockPdClient>, filter: ScanFilter) -> Vec<KvPair> {
+            let pairs = txn.scan_filtered(.., filter).await.unwrap();
+            pairs.try_collect().await.unwrap()
         }
-        let keys = self
-            .mutations
-            .into_iter()
-            .map(|mutation| mutation.key.into());
-        match self.options.kind {
-            TransactionKind::Optimistic => {
-                let req = new_batch_rollback_request(keys, self.start_version);
-                let plan = PlanBuilder::new(self.rpc, req)
-                    .resolve_lock(self.options.retry_options.lock_backoff)
-                    .retry_multi_region(self.options.retry_options.region_backoff)
-                    .extract_error()
-                    .plan();
-                plan.execute().await?;
+
+        impl FilterPushdown for MockPushdown {
+            fn compile(&self, _: &ScanFilter, range: BoundRange) -> Option<CoprocessorRequest> {
+                Some(CoprocessorRequest::new(
+                    CoprocessorRequestType::Dag,
+                    b"filter".to_vec(),
+                    vec![range],
+                ))
             }
-            TransactionKind::Pessimistic(for_update_ts) => {
-                let req = new_pessimistic_rollback_request(keys, self.start_version, for_update_ts);
-                let plan = PlanBuilder::new(self.rpc, req)
-                    .resolve_lock(self.options.retry_options.lock_backoff)
-                    .retry_multi_region(self.options.retry_options.region_backoff)
-                    .extract_error()
-                    .plan();
-                plan.execute().await?;
+
+            fn decode(&self, response: CoprocessorResponse) -> crate::Result<Vec<KvPair>> {
+                Ok(vec![KvPair::new(response.data, vec![])])
             }
         }
-        Ok(())
-    }
 
-    fn calc_txn_lock_ttl(&mut self) -> u64 {
-        let mut lock_ttl = DEFAULT_LOCK_TTL;
-        if self.write_size > TXN_COMMIT_BATCH_SIZE {
-            let size_mb = self.write_size as f64 / 1024.0 / 1024.0;
-            lock_ttl = (TTL_FACTOR * size_mb.sqrt()) as u64;
-            lock_ttl = lock_ttl.min(MAX_TTL).max(DEFAULT_LOCK_TTL);
-        }
-        lock_ttl
-    }
-}
+        let logger = Logger::root(slog::Discard, o!());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let requests_cloned = requests.clone();
+        let pd_client = Arc::new(MockPdClient::new(MockKvClient::with_dispatch_hook(
+            move |req: &dyn Any| {
+                if let Some(req) = req.downcast_ref::<coprocessor::Request>() {
+                    requests_cloned
+                        .lock()
+                        .unwrap()
+                        .push(req.ranges[0].start.clone());
+                    // the pushed down filter returns the start of each range
+                    let resp = coprocessor::Response {
+                        data: req.ranges[0].start.clone(),
+                        ..Default::default()
+                    };
+                    return Ok(Box::new(resp) as Box<dyn Any>);
+                }
+                let req = req.downcast_ref::<kvrpcpb::ScanRequest>().unwrap();
+                requests_cloned.lock().unwrap().push(req.start_key.clone());
+                let pairs = [
+                    (vec![1, 1], b"a".to_vec()),
+                    (vec![1, 2], b"bb".to_vec()),
+                    (vec![2, 1], b"cc".to_vec()),
+                    (vec![12, 1], b"dd".to_vec()),
+                    (vec![12, 2], b"e".to_vec()),
+                ]
+                .iter()
+                .filter(|(k, _)| {
+                    *k >= req.start_key && (req.end_key.is_empty() || *k < req.end_key)
+                })
+                .take(req.limit as usize)
+                .map(|(k, v)| kvrpcpb::KvPair {
+                    key: k.clone(),
+                    value: v.clone(),
+                    ..Default::default()
+                })
+                .collect();
+                let resp = kvrpcpb::ScanResponse {
+                    pairs,
+                    ..Default::default()
+                };
+                Ok(Box::new(resp) as Box<dyn Any>)
+            },
+        )));
+        let mut txn = Transaction::new(
+            Timestamp::default(),
+            pd_client,
+            TransactionOptions::new_optimistic().drop_check(CheckLevel::None),
+            logger,
+        );
 
-#[derive(PartialEq)]
-enum TransactionStatus {
-    /// The transaction is read-only [`Snapshot`](super::Snapshot), no need to commit or rollback or panic on drop.
-    ReadOnly,
-    /// The transaction have not been committed or rolled back.
-    Active,
-    /// The transaction has committed.
-    Committed,
-    /// The transaction has tried to commit. Only `commit` is allowed.
-    StartedCommit,
-    /// The transaction has rolled back.
-    Rolledback,
-    /// The transaction has tried to rollback. Only `rollback` is allowed.
-    StartedRollback,
-    /// The transaction has been dropped.
-    Dropped,
-}
+        // the client evaluates the filter on the pairs of both regions
+        let pairs = filtered(&mut txn, ScanFilter::new().value_len(2..)).await;
+        assert_eq!(
+            pairs,
+            vec![
+                KvPair::new(vec![1, 2], b"bb".to_vec()),
+                KvPair::new(vec![2, 1], b"cc".to_vec()),
+                KvPair::new(vec![12, 1], b"dd".to_vec()),
+            ]
+        );
 
-#[cfg(test)]
-mod tests {
-    use crate::{
-        mock::{MockKvClient, MockPdClient},
-        transaction::HeartbeatOption,
-        Transaction, TransactionOptions,
-    };
-    use fail::FailScenario;
-    use slog::{Drain, Logger};
-    use std::{
-        any::Any,
-        io,
-        sync::{
-            atomic::{AtomicUsize, Ordering},
-            Arc,
-        },
-        time::Duration,
-    };
-    use tikv_client_proto::{kvrpcpb, pdpb::Timestamp};
+        // only the keys with the prefix are scanned
+        requests.lock().unwrap().clear();
+        let filter = ScanFilter::new()
+            .key_prefix(vec![12])
+            .value_regex("d")
+            .unwrap();
+        let pairs = filtered(&mut txn, filter).await;
+        assert_eq!(pairs, vec![KvPair::new(vec![12, 1], b"dd".to_vec())]);
+        assert_eq!(*requests.lock().unwrap(), vec![vec![12]]);
+
+        // the coprocessor evaluates a filter which is pushed down
+        requests.lock().unwrap().clear();
+        let filter = ScanFilter::new()
+            .key_prefix(vec![1])
+            .pushdown(Arc::new(MockPushdown));
+        let pairs = filtered(&mut txn, filter.clone()).await;
+        assert_eq!(pairs, vec![KvPair::new(vec![1], vec![])]);
+        assert_eq!(*requests.lock().unwrap(), vec![vec![1]]);
+
+        // unless the transaction wrote to the range
+        txn.put(vec![1, 3], b"f".to_vec()).await.unwrap();
+        let pairs = filtered(&mut txn, filter).await;
+        assert_eq!(pairs.len(), 3);
+        assert_eq!(pairs[2], KvPair::new(vec![1, 3], b"f".to_vec()));
+    }
 
     #[tokio::test]
-    async fn test_optimistic_heartbeat() -> Result<(), io::Error> {
-        let plain = slog_term::PlainSyncDecorator::new(std::io::stdout());
-        let logger = Logger::root(
-            slog_term::FullFormat::new(plain)
-                .build()
-                .filter_level(slog::Level::Info)
-                .fuse(),
-            o!(),
-        );
-        let scenario = FailScenario::setup();
-        fail::cfg("after-prewrite", "sleep(1500)").unwrap();
-        let heartbeats = Arc::new(AtomicUsize::new(0));
-        let heartbeats_cloned = heartbeats.clone();
+    async fn test_scan_reverse() {
+        let logger = Logger::root(slog::Discard, o!());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let requests_cloned = requests.clone();
         let pd_client = Arc::new(MockPdClient::new(MockKvClient::with_dispatch_hook(
             move |req: &dyn Any| {
-                if req.downcast_ref::<kvrpcpb::TxnHeartBeatRequest>().is_some() {
-                    heartbeats_cloned.fetch_add(1, Ordering::SeqCst);
-                    Ok(Box::new(kvrpcpb::TxnHeartBeatResponse::default()) as Box<dyn Any>)
-                } else if req.downcast_ref::<kvrpcpb::PrewriteRequest>().is_some() {
+                let req = req.downcast_ref::<kvrpcpb::ScanRequest>().unwrap();
+                assert!(req.reverse);
+                requests_cloned
+                    .lock()
+                    .unwrap()
+                    .push((req.start_key.clone(), req.end_key.clone()));
+                // scans from `start_key` down to `end_key`
+                let pairs = [1u8, 2, 3, 12, 13]
+                    .iter()
+                    .rev()
+                    .map(|&k| vec![k])
+                    .filter(|k| {
+                        *k >= req.end_key && (req.start_key.is_empty() || *k < req.start_key)
+                    })
+                    .take(req.limit as usize)
+                    .map(|k| kvrpcpb::KvPair {
+                        value: k.clone(),
+                        key: k,
+                        ..Default::default()
+                    })
+                    .collect();
+                let resp = kvrpcpb::ScanResponse {
+                    pairs,
+                    ..Default::default()
+                };
+                Ok(Box::new(resp) as Box<dyn Any>)
+            },
+        )));
+        let mut txn = Transaction::new(
+            Timestamp::default(),
+            pd_client,
+            TransactionOptions::new_optimistic().drop_check(CheckLevel::None),
+            logger,
+        );
+        txn.put(vec![11], vec![11]).await.unwrap();
+        let keys: Vec<Key> = txn
+            .scan_keys_reverse(vec![2]..vec![13], 3)
+            .await
+            .unwrap()
+            .collect();
+        assert_eq!(
+            keys,
+            vec![Key::from(vec![12]), vec![11].into(), vec![3].into()]
+        );
+        // the last region is scanned first
+        assert_eq!(
+            *requests.lock().unwrap(),
+            vec![(vec![13], vec![10]), (vec![10], vec![2])]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_namespace() {
+        let logger = Logger::root(slog::Discard, o!());
+        let pd_client = Arc::new(MockPdClient::new(MockKvClient::with_dispatch_hook(
+            |req: &dyn Any| {
+                if let Some(req) = req.downcast_ref::<kvrpcpb::GetRequest>() {
+                    assert_eq!(req.key, b"orders/a".to_vec());
+                    let resp = kvrpcpb::GetResponse {
+                        value: vec![1],
+                        ..Default::default()
+                    };
+                    Ok(Box::new(resp) as Box<dyn Any>)
+                } else if let Some(req) = req.downcast_ref::<kvrpcpb::ScanRequest>() {
+                    assert_eq!(req.start_key, b"orders/".to_vec());
+                    assert_eq!(req.end_key, b"orders0".to_vec());
+                    let resp = kvrpcpb::ScanResponse {
+                        pairs: vec![kvrpcpb::KvPair {
+                            key: b"orders/a".to_vec(),
+                            value: vec![1],
+                            ..Default::default()
+                        }],
+                        ..Default::default()
+                    };
+                    Ok(Box::new(resp) as Box<dyn Any>)
+                } else if let Some(req) = req.downcast_ref::<kvrpcpb::PrewriteRequest>() {
+                    assert_eq!(req.mutations[0].key, b"orders/b".to_vec());
                     Ok(Box::new(kvrpcpb::PrewriteResponse::default()) as Box<dyn Any>)
                 } else {
-                    Ok(Box::new(kvrpcpb::CommitResponse::default()) as Box<dyn Any>)
+                    empty_response(req)
                 }
             },
         )));
-        let key1 = "key1".to_owned();
-        let mut heartbeat_txn = Transaction::new(
+        let mut txn = Transaction::new(
             Timestamp::default(),
             pd_client,
-            TransactionOptions::new_optimistic()
-                .heartbeat_option(HeartbeatOption::FixedTime(Duration::from_secs(1))),
-            logger.new(o!("child" => 1)),
+            TransactionOptions::new_optimistic(),
+            logger,
+        )
+        .with_namespace(Some(Namespace::new(None, "orders")));
+        assert_eq!(txn.get(b"a".to_vec()).await.unwrap(), Some(vec![1]));
+        txn.put(b"b".to_vec(), vec![2]).await.unwrap();
+        let pairs: Vec<KvPair> = txn.scan(.., 10).await.unwrap().collect();
+        assert_eq!(
+            pairs,
+            vec![
+                KvPair::new(b"a".to_vec(), vec![1]),
+                KvPair::new(b"b".to_vec(), vec![2]),
+            ]
         );
-        heartbeat_txn.put(key1.clone(), "foo").await.unwrap();
-        let heartbeat_txn_handle = tokio::task::spawn_blocking(move || {
-            assert!(futures::executor::block_on(heartbeat_txn.commit()).is_ok())
-        });
-        assert_eq!(heartbeats.load(Ordering::SeqCst), 0);
-        heartbeat_txn_handle.await.unwrap();
-        assert_eq!(heartbeats.load(Ordering::SeqCst), 1);
-        scenario.teardown();
-        Ok(())
+        txn.commit().await.unwrap();
     }
 
     #[tokio::test]
-    async fn test_pessimistic_heartbeat() -> Result<(), io::Error> {
-        let plain = slog_term::PlainSyncDecorator::new(std::io::stdout());
+    async fn test_replica_read() {
+        let logger = Logger::root(slog::Discard, o!());
+        let leader = Arc::new(MockPdClient::new(MockKvClient::with_dispatch_hook(
+            |req: &dyn Any| {
+                if req.is::<kvrpcpb::PrewriteRequest>() {
+                    Ok(Box::new(kvrpcpb::PrewriteResponse::default()) as Box<dyn Any>)
+                } else if req.is::<kvrpcpb::CommitRequest>() {
+                    Ok(Box::new(kvrpcpb::CommitResponse::default()) as Box<dyn Any>)
+                } else {
+                    panic!("reads must be sent to replicas")
+                }
+            },
+        )));
+        let replica = Arc::new(MockPdClient::new(MockKvClient::with_dispatch_hook(
+            |req: &dyn Any| {
+                let resp = if req.is::<kvrpcpb::GetRequest>() {
+                    Box::new(kvrpcpb::GetResponse {
+                        value: b"replica".to_vec(),
+                        ..Default::default()
+                    }) as Box<dyn Any>
+                } else if req.is::<kvrpcpb::BatchGetRequest>() {
+                    Box::new(kvrpcpb::BatchGetResponse::default()) as Box<dyn Any>
+                } else if req.is::<kvrpcpb::ScanRequest>() {
+                    Box::new(kvrpcpb::ScanResponse::default()) as Box<dyn Any>
+                } else {
+                    panic!("writes must be sent to leaders")
+                };
+                Ok(resp)
+            },
+        )));
+        let options = TransactionOptions::new_optimistic()
+            .heartbeat_option(HeartbeatOption::NoHeartbeat)
+            .replica_read(ReplicaRead::Follower);
+        let mut txn = Transaction::new(Timestamp::default(), leader, options, logger);
+        txn.replica_rpc = Some(replica);
+        assert_eq!(txn.get(vec![1]).await.unwrap(), Some(b"replica".to_vec()));
+        assert_eq!(txn.batch_get(vec![vec![2]]).await.unwrap().count(), 0);
+        assert_eq!(txn.scan(vec![3]..vec![4], 10).await.unwrap().count(), 0);
+        txn.put(vec![1], vec![2]).await.unwrap();
+        txn.commit().await.unwrap();
+    }
 
-        let logger = Logger::root(
-            slog_term::FullFormat::new(plain)
-                .build()
-                .filter_level(slog::Level::Info)
-                .fuse(),
-            o!(),
-        );
-        let heartbeats = Arc::new(AtomicUsize::new(0));
-        let heartbeats_cloned = heartbeats.clone();
+    #[tokio::test]
+    async fn test_stale_read_not_ready() {
+        let logger = Logger::root(slog::Discard, o!());
+        let pd_client = Arc::new(MockPdClient::new(MockKvClient::with_dispatch_hook(
+            |_: &dyn Any| {
+                let resp = kvrpcpb::BatchGetResponse {
+                    region_error: Some(errorpb::Error {
+                        data_is_not_ready: Some(errorpb::DataIsNotReady {
+                            region_id: 1,
+                            safe_ts: 5,
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                };
+                Ok(Box::new(resp) as Box<dyn Any>)
+            },
+        )));
+        let options = TransactionOptions::new_optimistic()
+            .read_only()
+            .no_resolve_regions();
+        let mut txn = Transaction::new(Timestamp::default(), pd_client, options, logger);
+        match txn.batch_get(vec![vec![1]]).await {
+            Err(Error::RequestFailed { source, .. }) => match *source {
+                Error::DataIsNotReady { region_id, safe_ts } => {
+                    assert_eq!((region_id, safe_ts), (1, 5));
+                }
+                other => panic!("unexpected error: {:?}", other),
+            },
+            other => panic!("unexpected result: {:?}", other.map(|pairs| pairs.count())),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_commit_chunks() {
+        let logger = Logger::root(slog::Discard, o!());
+        let prewrites = Arc::new(Mutex::new(Vec::new()));
+        let commits = Arc::new(Mutex::new(Vec::new()));
+        let (prewrites_cloned, commits_cloned) = (prewrites.clone(), commits.clone());
         let pd_client = Arc::new(MockPdClient::new(MockKvClient::with_dispatch_hook(
             move |req: &dyn Any| {
-                if req.downcast_ref::<kvrpcpb::TxnHeartBeatRequest>().is_some() {
-                    heartbeats_cloned.fetch_add(1, Ordering::SeqCst);
-                    Ok(Box::new(kvrpcpb::TxnHeartBeatResponse::default()) as Box<dyn Any>)
-                } else if req.downcast_ref::<kvrpcpb::PrewriteRequest>().is_some() {
+                if let Some(req) = req.downcast_ref::<kvrpcpb::PrewriteRequest>() {
+                    // the secondary keys are only sent to commit asynchronously
+                    assert!(req.secondaries.is_empty());
+                    prewrites_cloned.lock().unwrap().push(req.mutations.len());
                     Ok(Box::new(kvrpcpb::PrewriteResponse::default()) as Box<dyn Any>)
-                } else if req
-                    .downcast_ref::<kvrpcpb::PessimisticLockRequest>()
-                    .is_some()
-                {
-                    Ok(Box::new(kvrpcpb::PessimisticLockResponse::default()) as Box<dyn Any>)
-                } else {
+                } else if let Some(req) = req.downcast_ref::<kvrpcpb::CommitRequest>() {
+                    commits_cloned.lock().unwrap().push(req.keys.len());
                     Ok(Box::new(kvrpcpb::CommitResponse::default()) as Box<dyn Any>)
+                } else {
+                    empty_response(req)
                 }
             },
         )));
-        let key1 = "key1".to_owned();
-        let mut heartbeat_txn = Transaction::new(
-            Timestamp::default(),
-            pd_client,
-            TransactionOptions::new_pessimistic()
-                .heartbeat_option(HeartbeatOption::FixedTime(Duration::from_secs(1))),
-            logger.new(o!("child" => 1)),
+        let options = TransactionOptions::new_optimistic()
+            .heartbeat_option(HeartbeatOption::NoHeartbeat)
+            .commit_chunk_size(2)
+            .commit_concurrency(1);
+        let mut txn = Transaction::new(Timestamp::default(), pd_client, options, logger);
+        for key in 1..=5u8 {
+            txn.put(vec![key], vec![key]).await.unwrap();
+        }
+        txn.commit().await.unwrap();
+
+        let mut sizes = prewrites.lock().unwrap().clone();
+        sizes.sort_unstable();
+        assert_eq!(sizes, vec![1, 2, 2]);
+        // the secondary keys are committed in the background, in chunks too
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while commits.lock().unwrap().len() < 3 {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(*commits.lock().unwrap(), vec![1, 2, 2]);
+    }
+
+    #[tokio::test]
+    async fn test_get_all_versions() {
+        let logger = Logger::root(slog::Discard, o!());
+        let pd_client = Arc::new(MockPdClient::new(MockKvClient::with_dispatch_hook(
+            |req: &dyn Any| {
+                let req = req
+                    .downcast_ref::<kvrpcpb::MvccGetByKeyRequest>()
+                    .expect("unexpected request");
+                assert_eq!(req.key, vec![1]);
+                let mut lock = kvrpcpb::MvccLock::default();
+                lock.set_type(kvrpcpb::Op::PessimisticLock);
+                lock.set_start_ts(7);
+                lock.set_primary(vec![2]);
+                let mut put = kvrpcpb::MvccWrite::default();
+                put.set_type(kvrpcpb::Op::Put);
+                put.set_start_ts(3);
+                put.set_commit_ts(4);
+                let mut rollback = kvrpcpb::MvccWrite::default();
+                rollback.set_type(kvrpcpb::Op::Rollback);
+                rollback.set_start_ts(5);
+                rollback.set_commit_ts(5);
+                let mut value = kvrpcpb::MvccValue::default();
+                value.set_start_ts(3);
+                value.set_value(b"value".to_vec());
+                let mut info = kvrpcpb::MvccInfo::default();
+                info.set_lock(lock);
+                info.set_writes(vec![rollback, put]);
+                info.set_values(vec![value]);
+                let mut resp = kvrpcpb::MvccGetByKeyResponse::default();
+                resp.set_info(info);
+                Ok(Box::new(resp) as Box<dyn Any>)
+            },
+        )));
+        let options = TransactionOptions::new_optimistic().drop_check(CheckLevel::None);
+        let mut txn = Transaction::new(Timestamp::default(), pd_client, options, logger);
+        let history = txn.get_all_versions(vec![1]).await.unwrap();
+        let lock = history.lock.as_ref().unwrap();
+        assert_eq!((lock.kind, lock.start_ts), (MvccKind::PessimisticLock, 7));
+        assert_eq!(lock.primary, Key::from(vec![2]));
+        assert_eq!(
+            history
+                .writes
+                .iter()
+                .map(|write| (write.kind, write.commit_ts))
+                .collect::<Vec<_>>(),
+            vec![(MvccKind::Rollback, 5), (MvccKind::Put, 4)]
         );
-        heartbeat_txn.put(key1.clone(), "foo").await.unwrap();
-        assert_eq!(heartbeats.load(Ordering::SeqCst), 0);
-        tokio::time::sleep(tokio::time::Duration::from_millis(1500)).await;
-        assert_eq!(heartbeats.load(Ordering::SeqCst), 1);
-        let heartbeat_txn_handle = tokio::spawn(async move {
-            assert!(heartbeat_txn.commit().await.is_ok());
-        });
-        heartbeat_txn_handle.await.unwrap();
-        Ok(())
+        assert_eq!(history.value_at(10), Some(b"value".to_vec()));
+        assert_eq!(history.value_at(3), None);
+    }
+
+    #[test]
+    fn test_is_txn_conflict() {
+        let write_conflict = || Error::WriteConflict {
+            key: b"key".to_vec(),
+            primary: b"key".to_vec(),
+            start_ts: 1,
+            conflict_ts: 2,
+            conflict_commit_ts: 3,
+        };
+        let mut locked = kvrpcpb::KeyError::default();
+        locked.set_locked(kvrpcpb::LockInfo::default());
+
+        assert!(super::is_txn_conflict(&write_conflict()));
+        assert!(super::is_txn_conflict(&Error::KeyError(locked.clone())));
+        assert!(super::is_txn_conflict(&Error::ResolveLockError));
+        assert!(super::is_txn_conflict(&Error::CommitFailed {
+            source: Box::new(Error::MultipleKeyErrors(vec![
+                write_conflict(),
+                Error::KeyError(locked),
+            ])),
+            operations: Vec::new(),
+        }));
+        assert!(!super::is_txn_conflict(&Error::MultipleKeyErrors(vec![
+            write_conflict(),
+            Error::KeyAlreadyExists {
+                key: b"key".to_vec()
+            },
+        ])));
+        assert!(!super::is_txn_conflict(&Error::UndeterminedError(
+            Box::new(write_conflict())
+        )));
     }
 }
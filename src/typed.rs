@@ -0,0 +1,437 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! Typed keys and values, behind the `typed` feature.
+//!
+//! A [`TypedRawClient<K, V>`](TypedRawClient) and a [`TypedTransaction<K, V>`](TypedTransaction)
+//! read and write keys of type `K` and values of type `V`, which are converted to and from bytes
+//! by their [`Codec`]. Data of different types is kept apart at compile time: a client typed for
+//! users can't write an order, nor read a user with the key of an order.
+//!
+//! Codecs are provided for bytes, strings, and for any type implementing the traits of serde,
+//! wrapped in [`Bincode`] or [`Json`].
+//!
+//! TiKV orders keys by their encoded bytes. The keys of different types share the key space of
+//! TiKV, so each type should have its own namespace, see
+//! [`RawClient::namespace`](crate::RawClient::namespace) and
+//! [`TransactionClient::namespace`](crate::TransactionClient::namespace), for their ranges not to
+//! overlap: a scan fails if it reads a key or a value which its codec can't decode.
+//!
+//! # Examples
+//!
+//! ```rust,no_run
+//! # use tikv_client::{typed::{Bincode, Json, TypedRawClient}, RawClient};
+//! # use futures::prelude::*;
+//! # use serde_derive::{Deserialize, Serialize};
+//! # futures::executor::block_on(async {
+//! #[derive(Deserialize, Serialize)]
+//! struct User {
+//!     name: String,
+//! }
+//!
+//! let client = RawClient::new(vec!["192.168.0.100"], None).await.unwrap();
+//! let users: TypedRawClient<Bincode<u64>, Json<User>> =
+//!     TypedRawClient::new(client.namespace("users"));
+//! let user = User { name: "alice".to_owned() };
+//! users.put(&Bincode(1), &Json(user)).await.unwrap();
+//! let user = users.get(&Bincode(1)).await.unwrap();
+//! # });
+//! ```
+
+use crate::{
+    pd::{PdClient, PdRpcClient},
+    BoundRange, Error, Key, KvPair, RawClient, Result, Timestamp, Transaction,
+};
+use bincode::Options;
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    marker::PhantomData,
+    ops::{Bound, RangeBounds},
+};
+
+/// Marks the key and value types of a typed client, without owning or borrowing any of them.
+type Types<K, V> = PhantomData<fn(K, V) -> (K, V)>;
+
+/// Converts keys or values to and from the bytes stored in TiKV.
+pub trait Codec: Sized {
+    /// The bytes stored for `self`.
+    fn encode(&self) -> Result<Vec<u8>>;
+
+    /// Reverse [`encode`](Codec::encode).
+    fn decode(bytes: Vec<u8>) -> Result<Self>;
+}
+
+/// Bytes are stored as they are.
+impl Codec for Vec<u8> {
+    fn encode(&self) -> Result<Vec<u8>> {
+        Ok(self.clone())
+    }
+
+    fn decode(bytes: Vec<u8>) -> Result<Self> {
+        Ok(bytes)
+    }
+}
+
+/// Strings are stored as UTF-8, so that string keys are ordered as their bytes.
+impl Codec for String {
+    fn encode(&self) -> Result<Vec<u8>> {
+        Ok(self.as_bytes().to_vec())
+    }
+
+    fn decode(bytes: Vec<u8>) -> Result<Self> {
+        String::from_utf8(bytes).map_err(codec_error)
+    }
+}
+
+/// A value stored in the [bincode](https://docs.rs/bincode) format.
+///
+/// Integers are encoded in big-endian with a fixed size, so that the keys made of unsigned
+/// integers, or of tuples or structs of unsigned integers, are ordered as their encoded bytes.
+/// Strings and sequences are prefixed with their length, so they are not.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct Bincode<T>(pub T);
+
+impl<T: Serialize + DeserializeOwned> Codec for Bincode<T> {
+    fn encode(&self) -> Result<Vec<u8>> {
+        bincode_options().serialize(&self.0).map_err(codec_error)
+    }
+
+    fn decode(bytes: Vec<u8>) -> Result<Self> {
+        bincode_options()
+            .deserialize(&bytes)
+            .map(Bincode)
+            .map_err(codec_error)
+    }
+}
+
+fn bincode_options() -> impl Options {
+    bincode::DefaultOptions::new()
+        .with_big_endian()
+        .with_fixint_encoding()
+}
+
+/// A value stored as JSON, e.g. to be readable by clients in other languages.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct Json<T>(pub T);
+
+impl<T: Serialize + DeserializeOwned> Codec for Json<T> {
+    fn encode(&self) -> Result<Vec<u8>> {
+        serde_json::to_vec(&self.0).map_err(codec_error)
+    }
+
+    fn decode(bytes: Vec<u8>) -> Result<Self> {
+        serde_json::from_slice(&bytes)
+            .map(Json)
+            .map_err(codec_error)
+    }
+}
+
+fn codec_error(e: impl std::fmt::Display) -> Error {
+    Error::CodecError {
+        message: e.to_string(),
+    }
+}
+
+/// A [`RawClient`] reading and writing keys of type `K` and values of type `V`, see the
+/// [module documentation](self).
+pub struct TypedRawClient<K, V, PdC: PdClient = PdRpcClient> {
+    client: RawClient<PdC>,
+    _types: Types<K, V>,
+}
+
+impl<K, V, PdC: PdClient> Clone for TypedRawClient<K, V, PdC> {
+    fn clone(&self) -> Self {
+        TypedRawClient {
+            client: self.client.clone(),
+            _types: PhantomData,
+        }
+    }
+}
+
+impl<K: Codec, V: Codec, PdC: PdClient> TypedRawClient<K, V, PdC> {
+    /// Read and write typed keys and values with `client`, with its options.
+    pub fn new(client: RawClient<PdC>) -> Self {
+        TypedRawClient {
+            client,
+            _types: PhantomData,
+        }
+    }
+
+    /// The underlying client, which reads and writes bytes.
+    pub fn inner(&self) -> &RawClient<PdC> {
+        &self.client
+    }
+
+    /// Get the value of `key`, see [`RawClient::get`].
+    pub async fn get(&self, key: &K) -> Result<Option<V>> {
+        self.client
+            .get(key.encode()?)
+            .await?
+            .map(V::decode)
+            .transpose()
+    }
+
+    /// Get the values of `keys`, see [`RawClient::batch_get`]. Non-existent keys are skipped.
+    pub async fn batch_get<'a>(&self, keys: impl IntoIterator<Item = &'a K>) -> Result<Vec<(K, V)>>
+    where
+        K: 'a,
+    {
+        let keys = keys
+            .into_iter()
+            .map(Codec::encode)
+            .collect::<Result<Vec<_>>>()?;
+        decode_pairs(self.client.batch_get(keys).await?)
+    }
+
+    /// Write `value` at `key`, see [`RawClient::put`].
+    pub async fn put(&self, key: &K, value: &V) -> Result<()> {
+        self.client.put(key.encode()?, value.encode()?).await
+    }
+
+    /// Write the values of `pairs`, see [`RawClient::batch_put`].
+    pub async fn batch_put<'a>(&self, pairs: impl IntoIterator<Item = (&'a K, &'a V)>) -> Result<()>
+    where
+        K: 'a,
+        V: 'a,
+    {
+        let pairs = pairs
+            .into_iter()
+            .map(|(key, value)| Ok((key.encode()?, value.encode()?)))
+            .collect::<Result<Vec<_>>>()?;
+        self.client.batch_put(pairs).await
+    }
+
+    /// Delete `key`, see [`RawClient::delete`].
+    pub async fn delete(&self, key: &K) -> Result<()> {
+        self.client.delete(key.encode()?).await
+    }
+
+    /// Delete `keys`, see [`RawClient::batch_delete`].
+    pub async fn batch_delete<'a>(&self, keys: impl IntoIterator<Item = &'a K>) -> Result<()>
+    where
+        K: 'a,
+    {
+        let keys = keys
+            .into_iter()
+            .map(Codec::encode)
+            .collect::<Result<Vec<_>>>()?;
+        self.client.batch_delete(keys).await
+    }
+
+    /// Read up to `limit` pairs in `range`, in the order of their encoded keys, see
+    /// [`RawClient::scan`].
+    pub async fn scan(&self, range: impl RangeBounds<K>, limit: u32) -> Result<Vec<(K, V)>> {
+        decode_pairs(self.client.scan(encode_range(range)?, limit).await?)
+    }
+}
+
+/// A [`Transaction`] reading and writing keys of type `K` and values of type `V`, see the
+/// [module documentation](self).
+///
+/// Use [`into_inner`](TypedTransaction::into_inner) and [`new`](TypedTransaction::new) to read
+/// and write other types in the same transaction.
+pub struct TypedTransaction<K, V, PdC: PdClient = PdRpcClient> {
+    txn: Transaction<PdC>,
+    _types: Types<K, V>,
+}
+
+impl<K: Codec, V: Codec, PdC: PdClient> TypedTransaction<K, V, PdC> {
+    /// Read and write typed keys and values in `txn`.
+    pub fn new(txn: Transaction<PdC>) -> Self {
+        TypedTransaction {
+            txn,
+            _types: PhantomData,
+        }
+    }
+
+    /// The underlying transaction, which reads and writes bytes.
+    pub fn inner_mut(&mut self) -> &mut Transaction<PdC> {
+        &mut self.txn
+    }
+
+    /// Unwrap the underlying transaction.
+    pub fn into_inner(self) -> Transaction<PdC> {
+        self.txn
+    }
+
+    /// Get the value of `key`, see [`Transaction::get`].
+    pub async fn get(&mut self, key: &K) -> Result<Option<V>> {
+        self.txn
+            .get(key.encode()?)
+            .await?
+            .map(V::decode)
+            .transpose()
+    }
+
+    /// Get the values of `keys`, see [`Transaction::batch_get`]. Non-existent keys are skipped.
+    pub async fn batch_get<'a>(
+        &mut self,
+        keys: impl IntoIterator<Item = &'a K>,
+    ) -> Result<Vec<(K, V)>>
+    where
+        K: 'a,
+    {
+        let keys = keys
+            .into_iter()
+            .map(Codec::encode)
+            .collect::<Result<Vec<_>>>()?;
+        decode_pairs(self.txn.batch_get(keys).await?)
+    }
+
+    /// Write `value` at `key`, see [`Transaction::put`].
+    pub async fn put(&mut self, key: &K, value: &V) -> Result<()> {
+        self.txn.put(key.encode()?, value.encode()?).await
+    }
+
+    /// Delete `key`, see [`Transaction::delete`].
+    pub async fn delete(&mut self, key: &K) -> Result<()> {
+        self.txn.delete(key.encode()?).await
+    }
+
+    /// Read up to `limit` pairs in `range`, in the order of their encoded keys, see
+    /// [`Transaction::scan`].
+    pub async fn scan(&mut self, range: impl RangeBounds<K>, limit: u32) -> Result<Vec<(K, V)>> {
+        decode_pairs(self.txn.scan(encode_range(range)?, limit).await?)
+    }
+
+    /// Commit the transaction, see [`Transaction::commit`].
+    pub async fn commit(&mut self) -> Result<Option<Timestamp>> {
+        self.txn.commit().await
+    }
+
+    /// Roll back the transaction, see [`Transaction::rollback`].
+    pub async fn rollback(&mut self) -> Result<()> {
+        self.txn.rollback().await
+    }
+}
+
+fn encode_range<K: Codec>(range: impl RangeBounds<K>) -> Result<BoundRange> {
+    let encode = |bound: Bound<&K>| -> Result<Bound<Key>> {
+        Ok(match bound {
+            Bound::Included(key) => Bound::Included(key.encode()?.into()),
+            Bound::Excluded(key) => Bound::Excluded(key.encode()?.into()),
+            Bound::Unbounded => Bound::Unbounded,
+        })
+    };
+    Ok(BoundRange::from((
+        encode(range.start_bound())?,
+        encode(range.end_bound())?,
+    )))
+}
+
+fn decode_pairs<K: Codec, V: Codec>(
+    pairs: impl IntoIterator<Item = KvPair>,
+) -> Result<Vec<(K, V)>> {
+    pairs
+        .into_iter()
+        .map(|pair| {
+            let (key, value): (Key, Vec<u8>) = pair.into();
+            Ok((K::decode(key.into())?, V::decode(value)?))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        mock::{MockKvClient, MockPdClient},
+        CheckLevel, TransactionOptions,
+    };
+    use serde_derive::{Deserialize, Serialize};
+    use slog::Logger;
+    use std::{any::Any, sync::Arc};
+    use tikv_client_proto::kvrpcpb;
+
+    #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+    struct User {
+        name: String,
+        age: u8,
+    }
+
+    #[test]
+    fn test_codecs() {
+        let user = User {
+            name: "alice".to_owned(),
+            age: 42,
+        };
+        let bytes = Json(user.clone()).encode().unwrap();
+        assert_eq!(bytes, br#"{"name":"alice","age":42}"#.to_vec());
+        assert_eq!(Json::<User>::decode(bytes).unwrap().0, user);
+        let bytes = Bincode(user.clone()).encode().unwrap();
+        assert_eq!(Bincode::<User>::decode(bytes).unwrap().0, user);
+
+        // the keys of unsigned integers are ordered as their bytes
+        let keys = [(0u32, 300u64), (1, 2), (1, 256), (256, 0)];
+        let encoded: Vec<Vec<u8>> = keys.iter().map(|k| Bincode(*k).encode().unwrap()).collect();
+        assert!(encoded.windows(2).all(|w| w[0] < w[1]));
+
+        assert!(matches!(
+            Json::<User>::decode(b"{}".to_vec()),
+            Err(Error::CodecError { .. })
+        ));
+        assert!(matches!(
+            String::decode(vec![0xff]),
+            Err(Error::CodecError { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_typed_transaction() {
+        // nothing is stored in TiKV, everything is read from the buffer of the transaction
+        let pd_client = Arc::new(MockPdClient::new(MockKvClient::with_dispatch_hook(
+            |req: &dyn Any| {
+                if req.is::<kvrpcpb::GetRequest>() {
+                    let resp = kvrpcpb::GetResponse {
+                        not_found: true,
+                        ..Default::default()
+                    };
+                    Ok(Box::new(resp) as Box<dyn Any>)
+                } else if req.is::<kvrpcpb::ScanRequest>() {
+                    Ok(Box::new(kvrpcpb::ScanResponse::default()) as Box<dyn Any>)
+                } else {
+                    unreachable!()
+                }
+            },
+        )));
+        let txn = Transaction::new(
+            Timestamp::default(),
+            pd_client,
+            TransactionOptions::new_optimistic().drop_check(CheckLevel::None),
+            Logger::root(slog::Discard, o!()),
+        );
+        let mut users: TypedTransaction<Bincode<u64>, Json<User>, _> = TypedTransaction::new(txn);
+        let alice = User {
+            name: "alice".to_owned(),
+            age: 42,
+        };
+        let bob = User {
+            name: "bob".to_owned(),
+            age: 7,
+        };
+
+        users.put(&Bincode(1), &Json(alice.clone())).await.unwrap();
+        users.put(&Bincode(2), &Json(bob.clone())).await.unwrap();
+        users.put(&Bincode(300), &Json(bob.clone())).await.unwrap();
+        assert_eq!(
+            users.get(&Bincode(1)).await.unwrap(),
+            Some(Json(alice.clone()))
+        );
+        assert_eq!(users.get(&Bincode(3)).await.unwrap(), None);
+
+        let scanned = users.scan(Bincode(1)..Bincode(300), 10).await.unwrap();
+        assert_eq!(
+            scanned,
+            vec![(Bincode(1), Json(alice)), (Bincode(2), Json(bob))]
+        );
+
+        // other types can't be decoded as users
+        users.delete(&Bincode(2)).await.unwrap();
+        let mut txn = users.into_inner();
+        txn.put(vec![0xff], vec![0xff]).await.unwrap();
+        let mut users: TypedTransaction<Bincode<u64>, Json<User>, _> = TypedTransaction::new(txn);
+        assert!(matches!(
+            users.scan(.., 10).await,
+            Err(Error::CodecError { .. })
+        ));
+    }
+}
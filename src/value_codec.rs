@@ -0,0 +1,386 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! Client-side transformations of values.
+//!
+//! A [`ValueCodec`] is applied to values before they are written to TiKV, and reversed on values
+//! read from TiKV. Use [`RawClient::with_value_codec`](crate::RawClient::with_value_codec) to
+//! enable a codec.
+//!
+//! All values written through a codec must be read through the same codec.
+//!
+//! The codecs provided are [`Compression`] and, with the `encryption` feature, `Encryption`.
+//! Codecs are combined with [`ValueCodec::then`], e.g., to compress values before encrypting them.
+
+use crate::{Error, Key, Result, Value};
+
+/// Transforms values on their way to and from TiKV.
+///
+/// The key of a value is passed along with it, as the client sees it, i.e. before it is prefixed
+/// with a keyspace or namespace.
+pub trait ValueCodec: Send + Sync + 'static {
+    /// Transform the value of `key` before it is written to TiKV.
+    fn encode(&self, key: &Key, value: Value) -> Result<Value>;
+
+    /// Reverse [`encode`](ValueCodec::encode) on the value of `key` read from TiKV.
+    fn decode(&self, key: &Key, value: Value) -> Result<Value>;
+
+    /// A codec which encodes values with `self` and then with `next`, and decodes them in the
+    /// reverse order.
+    ///
+    /// # Examples
+    /// ```rust,ignore
+    /// # use tikv_client::value_codec::{Compression, CompressionAlgorithm, Encryption, ValueCodec};
+    /// // encrypted values don't compress, so compress them first
+    /// let codec = Compression::new(CompressionAlgorithm::Lz4, 1024).then(Encryption::new(keys));
+    /// ```
+    fn then<C: ValueCodec>(self, next: C) -> Chain<Self, C>
+    where
+        Self: Sized,
+    {
+        Chain { first: self, next }
+    }
+}
+
+/// A [`ValueCodec`] which applies two codecs in turn, see [`ValueCodec::then`].
+#[derive(Clone, Debug)]
+pub struct Chain<A, B> {
+    first: A,
+    next: B,
+}
+
+impl<A: ValueCodec, B: ValueCodec> ValueCodec for Chain<A, B> {
+    fn encode(&self, key: &Key, value: Value) -> Result<Value> {
+        let value = self.first.encode(key, value)?;
+        self.next.encode(key, value)
+    }
+
+    fn decode(&self, key: &Key, value: Value) -> Result<Value> {
+        let value = self.next.decode(key, value)?;
+        self.first.decode(key, value)
+    }
+}
+
+// The first byte of a value written by `Compression` tells how the rest of the value is encoded.
+const FORMAT_UNCOMPRESSED: u8 = 0;
+const FORMAT_LZ4: u8 = 1;
+const FORMAT_ZSTD: u8 = 2;
+
+/// A compression algorithm for [`Compression`].
+///
+/// Each algorithm is only available if the corresponding feature of this crate is enabled.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CompressionAlgorithm {
+    /// LZ4, requires the `compression-lz4` feature.
+    #[cfg(feature = "compression-lz4")]
+    Lz4,
+    /// Zstandard with the given compression level, requires the `compression-zstd` feature.
+    #[cfg(feature = "compression-zstd")]
+    Zstd { level: i32 },
+}
+
+/// A [`ValueCodec`] which compresses values larger than a threshold.
+///
+/// Every encoded value is prefixed with a format byte, so values compressed with different
+/// algorithms, or not compressed at all, can be read back by any `Compression` codec as long as
+/// the features for their algorithms are enabled.
+#[derive(Clone, Debug)]
+pub struct Compression {
+    algorithm: Option<CompressionAlgorithm>,
+    threshold: usize,
+}
+
+impl Compression {
+    /// Compress values of at least `threshold` bytes with `algorithm`. Smaller values are stored
+    /// uncompressed.
+    pub fn new(algorithm: CompressionAlgorithm, threshold: usize) -> Compression {
+        Compression {
+            algorithm: Some(algorithm),
+            threshold,
+        }
+    }
+
+    /// A codec which never compresses values, but can read compressed values.
+    pub fn uncompressed() -> Compression {
+        Compression {
+            algorithm: None,
+            threshold: usize::MAX,
+        }
+    }
+}
+
+impl ValueCodec for Compression {
+    fn encode(&self, _key: &Key, value: Value) -> Result<Value> {
+        let algorithm = match self.algorithm {
+            Some(algorithm) if value.len() >= self.threshold => algorithm,
+            _ => return Ok(with_format(FORMAT_UNCOMPRESSED, &value)),
+        };
+        match algorithm {
+            #[cfg(feature = "compression-lz4")]
+            CompressionAlgorithm::Lz4 => Ok(with_format(
+                FORMAT_LZ4,
+                &lz4_flex::compress_prepend_size(&value),
+            )),
+            #[cfg(feature = "compression-zstd")]
+            CompressionAlgorithm::Zstd { level } => {
+                let compressed = zstd::encode_all(value.as_slice(), level)?;
+                Ok(with_format(FORMAT_ZSTD, &compressed))
+            }
+        }
+    }
+
+    fn decode(&self, _key: &Key, value: Value) -> Result<Value> {
+        let (&format, data) = value.split_first().ok_or_else(|| Error::ValueCodecError {
+            message: "missing format byte".to_owned(),
+        })?;
+        match format {
+            FORMAT_UNCOMPRESSED => Ok(data.to_vec()),
+            #[cfg(feature = "compression-lz4")]
+            FORMAT_LZ4 => {
+                lz4_flex::decompress_size_prepended(data).map_err(|e| Error::ValueCodecError {
+                    message: e.to_string(),
+                })
+            }
+            #[cfg(feature = "compression-zstd")]
+            FORMAT_ZSTD => Ok(zstd::decode_all(data)?),
+            #[cfg(not(feature = "compression-lz4"))]
+            FORMAT_LZ4 => Err(format_not_enabled(format)),
+            #[cfg(not(feature = "compression-zstd"))]
+            FORMAT_ZSTD => Err(format_not_enabled(format)),
+            _ => Err(Error::ValueCodecError {
+                message: format!("unknown compression format {}", format),
+            }),
+        }
+    }
+}
+
+/// Provides the keys for [`Encryption`].
+///
+/// Keys are identified by an id, which is stored with each encrypted value. This allows keys to
+/// be rotated: new values are encrypted with the current key, and values encrypted with older keys
+/// can still be decrypted as long as the provider can return those keys.
+pub trait KeyProvider: Send + Sync + 'static {
+    /// The id and the content of the key to encrypt new values with.
+    fn current_key(&self) -> Result<(u32, [u8; 32])>;
+
+    /// The content of the key with the given id.
+    fn key(&self, id: u32) -> Result<[u8; 32]>;
+}
+
+#[cfg(feature = "encryption")]
+const ENCRYPTION_VERSION: u8 = 1;
+#[cfg(feature = "encryption")]
+const NONCE_LEN: usize = 12;
+// version (1 byte) + key id (4 bytes) + nonce
+#[cfg(feature = "encryption")]
+const ENCRYPTION_HEADER_LEN: usize = 5 + NONCE_LEN;
+
+/// A [`ValueCodec`] which encrypts values with AES-256-GCM, requires the `encryption` feature.
+///
+/// Each value is encrypted with a random nonce, so encrypting the same value twice gives different
+/// results. Therefore values compared by TiKV, such as the previous value of
+/// [`compare_and_swap`](crate::RawClient::compare_and_swap), never match encrypted values.
+///
+/// The key of each value is authenticated along with it, so a value copied to another key fails
+/// to decrypt.
+#[cfg(feature = "encryption")]
+pub struct Encryption<P: KeyProvider> {
+    provider: P,
+}
+
+#[cfg(feature = "encryption")]
+impl<P: KeyProvider> Encryption<P> {
+    pub fn new(provider: P) -> Encryption<P> {
+        Encryption { provider }
+    }
+}
+
+#[cfg(feature = "encryption")]
+impl<P: KeyProvider> ValueCodec for Encryption<P> {
+    fn encode(&self, key: &Key, value: Value) -> Result<Value> {
+        use aes_gcm::aead::{Aead, NewAead, Payload};
+
+        let (key_id, secret) = self.provider.current_key()?;
+        let nonce: [u8; NONCE_LEN] = rand::random();
+        let cipher = aes_gcm::Aes256Gcm::new(aes_gcm::Key::from_slice(&secret));
+        let payload = Payload {
+            msg: &value,
+            aad: key.into(),
+        };
+        let ciphertext = cipher
+            .encrypt(aes_gcm::Nonce::from_slice(&nonce), payload)
+            .map_err(|_| Error::ValueCodecError {
+                message: "failed to encrypt value".to_owned(),
+            })?;
+
+        let mut encrypted = Vec::with_capacity(ENCRYPTION_HEADER_LEN + ciphertext.len());
+        encrypted.push(ENCRYPTION_VERSION);
+        encrypted.extend_from_slice(&key_id.to_be_bytes());
+        encrypted.extend_from_slice(&nonce);
+        encrypted.extend_from_slice(&ciphertext);
+        Ok(encrypted)
+    }
+
+    fn decode(&self, key: &Key, value: Value) -> Result<Value> {
+        use aes_gcm::aead::{Aead, NewAead, Payload};
+        use std::convert::TryInto;
+
+        if value.len() < ENCRYPTION_HEADER_LEN || value[0] != ENCRYPTION_VERSION {
+            return Err(Error::ValueCodecError {
+                message: "invalid encrypted value".to_owned(),
+            });
+        }
+        let key_id = u32::from_be_bytes(value[1..5].try_into().unwrap());
+        let secret = self.provider.key(key_id)?;
+        let cipher = aes_gcm::Aes256Gcm::new(aes_gcm::Key::from_slice(&secret));
+        let payload = Payload {
+            msg: &value[ENCRYPTION_HEADER_LEN..],
+            aad: key.into(),
+        };
+        cipher
+            .decrypt(
+                aes_gcm::Nonce::from_slice(&value[5..ENCRYPTION_HEADER_LEN]),
+                payload,
+            )
+            .map_err(|_| Error::ValueCodecError {
+                message: format!("failed to decrypt value with key {}", key_id),
+            })
+    }
+}
+
+#[allow(dead_code)]
+fn format_not_enabled(format: u8) -> Error {
+    Error::ValueCodecError {
+        message: format!("compression format {} is not enabled", format),
+    }
+}
+
+fn with_format(format: u8, data: &[u8]) -> Value {
+    let mut value = Vec::with_capacity(data.len() + 1);
+    value.push(format);
+    value.extend_from_slice(data);
+    value
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_uncompressed() {
+        let key = Key::from(b"key".to_vec());
+        let codec = Compression::uncompressed();
+        let encoded = codec.encode(&key, b"value".to_vec()).unwrap();
+        assert_eq!(encoded, b"\x00value".to_vec());
+        assert_eq!(codec.decode(&key, encoded).unwrap(), b"value".to_vec());
+        assert!(codec.decode(&key, vec![]).is_err());
+        assert!(codec.decode(&key, vec![42]).is_err());
+    }
+
+    #[test]
+    fn test_chain() {
+        struct Append(u8);
+
+        impl ValueCodec for Append {
+            fn encode(&self, _key: &Key, mut value: Value) -> Result<Value> {
+                value.push(self.0);
+                Ok(value)
+            }
+
+            fn decode(&self, _key: &Key, mut value: Value) -> Result<Value> {
+                match value.pop() {
+                    Some(byte) if byte == self.0 => Ok(value),
+                    _ => Err(Error::ValueCodecError {
+                        message: format!("missing {}", self.0),
+                    }),
+                }
+            }
+        }
+
+        let key = Key::from(b"key".to_vec());
+        let codec = Append(1).then(Append(2)).then(Compression::uncompressed());
+        let encoded = codec.encode(&key, b"value".to_vec()).unwrap();
+        assert_eq!(encoded, b"\x00value\x01\x02".to_vec());
+        assert_eq!(codec.decode(&key, encoded).unwrap(), b"value".to_vec());
+
+        // the codecs are undone in the reverse order
+        assert!(codec.decode(&key, b"\x00value\x02\x01".to_vec()).is_err());
+    }
+
+    #[cfg(feature = "compression-lz4")]
+    #[test]
+    fn test_lz4() {
+        let key = Key::from(b"key".to_vec());
+        let codec = Compression::new(CompressionAlgorithm::Lz4, 16);
+        let small = b"small".to_vec();
+        assert_eq!(codec.encode(&key, small).unwrap()[0], FORMAT_UNCOMPRESSED);
+
+        let large = vec![7; 1024];
+        let encoded = codec.encode(&key, large.clone()).unwrap();
+        assert_eq!(encoded[0], FORMAT_LZ4);
+        assert!(encoded.len() < large.len());
+        assert_eq!(codec.decode(&key, encoded.clone()).unwrap(), large);
+        assert_eq!(
+            Compression::uncompressed().decode(&key, encoded).unwrap(),
+            large
+        );
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn test_encryption() {
+        struct TestKeys;
+
+        impl KeyProvider for TestKeys {
+            fn current_key(&self) -> Result<(u32, [u8; 32])> {
+                Ok((2, [2; 32]))
+            }
+
+            fn key(&self, id: u32) -> Result<[u8; 32]> {
+                match id {
+                    1 | 2 => Ok([id as u8; 32]),
+                    _ => Err(Error::ValueCodecError {
+                        message: "unknown key".to_owned(),
+                    }),
+                }
+            }
+        }
+
+        let key = Key::from(b"key".to_vec());
+        let codec = Encryption::new(TestKeys);
+        let value = b"secret".to_vec();
+        let encrypted = codec.encode(&key, value.clone()).unwrap();
+        assert_ne!(encrypted[ENCRYPTION_HEADER_LEN..], value[..]);
+        assert_ne!(codec.encode(&key, value.clone()).unwrap(), encrypted);
+        assert_eq!(codec.decode(&key, encrypted.clone()).unwrap(), value);
+
+        let mut tampered = encrypted.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(codec.decode(&key, tampered).is_err());
+
+        // the value can't be moved to another key
+        let other_key = Key::from(b"other".to_vec());
+        assert!(codec.decode(&other_key, encrypted.clone()).is_err());
+
+        let mut unknown_key = encrypted;
+        unknown_key[4] = 3;
+        assert!(codec.decode(&key, unknown_key).is_err());
+
+        // compressed values are encrypted
+        let codec = Compression::uncompressed().then(Encryption::new(TestKeys));
+        let encrypted = codec.encode(&key, value.clone()).unwrap();
+        assert_eq!(encrypted[0], ENCRYPTION_VERSION);
+        assert_eq!(codec.decode(&key, encrypted).unwrap(), value);
+    }
+
+    #[cfg(feature = "compression-zstd")]
+    #[test]
+    fn test_zstd() {
+        let key = Key::from(b"key".to_vec());
+        let codec = Compression::new(CompressionAlgorithm::Zstd { level: 3 }, 16);
+        let large = vec![7; 1024];
+        let encoded = codec.encode(&key, large.clone()).unwrap();
+        assert_eq!(encoded[0], FORMAT_ZSTD);
+        assert!(encoded.len() < large.len());
+        assert_eq!(codec.decode(&key, encoded).unwrap(), large);
+    }
+}
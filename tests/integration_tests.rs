@@ -760,7 +760,7 @@ async fn txn_pessimistic_heartbeat() -> Result<()> {
     Ok(())
 }
 
-// It tests very basic functionality of atomic operations (put, cas, delete).
+// It tests very basic functionality of atomic operations (put, cas, delete, batch delete).
 #[tokio::test]
 #[serial]
 async fn raw_cas() -> Result<()> {
@@ -805,9 +805,13 @@ async fn raw_cas() -> Result<()> {
     client.delete(key.clone()).await?;
     assert!(client.get(key.clone()).await?.is_none());
 
+    client.put(key.clone(), value.to_owned()).await?;
+    client.batch_delete(vec![key.clone()]).await?;
+    assert!(client.get(key.clone()).await?.is_none());
+
     // check unsupported operations
     assert!(matches!(
-        client.batch_delete(vec![key.clone()]).await.err().unwrap(),
+        client.delete_range(key.clone()..).await.err().unwrap(),
         Error::UnsupportedMode
     ));
     let client = RawClient::new(pd_addrs(), None).await?;
@@ -3,11 +3,13 @@ mod test {
     use grpcio::redirect_log;
     use log::debug;
     use mock_tikv::{start_mock_pd_server, start_mock_tikv_server, MOCK_PD_PORT};
+    use serial_test::serial;
     use simple_logger::SimpleLogger;
-    use tikv_client::{KvPair, RawClient};
+    use tikv_client::{Config, KvPair, RawClient};
 
     #[tokio::test]
     #[ignore]
+    #[serial]
     async fn test_raw_put_get() {
         SimpleLogger::new().init().unwrap();
         redirect_log();
@@ -81,4 +83,37 @@ mod test {
         // FIXME: shutdown PD server
         // let _ = pd_server.shutdown().await;
     }
+
+    #[tokio::test]
+    #[ignore]
+    #[serial]
+    async fn test_raw_batch_commands() {
+        let mut tikv_server = start_mock_tikv_server();
+        let _pd_server = start_mock_pd_server();
+
+        let config = Config::default().with_batch_commands(16);
+        let client =
+            RawClient::new_with_config(vec![format!("localhost:{}", MOCK_PD_PORT)], config, None)
+                .await
+                .unwrap();
+
+        // the requests are multiplexed over the batch commands stream of the store
+        let puts = (0..32).map(|i| client.put(format!("k{}", i), format!("v{}", i)));
+        for res in futures::future::join_all(puts).await {
+            res.unwrap();
+        }
+        let gets = (0..32).map(|i| client.get(format!("k{}", i)));
+        let values = futures::future::join_all(gets).await;
+        for (i, res) in values.into_iter().enumerate() {
+            assert_eq!(res.unwrap(), Some(format!("v{}", i).into_bytes()));
+        }
+
+        let res = client
+            .batch_get(vec!["k1".to_owned(), "k2".to_owned(), "k99".to_owned()])
+            .await
+            .unwrap();
+        assert_eq!(res.len(), 2);
+
+        let _ = tikv_server.shutdown().await;
+    }
 }
@@ -1,7 +1,9 @@
 // Copyright 2018 TiKV Project Authors. Licensed under Apache-2.0.
 
-use std::result;
+use crate::{Assertion, LockInfo};
+use std::{fmt, result, time::Duration};
 use thiserror::Error;
+use tikv_client_proto::{deadlock, errorpb};
 
 /// An error originating from the TiKV client or dependencies.
 #[derive(Debug, Error)]
@@ -13,6 +15,12 @@ pub enum Error {
     /// Duplicate key insertion happens.
     #[error("Duplicate key insertion")]
     DuplicateKeyInsertion,
+    /// A protobuf mutation which a transaction can't buffer, see `Mutation`.
+    #[error("Unsupported mutation of key {:?}: {}", key, message)]
+    UnsupportedMutation { key: Vec<u8>, message: String },
+    /// A key written was rejected by a key validator, see `Config::with_key_validator`.
+    #[error("Key {:?} rejected: {}", key, reason)]
+    KeyRejected { key: Vec<u8>, reason: String },
     /// Failed to resolve a lock
     #[error("Failed to resolve lock")]
     ResolveLockError,
@@ -22,6 +30,17 @@ pub enum Error {
     /// It's not allowed to perform operations in a transaction after it has been committed or rolled back.
     #[error("Cannot read or write data after any attempt to commit or roll back the transaction")]
     OperationAfterCommitError,
+    /// A transaction was rolled back to a savepoint it didn't take, or which was discarded by a
+    /// rollback to an earlier savepoint.
+    #[error("The savepoint is not a savepoint of the transaction")]
+    InvalidSavepoint,
+    /// A pessimistic transaction exceeded its maximum lifetime and was rolled back, see
+    /// `TransactionOptions::max_lifetime`.
+    #[error(
+        "Transaction exceeded its maximum lifetime of {:?} and was rolled back",
+        max_lifetime
+    )]
+    TransactionExpired { max_lifetime: std::time::Duration },
     /// We tried to use 1pc for a transaction, but it didn't work. Probably should have used 2pc.
     #[error("1PC transaction could not be committed.")]
     OnePcFailure,
@@ -52,9 +71,121 @@ pub enum Error {
     /// Whether the transaction is committed or not is undetermined
     #[error("Whether the transaction is committed or not is undetermined")]
     UndeterminedError(Box<Error>),
-    /// Wraps `tikv_client_proto::kvrpcpb::KeyError`
+    /// The commit of a transaction recording its operations failed with `source`, see
+    /// `TransactionOptions::record_operations`. `operations` are the operations performed on the
+    /// transaction, in order.
+    #[error("{} (operations: {})", source, operations.join(", "))]
+    CommitFailed {
+        source: Box<Error>,
+        operations: Vec<String>,
+    },
+    /// Wraps `tikv_client_proto::kvrpcpb::KeyError`, for the key errors which are not reported
+    /// as one of the variants below, e.g. locks and `abort` messages.
     #[error("{0:?}")]
     KeyError(tikv_client_proto::kvrpcpb::KeyError),
+    /// An inserted key already exists in TiKV.
+    #[error("Key {:?} already exists", key)]
+    KeyAlreadyExists { key: Vec<u8> },
+    /// The assertion of a mutation about the existence of its key does not hold, see
+    /// `Transaction::put_with_assertion`. `written_by` is the operation of the transaction which
+    /// wrote the key last, if the transaction records its operations, see
+    /// `TransactionOptions::record_operations`.
+    #[error(
+        "Assertion {:?} on key {:?} failed, the key was written by the transaction started at {} \
+         and committed at {}{}",
+        assertion,
+        key,
+        existing_start_ts,
+        existing_commit_ts,
+        written_by
+            .as_ref()
+            .map(|origin| format!(", the mutation was buffered by {}", origin))
+            .unwrap_or_default()
+    )]
+    AssertionFailed {
+        key: Vec<u8>,
+        assertion: Assertion,
+        start_ts: u64,
+        existing_start_ts: u64,
+        existing_commit_ts: u64,
+        written_by: Option<WriteOrigin>,
+    },
+    /// Another transaction committed a write to the key after the `start_ts` or
+    /// `for_update_ts` of the transaction.
+    #[error(
+        "Write conflict on key {:?}, the transaction started at {} conflicts with the one \
+         started at {} and committed at {}",
+        key,
+        start_ts,
+        conflict_ts,
+        conflict_commit_ts
+    )]
+    WriteConflict {
+        key: Vec<u8>,
+        primary: Vec<u8>,
+        start_ts: u64,
+        conflict_ts: u64,
+        conflict_commit_ts: u64,
+    },
+    /// A pessimistic lock would deadlock with other transactions waiting for locks, so it was
+    /// rejected. `wait_chain` is the cycle of transactions waiting for each other, as detected by
+    /// TiKV, empty if TiKV doesn't report it.
+    #[error(
+        "Deadlock on key {:?} locked by the transaction started at {}, wait chain: [{}]",
+        lock_key,
+        lock_ts,
+        wait_chain.iter().map(ToString::to_string).collect::<Vec<_>>().join(", ")
+    )]
+    Deadlock {
+        lock_key: Vec<u8>,
+        lock_ts: u64,
+        deadlock_key_hash: u64,
+        wait_chain: Vec<WaitForEntry>,
+    },
+    /// The transaction has no lock or write of its primary key.
+    #[error(
+        "Transaction started at {} not found, primary key {:?}",
+        start_ts,
+        primary_key
+    )]
+    TxnNotFound { start_ts: u64, primary_key: Vec<u8> },
+    /// The commit timestamp is less than the `min_commit_ts` of the lock, which was pushed by a
+    /// reader.
+    #[error(
+        "Commit of key {:?} at {} is earlier than the min commit ts {}",
+        key,
+        attempted_commit_ts,
+        min_commit_ts
+    )]
+    CommitTsExpired {
+        key: Vec<u8>,
+        start_ts: u64,
+        attempted_commit_ts: u64,
+        min_commit_ts: u64,
+    },
+    /// The start timestamp of a transaction verifying its commit timestamp is before the GC safe
+    /// point, so versions it read may have been garbage collected, see
+    /// `TransactionOptions::external_consistency`.
+    #[error(
+        "Transaction started at {} is before the GC safe point {}",
+        start_ts,
+        safe_point
+    )]
+    TxnTooOld { start_ts: u64, safe_point: u64 },
+    /// The commit timestamp of a transaction verifying its commit timestamp is earlier than
+    /// `min_commit_ts`, which is after its start and `for_update_ts` and not before the timestamp
+    /// fetched when its commit started, see `TransactionOptions::external_consistency`.
+    #[error(
+        "Commit timestamp {} of the transaction started at {} is earlier than {}",
+        commit_ts,
+        start_ts,
+        min_commit_ts
+    )]
+    InvalidCommitTs {
+        start_ts: u64,
+        commit_ts: u64,
+        min_commit_ts: u64,
+    },
     /// Multiple errors generated from the ExtractError plan.
     #[error("Multiple errors: {0:?}")]
     ExtractedErrors(Vec<Error>),
@@ -79,8 +210,113 @@ pub enum Error {
     /// Scan limit exceeds the maximum
     #[error("Limit {} exceeds max scan limit {}", limit, max_limit)]
     MaxScanLimitExceeded { limit: u32, max_limit: u32 },
+    /// The result of a request exceeds the byte limit of the client, see
+    /// `RawClient::with_max_result_bytes`. The part of the request before `resume_key` fits in
+    /// the limit, and the request can be continued at `resume_key`.
+    #[error("Result exceeds {} bytes, resume at key {:?}", max_bytes, resume_key)]
+    ResultTooLarge {
+        max_bytes: usize,
+        resume_key: Vec<u8>,
+    },
+    /// A request to TiKV took longer than the timeout set in its options.
+    #[error("Request timed out after {:?}", timeout)]
+    RequestTimeout { timeout: std::time::Duration },
+    /// A request to TiKV, including its retries, did not complete by the deadline set in its
+    /// options, see `RawOptions::deadline` and `TransactionOptions::deadline`.
+    #[error("Request did not complete within the deadline of {:?}", timeout)]
+    DeadlineExceeded { timeout: std::time::Duration },
+    /// The retries of a request are exhausted, with `source` as the last error. Only returned
+    /// instead of the last error if requested, see `OnRetriesExhausted::History`. `history` holds
+    /// the errors of all `attempts`, in order.
+    #[error("Retries exhausted after {} attempts: {}", attempts, source)]
+    RetriesExhausted {
+        source: Box<Error>,
+        attempts: u32,
+        history: Vec<String>,
+    },
+    /// A request to a region failed with `source`, see `FailedRequest`. Every error of a request
+    /// to a region, once its retries if any are exhausted, carries the request this way, see
+    /// [`without_request`](Error::without_request) to match on `source`.
+    #[error("{} ({})", source, request)]
+    RequestFailed {
+        source: Box<Error>,
+        request: FailedRequest,
+    },
+    /// The checksum of pairs read by a raw client does not match the checksum of the pairs
+    /// computed by TiKV, see `RawOptions::verify_checksums`.
+    #[error(
+        "Checksum {:x} of the pairs read does not match checksum {:x} computed by TiKV",
+        read,
+        stored
+    )]
+    ChecksumMismatch { read: u64, stored: u64 },
+    /// A key written by the client is larger than the maximum key size of its `SizeLimits`.
+    #[error(
+        "Key of {} bytes exceeds the maximum key size of {} bytes",
+        size,
+        limit
+    )]
+    KeyTooLarge {
+        key: Vec<u8>,
+        size: usize,
+        limit: usize,
+    },
+    /// A value written by the client is larger than the maximum value size of its `SizeLimits`.
+    #[error(
+        "Value of {} bytes of key {:?} exceeds the maximum value size of {} bytes",
+        size,
+        key,
+        limit
+    )]
+    ValueTooLarge {
+        key: Vec<u8>,
+        size: usize,
+        limit: usize,
+    },
+    /// A key in the memcomparable format, e.g. a region boundary returned by PD, could not be
+    /// decoded.
+    #[error("Key {:?} is not a valid encoded key: {}", key, message)]
+    InvalidEncodedKey { key: Vec<u8>, message: String },
+    /// The start of a range is after its end.
+    #[error("Invalid range: start {:?} is after end {:?}", start, end)]
+    InvalidRange { start: Vec<u8>, end: Vec<u8> },
+    /// A prefix is empty, so it would select all keys, e.g. of a `delete_prefix`.
+    #[error("Empty prefix, which would select all keys")]
+    EmptyPrefix,
+    /// A value could not be encoded or decoded by a value codec.
+    #[error("Value codec error: {}", message)]
+    ValueCodecError { message: String },
+    /// A key or a value could not be encoded or decoded by its typed codec.
+    #[error("Codec error: {}", message)]
+    CodecError { message: String },
     #[error("Invalid Semver string: {0:?}")]
     InvalidSemver(#[from] semver::Error),
+    /// A regular expression, e.g. of a `ScanFilter`, is invalid.
+    #[error("Invalid regex: {0}")]
+    InvalidRegex(#[from] regex::Error),
+    /// The cluster does not accept the API version of the request, e.g. a raw client using a
+    /// keyspace is connected to a cluster which does not run API V2.
+    #[error("API version not matched: {}", message)]
+    ApiVersionNotMatched { message: String },
+    /// The replica serving a stale read has not caught up with the timestamp of the read, it is
+    /// safe to read data up to `safe_ts`. Returned once the retries of the read are exhausted.
+    #[error(
+        "Data is not ready for the stale read in region {}, which is safe to read up to {}",
+        region_id,
+        safe_ts
+    )]
+    DataIsNotReady { region_id: u64, safe_ts: u64 },
+    /// A bulk import failed, e.g. because the pairs to import are not sorted or a store failed to
+    /// write the SST files, see `TransactionClient::import`.
+    #[error("Import failed: {}", message)]
+    ImportFailed { message: String },
+    /// A change feed failed and can't recover, e.g. because the stores don't support the feeds or
+    /// a region kept failing, see `TransactionClient::subscribe_changes`.
+    #[error("Change feed failed: {}", message)]
+    ChangeFeedFailed { message: String },
+    /// A TTL is set in a raw request but TTL is not enabled in the cluster.
+    #[error("TTL is not enabled in the cluster: {}", message)]
+    TtlNotEnabled { message: String },
     /// A string error returned by TiKV server
     #[error("Kv error. {}", message)]
     KvError { message: String },
@@ -90,15 +326,280 @@ pub enum Error {
     StringError(String),
 }
 
-impl From<tikv_client_proto::errorpb::Error> for Error {
-    fn from(e: tikv_client_proto::errorpb::Error) -> Error {
+impl Error {
+    /// Whether retrying the failed operation, or the transaction it is part of with a new start
+    /// timestamp, may succeed, e.g. after a transport error, a region error or a write conflict.
+    ///
+    /// Errors caused by the arguments of the operation, by the configuration of the client or the
+    /// cluster, or which leave it unknown whether a transaction is committed, are not retryable.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Error::Grpc(grpcio::Error::RpcFailure(status)) => [
+                grpcio::RpcStatusCode::UNAVAILABLE,
+                grpcio::RpcStatusCode::DEADLINE_EXCEEDED,
+                grpcio::RpcStatusCode::RESOURCE_EXHAUSTED,
+                grpcio::RpcStatusCode::ABORTED,
+            ]
+            .contains(&status.code()),
+            Error::Grpc(grpcio::Error::RemoteStopped) => true,
+            Error::Grpc(_) => false,
+            // the entries of a region are too large to propose whatever the attempt
+            Error::RegionError(e) => !e.has_raft_entry_too_large(),
+            Error::KeyError(e) => {
+                e.has_locked()
+                    || e.has_conflict()
+                    || e.has_deadlock()
+                    || e.has_commit_ts_expired()
+                    || !e.get_retryable().is_empty()
+            }
+            Error::UndeterminedError(_) => false,
+            Error::CommitFailed { source, .. }
+            | Error::RetriesExhausted { source, .. }
+            | Error::RequestFailed { source, .. } => source.is_retryable(),
+            Error::ExtractedErrors(errors) | Error::MultipleKeyErrors(errors) => {
+                !errors.is_empty() && errors.iter().all(Error::is_retryable)
+            }
+            Error::ResolveLockError
+            | Error::NoCurrentRegions
+            | Error::EntryNotFoundInRegionCache
+            | Error::WriteConflict { .. }
+            | Error::Deadlock { .. }
+            | Error::CommitTsExpired { .. }
+            | Error::RegionForKeyNotFound { .. }
+            | Error::RegionNotFoundInResponse { .. }
+            | Error::LeaderNotFound { .. }
+            | Error::RequestTimeout { .. }
+            | Error::DeadlineExceeded { .. }
+            | Error::DataIsNotReady { .. } => true,
+            // the pairs may have been read while they were written
+            Error::ChecksumMismatch { .. } => true,
+            Error::Unimplemented
+            | Error::DuplicateKeyInsertion
+            | Error::UnsupportedMutation { .. }
+            | Error::KeyRejected { .. }
+            | Error::InvalidTransactionType
+            | Error::OperationAfterCommitError
+            | Error::InvalidSavepoint
+            | Error::TransactionExpired { .. }
+            | Error::OnePcFailure
+            | Error::NoPrimaryKey
+            | Error::UnsupportedMode
+            | Error::Io(_)
+            | Error::Canceled(_)
+            | Error::KeyAlreadyExists { .. }
+            | Error::AssertionFailed { .. }
+            | Error::TxnNotFound { .. }
+            | Error::TxnTooOld { .. }
+            | Error::InvalidCommitTs { .. }
+            | Error::ColumnFamilyError(_)
+            | Error::JoinError(_)
+            | Error::MaxScanLimitExceeded { .. }
+            | Error::ResultTooLarge { .. }
+            | Error::KeyTooLarge { .. }
+            | Error::ValueTooLarge { .. }
+            | Error::InvalidEncodedKey { .. }
+            | Error::InvalidRange { .. }
+            | Error::EmptyPrefix
+            | Error::ValueCodecError { .. }
+            | Error::CodecError { .. }
+            | Error::InvalidSemver(_)
+            | Error::InvalidRegex(_)
+            | Error::ApiVersionNotMatched { .. }
+            | Error::ImportFailed { .. }
+            | Error::ChangeFeedFailed { .. }
+            | Error::TtlNotEnabled { .. }
+            | Error::KvError { .. }
+            | Error::InternalError { .. }
+            | Error::StringError(_) => false,
+        }
+    }
+
+    /// The request to a region which failed, if the error is or is caused by a
+    /// [`RequestFailed`](Error::RequestFailed) error.
+    pub fn failed_request(&self) -> Option<&FailedRequest> {
+        match self {
+            Error::RequestFailed { request, .. } => Some(request),
+            Error::UndeterminedError(source)
+            | Error::CommitFailed { source, .. }
+            | Error::RetriesExhausted { source, .. } => source.failed_request(),
+            _ => None,
+        }
+    }
+
+    /// The region error returned by TiKV which caused the error, if any, also if it was converted
+    /// into another error, e.g. [`DataIsNotReady`](Error::DataIsNotReady).
+    pub fn region_error(&self) -> Option<&errorpb::Error> {
+        match self {
+            Error::RegionError(e) => Some(e),
+            Error::RequestFailed { source, request } => request
+                .region_error
+                .as_ref()
+                .or_else(|| source.region_error()),
+            Error::UndeterminedError(source)
+            | Error::CommitFailed { source, .. }
+            | Error::RetriesExhausted { source, .. } => source.region_error(),
+            _ => None,
+        }
+    }
+
+    /// The error a request to a region failed with if the error is a
+    /// [`RequestFailed`](Error::RequestFailed) error, the error itself otherwise.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # use tikv_client_common::{Error, FailedRequest};
+    /// let e = Error::RequestFailed {
+    ///     source: Box::new(Error::Unimplemented),
+    ///     request: FailedRequest::default(),
+    /// };
+    /// assert!(matches!(e.without_request(), Error::Unimplemented));
+    /// ```
+    pub fn without_request(&self) -> &Error {
+        match self {
+            Error::RequestFailed { source, .. } => source,
+            e => e,
+        }
+    }
+
+    /// The lock which caused the error, if it is a [`KeyError`](Error::KeyError) holding a lock,
+    /// e.g. of a read which fails fast on locks.
+    pub fn lock(&self) -> Option<LockInfo> {
+        match self {
+            Error::KeyError(e) => e.locked.clone().map(Into::into),
+            Error::RequestFailed { source, .. } => source.lock(),
+            _ => None,
+        }
+    }
+}
+
+/// The request to a region which failed, see [`RequestFailed`](Error::RequestFailed).
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FailedRequest {
+    /// The type of the request, e.g. `kv_prewrite`.
+    pub request_type: &'static str,
+    /// The region the request was sent to.
+    pub region_id: u64,
+    /// The store the request was sent to, `None` if it is unknown.
+    pub store_id: Option<u64>,
+    /// The region error returned by TiKV, if any, also if it was converted into another error,
+    /// e.g. [`DataIsNotReady`](Error::DataIsNotReady).
+    pub region_error: Option<errorpb::Error>,
+}
+
+impl fmt::Display for FailedRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} to region {}", self.request_type, self.region_id)?;
+        if let Some(store_id) = self.store_id {
+            write!(f, " on store {}", store_id)?;
+        }
+        Ok(())
+    }
+}
+
+/// The operation of a transaction which wrote a key, see
+/// [`AssertionFailed`](Error::AssertionFailed).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct WriteOrigin {
+    /// The name of the operation, e.g. `insert` or `put_with_assertion`.
+    pub operation: &'static str,
+    /// The number of write operations performed by the transaction before it, so that the
+    /// mutation written by the first write operation has index 0.
+    pub index: u64,
+}
+
+impl fmt::Display for WriteOrigin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (write #{})", self.operation, self.index)
+    }
+}
+
+/// A transaction waiting for the lock of another transaction, see [`Deadlock`](Error::Deadlock).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct WaitForEntry {
+    /// The start timestamp of the waiting transaction.
+    pub txn: u64,
+    /// The start timestamp of the transaction holding the lock.
+    pub wait_for_txn: u64,
+    /// The key the waiting transaction is trying to lock.
+    pub key: Vec<u8>,
+    /// The hash of the key, which identifies it if TiKV doesn't report the key.
+    pub key_hash: u64,
+    /// How long the transaction has been waiting.
+    pub wait_time: Duration,
+}
+
+impl From<deadlock::WaitForEntry> for WaitForEntry {
+    fn from(entry: deadlock::WaitForEntry) -> WaitForEntry {
+        WaitForEntry {
+            txn: entry.txn,
+            wait_for_txn: entry.wait_for_txn,
+            key: entry.key,
+            key_hash: entry.key_hash,
+            wait_time: Duration::from_millis(entry.wait_time),
+        }
+    }
+}
+
+impl fmt::Display for WaitForEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} waits for {} on key {:?}",
+            self.txn, self.wait_for_txn, self.key
+        )
+    }
+}
+
+impl From<errorpb::Error> for Error {
+    fn from(e: errorpb::Error) -> Error {
         Error::RegionError(e)
     }
 }
 
 impl From<tikv_client_proto::kvrpcpb::KeyError> for Error {
-    fn from(e: tikv_client_proto::kvrpcpb::KeyError) -> Error {
-        Error::KeyError(e)
+    fn from(mut e: tikv_client_proto::kvrpcpb::KeyError) -> Error {
+        if let Some(exist) = e.already_exist.take() {
+            Error::KeyAlreadyExists { key: exist.key }
+        } else if let Some(failed) = e.assertion_failed.take() {
+            Error::AssertionFailed {
+                assertion: failed.get_assertion().into(),
+                key: failed.key,
+                start_ts: failed.start_ts,
+                existing_start_ts: failed.existing_start_ts,
+                existing_commit_ts: failed.existing_commit_ts,
+                written_by: None,
+            }
+        } else if let Some(conflict) = e.conflict.take() {
+            Error::WriteConflict {
+                key: conflict.key,
+                primary: conflict.primary,
+                start_ts: conflict.start_ts,
+                conflict_ts: conflict.conflict_ts,
+                conflict_commit_ts: conflict.conflict_commit_ts,
+            }
+        } else if let Some(deadlock) = e.deadlock.take() {
+            Error::Deadlock {
+                lock_key: deadlock.lock_key,
+                lock_ts: deadlock.lock_ts,
+                deadlock_key_hash: deadlock.deadlock_key_hash,
+                wait_chain: deadlock.wait_chain.into_iter().map(Into::into).collect(),
+            }
+        } else if let Some(not_found) = e.txn_not_found.take() {
+            Error::TxnNotFound {
+                start_ts: not_found.start_ts,
+                primary_key: not_found.primary_key,
+            }
+        } else if let Some(expired) = e.commit_ts_expired.take() {
+            Error::CommitTsExpired {
+                key: expired.key,
+                start_ts: expired.start_ts,
+                attempted_commit_ts: expired.attempted_commit_ts,
+                min_commit_ts: expired.min_commit_ts,
+            }
+        } else {
+            Error::KeyError(e)
+        }
     }
 }
 
@@ -116,3 +617,102 @@ macro_rules! internal_err {
         internal_err!(format!($f, $($arg),+))
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tikv_client_proto::kvrpcpb;
+
+    #[test]
+    fn test_deadlock_from_key_error() {
+        let mut error = kvrpcpb::KeyError::default();
+        error.set_deadlock(kvrpcpb::Deadlock {
+            lock_ts: 10,
+            lock_key: b"k1".to_vec(),
+            wait_chain: vec![deadlock::WaitForEntry {
+                txn: 10,
+                wait_for_txn: 20,
+                key: b"k2".to_vec(),
+                wait_time: 5,
+                ..Default::default()
+            }],
+            ..Default::default()
+        });
+        match Error::from(error) {
+            Error::Deadlock {
+                lock_key,
+                wait_chain,
+                ..
+            } => {
+                assert_eq!(lock_key, b"k1");
+                assert_eq!(
+                    wait_chain,
+                    vec![WaitForEntry {
+                        txn: 10,
+                        wait_for_txn: 20,
+                        key: b"k2".to_vec(),
+                        key_hash: 0,
+                        wait_time: Duration::from_millis(5),
+                    }]
+                );
+            }
+            e => panic!("unexpected error: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_is_retryable() {
+        let not_ready = errorpb::Error {
+            data_is_not_ready: Some(Default::default()),
+            ..Default::default()
+        };
+        let request = FailedRequest {
+            request_type: "kv_get",
+            region_id: 2,
+            store_id: Some(3),
+            region_error: Some(not_ready.clone()),
+        };
+        let e = Error::RetriesExhausted {
+            source: Box::new(Error::RequestFailed {
+                source: Box::new(Error::DataIsNotReady {
+                    region_id: 2,
+                    safe_ts: 5,
+                }),
+                request: request.clone(),
+            }),
+            attempts: 3,
+            history: Vec::new(),
+        };
+        assert!(e.is_retryable());
+        assert_eq!(e.failed_request(), Some(&request));
+        assert_eq!(e.region_error(), Some(&not_ready));
+        assert!(e.to_string().ends_with("(kv_get to region 2 on store 3)"));
+
+        let too_large = errorpb::Error {
+            raft_entry_too_large: Some(Default::default()),
+            ..Default::default()
+        };
+        assert!(!Error::RegionError(too_large).is_retryable());
+        let unavailable = grpcio::RpcStatus::new(grpcio::RpcStatusCode::UNAVAILABLE);
+        assert!(Error::Grpc(grpcio::Error::RpcFailure(unavailable)).is_retryable());
+
+        let abort = kvrpcpb::KeyError {
+            abort: "aborted".to_owned(),
+            ..Default::default()
+        };
+        let locked = kvrpcpb::KeyError {
+            locked: Some(Default::default()),
+            ..Default::default()
+        };
+        assert!(Error::MultipleKeyErrors(vec![Error::KeyError(locked.clone())]).is_retryable());
+        assert!(
+            !Error::MultipleKeyErrors(vec![Error::KeyError(locked), Error::KeyError(abort)])
+                .is_retryable()
+        );
+        assert!(!Error::UndeterminedError(Box::new(Error::RequestTimeout {
+            timeout: Duration::from_secs(1)
+        }))
+        .is_retryable());
+        assert_eq!(Error::Unimplemented.failed_request(), None);
+    }
+}
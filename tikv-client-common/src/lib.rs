@@ -1,9 +1,12 @@
 #[macro_use]
 mod errors;
 pub mod security;
+mod types;
 
 #[macro_use]
 extern crate log;
 
 #[doc(inline)]
-pub use crate::errors::{Error, Result};
+pub use crate::errors::{Error, FailedRequest, Result, WaitForEntry, WriteOrigin};
+#[doc(inline)]
+pub use crate::types::{Assertion, LockInfo};
@@ -4,10 +4,15 @@ use crate::Result;
 use grpcio::{Channel, ChannelBuilder, ChannelCredentialsBuilder, Environment};
 use regex::Regex;
 use std::{
+    borrow::Cow,
+    ffi::CString,
     fs::File,
     io::Read,
     path::{Path, PathBuf},
-    sync::Arc,
+    sync::{
+        atomic::{AtomicI32, Ordering},
+        Arc,
+    },
     time::Duration,
 };
 
@@ -15,6 +20,42 @@ lazy_static::lazy_static! {
     static ref SCHEME_REG: Regex = Regex::new(r"^\s*(https?://)").unwrap();
 }
 
+/// The address `addr` without its `http://` or `https://` scheme, e.g. the address of a client
+/// URL of a PD member.
+pub fn strip_scheme(addr: &str) -> Cow<'_, str> {
+    SCHEME_REG.replace(addr, "")
+}
+
+/// Distinguishes the arguments of each channel, gRPC shares a connection between the channels to
+/// an address whose arguments are the same.
+static CHANNEL_ID: AtomicI32 = AtomicI32::new(0);
+
+/// The options of the gRPC channels to the servers, see
+/// [`SecurityManager::with_channel_options`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ChannelOptions {
+    /// The time between two HTTP/2 pings checking that a connection is alive.
+    pub keepalive_time: Duration,
+    /// How long a ping waits for its acknowledgement before the connection is closed.
+    pub keepalive_timeout: Duration,
+    /// The maximum size of a message sent or received, the default of gRPC if `None`.
+    pub max_message_size: Option<usize>,
+    /// How long a channel without calls keeps its connection open, forever if `None`. The channel
+    /// connects again on its next call.
+    pub idle_timeout: Option<Duration>,
+}
+
+impl Default for ChannelOptions {
+    fn default() -> ChannelOptions {
+        ChannelOptions {
+            keepalive_time: Duration::from_secs(10),
+            keepalive_timeout: Duration::from_secs(3),
+            max_message_size: None,
+            idle_timeout: None,
+        }
+    }
+}
+
 fn check_pem_file(tag: &str, path: &Path) -> Result<File> {
     File::open(path)
         .map_err(|e| internal_err!("failed to open {} to load {}: {:?}", path.display(), tag, e))
@@ -44,6 +85,10 @@ pub struct SecurityManager {
     cert: Vec<u8>,
     /// The path to the file that contains the PEM encoding of the server’s private key.
     key: PathBuf,
+    /// The name checked against the subject alternative names of the servers' certificates, if
+    /// it isn't the host of their addresses.
+    server_name: Option<String>,
+    channel_options: ChannelOptions,
 }
 
 impl SecurityManager {
@@ -59,9 +104,30 @@ impl SecurityManager {
             ca: load_pem_file("ca", ca_path.as_ref())?,
             cert: load_pem_file("certificate", cert_path.as_ref())?,
             key: key_path,
+            server_name: None,
+            channel_options: ChannelOptions::default(),
         })
     }
 
+    /// Verify the certificates of the servers against `name` rather than the hosts of their
+    /// addresses, e.g. when the certificates of a cluster are issued for a single DNS name but
+    /// the servers are reached by IP address.
+    pub fn with_server_name(mut self, name: impl Into<String>) -> SecurityManager {
+        self.server_name = Some(name.into());
+        self
+    }
+
+    /// The name the certificates of the servers are verified against, if any.
+    pub fn server_name(&self) -> Option<&str> {
+        self.server_name.as_deref()
+    }
+
+    /// Connect to the servers with `options`.
+    pub fn with_channel_options(mut self, options: ChannelOptions) -> SecurityManager {
+        self.channel_options = options;
+        self
+    }
+
     /// Connect to gRPC server using TLS connection. If TLS is not configured, use normal connection.
     pub fn connect<Factory, Client>(
         &self,
@@ -74,15 +140,32 @@ impl SecurityManager {
     {
         info!("connect to rpc server at endpoint: {:?}", addr);
 
-        let addr = SCHEME_REG.replace(addr, "");
+        let addr = strip_scheme(addr);
 
-        let cb = ChannelBuilder::new(env)
-            .keepalive_time(Duration::from_secs(10))
-            .keepalive_timeout(Duration::from_secs(3));
+        let options = &self.channel_options;
+        let mut cb = ChannelBuilder::new(env)
+            .keepalive_time(options.keepalive_time)
+            .keepalive_timeout(options.keepalive_timeout)
+            .raw_cfg_int(
+                CString::new("random id").unwrap(),
+                CHANNEL_ID.fetch_add(1, Ordering::Relaxed),
+            );
+        if let Some(size) = options.max_message_size {
+            let size = size.min(i32::MAX as usize) as i32;
+            cb = cb.max_send_message_len(size).max_receive_message_len(size);
+        }
+        if let Some(timeout) = options.idle_timeout {
+            let millis = timeout.as_millis().min(i32::MAX as u128) as i32;
+            cb = cb.raw_cfg_int(CString::new("grpc.client_idle_timeout_ms").unwrap(), millis);
+        }
 
         let channel = if self.ca.is_empty() {
             cb.connect(&addr)
         } else {
+            let cb = match &self.server_name {
+                Some(name) => cb.override_ssl_target(name.clone()),
+                None => cb,
+            };
             let cred = ChannelCredentialsBuilder::new()
                 .root_cert(self.ca.clone())
                 .cert(self.cert.clone(), load_pem_file("private key", &self.key)?)
@@ -121,5 +204,8 @@ mod tests {
         assert_eq!(mgr.cert, vec![1]);
         let key = load_pem_file("private key", &key_path).unwrap();
         assert_eq!(key, vec![2]);
+        assert_eq!(mgr.server_name, None);
+        let mgr = mgr.with_server_name("tikv.example.com");
+        assert_eq!(mgr.server_name.as_deref(), Some("tikv.example.com"));
     }
 }
@@ -0,0 +1,156 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! Mirrors of the protobuf types which appear in the public API of the client, so that it stays
+//! stable when the protobuf code is regenerated. Advanced users working with the protobuf types
+//! directly convert between them with `From` and `Into`.
+
+use tikv_client_proto::kvrpcpb;
+
+/// What TiKV verifies about the existence of a key before a transaction writes it, see
+/// [`AssertionFailed`](crate::Error::AssertionFailed).
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Hash)]
+pub enum Assertion {
+    /// Nothing is verified (the default).
+    #[default]
+    None,
+    /// The key exists.
+    Exist,
+    /// The key does not exist.
+    NotExist,
+}
+
+impl From<Assertion> for kvrpcpb::Assertion {
+    fn from(assertion: Assertion) -> kvrpcpb::Assertion {
+        match assertion {
+            Assertion::None => kvrpcpb::Assertion::None,
+            Assertion::Exist => kvrpcpb::Assertion::Exist,
+            Assertion::NotExist => kvrpcpb::Assertion::NotExist,
+        }
+    }
+}
+
+impl From<kvrpcpb::Assertion> for Assertion {
+    fn from(assertion: kvrpcpb::Assertion) -> Assertion {
+        match assertion {
+            kvrpcpb::Assertion::None => Assertion::None,
+            kvrpcpb::Assertion::Exist => Assertion::Exist,
+            kvrpcpb::Assertion::NotExist => Assertion::NotExist,
+        }
+    }
+}
+
+/// A lock held on a key by a transaction, as returned by TiKV, e.g. in the error of a read which
+/// fails fast on locks, see [`Error::lock`](crate::Error::lock).
+///
+/// The type of the lock is left out, converting a `LockInfo` into the protobuf type sets it to
+/// the default.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct LockInfo {
+    /// The locked key.
+    pub key: Vec<u8>,
+    /// The primary key of the transaction holding the lock, whose lock decides its status.
+    pub primary_lock: Vec<u8>,
+    /// The start timestamp of the transaction holding the lock.
+    pub start_ts: u64,
+    /// The time to live of the lock in milliseconds, since the physical time of `start_ts`.
+    pub ttl: u64,
+    /// The `for_update_ts` of a pessimistic lock, 0 for other locks.
+    pub for_update_ts: u64,
+    /// The number of keys the transaction writes in the region of the key.
+    pub txn_size: u64,
+    /// Whether the transaction commits asynchronously.
+    pub use_async_commit: bool,
+    /// The minimum commit timestamp of the transaction, if it commits asynchronously.
+    pub min_commit_ts: u64,
+    /// The secondary keys of the transaction, if it commits asynchronously and `key` is its
+    /// primary key.
+    pub secondaries: Vec<Vec<u8>>,
+}
+
+impl From<kvrpcpb::LockInfo> for LockInfo {
+    fn from(lock: kvrpcpb::LockInfo) -> LockInfo {
+        LockInfo {
+            key: lock.key,
+            primary_lock: lock.primary_lock,
+            start_ts: lock.lock_version,
+            ttl: lock.lock_ttl,
+            for_update_ts: lock.lock_for_update_ts,
+            txn_size: lock.txn_size,
+            use_async_commit: lock.use_async_commit,
+            min_commit_ts: lock.min_commit_ts,
+            secondaries: lock.secondaries,
+        }
+    }
+}
+
+impl From<LockInfo> for kvrpcpb::LockInfo {
+    fn from(lock: LockInfo) -> kvrpcpb::LockInfo {
+        kvrpcpb::LockInfo {
+            key: lock.key,
+            primary_lock: lock.primary_lock,
+            lock_version: lock.start_ts,
+            lock_ttl: lock.ttl,
+            lock_for_update_ts: lock.for_update_ts,
+            txn_size: lock.txn_size,
+            use_async_commit: lock.use_async_commit,
+            min_commit_ts: lock.min_commit_ts,
+            secondaries: lock.secondaries,
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_assertion_conversion() {
+        for (assertion, proto) in [
+            (Assertion::None, kvrpcpb::Assertion::None),
+            (Assertion::Exist, kvrpcpb::Assertion::Exist),
+            (Assertion::NotExist, kvrpcpb::Assertion::NotExist),
+        ] {
+            assert_eq!(kvrpcpb::Assertion::from(assertion), proto);
+            assert_eq!(Assertion::from(proto), assertion);
+        }
+    }
+
+    #[test]
+    fn test_lock_info_conversion() {
+        let mut proto = kvrpcpb::LockInfo {
+            key: vec![2],
+            primary_lock: vec![1],
+            lock_version: 10,
+            lock_ttl: 3000,
+            lock_for_update_ts: 12,
+            txn_size: 2,
+            use_async_commit: true,
+            min_commit_ts: 15,
+            secondaries: vec![vec![2], vec![3]],
+            ..Default::default()
+        };
+        proto.set_lock_type(kvrpcpb::Op::PessimisticLock);
+        let lock = LockInfo::from(proto.clone());
+        assert_eq!(
+            lock,
+            LockInfo {
+                key: vec![2],
+                primary_lock: vec![1],
+                start_ts: 10,
+                ttl: 3000,
+                for_update_ts: 12,
+                txn_size: 2,
+                use_async_commit: true,
+                min_commit_ts: 15,
+                secondaries: vec![vec![2], vec![3]],
+            }
+        );
+
+        // the type of the lock is lost
+        let converted = kvrpcpb::LockInfo::from(lock);
+        assert_eq!(converted.get_lock_type(), kvrpcpb::Op::Put);
+        proto.set_lock_type(kvrpcpb::Op::Put);
+        assert_eq!(converted, proto);
+    }
+}
@@ -4,17 +4,21 @@ use crate::{timestamp::TimestampOracle, Error, Result, SecurityManager};
 use async_trait::async_trait;
 use grpcio::{CallOption, Environment};
 use std::{
-    collections::HashSet,
-    sync::Arc,
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
     time::{Duration, Instant},
 };
-use tikv_client_common::internal_err;
-use tikv_client_proto::pdpb::{self, Timestamp};
+use tikv_client_common::{internal_err, security::strip_scheme};
+use tikv_client_proto::{
+    keyspacepb,
+    pdpb::{self, Timestamp},
+};
 
 /// A PD cluster.
 pub struct Cluster {
     id: u64,
     client: pdpb::PdClient,
+    keyspace_client: keyspacepb::KeyspaceClient,
     members: pdpb::GetMembersResponse,
     tso: TimestampOracle,
 }
@@ -31,6 +35,11 @@ macro_rules! pd_request {
 
 // These methods make a single attempt to make a request.
 impl Cluster {
+    /// The id of the cluster, which PD assigns when the cluster is created.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
     pub async fn get_region(
         &self,
         key: Vec<u8>,
@@ -66,6 +75,11 @@ impl Cluster {
         self.tso.clone().get_timestamp().await
     }
 
+    /// Get `count` consecutive timestamps, returning the last of them.
+    pub async fn get_timestamps(&self, count: u32) -> Result<Timestamp> {
+        self.tso.clone().get_timestamps(count).await
+    }
+
     pub async fn update_safepoint(
         &self,
         safepoint: u64,
@@ -75,17 +89,108 @@ impl Cluster {
         req.set_safe_point(safepoint);
         req.send(&self.client, timeout).await
     }
+
+    pub async fn get_safepoint(&self, timeout: Duration) -> Result<pdpb::GetGcSafePointResponse> {
+        let req = pd_request!(self.id, pdpb::GetGcSafePointRequest);
+        req.send(&self.client, timeout).await
+    }
+
+    pub async fn get_members(&self, timeout: Duration) -> Result<pdpb::GetMembersResponse> {
+        let req = pd_request!(self.id, pdpb::GetMembersRequest);
+        req.send(&self.client, timeout).await
+    }
+
+    pub async fn split_and_scatter_regions(
+        &self,
+        split_keys: Vec<Vec<u8>>,
+        timeout: Duration,
+    ) -> Result<pdpb::SplitAndScatterRegionsResponse> {
+        let mut req = pd_request!(self.id, pdpb::SplitAndScatterRegionsRequest);
+        req.split_keys = split_keys;
+        req.send(&self.client, timeout).await
+    }
+
+    pub async fn scatter_regions(
+        &self,
+        region_ids: Vec<u64>,
+        timeout: Duration,
+    ) -> Result<pdpb::ScatterRegionResponse> {
+        let mut req = pd_request!(self.id, pdpb::ScatterRegionRequest);
+        req.regions_id = region_ids;
+        req.send(&self.client, timeout).await
+    }
+
+    pub async fn load_keyspace(
+        &self,
+        name: &str,
+        timeout: Duration,
+    ) -> Result<keyspacepb::LoadKeyspaceResponse> {
+        let mut req = pd_request!(self.id, keyspacepb::LoadKeyspaceRequest);
+        req.set_name(name.to_owned());
+        let option = CallOption::default().timeout(timeout);
+        let response = self
+            .keyspace_client
+            .load_keyspace_async_opt(&req, option)?
+            .await?;
+        let header = response.get_header();
+        if header.has_error() {
+            Err(internal_err!(header.get_error().get_message()))
+        } else {
+            Ok(response)
+        }
+    }
 }
 
+/// The clients of the services of a PD node, and its members.
+type Connected = (
+    pdpb::PdClient,
+    keyspacepb::KeyspaceClient,
+    pdpb::GetMembersResponse,
+);
+
+/// How long an endpoint which failed to respond is tried after the endpoints which did not.
+const ENDPOINT_FAILURE_PENALTY: Duration = Duration::from_secs(30);
+
 /// An object for connecting and reconnecting to a PD cluster.
 pub struct Connection {
     env: Arc<Environment>,
     security_mgr: Arc<SecurityManager>,
+    /// The priorities of endpoints by their addresses without scheme.
+    endpoint_priorities: HashMap<String, u32>,
+    /// When the endpoints which failed to respond last failed, by their addresses without scheme.
+    failed_endpoints: Mutex<HashMap<String, Instant>>,
 }
 
 impl Connection {
     pub fn new(env: Arc<Environment>, security_mgr: Arc<SecurityManager>) -> Connection {
-        Connection { env, security_mgr }
+        Connection {
+            env,
+            security_mgr,
+            endpoint_priorities: HashMap::new(),
+            failed_endpoints: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Set the priorities of PD endpoints. Endpoints with a higher priority are tried first when
+    /// connecting or reconnecting, unless they failed to respond recently. Endpoints without a
+    /// priority have priority 0. The scheme of the endpoints, if any, is ignored, so that the
+    /// priorities apply to the client URLs of the PD members as well.
+    pub fn with_endpoint_priorities(mut self, endpoint_priorities: HashMap<String, u32>) -> Self {
+        self.endpoint_priorities = endpoint_priorities
+            .into_iter()
+            .map(|(endpoint, priority)| (strip_scheme(&endpoint).into_owned(), priority))
+            .collect();
+        self
+    }
+
+    fn order_by_priority<'a>(&self, endpoints: impl IntoIterator<Item = &'a str>) -> Vec<&'a str> {
+        let failed_endpoints = self.failed_endpoints.lock().unwrap();
+        order_by_priority(
+            &self.endpoint_priorities,
+            &failed_endpoints,
+            Instant::now(),
+            endpoints,
+        )
     }
 
     pub async fn connect_cluster(
@@ -94,12 +199,13 @@ impl Connection {
         timeout: Duration,
     ) -> Result<Cluster> {
         let members = self.validate_endpoints(endpoints, timeout).await?;
-        let (client, members) = self.try_connect_leader(&members, timeout).await?;
+        let (client, keyspace_client, members) = self.try_connect_leader(&members, timeout).await?;
         let id = members.get_header().get_cluster_id();
         let tso = TimestampOracle::new(id, &client)?;
         let cluster = Cluster {
             id,
             client,
+            keyspace_client,
             members,
             tso,
         };
@@ -110,11 +216,13 @@ impl Connection {
     pub async fn reconnect(&self, cluster: &mut Cluster, timeout: Duration) -> Result<()> {
         warn!("updating pd client");
         let start = Instant::now();
-        let (client, members) = self.try_connect_leader(&cluster.members, timeout).await?;
+        let (client, keyspace_client, members) =
+            self.try_connect_leader(&cluster.members, timeout).await?;
         let tso = TimestampOracle::new(cluster.id, &client)?;
         *cluster = Cluster {
             id: cluster.id,
             client,
+            keyspace_client,
             members,
             tso,
         };
@@ -132,12 +240,12 @@ impl Connection {
 
         let mut members = None;
         let mut cluster_id = None;
-        for ep in endpoints {
+        for ep in self.order_by_priority(endpoints.iter().map(String::as_str)) {
             if !endpoints_set.insert(ep) {
                 return Err(internal_err!("duplicated PD endpoint {}", ep));
             }
 
-            let (_, resp) = match self.connect(ep, timeout).await {
+            let (_, _, resp) = match self.connect(ep, timeout).await {
                 Ok(resp) => resp,
                 // Ignore failed PD node.
                 Err(e) => {
@@ -175,20 +283,38 @@ impl Connection {
         }
     }
 
-    async fn connect(
-        &self,
-        addr: &str,
-        timeout: Duration,
-    ) -> Result<(pdpb::PdClient, pdpb::GetMembersResponse)> {
-        let client = self
-            .security_mgr
-            .connect(self.env.clone(), addr, pdpb::PdClient::new)?;
+    /// Returns true if the PD node at `addr` responds within `timeout`.
+    pub async fn is_reachable(&self, addr: &str, timeout: Duration) -> bool {
+        self.connect(addr, timeout).await.is_ok()
+    }
+
+    async fn connect(&self, addr: &str, timeout: Duration) -> Result<Connected> {
+        let result = self.connect_once(addr, timeout).await;
+        let mut failed_endpoints = self.failed_endpoints.lock().unwrap();
+        if result.is_ok() {
+            failed_endpoints.remove(strip_scheme(addr).as_ref());
+        } else {
+            failed_endpoints.insert(strip_scheme(addr).into_owned(), Instant::now());
+        }
+        result
+    }
+
+    async fn connect_once(&self, addr: &str, timeout: Duration) -> Result<Connected> {
+        // the services of PD share the channel
+        let (client, keyspace_client) =
+            self.security_mgr
+                .connect(self.env.clone(), addr, |channel| {
+                    (
+                        pdpb::PdClient::new(channel.clone()),
+                        keyspacepb::KeyspaceClient::new(channel),
+                    )
+                })?;
         let option = CallOption::default().timeout(timeout);
         let resp = client
             .get_members_async_opt(&pdpb::GetMembersRequest::default(), option)
             .map_err(Error::from)?
             .await?;
-        Ok((client, resp))
+        Ok((client, keyspace_client, resp))
     }
 
     async fn try_connect(
@@ -196,10 +322,10 @@ impl Connection {
         addr: &str,
         cluster_id: u64,
         timeout: Duration,
-    ) -> Result<(pdpb::PdClient, pdpb::GetMembersResponse)> {
-        let (client, r) = self.connect(addr, timeout).await?;
+    ) -> Result<Connected> {
+        let (client, keyspace_client, r) = self.connect(addr, timeout).await?;
         Connection::validate_cluster_id(addr, &r, cluster_id)?;
-        Ok((client, r))
+        Ok((client, keyspace_client, r))
     }
 
     fn validate_cluster_id(
@@ -224,28 +350,31 @@ impl Connection {
         &self,
         previous: &pdpb::GetMembersResponse,
         timeout: Duration,
-    ) -> Result<(pdpb::PdClient, pdpb::GetMembersResponse)> {
+    ) -> Result<Connected> {
         let previous_leader = previous.get_leader();
         let members = previous.get_members();
         let cluster_id = previous.get_header().get_cluster_id();
 
         let mut resp = None;
-        // Try to connect to other members, then the previous leader.
-        'outer: for m in members
-            .iter()
-            .filter(|m| *m != previous_leader)
-            .chain(Some(previous_leader))
-        {
-            for ep in m.get_client_urls() {
-                match self.try_connect(ep.as_str(), cluster_id, timeout).await {
-                    Ok((_, r)) => {
-                        resp = Some(r);
-                        break 'outer;
-                    }
-                    Err(e) => {
-                        error!("failed to connect to {}, {:?}", ep, e);
-                        continue;
-                    }
+        // Try to connect to other members, then the previous leader. Endpoints with a higher
+        // priority are tried first.
+        let endpoints = self.order_by_priority(
+            members
+                .iter()
+                .filter(|m| *m != previous_leader)
+                .chain(Some(previous_leader))
+                .flat_map(|m| m.get_client_urls())
+                .map(String::as_str),
+        );
+        for ep in endpoints {
+            match self.try_connect(ep, cluster_id, timeout).await {
+                Ok((_, _, r)) => {
+                    resp = Some(r);
+                    break;
+                }
+                Err(e) => {
+                    error!("failed to connect to {}, {:?}", ep, e);
+                    continue;
                 }
             }
         }
@@ -330,6 +459,42 @@ impl PdMessage for pdpb::UpdateGcSafePointRequest {
     }
 }
 
+#[async_trait]
+impl PdMessage for pdpb::GetGcSafePointRequest {
+    type Response = pdpb::GetGcSafePointResponse;
+
+    async fn rpc(&self, client: &pdpb::PdClient, opt: CallOption) -> GrpcResult<Self::Response> {
+        client.get_gc_safe_point_async_opt(self, opt)?.await
+    }
+}
+
+#[async_trait]
+impl PdMessage for pdpb::GetMembersRequest {
+    type Response = pdpb::GetMembersResponse;
+
+    async fn rpc(&self, client: &pdpb::PdClient, opt: CallOption) -> GrpcResult<Self::Response> {
+        client.get_members_async_opt(self, opt)?.await
+    }
+}
+
+#[async_trait]
+impl PdMessage for pdpb::ScatterRegionRequest {
+    type Response = pdpb::ScatterRegionResponse;
+
+    async fn rpc(&self, client: &pdpb::PdClient, opt: CallOption) -> GrpcResult<Self::Response> {
+        client.scatter_region_async_opt(self, opt)?.await
+    }
+}
+
+#[async_trait]
+impl PdMessage for pdpb::SplitAndScatterRegionsRequest {
+    type Response = pdpb::SplitAndScatterRegionsResponse;
+
+    async fn rpc(&self, client: &pdpb::PdClient, opt: CallOption) -> GrpcResult<Self::Response> {
+        client.split_and_scatter_regions_async_opt(self, opt)?.await
+    }
+}
+
 trait PdResponse {
     fn header(&self) -> &pdpb::ResponseHeader;
 }
@@ -357,3 +522,97 @@ impl PdResponse for pdpb::UpdateGcSafePointResponse {
         self.get_header()
     }
 }
+
+impl PdResponse for pdpb::GetGcSafePointResponse {
+    fn header(&self) -> &pdpb::ResponseHeader {
+        self.get_header()
+    }
+}
+
+impl PdResponse for pdpb::GetMembersResponse {
+    fn header(&self) -> &pdpb::ResponseHeader {
+        self.get_header()
+    }
+}
+
+impl PdResponse for pdpb::ScatterRegionResponse {
+    fn header(&self) -> &pdpb::ResponseHeader {
+        self.get_header()
+    }
+}
+
+impl PdResponse for pdpb::SplitAndScatterRegionsResponse {
+    fn header(&self) -> &pdpb::ResponseHeader {
+        self.get_header()
+    }
+}
+
+// Orders endpoints by descending priority, the endpoints which failed within the
+// `ENDPOINT_FAILURE_PENALTY` before `now` last, keeping the original order among endpoints with
+// the same priority. Endpoints are compared without their scheme.
+fn order_by_priority<'a>(
+    priorities: &HashMap<String, u32>,
+    failed_endpoints: &HashMap<String, Instant>,
+    now: Instant,
+    endpoints: impl IntoIterator<Item = &'a str>,
+) -> Vec<&'a str> {
+    let mut endpoints: Vec<&str> = endpoints.into_iter().collect();
+    endpoints.sort_by_key(|ep| {
+        let ep = strip_scheme(ep);
+        let failed = failed_endpoints.get(ep.as_ref()).is_some_and(|failed| {
+            now.saturating_duration_since(*failed) < ENDPOINT_FAILURE_PENALTY
+        });
+        let priority = priorities.get(ep.as_ref()).copied().unwrap_or(0);
+        (failed, std::cmp::Reverse(priority))
+    });
+    endpoints
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_order_by_priority() {
+        let priorities: HashMap<String, u32> = vec![("b".to_owned(), 10), ("d".to_owned(), 1)]
+            .into_iter()
+            .collect();
+        let now = Instant::now();
+        let no_failures = HashMap::new();
+        let endpoints = ["a", "b", "c", "d", "e"];
+        assert_eq!(
+            order_by_priority(&priorities, &no_failures, now, endpoints.iter().copied()),
+            vec!["b", "d", "a", "c", "e"]
+        );
+        assert_eq!(
+            order_by_priority(
+                &HashMap::new(),
+                &no_failures,
+                now,
+                endpoints.iter().copied()
+            ),
+            endpoints
+        );
+
+        // the client URLs of PD members carry a scheme
+        let endpoints = ["http://a", "https://b", "c", "http://d"];
+        assert_eq!(
+            order_by_priority(&priorities, &no_failures, now, endpoints.iter().copied()),
+            vec!["https://b", "http://d", "http://a", "c"]
+        );
+
+        // endpoints which failed recently are tried last
+        let failed: HashMap<String, Instant> = vec![("b".to_owned(), now), ("c".to_owned(), now)]
+            .into_iter()
+            .collect();
+        assert_eq!(
+            order_by_priority(&priorities, &failed, now, endpoints.iter().copied()),
+            vec!["http://d", "http://a", "https://b", "c"]
+        );
+        let later = now + ENDPOINT_FAILURE_PENALTY;
+        assert_eq!(
+            order_by_priority(&priorities, &failed, later, endpoints.iter().copied()),
+            vec!["https://b", "http://d", "http://a", "c"]
+        );
+    }
+}
@@ -10,6 +10,9 @@
 //! is polled, it tries to exhaust the channel to get as many requests as possible and sends a
 //! single `TsoRequest` to the PD server. The other future receives `TsoResponse`s from the PD
 //! server and allocates timestamps for the requests.
+//!
+//! A request may ask for several consecutive timestamps at once with `get_timestamps`, e.g. to
+//! hand them out later without asking PD again.
 
 use crate::{Error, Result};
 use futures::{
@@ -31,7 +34,8 @@ const MAX_BATCH_SIZE: usize = 64;
 /// TODO: This value should be adjustable.
 const MAX_PENDING_COUNT: usize = 1 << 16;
 
-type TimestampRequest = oneshot::Sender<Timestamp>;
+/// The number of timestamps requested, and the sender of the last of them.
+type TimestampRequest = (u32, oneshot::Sender<Timestamp>);
 
 /// The timestamp oracle (TSO) which provides monotonically increasing timestamps.
 #[derive(Clone)]
@@ -63,11 +67,17 @@ impl TimestampOracle {
         Ok(TimestampOracle { request_tx })
     }
 
-    pub(crate) async fn get_timestamp(mut self) -> Result<Timestamp> {
-        debug!("getting current timestamp");
+    pub(crate) async fn get_timestamp(self) -> Result<Timestamp> {
+        self.get_timestamps(1).await
+    }
+
+    /// Get `count` consecutive timestamps, returning the last of them: the others have the same
+    /// physical time and the `count - 1` preceding logical times.
+    pub(crate) async fn get_timestamps(mut self, count: u32) -> Result<Timestamp> {
+        debug!("getting {} current timestamps", count);
         let (request, response) = oneshot::channel();
         self.request_tx
-            .send(request)
+            .send((count.max(1), request))
             .await
             .map_err(|_| internal_err!("TimestampRequest channel is closed"))?;
         Ok(response.await?)
@@ -128,7 +138,7 @@ struct RequestGroup {
 
 struct TsoRequestStream<'a> {
     cluster_id: u64,
-    request_rx: Pin<&'a mut mpsc::Receiver<oneshot::Sender<Timestamp>>>,
+    request_rx: Pin<&'a mut mpsc::Receiver<TimestampRequest>>,
     pending_requests: Rc<RefCell<VecDeque<RequestGroup>>>,
     self_waker: Rc<AtomicWaker>,
 }
@@ -140,11 +150,13 @@ impl<'a> Stream for TsoRequestStream<'a> {
         let pending_requests = self.pending_requests.clone();
         let mut pending_requests = pending_requests.borrow_mut();
         let mut requests = Vec::new();
+        let mut count = 0;
 
         while requests.len() < MAX_BATCH_SIZE && pending_requests.len() < MAX_PENDING_COUNT {
             match self.request_rx.as_mut().poll_next(cx) {
-                Poll::Ready(Some(sender)) => {
-                    requests.push(sender);
+                Poll::Ready(Some(request)) => {
+                    count += request.0;
+                    requests.push(request);
                 }
                 Poll::Ready(None) => return Poll::Ready(None),
                 Poll::Pending => break,
@@ -158,7 +170,7 @@ impl<'a> Stream for TsoRequestStream<'a> {
                     // TODO
                     sender_id: 0,
                 }),
-                count: requests.len() as u32,
+                count,
                 // TODO
                 dc_location: String::new(),
             };
@@ -204,8 +216,8 @@ fn allocate_timestamps(
             ));
         }
 
-        for request in requests {
-            offset -= 1;
+        for (count, request) in requests {
+            offset -= count;
             let ts = Timestamp {
                 physical: tail_ts.physical,
                 logical: tail_ts.logical - offset as i64,
@@ -0,0 +1,217 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+use crate::{Error, Result};
+use futures::{
+    channel::{mpsc, oneshot},
+    future::{self, Either},
+    prelude::*,
+};
+use grpcio::{
+    CallOption, ClientDuplexReceiver, ClientDuplexSender, RpcStatus, RpcStatusCode, WriteFlags,
+};
+use std::{
+    any::Any,
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tikv_client_proto::tikvpb::{
+    batch_commands_request::{self, request::Cmd},
+    batch_commands_response::response::Cmd as ResponseCmd,
+    BatchCommandsRequest, BatchCommandsResponse, TikvClient,
+};
+
+type Callback = oneshot::Sender<Result<Box<dyn Any + Send>>>;
+
+/// The callbacks of the requests sent over a stream which wait for their responses, by request
+/// id. `None` once the stream failed.
+type InFlight = Arc<Mutex<Option<HashMap<u64, Callback>>>>;
+
+/// Sends the requests to a TiKV store over a single `BatchCommands` stream, instead of a unary
+/// call per request.
+///
+/// The requests which are dispatched while a message is being sent are sent together in the next
+/// message, at most `max_batch_size` per message, so that the many requests of a large
+/// transaction to a store need few messages and a single stream. Once the stream fails, the
+/// requests waiting for their responses fail with an unavailable error, and the next request
+/// opens a new stream.
+#[derive(Clone)]
+pub struct BatchCommandsClient {
+    requests: mpsc::UnboundedSender<(Cmd, Callback)>,
+}
+
+impl BatchCommandsClient {
+    pub fn new(client: Arc<TikvClient>, max_batch_size: usize) -> BatchCommandsClient {
+        let (sender, receiver) = mpsc::unbounded();
+        client.spawn(send_batches(
+            client.clone(),
+            receiver,
+            max_batch_size.max(1),
+        ));
+        BatchCommandsClient { requests: sender }
+    }
+
+    /// Send `cmd` over the stream, failing with a deadline exceeded error if its response
+    /// doesn't arrive within `timeout`.
+    pub async fn dispatch(&self, cmd: Cmd, timeout: Duration) -> Result<Box<dyn Any>> {
+        let (callback, response) = oneshot::channel();
+        if self.requests.unbounded_send((cmd, callback)).is_err() {
+            return Err(unavailable("the batch commands stream is closed"));
+        }
+        match future::select(response, futures_timer::Delay::new(timeout)).await {
+            Either::Left((Ok(result), _)) => result.map(|response| response as Box<dyn Any>),
+            Either::Left((Err(_), _)) => Err(unavailable("the batch commands stream is closed")),
+            Either::Right(_) => Err(Error::Grpc(grpcio::Error::RpcFailure(RpcStatus::new(
+                RpcStatusCode::DEADLINE_EXCEEDED,
+            )))),
+        }
+    }
+}
+
+/// Sends the requests in batches until all clients are dropped.
+async fn send_batches(
+    client: Arc<TikvClient>,
+    mut requests: mpsc::UnboundedReceiver<(Cmd, Callback)>,
+    max_batch_size: usize,
+) {
+    let mut stream: Option<(ClientDuplexSender<BatchCommandsRequest>, InFlight)> = None;
+    let mut next_id = 0;
+    while let Some(request) = requests.next().await {
+        let mut batch = vec![request];
+        while batch.len() < max_batch_size {
+            match requests.try_recv() {
+                Ok(request) => batch.push(request),
+                Err(_) => break,
+            }
+        }
+
+        // reopen the stream once it failed
+        let (mut sink, in_flight) = match stream
+            .take()
+            .filter(|(_, in_flight)| in_flight.lock().unwrap().is_some())
+        {
+            Some(stream) => stream,
+            None => match open_stream(&client) {
+                Ok(stream) => stream,
+                Err(e) => {
+                    let message = e.to_string();
+                    for (_, callback) in batch {
+                        let _ = callback.send(Err(unavailable(&message)));
+                    }
+                    continue;
+                }
+            },
+        };
+
+        let mut message = BatchCommandsRequest::default();
+        if let Some(waiting) = in_flight.lock().unwrap().as_mut() {
+            for (cmd, callback) in batch {
+                message.request_ids.push(next_id);
+                message
+                    .requests
+                    .push(batch_commands_request::Request { cmd: Some(cmd) });
+                waiting.insert(next_id, callback);
+                next_id += 1;
+            }
+        }
+        // the requests were failed along with the stream if it failed in the meantime
+        if message.requests.is_empty() {
+            continue;
+        }
+        match sink.send((message, WriteFlags::default())).await {
+            Ok(()) => stream = Some((sink, in_flight)),
+            Err(e) => fail_in_flight(&in_flight, &e.to_string()),
+        }
+    }
+}
+
+fn open_stream(
+    client: &TikvClient,
+) -> grpcio::Result<(ClientDuplexSender<BatchCommandsRequest>, InFlight)> {
+    let (sink, responses) = client.batch_commands_opt(CallOption::default())?;
+    let in_flight: InFlight = Arc::new(Mutex::new(Some(HashMap::new())));
+    client.spawn(receive_responses(responses, in_flight.clone()));
+    Ok((sink, in_flight))
+}
+
+/// Passes the responses of a stream to the requests waiting for them, until the stream fails.
+async fn receive_responses(
+    mut responses: ClientDuplexReceiver<BatchCommandsResponse>,
+    in_flight: InFlight,
+) {
+    let error = loop {
+        match responses.next().await {
+            Some(Ok(message)) => {
+                let mut in_flight = in_flight.lock().unwrap();
+                let waiting = match in_flight.as_mut() {
+                    Some(waiting) => waiting,
+                    None => return,
+                };
+                for (id, response) in message.request_ids.into_iter().zip(message.responses) {
+                    // the request may have timed out already
+                    if let Some(callback) = waiting.remove(&id) {
+                        let _ = callback.send(match response.cmd {
+                            Some(cmd) => Ok(response_into_any(cmd)),
+                            None => Err(unavailable("the store sent an empty response")),
+                        });
+                    }
+                }
+            }
+            Some(Err(e)) => break e.to_string(),
+            None => break "the batch commands stream was closed by the store".to_owned(),
+        }
+    };
+    fail_in_flight(&in_flight, &error);
+}
+
+fn fail_in_flight(in_flight: &InFlight, message: &str) {
+    if let Some(waiting) = in_flight.lock().unwrap().take() {
+        for (_, callback) in waiting {
+            let _ = callback.send(Err(unavailable(message)));
+        }
+    }
+}
+
+/// The requests which failed along with their stream may or may not have been applied, just like
+/// unary calls to an unreachable store.
+fn unavailable(message: &str) -> Error {
+    Error::Grpc(grpcio::Error::RpcFailure(RpcStatus::with_message(
+        RpcStatusCode::UNAVAILABLE,
+        message.to_owned(),
+    )))
+}
+
+/// The response as returned by the unary call of its request.
+fn response_into_any(cmd: ResponseCmd) -> Box<dyn Any + Send> {
+    match cmd {
+        ResponseCmd::Get(r) => Box::new(r),
+        ResponseCmd::Scan(r) => Box::new(r),
+        ResponseCmd::Prewrite(r) => Box::new(r),
+        ResponseCmd::Commit(r) => Box::new(r),
+        ResponseCmd::Import(r) => Box::new(r),
+        ResponseCmd::Cleanup(r) => Box::new(r),
+        ResponseCmd::BatchGet(r) => Box::new(r),
+        ResponseCmd::BatchRollback(r) => Box::new(r),
+        ResponseCmd::ScanLock(r) => Box::new(r),
+        ResponseCmd::ResolveLock(r) => Box::new(r),
+        ResponseCmd::Gc(r) => Box::new(r),
+        ResponseCmd::DeleteRange(r) => Box::new(r),
+        ResponseCmd::RawGet(r) => Box::new(r),
+        ResponseCmd::RawBatchGet(r) => Box::new(r),
+        ResponseCmd::RawPut(r) => Box::new(r),
+        ResponseCmd::RawBatchPut(r) => Box::new(r),
+        ResponseCmd::RawDelete(r) => Box::new(r),
+        ResponseCmd::RawBatchDelete(r) => Box::new(r),
+        ResponseCmd::RawScan(r) => Box::new(r),
+        ResponseCmd::RawDeleteRange(r) => Box::new(r),
+        ResponseCmd::RawBatchScan(r) => Box::new(r),
+        ResponseCmd::Coprocessor(r) => Box::new(r),
+        ResponseCmd::PessimisticLock(r) => Box::new(r),
+        ResponseCmd::PessimisticRollback(r) => Box::new(r),
+        ResponseCmd::CheckTxnStatus(r) => Box::new(r),
+        ResponseCmd::TxnHeartBeat(r) => Box::new(r),
+        ResponseCmd::CheckSecondaryLocks(r) => Box::new(r),
+        ResponseCmd::RawCoprocessor(r) => Box::new(r),
+        ResponseCmd::Empty(r) => Box::new(r),
+    }
+}
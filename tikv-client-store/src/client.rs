@@ -1,11 +1,23 @@
 // Copyright 2020 TiKV Project Authors. Licensed under Apache-2.0.
 
-use crate::{request::Request, Result, SecurityManager};
+use crate::{
+    batch::BatchCommandsClient, import::ImportRequest, request::Request, Error, Result,
+    SecurityManager,
+};
 use async_trait::async_trait;
 use derive_new::new;
-use grpcio::{CallOption, Environment};
+use futures::{
+    future::{self, Either},
+    prelude::*,
+    stream::BoxStream,
+};
+use grpcio::{CallOption, Environment, RpcStatus, RpcStatusCode, WriteFlags};
 use std::{any::Any, sync::Arc, time::Duration};
-use tikv_client_proto::tikvpb::TikvClient;
+use tikv_client_proto::{
+    cdcpb::{ChangeDataClient, ChangeDataEvent, ChangeDataRequest},
+    import_sstpb::ImportSstClient,
+    tikvpb::TikvClient,
+};
 
 /// A trait for connecting to TiKV stores.
 pub trait KvConnect: Sized + Send + Sync + 'static {
@@ -19,6 +31,19 @@ pub struct TikvConnect {
     env: Arc<Environment>,
     security_mgr: Arc<SecurityManager>,
     timeout: Duration,
+    /// The maximum number of requests in a message of the `BatchCommands` stream of a store,
+    /// requests are unary calls if `None`.
+    #[new(default)]
+    batch_commands: Option<usize>,
+}
+
+impl TikvConnect {
+    /// Send the requests to each store over its `BatchCommands` stream, at most `max_batch_size`
+    /// requests per message, see [`BatchCommandsClient`].
+    pub fn with_batch_commands(mut self, max_batch_size: usize) -> TikvConnect {
+        self.batch_commands = Some(max_batch_size);
+        self
+    }
 }
 
 impl KvConnect for TikvConnect {
@@ -26,14 +51,59 @@ impl KvConnect for TikvConnect {
 
     fn connect(&self, address: &str) -> Result<KvRpcClient> {
         self.security_mgr
-            .connect(self.env.clone(), address, TikvClient::new)
-            .map(|c| KvRpcClient::new(Arc::new(c), self.timeout))
+            .connect(self.env.clone(), address, |channel| {
+                (
+                    TikvClient::new(channel.clone()),
+                    ImportSstClient::new(channel.clone()),
+                    ChangeDataClient::new(channel),
+                )
+            })
+            .map(|(c, import, cdc)| {
+                let c = Arc::new(c);
+                let mut client =
+                    KvRpcClient::new(c.clone(), Arc::new(import), Arc::new(cdc), self.timeout);
+                client.batch = self
+                    .batch_commands
+                    .map(|max_batch_size| BatchCommandsClient::new(c, max_batch_size));
+                client
+            })
     }
 }
 
 #[async_trait]
 pub trait KvClient {
     async fn dispatch(&self, req: &dyn Request) -> Result<Box<dyn Any>>;
+
+    /// Like [`dispatch`](KvClient::dispatch), but cancels the call once it takes longer than
+    /// `timeout`, failing with a deadline exceeded error.
+    async fn dispatch_with_timeout(
+        &self,
+        req: &dyn Request,
+        timeout: Duration,
+    ) -> Result<Box<dyn Any>> {
+        match future::select(self.dispatch(req), futures_timer::Delay::new(timeout)).await {
+            Either::Left((result, _)) => result,
+            Either::Right(_) => Err(Error::Grpc(grpcio::Error::RpcFailure(RpcStatus::new(
+                RpcStatusCode::DEADLINE_EXCEEDED,
+            )))),
+        }
+    }
+
+    /// Send `req` to the `ImportSST` service of the store, cancelling the call once it takes
+    /// longer than `timeout`.
+    async fn dispatch_import(
+        &self,
+        req: &dyn ImportRequest,
+        timeout: Duration,
+    ) -> Result<Box<dyn Any>>;
+
+    /// Open an `EventFeed` stream to the `ChangeData` service of the store, which sends
+    /// `requests` to the store and returns the events it sends back. The stream lasts until
+    /// either side closes it, it has no timeout.
+    fn event_feed(
+        &self,
+        requests: BoxStream<'static, ChangeDataRequest>,
+    ) -> Result<BoxStream<'static, Result<ChangeDataEvent>>>;
 }
 
 /// This client handles requests for a single TiKV node. It converts the data
@@ -41,12 +111,22 @@ pub trait KvClient {
 #[derive(new, Clone)]
 pub struct KvRpcClient {
     rpc_client: Arc<TikvClient>,
+    import_client: Arc<ImportSstClient>,
+    cdc_client: Arc<ChangeDataClient>,
     timeout: Duration,
+    /// If set, the requests which TiKV accepts in a `BatchCommands` stream are sent over it.
+    #[new(default)]
+    batch: Option<BatchCommandsClient>,
 }
 
 #[async_trait]
 impl KvClient for KvRpcClient {
     async fn dispatch(&self, request: &dyn Request) -> Result<Box<dyn Any>> {
+        if let Some(batch) = &self.batch {
+            if let Some(cmd) = request.to_batch_command() {
+                return batch.dispatch(cmd, self.timeout).await;
+            }
+        }
         request
             .dispatch(
                 &self.rpc_client,
@@ -54,4 +134,50 @@ impl KvClient for KvRpcClient {
             )
             .await
     }
+
+    async fn dispatch_with_timeout(
+        &self,
+        request: &dyn Request,
+        timeout: Duration,
+    ) -> Result<Box<dyn Any>> {
+        if let Some(batch) = &self.batch {
+            if let Some(cmd) = request.to_batch_command() {
+                return batch.dispatch(cmd, timeout.min(self.timeout)).await;
+            }
+        }
+        // The deadline is sent to TiKV along with the call, and gRPC cancels the call when it
+        // expires, so TiKV doesn't keep processing a request nobody waits for.
+        request
+            .dispatch(
+                &self.rpc_client,
+                CallOption::default().timeout(timeout.min(self.timeout)),
+            )
+            .await
+    }
+
+    async fn dispatch_import(
+        &self,
+        request: &dyn ImportRequest,
+        timeout: Duration,
+    ) -> Result<Box<dyn Any>> {
+        // The calls carry whole SST files, so they are not bounded by the timeout of the client.
+        request
+            .dispatch(&self.import_client, CallOption::default().timeout(timeout))
+            .await
+    }
+
+    fn event_feed(
+        &self,
+        requests: BoxStream<'static, ChangeDataRequest>,
+    ) -> Result<BoxStream<'static, Result<ChangeDataEvent>>> {
+        let (mut sink, events) = self.cdc_client.event_feed_opt(CallOption::default())?;
+        self.cdc_client.spawn(async move {
+            let mut requests = requests.map(|request| Ok((request, WriteFlags::default())));
+            // a failure of the stream is reported to the receiver of the events
+            if sink.send_all(&mut requests).await.is_ok() {
+                let _ = sink.close().await;
+            }
+        });
+        Ok(events.map_err(Error::Grpc).boxed())
+    }
 }
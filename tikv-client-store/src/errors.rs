@@ -2,7 +2,7 @@
 
 use crate::Error;
 use std::fmt::Display;
-use tikv_client_proto::kvrpcpb;
+use tikv_client_proto::{coprocessor, kvrpcpb};
 
 // Those that can have a single region error
 pub trait HasRegionError {
@@ -55,6 +55,7 @@ has_region_error!(kvrpcpb::CheckTxnStatusResponse);
 has_region_error!(kvrpcpb::CheckSecondaryLocksResponse);
 has_region_error!(kvrpcpb::DeleteRangeResponse);
 has_region_error!(kvrpcpb::GcResponse);
+has_region_error!(kvrpcpb::UnsafeDestroyRangeResponse);
 has_region_error!(kvrpcpb::RawGetResponse);
 has_region_error!(kvrpcpb::RawBatchGetResponse);
 has_region_error!(kvrpcpb::RawPutResponse);
@@ -65,7 +66,13 @@ has_region_error!(kvrpcpb::RawDeleteRangeResponse);
 has_region_error!(kvrpcpb::RawScanResponse);
 has_region_error!(kvrpcpb::RawBatchScanResponse);
 has_region_error!(kvrpcpb::RawCasResponse);
+has_region_error!(kvrpcpb::RawGetKeyTtlResponse);
 has_region_error!(kvrpcpb::RawCoprocessorResponse);
+has_region_error!(kvrpcpb::RawChecksumResponse);
+has_region_error!(kvrpcpb::SplitRegionResponse);
+has_region_error!(kvrpcpb::MvccGetByKeyResponse);
+has_region_error!(kvrpcpb::MvccGetByStartTsResponse);
+has_region_error!(coprocessor::Response);
 
 macro_rules! has_key_error {
     ($type:ty) => {
@@ -115,9 +122,26 @@ has_str_error!(kvrpcpb::RawDeleteResponse);
 has_str_error!(kvrpcpb::RawBatchDeleteResponse);
 has_str_error!(kvrpcpb::RawDeleteRangeResponse);
 has_str_error!(kvrpcpb::RawCasResponse);
+has_str_error!(kvrpcpb::RawGetKeyTtlResponse);
 has_str_error!(kvrpcpb::RawCoprocessorResponse);
+has_str_error!(kvrpcpb::RawChecksumResponse);
 has_str_error!(kvrpcpb::ImportResponse);
 has_str_error!(kvrpcpb::DeleteRangeResponse);
+has_str_error!(kvrpcpb::UnsafeDestroyRangeResponse);
+has_str_error!(kvrpcpb::MvccGetByKeyResponse);
+has_str_error!(kvrpcpb::MvccGetByStartTsResponse);
+
+impl HasKeyErrors for coprocessor::Response {
+    fn key_errors(&mut self) -> Option<Vec<Error>> {
+        if self.get_other_error().is_empty() {
+            None
+        } else {
+            Some(vec![Error::KvError {
+                message: self.take_other_error(),
+            }])
+        }
+    }
+}
 
 impl HasKeyErrors for kvrpcpb::ScanResponse {
     fn key_errors(&mut self) -> Option<Vec<Error>> {
@@ -167,6 +191,18 @@ impl HasKeyErrors for kvrpcpb::PessimisticRollbackResponse {
     }
 }
 
+impl HasKeyErrors for kvrpcpb::StoreSafeTsResponse {
+    fn key_errors(&mut self) -> Option<Vec<Error>> {
+        None
+    }
+}
+
+impl HasKeyErrors for kvrpcpb::SplitRegionResponse {
+    fn key_errors(&mut self) -> Option<Vec<Error>> {
+        None
+    }
+}
+
 impl<T: HasKeyErrors, E: Display> HasKeyErrors for Result<T, E> {
     fn key_errors(&mut self) -> Option<Vec<Error>> {
         match self {
@@ -219,7 +255,7 @@ fn extract_errors(
 #[cfg(test)]
 mod test {
     use super::HasKeyErrors;
-    use tikv_client_common::{internal_err, Error};
+    use tikv_client_common::{internal_err, Assertion, Error};
     use tikv_client_proto::kvrpcpb;
     #[test]
     fn result_haslocks() {
@@ -240,4 +276,41 @@ mod test {
         let mut resp: Result<kvrpcpb::CommitResponse, _> = Err(internal_err!("some error"));
         assert!(resp.key_errors().is_some());
     }
+
+    #[test]
+    fn test_typed_key_errors() {
+        let mut resp = kvrpcpb::PrewriteResponse::default();
+        resp.errors.push(kvrpcpb::KeyError {
+            already_exist: Some(kvrpcpb::AlreadyExist {
+                key: b"k1".to_vec(),
+            }),
+            ..Default::default()
+        });
+        let mut failed = kvrpcpb::AssertionFailed {
+            key: b"k2".to_vec(),
+            existing_commit_ts: 10,
+            ..Default::default()
+        };
+        failed.set_assertion(kvrpcpb::Assertion::NotExist);
+        resp.errors.push(kvrpcpb::KeyError {
+            assertion_failed: Some(failed),
+            ..Default::default()
+        });
+        resp.errors.push(kvrpcpb::KeyError {
+            abort: "aborted".to_owned(),
+            ..Default::default()
+        });
+        let errors = resp.key_errors().unwrap();
+        assert!(matches!(&errors[0], Error::KeyAlreadyExists { key } if key == b"k1"));
+        assert!(matches!(
+            &errors[1],
+            Error::AssertionFailed {
+                assertion: Assertion::NotExist,
+                existing_commit_ts: 10,
+                ..
+            }
+        ));
+        // other key errors are passed on as they are
+        assert!(matches!(&errors[2], Error::KeyError(e) if e.abort == "aborted"));
+    }
 }
@@ -0,0 +1,94 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+use crate::{Error, Result};
+use async_trait::async_trait;
+use futures::{prelude::*, stream};
+use grpcio::{CallOption, WriteFlags};
+use std::any::Any;
+use tikv_client_proto::import_sstpb::{self, ImportSstClient};
+
+/// A request to the `ImportSST` service of a TiKV store, which loads SST files into the regions of
+/// the store, bypassing transactions and Raft proposals of the individual keys.
+#[async_trait]
+pub trait ImportRequest: Any + Sync + Send + 'static {
+    async fn dispatch(&self, client: &ImportSstClient, options: CallOption)
+        -> Result<Box<dyn Any>>;
+    fn label(&self) -> &'static str;
+    fn as_any(&self) -> &dyn Any;
+}
+
+macro_rules! impl_import_request {
+    ($name: ident, $fun: ident, $label: literal) => {
+        #[async_trait]
+        impl ImportRequest for import_sstpb::$name {
+            async fn dispatch(
+                &self,
+                client: &ImportSstClient,
+                options: CallOption,
+            ) -> Result<Box<dyn Any>> {
+                client
+                    .$fun(self, options)?
+                    .await
+                    .map(|r| Box::new(r) as Box<dyn Any>)
+                    .map_err(Error::Grpc)
+            }
+
+            fn label(&self) -> &'static str {
+                $label
+            }
+
+            fn as_any(&self) -> &dyn Any {
+                self
+            }
+        }
+    };
+}
+
+/// The requests of the client-streaming calls are the messages of the stream, in order.
+macro_rules! impl_import_stream_request {
+    ($name: ident, $fun: ident, $label: literal) => {
+        #[async_trait]
+        impl ImportRequest for Vec<import_sstpb::$name> {
+            async fn dispatch(
+                &self,
+                client: &ImportSstClient,
+                options: CallOption,
+            ) -> Result<Box<dyn Any>> {
+                let (mut sink, receiver) = client.$fun(options)?;
+                let messages: Vec<_> = self
+                    .iter()
+                    .map(|message| Ok((message.clone(), WriteFlags::default())))
+                    .collect();
+                let mut messages = stream::iter(messages);
+                sink.send_all(&mut messages).await?;
+                sink.close().await?;
+                receiver
+                    .await
+                    .map(|r| Box::new(r) as Box<dyn Any>)
+                    .map_err(Error::Grpc)
+            }
+
+            fn label(&self) -> &'static str {
+                $label
+            }
+
+            fn as_any(&self) -> &dyn Any {
+                self
+            }
+        }
+    };
+}
+
+impl_import_request!(
+    SwitchModeRequest,
+    switch_mode_async_opt,
+    "import_switch_mode"
+);
+impl_import_request!(IngestRequest, ingest_async_opt, "import_ingest");
+impl_import_request!(
+    MultiIngestRequest,
+    multi_ingest_async_opt,
+    "import_multi_ingest"
+);
+impl_import_stream_request!(UploadRequest, upload_opt, "import_upload");
+impl_import_stream_request!(WriteRequest, write_opt, "import_write");
@@ -1,13 +1,17 @@
 // Copyright 2018 TiKV Project Authors. Licensed under Apache-2.0.
 
+mod batch;
 mod client;
 mod errors;
+mod import;
 mod request;
 
 #[doc(inline)]
 pub use crate::{
+    batch::BatchCommandsClient,
     client::{KvClient, KvConnect, TikvConnect},
     errors::{HasKeyErrors, HasRegionError, HasRegionErrors},
+    import::ImportRequest,
     request::Request,
 };
 pub use tikv_client_common::{security::SecurityManager, Error, Result};
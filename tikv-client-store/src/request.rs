@@ -4,7 +4,10 @@ use crate::{Error, Result};
 use async_trait::async_trait;
 use grpcio::CallOption;
 use std::any::Any;
-use tikv_client_proto::{kvrpcpb, tikvpb::TikvClient};
+use tikv_client_proto::{
+    coprocessor, kvrpcpb,
+    tikvpb::{batch_commands_request::request::Cmd, TikvClient},
+};
 
 #[async_trait]
 pub trait Request: Any + Sync + Send + 'static {
@@ -12,10 +15,24 @@ pub trait Request: Any + Sync + Send + 'static {
     fn label(&self) -> &'static str;
     fn as_any(&self) -> &dyn Any;
     fn set_context(&mut self, context: kvrpcpb::Context);
+    /// Let the request ignore locks of the transactions started at `start_versions`. Requests
+    /// which don't read keys ignore them.
+    fn add_resolved_locks(&mut self, _start_versions: &[u64]) {}
+    /// The request as a command of the `BatchCommands` stream of a store, `None` if TiKV doesn't
+    /// accept it in the stream.
+    fn to_batch_command(&self) -> Option<Cmd> {
+        None
+    }
 }
 
 macro_rules! impl_request {
-    ($name: ident, $fun: ident, $label: literal) => {
+    (@batch) => {
+        None
+    };
+    (@batch $batch: ident) => {
+        Some(Cmd::$batch)
+    };
+    ($name: ident, $fun: ident, $label: literal $(, $batch: ident)?) => {
         #[async_trait]
         impl Request for kvrpcpb::$name {
             async fn dispatch(
@@ -41,87 +58,217 @@ macro_rules! impl_request {
             fn set_context(&mut self, context: kvrpcpb::Context) {
                 self.set_context(context);
             }
+
+            fn add_resolved_locks(&mut self, start_versions: &[u64]) {
+                self.mut_context()
+                    .resolved_locks
+                    .extend_from_slice(start_versions);
+            }
+
+            fn to_batch_command(&self) -> Option<Cmd> {
+                let cmd: Option<fn(Self) -> Cmd> = impl_request!(@batch $($batch)?);
+                cmd.map(|cmd| cmd(self.clone()))
+            }
         }
     };
 }
 
-impl_request!(RawGetRequest, raw_get_async_opt, "raw_get");
-impl_request!(RawBatchGetRequest, raw_batch_get_async_opt, "raw_batch_get");
-impl_request!(RawPutRequest, raw_put_async_opt, "raw_put");
-impl_request!(RawBatchPutRequest, raw_batch_put_async_opt, "raw_batch_put");
-impl_request!(RawDeleteRequest, raw_delete_async_opt, "raw_delete");
+impl_request!(RawGetRequest, raw_get_async_opt, "raw_get", RawGet);
+impl_request!(
+    RawBatchGetRequest,
+    raw_batch_get_async_opt,
+    "raw_batch_get",
+    RawBatchGet
+);
+impl_request!(RawPutRequest, raw_put_async_opt, "raw_put", RawPut);
+impl_request!(
+    RawBatchPutRequest,
+    raw_batch_put_async_opt,
+    "raw_batch_put",
+    RawBatchPut
+);
+impl_request!(
+    RawDeleteRequest,
+    raw_delete_async_opt,
+    "raw_delete",
+    RawDelete
+);
 impl_request!(
     RawBatchDeleteRequest,
     raw_batch_delete_async_opt,
-    "raw_batch_delete"
+    "raw_batch_delete",
+    RawBatchDelete
 );
-impl_request!(RawScanRequest, raw_scan_async_opt, "raw_scan");
+impl_request!(RawScanRequest, raw_scan_async_opt, "raw_scan", RawScan);
 impl_request!(
     RawBatchScanRequest,
     raw_batch_scan_async_opt,
-    "raw_batch_scan"
+    "raw_batch_scan",
+    RawBatchScan
 );
 impl_request!(
     RawDeleteRangeRequest,
     raw_delete_range_async_opt,
-    "raw_delete_range"
+    "raw_delete_range",
+    RawDeleteRange
 );
 impl_request!(
     RawCasRequest,
     raw_compare_and_swap_async_opt,
     "raw_compare_and_swap"
 );
+impl_request!(
+    RawGetKeyTtlRequest,
+    raw_get_key_ttl_async_opt,
+    "raw_get_key_ttl"
+);
 impl_request!(
     RawCoprocessorRequest,
     raw_coprocessor_async_opt,
-    "raw_coprocessor"
+    "raw_coprocessor",
+    RawCoprocessor
 );
+impl_request!(RawChecksumRequest, raw_checksum_async_opt, "raw_checksum");
 
-impl_request!(GetRequest, kv_get_async_opt, "kv_get");
-impl_request!(ScanRequest, kv_scan_async_opt, "kv_scan");
-impl_request!(PrewriteRequest, kv_prewrite_async_opt, "kv_prewrite");
-impl_request!(CommitRequest, kv_commit_async_opt, "kv_commit");
-impl_request!(CleanupRequest, kv_cleanup_async_opt, "kv_cleanup");
-impl_request!(BatchGetRequest, kv_batch_get_async_opt, "kv_batch_get");
+impl_request!(GetRequest, kv_get_async_opt, "kv_get", Get);
+impl_request!(ScanRequest, kv_scan_async_opt, "kv_scan", Scan);
+impl_request!(
+    PrewriteRequest,
+    kv_prewrite_async_opt,
+    "kv_prewrite",
+    Prewrite
+);
+impl_request!(CommitRequest, kv_commit_async_opt, "kv_commit", Commit);
+impl_request!(CleanupRequest, kv_cleanup_async_opt, "kv_cleanup", Cleanup);
+impl_request!(
+    BatchGetRequest,
+    kv_batch_get_async_opt,
+    "kv_batch_get",
+    BatchGet
+);
 impl_request!(
     BatchRollbackRequest,
     kv_batch_rollback_async_opt,
-    "kv_batch_rollback"
+    "kv_batch_rollback",
+    BatchRollback
 );
 impl_request!(
     PessimisticRollbackRequest,
     kv_pessimistic_rollback_async_opt,
-    "kv_pessimistic_rollback"
+    "kv_pessimistic_rollback",
+    PessimisticRollback
 );
 impl_request!(
     ResolveLockRequest,
     kv_resolve_lock_async_opt,
-    "kv_resolve_lock"
+    "kv_resolve_lock",
+    ResolveLock
+);
+impl_request!(
+    ScanLockRequest,
+    kv_scan_lock_async_opt,
+    "kv_scan_lock",
+    ScanLock
 );
-impl_request!(ScanLockRequest, kv_scan_lock_async_opt, "kv_scan_lock");
 impl_request!(
     PessimisticLockRequest,
     kv_pessimistic_lock_async_opt,
-    "kv_pessimistic_lock"
+    "kv_pessimistic_lock",
+    PessimisticLock
 );
 impl_request!(
     TxnHeartBeatRequest,
     kv_txn_heart_beat_async_opt,
-    "kv_txn_heart_beat"
+    "kv_txn_heart_beat",
+    TxnHeartBeat
 );
 impl_request!(
     CheckTxnStatusRequest,
     kv_check_txn_status_async_opt,
-    "kv_check_txn_status"
+    "kv_check_txn_status",
+    CheckTxnStatus
 );
 impl_request!(
     CheckSecondaryLocksRequest,
     kv_check_secondary_locks_async_opt,
-    "kv_check_secondary_locks_request"
+    "kv_check_secondary_locks_request",
+    CheckSecondaryLocks
 );
-impl_request!(GcRequest, kv_gc_async_opt, "kv_gc");
+impl_request!(GcRequest, kv_gc_async_opt, "kv_gc", Gc);
+impl_request!(
+    UnsafeDestroyRangeRequest,
+    unsafe_destroy_range_async_opt,
+    "unsafe_destroy_range"
+);
+impl_request!(SplitRegionRequest, split_region_async_opt, "split_region");
 impl_request!(
     DeleteRangeRequest,
     kv_delete_range_async_opt,
-    "kv_delete_range"
+    "kv_delete_range",
+    DeleteRange
+);
+impl_request!(
+    MvccGetByKeyRequest,
+    mvcc_get_by_key_async_opt,
+    "mvcc_get_by_key"
+);
+impl_request!(
+    MvccGetByStartTsRequest,
+    mvcc_get_by_start_ts_async_opt,
+    "mvcc_get_by_start_ts"
 );
+
+// the request targets a store rather than a region, it has no context
+#[async_trait]
+impl Request for kvrpcpb::StoreSafeTsRequest {
+    async fn dispatch(&self, client: &TikvClient, options: CallOption) -> Result<Box<dyn Any>> {
+        client
+            .get_store_safe_ts_async_opt(self, options)?
+            .await
+            .map(|r| Box::new(r) as Box<dyn Any>)
+            .map_err(Error::Grpc)
+    }
+
+    fn label(&self) -> &'static str {
+        "get_store_safe_ts"
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn set_context(&mut self, _: kvrpcpb::Context) {}
+}
+
+#[async_trait]
+impl Request for coprocessor::Request {
+    async fn dispatch(&self, client: &TikvClient, options: CallOption) -> Result<Box<dyn Any>> {
+        client
+            .coprocessor_async_opt(self, options)?
+            .await
+            .map(|r| Box::new(r) as Box<dyn Any>)
+            .map_err(Error::Grpc)
+    }
+
+    fn label(&self) -> &'static str {
+        "coprocessor"
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn set_context(&mut self, context: kvrpcpb::Context) {
+        self.set_context(context);
+    }
+
+    fn add_resolved_locks(&mut self, start_versions: &[u64]) {
+        self.mut_context()
+            .resolved_locks
+            .extend_from_slice(start_versions);
+    }
+
+    fn to_batch_command(&self) -> Option<Cmd> {
+        Some(Cmd::Coprocessor(self.clone()))
+    }
+}